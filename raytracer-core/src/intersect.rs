@@ -0,0 +1,510 @@
+use super::math::{acos, cbrt, cos, sqrt};
+use super::ray::{gamma, Dissolve, Intersection, Ray};
+use super::{FVec, Float};
+
+/*
+Return smallest t > min_distance such that P is on the surface, where:
+    P = ray.origin + ray.direction * t
+If no such t exists, return None
+ */
+pub fn intersect_sphere(
+    centre: &FVec,
+    radius: Float,
+    ray: &Ray,
+    min_distance: Float,
+) -> Option<Intersection> {
+    let a = ray.direction.norm_squared();
+    let difference = ray.origin - centre;
+    let b = 2.0 * ray.direction.dot(&difference);
+    let c = difference.norm_squared() - (radius * radius);
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let t1 = (-b + sqrt(discriminant)) / (2.0 * a);
+    let t2 = (-b - sqrt(discriminant)) / (2.0 * a);
+    [t1, t2]
+        .iter()
+        .copied()
+        .filter(|t| *t > min_distance)
+        .min_by(|a, b| a.partial_cmp(b).unwrap())
+        .map(|t| {
+            // Reproject onto the surface to tighten the error bound
+            let mut local = ray.extend(t) - centre;
+            local *= radius / local.norm();
+            let normal = local.normalize();
+            Intersection {
+                t,
+                pos: centre + local,
+                normal,
+                geometric_normal: None,
+                terminator_offset: None,
+                error: gamma(5) * (local.abs() + centre.abs()),
+                differentials: None,
+                vertex_colour: None,
+                uv: None,
+                uv_tangents: None,
+                dissolve: None,
+                time: ray.time,
+            }
+        })
+}
+
+// Real roots of a * t^2 + b * t + c, smaller first, computed without cancellation
+fn solve_quadratic(a: Float, b: Float, c: Float) -> Option<(Float, Float)> {
+    if a == 0.0 {
+        return (b != 0.0).then(|| (-c / b, -c / b));
+    }
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let q = -0.5 * (b + b.signum() * sqrt(discriminant));
+    let (t0, t1) = if q == 0.0 { (0.0, 0.0) } else { (q / a, c / q) };
+    Some((t0.min(t1), t0.max(t1)))
+}
+
+// Largest real root of t^3 + a * t^2 + b * t + c
+fn largest_cubic_root(a: Float, b: Float, c: Float) -> Float {
+    // Depressed to w^3 + p * w + q with t = w - a / 3
+    let p = b - a * a / 3.0;
+    let q = 2.0 * a * a * a / 27.0 - a * b / 3.0 + c;
+    let discriminant = q * q / 4.0 + p * p * p / 27.0;
+    let w = if discriminant > 0.0 {
+        let root = sqrt(discriminant);
+        cbrt(-q / 2.0 + root) + cbrt(-q / 2.0 - root)
+    } else if p == 0.0 {
+        0.0
+    } else {
+        // Three real roots, of which the angle 0 one is the largest
+        let m = 2.0 * sqrt(-p / 3.0);
+        let cos_3theta = (3.0 * q / (p * m)).clamp(-1.0, 1.0);
+        m * cos(acos(cos_3theta) / 3.0)
+    };
+    w - a / 3.0
+}
+
+/*
+Real roots of t^4 + c[3] * t^3 + c[2] * t^2 + c[1] * t + c[0], by Ferrari's
+method: the depressed quartic is split into two quadratics using a root of
+its resolvent cubic. Each root is then polished by Newton's method on the
+original quartic, which recovers the precision the closed form loses.
+ */
+fn solve_quartic(c: [Float; 4]) -> [Option<Float>; 4] {
+    let [c0, c1, c2, c3] = c;
+    // Depressed to y^4 + p * y^2 + q * y + r with t = y - c3 / 4
+    let shift = c3 / 4.0;
+    let p = c2 - 6.0 * shift * shift;
+    let q = c1 - 2.0 * c2 * shift + 8.0 * shift * shift * shift;
+    let r = c0 - c1 * shift + c2 * shift * shift - 3.0 * shift * shift * shift * shift;
+    let mut roots = [None; 4];
+    let add_quadratic = |roots: &mut [Option<Float>; 4], offset: usize, b: Float, c: Float| {
+        if let Some((y0, y1)) = solve_quadratic(1.0, b, c) {
+            roots[offset] = Some(y0 - shift);
+            roots[offset + 1] = Some(y1 - shift);
+        }
+    };
+    // z^3 + 2p * z^2 + (p^2 - 4r) * z - q^2, whose largest root is positive unless q is 0
+    let z = largest_cubic_root(2.0 * p, p * p - 4.0 * r, -q * q);
+    if z <= 0.0 {
+        // Biquadratic: y^4 + p * y^2 + r, a quadratic in y^2
+        if let Some((s0, s1)) = solve_quadratic(1.0, p, r) {
+            for (i, s) in [s0, s1].into_iter().enumerate() {
+                if s >= 0.0 {
+                    roots[2 * i] = Some(sqrt(s) - shift);
+                    roots[2 * i + 1] = Some(-sqrt(s) - shift);
+                }
+            }
+        }
+    } else {
+        let root_z = sqrt(z);
+        add_quadratic(&mut roots, 0, root_z, (p + z) / 2.0 - q / (2.0 * root_z));
+        add_quadratic(&mut roots, 2, -root_z, (p + z) / 2.0 + q / (2.0 * root_z));
+    }
+    roots.map(|root| {
+        root.map(|mut t| {
+            for _ in 0..3 {
+                let value = (((t + c3) * t + c2) * t + c1) * t + c0;
+                let slope = ((4.0 * t + 3.0 * c3) * t + 2.0 * c2) * t + c1;
+                if slope == 0.0 {
+                    break;
+                }
+                t -= value / slope;
+            }
+            t
+        })
+    })
+}
+
+// The nearest of the candidate hits, each a distance along the ray and the normal there
+fn nearest_candidate(
+    candidates: impl IntoIterator<Item = Option<(Float, FVec)>>,
+    min_distance: Float,
+) -> Option<(Float, FVec)> {
+    candidates
+        .into_iter()
+        .flatten()
+        .filter(|(t, _)| *t > min_distance)
+        .min_by(|(a, _), (b, _)| a.total_cmp(b))
+}
+
+pub fn intersect_plane(
+    point: &FVec,
+    normal: &FVec,
+    ray: &Ray,
+    min_distance: Float,
+) -> Option<Intersection> {
+    let n_dot_d = normal.dot(&ray.direction);
+    if n_dot_d == 0.0 {
+        return None;
+    }
+    let a_minus_p = point - ray.origin;
+    let t = normal.dot(&a_minus_p) / n_dot_d;
+    if t <= min_distance {
+        None
+    } else {
+        Some(Intersection {
+            t,
+            pos: ray.extend(t),
+            normal: *normal,
+            geometric_normal: None,
+            terminator_offset: None,
+            error: gamma(7) * (ray.origin.abs() + (t * ray.direction).abs()),
+            differentials: None,
+            vertex_colour: None,
+            uv: None,
+            uv_tangents: None,
+            dissolve: None,
+            time: ray.time,
+        })
+    }
+}
+
+/*
+Shift from a point on a face onto the smooth surface its vertex normals
+describe, as in Hanika's "Hacking the Shadow Terminator": the point is
+lifted onto the tangent plane of each corner it lies below, and these are
+interpolated. Rays leaving from there above the shading normal clear the
+neighbouring faces, so shadows end smoothly instead of in steps across a
+low-poly curve.
+ */
+fn terminator_offset(
+    vertices: &[FVec; 3],
+    normals: &[FVec; 3],
+    pos: &FVec,
+    barycentrics: [Float; 3],
+) -> FVec {
+    (0..3)
+        .map(|i| {
+            let below = (pos - vertices[i]).dot(&normals[i]).min(0.0);
+            -barycentrics[i] * below * normals[i]
+        })
+        .sum()
+}
+
+/*
+Moller-Trumbore intersection with the triangle with the given corners. The
+normal is interpolated from the vertex normals when there are any, and is
+otherwise the face normal, facing the side from which the corners wind
+anticlockwise; with vertex normals the hit also keeps its terminator
+offset. Vertex colours and texture coordinates are interpolated in
+the same way, and the face's dissolve is passed on to the hit.
+ */
+pub fn intersect_triangle(
+    vertices: &[FVec; 3],
+    normals: Option<&[FVec; 3]>,
+    colours: Option<&[FVec; 3]>,
+    uvs: Option<&[(Float, Float); 3]>,
+    dissolve: Option<&Dissolve>,
+    ray: &Ray,
+    min_distance: Float,
+) -> Option<Intersection> {
+    let [v0, v1, v2] = vertices;
+    let edge1 = v1 - v0;
+    let edge2 = v2 - v0;
+    let p = ray.direction.cross(&edge2);
+    let determinant = edge1.dot(&p);
+    if determinant == 0.0 {
+        return None;
+    }
+    let inverse = 1.0 / determinant;
+    let s = ray.origin - v0;
+    let b1 = s.dot(&p) * inverse;
+    if !(0.0..=1.0).contains(&b1) {
+        return None;
+    }
+    let q = s.cross(&edge1);
+    let b2 = ray.direction.dot(&q) * inverse;
+    if b2 < 0.0 || b1 + b2 > 1.0 {
+        return None;
+    }
+    let t = edge2.dot(&q) * inverse;
+    if t <= min_distance {
+        return None;
+    }
+    let b0 = 1.0 - b1 - b2;
+    // Interpolating the corners is more precise than stepping along the ray
+    let pos = b0 * v0 + b1 * v1 + b2 * v2;
+    let normal = match normals {
+        Some([n0, n1, n2]) => (b0 * n0 + b1 * n1 + b2 * n2).normalize(),
+        None => edge1.cross(&edge2).normalize(),
+    };
+    Some(Intersection {
+        t,
+        pos,
+        normal,
+        geometric_normal: normals.map(|_| edge1.cross(&edge2).normalize()),
+        terminator_offset: normals
+            .map(|normals| terminator_offset(vertices, normals, &pos, [b0, b1, b2])),
+        error: gamma(7) * ((b0 * v0).abs() + (b1 * v1).abs() + (b2 * v2).abs()),
+        differentials: None,
+        vertex_colour: colours.map(|[c0, c1, c2]| b0 * c0 + b1 * c1 + b2 * c2),
+        uv: uvs.map(|[uv0, uv1, uv2]| {
+            (
+                b0 * uv0.0 + b1 * uv1.0 + b2 * uv2.0,
+                b0 * uv0.1 + b1 * uv1.1 + b2 * uv2.1,
+            )
+        }),
+        uv_tangents: uvs.and_then(|[uv0, uv1, uv2]| {
+            // Solve the edges for the directions along which only u or only v changes
+            let (du1, dv1) = (uv1.0 - uv0.0, uv1.1 - uv0.1);
+            let (du2, dv2) = (uv2.0 - uv0.0, uv2.1 - uv0.1);
+            let determinant = du1 * dv2 - dv1 * du2;
+            (determinant != 0.0).then(|| {
+                (
+                    (dv2 * edge1 - dv1 * edge2) / determinant,
+                    (du1 * edge2 - du2 * edge1) / determinant,
+                )
+            })
+        }),
+        dissolve: dissolve.copied(),
+        time: ray.time,
+    })
+}
+
+/*
+Slab intersection with the box between two corners. The normal is that of
+the face hit, and the hit position is snapped onto the face exactly.
+ */
+pub fn intersect_box(
+    min: &FVec,
+    max: &FVec,
+    ray: &Ray,
+    min_distance: Float,
+) -> Option<Intersection> {
+    let (mut near, mut far) = ((Float::NEG_INFINITY, 0), (Float::INFINITY, 0));
+    for axis in 0..3 {
+        let (origin, direction) = (ray.origin[axis], ray.direction[axis]);
+        if direction == 0.0 {
+            if origin < min[axis] || origin > max[axis] {
+                return None;
+            }
+            continue;
+        }
+        let t0 = (min[axis] - origin) / direction;
+        let t1 = (max[axis] - origin) / direction;
+        let (t0, t1) = (t0.min(t1), t0.max(t1));
+        if t0 > near.0 {
+            near = (t0, axis);
+        }
+        if t1 < far.0 {
+            far = (t1, axis);
+        }
+    }
+    if near.0 > far.0 {
+        return None;
+    }
+    let (t, axis) = [near, far].into_iter().find(|(t, _)| *t > min_distance)?;
+    let mut hit = Intersection::on_ray(ray, t, FVec::zeros());
+    let on_max = hit.pos[axis] - min[axis] > max[axis] - hit.pos[axis];
+    hit.normal[axis] = if on_max { 1.0 } else { -1.0 };
+    hit.pos[axis] = if on_max { max[axis] } else { min[axis] };
+    hit.error[axis] = 0.0;
+    Some(hit)
+}
+
+// The part of a plane within the radius of the centre
+pub fn intersect_disc(
+    centre: &FVec,
+    normal: &FVec,
+    radius: Float,
+    ray: &Ray,
+    min_distance: Float,
+) -> Option<Intersection> {
+    let hit = intersect_plane(centre, normal, ray, min_distance)?;
+    ((hit.pos - centre).norm_squared() <= radius * radius).then_some(hit)
+}
+
+// Hit on a cap of a cylinder or cone: a disc facing along the normal
+fn cap_candidate(centre: &FVec, normal: &FVec, radius: Float, ray: &Ray) -> Option<(Float, FVec)> {
+    let hit = intersect_disc(centre, normal, radius, ray, Float::NEG_INFINITY)?;
+    Some((hit.t, *normal))
+}
+
+/*
+The closed cylinder of the radius around the segment from base to top, made
+of its curved side and the two flat caps.
+ */
+pub fn intersect_cylinder(
+    base: &FVec,
+    top: &FVec,
+    radius: Float,
+    ray: &Ray,
+    min_distance: Float,
+) -> Option<Intersection> {
+    let height = (top - base).norm();
+    let axis = (top - base) / height;
+    let offset = ray.origin - base;
+    // The ray and the offset to it across the axis
+    let across = ray.direction - axis * ray.direction.dot(&axis);
+    let offset_across = offset - axis * offset.dot(&axis);
+    let side = |t: Float| {
+        let local = offset + t * ray.direction;
+        let along = local.dot(&axis);
+        let radial = local - axis * along;
+        (0.0..=height)
+            .contains(&along)
+            .then(|| (t, radial.normalize()))
+    };
+    let roots = solve_quadratic(
+        across.norm_squared(),
+        2.0 * across.dot(&offset_across),
+        offset_across.norm_squared() - radius * radius,
+    );
+    let candidates = [
+        roots.and_then(|(t0, _)| side(t0)),
+        roots.and_then(|(_, t1)| side(t1)),
+        cap_candidate(base, &-axis, radius, ray),
+        cap_candidate(top, &axis, radius, ray),
+    ];
+    let (t, normal) = nearest_candidate(candidates, min_distance)?;
+    let mut hit = Intersection::on_ray(ray, t, normal);
+    if normal.dot(&axis).abs() < 0.5 {
+        // Reproject onto the side to tighten the position, as for spheres
+        let local = hit.pos - base;
+        let along = axis * local.dot(&axis);
+        hit.pos = base + along + normal * radius;
+    }
+    Some(hit)
+}
+
+/*
+The closed cone with its apex at one end of the axis and a circular base of
+the radius at the other, made of its sloping side and the base.
+ */
+pub fn intersect_cone(
+    base: &FVec,
+    apex: &FVec,
+    radius: Float,
+    ray: &Ray,
+    min_distance: Float,
+) -> Option<Intersection> {
+    let height = (base - apex).norm();
+    // From the apex towards the base
+    let axis = (base - apex) / height;
+    let slope = radius / height;
+    let k = 1.0 + slope * slope;
+    let offset = ray.origin - apex;
+    let (d_along, o_along) = (ray.direction.dot(&axis), offset.dot(&axis));
+    let roots = solve_quadratic(
+        ray.direction.norm_squared() - k * d_along * d_along,
+        2.0 * (ray.direction.dot(&offset) - k * d_along * o_along),
+        offset.norm_squared() - k * o_along * o_along,
+    );
+    let side = |t: Float| {
+        let local = offset + t * ray.direction;
+        let along = local.dot(&axis);
+        let radial = (local - axis * along).try_normalize(0.0)?;
+        // Outwards, tilted towards the apex by the slope of the side
+        (0.0..=height)
+            .contains(&along)
+            .then(|| (t, (radial - axis * slope).normalize()))
+    };
+    let candidates = [
+        roots.and_then(|(t0, _)| side(t0)),
+        roots.and_then(|(_, t1)| side(t1)),
+        cap_candidate(base, &axis, radius, ray),
+    ];
+    let (t, normal) = nearest_candidate(candidates, min_distance)?;
+    let mut hit = Intersection::on_ray(ray, t, normal);
+    let local = hit.pos - apex;
+    let along = local.dot(&axis);
+    if let Some(radial) = (local - axis * along).try_normalize(0.0) {
+        if normal.dot(&axis) < 0.0 {
+            // Reproject onto the side to tighten the position, as for cylinders
+            hit.pos = apex + axis * along + radial * along * slope;
+        }
+    }
+    Some(hit)
+}
+
+/*
+The torus of a tube of the minor radius swept around a circle of the major
+radius about the axis through the centre. Points p relative to the centre,
+with z along the axis, satisfy
+    (|p|^2 + R^2 - r^2)^2 = 4 R^2 (|p|^2 - z^2)
+which is a quartic in the distance along the ray. The ray is first moved up
+to the sphere bounding the torus, keeping the coefficients small.
+ */
+pub fn intersect_torus(
+    centre: &FVec,
+    axis: &FVec,
+    major_radius: Float,
+    minor_radius: Float,
+    ray: &Ray,
+    min_distance: Float,
+) -> Option<Intersection> {
+    let axis = axis.normalize();
+    let length = ray.direction.norm();
+    let direction = ray.direction / length;
+    let bound = major_radius + minor_radius;
+    let to_start = ray.origin - centre;
+    let (entry, exit) = solve_quadratic(
+        1.0,
+        2.0 * direction.dot(&to_start),
+        to_start.norm_squared() - bound * bound,
+    )?;
+    if exit / length <= min_distance {
+        return None;
+    }
+    let start = entry.max(0.0);
+    let origin = to_start + direction * start;
+    let (r2, m2) = (major_radius * major_radius, minor_radius * minor_radius);
+    let (o_z, d_z) = (origin.dot(&axis), direction.dot(&axis));
+    let o_dot_d = origin.dot(&direction);
+    let o_squared = origin.norm_squared();
+    let h = 2.0 * o_dot_d;
+    let j = o_squared + r2 - m2;
+    let coefficients = [
+        j * j - 4.0 * r2 * (o_squared - o_z * o_z),
+        2.0 * h * j - 8.0 * r2 * (o_dot_d - o_z * d_z),
+        h * h + 2.0 * j - 4.0 * r2 * (1.0 - d_z * d_z),
+        2.0 * h,
+    ];
+    let t = solve_quartic(coefficients)
+        .into_iter()
+        .flatten()
+        .map(|s| (s + start) / length)
+        .filter(|t| *t > min_distance)
+        .min_by(|a, b| a.total_cmp(b))?;
+    let local = ray.extend(t) - centre;
+    let across = local - axis * local.dot(&axis);
+    let ring = across.try_normalize(0.0)? * major_radius;
+    let normal = (local - ring).normalize();
+    // Reproject onto the tube to tighten the position, as for spheres
+    let local = ring + normal * minor_radius;
+    Some(Intersection {
+        t,
+        pos: centre + local,
+        normal,
+        geometric_normal: None,
+        terminator_offset: None,
+        error: gamma(9) * (local.abs() + ring.abs() + centre.abs()),
+        differentials: None,
+        vertex_colour: None,
+        uv: None,
+        uv_tangents: None,
+        dissolve: None,
+        time: ray.time,
+    })
+}
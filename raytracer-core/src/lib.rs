@@ -0,0 +1,54 @@
+/*
+Ray/shape intersection and shading math shared by the renderer. Without the
+`std` feature the crate is no_std, needing only `core` and nalgebra, so it
+can be compiled for targets such as WASM workers. Transcendental functions
+go through `math`, which falls back to libm then.
+ */
+#![cfg_attr(not(feature = "std"), no_std)]
+
+pub mod intersect;
+pub mod math;
+pub mod ray;
+pub mod shading;
+
+#[cfg(not(feature = "f32"))]
+pub type Float = f64;
+#[cfg(feature = "f32")]
+pub type Float = f32;
+pub type FVec = nalgebra::Vector3<Float>;
+
+// Constants such as pi in the precision of Float
+#[cfg(feature = "f32")]
+pub use ::core::f32::consts;
+#[cfg(not(feature = "f32"))]
+pub use ::core::f64::consts;
+
+// Narrowed to single precision for image buffers, or as it is where Float already is
+#[cfg(not(feature = "f32"))]
+pub fn single(x: Float) -> f32 {
+    x as f32
+}
+#[cfg(feature = "f32")]
+pub fn single(x: Float) -> f32 {
+    x
+}
+
+// Widened to double precision for files written in it
+#[cfg(not(feature = "f32"))]
+pub fn double(x: Float) -> f64 {
+    x
+}
+#[cfg(feature = "f32")]
+pub fn double(x: Float) -> f64 {
+    x.into()
+}
+
+pub fn clamp<T: PartialOrd>(x: T, min: T, max: T) -> T {
+    if x < min {
+        min
+    } else if x > max {
+        max
+    } else {
+        x
+    }
+}
@@ -0,0 +1,51 @@
+use super::Float;
+
+#[cfg(feature = "std")]
+pub fn sqrt(x: Float) -> Float {
+    x.sqrt()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn sqrt(x: Float) -> Float {
+    libm::Libm::<Float>::sqrt(x)
+}
+
+#[cfg(feature = "std")]
+pub fn powf(x: Float, y: Float) -> Float {
+    x.powf(y)
+}
+
+#[cfg(not(feature = "std"))]
+pub fn powf(x: Float, y: Float) -> Float {
+    libm::Libm::<Float>::pow(x, y)
+}
+
+#[cfg(feature = "std")]
+pub fn cbrt(x: Float) -> Float {
+    x.cbrt()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn cbrt(x: Float) -> Float {
+    libm::Libm::<Float>::cbrt(x)
+}
+
+#[cfg(feature = "std")]
+pub fn acos(x: Float) -> Float {
+    x.acos()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn acos(x: Float) -> Float {
+    libm::Libm::<Float>::acos(x)
+}
+
+#[cfg(feature = "std")]
+pub fn cos(x: Float) -> Float {
+    x.cos()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn cos(x: Float) -> Float {
+    libm::Libm::<Float>::cos(x)
+}
@@ -0,0 +1,152 @@
+use super::{FVec, Float};
+
+#[derive(Debug)]
+pub struct Ray {
+    pub origin: FVec,
+    pub direction: FVec,
+    pub differential: Option<RayDifferential>,
+    // Moment the ray is traced at, from 0 as the shutter opens to 1 as it closes
+    pub time: Float,
+}
+
+/*
+Offset rays through the neighbouring pixels in x and y, used to estimate the
+footprint of a ray on the surfaces it hits.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct RayDifferential {
+    pub rx_origin: FVec,
+    pub rx_direction: FVec,
+    pub ry_origin: FVec,
+    pub ry_direction: FVec,
+}
+
+impl RayDifferential {
+    pub fn scale(&self, ray: &Ray, s: Float) -> RayDifferential {
+        RayDifferential {
+            rx_origin: ray.origin + (self.rx_origin - ray.origin) * s,
+            rx_direction: ray.direction + (self.rx_direction - ray.direction) * s,
+            ry_origin: ray.origin + (self.ry_origin - ray.origin) * s,
+            ry_direction: ray.direction + (self.ry_direction - ray.direction) * s,
+        }
+    }
+}
+
+impl Ray {
+    pub fn extend(&self, t: Float) -> FVec {
+        self.origin + t * self.direction
+    }
+}
+
+/*
+How much a surface lets through what is behind it, unbent, as the dissolve
+of an OBJ file's material does: the surface's own colour counts by its
+opacity and the light behind by the rest, tinted by the filter.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dissolve {
+    pub opacity: Float,
+    pub filter: FVec,
+}
+
+#[derive(Clone)]
+pub struct Intersection {
+    pub t: Float,
+    pub pos: FVec,
+    pub normal: FVec,
+    // Normal of the face itself where the normal above is interpolated or bent
+    pub geometric_normal: Option<FVec>,
+    // Shift onto the smooth surface vertex normals describe, for rays leaving above it
+    pub terminator_offset: Option<FVec>,
+    // Absolute floating-point error bound on each component of pos
+    pub error: FVec,
+    pub differentials: Option<SurfaceDifferentials>,
+    // Colour interpolated from the vertex colours of a mesh that has them
+    pub vertex_colour: Option<FVec>,
+    // Texture coordinates interpolated from those of a mesh's vertices, if it has them
+    pub uv: Option<(Float, Float)>,
+    // Change in position per unit of u and of v, from the same texture coordinates
+    pub uv_tangents: Option<(FVec, FVec)>,
+    // Set on the faces of a mesh whose material in the file is partly see-through
+    pub dissolve: Option<Dissolve>,
+    // Moment of the ray that found the hit, for the rays leaving it
+    pub time: Float,
+}
+
+impl Intersection {
+    // A hit at distance t along the ray, with the error bound of computing its position there
+    pub fn on_ray(ray: &Ray, t: Float, normal: FVec) -> Intersection {
+        Intersection {
+            t,
+            pos: ray.extend(t),
+            normal,
+            geometric_normal: None,
+            terminator_offset: None,
+            error: gamma(7) * (ray.origin.abs() + (t * ray.direction).abs()),
+            differentials: None,
+            vertex_colour: None,
+            uv: None,
+            uv_tangents: None,
+            dissolve: None,
+            time: ray.time,
+        }
+    }
+
+    /*
+    Origin for a ray leaving the surface in the given direction. The hit point
+    is pushed along the face's normal just past its floating-point error
+    bounds, so the new ray cannot re-hit the surface it starts on, at any
+    scene scale, and then by the bias. Faces that stand in for a curved
+    surface are first left from the terminator offset on the side the shading
+    normal faces, so the ray does not graze a neighbouring face.
+     */
+    pub fn offset_origin(&self, direction: &FVec, bias: Float) -> FVec {
+        let normal = self.geometric_normal.unwrap_or(self.normal);
+        let d = normal.abs().dot(&self.error) + bias;
+        let mut offset = d * normal;
+        if direction.dot(&normal) < 0.0 {
+            offset = -offset;
+        }
+        let pos = match self.terminator_offset {
+            Some(lift) if direction.dot(&self.normal) > 0.0 => self.pos + lift,
+            _ => self.pos,
+        };
+        let mut origin = pos + offset;
+        for i in 0..3 {
+            if offset[i] > 0.0 {
+                origin[i] = next_float_up(origin[i]);
+            } else if offset[i] < 0.0 {
+                origin[i] = next_float_down(origin[i]);
+            }
+        }
+        origin
+    }
+}
+
+// Conservative bound on the relative error of n chained floating-point operations
+pub fn gamma(n: i32) -> Float {
+    let e = Float::EPSILON * 0.5;
+    (n as Float * e) / (1.0 - n as Float * e)
+}
+
+pub fn next_float_up(x: Float) -> Float {
+    if x.is_infinite() && x > 0.0 {
+        return x;
+    }
+    let x = if x == -0.0 { 0.0 } else { x };
+    let bits = x.to_bits();
+    Float::from_bits(if x >= 0.0 { bits + 1 } else { bits - 1 })
+}
+
+pub fn next_float_down(x: Float) -> Float {
+    -next_float_up(-x)
+}
+
+// Change in hit position and normal per pixel step in screen x and y
+#[derive(Debug, Clone, Copy)]
+pub struct SurfaceDifferentials {
+    pub dpdx: FVec,
+    pub dpdy: FVec,
+    pub dndx: FVec,
+    pub dndy: FVec,
+}
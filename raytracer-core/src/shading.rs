@@ -0,0 +1,164 @@
+use super::math::{powf, sqrt};
+use super::{clamp, FVec, Float};
+
+// Lambertian cosine term for light arriving along the unit vector to_light
+pub fn lambert(normal: &FVec, to_light: &FVec) -> Float {
+    clamp(normal.dot(to_light), 0., 1.)
+}
+
+// Blinn-Phong highlight strength from the half vector of to_light and to_viewer
+pub fn blinn_phong(normal: &FVec, to_light: &FVec, to_viewer: &FVec, shine: Float) -> Float {
+    let h = (to_light + to_viewer).normalize();
+    let coeff = powf(h.dot(normal), shine);
+    clamp(coeff, 0.0, 1.0)
+}
+
+/*
+Velvet sheen for light arriving along the unit vector to_light and leaving
+along to_viewer: the "Charlie" fibre distribution of Estevez and Kulla with
+Neubelt and Pettineo's visibility term. Fibres standing up from the surface
+catch the light edge on, so the rim of the object brightens, more sharply
+for lower roughness. Scaled like lambert, including the cosine term.
+ */
+pub fn sheen(normal: &FVec, to_light: &FVec, to_viewer: &FVec, roughness: Float) -> Float {
+    let n_dot_l = normal.dot(to_light);
+    let n_dot_v = normal.dot(to_viewer);
+    if n_dot_l <= 0.0 || n_dot_v <= 0.0 {
+        return 0.0;
+    }
+    let cos_h = normal.dot(&(to_light + to_viewer).normalize());
+    let sin2_h = (1.0 - cos_h * cos_h).max(0.0);
+    let inverse_alpha = 1.0 / (roughness * roughness).max(1e-3);
+    // The distribution times pi, as lambert leaves out the 1/pi of a diffuse BRDF
+    let distribution = (2.0 + inverse_alpha) * powf(sin2_h, 0.5 * inverse_alpha) / 2.0;
+    let visibility = 1.0 / (4.0 * (n_dot_l + n_dot_v - n_dot_l * n_dot_v));
+    distribution * visibility * n_dot_l
+}
+
+/*
+GGX (Trowbridge-Reitz) microfacet reflection for light arriving along the
+unit vector to_light and leaving along to_viewer, with Smith's height-
+correlated masking (Heitz 2014) and Schlick's Fresnel term for the
+reflectance f0 head on. alpha is the roughness squared. Scaled like lambert,
+including the cosine term.
+ */
+pub fn ggx(normal: &FVec, to_light: &FVec, to_viewer: &FVec, alpha: Float, f0: &FVec) -> FVec {
+    let n_dot_l = normal.dot(to_light);
+    let n_dot_v = normal.dot(to_viewer);
+    if n_dot_l <= 0.0 || n_dot_v <= 0.0 {
+        return FVec::zeros();
+    }
+    let half = (to_light + to_viewer).normalize();
+    let distribution = ggx_distribution(normal.dot(&half), alpha);
+    let visibility = smith_visibility(n_dot_l, n_dot_v, alpha);
+    schlick_colour(half.dot(to_viewer), f0) * (distribution * visibility * n_dot_l)
+}
+
+// GGX distribution of microfacet normals at the cosine to the normal, times pi as in sheen
+pub fn ggx_distribution(n_dot_h: Float, alpha: Float) -> Float {
+    let a2 = alpha * alpha;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    a2 / (d * d)
+}
+
+// Smith's masking and shadowing over 4 (n.l) (n.v), the denominator of a microfacet BRDF
+pub fn smith_visibility(n_dot_l: Float, n_dot_v: Float, alpha: Float) -> Float {
+    let a2 = alpha * alpha;
+    let view = n_dot_l * sqrt(n_dot_v * n_dot_v * (1.0 - a2) + a2);
+    let light = n_dot_v * sqrt(n_dot_l * n_dot_l * (1.0 - a2) + a2);
+    0.5 / (view + light)
+}
+
+// Schlick's approximation for each colour channel of a reflectance head on
+pub fn schlick_colour(cos_theta: Float, r0: &FVec) -> FVec {
+    r0.map(|r0| schlick(cos_theta, r0))
+}
+
+/*
+Schlick's approximation of the fraction of light reflected by a dielectric
+surface, where cos_theta is the cosine of the angle to the normal and r0 the
+reflectance head on.
+ */
+pub fn schlick(cos_theta: Float, r0: Float) -> Float {
+    let m = clamp(1.0 - cos_theta, 0.0, 1.0);
+    r0 + (1.0 - r0) * m * m * m * m * m
+}
+
+// Cosine between the viewer and a shading normal bent back towards them by facing_normal
+const MIN_FACING_COS: Float = 0.01;
+
+/*
+A shading normal turned back towards the viewer, along the unit vector to
+them, where interpolation or a normal map tilts it away from them although
+the face itself is towards them. Left so, nothing lights the surface where
+it is seen and it shows black fringes. None where it needs no correction.
+ */
+pub fn facing_normal(normal: &FVec, geometric: &FVec, to_viewer: &FVec) -> Option<FVec> {
+    // Faces wound against their vertex normals are taken as facing the same way
+    let geometric = if geometric.dot(normal) < 0.0 {
+        -geometric
+    } else {
+        *geometric
+    };
+    let cos_theta = normal.dot(to_viewer);
+    if geometric.dot(to_viewer) <= 0.0 || cos_theta >= MIN_FACING_COS {
+        return None;
+    }
+    Some((normal + (MIN_FACING_COS - cos_theta) * to_viewer).normalize())
+}
+
+// Mirror direction of d about the normal
+pub fn reflect(d: &FVec, normal: &FVec) -> FVec {
+    d - 2.0 * d.dot(normal) * normal
+}
+
+/*
+Direction of the unit vector d refracted through a surface whose unit normal
+faces it, where eta is the ratio of the refractive indices before and after
+the surface. None on total internal reflection.
+ */
+pub fn refract(d: &FVec, normal: &FVec, eta: Float) -> Option<FVec> {
+    let cos_i = -normal.dot(d);
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+    if sin2_t > 1.0 {
+        return None;
+    }
+    let cos_t = sqrt(1.0 - sin2_t);
+    Some(eta * d + (eta * cos_i - cos_t) * normal)
+}
+
+/*
+Reflected direction of an offset ray with direction dd, hitting where the
+normal has changed by dn. Differentiates d - 2(d.n)n to first order, which
+keeps reflected ray differentials accurate (PBRT 10.1.3).
+ */
+pub fn reflect_differential(d: &FVec, normal: &FVec, dd: &FVec, dn: &FVec) -> FVec {
+    let ddn = (dd - d).dot(normal) + d.dot(dn);
+    reflect(d, normal) + (dd - d) - 2.0 * (d.dot(normal) * dn + ddn * normal)
+}
+
+/*
+Refracted direction of an offset ray with unit direction dd, hitting where
+the normal has changed by dn, to first order as for reflect_differential.
+Differentiates eta d + (eta cos_i - cos_t) n, where cos_t changes with cos_i
+by eta^2 cos_i / cos_t. None where either ray is totally internally
+reflected or leaves along the surface.
+ */
+pub fn refract_differential(
+    d: &FVec,
+    normal: &FVec,
+    eta: Float,
+    dd: &FVec,
+    dn: &FVec,
+) -> Option<FVec> {
+    let t = refract(d, normal, eta)?;
+    let cos_i = -normal.dot(d);
+    let cos_t = -normal.dot(&t);
+    if cos_t <= 0.0 {
+        return None;
+    }
+    let dd = dd - d;
+    let dcos_i = -(dn.dot(d) + normal.dot(&dd));
+    let dmu = (eta - eta * eta * cos_i / cos_t) * dcos_i;
+    Some(t + eta * dd + (eta * cos_i - cos_t) * dn + dmu * normal)
+}
@@ -0,0 +1,74 @@
+use crate::tonemap::LUMINANCE_WEIGHTS;
+use crate::{FVec, Float};
+use serde::Deserialize;
+
+// Samples a pixel takes before its noise is first estimated; fewer say nothing about it
+pub(crate) const MIN_SAMPLES: u32 = 8;
+// Luminance below which pixels count as this bright, so dark ones need not be sampled forever
+const DARK_LUMINANCE: Float = 0.05;
+
+/*
+Samples taken per pixel until the pixel's noise is low enough. Each pixel
+starts with the camera's samples, at least the minimum, and takes more a
+batch at a time while the standard error of its mean luminance, relative to
+the luminance, is above the threshold. Flat areas then stop early and noisy
+ones, such as soft shadow edges and glossy reflections, get the samples.
+First samples that happen to agree stop a pixel too soon, so scenes with
+small details want more of them.
+ */
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Adaptive {
+    // Relative standard error below which a pixel stops taking samples
+    #[serde(default = "default_threshold")]
+    pub(crate) threshold: Float,
+    // Most samples taken in a pixel
+    #[serde(default = "default_max_samples")]
+    pub(crate) max_samples: u32,
+    // Samples added to a pixel at a time
+    #[serde(default = "default_batch")]
+    pub(crate) batch: u32,
+}
+
+fn default_threshold() -> Float {
+    0.02
+}
+
+fn default_max_samples() -> u32 {
+    256
+}
+
+fn default_batch() -> u32 {
+    8
+}
+
+// Running mean and variance of the luminance of a pixel's samples, by Welford's method
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Estimate {
+    count: u32,
+    mean: Float,
+    squared_deviations: Float,
+}
+
+impl Estimate {
+    pub(crate) fn add(&mut self, colour: &FVec) {
+        let luminance = colour.dot(&FVec::from(LUMINANCE_WEIGHTS));
+        self.count += 1;
+        let delta = luminance - self.mean;
+        self.mean += delta / self.count as Float;
+        self.squared_deviations += delta * (luminance - self.mean);
+    }
+
+    // Whether the pixel has been sampled enough, by its noise or by the budget
+    pub(crate) fn is_done(&self, adaptive: &Adaptive) -> bool {
+        if self.count >= adaptive.max_samples {
+            return true;
+        }
+        if self.count < MIN_SAMPLES {
+            return false;
+        }
+        let variance = self.squared_deviations / (self.count - 1) as Float;
+        let standard_error = (variance / self.count as Float).sqrt();
+        standard_error <= adaptive.threshold * self.mean.abs().max(DARK_LUMINANCE)
+    }
+}
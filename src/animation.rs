@@ -0,0 +1,104 @@
+use crate::Float;
+use serde::Deserialize;
+use std::ops::{Add, Mul, Sub};
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct Keyframe<T> {
+    pub frame: Float,
+    pub value: T,
+    // How the value moves from this key to the next
+    #[serde(default)]
+    pub interpolation: Interpolation,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum Interpolation {
+    // In a straight line at a steady rate
+    #[default]
+    Linear,
+    /*
+    Along a Catmull-Rom spline through the keys around it, so motion eases
+    through keys instead of turning sharply at them
+     */
+    Spline,
+    // Holding the key's value until the next key
+    Step,
+}
+
+/*
+Value of a keyframed track at the given frame, interpolated as each key
+asks, holding the first and last values outside the keyed range. Keys are
+assumed to be sorted by frame. Returns None for an empty track.
+ */
+pub fn interpolate<T>(keys: &[Keyframe<T>], frame: Float) -> Option<T>
+where
+    T: Copy + Add<Output = T> + Sub<Output = T> + Mul<Float, Output = T>,
+{
+    let first = keys.first()?;
+    if frame <= first.frame {
+        return Some(first.value);
+    }
+    for (index, pair) in keys.windows(2).enumerate() {
+        let (a, b) = (&pair[0], &pair[1]);
+        if frame <= b.frame {
+            let span = b.frame - a.frame;
+            if span <= 0.0 {
+                return Some(b.value);
+            }
+            let t = (frame - a.frame) / span;
+            return Some(match a.interpolation {
+                Interpolation::Linear => a.value + (b.value - a.value) * t,
+                Interpolation::Step => a.value,
+                Interpolation::Spline => {
+                    // Keys at the ends have none beyond them and use the segment's own slope
+                    let before = index.checked_sub(1).map_or(a, |i| &keys[i]);
+                    let after = keys.get(index + 2).unwrap_or(b);
+                    let slope = |from: &Keyframe<T>, to: &Keyframe<T>| {
+                        (to.value - from.value) * (span / (to.frame - from.frame))
+                    };
+                    hermite(a.value, slope(before, b), b.value, slope(a, after), t)
+                }
+            });
+        }
+    }
+    keys.last().map(|key| key.value)
+}
+
+// Cubic from a to b as t goes from 0 to 1, leaving and arriving with the given changes per unit t
+fn hermite<T>(a: T, leaving: T, b: T, arriving: T, t: Float) -> T
+where
+    T: Copy + Add<Output = T> + Mul<Float, Output = T>,
+{
+    let (t2, t3) = (t * t, t * t * t);
+    a * (2.0 * t3 - 3.0 * t2 + 1.0)
+        + leaving * (t3 - 2.0 * t2 + t)
+        + b * (-2.0 * t3 + 3.0 * t2)
+        + arriving * (t3 - t2)
+}
+
+fn hash(seed: u32, i: i64) -> u32 {
+    let mut h = (i as u64 as u32) ^ seed.wrapping_mul(0x9e37_79b9);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb_352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846c_a68b);
+    h ^= h >> 16;
+    h
+}
+
+// Deterministic pseudo-random value in [0, 1) for an integer lattice point
+pub fn lattice_random(seed: u32, i: i64) -> Float {
+    hash(seed, i) as Float / (u32::MAX as Float + 1.0)
+}
+
+// Smooth 1D value noise in [-1, 1], varying on the scale of one unit of x
+pub fn value_noise(seed: u32, x: Float) -> Float {
+    let i = x.floor();
+    let t = x - i;
+    let t = t * t * (3.0 - 2.0 * t);
+    let a = lattice_random(seed, i as i64);
+    let b = lattice_random(seed, i as i64 + 1);
+    (a + (b - a) * t) * 2.0 - 1.0
+}
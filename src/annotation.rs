@@ -0,0 +1,129 @@
+use crate::gbuffer::GBuffer;
+use crate::{Float, Scene};
+use image::ImageError;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+/*
+Bounding boxes of objects in a rendered frame for training object detectors,
+in the JSON of the COCO dataset: a 2D box around the pixels where each object
+is seen, leaving out what hides it, and a 3D box around it in the scene in
+metres. Objects are those picked by index, name or tag, or all those with a
+classId, which is the category of each box; objects out of sight get none.
+ */
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AnnotationOutput {
+    pub path: String,
+    pub objects: Option<Vec<usize>>,
+    // Names of the categories by class id
+    #[serde(default)]
+    pub names: BTreeMap<u8, String>,
+}
+
+// The pixels of the frame where an object is seen
+#[derive(Debug, Clone, Copy)]
+pub struct PixelBox {
+    pub min: (u32, u32),
+    pub max: (u32, u32),
+    // Pixels covered, counting the share of samples of partly covered ones
+    pub area: Float,
+}
+
+impl GBuffer {
+    // Box of the pixels of the frame where each of the objects is seen, by object
+    pub fn object_boxes(&self, wanted: impl Fn(usize) -> bool) -> BTreeMap<usize, PixelBox> {
+        let mut boxes: BTreeMap<usize, PixelBox> = BTreeMap::new();
+        let frame = (
+            self.width - 2 * self.overscan,
+            self.height - 2 * self.overscan,
+        );
+        for pixel in &self.pixels {
+            let x = (pixel.x - self.origin.0).checked_sub(self.overscan);
+            let y = (pixel.y - self.origin.1).checked_sub(self.overscan);
+            let (Some(x), Some(y)) = (x, y) else {
+                continue;
+            };
+            if x >= frame.0 || y >= frame.1 {
+                continue;
+            }
+            let share = 1.0 / pixel.samples.len() as Float;
+            for object in pixel.samples.iter().filter_map(|sample| sample.object()) {
+                if !wanted(object) {
+                    continue;
+                }
+                let found = boxes.entry(object).or_insert(PixelBox {
+                    min: (x, y),
+                    max: (x, y),
+                    area: 0.0,
+                });
+                found.min = (found.min.0.min(x), found.min.1.min(y));
+                found.max = (found.max.0.max(x), found.max.1.max(y));
+                found.area += share;
+            }
+        }
+        boxes
+    }
+}
+
+impl Scene {
+    // Write the boxes of the objects seen in the frame rendered to the image at the path
+    pub(crate) fn write_annotations(
+        &self,
+        gbuffer: &GBuffer,
+        output: &AnnotationOutput,
+        image_path: &str,
+    ) -> Result<(), ImageError> {
+        let wanted = |object: usize| match &output.objects {
+            Some(objects) => objects.contains(&object),
+            None => self.objects[object].class_id.is_some(),
+        };
+        let category = |object: usize| self.objects[object].class_id.unwrap_or(0);
+        let boxes = gbuffer.object_boxes(wanted);
+        let annotations: Vec<Value> = boxes
+            .iter()
+            .enumerate()
+            .map(|(id, (&object, found))| {
+                let width = found.max.0 - found.min.0 + 1;
+                let height = found.max.1 - found.min.1 + 1;
+                let bounds = self.objects[object].bounding_box();
+                json!({
+                    "id": id + 1,
+                    "image_id": self.frame,
+                    "category_id": category(object),
+                    "bbox": [found.min.0, found.min.1, width, height],
+                    "area": found.area,
+                    "iscrowd": 0,
+                    "object": object,
+                    "name": self.objects[object].name,
+                    "bbox3d": bounds.map(|bounds| json!({
+                        "min": [bounds.min.x, bounds.min.y, bounds.min.z],
+                        "max": [bounds.max.x, bounds.max.y, bounds.max.z],
+                    })),
+                })
+            })
+            .collect();
+        let mut categories: Vec<u8> = boxes.keys().map(|&object| category(object)).collect();
+        categories.sort_unstable();
+        categories.dedup();
+        let categories: Vec<Value> = categories
+            .into_iter()
+            .map(|id| json!({ "id": id, "name": output.names.get(&id) }))
+            .collect();
+        let overscan = 2 * gbuffer.overscan;
+        let document = json!({
+            "images": [{
+                "id": self.frame,
+                "file_name": image_path,
+                "width": gbuffer.width - overscan,
+                "height": gbuffer.height - overscan,
+            }],
+            "annotations": annotations,
+            "categories": categories,
+        });
+        let text = serde_json::to_string_pretty(&document).map_err(std::io::Error::other)?;
+        std::fs::write(&output.path, text + "\n")?;
+        Ok(())
+    }
+}
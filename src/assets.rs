@@ -0,0 +1,125 @@
+use crate::colour::TextureColourSpace;
+use crate::mesh::{Normals, TriangleMesh};
+use crate::texture::ImageSet;
+use crate::transform::Transform;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+
+/*
+Meshes and images read for one frame of a sequence, kept for the frames
+after it so an asset is only read again once its files change. Images keep
+their place in the tile cache too, so what was decoded for one frame is
+sampled again by the next. Nothing is kept unless a Keep is alive, as a
+scene loaded on its own would only hold on to its assets for longer.
+ */
+#[derive(Default)]
+struct Assets {
+    meshes: Vec<CachedMesh>,
+    images: Vec<CachedImages>,
+}
+
+// A mesh file read and placed one way, with when the file was last modified
+struct CachedMesh {
+    source: MeshSource,
+    modified: Option<SystemTime>,
+    mesh: Arc<TriangleMesh>,
+}
+
+// What a mesh is read with: its file, the transform, the normals and the simplification
+pub(crate) type MeshSource = (PathBuf, Transform, Normals, Option<usize>);
+
+// An image texture by its path, UDIM tiles unexpanded, and the colour space it is read in
+struct CachedImages {
+    path: PathBuf,
+    colour_space: Option<TextureColourSpace>,
+    images: Arc<ImageSet>,
+}
+
+static ASSETS: Mutex<Option<Assets>> = Mutex::new(None);
+
+fn assets() -> MutexGuard<'static, Option<Assets>> {
+    ASSETS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// Assets are kept between scene loads for as long as this is
+pub(crate) struct Keep;
+
+impl Keep {
+    pub(crate) fn start() -> Keep {
+        *assets() = Some(Assets::default());
+        Keep
+    }
+}
+
+impl Drop for Keep {
+    fn drop(&mut self) {
+        *assets() = None;
+    }
+}
+
+pub(crate) fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/*
+The mesh read from the source before if its file is unchanged since, or
+else the one load reads, kept for later if assets are being kept.
+ */
+pub(crate) fn mesh<E>(
+    source: MeshSource,
+    load: impl FnOnce() -> Result<TriangleMesh, E>,
+) -> Result<Arc<TriangleMesh>, E> {
+    let modified = modified(&source.0);
+    if let Some(assets) = assets().as_mut() {
+        let kept = assets.meshes.iter().find(|kept| kept.source == source);
+        if let Some(kept) = kept.filter(|kept| kept.modified == modified) {
+            return Ok(kept.mesh.clone());
+        }
+    }
+    // Read without the lock held, as big meshes take a while
+    let mesh = Arc::new(load()?);
+    if let Some(assets) = assets().as_mut() {
+        assets.meshes.retain(|kept| kept.source != source);
+        assets.meshes.push(CachedMesh {
+            source,
+            modified,
+            mesh: mesh.clone(),
+        });
+    }
+    Ok(mesh)
+}
+
+/*
+The images read from the path in the colour space before if current says
+they still are, or else those load reads, kept for later if assets are
+being kept.
+ */
+pub(crate) fn images<E>(
+    path: PathBuf,
+    colour_space: Option<TextureColourSpace>,
+    current: impl Fn(&ImageSet) -> bool,
+    load: impl FnOnce() -> Result<ImageSet, E>,
+) -> Result<Arc<ImageSet>, E> {
+    let same = |kept: &CachedImages| kept.path == path && kept.colour_space == colour_space;
+    if let Some(assets) = assets().as_mut() {
+        let kept = assets.images.iter().find(|kept| same(kept));
+        if let Some(kept) = kept.filter(|kept| current(&kept.images)) {
+            return Ok(kept.images.clone());
+        }
+    }
+    let images = Arc::new(load()?);
+    if let Some(assets) = assets().as_mut() {
+        assets.images.retain(|kept| !same(kept));
+        assets.images.push(CachedImages {
+            path,
+            colour_space,
+            images: images.clone(),
+        });
+    }
+    Ok(images)
+}
@@ -0,0 +1,191 @@
+use crate::core::consts::PI;
+use crate::light::DistantDirection;
+use crate::{FVec, Float};
+use serde::Deserialize;
+
+// Sizes of the Earth and the top of its atmosphere, in metres
+const EARTH_RADIUS: Float = 6_360_000.0;
+const ATMOSPHERE_RADIUS: Float = 6_420_000.0;
+// Heights over which the density of air molecules and of aerosols falls by a factor of e
+const RAYLEIGH_SCALE_HEIGHT: Float = 8_000.0;
+const MIE_SCALE_HEIGHT: Float = 1_200.0;
+// Scattering per metre at sea level (Hillaire 2020); air molecules scatter blue the most
+const RAYLEIGH_SCATTERING: [Float; 3] = [5.802e-6, 13.558e-6, 33.1e-6];
+const MIE_SCATTERING: Float = 3.996e-6;
+const MIE_EXTINCTION: Float = 4.40e-6;
+// Aerosols scatter mostly forwards, which makes the glow around the sun
+const MIE_ASYMMETRY: Float = 0.8;
+// Steps along a view ray through the sky, along a stretch of air in front of a surface
+// and from each step towards the sun
+const SKY_STEPS: usize = 16;
+const AERIAL_STEPS: usize = 8;
+const SUN_STEPS: usize = 8;
+
+/*
+Air around a planet like the Earth lit by the sun, scattering light by air
+molecules (Rayleigh) and by haze (Mie) once on its way to the viewer, as in
+Nishita et al. 1993. It gives the colour of the sky, from blue overhead to
+reds at sunset, and the aerial perspective that fades and blues distant
+surfaces. Scene positions are taken in metres with +z up, so the effect only
+shows on scenes kilometres across.
+ */
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Atmosphere {
+    // Direction the sunlight travels in, or the sun's position; that of the first distant light
+    // in the scene when unset
+    pub(crate) sun: Option<DistantDirection>,
+    #[serde(default = "default_sun_intensity")]
+    pub(crate) sun_intensity: Float,
+    // Height of the scene origin above sea level, in metres whatever the scene's units
+    #[serde(default)]
+    pub(crate) altitude: Float,
+    // Multiplies the amount of aerosols; higher for hazy or polluted air
+    #[serde(default = "default_haze")]
+    pub(crate) haze: Float,
+    // Unit vector towards the sun, set when the scene is loaded
+    #[serde(skip)]
+    pub(crate) to_sun: FVec,
+}
+
+fn default_sun_intensity() -> Float {
+    20.0
+}
+
+fn default_haze() -> Float {
+    1.0
+}
+
+impl Atmosphere {
+    // Light scattered towards the viewer by the air along the direction, out to space or the ground
+    pub fn sky(&self, origin: &FVec, direction: &FVec) -> FVec {
+        let direction = direction.normalize();
+        let from = self.planet_relative(origin);
+        let length = match intersect_ground(&from, &direction) {
+            Some(ground) => ground,
+            None => intersect_sphere(&from, &direction, ATMOSPHERE_RADIUS).unwrap_or(0.0),
+        };
+        self.scatter(&from, &direction, length, SKY_STEPS).0
+    }
+
+    /*
+    Sunlight the air between the point and the origin scatters towards the
+    origin, and the fraction of a surface's light at the point it lets through.
+     */
+    pub fn aerial_haze(&self, origin: &FVec, point: &FVec) -> (FVec, FVec) {
+        let offset = point - origin;
+        let length = offset.norm();
+        if length == 0.0 {
+            return (FVec::zeros(), FVec::repeat(1.0));
+        }
+        let from = self.planet_relative(origin);
+        self.scatter(&from, &(offset / length), length, AERIAL_STEPS)
+    }
+
+    // Position relative to the centre of the planet
+    fn planet_relative(&self, pos: &FVec) -> FVec {
+        pos + FVec::new(0.0, 0.0, EARTH_RADIUS + self.altitude)
+    }
+
+    /*
+    Sunlight scattered towards the start of a stretch of air of the length
+    along the direction, with the fraction of light from its far end that
+    gets through. Light is dimmed on its way in from the sun and on its way
+    out to the start, and none reaches points in the planet's shadow.
+     */
+    fn scatter(&self, from: &FVec, direction: &FVec, length: Float, steps: usize) -> (FVec, FVec) {
+        let cos_angle = direction.dot(&self.to_sun);
+        let rayleigh_phase = 3.0 / (16.0 * PI) * (1.0 + cos_angle * cos_angle);
+        let mie_phase = henyey_greenstein(cos_angle, MIE_ASYMMETRY);
+        let step = length / steps as Float;
+        let mut depth = (0.0, 0.0);
+        let mut rayleigh = FVec::zeros();
+        let mut mie = FVec::zeros();
+        for i in 0..steps {
+            let pos = from + direction * (step * (i as Float + 0.5));
+            let (rayleigh_density, mie_density) = self.densities(&pos);
+            // Depth to the middle of the step
+            let view = (
+                depth.0 + rayleigh_density * step * 0.5,
+                depth.1 + mie_density * step * 0.5,
+            );
+            depth.0 += rayleigh_density * step;
+            depth.1 += mie_density * step;
+            let Some(sun) = self.depth_to_sun(&pos) else {
+                continue;
+            };
+            let through = extinction(view.0 + sun.0, view.1 + sun.1).map(|e| (-e).exp());
+            rayleigh += through * rayleigh_density * step;
+            mie += through * mie_density * step;
+        }
+        let inscattered = (rayleigh.component_mul(&FVec::from(RAYLEIGH_SCATTERING))
+            * rayleigh_phase
+            + mie * MIE_SCATTERING * mie_phase)
+            * self.sun_intensity;
+        (
+            inscattered,
+            extinction(depth.0, depth.1).map(|e| (-e).exp()),
+        )
+    }
+
+    // Amounts of air and of aerosols at a point relative to those at sea level
+    fn densities(&self, from_centre: &FVec) -> (Float, Float) {
+        let height = (from_centre.norm() - EARTH_RADIUS).max(0.0);
+        (
+            (-height / RAYLEIGH_SCALE_HEIGHT).exp(),
+            self.haze * (-height / MIE_SCALE_HEIGHT).exp(),
+        )
+    }
+
+    // Air and aerosols between the point and space towards the sun, or None behind the planet
+    fn depth_to_sun(&self, from_centre: &FVec) -> Option<(Float, Float)> {
+        if intersect_ground(from_centre, &self.to_sun).is_some() {
+            return None;
+        }
+        let length = intersect_sphere(from_centre, &self.to_sun, ATMOSPHERE_RADIUS)?;
+        let step = length / SUN_STEPS as Float;
+        let mut depth = (0.0, 0.0);
+        for i in 0..SUN_STEPS {
+            let pos = from_centre + self.to_sun * (step * (i as Float + 0.5));
+            let (rayleigh_density, mie_density) = self.densities(&pos);
+            depth.0 += rayleigh_density * step;
+            depth.1 += mie_density * step;
+        }
+        Some(depth)
+    }
+}
+
+// Optical depth per colour channel of lengths of sea-level air and aerosols
+fn extinction(rayleigh: Float, mie: Float) -> FVec {
+    FVec::from(RAYLEIGH_SCATTERING) * rayleigh + FVec::repeat(MIE_EXTINCTION * mie)
+}
+
+fn henyey_greenstein(cos_angle: Float, g: Float) -> Float {
+    let denominator = 1.0 + g * g - 2.0 * g * cos_angle;
+    (1.0 - g * g) / (4.0 * PI * denominator * denominator.sqrt())
+}
+
+/*
+Distance along the unit direction to the surface of the planet, for points
+above it. Points on the ground only count as hitting it looking downwards.
+ */
+fn intersect_ground(from_centre: &FVec, direction: &FVec) -> Option<Float> {
+    let b = from_centre.dot(direction);
+    if b >= 0.0 {
+        return None;
+    }
+    let discriminant = b * b - (from_centre.norm_squared() - EARTH_RADIUS * EARTH_RADIUS);
+    (discriminant >= 0.0).then(|| (-b - discriminant.sqrt()).max(0.0))
+}
+
+// Distance along the unit direction to where it first leaves or enters a sphere about the origin
+fn intersect_sphere(origin: &FVec, direction: &FVec, radius: Float) -> Option<Float> {
+    let b = origin.dot(direction);
+    let c = origin.norm_squared() - radius * radius;
+    let discriminant = b * b - c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let root = discriminant.sqrt();
+    [-b - root, -b + root].into_iter().find(|&t| t > 0.0)
+}
@@ -0,0 +1,68 @@
+use crate::{FVec, Float};
+use nalgebra::Matrix3;
+
+// Second radiation constant hc/k, in metre kelvins
+const C2: Float = 1.438_776_9e-2;
+
+// Coolest temperature rendered, roughly where a black body starts to glow visibly
+const MIN_KELVIN: Float = 800.0;
+
+// Wavelengths in nanometres over which the spectrum is integrated
+pub(crate) const FIRST_WAVELENGTH: Float = 380.0;
+pub(crate) const LAST_WAVELENGTH: Float = 780.0;
+const WAVELENGTH_STEP: Float = 5.0;
+
+// Piecewise Gaussian with different widths either side of its peak
+fn lobe(wavelength: Float, peak: Float, below: Float, above: Float) -> Float {
+    let width = if wavelength < peak { below } else { above };
+    let t = (wavelength - peak) / width;
+    (-0.5 * t * t).exp()
+}
+
+/*
+CIE 1931 2-degree colour matching functions at a wavelength in nanometres,
+using the multi-lobe fit of Wyman, Sloan and Shirley (2013).
+ */
+pub(crate) fn colour_matching(wavelength: Float) -> FVec {
+    let l = wavelength;
+    FVec::new(
+        1.056 * lobe(l, 599.8, 37.9, 31.0) + 0.362 * lobe(l, 442.0, 16.0, 26.7)
+            - 0.065 * lobe(l, 501.1, 20.4, 26.2),
+        0.821 * lobe(l, 568.8, 46.9, 40.5) + 0.286 * lobe(l, 530.9, 16.3, 31.1),
+        1.217 * lobe(l, 437.0, 11.8, 36.0) + 0.681 * lobe(l, 459.0, 26.0, 13.8),
+    )
+}
+
+// Planck's law at a wavelength in nanometres, up to a constant factor
+fn planck(wavelength: Float, kelvin: Float) -> Float {
+    let metres = wavelength * 1e-9;
+    1.0 / (metres.powi(5) * ((C2 / (metres * kelvin)).exp() - 1.0))
+}
+
+/*
+Colour of a black body glowing at the given temperature in kelvin, in linear
+sRGB with a luminance of 1 so it tints without changing brightness. Around
+1900 K is candlelight, 2700 K an incandescent bulb and 6500 K daylight.
+Channels outside the sRGB gamut, such as blue at low temperatures, are
+clipped to 0, and temperatures below a dull red glow are raised to it.
+ */
+pub fn blackbody(kelvin: Float) -> FVec {
+    let kelvin = kelvin.max(MIN_KELVIN);
+    let steps = ((LAST_WAVELENGTH - FIRST_WAVELENGTH) / WAVELENGTH_STEP) as u32;
+    let xyz: FVec = (0..=steps)
+        .map(|i| FIRST_WAVELENGTH + i as Float * WAVELENGTH_STEP)
+        .map(|wavelength| colour_matching(wavelength) * planck(wavelength, kelvin))
+        .sum();
+    xyz_to_srgb(&(xyz / xyz.y)).map(|c| c.max(0.0))
+}
+
+// Linear sRGB of a CIE XYZ colour, negative outside the sRGB gamut
+pub(crate) fn xyz_to_srgb(xyz: &FVec) -> FVec {
+    #[rustfmt::skip]
+    let matrix = Matrix3::new(
+        3.2406, -1.5372, -0.4986,
+        -0.9689, 1.8758, 0.0415,
+        0.0557, -0.2040, 1.0570,
+    );
+    matrix * xyz
+}
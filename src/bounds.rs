@@ -0,0 +1,175 @@
+use crate::core::ray::{gamma, Ray};
+use crate::{FVec, Float};
+
+// Axis-aligned bounding box
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: FVec,
+    pub max: FVec,
+}
+
+impl Aabb {
+    // Smallest box containing all the points, or None if there are none
+    pub fn around(points: impl IntoIterator<Item = FVec>) -> Option<Aabb> {
+        points.into_iter().fold(None, |aabb, point| {
+            Some(match aabb {
+                Some(Aabb { min, max }) => Aabb {
+                    min: min.inf(&point),
+                    max: max.sup(&point),
+                },
+                None => Aabb {
+                    min: point,
+                    max: point,
+                },
+            })
+        })
+    }
+
+    // Box covering every position of this one as it moves along the offset
+    pub fn swept(&self, offset: &FVec) -> Aabb {
+        Aabb {
+            min: self.min.inf(&(self.min + offset)),
+            max: self.max.sup(&(self.max + offset)),
+        }
+    }
+
+    pub fn surface_area(&self) -> Float {
+        let size = self.max - self.min;
+        2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+    }
+
+    /*
+    Whether the ray passes through the box between the two distances. The
+    exit distances are widened slightly so rounding error cannot make a ray
+    grazing a face miss the box.
+     */
+    pub fn hit_by(&self, ray: &Ray, min_distance: Float, max_distance: Float) -> bool {
+        self.entry_exit(ray, min_distance, max_distance).is_some()
+    }
+
+    // Distances along the ray at which it enters and leaves the box, within the two given
+    pub fn entry_exit(
+        &self,
+        ray: &Ray,
+        min_distance: Float,
+        max_distance: Float,
+    ) -> Option<(Float, Float)> {
+        let inverse = ray.direction.map(|d| 1.0 / d);
+        self.entry_exit_inverse(&ray.origin, &inverse, min_distance, max_distance)
+    }
+
+    // As hit_by, for a ray whose direction's reciprocals are worked out once for many boxes
+    pub fn hit_by_inverse(
+        &self,
+        origin: &FVec,
+        inverse: &FVec,
+        min_distance: Float,
+        max_distance: Float,
+    ) -> bool {
+        self.entry_exit_inverse(origin, inverse, min_distance, max_distance)
+            .is_some()
+    }
+
+    fn entry_exit_inverse(
+        &self,
+        origin: &FVec,
+        inverse: &FVec,
+        min_distance: Float,
+        max_distance: Float,
+    ) -> Option<(Float, Float)> {
+        let (mut near, mut far) = (min_distance, max_distance);
+        for axis in 0..3 {
+            let inverse = inverse[axis];
+            let t0 = (self.min[axis] - origin[axis]) * inverse;
+            let t1 = (self.max[axis] - origin[axis]) * inverse;
+            let (t0, t1) = if t0 > t1 { (t1, t0) } else { (t0, t1) };
+            near = near.max(t0);
+            far = far.min(t1 * (1.0 + 2.0 * gamma(3)));
+            if near > far {
+                return None;
+            }
+        }
+        Some((near, far))
+    }
+
+    // Corner furthest along the given direction
+    fn positive_vertex(&self, direction: &FVec) -> FVec {
+        FVec::new(
+            if direction.x >= 0.0 {
+                self.max.x
+            } else {
+                self.min.x
+            },
+            if direction.y >= 0.0 {
+                self.max.y
+            } else {
+                self.min.y
+            },
+            if direction.z >= 0.0 {
+                self.max.z
+            } else {
+                self.min.z
+            },
+        )
+    }
+
+    // The twelve edges of the box as pairs of corners
+    pub fn edges(&self) -> Vec<(FVec, FVec)> {
+        let corner = |i: usize| {
+            FVec::new(
+                if i & 1 == 0 { self.min.x } else { self.max.x },
+                if i & 2 == 0 { self.min.y } else { self.max.y },
+                if i & 4 == 0 { self.min.z } else { self.max.z },
+            )
+        };
+        // Corners differing in exactly one coordinate share an edge
+        (0..8)
+            .flat_map(|i| [1, 2, 4].map(|axis| (i, i | axis)))
+            .filter(|(i, j)| i != j)
+            .map(|(i, j)| (corner(i), corner(j)))
+            .collect()
+    }
+}
+
+// Half-space of points p with normal.dot(p) >= offset
+#[derive(Debug, Clone, Copy)]
+pub struct HalfSpace {
+    pub normal: FVec,
+    pub offset: Float,
+}
+
+// Convex region bounded by half-spaces, e.g. a camera's view volume
+#[derive(Debug, Clone)]
+pub struct Frustum {
+    pub planes: Vec<HalfSpace>,
+}
+
+impl Frustum {
+    // Conservative test: may report boxes just outside a corner as visible
+    pub fn may_contain(&self, aabb: &Aabb) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| plane.normal.dot(&aabb.positive_vertex(&plane.normal)) >= plane.offset)
+    }
+
+    // Pyramid from the apex through four corner directions given in winding order
+    pub fn from_corners(apex: &FVec, corners: &[FVec; 4], forward: &FVec) -> Frustum {
+        let mut planes: Vec<HalfSpace> = (0..4)
+            .map(|i| {
+                let mut normal = corners[i].cross(&corners[(i + 1) % 4]).normalize();
+                if normal.dot(forward) < 0.0 {
+                    normal = -normal;
+                }
+                HalfSpace {
+                    normal,
+                    offset: normal.dot(apex),
+                }
+            })
+            .collect();
+        planes.push(HalfSpace {
+            normal: *forward,
+            offset: forward.dot(apex),
+        });
+        Frustum { planes }
+    }
+}
@@ -0,0 +1,477 @@
+use crate::bounds::Aabb;
+use crate::core::ray::Ray;
+use crate::stats::{self, Counter};
+use crate::{FVec, Float};
+
+// Most primitives kept together in a leaf before it is split
+const MAX_LEAF_SIZE: usize = 4;
+
+// Cost of visiting a node relative to testing a primitive, for the surface area heuristic
+const TRAVERSAL_COST: Float = 1.0;
+
+// How far a refitted tree's cost may rise over the cost it was built with before it is rebuilt
+const REBUILD_DRIFT: Float = 0.3;
+
+// Deepest a tree over any realistic number of primitives gets when split at the median
+const MAX_DEPTH: usize = 64;
+
+// Most rays traced together by traverse_packet, one per bit of the mask of active rays
+pub const PACKET_RAYS: usize = 64;
+
+/*
+Bounding volume hierarchy over a list of boxes. Each node's children split
+its primitives in half along the axis their centres are most spread over,
+so a ray only visits the primitives whose boxes it passes through, in
+roughly front-to-back order.
+ */
+#[derive(Debug, Default, Clone)]
+pub struct Bvh {
+    nodes: Vec<Node>,
+    // Indices of the primitives, ordered so every leaf holds a contiguous run
+    order: Vec<usize>,
+    // Expected cost of a ray, by the surface area heuristic, when the tree was last built
+    built_cost: Float,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    bounds: Aabb,
+    kind: NodeKind,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum NodeKind {
+    Leaf { start: usize, count: usize },
+    // The first child directly follows its parent
+    Interior { second: usize, axis: usize },
+}
+
+impl Bvh {
+    pub fn build(bounds: &[Aabb]) -> Bvh {
+        let mut bvh = Bvh {
+            nodes: Vec::new(),
+            order: (0..bounds.len()).collect(),
+            built_cost: 0.0,
+        };
+        if !bounds.is_empty() {
+            bvh.build_node(bounds, 0, bounds.len());
+        }
+        bvh.built_cost = bvh.cost();
+        bvh
+    }
+
+    /*
+    Tree over the same primitives after their boxes have moved, such as in the
+    next frame of an animation. Refitting the boxes of this tree is quicker
+    than building one, but a tree whose primitives have moved far apart from
+    their neighbours in it gets slower to trace, so it is rebuilt once its
+    cost has drifted too far over the cost it was built with.
+     */
+    pub fn rebuild(&self, bounds: &[Aabb]) -> Bvh {
+        if self.order.len() != bounds.len() {
+            return Bvh::build(bounds);
+        }
+        let mut refitted = self.clone();
+        // Children come after their parents, so going backwards fits them first
+        for index in (0..refitted.nodes.len()).rev() {
+            let fitted = match refitted.nodes[index].kind {
+                NodeKind::Leaf { start, count } => {
+                    let items = &refitted.order[start..start + count];
+                    Aabb::around(items.iter().flat_map(|&i| [bounds[i].min, bounds[i].max]))
+                }
+                NodeKind::Interior { second, .. } => {
+                    let children = [refitted.nodes[index + 1], refitted.nodes[second]];
+                    Aabb::around(children.iter().flat_map(|n| [n.bounds.min, n.bounds.max]))
+                }
+            };
+            refitted.nodes[index].bounds = fitted.expect("nodes are never empty");
+        }
+        let drift = refitted.cost() / self.built_cost.max(Float::MIN_POSITIVE);
+        let primitives = bounds.len();
+        if drift <= 1.0 + REBUILD_DRIFT {
+            debug!("Refitted the hierarchy over {primitives} primitives, {drift:.2}x its cost");
+            return refitted;
+        }
+        debug!("Rebuilt the hierarchy over {primitives} primitives, refitted {drift:.2}x its cost");
+        Bvh::build(bounds)
+    }
+
+    /*
+    Expected cost of tracing a ray through the tree by the surface area
+    heuristic: each node is paid for in proportion to the chance a ray
+    through the root passes through its box.
+     */
+    fn cost(&self) -> Float {
+        let Some(root) = self.nodes.first() else {
+            return 0.0;
+        };
+        let root_area = root.bounds.surface_area();
+        if root_area <= 0.0 {
+            return self.order.len() as Float;
+        }
+        let costs = self.nodes.iter().map(|node| {
+            let chance = node.bounds.surface_area() / root_area;
+            match node.kind {
+                NodeKind::Leaf { count, .. } => chance * count as Float,
+                NodeKind::Interior { .. } => chance * TRAVERSAL_COST,
+            }
+        });
+        costs.sum()
+    }
+
+    /*
+    Boxes of the nodes down to the given number of levels below the root,
+    each with its depth, the root's being 0.
+     */
+    pub fn boxes(&self, levels: usize) -> Vec<(usize, Aabb)> {
+        let mut boxes = Vec::new();
+        let mut stack = vec![(0, 0)];
+        while let Some((index, depth)) = stack.pop() {
+            let Some(node) = self.nodes.get(index).filter(|_| depth <= levels) else {
+                continue;
+            };
+            boxes.push((depth, node.bounds));
+            if let NodeKind::Interior { second, .. } = node.kind {
+                stack.extend([(index + 1, depth + 1), (second, depth + 1)]);
+            }
+        }
+        boxes
+    }
+
+    // Memory taken by the nodes and the primitive order
+    pub fn bytes(&self) -> usize {
+        std::mem::size_of_val(self.nodes.as_slice()) + std::mem::size_of_val(self.order.as_slice())
+    }
+
+    fn build_node(&mut self, bounds: &[Aabb], start: usize, end: usize) {
+        let items = &mut self.order[start..end];
+        let node_bounds = Aabb::around(items.iter().flat_map(|&i| [bounds[i].min, bounds[i].max]))
+            .expect("nodes are never empty");
+        let mut node = Node {
+            bounds: node_bounds,
+            kind: NodeKind::Leaf {
+                start,
+                count: items.len(),
+            },
+        };
+        if items.len() <= MAX_LEAF_SIZE {
+            self.nodes.push(node);
+            return;
+        }
+        let centre = |i: usize| (bounds[i].min + bounds[i].max) * 0.5;
+        let centres =
+            Aabb::around(items.iter().map(|&i| centre(i))).expect("nodes are never empty");
+        let axis = (centres.max - centres.min).imax();
+        let middle = items.len() / 2;
+        items.select_nth_unstable_by(middle, |&a, &b| centre(a)[axis].total_cmp(&centre(b)[axis]));
+        let index = self.nodes.len();
+        self.nodes.push(node);
+        self.build_node(bounds, start, start + middle);
+        let second = self.nodes.len();
+        self.build_node(bounds, start + middle, end);
+        node.kind = NodeKind::Interior { second, axis };
+        self.nodes[index] = node;
+    }
+
+    /*
+    Call visit with the index of every primitive whose box the ray may pass
+    through between the two distances, nearer subtrees first. Visit returns
+    the distance beyond which nothing more is wanted, such as that of the
+    nearest hit so far; a distance below min_distance ends the search.
+     */
+    pub fn traverse(
+        &self,
+        ray: &Ray,
+        min_distance: Float,
+        mut max_distance: Float,
+        mut visit: impl FnMut(usize) -> Float,
+    ) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let mut stack = [0; MAX_DEPTH];
+        let mut depth = 1;
+        let mut visited = 0;
+        while depth > 0 {
+            depth -= 1;
+            let index = stack[depth];
+            let node = &self.nodes[index];
+            visited += 1;
+            if !node.bounds.hit_by(ray, min_distance, max_distance) {
+                continue;
+            }
+            match node.kind {
+                NodeKind::Leaf { start, count } => {
+                    for &primitive in &self.order[start..start + count] {
+                        max_distance = max_distance.min(visit(primitive));
+                        if max_distance < min_distance {
+                            stats::add(Counter::BvhNodes, visited);
+                            return;
+                        }
+                    }
+                }
+                NodeKind::Interior { second, axis } => {
+                    // The second child holds the primitives further along the axis
+                    let (near, far) = if ray.direction[axis] < 0.0 {
+                        (second, index + 1)
+                    } else {
+                        (index + 1, second)
+                    };
+                    stack[depth] = far;
+                    stack[depth + 1] = near;
+                    depth += 2;
+                }
+            }
+        }
+        stats::add(Counter::BvhNodes, visited);
+    }
+
+    /*
+    Traverse the tree for up to PACKET_RAYS rays at once, as traverse does
+    for one. A node is visited while any ray still active there passes
+    through its box, and only the rays that do are carried down to its
+    children, so coherent rays such as those of neighbouring pixels share
+    one walk down the tree. Visit is called with a primitive and the index
+    of a ray in the packet and returns that ray's new furthest distance, kept
+    in max_distances; a ray whose distance falls below min_distance drops
+    out of the packet.
+     */
+    pub fn traverse_packet(
+        &self,
+        rays: &[&Ray],
+        min_distance: Float,
+        max_distances: &mut [Float],
+        mut visit: impl FnMut(usize, usize) -> Float,
+    ) {
+        debug_assert!(rays.len() <= PACKET_RAYS && rays.len() == max_distances.len());
+        if self.nodes.is_empty() || rays.is_empty() {
+            return;
+        }
+        let inverses: Vec<FVec> = rays
+            .iter()
+            .map(|ray| ray.direction.map(|d| 1.0 / d))
+            .collect();
+        let mut finished = 0u64;
+        let all = u64::MAX >> (PACKET_RAYS - rays.len());
+        let mut stack = [(0, 0u64); MAX_DEPTH];
+        stack[0] = (0, all);
+        let mut depth = 1;
+        let mut visited = 0;
+        while depth > 0 {
+            depth -= 1;
+            let (index, mask) = stack[depth];
+            let node = &self.nodes[index];
+            let mut active = 0u64;
+            for ray in set_bits(mask & !finished) {
+                visited += 1;
+                let (origin, inverse) = (&rays[ray].origin, &inverses[ray]);
+                if node
+                    .bounds
+                    .hit_by_inverse(origin, inverse, min_distance, max_distances[ray])
+                {
+                    active |= 1 << ray;
+                }
+            }
+            if active == 0 {
+                continue;
+            }
+            match node.kind {
+                NodeKind::Leaf { start, count } => {
+                    for &primitive in &self.order[start..start + count] {
+                        for ray in set_bits(active & !finished) {
+                            let distance = max_distances[ray].min(visit(primitive, ray));
+                            max_distances[ray] = distance;
+                            if distance < min_distance {
+                                finished |= 1 << ray;
+                            }
+                        }
+                    }
+                    if finished == all {
+                        break;
+                    }
+                }
+                NodeKind::Interior { second, axis } => {
+                    // Ordered for the first active ray; the others mostly agree
+                    let first = active.trailing_zeros() as usize;
+                    let (near, far) = if rays[first].direction[axis] < 0.0 {
+                        (second, index + 1)
+                    } else {
+                        (index + 1, second)
+                    };
+                    stack[depth] = (far, active);
+                    stack[depth + 1] = (near, active);
+                    depth += 2;
+                }
+            }
+        }
+        stats::add(Counter::BvhNodes, visited);
+    }
+}
+
+// Indices of the set bits of the mask, lowest first
+fn set_bits(mut mask: u64) -> impl Iterator<Item = usize> {
+    std::iter::from_fn(move || {
+        if mask == 0 {
+            return None;
+        }
+        let bit = mask.trailing_zeros() as usize;
+        mask &= mask - 1;
+        Some(bit)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RADIUS: Float = 0.4;
+
+    // A grid of spheres, five across and four up, in the plane z = 5
+    fn centres() -> Vec<FVec> {
+        (0..20)
+            .map(|i| FVec::new((i % 5) as Float, (i / 5) as Float, 5.0))
+            .collect()
+    }
+
+    fn tree(centres: &[FVec]) -> Bvh {
+        let bounds: Vec<Aabb> = centres
+            .iter()
+            .map(|centre| Aabb {
+                min: centre - FVec::repeat(RADIUS),
+                max: centre + FVec::repeat(RADIUS),
+            })
+            .collect();
+        Bvh::build(&bounds)
+    }
+
+    // Distance along the ray to the sphere about the centre, or infinity if it misses
+    fn sphere_distance(ray: &Ray, centre: &FVec) -> Float {
+        let to_origin = ray.origin - centre;
+        let a = ray.direction.norm_squared();
+        let b = 2.0 * ray.direction.dot(&to_origin);
+        let c = to_origin.norm_squared() - RADIUS * RADIUS;
+        let discriminant = b * b - 4.0 * a * c;
+        if discriminant < 0.0 {
+            return Float::INFINITY;
+        }
+        let t = (-b - discriminant.sqrt()) / (2.0 * a);
+        if t > 0.0 {
+            t
+        } else {
+            Float::INFINITY
+        }
+    }
+
+    /*
+    Rays from in front of the grid, in turn at the centre of a sphere, at the
+    gap between four of them, and away from the grid, so a packet mixes hits
+    and misses.
+     */
+    fn rays(count: usize) -> Vec<Ray> {
+        let origin = FVec::new(2.0, 1.5, 0.0);
+        (0..count)
+            .map(|k| {
+                let cell = FVec::new((k % 5) as Float, ((k / 5) % 4) as Float, 5.0);
+                let direction = match k % 3 {
+                    0 => cell - origin,
+                    1 => cell + FVec::new(0.5, 0.5, 0.0) - origin,
+                    _ => origin - cell,
+                };
+                Ray {
+                    origin,
+                    direction,
+                    differential: None,
+                    time: 0.0,
+                }
+            })
+            .collect()
+    }
+
+    // Nearest sphere and its distance along the ray, found by traversing for it alone
+    fn nearest_alone(bvh: &Bvh, centres: &[FVec], ray: &Ray) -> Option<(usize, Float)> {
+        let mut nearest = None;
+        bvh.traverse(ray, 0.0, Float::INFINITY, |primitive| {
+            let distance = sphere_distance(ray, &centres[primitive]);
+            if distance < nearest.map_or(Float::INFINITY, |(_, d)| d) {
+                nearest = Some((primitive, distance));
+            }
+            nearest.map_or(Float::INFINITY, |(_, d)| d)
+        });
+        nearest
+    }
+
+    #[test]
+    fn packets_find_the_hits_of_single_rays() {
+        let centres = centres();
+        let bvh = tree(&centres);
+        // A short packet, a full one, and one with a ray to spare
+        for count in [1, 7, PACKET_RAYS] {
+            let rays = rays(count);
+            let packet: Vec<&Ray> = rays.iter().collect();
+            let mut nearest = vec![None; count];
+            let mut max_distances = vec![Float::INFINITY; count];
+            bvh.traverse_packet(&packet, 0.0, &mut max_distances, |primitive, ray| {
+                let distance = sphere_distance(&rays[ray], &centres[primitive]);
+                let best: &mut Option<(usize, Float)> = &mut nearest[ray];
+                if distance < best.map_or(Float::INFINITY, |(_, d)| d) {
+                    *best = Some((primitive, distance));
+                }
+                best.map_or(Float::INFINITY, |(_, d)| d)
+            });
+            let mut hits = 0;
+            for (ray, found) in rays.iter().zip(&nearest) {
+                assert_eq!(*found, nearest_alone(&bvh, &centres, ray), "{ray:?}");
+                hits += found.is_some() as usize;
+            }
+            if count > 1 {
+                assert!(hits > 0 && hits < count, "{hits} of {count} rays hit");
+            }
+        }
+        let rays = rays(PACKET_RAYS - 1);
+        let packet: Vec<&Ray> = rays.iter().collect();
+        let mut max_distances = vec![Float::INFINITY; rays.len()];
+        bvh.traverse_packet(&packet, 0.0, &mut max_distances, |_, ray| {
+            assert!(ray < rays.len(), "visited ray {ray} outside the packet");
+            Float::INFINITY
+        });
+    }
+
+    #[test]
+    fn rays_leave_the_packet_once_they_stop() {
+        let centres = centres();
+        let bvh = tree(&centres);
+        let rays = rays(PACKET_RAYS);
+        let packet: Vec<&Ray> = rays.iter().collect();
+        let mut max_distances = vec![Float::INFINITY; rays.len()];
+        let mut stopped = vec![false; rays.len()];
+        // A distance below the least one stops a ray, as for shadow rays blocked by any hit
+        bvh.traverse_packet(&packet, 0.0, &mut max_distances, |primitive, ray| {
+            assert!(!stopped[ray], "ray {ray} visited after it stopped");
+            if sphere_distance(&rays[ray], &centres[primitive]).is_finite() {
+                stopped[ray] = true;
+                return -1.0;
+            }
+            Float::INFINITY
+        });
+        for (ray, stopped) in rays.iter().zip(stopped) {
+            assert_eq!(
+                stopped,
+                nearest_alone(&bvh, &centres, ray).is_some(),
+                "{ray:?}"
+            );
+        }
+
+        // Once every ray has stopped nothing more is visited
+        let hitting: Vec<&Ray> = rays
+            .iter()
+            .filter(|ray| nearest_alone(&bvh, &centres, ray).is_some())
+            .collect();
+        let mut max_distances = vec![Float::INFINITY; hitting.len()];
+        let mut visits = 0;
+        bvh.traverse_packet(&hitting, 0.0, &mut max_distances, |_, _| {
+            visits += 1;
+            -1.0
+        });
+        assert_eq!(visits, hitting.len());
+    }
+}
@@ -0,0 +1,674 @@
+use crate::adaptive::{self, Adaptive};
+use crate::animation::{interpolate, Keyframe};
+use crate::bounds::Frustum;
+use crate::core::consts::PI;
+use crate::core::ray::{Ray, RayDifferential};
+use crate::filter::PixelFilter;
+use crate::importance::Importance;
+use crate::light::coordinate_system;
+use crate::region::Region;
+use crate::sampling::{self, BlueNoiseMask};
+use crate::{FVec, Float, UP};
+use nalgebra::Matrix3;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Camera {
+    pub name: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub position: FVec,
+    // Where the camera looks; worked out from lookAt when that is given instead
+    #[serde(default)]
+    pub direction: FVec,
+    // Point the camera looks at, following it as the camera moves
+    pub look_at: Option<FVec>,
+    // Direction up the image, the scene's up by default; it need not be square to the view
+    pub up: Option<FVec>,
+    // Degrees the camera is turned clockwise about its view direction, as seen from behind
+    #[serde(default)]
+    pub roll: Float,
+    pub screen_distance: Float,
+    pub screen_width: Float,
+    pub screen_height: Float,
+    pub screen_columns: u32,
+    pub screen_rows: u32,
+    // Rays per pixel, jittered inside it and averaged by the pixel filter
+    #[serde(default = "default_samples", alias = "samplesPerPixel")]
+    pub samples: u32,
+    #[serde(default)]
+    pub(crate) filter: PixelFilter,
+    // Diameter of the lens in metres; 0 for a pinhole that keeps everything in focus
+    #[serde(default)]
+    pub aperture: Float,
+    // Distance in metres along the view direction that is in focus, the screen's by default
+    pub focal_distance: Option<Float>,
+    // Extra pixels rendered on every side of the frame, kept only in EXR outputs
+    #[serde(default)]
+    pub overscan: u32,
+    // Fraction of each frame the shutter stays open for, blurring moving objects; 0 freezes them
+    #[serde(default)]
+    pub shutter: Float,
+    // How directions from the camera are laid out on the film
+    #[serde(default)]
+    pub projection: Projection,
+    pub(crate) animation: Option<CameraAnimation>,
+    // Shares the samples out unevenly between pixels when present
+    pub(crate) importance: Option<Importance>,
+    // Keeps adding samples to noisy pixels when present
+    pub(crate) adaptive: Option<Adaptive>,
+    #[serde(skip)]
+    pub(crate) screen: ScreenMapping,
+    // Importance of every film pixel; empty when all pixels are sampled alike
+    #[serde(skip)]
+    pub(crate) importance_map: Vec<Float>,
+    // The same camera one frame later when it is animated, for motion vectors
+    #[serde(skip)]
+    pub(crate) next_frame: Option<Box<Camera>>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CameraAnimation {
+    #[serde(default)]
+    pub(crate) position: Vec<Keyframe<FVec>>,
+    #[serde(default)]
+    pub(crate) direction: Vec<Keyframe<FVec>>,
+}
+
+pub fn default_samples() -> u32 {
+    1
+}
+
+/*
+Mapping of the directions seen from the camera onto the film. Perspective
+projects them onto the screen in front of the camera. Orthographic sends
+parallel rays along the view direction from across the screen, moved back to
+the camera, so things keep their size however far away they are; the screen's
+width and height are the size of the area seen. Stereographic maps the angle
+from the view direction to distance from the film centre, squeezing the whole
+surroundings but the point straight behind into the frame; a camera looking
+straight down with a wide field of view turns the ground of a panorama into a
+"little planet". Fisheye does the same with distance proportional to the
+angle, as most fisheye lenses do. Equirectangular lays out every direction
+with longitude across the frame and latitude up it, for 360° panoramas in
+frames twice as wide as they are high; keep the camera's direction level for
+a level horizon. Only perspective uses the screen's distance, and only it and
+orthographic its size. All but perspective ignore the aperture.
+ */
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum Projection {
+    #[default]
+    Perspective,
+    Orthographic,
+    #[serde(rename_all = "camelCase")]
+    Stereographic {
+        // Angle in degrees seen from the top to the bottom of the frame, up to 360
+        #[serde(default = "default_stereographic_fov")]
+        field_of_view: Float,
+    },
+    #[serde(rename_all = "camelCase")]
+    Fisheye {
+        // Angle in degrees seen from the top to the bottom of the frame, up to 360
+        #[serde(default = "default_fisheye_fov")]
+        field_of_view: Float,
+    },
+    Equirectangular,
+}
+
+fn default_stereographic_fov() -> Float {
+    270.0
+}
+
+fn default_fisheye_fov() -> Float {
+    180.0
+}
+
+/*
+Distortion of OpenCV's fisheye model for a stereographic lens: the Taylor
+series of 2 tan(θ/2) / θ in θ², good to a fraction of a pixel well short of
+straight to the side.
+ */
+const STEREOGRAPHIC_SERIES: [Float; 4] = [1.0 / 12.0, 1.0 / 120.0, 17.0 / 20160.0, 31.0 / 362880.0];
+
+// Distance from the film centre, for a unit focal length, of directions half the view away
+fn stereographic_radius(field_of_view: Float) -> Float {
+    2.0 * (field_of_view.clamp(1.0, 359.0).to_radians() * 0.25).tan()
+}
+
+impl Projection {
+    /*
+    Angle from the view direction of the directions at a distance from the
+    film centre, in half frame heights, for projections laid out around the
+    centre by that angle. Beyond straight behind the camera is straight behind.
+     */
+    fn angle_at(self, distance: Float) -> Float {
+        match self {
+            Projection::Stereographic { field_of_view } => {
+                2.0 * (distance * stereographic_radius(field_of_view) * 0.5).atan()
+            }
+            Projection::Fisheye { field_of_view } => {
+                (distance * field_of_view.clamp(1.0, 360.0).to_radians() * 0.5).min(PI)
+            }
+            _ => unreachable!("not laid out around the film centre"),
+        }
+    }
+
+    // Inverse of angle_at
+    fn distance_at(self, angle: Float) -> Float {
+        match self {
+            Projection::Stereographic { field_of_view } => {
+                2.0 * (angle * 0.5).tan() / stereographic_radius(field_of_view)
+            }
+            Projection::Fisheye { field_of_view } => {
+                angle / (field_of_view.clamp(1.0, 360.0).to_radians() * 0.5)
+            }
+            _ => unreachable!("not laid out around the film centre"),
+        }
+    }
+}
+
+/*
+Unnormalized ray direction through pixel (0, 0) and its change per pixel in x
+and y. Directions are linear in pixel coordinates, so these three vectors are
+all a ray needs.
+ */
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ScreenMapping {
+    pub(crate) origin: FVec,
+    pub(crate) step_x: FVec,
+    pub(crate) step_y: FVec,
+}
+
+impl Camera {
+    /*
+    A camera at the position looking along the direction, with a screen one
+    metre in front of it and one metre high, divided into the given number of
+    pixels. One sample per pixel.
+     */
+    pub fn new(position: FVec, direction: FVec, screen_columns: u32, screen_rows: u32) -> Camera {
+        let mut camera = Camera {
+            name: None,
+            tags: Vec::new(),
+            position,
+            direction,
+            look_at: None,
+            up: None,
+            roll: 0.0,
+            screen_distance: 1.0,
+            screen_width: screen_columns as Float / screen_rows as Float,
+            screen_height: 1.0,
+            screen_columns,
+            screen_rows,
+            samples: default_samples(),
+            filter: PixelFilter::default(),
+            aperture: 0.0,
+            focal_distance: None,
+            overscan: 0,
+            shutter: 0.0,
+            projection: Projection::default(),
+            animation: None,
+            importance: None,
+            adaptive: None,
+            screen: ScreenMapping::default(),
+            importance_map: Vec::new(),
+            next_frame: None,
+        };
+        camera.prepare();
+        camera
+    }
+
+    // How the camera is referred to in messages
+    pub fn describe(&self) -> String {
+        match &self.name {
+            Some(name) => format!("camera {name:?}"),
+            None => "camera".to_string(),
+        }
+    }
+
+    /*
+    Move the camera to its keyframed position and direction at the given
+    frame, then turn it to its look-at point if it has one.
+     */
+    pub(crate) fn apply_frame(&mut self, frame: Float) {
+        if let Some(animation) = &self.animation {
+            if let Some(position) = interpolate(&animation.position, frame) {
+                self.position = position;
+            }
+            if let Some(direction) = interpolate(&animation.direction, frame) {
+                self.direction = direction;
+            }
+        }
+        if let Some(target) = self.look_at {
+            self.direction = target - self.position;
+        }
+    }
+
+    /*
+    Unit vectors along the view direction, to the right of the image and up
+    it. Looking straight along the up direction leaves the image's up
+    undecided, so a fixed direction square to the view is used instead of
+    breaking down.
+     */
+    pub(crate) fn get_basis_vectors(&self) -> (FVec, FVec, FVec) {
+        let u = self.direction.normalize();
+        let up = self.up.unwrap_or(UP);
+        let v = match u.cross(&up).try_normalize(1e-9) {
+            Some(v) => v,
+            None => coordinate_system(&u).0,
+        };
+        let w = v.cross(&u);
+        let (sin, cos) = self.roll.to_radians().sin_cos();
+        (u, v * cos - w * sin, w * cos + v * sin)
+    }
+
+    // Precompute the screen mapping; must be called before generating rays
+    pub(crate) fn prepare(&mut self) {
+        let (u, v, w) = self.get_basis_vectors();
+        // Center of screen is origin
+        let step_x = v * (self.screen_width * 0.5 / self.screen_columns as Float);
+        let step_y = w * (self.screen_height * -0.5 / self.screen_rows as Float);
+        // Film pixel (0, 0) is the top-left corner of the overscan margin
+        let overscan = self.overscan as Float;
+        self.screen = ScreenMapping {
+            origin: self.screen_distance * u
+                - ((self.screen_columns / 2) as Float + overscan) * step_x
+                - ((self.screen_rows / 2) as Float + overscan) * step_y,
+            step_x,
+            step_y,
+        };
+        if let Some(next) = &mut self.next_frame {
+            next.prepare();
+        }
+    }
+
+    // Turned into another set of axes by a matrix that keeps lengths
+    pub(crate) fn reorient(&mut self, axes: &Matrix3<Float>) {
+        self.position = axes * self.position;
+        self.direction = axes * self.direction;
+        self.look_at = self.look_at.map(|target| axes * target);
+        self.up = self.up.map(|up| axes * up);
+        if let Some(next) = &mut self.next_frame {
+            next.reorient(axes);
+        }
+    }
+
+    pub(crate) fn scale(&mut self, factor: Float) {
+        self.position *= factor;
+        self.look_at = self.look_at.map(|target| target * factor);
+        self.screen_distance *= factor;
+        self.screen_width *= factor;
+        self.screen_height *= factor;
+        self.aperture *= factor;
+        self.focal_distance = self.focal_distance.map(|distance| distance * factor);
+        if let Some(next) = &mut self.next_frame {
+            next.scale(factor);
+        }
+    }
+
+    /*
+    How far in film pixels a point in the scene moves across the screen by
+    the next frame, with x to the right and y down. Objects do not move, so
+    only the camera's own motion counts.
+     */
+    pub(crate) fn motion(&self, p: &FVec) -> (Float, Float) {
+        let Some(next) = &self.next_frame else {
+            return (0.0, 0.0);
+        };
+        let (x, y) = self.project(p);
+        let (next_x, next_y) = next.project(p);
+        (next_x - x, next_y - y)
+    }
+
+    // Size in pixels of the rendered film, including the overscan margin
+    pub fn film_columns(&self) -> u32 {
+        self.screen_columns + 2 * self.overscan
+    }
+
+    pub fn film_rows(&self) -> u32 {
+        self.screen_rows + 2 * self.overscan
+    }
+
+    pub fn film_region(&self) -> Region {
+        Region::new(0, 0, self.film_columns(), self.film_rows())
+    }
+
+    /*
+    Ray through the point (x, y) in film pixel coordinates; fractional coordinates
+    address positions inside a pixel.
+     */
+    pub(crate) fn get_ray(&self, x: Float, y: Float) -> Ray {
+        let screen = &self.screen;
+        let mut origin = self.position;
+        let direction = match self.projection {
+            Projection::Perspective => screen.origin + x * screen.step_x + y * screen.step_y,
+            Projection::Orthographic => {
+                let (cx, cy) = self.film_centre();
+                origin += (x - cx) * screen.step_x + (y - cy) * screen.step_y;
+                self.direction
+            }
+            projection @ (Projection::Stereographic { .. } | Projection::Fisheye { .. }) => {
+                let (forward, right, up) = self.get_basis_vectors();
+                let (cx, cy) = self.film_centre();
+                let half_height = self.screen_rows as Float * 0.5;
+                let (dx, dy) = ((x - cx) / half_height, (y - cy) / half_height);
+                let radius = dx.hypot(dy);
+                // The angle from the view direction grows with the distance from the centre
+                let angle = projection.angle_at(radius);
+                let across = if radius > 0.0 {
+                    (right * dx - up * dy) / radius
+                } else {
+                    FVec::zeros()
+                };
+                forward * angle.cos() + across * angle.sin()
+            }
+            Projection::Equirectangular => {
+                let (forward, right, up) = self.get_basis_vectors();
+                let (cx, cy) = self.film_centre();
+                let longitude = (x - cx) / self.screen_columns as Float * 2.0 * PI;
+                let latitude = (cy - y) / self.screen_rows as Float * PI;
+                let level = forward * longitude.cos() + right * longitude.sin();
+                level * latitude.cos() + up * latitude.sin()
+            }
+        };
+        Ray {
+            origin,
+            direction: direction.normalize(),
+            differential: None,
+            time: 0.0,
+        }
+    }
+
+    /*
+    Intrinsics, distortion and pose of the camera in OpenCV's convention, for
+    computer vision tools reading rendered frames: pixel centres at whole
+    coordinates of the frame without its overscan, and camera axes x right, y
+    down and z forward in metres. Perspective cameras are pinholes without
+    distortion; fisheye and stereographic cameras follow the cv::fisheye
+    model, which only reaches to the side of the camera. None for
+    projections OpenCV has no model of.
+     */
+    pub fn calibration(&self) -> Option<Value> {
+        let half_height = self.screen_rows as Float * 0.5;
+        let (model, fx, fy, distortion) = match self.projection {
+            Projection::Perspective => {
+                let pixel_width = self.screen_width * 0.5 / self.screen_columns as Float;
+                let pixel_height = self.screen_height * 0.5 / self.screen_rows as Float;
+                let distance = self.screen_distance;
+                (
+                    "pinhole",
+                    distance / pixel_width,
+                    distance / pixel_height,
+                    vec![0.0; 5],
+                )
+            }
+            Projection::Fisheye { field_of_view } => {
+                let focal = half_height / (field_of_view.clamp(1.0, 360.0).to_radians() * 0.5);
+                ("fisheye", focal, focal, vec![0.0; 4])
+            }
+            Projection::Stereographic { field_of_view } => {
+                let focal = half_height / stereographic_radius(field_of_view);
+                ("fisheye", focal, focal, STEREOGRAPHIC_SERIES.to_vec())
+            }
+            Projection::Orthographic | Projection::Equirectangular => return None,
+        };
+        let (cx, cy) = (
+            (self.screen_columns / 2) as Float,
+            (self.screen_rows / 2) as Float,
+        );
+        let (forward, right, up) = self.get_basis_vectors();
+        let axes = [right.transpose(), -up.transpose(), forward.transpose()];
+        let rotation = Matrix3::from_rows(&axes);
+        let translation = -(rotation * self.position);
+        let rows = |matrix: &Matrix3<Float>| {
+            (0..3)
+                .map(|r| [0, 1, 2].map(|c| matrix[(r, c)]))
+                .collect::<Vec<_>>()
+        };
+        Some(json!({
+            "model": model,
+            "imageWidth": self.screen_columns,
+            "imageHeight": self.screen_rows,
+            "cameraMatrix": [[fx, 0.0, cx], [0.0, fy, cy], [0.0, 0.0, 1.0]],
+            "distCoeffs": distortion,
+            "rotation": rows(&rotation),
+            "translation": [translation.x, translation.y, translation.z],
+        }))
+    }
+
+    // Film coordinates of the point the view direction goes through
+    fn film_centre(&self) -> (Float, Float) {
+        let overscan = self.overscan as Float;
+        (
+            (self.screen_columns / 2) as Float + overscan,
+            (self.screen_rows / 2) as Float + overscan,
+        )
+    }
+
+    /*
+    Ray through the point (x, y) in film pixel coordinates leaving from a point
+    on the lens, given by a sample in the unit square. Rays through the same
+    film point from anywhere on the lens meet at the focal distance, so only
+    objects near it are sharp. Without an aperture this is the pinhole ray.
+     */
+    pub(crate) fn get_lens_ray(&self, x: Float, y: Float, lens: (Float, Float)) -> Ray {
+        let ray = self.get_ray(x, y);
+        if self.aperture <= 0.0 || !matches!(self.projection, Projection::Perspective) {
+            return ray;
+        }
+        let (forward, right, up) = self.get_basis_vectors();
+        let focal_distance = self.focal_distance.unwrap_or(self.screen_distance);
+        let focus = ray.origin + ray.direction * (focal_distance / ray.direction.dot(&forward));
+        let (dx, dy) = sampling::concentric_disc(lens.0, lens.1);
+        let offset = right * dx + up * dy;
+        let origin = self.position + offset * (0.5 * self.aperture);
+        Ray {
+            origin,
+            direction: (focus - origin).normalize(),
+            differential: None,
+            time: ray.time,
+        }
+    }
+
+    /*
+    Volume that every primary ray lies in, padded by a pixel on each side to
+    cover jittered samples. None for a camera with an aperture, whose rays
+    leave from all over the lens instead of a single point.
+     */
+    pub(crate) fn get_frustum(&self) -> Option<Frustum> {
+        if self.aperture > 0.0 || !matches!(self.projection, Projection::Perspective) {
+            return None;
+        }
+        let (left, right) = (-1.0, self.film_columns() as Float + 1.0);
+        let (top, bottom) = (-1.0, self.film_rows() as Float + 1.0);
+        let corners = [
+            self.get_ray(left, top).direction,
+            self.get_ray(right, top).direction,
+            self.get_ray(right, bottom).direction,
+            self.get_ray(left, bottom).direction,
+        ];
+        let forward = self.direction.normalize();
+        Some(Frustum::from_corners(&self.position, &corners, &forward))
+    }
+
+    /*
+    Film pixel coordinates of a segment's end points, with the part behind the
+    screen cut off. None if the whole segment is behind it.
+     */
+    pub(crate) fn project_segment(
+        &self,
+        a: &FVec,
+        b: &FVec,
+    ) -> Option<((Float, Float), (Float, Float))> {
+        let near = match self.projection {
+            Projection::Perspective => self.screen_distance,
+            Projection::Orthographic => 0.0,
+            // These see all around, so nothing is cut off; straight edges just come out bent
+            _ => return Some((self.project(a), self.project(b))),
+        };
+        let forward = self.direction.normalize();
+        let (da, db) = (
+            forward.dot(&(a - self.position)),
+            forward.dot(&(b - self.position)),
+        );
+        if da < near && db < near {
+            return None;
+        }
+        let clip = |p: &FVec, q: &FVec, dp: Float, dq: Float| {
+            if dp >= near {
+                *p
+            } else {
+                p + (q - p) * ((near - dp) / (dq - dp))
+            }
+        };
+        let a_clipped = clip(a, b, da, db);
+        let b_clipped = clip(b, a, db, da);
+        Some((self.project(&a_clipped), self.project(&b_clipped)))
+    }
+
+    // Film pixel coordinates of a point in front of the screen; inverse of get_ray
+    pub(crate) fn project(&self, p: &FVec) -> (Float, Float) {
+        let screen = &self.screen;
+        let (cx, cy) = self.film_centre();
+        match self.projection {
+            Projection::Perspective => {
+                let d = p - self.position;
+                let depth = self.direction.normalize().dot(&d);
+                let on_screen = d * (self.screen_distance / depth) - screen.origin;
+                (
+                    on_screen.dot(&screen.step_x) / screen.step_x.norm_squared(),
+                    on_screen.dot(&screen.step_y) / screen.step_y.norm_squared(),
+                )
+            }
+            Projection::Orthographic => {
+                let d = p - self.position;
+                (
+                    cx + d.dot(&screen.step_x) / screen.step_x.norm_squared(),
+                    cy + d.dot(&screen.step_y) / screen.step_y.norm_squared(),
+                )
+            }
+            projection @ (Projection::Stereographic { .. } | Projection::Fisheye { .. }) => {
+                let (forward, right, up) = self.get_basis_vectors();
+                let d = (p - self.position).normalize();
+                let angle = d.dot(&forward).clamp(-1.0, 1.0).acos();
+                let (across_x, across_y) = (d.dot(&right), -d.dot(&up));
+                let length = across_x.hypot(across_y);
+                if length == 0.0 {
+                    return (cx, cy);
+                }
+                let radius = projection.distance_at(angle) * self.screen_rows as Float * 0.5;
+                (
+                    cx + across_x / length * radius,
+                    cy + across_y / length * radius,
+                )
+            }
+            Projection::Equirectangular => {
+                let (forward, right, up) = self.get_basis_vectors();
+                let d = (p - self.position).normalize();
+                let longitude = d.dot(&right).atan2(d.dot(&forward));
+                let latitude = d.dot(&up).clamp(-1.0, 1.0).asin();
+                (
+                    cx + longitude / (2.0 * PI) * self.screen_columns as Float,
+                    cy - latitude / PI * self.screen_rows as Float,
+                )
+            }
+        }
+    }
+
+    // The lens ray with rays one pixel across and down from the same point on the lens
+    pub(crate) fn get_differential_ray(
+        &self,
+        x: Float,
+        y: Float,
+        lens: (Float, Float),
+        samples: u32,
+    ) -> Ray {
+        let mut ray = self.get_lens_ray(x, y, lens);
+        let rx = self.get_lens_ray(x + 1.0, y, lens);
+        let ry = self.get_lens_ray(x, y + 1.0, lens);
+        let differential = RayDifferential {
+            rx_origin: rx.origin,
+            rx_direction: rx.direction,
+            ry_origin: ry.origin,
+            ry_direction: ry.direction,
+        };
+        // Footprints shrink as more samples are taken per pixel
+        let scale = 1.0 / (samples.max(1) as Float).sqrt();
+        ray.differential = Some(differential.scale(&ray, scale));
+        ray
+    }
+
+    /*
+    Pixel sample positions. A single sample goes through the pixel corner as
+    before; with more samples each one is jittered inside the pixel using the
+    blue-noise mask, so the remaining noise is spread at high frequencies.
+    Points on the lens and moments in the shutter interval are picked from the
+    mask in the same way.
+     */
+    pub(crate) fn get_pixel_rays(&self, x: u32, y: u32) -> Vec<((Float, Float), Ray)> {
+        let samples = self.pixel_samples(x, y);
+        if samples <= 1 {
+            let mask = BlueNoiseMask::get();
+            let lens = mask.sample_2d(x, y, 0, sampling::DIMENSION_LENS);
+            let mut ray = self.get_differential_ray(x as Float, y as Float, lens, samples);
+            if self.shutter > 0.0 {
+                ray.time = mask.sample(x, y, 0, sampling::DIMENSION_TIME);
+            }
+            return vec![((0.0, 0.0), ray)];
+        }
+        self.get_jittered_rays(x, y, 0..samples, samples)
+    }
+
+    /*
+    Jittered rays of the pixel's samples with the given indices, with
+    footprints as for the given number of samples. Further samples of a pixel
+    carry on its sequences from where the first ones left off.
+     */
+    pub(crate) fn get_jittered_rays(
+        &self,
+        x: u32,
+        y: u32,
+        indices: std::ops::Range<u32>,
+        samples: u32,
+    ) -> Vec<((Float, Float), Ray)> {
+        let mask = BlueNoiseMask::get();
+        indices
+            .map(|i| {
+                let dx = mask.sample(x, y, i, sampling::DIMENSION_PIXEL_X) - 0.5;
+                let dy = mask.sample(x, y, i, sampling::DIMENSION_PIXEL_Y) - 0.5;
+                let (px, py) = (x as Float + dx, y as Float + dy);
+                let lens = mask.sample_2d(x, y, i, sampling::DIMENSION_LENS);
+                let mut ray = self.get_differential_ray(px, py, lens, samples);
+                if self.shutter > 0.0 {
+                    ray.time = mask.sample(x, y, i, sampling::DIMENSION_TIME);
+                }
+                ((dx, dy), ray)
+            })
+            .collect()
+    }
+
+    /*
+    Samples first taken in a film pixel: the camera's count scaled by the
+    pixel's importance, and enough to estimate its noise when sampling is
+    adaptive.
+     */
+    pub(crate) fn pixel_samples(&self, x: u32, y: u32) -> u32 {
+        if self.adaptive.is_some() {
+            return self.importance_samples(x, y).max(adaptive::MIN_SAMPLES);
+        }
+        self.importance_samples(x, y)
+    }
+
+    // The camera's sample count scaled by the pixel's importance
+    fn importance_samples(&self, x: u32, y: u32) -> u32 {
+        if self.samples <= 1 {
+            return self.samples;
+        }
+        let index = (y * self.film_columns() + x) as usize;
+        let importance = self.importance_map.get(index).copied().unwrap_or(1.0);
+        ((self.samples as Float * importance).round() as u32).max(1)
+    }
+}
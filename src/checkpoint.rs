@@ -0,0 +1,447 @@
+use crate::camera::Camera;
+use crate::core::double;
+use crate::region::Region;
+use crate::{FVec, Float};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Start of every checkpoint file, with the version of its layout
+const MAGIC: &[u8; 8] = b"RTCKPT1\n";
+// Names in the accumulation file of the sample count channel and of the seed it was taken with
+#[cfg(feature = "exr")]
+const SAMPLES_CHANNEL: &str = "samples";
+#[cfg(feature = "exr")]
+const SEED_ATTRIBUTE: &str = "raytracerSeed";
+
+/*
+Periodic saving of the pixels a long render has finished, so a render that
+crashes or is stopped can be resumed instead of started over. Pixels are
+saved a tile at a time with their colours and sample counts beside the
+output, as the output path with ".checkpoint" appended, and the file is
+removed once the image is written. Every pixel is sampled the same way
+whenever it is rendered, so a resumed render gives the same image as one
+left to finish. Only plain images with a pixel-sized filter keep
+checkpoints; extra outputs and wider filters need every sample at once.
+
+A finished image can also keep its accumulation: the mean radiance and
+sample count of every pixel, in an OpenEXR file beside it with ".samples.exr"
+appended. Rendering to the same output with more samples then carries each
+pixel's samples on from where they stopped instead of starting over.
+Adaptive sampling only adds samples to pixels rendered afresh.
+
+A render split between machines by tile range (see TileRange) saves the
+pixels of its tiles in the same layout as a checkpoint, as a part that merge
+gathers with the others into the finished image.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Checkpoint {
+    // Time between saves; renders that finish sooner never write one
+    pub interval: Duration,
+    // Carry on from the checkpoint left by an earlier render to the same output
+    pub resume: bool,
+    // Keep the accumulation of the finished image, and continue from the one there is
+    pub accumulate: bool,
+}
+
+// Checkpoints are only resumed by renders of the same size, samples and seed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Fingerprint {
+    width: u32,
+    height: u32,
+    samples: u32,
+    seed: u64,
+}
+
+impl Fingerprint {
+    pub(crate) fn of(camera: &Camera, seed: u64) -> Fingerprint {
+        Fingerprint {
+            width: camera.film_columns(),
+            height: camera.film_rows(),
+            samples: camera.samples,
+            seed,
+        }
+    }
+}
+
+// A finished pixel at (x, y), with its colour and the samples it took
+pub(crate) type Finished = (u32, u32, FVec, u32);
+
+// Keeps the finished pixels of one render and saves them as they come in
+pub(crate) struct Recorder {
+    output: String,
+    path: String,
+    fingerprint: Fingerprint,
+    interval: Duration,
+    accumulate: bool,
+    // Pixels from the checkpoint or accumulation the render started from
+    previous: HashMap<(u32, u32), (FVec, u32)>,
+    pixels: Mutex<HashMap<(u32, u32), (FVec, u32)>>,
+    // Held while saving, so only one thread saves at a time
+    last_saved: Mutex<Instant>,
+}
+
+impl Recorder {
+    /*
+    Recorder for a render to the output, starting from the pixels of its
+    checkpoint when resuming, or else of its accumulation when accumulating.
+     */
+    pub(crate) fn start(
+        checkpoint: Checkpoint,
+        output: &str,
+        fingerprint: Fingerprint,
+    ) -> Recorder {
+        let path = format!("{}.checkpoint", output);
+        let resumed = checkpoint.resume.then(|| match load(&path, fingerprint) {
+            Ok(Some(pixels)) => {
+                info!(
+                    "Resuming with {} finished pixels from {}",
+                    pixels.len(),
+                    path
+                );
+                Some(pixels)
+            }
+            Ok(None) => {
+                warn!("{} is of a different render; starting afresh", path);
+                None
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                info!("No checkpoint at {}; starting afresh", path);
+                None
+            }
+            Err(error) => {
+                warn!("Could not read {}: {}; starting afresh", path, error);
+                None
+            }
+        });
+        let previous = match resumed.flatten() {
+            Some(pixels) => pixels,
+            None if checkpoint.accumulate => continue_accumulation(output, fingerprint),
+            None => Vec::new(),
+        };
+        let previous: HashMap<_, _> = previous
+            .into_iter()
+            .map(|(x, y, colour, n)| ((x, y), (colour, n)))
+            .collect();
+        Recorder {
+            output: output.to_string(),
+            path,
+            fingerprint,
+            interval: checkpoint.interval,
+            accumulate: checkpoint.accumulate,
+            pixels: Mutex::new(previous.clone()),
+            previous,
+            last_saved: Mutex::new(Instant::now()),
+        }
+    }
+
+    /*
+    Recorder for merging the parts of a render split by tile range, starting
+    from all their pixels. It never saves, as the parts are kept.
+     */
+    pub(crate) fn merged(
+        output: &str,
+        fingerprint: Fingerprint,
+        parts: &[String],
+    ) -> io::Result<Recorder> {
+        let mut previous = HashMap::new();
+        for part in parts {
+            let pixels = match load(part, fingerprint) {
+                Ok(Some(pixels)) => pixels,
+                Ok(None) => {
+                    let error = format!("{part} is of a different render");
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, error));
+                }
+                Err(error) => return Err(io::Error::new(error.kind(), format!("{part}: {error}"))),
+            };
+            previous.extend(
+                pixels
+                    .into_iter()
+                    .map(|(x, y, colour, n)| ((x, y), (colour, n))),
+            );
+        }
+        let missing = (fingerprint.width * fingerprint.height) as usize;
+        let missing = missing.saturating_sub(previous.len());
+        if missing > 0 {
+            warn!("The parts lack {} pixels, which are rendered now", missing);
+        }
+        Ok(Recorder {
+            output: output.to_string(),
+            path: format!("{}.checkpoint", output),
+            fingerprint,
+            interval: Duration::MAX,
+            accumulate: false,
+            pixels: Mutex::new(previous.clone()),
+            previous,
+            last_saved: Mutex::new(Instant::now()),
+        })
+    }
+
+    // The pixels of the tile the render started from, if it has all of them
+    pub(crate) fn previous(&self, tile: &Region) -> Option<Vec<Finished>> {
+        let rows = tile.y..tile.y + tile.height;
+        rows.flat_map(|y| (tile.x..tile.x + tile.width).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let (colour, samples) = self.previous.get(&(x, y))?;
+                Some((x, y, *colour, *samples))
+            })
+            .collect()
+    }
+
+    // Keep the pixels of a finished tile, saving every pixel so far when a save is due
+    pub(crate) fn record(&self, finished: &[Finished]) {
+        let mut pixels = self.pixels.lock().unwrap();
+        pixels.extend(
+            finished
+                .iter()
+                .map(|&(x, y, colour, n)| ((x, y), (colour, n))),
+        );
+        drop(pixels);
+        // Another thread is already saving
+        let Ok(mut last_saved) = self.last_saved.try_lock() else {
+            return;
+        };
+        if last_saved.elapsed() < self.interval {
+            return;
+        }
+        let pixels = self.finished();
+        match save(&self.path, self.fingerprint, &pixels) {
+            Ok(()) => debug!("Saved {} finished pixels to {}", pixels.len(), self.path),
+            Err(error) => warn!("Could not write {}: {}", self.path, error),
+        }
+        *last_saved = Instant::now();
+    }
+
+    fn finished(&self) -> Vec<Finished> {
+        let pixels = self.pixels.lock().unwrap();
+        pixels
+            .iter()
+            .map(|(&(x, y), &(colour, n))| (x, y, colour, n))
+            .collect()
+    }
+
+    // Once the image is written, remove its checkpoint and keep its accumulation if asked to
+    pub(crate) fn finish(self) {
+        if self.accumulate {
+            let path = accumulation_path(&self.output);
+            match save_accumulation(&path, self.fingerprint, &self.finished()) {
+                Ok(()) => info!("Wrote the accumulated samples to {}", path),
+                Err(error) => warn!("Could not write {}: {}", path, error),
+            }
+        }
+        match fs::remove_file(&self.path) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => {
+                warn!("Could not remove {}: {}", self.path, error);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn accumulation_path(output: &str) -> String {
+    format!("{}.samples.exr", output)
+}
+
+// The pixels of the output's accumulation, if it has one for a film of this size and seed
+fn continue_accumulation(output: &str, fingerprint: Fingerprint) -> Vec<Finished> {
+    let path = accumulation_path(output);
+    if !Path::new(&path).exists() {
+        return Vec::new();
+    }
+    match load_accumulation(&path, fingerprint) {
+        Ok(Some(pixels)) => {
+            let samples = pixels.iter().map(|&(.., n)| n as u64).sum::<u64>();
+            let mean = samples as Float / pixels.len().max(1) as Float;
+            info!(
+                "Continuing from {} samples per pixel on average in {}",
+                mean, path
+            );
+            pixels
+        }
+        Ok(None) => {
+            warn!("{} is of a different film or seed; starting afresh", path);
+            Vec::new()
+        }
+        Err(error) => {
+            warn!("Could not read {}: {}; starting afresh", path, error);
+            Vec::new()
+        }
+    }
+}
+
+// Mean radiance as R, G and B channels and the sample count as an integer channel
+#[cfg(feature = "exr")]
+fn save_accumulation(path: &str, fingerprint: Fingerprint, pixels: &[Finished]) -> io::Result<()> {
+    use crate::core::single;
+    use exr::prelude::*;
+    let (width, height) = (fingerprint.width as usize, fingerprint.height as usize);
+    let mut colours = vec![vec![0.0; width * height]; 3];
+    let mut counts = vec![0; width * height];
+    for &(x, y, colour, samples) in pixels {
+        let index = y as usize * width + x as usize;
+        for (channel, value) in colours.iter_mut().zip(colour.iter()) {
+            channel[index] = single(*value);
+        }
+        counts[index] = samples;
+    }
+    let mut channels: SmallVec<[AnyChannel<FlatSamples>; 4]> = SmallVec::new();
+    for (name, channel) in ["R", "G", "B"].into_iter().zip(colours) {
+        channels.push(AnyChannel::new(name, FlatSamples::F32(channel)));
+    }
+    channels.push(AnyChannel::new(SAMPLES_CHANNEL, FlatSamples::U32(counts)));
+    let mut attributes = LayerAttributes::default();
+    let seed = AttributeValue::Text(Text::from(fingerprint.seed.to_string().as_str()));
+    attributes.other.insert(Text::from(SEED_ATTRIBUTE), seed);
+    let encoding = Encoding::SMALL_LOSSLESS;
+    let layer = Layer::new(
+        Vec2(width, height),
+        attributes,
+        encoding,
+        AnyChannels::sort(channels),
+    );
+    Image::from_layer(layer)
+        .write()
+        .to_file(path)
+        .map_err(io::Error::other)
+}
+
+// The pixels of an accumulation, or None if it is of another film or seed
+#[cfg(feature = "exr")]
+fn load_accumulation(path: &str, fingerprint: Fingerprint) -> io::Result<Option<Vec<Finished>>> {
+    use exr::prelude::*;
+    let image = read_first_flat_layer_from_file(path).map_err(io::Error::other)?;
+    let layer = &image.layer_data;
+    let seed = match layer.attributes.other.get(&Text::from(SEED_ATTRIBUTE)) {
+        Some(AttributeValue::Text(seed)) => seed.to_string().parse::<u64>().ok(),
+        _ => None,
+    };
+    let size = Vec2(fingerprint.width as usize, fingerprint.height as usize);
+    if layer.size != size || seed != Some(fingerprint.seed) {
+        return Ok(None);
+    }
+    let channel = |name: &str| {
+        let channel = layer.channel_data.list.iter().find(|c| c.name == *name);
+        channel.ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no {name} channel"),
+        ))
+    };
+    let [red, green, blue] = ["R", "G", "B"].map(channel);
+    let (red, green, blue) = (red?, green?, blue?);
+    let FlatSamples::U32(counts) = &channel(SAMPLES_CHANNEL)?.sample_data else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "sample counts are not integers",
+        ));
+    };
+    let pixels = counts
+        .iter()
+        .enumerate()
+        .filter(|(_, &samples)| samples > 0)
+        .map(|(index, &samples)| {
+            let value = |channel: &AnyChannel<FlatSamples>| {
+                channel.sample_data.value_by_flat_index(index).to_f32() as Float
+            };
+            let colour = FVec::new(value(red), value(green), value(blue));
+            let (x, y) = ((index % size.0) as u32, (index / size.0) as u32);
+            (x, y, colour, samples)
+        })
+        .collect();
+    Ok(Some(pixels))
+}
+
+#[cfg(not(feature = "exr"))]
+fn save_accumulation(
+    _path: &str,
+    _fingerprint: Fingerprint,
+    _pixels: &[Finished],
+) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "OpenEXR support is not built in",
+    ))
+}
+
+#[cfg(not(feature = "exr"))]
+fn load_accumulation(_path: &str, _fingerprint: Fingerprint) -> io::Result<Option<Vec<Finished>>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "OpenEXR support is not built in",
+    ))
+}
+
+// Written beside the checkpoint and then moved over it, so a crash never leaves half a file
+pub(crate) fn save(path: &str, fingerprint: Fingerprint, pixels: &[Finished]) -> io::Result<()> {
+    let partial = format!("{}.partial", path);
+    let mut out = BufWriter::new(File::create(&partial)?);
+    out.write_all(MAGIC)?;
+    out.write_all(&fingerprint.width.to_le_bytes())?;
+    out.write_all(&fingerprint.height.to_le_bytes())?;
+    out.write_all(&fingerprint.samples.to_le_bytes())?;
+    out.write_all(&fingerprint.seed.to_le_bytes())?;
+    out.write_all(&(pixels.len() as u64).to_le_bytes())?;
+    for (x, y, colour, samples) in pixels {
+        out.write_all(&x.to_le_bytes())?;
+        out.write_all(&y.to_le_bytes())?;
+        out.write_all(&samples.to_le_bytes())?;
+        for channel in colour.iter() {
+            out.write_all(&double(*channel).to_le_bytes())?;
+        }
+    }
+    out.into_inner()
+        .map_err(|error| error.into_error())?
+        .sync_all()?;
+    fs::rename(partial, path)
+}
+
+// The pixels of a checkpoint, or None if it is of another render
+fn load(path: &str, fingerprint: Fingerprint) -> io::Result<Option<Vec<Finished>>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut magic = [0; 8];
+    file.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a checkpoint",
+        ));
+    }
+    let saved = Fingerprint {
+        width: read_u32(&mut file)?,
+        height: read_u32(&mut file)?,
+        samples: read_u32(&mut file)?,
+        seed: read_u64(&mut file)?,
+    };
+    if saved != fingerprint {
+        return Ok(None);
+    }
+    let count = read_u64(&mut file)?;
+    let mut pixels = Vec::new();
+    for _ in 0..count {
+        let (x, y, samples) = (
+            read_u32(&mut file)?,
+            read_u32(&mut file)?,
+            read_u32(&mut file)?,
+        );
+        let mut colour = FVec::zeros();
+        for channel in colour.iter_mut() {
+            *channel = f64::from_bits(read_u64(&mut file)?) as Float;
+        }
+        pixels.push((x, y, colour, samples));
+    }
+    Ok(Some(pixels))
+}
+
+fn read_u32(file: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    file.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(file: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    file.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
@@ -0,0 +1,556 @@
+use crate::config::find_asset;
+use crate::{FVec, Float};
+use image::ImageFormat;
+use nalgebra::Matrix3;
+use serde::Deserialize;
+use serde_yaml::Value;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+// Guards against configs whose colour spaces are defined in terms of each other
+const MAX_TRANSFORM_DEPTH: u32 = 16;
+
+/*
+Colour pipeline set up from an OpenColorIO config. Material and light colours
+are in the working space, textures are converted from the texture space when
+loaded unless tagged otherwise, and 8-bit outputs are converted to the output space when written.
+ */
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ColourManagement {
+    // Path to the config, relative to the scene file
+    pub config: String,
+    // Colour space names may also be roles, such as the default `scene_linear`
+    #[serde(default = "default_working_space")]
+    pub working_space: String,
+    // Colour space of textures without their own tag
+    pub texture_space: Option<String>,
+    pub output_space: String,
+}
+
+fn default_working_space() -> String {
+    "scene_linear".to_string()
+}
+
+/*
+How the values stored in a texture are to be read. sRGB and linear textures
+are assumed to share the working space's primaries; any other name is looked
+up in the OCIO config.
+ */
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(from = "String")]
+pub enum TextureColourSpace {
+    // Colour stored with the sRGB transfer curve, as in most 8-bit images
+    Srgb,
+    // Colour stored as linear values, as in most floating-point images
+    Linear,
+    // Non-colour values such as normals or roughness, never converted
+    Data,
+    Named(String),
+}
+
+impl From<String> for TextureColourSpace {
+    fn from(name: String) -> TextureColourSpace {
+        match name.to_ascii_lowercase().as_str() {
+            "srgb" => TextureColourSpace::Srgb,
+            "linear" => TextureColourSpace::Linear,
+            "data" | "raw" => TextureColourSpace::Data,
+            _ => TextureColourSpace::Named(name),
+        }
+    }
+}
+
+// Conversions needed while rendering; the output is unchanged without colour management
+#[derive(Debug, Clone, Default)]
+pub struct ColourPipeline {
+    config: Option<Arc<OcioConfig>>,
+    working_space: String,
+    texture_space: Option<String>,
+    pub output: Processor,
+}
+
+impl ColourManagement {
+    pub fn pipeline(&self, base_dir: &Path) -> Result<ColourPipeline, Box<dyn Error>> {
+        let config = OcioConfig::load(&find_asset(base_dir, &self.config))?;
+        let output = config.processor(&self.working_space, &self.output_space)?;
+        Ok(ColourPipeline {
+            config: Some(Arc::new(config)),
+            working_space: self.working_space.clone(),
+            texture_space: self.texture_space.clone(),
+            output,
+        })
+    }
+}
+
+impl ColourPipeline {
+    /*
+    Conversion of the texture at the given path into the working space. An
+    untagged texture uses the config's texture space if there is one, and
+    otherwise is taken as linear for floating-point formats and sRGB for the
+    rest.
+     */
+    pub fn texture_processor(
+        &self,
+        colour_space: Option<&TextureColourSpace>,
+        path: &Path,
+    ) -> Result<Processor, Box<dyn Error>> {
+        let named = |name: &str| match &self.config {
+            Some(config) => config.processor(name, &self.working_space),
+            None => Err(format!("texture colour space `{}` needs colour management", name).into()),
+        };
+        match colour_space {
+            Some(TextureColourSpace::Srgb) => Ok(Processor::srgb_to_linear()),
+            Some(TextureColourSpace::Linear | TextureColourSpace::Data) => Ok(Processor::default()),
+            Some(TextureColourSpace::Named(name)) => named(name),
+            None => match &self.texture_space {
+                Some(name) => named(name),
+                None => match ImageFormat::from_path(path) {
+                    Ok(ImageFormat::OpenExr | ImageFormat::Hdr) => Ok(Processor::default()),
+                    _ => Ok(Processor::srgb_to_linear()),
+                },
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Op {
+    // Applied to the colour as a column vector, followed by the offset
+    Matrix {
+        matrix: Matrix3<Float>,
+        offset: FVec,
+    },
+    Exponent {
+        value: FVec,
+    },
+    // Power curve with a linear segment near zero (as in sRGB), decoding to linear
+    Moncurve {
+        gamma: FVec,
+        offset: FVec,
+    },
+    // Inverse of the above, encoding linear values
+    MoncurveInverse {
+        gamma: FVec,
+        offset: FVec,
+    },
+}
+
+impl Op {
+    fn apply(&self, colour: FVec) -> FVec {
+        match self {
+            Op::Matrix { matrix, offset } => matrix * colour + offset,
+            Op::Exponent { value } => colour.zip_map(value, |c, v| c.max(0.0).powf(v)),
+            Op::Moncurve { gamma, offset } => FVec::from_fn(|i, _| {
+                let (x, g, o) = (colour[i], gamma[i], offset[i]);
+                let (x_break, slope) = moncurve_break(g, o);
+                if x <= x_break {
+                    x / slope
+                } else {
+                    ((x + o) / (1.0 + o)).powf(g)
+                }
+            }),
+            Op::MoncurveInverse { gamma, offset } => FVec::from_fn(|i, _| {
+                let (y, g, o) = (colour[i], gamma[i], offset[i]);
+                let (x_break, slope) = moncurve_break(g, o);
+                if y <= x_break / slope {
+                    y * slope
+                } else {
+                    (1.0 + o) * y.powf(1.0 / g) - o
+                }
+            }),
+        }
+    }
+}
+
+/*
+Encoded value below which a monitor curve is linear, and the slope of the
+encoding there, chosen so the two pieces meet with matching derivatives.
+ */
+fn moncurve_break(gamma: Float, offset: Float) -> (Float, Float) {
+    if offset <= 0.0 {
+        return (0.0, 1.0);
+    }
+    let x_break = offset / (gamma - 1.0);
+    let slope =
+        (gamma - 1.0) / offset * ((offset * gamma) / ((gamma - 1.0) * (1.0 + offset))).powf(gamma);
+    (x_break, 1.0 / slope)
+}
+
+// A chain of colour operations from one colour space to another
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Processor {
+    ops: Vec<Op>,
+}
+
+impl Processor {
+    pub fn srgb_to_linear() -> Processor {
+        Processor {
+            ops: vec![Op::Moncurve {
+                gamma: FVec::repeat(2.4),
+                offset: FVec::repeat(0.055),
+            }],
+        }
+    }
+
+    pub fn linear_to_srgb() -> Processor {
+        Processor {
+            ops: vec![Op::MoncurveInverse {
+                gamma: FVec::repeat(2.4),
+                offset: FVec::repeat(0.055),
+            }],
+        }
+    }
+
+    pub fn apply(&self, colour: FVec) -> FVec {
+        self.ops.iter().fold(colour, |colour, op| op.apply(colour))
+    }
+}
+
+#[derive(Debug)]
+struct ColourSpace {
+    name: String,
+    aliases: Vec<String>,
+    // Data such as normals, which OCIO never converts
+    is_data: bool,
+    to_reference: Option<Value>,
+    from_reference: Option<Value>,
+}
+
+/*
+The scene-referred colour spaces and roles of an OCIO config (version 1 or 2).
+Only analytic transforms are supported: matrices, exponents, exponents with a
+linear segment, groups and references to other colour spaces. Transforms that
+need LUT files or built-in functions are reported as errors.
+ */
+#[derive(Debug)]
+pub struct OcioConfig {
+    roles: Vec<(String, String)>,
+    spaces: Vec<ColourSpace>,
+}
+
+impl OcioConfig {
+    pub fn load(path: &Path) -> Result<OcioConfig, Box<dyn Error>> {
+        OcioConfig::parse(&fs::read_to_string(path)?)
+    }
+
+    fn parse(text: &str) -> Result<OcioConfig, Box<dyn Error>> {
+        let yaml: Value = serde_yaml::from_str(&local_tags(text))?;
+        let roles = yaml
+            .get("roles")
+            .and_then(Value::as_mapping)
+            .into_iter()
+            .flatten()
+            .filter_map(|(role, space)| {
+                Some((role.as_str()?.to_string(), space.as_str()?.to_string()))
+            })
+            .collect();
+        let spaces = yaml
+            .get("colorspaces")
+            .and_then(Value::as_sequence)
+            .ok_or("OCIO config has no colorspaces")?
+            .iter()
+            .map(|space| {
+                let field = |keys: [&str; 2]| keys.iter().find_map(|k| space.get(k)).cloned();
+                ColourSpace {
+                    name: space
+                        .get("name")
+                        .and_then(Value::as_str)
+                        .unwrap_or("")
+                        .to_string(),
+                    aliases: space
+                        .get("aliases")
+                        .and_then(Value::as_sequence)
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|alias| Some(alias.as_str()?.to_string()))
+                        .collect(),
+                    is_data: space
+                        .get("isdata")
+                        .and_then(Value::as_bool)
+                        .unwrap_or(false),
+                    to_reference: field(["to_scene_reference", "to_reference"]),
+                    from_reference: field(["from_scene_reference", "from_reference"]),
+                }
+            })
+            .collect();
+        Ok(OcioConfig { roles, spaces })
+    }
+
+    pub fn processor(&self, source: &str, destination: &str) -> Result<Processor, Box<dyn Error>> {
+        let ops = self.conversion(source, destination, 0)?;
+        Ok(Processor { ops })
+    }
+
+    // Colour space by name, alias or role, ignoring case as OCIO does
+    fn space(&self, name: &str) -> Result<&ColourSpace, Box<dyn Error>> {
+        let name = self
+            .roles
+            .iter()
+            .find(|(role, _)| role.eq_ignore_ascii_case(name))
+            .map_or(name, |(_, space)| space.as_str());
+        self.spaces
+            .iter()
+            .find(|space| {
+                space.name.eq_ignore_ascii_case(name)
+                    || space.aliases.iter().any(|a| a.eq_ignore_ascii_case(name))
+            })
+            .ok_or_else(|| format!("unknown OCIO colour space `{}`", name).into())
+    }
+
+    fn conversion(
+        &self,
+        source: &str,
+        destination: &str,
+        depth: u32,
+    ) -> Result<Vec<Op>, Box<dyn Error>> {
+        if depth > MAX_TRANSFORM_DEPTH {
+            return Err("OCIO colour spaces refer to each other in a cycle".into());
+        }
+        let (source, destination) = (self.space(source)?, self.space(destination)?);
+        if source.is_data || destination.is_data || std::ptr::eq(source, destination) {
+            return Ok(Vec::new());
+        }
+        let mut ops = match (&source.to_reference, &source.from_reference) {
+            (Some(transform), _) => self.transform(transform, false, depth)?,
+            (None, Some(transform)) => self.transform(transform, true, depth)?,
+            (None, None) => Vec::new(),
+        };
+        ops.extend(
+            match (&destination.from_reference, &destination.to_reference) {
+                (Some(transform), _) => self.transform(transform, false, depth)?,
+                (None, Some(transform)) => self.transform(transform, true, depth)?,
+                (None, None) => Vec::new(),
+            },
+        );
+        Ok(ops)
+    }
+
+    fn transform(
+        &self,
+        transform: &Value,
+        inverse: bool,
+        depth: u32,
+    ) -> Result<Vec<Op>, Box<dyn Error>> {
+        let inverse =
+            inverse ^ (transform.get("direction").and_then(Value::as_str) == Some("inverse"));
+        let tag = match transform {
+            Value::Tagged(tagged) => tagged.tag.to_string(),
+            _ => String::new(),
+        };
+        match tag.trim_start_matches('!') {
+            "GroupTransform" => {
+                let children = transform
+                    .get("children")
+                    .and_then(Value::as_sequence)
+                    .into_iter()
+                    .flatten();
+                let mut ops = Vec::new();
+                for child in children {
+                    ops.push(self.transform(child, inverse, depth)?);
+                }
+                if inverse {
+                    ops.reverse();
+                }
+                Ok(ops.concat())
+            }
+            "ColorSpaceTransform" => {
+                let name = |key| {
+                    transform
+                        .get(key)
+                        .and_then(Value::as_str)
+                        .ok_or("ColorSpaceTransform needs src and dst")
+                };
+                let (source, destination) = (name("src")?, name("dst")?);
+                if inverse {
+                    self.conversion(destination, source, depth + 1)
+                } else {
+                    self.conversion(source, destination, depth + 1)
+                }
+            }
+            "MatrixTransform" => {
+                let matrix = floats(transform.get("matrix"), 16)?;
+                let offset = floats(transform.get("offset"), 4)?;
+                let mut matrix = match matrix {
+                    Some(m) => Matrix3::from_fn(|row, column| m[row * 4 + column]),
+                    None => Matrix3::identity(),
+                };
+                let mut offset = offset.map_or(FVec::zeros(), |o| FVec::new(o[0], o[1], o[2]));
+                if inverse {
+                    matrix = matrix
+                        .try_inverse()
+                        .ok_or("MatrixTransform is not invertible")?;
+                    offset = -(matrix * offset);
+                }
+                Ok(vec![Op::Matrix { matrix, offset }])
+            }
+            "ExponentTransform" => {
+                let value = channels(transform.get("value"), 1.0)?;
+                let value = if inverse {
+                    value.map(|v| 1.0 / v)
+                } else {
+                    value
+                };
+                Ok(vec![Op::Exponent { value }])
+            }
+            "ExponentWithLinearTransform" => {
+                let gamma = channels(transform.get("gamma"), 1.0)?;
+                let offset = channels(transform.get("offset"), 0.0)?;
+                Ok(vec![if inverse {
+                    Op::MoncurveInverse { gamma, offset }
+                } else {
+                    Op::Moncurve { gamma, offset }
+                }])
+            }
+            _ => Err(format!("unsupported OCIO transform `{}`", tag).into()),
+        }
+    }
+}
+
+/*
+The config with verbatim tags such as `!<MatrixTransform>`, which OCIO
+writes for every transform, made local tags such as `!MatrixTransform`, as
+serde_yaml keeps only local tags and drops the others. Quoted strings and
+comments are passed over, so text in them that looks like a tag is kept.
+ */
+fn local_tags(text: &str) -> String {
+    let mut local = String::with_capacity(text.len());
+    let mut chars = text.char_indices();
+    let (mut quote, mut comment, mut previous) = (None, false, '\n');
+    while let Some((i, c)) = chars.next() {
+        // Quotes and tags only start a value, after a space or indicator
+        let starts_value = previous.is_whitespace() || "[{,:-".contains(previous);
+        match quote {
+            Some('"') if c == '\\' => {
+                local.push(c);
+                previous = chars.next().map_or(c, |(_, escaped)| escaped);
+                local.push(previous);
+                continue;
+            }
+            Some(end) if c == end => quote = None,
+            Some(_) => {}
+            None if comment => comment = c != '\n',
+            None if c == '#' && previous.is_whitespace() => comment = true,
+            None if (c == '\'' || c == '"') && starts_value => quote = Some(c),
+            None if c == '!' && starts_value => {
+                let name = |c: char| c.is_ascii_alphanumeric() || c == '_';
+                let tag = text[i + 1..]
+                    .strip_prefix('<')
+                    .and_then(|rest| rest.split_once('>'))
+                    .map(|(tag, _)| tag)
+                    .filter(|tag| !tag.is_empty() && tag.chars().all(name));
+                if let Some(tag) = tag {
+                    local.push('!');
+                    local.push_str(tag);
+                    // Past the tag and its closing bracket
+                    chars.nth(tag.len() + 1);
+                    previous = '>';
+                    continue;
+                }
+            }
+            None => {}
+        }
+        local.push(c);
+        previous = c;
+    }
+    local
+}
+
+// A list of exactly `count` numbers, or None when the key is absent
+fn floats(value: Option<&Value>, count: usize) -> Result<Option<Vec<Float>>, Box<dyn Error>> {
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    let numbers: Option<Vec<Float>> = value.as_sequence().map(|items| {
+        items
+            .iter()
+            .filter_map(Value::as_f64)
+            .map(|v| v as Float)
+            .collect()
+    });
+    match numbers {
+        Some(numbers) if numbers.len() == count => Ok(Some(numbers)),
+        _ => Err(format!("expected a list of {} numbers", count).into()),
+    }
+}
+
+// Per-channel parameter given as one number or as RGBA values
+fn channels(value: Option<&Value>, default: Float) -> Result<FVec, Box<dyn Error>> {
+    match value {
+        None => Ok(FVec::repeat(default)),
+        Some(value) => match value.as_f64() {
+            Some(v) => Ok(FVec::repeat(v as Float)),
+            None => {
+                let v = floats(Some(value), 4)?.unwrap_or_default();
+                Ok(FVec::new(v[0], v[1], v[2]))
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "\
+ocio_profile_version: 2
+roles:
+  scene_linear: 'Linear # Rec.709'  # quoted, so the # is part of the name
+colorspaces:
+  - !<ColorSpace>
+    name: 'Linear # Rec.709'
+    aliases: [\"lin\\u00e9aire\"]
+  - !<ColorSpace>
+    name: Bright
+    from_scene_reference: &bright !<GroupTransform>
+      children:
+        - !<MatrixTransform> {matrix: [4, 0, 0, 0, 0, 4, 0, 0, 0, 0, 4, 0, 0, 0, 0, 1]}
+        - !<ExponentTransform> {value: [2, 2, 2, 1], direction: inverse}
+  - !<ColorSpace>
+    name: Also bright
+    from_scene_reference: *bright
+  - !<ColorSpace>
+    name: Raw
+    isdata: true
+";
+
+    #[test]
+    fn reads_roles_aliases_and_transforms() {
+        let config = OcioConfig::parse(CONFIG).unwrap();
+        let colour = FVec::new(0.25, 1.0, 4.0);
+        let expected = FVec::new(1.0, 2.0, 4.0);
+        for destination in ["Bright", "also bright"] {
+            let bright = config.processor("scene_linear", destination).unwrap();
+            assert!((bright.apply(colour) - expected).norm() < 1e-6);
+        }
+        let back = config.processor("Bright", "linéaire").unwrap();
+        assert!((back.apply(expected) - colour).norm() < 1e-6);
+        assert_eq!(
+            config.processor("Raw", "Bright").unwrap().apply(colour),
+            colour
+        );
+        assert!(config.processor("Linear", "Bright").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_configs() {
+        assert!(OcioConfig::parse("colorspaces:\n  - name: a\n bad: 1\n").is_err());
+        assert!(OcioConfig::parse("roles: {}\n").is_err());
+        let lut = "\
+colorspaces:
+  - name: a
+    to_reference: !<FileTransform> {src: a.cube}
+  - name: b
+";
+        let config = OcioConfig::parse(lut).unwrap();
+        assert!(config.processor("a", "b").is_err());
+    }
+
+    #[test]
+    fn makes_only_tags_local() {
+        let text = "a: !<MatrixTransform> {}\nb: [!<X>, '1 !<Y>'] # !<Z>\nc: \"\\\" !<W>\"";
+        let local = "a: !MatrixTransform {}\nb: [!X, '1 !<Y>'] # !<Z>\nc: \"\\\" !<W>\"";
+        assert_eq!(local_tags(text), local);
+    }
+}
@@ -0,0 +1,162 @@
+use crate::gbuffer::heat;
+use crate::Float;
+use image::{DynamicImage, GrayImage, Rgb, RgbImage};
+
+// Stabilising constants of SSIM for 8-bit values (Wang et al. 2004)
+const SSIM_C1: Float = (0.01 * 255.0) * (0.01 * 255.0);
+const SSIM_C2: Float = (0.03 * 255.0) * (0.03 * 255.0);
+// Gaussian window the local statistics of SSIM are taken over
+const SSIM_SIGMA: Float = 1.5;
+const SSIM_RADIUS: usize = 5;
+
+// How far apart two images are
+pub struct Comparison {
+    // Mean squared error of the 8-bit colours scaled to [0, 1]
+    pub mse: Float,
+    // Peak signal-to-noise ratio in decibels over the 8-bit colours; infinite for equal images
+    pub psnr: Float,
+    // Mean structural similarity of the luminance, 1 for equal images
+    pub ssim: Float,
+    // Difference at each pixel, from blue where the images agree to red where they differ most
+    pub heatmap: RgbImage,
+}
+
+/*
+Compare two images of the same size, as for checking a render against a
+reference one rendered with the same seed, or how fast a render converges.
+Both are taken as 8-bit.
+ */
+pub fn compare(a: &DynamicImage, b: &DynamicImage) -> Result<Comparison, String> {
+    if a.width() != b.width() || a.height() != b.height() {
+        return Err(format!(
+            "the images are {}x{} and {}x{} pixels",
+            a.width(),
+            a.height(),
+            b.width(),
+            b.height()
+        ));
+    }
+    let (rgb_a, rgb_b) = (a.to_rgb8(), b.to_rgb8());
+    let mut squared_error = 0.0;
+    let differences: Vec<Float> = rgb_a
+        .pixels()
+        .zip(rgb_b.pixels())
+        .map(|(p, q)| {
+            let channels = p.0.iter().zip(q.0).map(|(&x, y)| x as Float - y as Float);
+            channels.fold(0.0, |largest: Float, d| {
+                squared_error += d * d;
+                largest.max(d.abs())
+            })
+        })
+        .collect();
+    let mse = squared_error / (3 * differences.len().max(1)) as Float;
+    let psnr = if mse > 0.0 {
+        10.0 * (255.0 * 255.0 / mse).log10()
+    } else {
+        Float::INFINITY
+    };
+    let largest = differences.iter().copied().fold(1.0, Float::max);
+    let heatmap = RgbImage::from_fn(a.width(), a.height(), |x, y| {
+        let difference = differences[(y * a.width() + x) as usize];
+        Rgb(heat(difference / largest)
+            .map(|c| (c * 255.0).round() as u8)
+            .into())
+    });
+    Ok(Comparison {
+        mse: mse / (255.0 * 255.0),
+        psnr,
+        ssim: mean_ssim(&a.to_luma8(), &b.to_luma8()),
+        heatmap,
+    })
+}
+
+// Width in pixels of the line between the two sides of a split image
+const SPLIT_LINE: Float = 2.0;
+
+/*
+Two images of the same size as one for comparing them side by side: a to
+the left of a line through the middle of the frame at the fraction of its
+width across, b to the right, and the line white. The line is turned
+clockwise from upright by the angle in degrees, for a diagonal wipe.
+ */
+pub fn split(a: &RgbImage, b: &RgbImage, at: Float, angle: Float) -> RgbImage {
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let centre = (at * a.width() as Float, 0.5 * a.height() as Float);
+    RgbImage::from_fn(a.width(), a.height(), |x, y| {
+        // Distance across the line at the pixel's centre, negative on a's side
+        let across = (x as Float + 0.5 - centre.0) * cos + (y as Float + 0.5 - centre.1) * sin;
+        if across.abs() < 0.5 * SPLIT_LINE {
+            Rgb([255; 3])
+        } else if across < 0.0 {
+            *a.get_pixel(x, y)
+        } else {
+            *b.get_pixel(x, y)
+        }
+    })
+}
+
+// Structural similarity averaged over every pixel's window
+fn mean_ssim(a: &GrayImage, b: &GrayImage) -> Float {
+    let (width, height) = (a.width() as usize, a.height() as usize);
+    let x: Vec<Float> = a.pixels().map(|p| p.0[0] as Float).collect();
+    let y: Vec<Float> = b.pixels().map(|p| p.0[0] as Float).collect();
+    let product =
+        |p: &[Float], q: &[Float]| -> Vec<Float> { p.iter().zip(q).map(|(p, q)| p * q).collect() };
+    let kernel = gaussian_kernel();
+    let blur = |values: &[Float]| blur(values, width, height, &kernel);
+    let (mean_x, mean_y) = (blur(&x), blur(&y));
+    let (mean_xx, mean_yy, mean_xy) = (
+        blur(&product(&x, &x)),
+        blur(&product(&y, &y)),
+        blur(&product(&x, &y)),
+    );
+    let total: Float = (0..x.len())
+        .map(|i| {
+            let (mx, my) = (mean_x[i], mean_y[i]);
+            let variance_x = mean_xx[i] - mx * mx;
+            let variance_y = mean_yy[i] - my * my;
+            let covariance = mean_xy[i] - mx * my;
+            (2.0 * mx * my + SSIM_C1) * (2.0 * covariance + SSIM_C2)
+                / ((mx * mx + my * my + SSIM_C1) * (variance_x + variance_y + SSIM_C2))
+        })
+        .sum();
+    total / x.len().max(1) as Float
+}
+
+fn gaussian_kernel() -> Vec<Float> {
+    let weights: Vec<Float> = (0..=2 * SSIM_RADIUS)
+        .map(|i| {
+            let d = i as Float - SSIM_RADIUS as Float;
+            (-d * d / (2.0 * SSIM_SIGMA * SSIM_SIGMA)).exp()
+        })
+        .collect();
+    let sum: Float = weights.iter().sum();
+    weights.iter().map(|w| w / sum).collect()
+}
+
+// Separable blur of an image stored row by row, repeating the edge pixels beyond the borders
+fn blur(values: &[Float], width: usize, height: usize, kernel: &[Float]) -> Vec<Float> {
+    let radius = kernel.len() / 2;
+    let tap =
+        |i: usize, offset: usize, limit: usize| (i + offset).saturating_sub(radius).min(limit - 1);
+    let rows: Vec<Float> = (0..values.len())
+        .map(|i| {
+            let (x, y) = (i % width, i / width);
+            kernel
+                .iter()
+                .enumerate()
+                .map(|(k, w)| w * values[y * width + tap(x, k, width)])
+                .sum()
+        })
+        .collect();
+    (0..values.len())
+        .map(|i| {
+            let (x, y) = (i % width, i / width);
+            kernel
+                .iter()
+                .enumerate()
+                .map(|(k, w)| w * rows[tap(y, k, height) * width + x])
+                .sum()
+        })
+        .collect()
+}
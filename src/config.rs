@@ -0,0 +1,285 @@
+use log::LevelFilter;
+use serde_json::Value;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/*
+Defaults for command line options, read from the user's config file and
+then from environment variables, each overriding the one before. Flags on
+the command line override both.
+
+The config file is TOML with these keys at the top level, e.g.
+
+    threads = 8
+    output_dir = "~/renders"
+    log_level = "warn"
+    denoise = true
+    asset_paths = ["~/textures", "/opt/luts"]
+    texture_cache_mb = 2048
+    memory_budget_mb = 16384
+ */
+#[derive(Debug, Default)]
+pub struct Settings {
+    // Worker threads for rendering; all cores by default
+    pub threads: Option<usize>,
+    // Directory that relative output paths are written under
+    pub output_dir: Option<PathBuf>,
+    pub log_level: Option<LevelFilter>,
+    // Whether images are denoised unless their scene sets how
+    pub denoise: Option<bool>,
+    // Directories searched for textures and colour configs not found next to the scene
+    pub asset_paths: Vec<PathBuf>,
+    // Most memory in megabytes taken by decoded texture images at once
+    pub texture_cache_mb: Option<usize>,
+    // Most memory in megabytes a scene is estimated to need before it is refused
+    pub memory_budget_mb: Option<usize>,
+}
+
+static ASSET_PATHS: OnceLock<Vec<PathBuf>> = OnceLock::new();
+
+static TEXTURE_CACHE_MB: OnceLock<usize> = OnceLock::new();
+
+const DEFAULT_TEXTURE_CACHE_MB: usize = 4096;
+
+static MEMORY_BUDGET_MB: OnceLock<usize> = OnceLock::new();
+
+// $RAYTRACER_CONFIG, or config.toml under the XDG config directory
+fn config_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("RAYTRACER_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_home.join("raytracer").join("config.toml"))
+}
+
+// Paths starting with ~ are relative to the home directory
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), env::var_os("HOME")) {
+        (Some(rest), Some(home)) => Path::new(&home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+// One of off, error, warn, info, debug or trace, in any case
+pub fn parse_log_level(text: &str) -> Result<LevelFilter, String> {
+    text.parse()
+        .map_err(|_| format!("unknown log level {text:?}"))
+}
+
+fn as_string(value: &Value) -> Result<&str, String> {
+    value
+        .as_str()
+        .ok_or(format!("expected a string, found {value}"))
+}
+
+impl Settings {
+    // Settings from the config file if there is one, overridden by the environment
+    pub fn load() -> Result<Settings, String> {
+        let mut settings = Settings::default();
+        if let Some(path) = config_path().filter(|path| path.is_file()) {
+            let text = std::fs::read_to_string(&path).map_err(|e| e.to_string())?;
+            settings
+                .read_toml(&text)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        settings.read_env()?;
+        Ok(settings)
+    }
+
+    fn read_toml(&mut self, text: &str) -> Result<(), String> {
+        let table: toml::Table = toml::from_str(text).map_err(|e| e.to_string())?;
+        for (key, value) in table {
+            let value = serde_json::to_value(value).map_err(|e| e.to_string())?;
+            self.set(&key, &value).map_err(|e| format!("{key}: {e}"))?;
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: &Value) -> Result<(), String> {
+        match key {
+            "threads" => {
+                let threads = value.as_u64().filter(|&threads| threads > 0);
+                self.threads = Some(threads.ok_or("threads must be a positive integer")? as usize);
+            }
+            "output_dir" => self.output_dir = Some(expand_home(as_string(value)?)),
+            "log_level" => self.log_level = Some(parse_log_level(as_string(value)?)?),
+            "denoise" => {
+                self.denoise = Some(value.as_bool().ok_or("denoise must be true or false")?)
+            }
+            "asset_paths" => {
+                let paths = value.as_array().ok_or("asset_paths must be an array")?;
+                self.asset_paths = paths
+                    .iter()
+                    .map(|path| as_string(path).map(expand_home))
+                    .collect::<Result<_, _>>()?;
+            }
+            "texture_cache_mb" => {
+                let megabytes = value.as_u64().filter(|&megabytes| megabytes > 0);
+                let megabytes = megabytes.ok_or("texture_cache_mb must be a positive integer")?;
+                self.texture_cache_mb = Some(megabytes as usize);
+            }
+            "memory_budget_mb" => {
+                let megabytes = value.as_u64().filter(|&megabytes| megabytes > 0);
+                let megabytes = megabytes.ok_or("memory_budget_mb must be a positive integer")?;
+                self.memory_budget_mb = Some(megabytes as usize);
+            }
+            _ => warn!("Ignoring unknown setting {key:?}"),
+        }
+        Ok(())
+    }
+
+    fn read_env(&mut self) -> Result<(), String> {
+        if let Ok(threads) = env::var("RAYTRACER_THREADS") {
+            let threads = threads.parse().ok().filter(|&threads| threads > 0);
+            self.threads = Some(threads.ok_or("RAYTRACER_THREADS must be a positive integer")?);
+        }
+        if let Some(dir) = env::var_os("RAYTRACER_OUTPUT_DIR") {
+            self.output_dir = Some(PathBuf::from(dir));
+        }
+        if let Ok(level) = env::var("RAYTRACER_LOG_LEVEL") {
+            self.log_level = Some(parse_log_level(&level)?);
+        }
+        if let Ok(denoise) = env::var("RAYTRACER_DENOISE") {
+            let denoise = match denoise.as_str() {
+                "1" | "true" => true,
+                "0" | "false" => false,
+                _ => return Err("RAYTRACER_DENOISE must be 1, 0, true or false".to_string()),
+            };
+            self.denoise = Some(denoise);
+        }
+        if let Ok(megabytes) = env::var("RAYTRACER_TEXTURE_CACHE_MB") {
+            let megabytes = megabytes.parse().ok().filter(|&megabytes| megabytes > 0);
+            let error = "RAYTRACER_TEXTURE_CACHE_MB must be a positive integer";
+            self.texture_cache_mb = Some(megabytes.ok_or(error)?);
+        }
+        if let Ok(megabytes) = env::var("RAYTRACER_MEMORY_BUDGET_MB") {
+            let megabytes = megabytes.parse().ok().filter(|&megabytes| megabytes > 0);
+            let error = "RAYTRACER_MEMORY_BUDGET_MB must be a positive integer";
+            self.memory_budget_mb = Some(megabytes.ok_or(error)?);
+        }
+        // Separated like PATH
+        if let Some(paths) = env::var_os("RAYTRACER_ASSET_PATH") {
+            self.asset_paths = env::split_paths(&paths).collect();
+        }
+        Ok(())
+    }
+}
+
+// Each of these can be set once per process, before rendering starts
+pub fn set_threads(threads: usize) -> Result<(), String> {
+    #[cfg(feature = "parallel")]
+    return rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build_global()
+        .map_err(|e| e.to_string());
+    #[cfg(not(feature = "parallel"))]
+    {
+        let _ = threads;
+        Ok(())
+    }
+}
+
+// Worker threads renders are split between
+pub fn threads() -> usize {
+    #[cfg(feature = "parallel")]
+    return rayon::current_num_threads();
+    #[cfg(not(feature = "parallel"))]
+    1
+}
+
+pub fn set_asset_paths(paths: Vec<PathBuf>) -> Result<(), String> {
+    ASSET_PATHS
+        .set(paths)
+        .map_err(|_| "the asset paths are already set".to_string())
+}
+
+pub fn set_texture_cache_mb(megabytes: usize) -> Result<(), String> {
+    TEXTURE_CACHE_MB
+        .set(megabytes)
+        .map_err(|_| "the texture cache size is already set".to_string())
+}
+
+// Budget for decoded texture images, after which the least recently used are dropped
+pub(crate) fn texture_cache_bytes() -> usize {
+    let megabytes = TEXTURE_CACHE_MB
+        .get()
+        .copied()
+        .unwrap_or(DEFAULT_TEXTURE_CACHE_MB);
+    megabytes.saturating_mul(1 << 20)
+}
+
+pub fn set_memory_budget_mb(megabytes: usize) -> Result<(), String> {
+    MEMORY_BUDGET_MB
+        .set(megabytes)
+        .map_err(|_| "the memory budget is already set".to_string())
+}
+
+// Most memory a scene may be estimated to need to be rendered; unlimited when unset
+pub fn memory_budget_bytes() -> Option<usize> {
+    MEMORY_BUDGET_MB
+        .get()
+        .map(|megabytes| megabytes.saturating_mul(1 << 20))
+}
+
+/*
+Where a file referred to by the scene is: next to the scene if it exists
+there, otherwise in the first asset search path that has it. Missing files
+resolve next to the scene so the error names the path the scene gave.
+ */
+pub fn find_asset(base_dir: &Path, path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    let local = base_dir.join(path);
+    if local.exists() || path.is_absolute() {
+        return local;
+    }
+    let search_paths = ASSET_PATHS.get().map(Vec::as_slice).unwrap_or_default();
+    search_paths
+        .iter()
+        .map(|dir| dir.join(path))
+        .find(|candidate| candidate.exists())
+        .unwrap_or(local)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_toml_settings() {
+        let mut settings = Settings::default();
+        let text = "\
+# Defaults for every render
+threads = 4  # half the cores
+log_level = 'warn'
+denoise = true
+asset_paths = [
+    \"/opt/textures\",
+    \"/opt/luts\",
+]
+texture_cache_mb = 2048
+";
+        settings.read_toml(text).unwrap();
+        assert_eq!(settings.threads, Some(4));
+        assert_eq!(settings.log_level, Some(LevelFilter::Warn));
+        assert_eq!(settings.denoise, Some(true));
+        let asset_paths = [PathBuf::from("/opt/textures"), PathBuf::from("/opt/luts")];
+        assert_eq!(settings.asset_paths, asset_paths);
+        assert_eq!(settings.texture_cache_mb, Some(2048));
+        assert_eq!(settings.memory_budget_mb, None);
+    }
+
+    #[test]
+    fn rejects_malformed_settings() {
+        for text in [
+            "threads = [4",
+            "threads = 0",
+            "denoise = \"yes\"",
+            "a = 1\na = 2",
+        ] {
+            assert!(Settings::default().read_toml(text).is_err(), "{text}");
+        }
+    }
+}
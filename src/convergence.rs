@@ -0,0 +1,54 @@
+use crate::compare::compare;
+use crate::render::Renderer;
+use crate::Scene;
+use image::DynamicImage;
+use std::error::Error;
+use std::io::Write;
+use std::time::Instant;
+
+// Columns of the table written for each render
+const HEADER: &str = "samples,seconds,mse,psnr,ssim";
+
+// Sample counts rendered: doubling from one up to the camera's own, which comes last
+fn sample_counts(samples: u32) -> Vec<u32> {
+    let mut counts: Vec<u32> = (0..32)
+        .map(|i| 1 << i)
+        .take_while(|&n| n < samples)
+        .collect();
+    counts.push(samples.max(1));
+    counts
+}
+
+/*
+Render the scene with more and more samples per pixel and write, as CSV, how
+far each image is from the reference and how long it took, to show how
+quickly a change to the integrator or sampler converges. Every render starts
+afresh with the scene's seed, so the timings are of whole renders. The
+reference is best rendered with far more samples, or by another renderer.
+ */
+pub fn run(
+    scene: &Scene,
+    reference: &DynamicImage,
+    output: &mut impl Write,
+) -> Result<(), Box<dyn Error>> {
+    writeln!(output, "{}", HEADER)?;
+    for samples in sample_counts(scene.camera.samples) {
+        let mut camera = scene.camera.clone();
+        camera.samples = samples;
+        let start = Instant::now();
+        let image = Renderer::with_camera(scene, camera).render();
+        let seconds = start.elapsed().as_secs_f64();
+        let comparison = compare(&DynamicImage::from(image), reference)?;
+        info!(
+            "{} samples in {:.2}s: PSNR {:.2} dB, SSIM {:.4}",
+            samples, seconds, comparison.psnr, comparison.ssim
+        );
+        writeln!(
+            output,
+            "{},{:.4},{:.6e},{:.3},{:.5}",
+            samples, seconds, comparison.mse, comparison.psnr, comparison.ssim
+        )?;
+        output.flush()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,175 @@
+use crate::bounds::Aabb;
+use crate::core::ray::{Intersection, Ray};
+use crate::primitives::intersect_solid;
+use crate::{Float, Shape};
+use serde::Deserialize;
+
+// Most surfaces of the children crossed along one ray before giving up
+const MAX_CROSSINGS: usize = 64;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum CsgOperation {
+    // Inside either shape
+    Union,
+    // Inside both shapes
+    Intersection,
+    // Inside the first shape but not the second
+    Difference,
+}
+
+impl CsgOperation {
+    fn combine(self, inside_a: bool, inside_b: bool) -> bool {
+        match self {
+            CsgOperation::Union => inside_a || inside_b,
+            CsgOperation::Intersection => inside_a && inside_b,
+            CsgOperation::Difference => inside_a && !inside_b,
+        }
+    }
+
+    // Box enclosing the combination of shapes in the boxes, None meaning unbounded
+    pub(crate) fn bounding_box(self, a: Option<Aabb>, b: Option<Aabb>) -> Option<Aabb> {
+        match (self, a, b) {
+            (CsgOperation::Union, Some(a), Some(b)) => Aabb::around([a.min, a.max, b.min, b.max]),
+            (CsgOperation::Union, _, _) => None,
+            (CsgOperation::Intersection, Some(a), Some(b)) => Some(Aabb {
+                min: a.min.sup(&b.min),
+                max: a.max.inf(&b.max),
+            }),
+            (CsgOperation::Intersection, a, b) => a.or(b),
+            (CsgOperation::Difference, a, _) => a,
+        }
+    }
+}
+
+/*
+Whether the point at distance t along the ray is inside the shape. Planes
+bound the half-space behind their normal. For closed shapes this is read off
+the next surface along the ray, which is an exit exactly when the point is
+inside.
+ */
+fn inside_at(shape: &Shape, ray: &Ray, t: Float) -> bool {
+    match shape {
+        Shape::Plane { point, normal } => normal.dot(&(ray.extend(t) - point)) < 0.0,
+        Shape::Csg { operation, a, b } => {
+            operation.combine(inside_at(a, ray, t), inside_at(b, ray, t))
+        }
+        _ => intersect_solid(shape, ray, t).is_some_and(|hit| hit.normal.dot(&ray.direction) > 0.0),
+    }
+}
+
+/*
+Nearest surface of the combined shapes past min_distance, found by walking
+the surfaces of both children along the ray in order and keeping track of
+which of them the ray is inside, until it passes into or out of the
+combination. The normal is turned to face out of the combination, so the
+walls of a hole cut by a difference face into the hole.
+ */
+pub(crate) fn intersect_csg(
+    operation: CsgOperation,
+    a: &Shape,
+    b: &Shape,
+    ray: &Ray,
+    min_distance: Float,
+) -> Option<Intersection> {
+    let children = [a, b];
+    let mut inside = children.map(|child| inside_at(child, ray, min_distance));
+    let mut next = children.map(|child| intersect_solid(child, ray, min_distance));
+    for _ in 0..MAX_CROSSINGS {
+        let was_inside = operation.combine(inside[0], inside[1]);
+        let nearer = match (&next[0], &next[1]) {
+            (None, None) => return None,
+            (Some(_), None) => 0,
+            (None, Some(_)) => 1,
+            (Some(hit_a), Some(hit_b)) => usize::from(hit_b.t < hit_a.t),
+        };
+        let mut hit = next[nearer].take()?;
+        // Surfaces of children face out of them, so the ray enters a child against its normal
+        let entering = hit.normal.dot(&ray.direction) < 0.0;
+        inside[nearer] = entering;
+        next[nearer] = intersect_solid(children[nearer], ray, hit.t);
+        let now_inside = operation.combine(inside[0], inside[1]);
+        if now_inside != was_inside {
+            if entering != now_inside {
+                hit.normal = -hit.normal;
+            }
+            return Some(hit);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FVec;
+
+    // Unit spheres about the origin and one unit along x, overlapping between x = 0 and x = 1
+    fn spheres() -> (Shape, Shape) {
+        let sphere = |x| Shape::Sphere {
+            centre: FVec::new(x, 0.0, 0.0),
+            radius: 1.0,
+        };
+        (sphere(0.0), sphere(1.0))
+    }
+
+    fn ray_along_x(from: Float) -> Ray {
+        Ray {
+            origin: FVec::new(from, 0.0, 0.0),
+            direction: FVec::new(1.0, 0.0, 0.0),
+            differential: None,
+            time: 0.0,
+        }
+    }
+
+    /*
+    Distances along the ray and x components of the normals where it enters
+    and leaves the combination, from a ray along the x axis from x = -5.
+     */
+    fn entry_and_exit(operation: CsgOperation) -> ((Float, Float), (Float, Float)) {
+        let (a, b) = spheres();
+        let ray = ray_along_x(-5.0);
+        let entry = intersect_csg(operation, &a, &b, &ray, 0.0).expect("the ray enters");
+        let exit = intersect_csg(operation, &a, &b, &ray, entry.t).expect("the ray leaves");
+        assert!(intersect_csg(operation, &a, &b, &ray, exit.t).is_none());
+        ((entry.t, entry.normal.x), (exit.t, exit.normal.x))
+    }
+
+    fn assert_close(found: (Float, Float), expected: (Float, Float)) {
+        let close = (found.0 - expected.0).abs() < 1e-6 && (found.1 - expected.1).abs() < 1e-6;
+        assert!(close, "found {found:?}, expected {expected:?}");
+    }
+
+    #[test]
+    fn union_spans_both_spheres() {
+        let (entry, exit) = entry_and_exit(CsgOperation::Union);
+        assert_close(entry, (4.0, -1.0));
+        assert_close(exit, (7.0, 1.0));
+    }
+
+    #[test]
+    fn intersection_spans_the_overlap() {
+        let (entry, exit) = entry_and_exit(CsgOperation::Intersection);
+        assert_close(entry, (5.0, -1.0));
+        assert_close(exit, (6.0, 1.0));
+    }
+
+    #[test]
+    fn difference_leaves_through_the_wall_of_the_hole() {
+        // The wall is the second sphere's surface, turned to face into the hole
+        let (entry, exit) = entry_and_exit(CsgOperation::Difference);
+        assert_close(entry, (4.0, -1.0));
+        assert_close(exit, (5.0, 1.0));
+    }
+
+    #[test]
+    fn ray_starting_inside_a_difference_first_meets_its_exit() {
+        let (a, b) = spheres();
+        let ray = ray_along_x(-0.5);
+        let hit = intersect_csg(CsgOperation::Difference, &a, &b, &ray, 0.0).unwrap();
+        assert_close((hit.t, hit.normal.x), (0.5, 1.0));
+        assert!(hit.normal.dot(&ray.direction) > 0.0);
+        // Beyond the hole the ray is outside the first sphere, so never enters the difference again
+        assert!(intersect_csg(CsgOperation::Difference, &a, &b, &ray, hit.t).is_none());
+    }
+}
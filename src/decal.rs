@@ -0,0 +1,143 @@
+use crate::colour::ColourPipeline;
+use crate::light::coordinate_system;
+use crate::texture::Texture;
+use crate::{FVec, Float, UP};
+use nalgebra::Matrix3;
+use serde::Deserialize;
+use std::error::Error;
+use std::path::Path;
+
+/*
+A projector laying a texture over the surfaces in front of it, like a slide
+on a wall, for labels, posters and dirt. It projects straight along its
+direction through a box the width and height of the image, or from a point
+through a frustum when it has a field of view, out to its depth. The image
+is blended over the colour of the surfaces facing it by its opacity and by
+its mask, so only the shape of the decal shows; the rest of the material,
+such as how shiny it is, is left as it was. Nothing blocks a projector, so
+an object in front of another does not shade it from the decal; list the
+objects it should land on to keep it off those behind.
+ */
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Decal {
+    pub(crate) colour: Texture,
+    // Where the decal covers, as the brightness of a data texture; all of the image when unset
+    pub(crate) mask: Option<Texture>,
+    #[serde(default = "default_opacity")]
+    pub(crate) opacity: Float,
+    pub(crate) position: FVec,
+    pub(crate) direction: FVec,
+    // Towards the top of the image; the z axis by default
+    pub(crate) up: Option<FVec>,
+    // Size of the image as it leaves the projector; only its shape with a field of view
+    pub(crate) width: Float,
+    pub(crate) height: Float,
+    // How far along the direction the decal reaches
+    #[serde(default = "default_depth")]
+    pub(crate) depth: Float,
+    // Vertical angle in degrees of a projector spreading from its position
+    pub(crate) field_of_view: Option<Float>,
+    // Objects by index, name or tag that are the only ones the decal lands on; all when unset
+    pub(crate) objects: Option<Vec<usize>>,
+}
+
+fn default_opacity() -> Float {
+    1.0
+}
+
+fn default_depth() -> Float {
+    Float::INFINITY
+}
+
+impl Decal {
+    pub(crate) fn load_textures(
+        &mut self,
+        base_dir: &Path,
+        colour: &ColourPipeline,
+    ) -> Result<(), Box<dyn Error>> {
+        self.colour.load(base_dir, colour)?;
+        if let Some(mask) = &mut self.mask {
+            mask.load_data(base_dir, colour)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn textures(&self) -> impl Iterator<Item = &Texture> {
+        std::iter::once(&self.colour).chain(&self.mask)
+    }
+
+    // Turned into another set of axes by a matrix that keeps lengths
+    pub(crate) fn reorient(&mut self, axes: &Matrix3<Float>) {
+        self.position = axes * self.position;
+        self.direction = axes * self.direction;
+        self.up = self.up.map(|up| axes * up);
+    }
+
+    pub(crate) fn scale(&mut self, factor: Float) {
+        self.position *= factor;
+        self.depth *= factor;
+        if self.field_of_view.is_none() {
+            self.width *= factor;
+            self.height *= factor;
+        }
+    }
+
+    // Whether the decal may land on the object at the index
+    pub(crate) fn covers_object(&self, object: usize) -> bool {
+        self.objects
+            .as_ref()
+            .is_none_or(|objects| objects.contains(&object))
+    }
+
+    // The unit direction and the unit vectors to the right of and up the image
+    fn basis(&self) -> (FVec, FVec, FVec) {
+        let forward = self.direction.normalize();
+        let right = match forward.cross(&self.up.unwrap_or(UP)).try_normalize(1e-9) {
+            Some(right) => right,
+            None => coordinate_system(&forward).0,
+        };
+        (forward, right, right.cross(&forward))
+    }
+
+    // Coordinates in the image of a point with the outward normal there, if the decal reaches it
+    fn image_uv(&self, pos: &FVec, normal: &FVec) -> Option<(Float, Float)> {
+        let (forward, right, up) = self.basis();
+        let offset = pos - self.position;
+        let distance = offset.dot(&forward);
+        if distance < 0.0 || distance > self.depth {
+            return None;
+        }
+        let (width, height) = match self.field_of_view {
+            Some(angle) => {
+                let height = 2.0 * distance * (angle.to_radians() / 2.0).tan();
+                (height * self.width / self.height, height)
+            }
+            None => (self.width, self.height),
+        };
+        let u = 0.5 + offset.dot(&right) / width;
+        let v = 0.5 - offset.dot(&up) / height;
+        if !(0.0..=1.0).contains(&u) || !(0.0..=1.0).contains(&v) {
+            return None;
+        }
+        // Only surfaces turned towards the projector take the decal, not those behind them
+        let towards = match self.field_of_view {
+            Some(_) => -offset,
+            None => -forward,
+        };
+        (normal.dot(&towards) > 0.0).then_some((u, v))
+    }
+
+    // The colour of a surface at a point with the decal over it
+    pub(crate) fn apply(&self, albedo: FVec, pos: &FVec, normal: &FVec) -> FVec {
+        let Some(uv) = self.image_uv(pos, normal) else {
+            return albedo;
+        };
+        let coverage = match &self.mask {
+            Some(mask) => mask.at(uv, None, pos, normal, None).mean(),
+            None => 1.0,
+        };
+        let alpha = (self.opacity * coverage).clamp(0.0, 1.0);
+        albedo.lerp(&self.colour.at(uv, None, pos, normal, None), alpha)
+    }
+}
@@ -0,0 +1,284 @@
+use crate::mesh::Triangle;
+use crate::{FVec, Float};
+use nalgebra::{Matrix3, Matrix4, Vector4};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+// How much more an open edge resists moving off its line than a face does off its plane
+const BOUNDARY_WEIGHT: Float = 100.0;
+
+// An edge that could be collapsed, and the squared error of collapsing it
+struct Candidate {
+    cost: Float,
+    position: FVec,
+    edge: (usize, usize),
+    // Versions of the two vertices when the cost was worked out
+    versions: (u32, u32),
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Candidate) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Candidate) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Reversed, so the heap yields the cheapest collapse first
+impl Ord for Candidate {
+    fn cmp(&self, other: &Candidate) -> Ordering {
+        other.cost.total_cmp(&self.cost)
+    }
+}
+
+/*
+The mesh as vertices shared between faces, with the error quadric of every
+vertex. Vertices are shared where positions are identical.
+ */
+struct Decimator {
+    positions: Vec<FVec>,
+    quadrics: Vec<Matrix4<Float>>,
+    versions: Vec<u32>,
+    // Faces around each vertex, which may include removed ones
+    faces_at: Vec<Vec<usize>>,
+    faces: Vec<[usize; 3]>,
+    removed: Vec<bool>,
+    live_faces: usize,
+}
+
+// Quadric measuring the squared distance from a plane through a point
+fn plane_quadric(normal: &FVec, point: &FVec, weight: Float) -> Matrix4<Float> {
+    let plane = Vector4::new(normal.x, normal.y, normal.z, -normal.dot(point));
+    plane * plane.transpose() * weight
+}
+
+fn error(quadric: &Matrix4<Float>, position: &FVec) -> Float {
+    let point = position.push(1.0);
+    (point.transpose() * quadric * point)[0].max(0.0)
+}
+
+impl Decimator {
+    fn new(triangles: &[Triangle]) -> Decimator {
+        let key = |v: &FVec| [v.x, v.y, v.z].map(Float::to_bits);
+        let mut indices: HashMap<_, usize> = HashMap::new();
+        let mut positions = Vec::new();
+        let faces: Vec<[usize; 3]> = triangles
+            .iter()
+            .map(|triangle| {
+                triangle.vertices.map(|vertex| {
+                    *indices.entry(key(&vertex)).or_insert_with(|| {
+                        positions.push(vertex);
+                        positions.len() - 1
+                    })
+                })
+            })
+            .collect();
+        let mut quadrics = vec![Matrix4::zeros(); positions.len()];
+        let mut faces_at = vec![Vec::new(); positions.len()];
+        // Faces on each edge, to find the open edges
+        let mut edge_faces: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+        for (index, face) in faces.iter().enumerate() {
+            let [p0, p1, p2] = face.map(|v| positions[v]);
+            let cross = (p1 - p0).cross(&(p2 - p0));
+            let area = cross.norm() / 2.0;
+            if let Some(normal) = cross.try_normalize(0.0) {
+                let quadric = plane_quadric(&normal, &p0, area);
+                face.iter().for_each(|&v| quadrics[v] += quadric);
+            }
+            for (corner, &v) in face.iter().enumerate() {
+                faces_at[v].push(index);
+                let next = face[(corner + 1) % 3];
+                edge_faces
+                    .entry((v.min(next), v.max(next)))
+                    .or_default()
+                    .push(index);
+            }
+        }
+        for (&(a, b), on_edge) in &edge_faces {
+            let [face] = on_edge.as_slice() else {
+                continue;
+            };
+            let [p0, p1, p2] = faces[*face].map(|v| positions[v]);
+            let face_normal = (p1 - p0).cross(&(p2 - p0));
+            let edge = positions[b] - positions[a];
+            // A plane through the edge, upright on the face, keeps the edge where it is
+            if let Some(normal) = edge.cross(&face_normal).try_normalize(0.0) {
+                let quadric = plane_quadric(
+                    &normal,
+                    &positions[a],
+                    BOUNDARY_WEIGHT * edge.norm_squared(),
+                );
+                quadrics[a] += quadric;
+                quadrics[b] += quadric;
+            }
+        }
+        Decimator {
+            versions: vec![0; positions.len()],
+            removed: vec![false; faces.len()],
+            live_faces: faces.len(),
+            positions,
+            quadrics,
+            faces_at,
+            faces,
+        }
+    }
+
+    fn live_faces_at(&self, vertex: usize) -> impl Iterator<Item = usize> + '_ {
+        self.faces_at[vertex]
+            .iter()
+            .copied()
+            .filter(|&face| !self.removed[face])
+    }
+
+    fn neighbours(&self, vertex: usize) -> Vec<usize> {
+        let mut neighbours: Vec<usize> = self
+            .live_faces_at(vertex)
+            .flat_map(|face| self.faces[face])
+            .filter(|&v| v != vertex)
+            .collect();
+        neighbours.sort_unstable();
+        neighbours.dedup();
+        neighbours
+    }
+
+    /*
+    Where collapsing an edge should leave the merged vertex: the point of least
+    error where the summed quadric has one, or else the better of the ends and
+    the middle of the edge.
+     */
+    fn candidate(&self, a: usize, b: usize) -> Candidate {
+        let quadric = self.quadrics[a] + self.quadrics[b];
+        let linear: Matrix3<Float> = quadric.fixed_view::<3, 3>(0, 0).into();
+        let optimum = linear
+            .try_inverse()
+            .map(|inverse| -(inverse * quadric.fixed_view::<3, 1>(0, 3)));
+        let (pa, pb) = (self.positions[a], self.positions[b]);
+        let (cost, position) = [Some(pa), Some(pb), Some((pa + pb) / 2.0), optimum]
+            .into_iter()
+            .flatten()
+            .filter(|position| position.iter().all(|c| c.is_finite()))
+            .map(|position| (error(&quadric, &position), position))
+            .min_by(|(x, _), (y, _)| x.total_cmp(y))
+            .unwrap_or((0.0, pa));
+        Candidate {
+            cost,
+            position,
+            edge: (a, b),
+            versions: (self.versions[a], self.versions[b]),
+        }
+    }
+
+    /*
+    Whether merging b into a at the position keeps the surface as it was
+    connected, without pinching it where a and b share more neighbours than
+    the faces between them, and without turning any face over.
+     */
+    fn can_collapse(&self, a: usize, b: usize, position: &FVec) -> bool {
+        let shared_faces = self
+            .live_faces_at(a)
+            .filter(|&face| self.faces[face].contains(&b))
+            .count();
+        let neighbours_a = self.neighbours(a);
+        let shared_neighbours = self
+            .neighbours(b)
+            .iter()
+            .filter(|v| neighbours_a.binary_search(v).is_ok())
+            .count();
+        if shared_neighbours != shared_faces {
+            return false;
+        }
+        [a, b].iter().all(|&moved| {
+            self.live_faces_at(moved)
+                .filter(|&face| !self.faces[face].contains(&a) || !self.faces[face].contains(&b))
+                .all(|face| {
+                    let before = self.faces[face].map(|v| self.positions[v]);
+                    let after = self.faces[face].map(|v| {
+                        if v == moved {
+                            *position
+                        } else {
+                            self.positions[v]
+                        }
+                    });
+                    let normal = |[p0, p1, p2]: [FVec; 3]| (p1 - p0).cross(&(p2 - p0));
+                    let (before, after) = (normal(before), normal(after));
+                    after.dot(&before) > 0.0
+                })
+        })
+    }
+
+    fn collapse(&mut self, a: usize, b: usize, position: FVec) {
+        self.positions[a] = position;
+        let quadric = self.quadrics[b];
+        self.quadrics[a] += quadric;
+        self.versions[a] += 1;
+        self.versions[b] += 1;
+        for face in std::mem::take(&mut self.faces_at[b]) {
+            if self.removed[face] {
+                continue;
+            }
+            if self.faces[face].contains(&a) {
+                self.removed[face] = true;
+                self.live_faces -= 1;
+            } else {
+                self.faces[face]
+                    .iter_mut()
+                    .filter(|v| **v == b)
+                    .for_each(|v| *v = a);
+                self.faces_at[a].push(face);
+            }
+        }
+    }
+}
+
+/*
+Collapse edges of the mesh, cheapest first by quadric error, until at most
+target triangles remain or no edge can be collapsed without folding the
+surface. Corners keep their own normals, colours and texture coordinates.
+ */
+pub fn decimate(triangles: Vec<Triangle>, target: usize) -> Vec<Triangle> {
+    if triangles.len() <= target {
+        return triangles;
+    }
+    let mut decimator = Decimator::new(&triangles);
+    let edges: HashSet<(usize, usize)> = decimator
+        .faces
+        .iter()
+        .flat_map(|face| (0..3).map(move |corner| (face[corner], face[(corner + 1) % 3])))
+        .map(|(a, b)| (a.min(b), a.max(b)))
+        .collect();
+    let mut heap: BinaryHeap<Candidate> = edges
+        .into_iter()
+        .map(|(a, b)| decimator.candidate(a, b))
+        .collect();
+    while decimator.live_faces > target {
+        let Some(candidate) = heap.pop() else {
+            break;
+        };
+        let (a, b) = candidate.edge;
+        if candidate.versions != (decimator.versions[a], decimator.versions[b])
+            || !decimator.can_collapse(a, b, &candidate.position)
+        {
+            continue;
+        }
+        decimator.collapse(a, b, candidate.position);
+        for neighbour in decimator.neighbours(a) {
+            heap.push(decimator.candidate(a, neighbour));
+        }
+    }
+    triangles
+        .into_iter()
+        .zip(&decimator.faces)
+        .zip(&decimator.removed)
+        .filter(|(_, &removed)| !removed)
+        .map(|((triangle, face), _)| Triangle {
+            vertices: face.map(|v| decimator.positions[v]),
+            ..triangle
+        })
+        .collect()
+}
@@ -0,0 +1,153 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+const MAGIC: i32 = 20000630;
+// Format version 2 with the flag marking deep ("non-image") data
+const VERSION: i32 = 2 | 0x800;
+const PIXEL_TYPE_FLOAT: i32 = 2;
+// Channel names in the alphabetical order the format requires
+const CHANNELS: [&str; 5] = ["A", "B", "G", "R", "Z"];
+
+// One surface fragment of a deep pixel, with colour premultiplied by alpha
+#[derive(Debug, Clone, Copy)]
+pub struct DeepSample {
+    pub depth: f32,
+    pub colour: [f32; 3],
+    pub alpha: f32,
+}
+
+impl DeepSample {
+    fn channel(&self, index: usize) -> f32 {
+        match index {
+            0 => self.alpha,
+            1 => self.colour[2],
+            2 => self.colour[1],
+            3 => self.colour[0],
+            _ => self.depth,
+        }
+    }
+}
+
+/*
+Write an uncompressed single-part deep scanline OpenEXR image. Pixels are
+given row by row, each with any number of samples sorted front to back. The
+outermost `overscan` pixels on each side lie outside the display window.
+ */
+pub fn write_deep_exr(
+    path: &str,
+    width: u32,
+    height: u32,
+    overscan: u32,
+    pixels: &[Vec<DeepSample>],
+) -> io::Result<()> {
+    let mut header = Vec::new();
+    write_header(&mut header, width, height, overscan, pixels);
+
+    let rows: Vec<&[Vec<DeepSample>]> = pixels.chunks(width as usize).collect();
+    let chunks: Vec<Vec<u8>> = rows
+        .iter()
+        .zip(0..)
+        .map(|(row, y)| scanline_chunk(y - overscan as i32, row))
+        .collect();
+
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(&MAGIC.to_le_bytes())?;
+    out.write_all(&VERSION.to_le_bytes())?;
+    out.write_all(&header)?;
+    // The offset table gives the file position of every chunk
+    let mut offset = (8 + header.len() + 8 * chunks.len()) as u64;
+    for chunk in &chunks {
+        out.write_all(&offset.to_le_bytes())?;
+        offset += chunk.len() as u64;
+    }
+    for chunk in &chunks {
+        out.write_all(chunk)?;
+    }
+    out.flush()
+}
+
+fn write_header(
+    out: &mut Vec<u8>,
+    width: u32,
+    height: u32,
+    overscan: u32,
+    pixels: &[Vec<DeepSample>],
+) {
+    let mut channels = Vec::new();
+    for name in CHANNELS {
+        channels.extend_from_slice(name.as_bytes());
+        channels.push(0);
+        channels.extend_from_slice(&PIXEL_TYPE_FLOAT.to_le_bytes());
+        // Perceptually linear flag and three reserved bytes
+        channels.extend_from_slice(&[0; 4]);
+        channels.extend_from_slice(&1i32.to_le_bytes());
+        channels.extend_from_slice(&1i32.to_le_bytes());
+    }
+    channels.push(0);
+
+    let box2i =
+        |corners: [i32; 4]| -> Vec<u8> { corners.iter().flat_map(|v| v.to_le_bytes()).collect() };
+    let (width, height, overscan) = (width as i32, height as i32, overscan as i32);
+    let data_window = box2i([
+        -overscan,
+        -overscan,
+        width - overscan - 1,
+        height - overscan - 1,
+    ]);
+    let display_window = box2i([0, 0, width - 2 * overscan - 1, height - 2 * overscan - 1]);
+    let max_samples = pixels.iter().map(Vec::len).max().unwrap_or(0) as i32;
+
+    attribute(out, "channels", "chlist", &channels);
+    attribute(out, "chunkCount", "int", &height.to_le_bytes());
+    // No compression
+    attribute(out, "compression", "compression", &[0]);
+    attribute(out, "dataWindow", "box2i", &data_window);
+    attribute(out, "displayWindow", "box2i", &display_window);
+    // Increasing y
+    attribute(out, "lineOrder", "lineOrder", &[0]);
+    attribute(out, "maxSamplesPerPixel", "int", &max_samples.to_le_bytes());
+    attribute(out, "name", "string", b"deep");
+    attribute(out, "pixelAspectRatio", "float", &1f32.to_le_bytes());
+    attribute(out, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(out, "screenWindowWidth", "float", &1f32.to_le_bytes());
+    attribute(out, "type", "string", b"deepscanline");
+    attribute(out, "version", "int", &1i32.to_le_bytes());
+    out.push(0);
+}
+
+fn attribute(out: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    out.extend_from_slice(name.as_bytes());
+    out.push(0);
+    out.extend_from_slice(kind.as_bytes());
+    out.push(0);
+    out.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    out.extend_from_slice(value);
+}
+
+/*
+One scanline: the cumulative sample count of each pixel, then for every
+channel in turn all samples of every pixel.
+ */
+fn scanline_chunk(y: i32, row: &[Vec<DeepSample>]) -> Vec<u8> {
+    let mut counts = Vec::new();
+    let mut total = 0;
+    for pixel in row {
+        total += pixel.len() as i32;
+        counts.extend_from_slice(&total.to_le_bytes());
+    }
+    let mut data = Vec::new();
+    for channel in 0..CHANNELS.len() {
+        for sample in row.iter().flatten() {
+            data.extend_from_slice(&sample.channel(channel).to_le_bytes());
+        }
+    }
+    let mut chunk = Vec::new();
+    chunk.extend_from_slice(&y.to_le_bytes());
+    chunk.extend_from_slice(&(counts.len() as u64).to_le_bytes());
+    // Packed and unpacked sizes match without compression
+    chunk.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    chunk.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    chunk.extend_from_slice(&counts);
+    chunk.extend_from_slice(&data);
+    chunk
+}
@@ -0,0 +1,167 @@
+use crate::gbuffer::GBuffer;
+use crate::render::LinearImage;
+use crate::{FVec, Float};
+use image::Rgb;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use serde::Deserialize;
+
+// Weights of the 5x5 B3 spline kernel the filter spreads out over its passes, along each axis
+const KERNEL: [Float; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/*
+Smoothing of the noise of renders with few samples, before tone mapping. The
+filter (edge-avoiding à-trous wavelets, Dammertz et al. 2010) averages each
+pixel with others further away on every pass, but less with those whose
+normal, albedo, depth or colour differ, so edges and silhouettes stay sharp.
+It smooths the lighting rather than the colour, dividing out the albedo and
+multiplying it back after, so textures keep their detail. Only the main
+image is denoised; extra outputs keep their own samples.
+ */
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Denoise {
+    // Each pass reaches twice as far as the one before; five reach about 60 pixels
+    #[serde(default = "default_passes")]
+    pub(crate) passes: u32,
+    // How different colours may be and still be averaged, halving with each pass
+    #[serde(default = "default_colour_sigma")]
+    pub(crate) colour_sigma: Float,
+    #[serde(default = "default_normal_sigma")]
+    pub(crate) normal_sigma: Float,
+    #[serde(default = "default_albedo_sigma")]
+    pub(crate) albedo_sigma: Float,
+    // As a fraction of the depth of the pixel being filtered
+    #[serde(default = "default_depth_sigma")]
+    pub(crate) depth_sigma: Float,
+}
+
+fn default_passes() -> u32 {
+    5
+}
+
+fn default_colour_sigma() -> Float {
+    0.5
+}
+
+fn default_normal_sigma() -> Float {
+    0.3
+}
+
+fn default_albedo_sigma() -> Float {
+    0.1
+}
+
+fn default_depth_sigma() -> Float {
+    0.05
+}
+
+// The surface seen in each pixel, averaged over its samples; zero where they miss
+struct Guide {
+    normal: FVec,
+    albedo: FVec,
+    depth: Float,
+}
+
+fn guides(gbuffer: &GBuffer) -> Vec<Guide> {
+    let mut guides: Vec<Guide> = (0..gbuffer.width * gbuffer.height)
+        .map(|_| Guide {
+            normal: FVec::zeros(),
+            albedo: FVec::zeros(),
+            depth: 0.0,
+        })
+        .collect();
+    for pixel in &gbuffer.pixels {
+        let (x, y) = (pixel.x - gbuffer.origin.0, pixel.y - gbuffer.origin.1);
+        let guide = &mut guides[(y * gbuffer.width + x) as usize];
+        let hits = pixel
+            .samples
+            .iter()
+            .filter_map(|sample| sample.hit.as_ref());
+        for hit in hits {
+            guide.normal += hit.intersection.normal;
+            guide.albedo += hit.albedo;
+            guide.depth += hit.depth;
+        }
+        let count = pixel.samples.len().max(1) as Float;
+        guide.normal /= count;
+        guide.albedo /= count;
+        guide.depth /= count;
+    }
+    guides
+}
+
+// The albedo divided out of a colour, where there is one to divide by
+fn demodulate(colour: FVec, albedo: &FVec) -> FVec {
+    colour.zip_map(albedo, |c, a| if a > 1e-3 { c / a } else { c })
+}
+
+fn remodulate(lighting: FVec, albedo: &FVec) -> FVec {
+    lighting.zip_map(albedo, |l, a| if a > 1e-3 { l * a } else { l })
+}
+
+// Compressed so bright and dark colours are compared alike
+fn compress(colour: &FVec) -> FVec {
+    colour.map(|c| c.max(0.0) / (1.0 + c.max(0.0)))
+}
+
+impl Denoise {
+    // The image with its noise smoothed, guided by the first hits it was shaded from
+    pub(crate) fn apply(&self, image: &LinearImage, gbuffer: &GBuffer) -> LinearImage {
+        let (width, height) = (image.width() as i64, image.height() as i64);
+        let guides = guides(gbuffer);
+        let mut lighting: Vec<FVec> = image
+            .pixels()
+            .zip(&guides)
+            .map(|(pixel, guide)| demodulate(FVec::from(pixel.0), &guide.albedo))
+            .collect();
+        for pass in 0..self.passes {
+            let step = 1i64 << pass;
+            let colour_sigma = self.colour_sigma / (1 << pass) as Float;
+            let filter_row = |y: i64| {
+                let row = (0..width).map(|x| {
+                    let index = (y * width + x) as usize;
+                    let (guide, colour) = (&guides[index], compress(&lighting[index]));
+                    let mut total = FVec::zeros();
+                    let mut weights = 0.0;
+                    for (dy, ky) in (-2..=2).zip(KERNEL) {
+                        for (dx, kx) in (-2..=2).zip(KERNEL) {
+                            let (qx, qy) = (x + dx * step, y + dy * step);
+                            if !(0..width).contains(&qx) || !(0..height).contains(&qy) {
+                                continue;
+                            }
+                            let other = (qy * width + qx) as usize;
+                            let q = &guides[other];
+                            let depth_scale = self.depth_sigma * guide.depth.max(1e-6);
+                            let distance = (colour - compress(&lighting[other])).norm_squared()
+                                / (colour_sigma * colour_sigma)
+                                + (guide.normal - q.normal).norm_squared()
+                                    / (self.normal_sigma * self.normal_sigma)
+                                + (guide.albedo - q.albedo).norm_squared()
+                                    / (self.albedo_sigma * self.albedo_sigma)
+                                + ((guide.depth - q.depth) / depth_scale).powi(2);
+                            let weight = kx * ky * (-distance).exp();
+                            total += weight * lighting[other];
+                            weights += weight;
+                        }
+                    }
+                    total / weights
+                });
+                row.collect::<Vec<_>>()
+            };
+            #[cfg(feature = "parallel")]
+            let filtered = (0..height)
+                .into_par_iter()
+                .flat_map_iter(filter_row)
+                .collect();
+            #[cfg(not(feature = "parallel"))]
+            let filtered = (0..height).flat_map(filter_row).collect();
+            lighting = filtered;
+        }
+        let mut denoised = LinearImage::new(image.width(), image.height());
+        for ((pixel, lighting), guide) in denoised.pixels_mut().zip(lighting).zip(&guides) {
+            *pixel = Rgb(remodulate(lighting, &guide.albedo).into());
+        }
+        denoised
+    }
+}
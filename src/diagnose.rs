@@ -0,0 +1,412 @@
+use crate::bounds::Aabb;
+use crate::core::ray::{Intersection, Ray};
+use crate::light::{LightSample, LightSource};
+use crate::scene::describe;
+use crate::studio::Studio;
+use crate::validate::SceneProblem;
+use crate::wireframe::draw_line;
+use crate::{save_image, Camera, FVec, Float, Material, Renderer, Scene, UP};
+use image::{DynamicImage, ImageError, Rgb};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/*
+A small image for working out why a scene renders nothing to see: the
+scene from a camera framing all of it, the scene's camera and lights
+included, with every object in the same grey clay lit by a studio so its
+shapes show whatever its own lighting and materials. Over it are drawn the
+scene's camera as the pyramid it sees, each of its lights as a cross and
+the box around any object the camera is inside, in red where they are
+likely why the render is dark, as the reasons found are told in words.
+ */
+const COLUMNS: u32 = 320;
+const ROWS: u32 = 240;
+
+// Rays across and down the scene's view when looking for what it sees
+const PROBES: (u32, u32) = (16, 12);
+
+const FINE: Rgb<u8> = Rgb([80, 220, 80]);
+const LIGHT: Rgb<u8> = Rgb([255, 220, 60]);
+const CULPRIT: Rgb<u8> = Rgb([255, 40, 40]);
+
+// What the scene's camera sees, and what of that is likely wrong
+struct Diagnosis {
+    problems: Vec<String>,
+    camera_blind: bool,
+    // The object the camera looks out of, seeing the back of its surface
+    inside: Option<usize>,
+    dark_lights: Vec<usize>,
+}
+
+impl Scene {
+    /*
+    Write the diagnostic image to the path, returning the likely problems
+    it marks.
+     */
+    pub fn write_diagnostic(&self, path: &str) -> Result<Vec<String>, ImageError> {
+        let diagnosis = self.diagnose();
+        let (scene, camera) = self.diagnostic_scene();
+        let mut image = Renderer::with_camera(&scene, camera.clone()).render();
+        let mut draw = |a: &FVec, b: &FVec, colour: Rgb<u8>| {
+            if let Some((from, to)) = camera.project_segment(a, b) {
+                draw_line(&mut image, from, to, colour);
+            }
+        };
+        let size = 0.05 * scene_radius(&self.framed_bounds());
+        // The scene's camera as the corners of its view a little way in front of it
+        let seen = &self.camera;
+        let colour = if diagnosis.camera_blind {
+            CULPRIT
+        } else {
+            FINE
+        };
+        let (columns, rows) = (seen.film_columns() as Float, seen.film_rows() as Float);
+        let corners: Vec<FVec> = [(0.0, 0.0), (columns, 0.0), (columns, rows), (0.0, rows)]
+            .into_iter()
+            .map(|(x, y)| seen.position + 4.0 * size * seen.get_ray(x, y).direction)
+            .collect();
+        for (index, corner) in corners.iter().enumerate() {
+            draw(&seen.position, corner, colour);
+            draw(corner, &corners[(index + 1) % corners.len()], colour);
+        }
+        for (index, light) in self.lights.iter().enumerate() {
+            let colour = if diagnosis.dark_lights.contains(&index) {
+                CULPRIT
+            } else {
+                LIGHT
+            };
+            // Distant lights are drawn a little way from the diagnostic camera towards them
+            let centre = if light.is_distant() {
+                camera.position + 8.0 * size * towards(light, &camera.position)
+            } else {
+                light.centre()
+            };
+            for axis in [FVec::x(), FVec::y(), FVec::z()] {
+                draw(&(centre - size * axis), &(centre + size * axis), colour);
+            }
+        }
+        if let Some(bounds) = diagnosis
+            .inside
+            .and_then(|i| self.objects[i].bounding_box())
+        {
+            for (a, b) in box_edges(&bounds) {
+                draw(&a, &b, CULPRIT);
+            }
+        }
+        save_image(DynamicImage::from(image), path)?;
+        Ok(diagnosis.problems)
+    }
+
+    /*
+    Reasons the scene's camera may see nothing lit: it is inside an object
+    or looking away from them all, nothing lights the scene, or a light
+    reaches none of what the camera sees, being behind it or blocked.
+     */
+    fn diagnose(&self) -> Diagnosis {
+        let mut problems = Vec::new();
+        let camera = &self.camera;
+        let (columns, rows) = (camera.film_columns() as Float, camera.film_rows() as Float);
+        let mut hits: Vec<(usize, Intersection, FVec)> = Vec::new();
+        for (i, j) in (0..PROBES.0).flat_map(|i| (0..PROBES.1).map(move |j| (i, j))) {
+            let x = (i as Float + 0.5) / PROBES.0 as Float * columns;
+            let y = (j as Float + 0.5) / PROBES.1 as Float * rows;
+            let ray = camera.get_ray(x, y);
+            if let Some((object, hit)) = self._get_nearest_hit(&ray, 0.0, None) {
+                hits.push((object, hit, ray.direction));
+            }
+        }
+        let probes = (PROBES.0 * PROBES.1) as usize;
+        let shown = self
+            .objects
+            .iter()
+            .filter(|object| !object.hidden && !object.degenerate);
+        let camera_blind = match shown.count() {
+            0 => {
+                problems.push("the scene has no objects to see".to_string());
+                true
+            }
+            count if hits.is_empty() => {
+                let message = format!(
+                    "the {} sees none of the {count} objects; check where it looks",
+                    camera.describe()
+                );
+                problems.push(message);
+                true
+            }
+            _ => false,
+        };
+        // Most of the view being the back of one surface puts the camera inside or behind it
+        let backs = |object: usize| {
+            let facing_away = |(o, hit, direction): &&(usize, Intersection, FVec)| {
+                *o == object && hit.normal.dot(direction) > 0.0
+            };
+            hits.iter().filter(facing_away).count()
+        };
+        let inside = (0..self.objects.len()).find(|&object| 2 * backs(object) > probes);
+        if let Some(object) = inside {
+            problems.push(format!(
+                "the {} is inside or behind {}, seeing the back of its surface",
+                camera.describe(),
+                self.objects[object].describe(object)
+            ));
+        }
+        let unlit = self.lights.is_empty()
+            && self.ambient_light.max() <= 0.0
+            && self.ambient_gradient.is_none()
+            && self.environment.is_none()
+            && self.emitters.is_empty();
+        if unlit {
+            problems.push(
+                "nothing lights the scene: it has no lights, ambient light, environment \
+                 or emitting objects"
+                    .to_string(),
+            );
+        }
+        let fronts: Vec<(usize, &Intersection)> = hits
+            .iter()
+            .filter(|(_, hit, direction)| hit.normal.dot(direction) < 0.0)
+            .map(|(object, hit, _)| (*object, hit))
+            .collect();
+        let mut dark_lights = Vec::new();
+        for (index, light) in self.lights.iter().enumerate() {
+            let lit = fronts
+                .iter()
+                .filter(|(object, _)| light.lights_object(*object));
+            let lit: Vec<&Intersection> = lit.map(|(_, hit)| *hit).collect();
+            let facing: Vec<(&Intersection, FVec, Float)> = lit
+                .iter()
+                .filter_map(|hit| {
+                    let (direction, distance) = match light.sample(&hit.pos, [0.5; 3]) {
+                        LightSample::Point(point) => {
+                            let to_light = point - hit.pos;
+                            (to_light.normalize(), to_light.norm())
+                        }
+                        LightSample::Distant(direction) => (direction, Float::INFINITY),
+                    };
+                    (hit.normal.dot(&direction) > 0.0).then_some((*hit, direction, distance))
+                })
+                .collect();
+            let reaches = facing.iter().any(|(hit, direction, distance)| {
+                let ray = Ray {
+                    origin: hit.offset_origin(direction, self.ray_bias),
+                    direction: *direction,
+                    differential: None,
+                    time: hit.time,
+                };
+                !self._is_occluded(&ray, *distance)
+            });
+            let light_name = describe("light", index, light.name.as_deref());
+            let reason = if light.intensity <= 0.0 || light.colour.max() <= 0.0 {
+                Some("gives no light")
+            } else if fronts.is_empty() || reaches {
+                None
+            } else if lit.is_empty() {
+                Some("is linked to none of the objects the camera sees")
+            } else if facing.is_empty() {
+                Some("is behind every surface the camera sees")
+            } else {
+                Some("is blocked by geometry from everything the camera sees")
+            };
+            if let Some(reason) = reason {
+                problems.push(format!("{light_name} {reason}"));
+                dark_lights.push(index);
+            }
+        }
+        Diagnosis {
+            problems,
+            camera_blind: camera_blind || inside.is_some(),
+            inside,
+            dark_lights,
+        }
+    }
+
+    // Box around the objects shown, the camera and the lights that have a place
+    fn framed_bounds(&self) -> Aabb {
+        let objects = self
+            .objects
+            .iter()
+            .filter(|object| !object.degenerate && !object.hidden)
+            .filter_map(|object| object.bounding_box())
+            .flat_map(|bounds| [bounds.min, bounds.max]);
+        let lights = self.lights.iter().filter(|light| !light.is_distant());
+        let points = objects
+            .chain(lights.map(LightSource::centre))
+            .chain([self.camera.position]);
+        let bounds = Aabb::around(points).expect("the camera is always framed");
+        let margin = FVec::repeat(0.5 * scene_radius(&bounds).max(0.5));
+        Aabb {
+            min: bounds.min - margin,
+            max: bounds.max + margin,
+        }
+    }
+
+    /*
+    The scene in clay under a studio, seen from above and behind its camera
+    far enough back to frame all of it.
+     */
+    fn diagnostic_scene(&self) -> (Scene, Camera) {
+        let bounds = self.framed_bounds();
+        let centre = 0.5 * (bounds.min + bounds.max);
+        let radius = scene_radius(&bounds);
+        let level = FVec::new(self.camera.direction.x, self.camera.direction.y, 0.0);
+        let direction = (level.try_normalize(1e-9).unwrap_or(FVec::x()) - 0.6 * UP).normalize();
+        // The screen is a metre high a metre away, so this fits the radius into its height
+        let position = centre - 2.2 * radius * direction;
+        let camera = Camera::new(position, direction, COLUMNS, ROWS);
+        let clay = Material::deserialize(json!({
+            "colour": [0.7, 0.7, 0.7],
+            "kDiffuse": 0.8,
+            "kAmbient": 1,
+            "kSpecular": 0.2,
+            "kReflect": 0,
+            "shine": 20,
+        }))
+        .expect("clay is a valid material");
+        let mut scene = self.clone();
+        for object in scene.objects.iter_mut() {
+            object.material = clay.clone();
+        }
+        scene.camera = camera.clone();
+        scene.lights.clear();
+        let setup = Studio::Enabled(true)
+            .setup()
+            .expect("an enabled studio has a setup");
+        scene.add_studio_lights(&setup, &centre, radius);
+        scene.ambient_light = FVec::repeat(0.15);
+        scene.ambient_gradient = None;
+        scene.ambient_from_environment = false;
+        scene.irradiance = None;
+        scene.default_colour = FVec::new(0.1, 0.12, 0.16);
+        scene.environment = None;
+        scene.atmosphere = None;
+        scene.fog = None;
+        scene.fog_volumes.clear();
+        scene.volumes.clear();
+        scene.decals.clear();
+        scene.emitters.clear();
+        scene.photons = None;
+        scene.caustics = None;
+        scene.guiding = None;
+        scene.integrator = Default::default();
+        scene.spectral = false;
+        scene.denoise = None;
+        scene.tone_mapping = None;
+        scene.grading = None;
+        scene.colour = Default::default();
+        scene.pass = None;
+        scene.light_paths = None;
+        (scene, camera)
+    }
+}
+
+fn scene_radius(bounds: &Aabb) -> Float {
+    (0.5 * (bounds.max - bounds.min).norm()).max(1e-3)
+}
+
+// Unit vector from the point towards the light
+fn towards(light: &LightSource, from: &FVec) -> FVec {
+    match light.sample(from, [0.5; 3]) {
+        LightSample::Point(point) => (point - from).normalize(),
+        LightSample::Distant(direction) => direction,
+    }
+}
+
+fn box_edges(bounds: &Aabb) -> Vec<(FVec, FVec)> {
+    let corner = |i: usize| {
+        FVec::new(
+            if i & 1 == 0 {
+                bounds.min.x
+            } else {
+                bounds.max.x
+            },
+            if i & 2 == 0 {
+                bounds.min.y
+            } else {
+                bounds.max.y
+            },
+            if i & 4 == 0 {
+                bounds.min.z
+            } else {
+                bounds.max.z
+            },
+        )
+    };
+    // Corners one bit apart share an edge
+    (0..8)
+        .flat_map(|i| [1, 2, 4].map(|bit| (i, i | bit)))
+        .filter(|(i, j)| i != j)
+        .map(|(i, j)| (corner(i), corner(j)))
+        .collect()
+}
+
+/*
+The scene description without the values validation found problems with,
+for a diagnostic image of what is left: entries of lists such as objects
+and lights with a problem are left out, along with what refers to objects
+by index, as the indices no longer match, and other values are left to
+their defaults, those the camera cannot do without set to a plain view.
+ */
+pub fn without_problems(value: &Value, problems: &[SceneProblem]) -> Value {
+    let mut value = value.clone();
+    let Some(scene) = value.as_object_mut() else {
+        return value;
+    };
+    let mut dropped: Vec<(String, usize)> = Vec::new();
+    for problem in problems {
+        let top = problem.path.split('.').next().unwrap_or_default();
+        match top.split_once('[') {
+            Some((list, index)) => {
+                if let Ok(index) = index.trim_end_matches(']').parse() {
+                    dropped.push((list.to_string(), index));
+                }
+            }
+            None if top == "camera" => {
+                let field = problem.path.split('.').nth(1).unwrap_or_default();
+                let camera = scene.get_mut("camera").and_then(Value::as_object_mut);
+                if let Some(camera) = camera {
+                    camera.remove(field);
+                    let defaults = json!({
+                        "position": [0, 0, 0],
+                        "direction": [1, 0, 0],
+                        "screenDistance": 1,
+                        "screenWidth": 4.0 / 3.0,
+                        "screenHeight": 1,
+                        "screenColumns": COLUMNS,
+                        "screenRows": ROWS,
+                    });
+                    for (key, default) in defaults.as_object().into_iter().flatten() {
+                        camera.entry(key).or_insert(default.clone());
+                    }
+                }
+            }
+            None => {
+                scene.remove(top);
+            }
+        }
+    }
+    // From the last so earlier indices still point at the same entries
+    dropped.sort_unstable();
+    dropped.dedup();
+    for (list, index) in dropped.iter().rev() {
+        if let Some(Value::Array(entries)) = scene.get_mut(list) {
+            if *index < entries.len() {
+                entries.remove(*index);
+            }
+        }
+    }
+    if dropped.iter().any(|(list, _)| list == "objects") {
+        scene.remove("layers");
+        scene.remove("isolate");
+        for list in ["lights", "decals"] {
+            let entries = scene.get_mut(list).and_then(Value::as_array_mut);
+            for entry in entries
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_object_mut)
+            {
+                entry.remove("objects");
+            }
+        }
+    }
+    value
+}
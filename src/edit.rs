@@ -0,0 +1,165 @@
+use crate::light::{Emitter, LightSource};
+use crate::transform::Transform;
+use crate::validate::LoadError;
+use crate::{Float, Material, Scene, SceneObject, Shape};
+
+/*
+Finding and changing the objects and lights of a loaded scene, for editors
+and scripts. Objects and lights are found by the name and tags given to them
+in the scene file, and referred to afterwards by their index.
+
+Lengths and positions of loaded objects and lights are in metres, but
+transforms are written in the units of the scene file, as there. Moving
+objects leaves the scene's intersection structures out of date until
+update_geometry is called, so a batch of edits rebuilds them once; changing
+materials and lights needs no rebuild.
+ */
+impl Scene {
+    pub fn find_object(&self, name: &str) -> Option<usize> {
+        self.objects
+            .iter()
+            .position(|object| object.name.as_deref() == Some(name))
+    }
+
+    pub fn objects_tagged<'a>(&'a self, tag: &'a str) -> impl Iterator<Item = usize> + 'a {
+        self.objects
+            .iter()
+            .enumerate()
+            .filter(move |(_, object)| object.tags.iter().any(|t| t == tag))
+            .map(|(index, _)| index)
+    }
+
+    pub fn find_light(&self, name: &str) -> Option<usize> {
+        self.lights
+            .iter()
+            .position(|light| light.name.as_deref() == Some(name))
+    }
+
+    pub fn lights_tagged<'a>(&'a self, tag: &'a str) -> impl Iterator<Item = usize> + 'a {
+        self.lights
+            .iter()
+            .enumerate()
+            .filter(move |(_, light)| light.tags.iter().any(|t| t == tag))
+            .map(|(index, _)| index)
+    }
+
+    pub fn object(&self, index: usize) -> Option<&SceneObject> {
+        self.objects.get(index)
+    }
+
+    // The object's own transform, leaving out those of its parents
+    pub fn object_transform(&self, index: usize) -> Option<&Transform> {
+        self.objects.get(index)?.transform.as_ref()
+    }
+
+    /*
+    Give an object a new transform, or none, and move it and every object
+    parented to it to match. On an error, such as a sphere being scaled
+    unevenly, nothing is changed.
+     */
+    pub fn set_object_transform(
+        &mut self,
+        index: usize,
+        transform: Option<Transform>,
+    ) -> Result<(), String> {
+        let object = self
+            .objects
+            .get_mut(index)
+            .ok_or(format!("object {index} does not exist"))?;
+        let previous = std::mem::replace(&mut object.transform, transform);
+        let placed = self
+            .replaced_shapes(index)
+            .inspect_err(|_| self.objects[index].transform = previous)?;
+        for (moved, shape, local_shape) in placed {
+            self.objects[moved].shape = shape;
+            self.objects[moved].local_shape = local_shape;
+        }
+        self.geometry_changed = true;
+        Ok(())
+    }
+
+    // Shapes, and shapes before their transforms, of the object and its descendants moved anew
+    fn replaced_shapes(&self, index: usize) -> Result<Vec<(usize, Shape, Option<Shape>)>, String> {
+        let mut placed = Vec::new();
+        for moved in (0..self.objects.len()).filter(|&i| self.descends_from(i, index)) {
+            let object = &self.objects[moved];
+            let local = object.local_shape.as_ref().unwrap_or(&object.shape).clone();
+            let shape = match self.placement(moved)? {
+                Some(mut matrix) => {
+                    // Transforms move by authored lengths, which the shape has left behind
+                    for row in 0..3 {
+                        matrix[(row, 3)] *= object.metres;
+                    }
+                    let shape = local.transformed(&matrix);
+                    shape.map_err(|error| format!("object {moved}: {error}"))?
+                }
+                None => local.clone(),
+            };
+            let local_shape = (shape != local).then_some(local);
+            placed.push((moved, shape, local_shape));
+        }
+        Ok(placed)
+    }
+
+    // Whether the object is the ancestor or a descendant of it through parents
+    fn descends_from(&self, object: usize, ancestor: usize) -> bool {
+        let mut current = Some(object);
+        for _ in 0..=self.objects.len() {
+            match current {
+                Some(i) if i == ancestor => return true,
+                Some(i) => current = self.objects.get(i).and_then(|o| o.parent),
+                None => return false,
+            }
+        }
+        false
+    }
+
+    /*
+    Replace an object's material, loading its textures relative to the scene
+    file. Parameters written as expressions take the values of the scene's
+    frame, as when the scene is loaded.
+     */
+    pub fn set_object_material(
+        &mut self,
+        index: usize,
+        mut material: Material,
+    ) -> Result<(), LoadError> {
+        let object = self
+            .objects
+            .get_mut(index)
+            .ok_or_else(|| LoadError::Parse(format!("object {index} does not exist").into()))?;
+        material.apply_temperature();
+        let frame = self.frame as Float;
+        material.settle_expressions(frame, self.frame_rate, object.instance_seed);
+        material
+            .load_textures(&self.base_dir, &self.colour)
+            .map_err(LoadError::Asset)?;
+        object.material = material;
+        self.emitters = Emitter::find(&self.objects);
+        self.photons = self.trace_photons();
+        self.train_guide();
+        Ok(())
+    }
+
+    pub fn light(&self, index: usize) -> Option<&LightSource> {
+        self.lights.get(index)
+    }
+
+    // A light to change in place; its position and lengths are in metres
+    pub fn light_mut(&mut self, index: usize) -> Option<&mut LightSource> {
+        self.lights.get_mut(index)
+    }
+
+    /*
+    Bring the intersection structures up to date with the objects moved since
+    they were built. Does nothing if none have been.
+     */
+    pub fn update_geometry(&mut self) {
+        if std::mem::take(&mut self.geometry_changed) {
+            self.primitives = self.primitives.rebuild(&self.objects);
+            self.emitters = Emitter::find(&self.objects);
+            self.photons = self.trace_photons();
+            self.train_guide();
+        }
+    }
+}
@@ -0,0 +1,448 @@
+use crate::colour::{ColourPipeline, TextureColourSpace};
+use crate::config::find_asset;
+use crate::core::consts::PI;
+use crate::core::single;
+use crate::light::{Attenuation, DistantDirection, LightShape, LightSource};
+use crate::texture::ImageTexture;
+use crate::tonemap::LUMINANCE_WEIGHTS;
+use crate::{FVec, Float};
+use serde::Deserialize;
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+
+/*
+What rays see when they leave the scene without hitting anything, in place
+of the flat default colour. Either a sky blended from the horizon up to the
+zenith, or an equirectangular image such as an HDR panorama, wrapped around
+the scene with its centre column facing +x and its top row straight up.
+ */
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Environment {
+    Gradient {
+        zenith: FVec,
+        horizon: FVec,
+        // Colour straight down, blended from the horizon colour; the horizon colour by default
+        ground: Option<FVec>,
+    },
+    Image {
+        // Relative to the scene file or a search path
+        #[serde(rename = "image")]
+        path: String,
+        // Degrees the image is turned about the up axis, anticlockwise seen from above
+        #[serde(default)]
+        rotation: Float,
+        // Multiplies the image's colours
+        #[serde(default = "default_intensity")]
+        intensity: Float,
+        #[serde(rename = "colourSpace")]
+        colour_space: Option<TextureColourSpace>,
+        // Degrees the image is blurred by, for softer reflections and less noise in bounced light
+        #[serde(default)]
+        blur: Float,
+        /*
+        Brightness above which texels are dimmed to it, the light taken off
+        them shining instead from a distant light in their direction, so a
+        small bright sun is lit by shadow rays rather than found by chance
+        by the rays of the path integrator.
+         */
+        #[serde(rename = "sunThreshold")]
+        sun_threshold: Option<Float>,
+        /*
+        Share of the bounces of the path integrator drawn towards the bright
+        parts of the image rather than by the surface alone, so small bright
+        lights in it are found by more than chance; 0 turns this off.
+         */
+        #[serde(rename = "importanceShare", default = "default_importance_share")]
+        importance_share: Float,
+        #[serde(skip)]
+        texture: Option<Arc<ImageTexture>>,
+        #[serde(skip)]
+        distribution: Option<Arc<SkyDistribution>>,
+    },
+}
+
+fn default_intensity() -> Float {
+    1.0
+}
+
+fn default_importance_share() -> Float {
+    0.5
+}
+
+// Most cells across the table of where an environment's light comes from
+const DISTRIBUTION_COLUMNS: u32 = 1024;
+
+impl Environment {
+    /*
+    Load the image relative to base_dir or a search path, converting it to the
+    working space, and return the light of the sun taken out of it, if any.
+     */
+    pub fn load(
+        &mut self,
+        base_dir: &Path,
+        colour: &ColourPipeline,
+    ) -> Result<Option<LightSource>, Box<dyn Error>> {
+        let Environment::Image {
+            path,
+            rotation,
+            intensity,
+            colour_space,
+            blur,
+            sun_threshold,
+            importance_share,
+            texture,
+            distribution,
+        } = self
+        else {
+            return Ok(None);
+        };
+        let path = find_asset(base_dir, &*path);
+        let to_working = colour.texture_processor(colour_space.as_ref(), &path)?;
+        let mut image = ImageTexture::load(&path)?;
+        image.map_colours(|colour| to_working.apply(colour));
+        // Taken out before blurring, which would spread the sun over the sky
+        let sun = sun_threshold.and_then(|threshold| {
+            extract_sun(&mut image, threshold / *intensity, *rotation, *intensity)
+        });
+        if *blur > 0.0 {
+            blur_sky(&mut image, blur.to_radians());
+        }
+        if *importance_share > 0.0 {
+            *distribution = SkyDistribution::build(&image, *rotation).map(Arc::new);
+        }
+        *texture = Some(Arc::new(image));
+        Ok(sun)
+    }
+
+    // Where the bounces of the path integrator are partly drawn from, with the share drawn there
+    pub(crate) fn importance(&self) -> Option<(&SkyDistribution, Float)> {
+        match self {
+            Environment::Image {
+                distribution: Some(distribution),
+                importance_share,
+                ..
+            } => Some((distribution, *importance_share)),
+            _ => None,
+        }
+    }
+
+    // Colour seen looking along the direction, which need not be of unit length
+    pub fn colour(&self, direction: &FVec) -> FVec {
+        let d = direction.normalize();
+        match self {
+            Environment::Gradient {
+                zenith,
+                horizon,
+                ground,
+            } => {
+                if d.z >= 0.0 {
+                    horizon + (zenith - horizon) * d.z
+                } else {
+                    let ground = ground.unwrap_or(*horizon);
+                    horizon + (ground - horizon) * -d.z
+                }
+            }
+            Environment::Image {
+                rotation,
+                intensity,
+                texture,
+                ..
+            } => {
+                let texture = texture
+                    .as_ref()
+                    .expect("environments are loaded with the scene");
+                let longitude = d.y.atan2(d.x) - rotation.to_radians();
+                // Longitude 0 is at the centre of the image and grows to the left
+                let u = (0.5 - longitude / (2.0 * PI)).rem_euclid(1.0);
+                let v = d.z.clamp(-1.0, 1.0).acos() / PI;
+                texture.sample(u, v) * *intensity
+            }
+        }
+    }
+
+    // Memory taken by the decoded image and the table of where its light comes from
+    pub fn bytes(&self) -> usize {
+        match self {
+            Environment::Image {
+                texture: Some(texture),
+                distribution,
+                ..
+            } => texture.bytes() + distribution.as_ref().map_or(0, |d| d.bytes()),
+            _ => 0,
+        }
+    }
+}
+
+/*
+Where an environment image's light comes from, for drawing directions in
+proportion to it (Pharr et al., Physically Based Rendering 13.6). The image
+is summed into a grid of cells, each texel weighed by the greatest luminance
+around it times its solid angle: a cumulative table over the rows (the marginal) picks a row,
+then one over the cells of that row (the conditional) picks a cell, and the
+direction is drawn evenly over the cell's solid angle.
+ */
+#[derive(Debug)]
+pub(crate) struct SkyDistribution {
+    columns: usize,
+    rows: usize,
+    // Radians the image is turned about the up axis
+    rotation: Float,
+    // Cumulative shares of the rows, from 0 to 1, one more than there are rows
+    marginal: Vec<Float>,
+    // Cumulative shares of the cells within each row, one more than there are columns per row
+    conditional: Vec<Float>,
+    // Share of the light in each cell, row by row
+    shares: Vec<Float>,
+}
+
+impl SkyDistribution {
+    // None for an image that gives no light
+    fn build(image: &ImageTexture, rotation: Float) -> Option<SkyDistribution> {
+        let weights = FVec::from(LUMINANCE_WEIGHTS);
+        let (width, height) = image.dimensions();
+        let columns = width.min(DISTRIBUTION_COLUMNS);
+        let rows = height.min(columns.div_ceil(2)).max(1);
+        let mut cells = vec![0.0; (columns * rows) as usize];
+        for y in 0..height {
+            let row = (y * rows / height) as usize;
+            let solid_angle = texel_solid_angle(y, width, height);
+            for x in 0..width {
+                let column = (x * columns / width) as usize;
+                // Filtered lookups spread a texel's light half a texel around it
+                let (x, y) = (x as i64, y as i64);
+                let neighbours = (-1..=1).flat_map(|dy| (-1..=1).map(move |dx| (x + dx, y + dy)));
+                let luminance = neighbours
+                    .map(|(x, y)| image.texel(x, y).dot(&weights))
+                    .fold(0.0, Float::max);
+                cells[row * columns as usize + column] += luminance * solid_angle;
+            }
+        }
+        let total: Float = cells.iter().sum();
+        if !(total > 0.0 && total.is_finite()) {
+            return None;
+        }
+        let shares: Vec<Float> = cells.iter().map(|cell| cell / total).collect();
+        let (columns, rows) = (columns as usize, rows as usize);
+        let mut marginal = vec![0.0];
+        let mut conditional = Vec::with_capacity((columns + 1) * rows);
+        for row in shares.chunks(columns) {
+            let row_total: Float = row.iter().sum();
+            marginal.push(marginal.last().copied().unwrap_or(0.0) + row_total);
+            conditional.push(0.0);
+            let mut cumulative = 0.0;
+            for share in row {
+                // Rows without light are never picked, so spread evenly to keep the table sound
+                cumulative += match row_total > 0.0 {
+                    true => share / row_total,
+                    false => 1.0 / columns as Float,
+                };
+                conditional.push(cumulative);
+            }
+        }
+        Some(SkyDistribution {
+            columns,
+            rows,
+            rotation: rotation.to_radians(),
+            marginal,
+            conditional,
+            shares,
+        })
+    }
+
+    fn bytes(&self) -> usize {
+        let entries = self.marginal.len() + self.conditional.len() + self.shares.len();
+        entries * std::mem::size_of::<Float>()
+    }
+
+    // Solid angle of the cells of a row
+    fn cell_solid_angle(&self, row: usize) -> Float {
+        let (top, bottom) = (self.row_cos(row), self.row_cos(row + 1));
+        2.0 * PI / self.columns as Float * (top - bottom)
+    }
+
+    // Cosine of the angle from straight up of the top edge of a row
+    fn row_cos(&self, row: usize) -> Float {
+        (row as Float / self.rows as Float * PI).cos()
+    }
+
+    // A unit direction drawn in proportion to the light from it, and its probability per steradian
+    pub(crate) fn sample(&self, u1: Float, u2: Float) -> (FVec, Float) {
+        let row = pick(&self.marginal, u1);
+        let start = row * (self.columns + 1);
+        let column = pick(&self.conditional[start..start + self.columns + 1], u2);
+        // Where within the cell, from where each draw fell within its entry
+        let within = |table: &[Float], index: usize, u: Float| {
+            let (low, high) = (table[index], table[index + 1]);
+            ((u - low) / (high - low)).clamp(0.0, 1.0)
+        };
+        let across = within(&self.conditional[start..], column, u2);
+        let down = within(&self.marginal, row, u1);
+        let z = self.row_cos(row) + (self.row_cos(row + 1) - self.row_cos(row)) * down;
+        let u = (column as Float + across) / self.columns as Float;
+        let longitude = (0.5 - u) * 2.0 * PI + self.rotation;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let direction = FVec::new(r * longitude.cos(), r * longitude.sin(), z);
+        let pdf = self.shares[row * self.columns + column] / self.cell_solid_angle(row);
+        (direction, pdf)
+    }
+
+    // Probability per steradian of sample drawing the unit direction
+    pub(crate) fn pdf(&self, direction: &FVec) -> Float {
+        let longitude = direction.y.atan2(direction.x) - self.rotation;
+        let u = (0.5 - longitude / (2.0 * PI)).rem_euclid(1.0);
+        let v = direction.z.clamp(-1.0, 1.0).acos() / PI;
+        let column = ((u * self.columns as Float) as usize).min(self.columns - 1);
+        let row = ((v * self.rows as Float) as usize).min(self.rows - 1);
+        let solid_angle = self.cell_solid_angle(row);
+        match solid_angle > 0.0 {
+            true => self.shares[row * self.columns + column] / solid_angle,
+            false => 0.0,
+        }
+    }
+}
+
+// Index of the entry of a cumulative table the draw falls in, skipping entries of no width
+fn pick(table: &[Float], u: Float) -> usize {
+    let index = table.partition_point(|&cumulative| cumulative <= u);
+    index.clamp(1, table.len() - 1) - 1
+}
+
+// Unit vector towards the middle of a texel of an equirectangular image turned by the rotation
+fn texel_direction(x: u32, y: u32, width: u32, height: u32, rotation: Float) -> FVec {
+    let u = (x as Float + 0.5) / width as Float;
+    let theta = (y as Float + 0.5) / height as Float * PI;
+    let longitude = (0.5 - u) * 2.0 * PI + rotation.to_radians();
+    FVec::new(
+        theta.sin() * longitude.cos(),
+        theta.sin() * longitude.sin(),
+        theta.cos(),
+    )
+}
+
+// Solid angle of the texels of a row of an equirectangular image
+fn texel_solid_angle(y: u32, width: u32, height: u32) -> Float {
+    let theta = (y as Float + 0.5) / height as Float * PI;
+    (2.0 * PI / width as Float) * (PI / height as Float) * theta.sin()
+}
+
+/*
+Dim the texels brighter than the threshold to it, keeping their hue, and
+return a distant light giving the light taken off them from their direction
+weighted by brightness. It lights surfaces as the texels did: a sky of
+radiance L lights a diffuse surface with L times the cosine-weighted solid
+angle over π.
+ */
+fn extract_sun(
+    image: &mut ImageTexture,
+    threshold: Float,
+    rotation: Float,
+    intensity: Float,
+) -> Option<LightSource> {
+    let weights = FVec::from(LUMINANCE_WEIGHTS);
+    let image = image.image_mut();
+    let (width, height) = image.dimensions();
+    let mut taken = FVec::zeros();
+    let mut towards = FVec::zeros();
+    for (x, y, texel) in image.enumerate_pixels_mut() {
+        let colour = FVec::new(texel[0] as Float, texel[1] as Float, texel[2] as Float);
+        let luminance = colour.dot(&weights);
+        if luminance <= threshold {
+            continue;
+        }
+        let kept = colour * (threshold / luminance);
+        texel.0 = kept.map(single).into();
+        let excess = (colour - kept) * texel_solid_angle(y, width, height);
+        taken += excess;
+        towards += excess.dot(&weights) * texel_direction(x, y, width, height, rotation);
+    }
+    let towards = towards.try_normalize(0.0)?;
+    let taken = taken * intensity / PI;
+    let strength = taken.max();
+    if strength <= 0.0 {
+        return None;
+    }
+    debug!(
+        "Took a sun of intensity {} towards {:?} out of the environment",
+        strength, towards
+    );
+    Some(LightSource {
+        name: Some("environment sun".to_string()),
+        tags: Vec::new(),
+        colour: taken / strength,
+        pos: FVec::zeros(),
+        intensity: strength,
+        temperature: None,
+        cutoff_radius: None,
+        attenuation: Attenuation::InverseSquare,
+        shape: LightShape::Distant {
+            direction: DistantDirection::Vector(-towards),
+        },
+        shadow_samples: None,
+        spot: None,
+        animation: None,
+        objects: None,
+    })
+}
+
+/*
+Blur an equirectangular image by a Gaussian of the angle, made of three box
+blurs of each row and then each column. Rows are blurred over more texels
+towards the poles, where their texels cover less of the sky, and wrap
+around; columns stop at the poles.
+ */
+fn blur_sky(image: &mut ImageTexture, sigma: Float) {
+    let image = image.image_mut();
+    let (width, height) = image.dimensions();
+    let (columns, rows) = (width as usize, height as usize);
+    let mut texels: Vec<FVec> = image
+        .pixels()
+        .map(|p| FVec::new(p[0] as Float, p[1] as Float, p[2] as Float))
+        .collect();
+    for (y, row) in texels.chunks_mut(columns).enumerate() {
+        let theta = (y as Float + 0.5) / rows as Float * PI;
+        let texel_angle = 2.0 * PI / columns as Float * theta.sin().max(1e-6);
+        let radius = box_radius(sigma / texel_angle).min((columns - 1) / 2);
+        for _ in 0..3 {
+            box_blur(row, radius, true);
+        }
+    }
+    let radius = box_radius(sigma / (PI / rows as Float));
+    for x in 0..columns {
+        let mut column: Vec<FVec> = (0..rows).map(|y| texels[y * columns + x]).collect();
+        for _ in 0..3 {
+            box_blur(&mut column, radius, false);
+        }
+        for (y, colour) in column.into_iter().enumerate() {
+            texels[y * columns + x] = colour;
+        }
+    }
+    for (texel, colour) in image.pixels_mut().zip(texels) {
+        texel.0 = colour.map(single).into();
+    }
+}
+
+// Radius in texels of a box blur that three times over blurs like a Gaussian of the deviation
+fn box_radius(sigma: Float) -> usize {
+    (((4.0 * sigma * sigma + 1.0).sqrt() - 1.0) / 2.0).round() as usize
+}
+
+// Mean of each value and its neighbours within the radius, wrapping around or else clamped
+fn box_blur(values: &mut [FVec], radius: usize, wrap: bool) {
+    if radius == 0 || values.is_empty() {
+        return;
+    }
+    let (n, r) = (values.len() as isize, radius as isize);
+    let at = |i: isize| match wrap {
+        true => values[i.rem_euclid(n) as usize],
+        false => values[i.clamp(0, n - 1) as usize],
+    };
+    let mut sum: FVec = (-r..=r).map(at).sum();
+    let mut blurred = Vec::with_capacity(values.len());
+    for i in 0..n {
+        blurred.push(sum / (2 * r + 1) as Float);
+        sum += at(i + r + 1) - at(i - r);
+    }
+    values.copy_from_slice(&blurred);
+}
@@ -0,0 +1,527 @@
+use crate::core::consts::PI;
+use crate::sampling::instance_random;
+use crate::{FVec, Float};
+use serde::Deserialize;
+use std::fmt;
+
+/*
+A formula of the point being shaded and the time, such as
+"0.2 + 0.6 * smoothstep(0, 2, z)" or "0.5 + 0.5 * sin(2 * pi * t)". Parsed once
+when the scene is loaded and evaluated at every point it is needed.
+
+Numbers, + - * / and ^ (power, binding tightest), parentheses, the constant
+pi and these variables are understood:
+
+    x, y, z     position in metres
+    u, v        texture coordinates of the surface, as for textures
+    t           time in seconds of the frame being rendered
+    frame       number of the frame being rendered
+    rand        random value in [0, 1) of the object being shaded, the same
+                everywhere on it and in every render with the same seed
+
+along with the functions sin, cos, tan, asin, acos, atan, atan2, abs, sqrt,
+exp, ln, floor, ceil, fract, min, max, pow, step(edge, x), clamp(x, lo, hi),
+mix(a, b, f), smoothstep(lo, hi, x) and random(n), the object's n-th random
+value, independent of rand and of the others.
+ */
+#[derive(Deserialize, Clone)]
+#[serde(try_from = "String")]
+pub struct Expression {
+    source: String,
+    root: Node,
+}
+
+// Values the variables of an expression take
+#[derive(Debug, Clone, Copy)]
+pub struct Inputs {
+    pub position: FVec,
+    pub uv: (Float, Float),
+    pub time: Float,
+    pub frame: Float,
+    // Seed of the random values of the object being shaded
+    pub instance: u64,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Number(Float),
+    Variable(Variable),
+    Negate(Box<Node>),
+    Binary(Operator, Box<Node>, Box<Node>),
+    Call(Function, Vec<Node>),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Variable {
+    X,
+    Y,
+    Z,
+    U,
+    V,
+    Time,
+    Frame,
+    Random,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Power,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Function {
+    Sin,
+    Cos,
+    Tan,
+    Asin,
+    Acos,
+    Atan,
+    Atan2,
+    Abs,
+    Sqrt,
+    Exp,
+    Ln,
+    Floor,
+    Ceil,
+    Fract,
+    Min,
+    Max,
+    Pow,
+    Step,
+    Clamp,
+    Mix,
+    Smoothstep,
+    Random,
+}
+
+impl Function {
+    fn named(name: &str) -> Option<Function> {
+        Some(match name {
+            "sin" => Function::Sin,
+            "cos" => Function::Cos,
+            "tan" => Function::Tan,
+            "asin" => Function::Asin,
+            "acos" => Function::Acos,
+            "atan" => Function::Atan,
+            "atan2" => Function::Atan2,
+            "abs" => Function::Abs,
+            "sqrt" => Function::Sqrt,
+            "exp" => Function::Exp,
+            "ln" => Function::Ln,
+            "floor" => Function::Floor,
+            "ceil" => Function::Ceil,
+            "fract" => Function::Fract,
+            "min" => Function::Min,
+            "max" => Function::Max,
+            "pow" => Function::Pow,
+            "step" => Function::Step,
+            "clamp" => Function::Clamp,
+            "mix" => Function::Mix,
+            "smoothstep" => Function::Smoothstep,
+            "random" => Function::Random,
+            _ => return None,
+        })
+    }
+
+    fn arity(self) -> usize {
+        match self {
+            Function::Atan2 | Function::Min | Function::Max | Function::Pow | Function::Step => 2,
+            Function::Clamp | Function::Mix | Function::Smoothstep => 3,
+            _ => 1,
+        }
+    }
+
+    fn apply(self, a: &[Float], inputs: &Inputs) -> Float {
+        match self {
+            Function::Sin => a[0].sin(),
+            Function::Cos => a[0].cos(),
+            Function::Tan => a[0].tan(),
+            Function::Asin => a[0].asin(),
+            Function::Acos => a[0].acos(),
+            Function::Atan => a[0].atan(),
+            Function::Atan2 => a[0].atan2(a[1]),
+            Function::Abs => a[0].abs(),
+            Function::Sqrt => a[0].sqrt(),
+            Function::Exp => a[0].exp(),
+            Function::Ln => a[0].ln(),
+            Function::Floor => a[0].floor(),
+            Function::Ceil => a[0].ceil(),
+            Function::Fract => a[0] - a[0].floor(),
+            Function::Min => a[0].min(a[1]),
+            Function::Max => a[0].max(a[1]),
+            Function::Pow => a[0].powf(a[1]),
+            Function::Step => {
+                if a[1] < a[0] {
+                    0.0
+                } else {
+                    1.0
+                }
+            }
+            Function::Clamp => a[0].max(a[1]).min(a[2]),
+            Function::Mix => a[0] + (a[1] - a[0]) * a[2],
+            Function::Smoothstep => {
+                let f = ((a[2] - a[0]) / (a[1] - a[0])).clamp(0.0, 1.0);
+                f * f * (3.0 - 2.0 * f)
+            }
+            // Counted from 1, as rand is the 0th
+            Function::Random => instance_random(inputs.instance, a[0].max(0.0) as u64 + 1),
+        }
+    }
+}
+
+impl Node {
+    fn evaluate(&self, inputs: &Inputs) -> Float {
+        match self {
+            Node::Number(value) => *value,
+            Node::Variable(variable) => match variable {
+                Variable::X => inputs.position.x,
+                Variable::Y => inputs.position.y,
+                Variable::Z => inputs.position.z,
+                Variable::U => inputs.uv.0,
+                Variable::V => inputs.uv.1,
+                Variable::Time => inputs.time,
+                Variable::Frame => inputs.frame,
+                Variable::Random => instance_random(inputs.instance, 0),
+            },
+            Node::Negate(node) => -node.evaluate(inputs),
+            Node::Binary(operator, a, b) => {
+                let (a, b) = (a.evaluate(inputs), b.evaluate(inputs));
+                match operator {
+                    Operator::Add => a + b,
+                    Operator::Subtract => a - b,
+                    Operator::Multiply => a * b,
+                    Operator::Divide => a / b,
+                    Operator::Power => a.powf(b),
+                }
+            }
+            Node::Call(function, arguments) => {
+                let values: Vec<Float> = arguments.iter().map(|a| a.evaluate(inputs)).collect();
+                function.apply(&values, inputs)
+            }
+        }
+    }
+}
+
+// Recursive descent over the characters of the source, one level per precedence
+struct Parser<'a> {
+    chars: Vec<char>,
+    at: usize,
+    source: &'a str,
+}
+
+impl Parser<'_> {
+    fn skip_space(&mut self) {
+        while self.chars.get(self.at).is_some_and(|c| c.is_whitespace()) {
+            self.at += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_space();
+        self.chars.get(self.at).copied()
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("{message} at column {} of {:?}", self.at + 1, self.source)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        if self.peek() != Some(expected) {
+            return Err(self.error(&format!("expected '{expected}'")));
+        }
+        self.at += 1;
+        Ok(())
+    }
+
+    // Sums and differences of terms
+    fn sum(&mut self) -> Result<Node, String> {
+        let mut node = self.product()?;
+        while let Some(c @ ('+' | '-')) = self.peek() {
+            self.at += 1;
+            let operator = if c == '+' {
+                Operator::Add
+            } else {
+                Operator::Subtract
+            };
+            node = Node::Binary(operator, Box::new(node), Box::new(self.product()?));
+        }
+        Ok(node)
+    }
+
+    fn product(&mut self) -> Result<Node, String> {
+        let mut node = self.unary()?;
+        while let Some(c @ ('*' | '/')) = self.peek() {
+            self.at += 1;
+            let operator = if c == '*' {
+                Operator::Multiply
+            } else {
+                Operator::Divide
+            };
+            node = Node::Binary(operator, Box::new(node), Box::new(self.unary()?));
+        }
+        Ok(node)
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        if self.peek() == Some('-') {
+            self.at += 1;
+            return Ok(Node::Negate(Box::new(self.unary()?)));
+        }
+        self.power()
+    }
+
+    // Powers group to the right, so 2^3^2 is 2^9, and bind tighter than negation
+    fn power(&mut self) -> Result<Node, String> {
+        let base = self.atom()?;
+        if self.peek() == Some('^') {
+            self.at += 1;
+            let exponent = self.unary()?;
+            return Ok(Node::Binary(
+                Operator::Power,
+                Box::new(base),
+                Box::new(exponent),
+            ));
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        match self.peek() {
+            Some('(') => {
+                self.at += 1;
+                let node = self.sum()?;
+                self.expect(')')?;
+                Ok(node)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => self.number(),
+            Some(c) if c.is_ascii_alphabetic() => self.name(),
+            Some(c) => Err(self.error(&format!("unexpected '{c}'"))),
+            None => Err(self.error("unexpected end")),
+        }
+    }
+
+    fn number(&mut self) -> Result<Node, String> {
+        let start = self.at;
+        while self
+            .chars
+            .get(self.at)
+            .is_some_and(|c| c.is_ascii_digit() || *c == '.')
+        {
+            self.at += 1;
+        }
+        // An exponent, as in 1e-3
+        if self
+            .chars
+            .get(self.at)
+            .is_some_and(|c| *c == 'e' || *c == 'E')
+        {
+            let mut end = self.at + 1;
+            if self.chars.get(end).is_some_and(|c| *c == '+' || *c == '-') {
+                end += 1;
+            }
+            if self.chars.get(end).is_some_and(char::is_ascii_digit) {
+                self.at = end;
+                while self.chars.get(self.at).is_some_and(char::is_ascii_digit) {
+                    self.at += 1;
+                }
+            }
+        }
+        let text: String = self.chars[start..self.at].iter().collect();
+        text.parse().map(Node::Number).map_err(|_| {
+            self.at = start;
+            self.error(&format!("invalid number {text:?}"))
+        })
+    }
+
+    fn name(&mut self) -> Result<Node, String> {
+        let start = self.at;
+        while self
+            .chars
+            .get(self.at)
+            .is_some_and(|c| c.is_ascii_alphanumeric() || *c == '_')
+        {
+            self.at += 1;
+        }
+        let name: String = self.chars[start..self.at].iter().collect();
+        let variable = match name.as_str() {
+            "x" => Variable::X,
+            "y" => Variable::Y,
+            "z" => Variable::Z,
+            "u" => Variable::U,
+            "v" => Variable::V,
+            "t" => Variable::Time,
+            "frame" => Variable::Frame,
+            "rand" => Variable::Random,
+            "pi" => return Ok(Node::Number(PI)),
+            _ => {
+                self.at = start;
+                let function = Function::named(&name)
+                    .ok_or_else(|| self.error(&format!("unknown name {name:?}")))?;
+                self.at += name.chars().count();
+                return self.call(function, &name);
+            }
+        };
+        Ok(Node::Variable(variable))
+    }
+
+    fn call(&mut self, function: Function, name: &str) -> Result<Node, String> {
+        self.expect('(')?;
+        let mut arguments = vec![self.sum()?];
+        while self.peek() == Some(',') {
+            self.at += 1;
+            arguments.push(self.sum()?);
+        }
+        self.expect(')')?;
+        if arguments.len() != function.arity() {
+            let count = function.arity();
+            let message = format!("{name} takes {count} arguments, not {}", arguments.len());
+            return Err(self.error(&message));
+        }
+        Ok(Node::Call(function, arguments))
+    }
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Expression, String> {
+        let mut parser = Parser {
+            chars: source.chars().collect(),
+            at: 0,
+            source,
+        };
+        let root = parser.sum()?;
+        if let Some(c) = parser.peek() {
+            return Err(parser.error(&format!("unexpected '{c}'")));
+        }
+        Ok(Expression {
+            source: source.to_string(),
+            root,
+        })
+    }
+
+    pub fn evaluate(&self, inputs: &Inputs) -> Float {
+        self.root.evaluate(inputs)
+    }
+}
+
+impl TryFrom<String> for Expression {
+    type Error = String;
+
+    fn try_from(source: String) -> Result<Expression, String> {
+        Expression::parse(&source)
+    }
+}
+
+impl fmt::Debug for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Expression({:?})", self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INPUTS: Inputs = Inputs {
+        position: FVec::new(1.0, 2.0, 3.0),
+        uv: (0.25, 0.75),
+        time: 1.5,
+        frame: 36.0,
+        instance: 42,
+    };
+
+    fn value(source: &str) -> Float {
+        Expression::parse(source).unwrap().evaluate(&INPUTS)
+    }
+
+    fn error(source: &str) -> String {
+        Expression::parse(source).unwrap_err()
+    }
+
+    #[test]
+    fn operators_bind_by_precedence() {
+        assert_eq!(value("1 + 2 * 3"), 7.0);
+        assert_eq!(value("(1 + 2) * 3"), 9.0);
+        assert_eq!(value("2 * 3 ^ 2"), 18.0);
+        assert_eq!(value("1 + 6 / 2 - 1"), 3.0);
+        assert_eq!(value("2 * pi"), 2.0 * PI);
+    }
+
+    #[test]
+    fn operators_group_left_except_powers() {
+        assert_eq!(value("10 - 4 - 3"), 3.0);
+        assert_eq!(value("8 / 4 / 2"), 1.0);
+        assert_eq!(value("2 ^ 3 ^ 2"), 512.0);
+    }
+
+    #[test]
+    fn minus_negates_what_follows_it() {
+        assert_eq!(value("-3"), -3.0);
+        assert_eq!(value("--3"), 3.0);
+        assert_eq!(value("3 * -2"), -6.0);
+        // Powers bind tighter than negation, on either side of the ^
+        assert_eq!(value("-2 ^ 2"), -4.0);
+        assert_eq!(value("2 ^ -1"), 0.5);
+        assert_eq!(value("2e-1 * -1e1"), -2.0);
+    }
+
+    #[test]
+    fn reports_what_is_wrong_and_where() {
+        assert_eq!(
+            error("foo(1)"),
+            r#"unknown name "foo" at column 1 of "foo(1)""#
+        );
+        assert_eq!(
+            error("2 * min(1)"),
+            r#"min takes 2 arguments, not 1 at column 11 of "2 * min(1)""#
+        );
+        assert_eq!(
+            error("clamp(1, 2, 3, 4)"),
+            r#"clamp takes 3 arguments, not 4 at column 18 of "clamp(1, 2, 3, 4)""#
+        );
+        assert_eq!(
+            error("1 + 2 )"),
+            r#"unexpected ')' at column 7 of "1 + 2 )""#
+        );
+        assert_eq!(error("1 2"), r#"unexpected '2' at column 3 of "1 2""#);
+        assert_eq!(error("(1 + 2"), r#"expected ')' at column 7 of "(1 + 2""#);
+        assert_eq!(error("1 +"), r#"unexpected end at column 4 of "1 +""#);
+    }
+
+    #[test]
+    fn evaluates_the_inputs() {
+        assert_eq!(value("x + 10 * y + 100 * z"), 321.0);
+        assert_eq!(value("u + v"), 1.0);
+        assert_eq!(value("t * frame"), 54.0);
+        assert_eq!(value("mix(10, 20, u)"), 12.5);
+        assert_eq!(value("smoothstep(0, 2, 1)"), 0.5);
+        assert_eq!(value("clamp(z, 0, 2) + step(2, y)"), 3.0);
+    }
+
+    #[test]
+    fn random_values_belong_to_the_object() {
+        let rand = value("rand");
+        assert!((0.0..1.0).contains(&rand));
+        assert_eq!(rand, instance_random(INPUTS.instance, 0));
+        // The n-th random value is neither rand nor another one
+        assert_eq!(value("random(0)"), instance_random(INPUTS.instance, 1));
+        assert_ne!(value("random(0)"), rand);
+        assert_ne!(value("random(0)"), value("random(1)"));
+        // The same everywhere on the object, and different on another
+        let elsewhere = Inputs {
+            position: FVec::new(-4.0, 0.5, 9.0),
+            time: 0.0,
+            ..INPUTS
+        };
+        let expression = Expression::parse("rand").unwrap();
+        assert_eq!(expression.evaluate(&elsewhere), rand);
+        let other = Inputs {
+            instance: 43,
+            ..INPUTS
+        };
+        assert_ne!(expression.evaluate(&other), rand);
+    }
+}
@@ -0,0 +1,221 @@
+use crate::core::consts::PI;
+use crate::{FVec, Float};
+use serde::Deserialize;
+
+/*
+Reconstruction filter weighting each sample's contribution to the pixels
+around it. Pixel centres sit at integer film coordinates.
+ */
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum PixelFilter {
+    // Every sample counts fully towards its own pixel only
+    #[default]
+    Box,
+    Tent {
+        #[serde(default = "default_tent_radius")]
+        radius: Float,
+    },
+    Gaussian {
+        #[serde(default = "default_wide_radius")]
+        radius: Float,
+        #[serde(default = "default_sigma")]
+        sigma: Float,
+    },
+    BlackmanHarris {
+        #[serde(default = "default_wide_radius")]
+        radius: Float,
+    },
+}
+
+fn default_tent_radius() -> Float {
+    1.0
+}
+
+fn default_wide_radius() -> Float {
+    1.5
+}
+
+fn default_sigma() -> Float {
+    0.5
+}
+
+impl PixelFilter {
+    // Whether samples only ever contribute to the pixel they were taken in
+    pub fn is_pixel_sized(&self) -> bool {
+        matches!(self, PixelFilter::Box)
+    }
+
+    // How many pixels away from its own a sample can contribute to
+    pub fn margin(&self) -> u32 {
+        if self.is_pixel_sized() {
+            return 0;
+        }
+        // Jittered samples lie up to half a pixel from the pixel centre
+        (self.radius() + 0.5).floor() as u32
+    }
+
+    fn radius(&self) -> Float {
+        match self {
+            PixelFilter::Box => 0.5,
+            PixelFilter::Tent { radius }
+            | PixelFilter::Gaussian { radius, .. }
+            | PixelFilter::BlackmanHarris { radius } => *radius,
+        }
+    }
+
+    // Weight along one axis for a sample at the given distance from a pixel centre
+    fn weight_1d(&self, d: Float) -> Float {
+        let radius = self.radius();
+        if d.abs() > radius {
+            return 0.0;
+        }
+        match self {
+            PixelFilter::Box => 1.0,
+            PixelFilter::Tent { .. } => 1.0 - d.abs() / radius,
+            PixelFilter::Gaussian { sigma, .. } => {
+                let gaussian = |x: Float| (-x * x / (2.0 * sigma * sigma)).exp();
+                // Shifted down so the weight reaches zero at the radius
+                (gaussian(d) - gaussian(radius)).max(0.0)
+            }
+            PixelFilter::BlackmanHarris { .. } => {
+                let t = 2.0 * PI * (d / (2.0 * radius) + 0.5);
+                0.35875 - 0.48829 * t.cos() + 0.14128 * (2.0 * t).cos() - 0.01168 * (3.0 * t).cos()
+            }
+        }
+    }
+
+    fn weight(&self, dx: Float, dy: Float) -> Float {
+        self.weight_1d(dx) * self.weight_1d(dy)
+    }
+}
+
+/*
+Accumulates filtered samples into pixels. Each sample is splatted into every
+pixel whose filter footprint contains it, and pixels are normalised by their
+total weight.
+ */
+pub struct Film {
+    width: u32,
+    height: u32,
+    filter: PixelFilter,
+    colours: Vec<FVec>,
+    alphas: Vec<Float>,
+    weights: Vec<Float>,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32, filter: PixelFilter) -> Film {
+        let size = (width * height) as usize;
+        Film {
+            width,
+            height,
+            filter,
+            colours: vec![FVec::zeros(); size],
+            alphas: vec![0.0; size],
+            weights: vec![0.0; size],
+        }
+    }
+
+    // Add a sample taken at film position (x, y)
+    pub fn add_sample(&mut self, x: Float, y: Float, colour: &FVec, alpha: Float) {
+        let radius = self.filter.radius();
+        let x_range = (x - radius).ceil().max(0.0) as u32..=(x + radius).floor() as u32;
+        for py in (y - radius).ceil().max(0.0) as u32..=(y + radius).floor() as u32 {
+            for px in x_range.clone() {
+                if px >= self.width || py >= self.height {
+                    continue;
+                }
+                let weight = self.filter.weight(px as Float - x, py as Float - y);
+                let index = (py * self.width + px) as usize;
+                self.colours[index] += colour * weight;
+                self.alphas[index] += alpha * weight;
+                self.weights[index] += weight;
+            }
+        }
+    }
+
+    // Filtered colour and alpha of a pixel, or zeros if no sample reached it
+    pub fn pixel(&self, x: u32, y: u32) -> (FVec, Float) {
+        let index = (y * self.width + x) as usize;
+        let weight = self.weights[index];
+        if weight <= 0.0 {
+            return (FVec::zeros(), 0.0);
+        }
+        (self.colours[index] / weight, self.alphas[index] / weight)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILTERS: [PixelFilter; 4] = [
+        PixelFilter::Box,
+        PixelFilter::Tent { radius: 1.0 },
+        PixelFilter::Gaussian {
+            radius: 1.5,
+            sigma: 0.5,
+        },
+        PixelFilter::BlackmanHarris { radius: 1.5 },
+    ];
+
+    #[test]
+    fn weights_vanish_outside_the_radius() {
+        for filter in FILTERS {
+            let radius = filter.radius();
+            for d in [radius + 1e-3, radius + 0.5, 10.0] {
+                assert_eq!(filter.weight_1d(d), 0.0, "{filter:?} at {d}");
+                assert_eq!(filter.weight_1d(-d), 0.0, "{filter:?} at {}", -d);
+            }
+        }
+    }
+
+    #[test]
+    fn weights_are_symmetric_and_fall_away_from_the_centre() {
+        for filter in FILTERS {
+            let radius = filter.radius();
+            let mut previous = filter.weight_1d(0.0);
+            for step in 1..=10 {
+                let d = radius * step as Float / 10.0;
+                let weight = filter.weight_1d(d);
+                assert!(
+                    (weight - filter.weight_1d(-d)).abs() < 1e-6,
+                    "{filter:?} at {d}"
+                );
+                assert!(weight <= previous + 1e-6, "{filter:?} rises at {d}");
+                previous = weight;
+            }
+        }
+    }
+
+    #[test]
+    fn weights_match_their_formulas() {
+        let close = |a: Float, b: Float| (a - b).abs() < 1e-4;
+        let box_filter = PixelFilter::Box;
+        assert_eq!(box_filter.weight_1d(0.0), 1.0);
+        assert_eq!(box_filter.weight_1d(0.5), 1.0);
+        assert_eq!(box_filter.weight_1d(0.6), 0.0);
+
+        let tent = PixelFilter::Tent { radius: 2.0 };
+        assert!(close(tent.weight_1d(0.0), 1.0));
+        assert!(close(tent.weight_1d(0.5), 0.75));
+        assert!(close(tent.weight_1d(-1.5), 0.25));
+        assert!(close(tent.weight_1d(2.0), 0.0));
+
+        // Shifted down by the Gaussian's value at the radius, exp(-4.5) here
+        let gaussian = PixelFilter::Gaussian {
+            radius: 1.5,
+            sigma: 0.5,
+        };
+        let tail = (-4.5 as Float).exp();
+        assert!(close(gaussian.weight_1d(0.0), 1.0 - tail));
+        assert!(close(gaussian.weight_1d(0.5), (-0.5 as Float).exp() - tail));
+        assert!(close(gaussian.weight_1d(1.5), 0.0));
+
+        let blackman_harris = PixelFilter::BlackmanHarris { radius: 1.5 };
+        assert!(close(blackman_harris.weight_1d(0.0), 1.0));
+        assert!(close(blackman_harris.weight_1d(1.5), 0.0));
+        assert!(close(blackman_harris.weight_1d(-1.5), 0.0));
+    }
+}
@@ -0,0 +1,187 @@
+use crate::bounds::Aabb;
+use crate::core::ray::Ray;
+use crate::{FVec, Float};
+use nalgebra::Matrix3;
+use serde::Deserialize;
+
+// Steps of the march towards the lights through the scattering volume of an object
+pub(crate) const VOLUME_STEPS: u32 = 16;
+// Marches stop where this little of the light from further away gets through
+pub(crate) const MIN_TRANSMITTANCE: Float = 1e-3;
+
+/*
+Fog filling the space between surfaces evenly. Whatever is seen through it
+fades by Beer's law with distance, and the light it scatters is added back:
+the ambient light everywhere, and that of the scene's lights found by
+marching along each ray with a shadow ray at every step, so objects cast
+shafts of shadow through lit fog. The light reaching the fog and surfaces is
+not dimmed on its way from the lights.
+ */
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Fog {
+    // Fraction of the light stopped per metre
+    pub(crate) density: Float,
+    // Fraction of the stopped light scattered rather than absorbed, per colour channel
+    #[serde(default = "default_colour")]
+    pub(crate) colour: FVec,
+    // From -1 for scattering back towards the lights to 1 for straight on, which makes halos
+    #[serde(default)]
+    pub(crate) anisotropy: Float,
+    // Steps of the march towards the lights along each ray; 0 leaves only the ambient light
+    #[serde(default = "default_steps")]
+    pub(crate) steps: u32,
+}
+
+fn default_colour() -> FVec {
+    FVec::repeat(1.0)
+}
+
+fn default_steps() -> u32 {
+    16
+}
+
+// What a stretch of the space a ray travels through takes out of its light and adds back
+pub(crate) struct Volume {
+    // Light absorbed and scattered per metre
+    pub(crate) absorption: FVec,
+    pub(crate) scattering: FVec,
+    pub(crate) anisotropy: Float,
+    pub(crate) steps: u32,
+}
+
+impl Fog {
+    pub(crate) fn volume(&self) -> Volume {
+        let colour = self.colour.map(|c| c.clamp(0.0, 1.0));
+        Volume {
+            absorption: (FVec::repeat(1.0) - colour) * self.density,
+            scattering: colour * self.density,
+            anisotropy: self.anisotropy,
+            steps: self.steps,
+        }
+    }
+}
+
+impl Volume {
+    pub(crate) fn extinction(&self) -> FVec {
+        self.absorption + self.scattering
+    }
+
+    // Fraction of the light that gets through the distance, which may be infinite
+    pub(crate) fn transmittance(&self, distance: Float) -> FVec {
+        self.extinction()
+            .map(|e| if e > 0.0 { (-e * distance).exp() } else { 1.0 })
+    }
+
+    /*
+    Length of the stretch worth marching along out of the distance: past the
+    point where almost no light of a scattering channel gets back, the rest
+    adds nothing that shows.
+     */
+    pub(crate) fn march_length(&self, distance: Float) -> Float {
+        let thinnest = self
+            .extinction()
+            .iter()
+            .zip(self.scattering.iter())
+            .filter(|(_, s)| **s > 0.0)
+            .map(|(e, _)| *e)
+            .fold(Float::INFINITY, Float::min);
+        distance.min(-MIN_TRANSMITTANCE.ln() / thinnest)
+    }
+
+    // Ambient light scattered towards the viewer over the distance
+    pub(crate) fn ambient(&self, ambient_light: &FVec, distance: Float) -> FVec {
+        let extinction = self.extinction();
+        let transmittance = self.transmittance(distance);
+        FVec::from_fn(|i, _| {
+            if extinction[i] > 0.0 {
+                self.scattering[i] / extinction[i] * (1.0 - transmittance[i]) * ambient_light[i]
+            } else {
+                0.0
+            }
+        })
+    }
+
+    /*
+    Share of the light from a light scattered back along a ray, given the
+    cosine of the angle between the ray, heading away from the viewer, and the
+    direction towards the light. Henyey-Greenstein, times pi as the lambert
+    term of surfaces leaves out its 1/pi, so fog and surfaces lit alike look
+    alike.
+     */
+    pub(crate) fn phase(&self, cos_angle: Float) -> Float {
+        let g = self.anisotropy.clamp(-0.99, 0.99);
+        let denominator = 1.0 + g * g - 2.0 * g * cos_angle;
+        (1.0 - g * g) / (4.0 * denominator * denominator.sqrt())
+    }
+}
+
+/*
+Fog of its own density and colour filling a box or a sphere, for mist lying
+in a valley or haze inside a room while the rest of the scene stays clear.
+Drawn like the scene's fog along the stretch of each ray inside it.
+ */
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LocalFog {
+    pub(crate) bounds: FogBounds,
+    #[serde(flatten)]
+    pub(crate) fog: Fog,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum FogBounds {
+    Box { min: FVec, max: FVec },
+    Sphere { centre: FVec, radius: Float },
+}
+
+impl LocalFog {
+    /*
+    Distances along the ray's direction, taken as a unit vector, at which it
+    enters and leaves the fog, kept to between its origin and the distance.
+     */
+    pub(crate) fn entry_exit(&self, ray: &Ray, distance: Float) -> Option<(Float, Float)> {
+        let direction = ray.direction.normalize();
+        let (near, far) = match self.bounds {
+            FogBounds::Box { min, max } => {
+                let unit = Ray { direction, ..*ray };
+                Aabb { min, max }.entry_exit(&unit, 0.0, distance)?
+            }
+            FogBounds::Sphere { centre, radius } => {
+                let offset = ray.origin - centre;
+                let b = offset.dot(&direction);
+                let discriminant = b * b - (offset.norm_squared() - radius * radius);
+                if discriminant < 0.0 {
+                    return None;
+                }
+                let root = discriminant.sqrt();
+                ((-b - root).max(0.0), (-b + root).min(distance))
+            }
+        };
+        (near < far).then_some((near, far))
+    }
+
+    pub(crate) fn reorient(&mut self, axes: &Matrix3<Float>) {
+        match &mut self.bounds {
+            FogBounds::Box { min, max } => {
+                let (a, b) = (axes * *min, axes * *max);
+                (*min, *max) = (a.inf(&b), a.sup(&b));
+            }
+            FogBounds::Sphere { centre, .. } => *centre = axes * *centre,
+        }
+    }
+
+    pub(crate) fn scale(&mut self, factor: Float) {
+        match &mut self.bounds {
+            FogBounds::Box { min, max } => {
+                *min *= factor;
+                *max *= factor;
+            }
+            FogBounds::Sphere { centre, radius } => {
+                *centre *= factor;
+                *radius *= factor;
+            }
+        }
+    }
+}
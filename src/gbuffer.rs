@@ -0,0 +1,359 @@
+use crate::core::consts::PI;
+use crate::core::ray::{Intersection, Ray};
+use crate::core::single;
+use crate::filter::PixelFilter;
+use crate::sampling::Rng;
+use crate::{FVec, Float};
+use image::{
+    imageops, DynamicImage, ImageBuffer, ImageError, ImageFormat, Pixel, Rgb, Rgb32FImage,
+};
+use serde::Deserialize;
+use std::path::Path;
+
+// The first object a primary ray hits, with surface coordinates at the hit
+pub struct FirstHit {
+    pub object: usize,
+    pub intersection: Intersection,
+    pub uv: (Float, Float),
+    // Hit position relative to the object's own origin and axes
+    pub object_position: FVec,
+    // Film pixels the hit point moves by the next frame
+    pub motion: (Float, Float),
+    // Distance in metres in front of the camera, along its view direction
+    pub depth: Float,
+    // Colour of the surface from its material and textures, before any lighting
+    pub albedo: FVec,
+    // Length in metres of the path light took to the camera, when a pathLength AOV is written
+    pub path_length: Option<Float>,
+    // Whether the shading normal was turned back towards the camera, when that AOV is written
+    pub normal_corrected: bool,
+}
+
+pub struct PrimarySample {
+    pub ray: Ray,
+    // Film position relative to the pixel centre
+    pub offset: (Float, Float),
+    pub hit: Option<FirstHit>,
+}
+
+pub struct PixelSamples {
+    pub x: u32,
+    pub y: u32,
+    pub samples: Vec<PrimarySample>,
+}
+
+/*
+First hits of every primary ray of a render. Shading only needs these plus
+the scene's materials and lights, so a scene whose geometry and camera are
+unchanged can be re-shaded from the buffer without tracing primary rays again.
+ */
+pub struct GBuffer {
+    pub width: u32,
+    pub height: u32,
+    // Film position of the top-left pixel; pixel coordinates are film positions
+    pub origin: (u32, u32),
+    pub filter: PixelFilter,
+    // Pixels on each side beyond the frame; width and height include them
+    pub overscan: u32,
+    pub pixels: Vec<PixelSamples>,
+}
+
+// Per-pixel data that can be written out alongside the shaded image
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum Aov {
+    // Distance of the hit in metres in front of the camera along its view direction, in all three
+    Depth,
+    // Distance of the hit in metres from the camera along the ray, as range sensors measure it
+    Range,
+    /*
+    Distance light travels to the camera from the surface that scatters it,
+    through mirrors and glass, as time-of-flight cameras measure it; see
+    Scene::_get_path_length.
+     */
+    PathLength,
+    // World-space hit position in metres
+    Position,
+    // Hit position in metres in the space of the object that was hit
+    ObjectPosition,
+    // Unit surface normal, components in [-1, 1]
+    Normal,
+    // Surface colour before lighting, as denoisers take alongside the normal
+    Albedo,
+    // A stable pseudo-random colour per scene object
+    ObjectId,
+    // White where the output's objects are seen, or any object when it lists none
+    Mask,
+    // Surface coordinates in the red and green channels
+    Uv,
+    // Screen-space motion to the next frame in pixels, x right and y down, in red and green
+    Motion,
+    // Samples taken in the pixel, from blue for the fewest through green to red for the most
+    Samples,
+    // White where the shading normal faced away from the camera and was turned back to it
+    NormalCorrection,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AovOutput {
+    pub aov: Aov,
+    // Only written into the multilayer output when unset
+    pub path: Option<String>,
+    // Objects by index, name or tag that a mask covers
+    pub objects: Option<Vec<usize>>,
+    // Whether a mask is wholly white or black in each pixel, by what most of its samples see
+    #[serde(default)]
+    pub binary: bool,
+    // Layer name in multilayer outputs, that of the AOV by default
+    #[cfg_attr(not(feature = "exr"), allow(dead_code))]
+    pub name: Option<String>,
+    // Errors of a simulated sensor added to a range or path length
+    pub noise: Option<RangeNoise>,
+}
+
+/*
+How a depth sensor or LiDAR gets distances wrong. The error is Gaussian, with
+a deviation growing as a power of the distance: 0 for the even error of a
+LiDAR, 2 for structured light. Pixels read 0 where the sensor gets nothing
+back: outside its range, at random dropouts, and where nothing is hit.
+ */
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct RangeNoise {
+    // Standard deviation in metres of the error at one metre
+    #[serde(default)]
+    pub deviation: Float,
+    #[serde(default)]
+    pub power: Float,
+    // Step in metres distances are rounded to, or 0 to keep them as they are
+    #[serde(default)]
+    pub resolution: Float,
+    #[serde(default)]
+    pub min_range: Float,
+    #[serde(default = "default_max_range")]
+    pub max_range: Float,
+    // Share of pixels that read nothing at random
+    #[serde(default)]
+    pub dropout: Float,
+    #[serde(default)]
+    pub seed: u64,
+}
+
+fn default_max_range() -> Float {
+    Float::INFINITY
+}
+
+impl Aov {
+    // Layer name in multilayer outputs
+    #[cfg(feature = "exr")]
+    pub fn name(self) -> &'static str {
+        match self {
+            Aov::Depth => "depth",
+            Aov::Range => "range",
+            Aov::PathLength => "pathLength",
+            Aov::Position => "position",
+            Aov::ObjectPosition => "objectPosition",
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::ObjectId => "objectId",
+            Aov::Mask => "mask",
+            Aov::Uv => "uv",
+            Aov::Motion => "motion",
+            Aov::Samples => "samples",
+            Aov::NormalCorrection => "normalCorrection",
+        }
+    }
+}
+
+impl AovOutput {
+    #[cfg(feature = "exr")]
+    pub fn layer_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(self.aov.name())
+    }
+}
+
+impl PrimarySample {
+    pub fn position(&self) -> Option<FVec> {
+        self.hit.as_ref().map(|hit| hit.intersection.pos)
+    }
+
+    pub fn object_position(&self) -> Option<FVec> {
+        self.hit.as_ref().map(|hit| hit.object_position)
+    }
+
+    pub fn normal(&self) -> Option<FVec> {
+        self.hit.as_ref().map(|hit| hit.intersection.normal)
+    }
+
+    pub fn object(&self) -> Option<usize> {
+        self.hit.as_ref().map(|hit| hit.object)
+    }
+
+    pub fn uv(&self) -> Option<(Float, Float)> {
+        self.hit.as_ref().map(|hit| hit.uv)
+    }
+
+    pub fn motion(&self) -> Option<(Float, Float)> {
+        self.hit.as_ref().map(|hit| hit.motion)
+    }
+
+    // Distance from the camera to the hit along the ray
+    pub fn range(&self) -> Option<Float> {
+        self.position().map(|pos| (pos - self.ray.origin).norm())
+    }
+
+    fn distance(&self, aov: Aov) -> Option<Float> {
+        match aov {
+            Aov::PathLength => self.hit.as_ref().and_then(|hit| hit.path_length),
+            _ => self.range(),
+        }
+    }
+
+    fn aov(&self, aov: Aov, objects: Option<&[usize]>) -> FVec {
+        let value = match aov {
+            Aov::Depth => self.hit.as_ref().map(|hit| FVec::repeat(hit.depth)),
+            Aov::Range | Aov::PathLength => self.distance(aov).map(FVec::repeat),
+            Aov::Position => self.position(),
+            Aov::ObjectPosition => self.object_position(),
+            Aov::Normal => self.normal(),
+            Aov::Albedo => self.hit.as_ref().map(|hit| hit.albedo),
+            Aov::ObjectId => self.object().map(object_colour),
+            Aov::Mask => self
+                .object()
+                .filter(|object| objects.is_none_or(|objects| objects.contains(object)))
+                .map(|_| FVec::repeat(1.0)),
+            Aov::Uv => self.uv().map(|(u, v)| FVec::new(u, v, 0.0)),
+            Aov::Motion => self.motion().map(|(x, y)| FVec::new(x, y, 0.0)),
+            Aov::NormalCorrection => self
+                .hit
+                .as_ref()
+                .filter(|hit| hit.normal_corrected)
+                .map(|_| FVec::repeat(1.0)),
+            // A property of the whole pixel, worked out there
+            Aov::Samples => None,
+        };
+        value.unwrap_or_else(FVec::zeros)
+    }
+}
+
+impl PixelSamples {
+    // Whether any sample of this pixel first hits an object for which the predicate holds
+    pub fn hits_any(&self, predicate: impl Fn(usize) -> bool) -> bool {
+        self.samples
+            .iter()
+            .any(|sample| sample.object().is_some_and(&predicate))
+    }
+}
+
+impl GBuffer {
+    /*
+    Image of one AOV, averaged over each pixel's samples the same way colours
+    are. Samples that miss every object contribute zero.
+     */
+    pub fn aov(&self, output: &AovOutput) -> Rgb32FImage {
+        let (aov, objects) = (output.aov, output.objects.as_deref());
+        let mut image = Rgb32FImage::new(self.width, self.height);
+        let counts = self.pixels.iter().map(|pixel| pixel.samples.len());
+        let (fewest, most) = (counts.clone().min().unwrap_or(0), counts.max().unwrap_or(0));
+        for pixel in &self.pixels {
+            let value = match aov {
+                Aov::Samples => {
+                    let range = (most - fewest).max(1) as Float;
+                    heat((pixel.samples.len() - fewest) as Float / range)
+                }
+                // Misses are left out, as a sensor reads the surfaces it sees in the pixel
+                Aov::Range | Aov::PathLength => {
+                    let distances: Vec<Float> = pixel
+                        .samples
+                        .iter()
+                        .filter_map(|sample| sample.distance(aov))
+                        .collect();
+                    let distance = (!distances.is_empty())
+                        .then(|| distances.iter().sum::<Float>() / distances.len() as Float);
+                    let measured = match (&output.noise, distance) {
+                        (Some(noise), Some(distance)) => {
+                            let mut rng = Rng::for_sample(noise.seed, pixel.x, pixel.y, 0);
+                            noise.measure(distance, &mut rng)
+                        }
+                        _ => distance,
+                    };
+                    FVec::repeat(measured.unwrap_or(0.0))
+                }
+                Aov::Mask if output.binary => {
+                    let covered = pixel.samples.iter().filter(|s| s.aov(aov, objects).x > 0.0);
+                    let most = 2 * covered.count() >= pixel.samples.len();
+                    FVec::repeat(if most { 1.0 } else { 0.0 })
+                }
+                _ => {
+                    let total: FVec = pixel
+                        .samples
+                        .iter()
+                        .map(|sample| sample.aov(aov, objects))
+                        .sum();
+                    total / pixel.samples.len() as Float
+                }
+            };
+            let (x, y) = (pixel.x - self.origin.0, pixel.y - self.origin.1);
+            image.put_pixel(x, y, Rgb(value.map(single).into()));
+        }
+        image
+    }
+
+    /*
+    Write an AOV to the given path, cropped to the frame. Floating-point
+    formats keep the raw values; anything else is clamped to [0, 1] and stored
+    as 8-bit.
+     */
+    pub fn save_aov(&self, output: &AovOutput, path: &str) -> Result<(), ImageError> {
+        let image = DynamicImage::ImageRgb32F(crop_overscan(self.aov(output), self.overscan));
+        match ImageFormat::from_path(Path::new(path))? {
+            ImageFormat::OpenExr | ImageFormat::Hdr => image.save(path),
+            _ => image.to_rgb8().save(path),
+        }
+    }
+}
+
+impl RangeNoise {
+    // The distance the sensor reads for a true distance, or none if it gets nothing back
+    fn measure(&self, distance: Float, rng: &mut Rng) -> Option<Float> {
+        if !(self.min_range..=self.max_range).contains(&distance) || rng.next_float() < self.dropout
+        {
+            return None;
+        }
+        // Box-Muller transform of two uniform numbers
+        let (u1, u2) = (rng.next_float(), rng.next_float());
+        let gaussian = (-2.0 * (1.0 - u1).ln()).sqrt() * (2.0 * PI * u2).cos();
+        let measured = distance + self.deviation * distance.powf(self.power) * gaussian;
+        let measured = if self.resolution > 0.0 {
+            (measured / self.resolution).round() * self.resolution
+        } else {
+            measured
+        };
+        Some(measured.max(0.0))
+    }
+}
+
+// The frame inside an image rendered with the given overscan margin
+pub fn crop_overscan<P: Pixel + 'static>(
+    image: ImageBuffer<P, Vec<P::Subpixel>>,
+    overscan: u32,
+) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    if overscan == 0 {
+        return image;
+    }
+    let width = image.width() - 2 * overscan;
+    let height = image.height() - 2 * overscan;
+    imageops::crop_imm(&image, overscan, overscan, width, height).to_image()
+}
+
+// Colour of a fraction in [0, 1] on a scale from blue through green to red
+pub(crate) fn heat(t: Float) -> FVec {
+    let t = 2.0 * t.clamp(0.0, 1.0) - 1.0;
+    FVec::new(t.max(0.0), 1.0 - t.abs(), (-t).max(0.0))
+}
+
+fn object_colour(object: usize) -> FVec {
+    let mut rng = Rng::new(object as u64, 0);
+    FVec::new(rng.next_float(), rng.next_float(), rng.next_float())
+}
@@ -0,0 +1,118 @@
+use crate::mesh::{Triangle, TriangleMesh};
+use crate::{FVec, Float};
+use base64::Engine;
+use gltf::buffer::Source;
+use gltf::mesh::Mode;
+use gltf::{Gltf, Node};
+use nalgebra::Matrix4;
+use std::path::Path;
+
+// Contents of every buffer of the file, from the GLB chunk, a data URI or a file relative to dir
+fn read_buffers(gltf: &Gltf, dir: &Path) -> Result<Vec<Vec<u8>>, String> {
+    gltf.buffers()
+        .map(|buffer| {
+            let data = match buffer.source() {
+                Source::Bin => gltf.blob.clone().ok_or("missing binary chunk")?,
+                Source::Uri(uri) => match uri.strip_prefix("data:") {
+                    Some(data) => {
+                        let (_, encoded) = data
+                            .split_once(";base64,")
+                            .ok_or("only base64 data URIs are supported")?;
+                        base64::engine::general_purpose::STANDARD
+                            .decode(encoded)
+                            .map_err(|e| e.to_string())?
+                    }
+                    None => std::fs::read(dir.join(uri))
+                        .map_err(|e| format!("{}: {}", dir.join(uri).display(), e))?,
+                },
+            };
+            if data.len() < buffer.length() {
+                return Err(format!("buffer {} is shorter than it says", buffer.index()));
+            }
+            Ok(data)
+        })
+        .collect()
+}
+
+// Triangles of a node's meshes and its children's, placed by their transforms
+fn read_node(
+    node: &Node,
+    parent: &Matrix4<Float>,
+    buffers: &[Vec<u8>],
+    triangles: &mut Vec<Triangle>,
+) -> Result<(), String> {
+    let local = node.transform().matrix();
+    let matrix = parent * Matrix4::from_fn(|row, column| local[column][row] as Float);
+    if let Some(mesh) = node.mesh() {
+        let mut local_triangles = Vec::new();
+        for primitive in mesh.primitives() {
+            if primitive.mode() != Mode::Triangles {
+                continue;
+            }
+            let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(Vec::as_slice));
+            let to_vector = |v: [f32; 3]| FVec::new(v[0] as Float, v[1] as Float, v[2] as Float);
+            let positions: Vec<FVec> = reader
+                .read_positions()
+                .ok_or("a primitive has no positions")?
+                .map(to_vector)
+                .collect();
+            let normals: Option<Vec<FVec>> = reader
+                .read_normals()
+                .map(|normals| normals.map(to_vector).collect());
+            let colours: Option<Vec<FVec>> = reader
+                .read_colors(0)
+                .map(|colours| colours.into_rgb_f32().map(to_vector).collect());
+            let uvs: Option<Vec<(Float, Float)>> = reader.read_tex_coords(0).map(|uvs| {
+                uvs.into_f32()
+                    .map(|[u, v]| (u as Float, v as Float))
+                    .collect()
+            });
+            let indices: Vec<usize> = match reader.read_indices() {
+                Some(indices) => indices.into_u32().map(|i| i as usize).collect(),
+                None => (0..positions.len()).collect(),
+            };
+            for corners in indices.chunks_exact(3) {
+                let corners = [corners[0], corners[1], corners[2]];
+                if corners.iter().any(|&i| i >= positions.len()) {
+                    return Err(format!(
+                        "vertex index out of range in mesh {}",
+                        mesh.index()
+                    ));
+                }
+                local_triangles.push(Triangle {
+                    vertices: corners.map(|i| positions[i]),
+                    normals: normals.as_ref().map(|normals| corners.map(|i| normals[i])),
+                    colours: colours.as_ref().map(|colours| corners.map(|i| colours[i])),
+                    uvs: uvs.as_ref().map(|uvs| corners.map(|i| uvs[i])),
+                    dissolve: None,
+                });
+            }
+        }
+        let mesh = TriangleMesh::new(local_triangles, FVec::zeros()).transformed(&matrix);
+        triangles.extend(mesh.triangles);
+    }
+    for child in node.children() {
+        read_node(&child, &matrix, buffers, triangles)?;
+    }
+    Ok(())
+}
+
+/*
+Triangles of the default scene in a glTF file, .gltf or .glb, with buffers
+relative to dir. Vertices may carry normals, the first set of texture
+coordinates and the first set of colours, which glTF stores linear, with any
+alpha dropped. Primitives drawn as points or lines are skipped.
+ */
+pub fn parse_gltf(bytes: &[u8], dir: &Path) -> Result<Vec<Triangle>, String> {
+    let gltf = Gltf::from_slice(bytes).map_err(|e| e.to_string())?;
+    let buffers = read_buffers(&gltf, dir)?;
+    let scene = gltf
+        .default_scene()
+        .or_else(|| gltf.scenes().next())
+        .ok_or("the file has no scenes")?;
+    let mut triangles = Vec::new();
+    for node in scene.nodes() {
+        read_node(&node, &Matrix4::identity(), &buffers, &mut triangles)?;
+    }
+    Ok(triangles)
+}
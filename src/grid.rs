@@ -0,0 +1,303 @@
+use crate::bounds::Aabb;
+use crate::core::ray::Ray;
+use crate::core::single;
+use crate::fog::MIN_TRANSMITTANCE;
+use crate::sampling::Rng;
+use crate::{FVec, Float};
+use image::{Rgba, RgbaImage};
+use nalgebra::Matrix3;
+use serde::Deserialize;
+use std::error::Error;
+use std::fs;
+use std::mem::size_of;
+use std::path::Path;
+use std::sync::Arc;
+
+/*
+A grid of densities, as from a CT scan or a simulation, filling a box in the
+scene and drawn through a transfer function giving each density a colour and
+an opacity. Rays crossing the box take on the colour of every density they
+pass, each dimming what lies behind it by its opacity; the grid glows with
+these colours rather than being lit, so they show the data as chosen.
+ */
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GridVolume {
+    // Raw file of the values without a header, little-endian, x varying fastest and then y
+    pub(crate) path: String,
+    // Values along x, y and z; the first and last along each axis lie on the faces of the box
+    pub(crate) dimensions: [usize; 3],
+    #[serde(default)]
+    pub(crate) format: GridFormat,
+    // Corners of the box the grid fills
+    pub(crate) min: FVec,
+    pub(crate) max: FVec,
+    // Stored values that are densities 0 and 1 to the transfer function; the format's by default
+    pub(crate) range: Option<[Float; 2]>,
+    // Colours and opacities at increasing densities, blended linearly between them
+    pub(crate) transfer_function: Vec<TransferPoint>,
+    // Fraction of the light stopped per metre where the opacity is 1
+    #[serde(default = "default_extinction")]
+    pub(crate) extinction: Float,
+    // Length in metres of the steps marched through the box, half a voxel by default
+    pub(crate) step: Option<Float>,
+    // Densities mapped by the range, in the order of the file
+    #[serde(skip)]
+    pub(crate) densities: Arc<Vec<f32>>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum GridFormat {
+    // Unsigned bytes, 0 to 255 by default
+    U8,
+    // Unsigned 16-bit integers, 0 to 65535 by default
+    U16,
+    // 32-bit floats, already densities from 0 to 1 by default
+    #[default]
+    F32,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferPoint {
+    pub(crate) density: Float,
+    pub(crate) colour: FVec,
+    // From 0 for empty space to 1 for the volume's full extinction
+    pub(crate) opacity: Float,
+}
+
+fn default_extinction() -> Float {
+    10.0
+}
+
+impl GridFormat {
+    fn bytes(self) -> usize {
+        match self {
+            GridFormat::U8 => 1,
+            GridFormat::U16 => 2,
+            GridFormat::F32 => 4,
+        }
+    }
+
+    fn range(self) -> [Float; 2] {
+        match self {
+            GridFormat::U8 => [0.0, u8::MAX as Float],
+            GridFormat::U16 => [0.0, u16::MAX as Float],
+            GridFormat::F32 => [0.0, 1.0],
+        }
+    }
+
+    fn decode(self, bytes: &[u8]) -> Float {
+        match self {
+            GridFormat::U8 => bytes[0] as Float,
+            GridFormat::U16 => u16::from_le_bytes([bytes[0], bytes[1]]) as Float,
+            GridFormat::F32 => f32::from_le_bytes(bytes.try_into().unwrap()) as Float,
+        }
+    }
+}
+
+impl GridVolume {
+    // Read the grid's file, found relative to the scene's directory
+    pub(crate) fn load(&mut self, base_dir: &Path) -> Result<(), Box<dyn Error>> {
+        let path = base_dir.join(&self.path);
+        let bytes = fs::read(&path).map_err(|error| format!("{}: {error}", path.display()))?;
+        let [nx, ny, nz] = self.dimensions;
+        let expected = nx * ny * nz * self.format.bytes();
+        if bytes.len() != expected {
+            return Err(format!(
+                "{}: a {nx}x{ny}x{nz} grid of {} takes {expected} bytes, not {}",
+                path.display(),
+                format!("{:?}", self.format).to_lowercase(),
+                bytes.len()
+            )
+            .into());
+        }
+        let [low, high] = self.range.unwrap_or(self.format.range());
+        let densities = bytes
+            .chunks_exact(self.format.bytes())
+            .map(|value| single((self.format.decode(value) - low) / (high - low)));
+        self.densities = Arc::new(densities.collect());
+        self.transfer_function
+            .sort_by(|a, b| a.density.total_cmp(&b.density));
+        Ok(())
+    }
+
+    /*
+    Turned into another set of axes by a matrix that only swaps and flips
+    them, with the values reordered to lie along the axes they now do.
+     */
+    pub(crate) fn reorient(&mut self, axes: &Matrix3<Float>) {
+        let (a, b) = (axes * self.min, axes * self.max);
+        (self.min, self.max) = (a.inf(&b), a.sup(&b));
+        // The old axis along each new one, and whether it runs the other way
+        let source: [(usize, bool); 3] = std::array::from_fn(|row| {
+            let column = axes.row(row).transpose().iamax();
+            (column, axes[(row, column)] < 0.0)
+        });
+        let old = self.dimensions;
+        self.dimensions = source.map(|(axis, _)| old[axis]);
+        let [nx, ny, nz] = self.dimensions;
+        let mut densities = Vec::with_capacity(self.densities.len());
+        for z in 0..nz {
+            for y in 0..ny {
+                for x in 0..nx {
+                    let mut index = [0; 3];
+                    for (i, (axis, flipped)) in [x, y, z].into_iter().zip(source) {
+                        index[axis] = if flipped { old[axis] - 1 - i } else { i };
+                    }
+                    let [i, j, k] = index;
+                    densities.push(self.densities[(k * old[1] + j) * old[0] + i]);
+                }
+            }
+        }
+        self.densities = Arc::new(densities);
+    }
+
+    pub(crate) fn scale(&mut self, factor: Float) {
+        self.min *= factor;
+        self.max *= factor;
+    }
+
+    pub(crate) fn bytes(&self) -> usize {
+        self.densities.len() * size_of::<f32>()
+    }
+
+    fn bounds(&self) -> Aabb {
+        Aabb {
+            min: self.min,
+            max: self.max,
+        }
+    }
+
+    // Distance in metres between neighbouring values along each axis
+    fn voxel_size(&self) -> FVec {
+        let cells = FVec::from_fn(|i, _| (self.dimensions[i].max(2) - 1) as Float);
+        (self.max - self.min).component_div(&cells)
+    }
+
+    // Density at a point in the box, blended between the eight values around it
+    fn density_at(&self, p: &FVec) -> Float {
+        let mut base = [0; 3];
+        let mut fraction = [0.0; 3];
+        for axis in 0..3 {
+            let cells = self.dimensions[axis].max(1) - 1;
+            let extent = self.max[axis] - self.min[axis];
+            let position =
+                ((p[axis] - self.min[axis]) / extent * cells as Float).clamp(0.0, cells as Float);
+            base[axis] = (position.floor() as usize).min(cells.saturating_sub(1));
+            fraction[axis] = position - base[axis] as Float;
+        }
+        let [nx, ny, _] = self.dimensions;
+        let value = |x: usize, y: usize, z: usize| {
+            let clamped = |i: usize, axis: usize| i.min(self.dimensions[axis] - 1);
+            let index = (clamped(z, 2) * ny + clamped(y, 1)) * nx + clamped(x, 0);
+            self.densities[index] as Float
+        };
+        let [x, y, z] = base;
+        let [fx, fy, fz] = fraction;
+        let lerp = |a: Float, b: Float, t: Float| a + (b - a) * t;
+        let along_x = |y, z| lerp(value(x, y, z), value(x + 1, y, z), fx);
+        let along_y = |z| lerp(along_x(y, z), along_x(y + 1, z), fy);
+        lerp(along_y(z), along_y(z + 1), fz)
+    }
+
+    // Colour and opacity of a density by the transfer function
+    fn transfer(&self, density: Float) -> (FVec, Float) {
+        let points = &self.transfer_function;
+        let after = points.partition_point(|point| point.density <= density);
+        let point = match (after.checked_sub(1), points.get(after)) {
+            (Some(before), Some(next)) => {
+                let before = &points[before];
+                let t = (density - before.density) / (next.density - before.density);
+                let colour = before.colour.lerp(&next.colour, t);
+                return (colour, before.opacity + (next.opacity - before.opacity) * t);
+            }
+            (Some(before), None) => &points[before],
+            (None, _) => &points[0],
+        };
+        (point.colour, point.opacity)
+    }
+
+    // Distance in metres along the ray to where it enters the box, if it does
+    pub(crate) fn entry(&self, ray: &Ray) -> Option<Float> {
+        let entry_exit = self
+            .bounds()
+            .entry_exit(&unit_ray(ray), 0.0, Float::INFINITY);
+        entry_exit.map(|(near, _)| near)
+    }
+
+    /*
+    Colour along a ray through the box up to the distance in metres, in front
+    of the colour seen beyond it. The march takes even steps from a random
+    start and stops early once almost nothing behind shows through.
+     */
+    pub(crate) fn march(
+        &self,
+        ray: &Ray,
+        distance: Float,
+        behind: FVec,
+        glowing: bool,
+        rng: &mut Rng,
+    ) -> FVec {
+        let unit = unit_ray(ray);
+        let Some((near, far)) = self.bounds().entry_exit(&unit, 0.0, distance) else {
+            return behind;
+        };
+        let far = far.min(distance);
+        let step = self.step.unwrap_or_else(|| 0.5 * self.voxel_size().min());
+        let mut colour = FVec::zeros();
+        let mut transmittance = 1.0;
+        let mut t = near + rng.next_float() * step;
+        while t < far && transmittance > MIN_TRANSMITTANCE {
+            let density = self.density_at(&(unit.origin + unit.direction * t));
+            let (emitted, opacity) = self.transfer(density);
+            let alpha = 1.0 - (-opacity.max(0.0) * self.extinction * step).exp();
+            colour += transmittance * alpha * emitted;
+            transmittance *= 1.0 - alpha;
+            t += step;
+        }
+        if !glowing {
+            colour = FVec::zeros();
+        }
+        colour + transmittance * behind
+    }
+
+    /*
+    Cut through the box square to an axis, a fraction of the way along it, as
+    an image with one pixel per value across the other two axes. Each pixel
+    has the colour of its density by the transfer function and its opacity
+    as alpha. Of the other two axes, the first runs to the right and the
+    second upwards.
+     */
+    pub(crate) fn slice(&self, axis: usize, depth: Float) -> RgbaImage {
+        let (across, up) = match axis {
+            0 => (1, 2),
+            1 => (0, 2),
+            _ => (0, 1),
+        };
+        let (width, height) = (self.dimensions[across], self.dimensions[up]);
+        let fraction = |i: u32, count: usize| i as Float / (count.max(2) - 1) as Float;
+        RgbaImage::from_fn(width as u32, height as u32, |x, y| {
+            let mut p = self.min;
+            p[axis] += depth.clamp(0.0, 1.0) * (self.max[axis] - self.min[axis]);
+            p[across] += fraction(x, width) * (self.max[across] - self.min[across]);
+            p[up] = self.max[up] - fraction(y, height) * (self.max[up] - self.min[up]);
+            let (colour, opacity) = self.transfer(self.density_at(&p));
+            let [r, g, b] = colour
+                .map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8)
+                .into();
+            Rgba([r, g, b, (opacity.clamp(0.0, 1.0) * 255.0).round() as u8])
+        })
+    }
+}
+
+// The ray with a unit direction, so distances along it are in metres
+fn unit_ray(ray: &Ray) -> Ray {
+    Ray {
+        origin: ray.origin,
+        direction: ray.direction.normalize(),
+        differential: None,
+        time: ray.time,
+    }
+}
@@ -0,0 +1,259 @@
+use crate::core::consts::PI;
+use crate::logging::StageTimer;
+use crate::region::Region;
+use crate::render::Integrator;
+use crate::sampling::Rng;
+use crate::{FVec, Float, Scene};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use serde::Deserialize;
+use std::fmt;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Slots the cells of space are hashed into; cells that share a slot share what they learn
+const SLOTS: usize = 4096;
+// Bands of the cosine of the angle to the z axis, and sectors around it, of the directions
+const BANDS: usize = 8;
+const SECTORS: usize = 16;
+const BINS: usize = BANDS * SECTORS;
+// Paths recorded in a cell before its distribution is trusted to guide others
+const MIN_RECORDED: u32 = 64;
+// Steps per unit of light in the fixed-point sums of a training pass
+const FIXED_POINT_SCALE: Float = 65536.0;
+// Brightest a recorded bounce counts as, which keeps the sums far from overflowing
+const MAX_RECORDED_BRIGHTNESS: Float = 1e4;
+// Spreads the seeds of the training passes away from the render's
+const TRAINING_SEEDS: u64 = 0xd6e8_feb8_6659_fd93;
+
+/*
+Path guiding for the path integrator: space is divided into cells, and each
+cell keeps a histogram over the sphere of the light arriving along bounces
+from it. Bounces off diffuse surfaces are then drawn partly from the
+histogram of their cell, which steers them towards the bright directions
+that cosine sampling rarely finds, such as a small lit opening or the caustic
+under a glass. The histograms learn before the render, in passes of one
+sample per pixel that each draw from what the passes before them learned,
+and are then left as they are, so a render depends only on its scene and
+seed. Directions are weighted by their probability under the mixture, so
+the image stays unbiased whatever has been learned.
+ */
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Guiding {
+    // Width of the cells in metres
+    #[serde(default = "default_cell_size")]
+    pub(crate) cell_size: Float,
+    // Fraction of bounces drawn from the histograms once their cell has learned enough
+    #[serde(default = "default_fraction")]
+    pub(crate) fraction: Float,
+    // Passes the histograms learn from before the render
+    #[serde(default = "default_training_passes")]
+    pub(crate) training_passes: u32,
+    // What the training passes learned, which copies of the scene share as it no longer changes
+    #[serde(skip)]
+    pub(crate) field: Arc<GuideField>,
+    // Bounces of the training pass under way, added to the field once the pass is over
+    #[serde(skip)]
+    learning: Option<Arc<Learning>>,
+}
+
+fn default_cell_size() -> Float {
+    0.5
+}
+
+fn default_fraction() -> Float {
+    0.5
+}
+
+fn default_training_passes() -> u32 {
+    3
+}
+
+// Light arriving in each bin of directions of each slot, and the paths recorded in each slot
+#[derive(Default)]
+pub struct GuideField {
+    // Both empty until a pass has learned something
+    bins: Vec<Float>,
+    recorded: Vec<u32>,
+}
+
+// The learned histograms are too large to print
+impl fmt::Debug for GuideField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let recorded: u64 = self.recorded.iter().map(|&r| r as u64).sum();
+        write!(f, "GuideField {{ recorded: {recorded} }}")
+    }
+}
+
+/*
+Bounces recorded by the threads of a training pass. Light is summed in
+fixed point, as integer sums come out the same whatever order the threads
+add to them in.
+ */
+struct Learning {
+    bins: Vec<AtomicU64>,
+    recorded: Vec<AtomicU32>,
+}
+
+impl fmt::Debug for Learning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Learning")
+    }
+}
+
+impl Learning {
+    fn new() -> Learning {
+        Learning {
+            bins: (0..SLOTS * BINS).map(|_| AtomicU64::new(0)).collect(),
+            recorded: (0..SLOTS).map(|_| AtomicU32::new(0)).collect(),
+        }
+    }
+
+    // The field with what was learned since it was added to it
+    fn added_to(&self, field: &GuideField) -> GuideField {
+        let learned = |index: usize| {
+            let bits = self.bins[index].load(Ordering::Relaxed);
+            bits as Float / FIXED_POINT_SCALE + field.bins.get(index).copied().unwrap_or(0.0)
+        };
+        let recorded = |slot: usize| {
+            let count = self.recorded[slot].load(Ordering::Relaxed);
+            count.saturating_add(field.recorded.get(slot).copied().unwrap_or(0))
+        };
+        GuideField {
+            bins: (0..SLOTS * BINS).map(learned).collect(),
+            recorded: (0..SLOTS).map(recorded).collect(),
+        }
+    }
+}
+
+// A cell's histogram, which a bounce is drawn from
+pub(crate) struct Snapshot<'a> {
+    weights: &'a [Float],
+    total: Float,
+}
+
+impl Guiding {
+    fn slot(&self, pos: &FVec) -> usize {
+        let cell = pos.map(|x| (x / self.cell_size).floor() as i64);
+        let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+        for coordinate in cell.iter() {
+            hash = (hash ^ *coordinate as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+        (hash % SLOTS as u64) as usize
+    }
+
+    // The histogram of the cell holding the point, if it has learned enough to guide bounces
+    pub(crate) fn snapshot(&self, pos: &FVec) -> Option<Snapshot<'_>> {
+        let slot = self.slot(pos);
+        if *self.field.recorded.get(slot)? < MIN_RECORDED {
+            return None;
+        }
+        let weights = &self.field.bins[slot * BINS..(slot + 1) * BINS];
+        let total = weights.iter().sum();
+        (total > 0.0).then_some(Snapshot { weights, total })
+    }
+
+    /*
+    Learn from a bounce from the point along the unit direction that brought
+    back the colour, during a training pass; renders learn nothing.
+     */
+    pub(crate) fn record(&self, pos: &FVec, direction: &FVec, colour: &FVec) {
+        let Some(learning) = &self.learning else {
+            return;
+        };
+        let brightness = colour.sum() / 3.0;
+        if !brightness.is_finite() || brightness < 0.0 {
+            return;
+        }
+        let slot = self.slot(pos);
+        let added = brightness.min(MAX_RECORDED_BRIGHTNESS) * FIXED_POINT_SCALE;
+        learning.bins[slot * BINS + bin_of(direction)].fetch_add(added as u64, Ordering::Relaxed);
+        learning.recorded[slot].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Scene {
+    /*
+    Learn the histograms of the path guide from scratch in its training
+    passes, each of one sample per pixel of the scene's camera with a seed of
+    its own. Bounces are drawn from what the passes before learned, and what
+    a pass learns is only added in once all its pixels are done, so the
+    histograms do not depend on the order the threads take the tiles in.
+     */
+    pub(crate) fn train_guide(&mut self) {
+        let Some(guiding) = self.guiding.clone() else {
+            return;
+        };
+        let mut field = Arc::new(GuideField::default());
+        if matches!(self.integrator, Integrator::Path) && guiding.training_passes > 0 {
+            let _timer = StageTimer::start("Training the path guide");
+            let seed = self.seed;
+            let mut camera = self.camera.clone();
+            camera.samples = 1;
+            camera.adaptive = None;
+            for pass in 1..=guiding.training_passes as u64 {
+                let learning = Arc::new(Learning::new());
+                self.guiding = Some(Guiding {
+                    field: field.clone(),
+                    learning: Some(learning.clone()),
+                    ..guiding.clone()
+                });
+                self.seed = seed ^ pass.wrapping_mul(TRAINING_SEEDS);
+                let scene = &*self;
+                let view = scene.view(&camera);
+                let tiles = scene._get_tiles(&camera.film_region());
+                let train_tile = |tile: &Region| {
+                    for pixel in scene._trace_tile(&view, tile) {
+                        scene._shade_pixel(&pixel);
+                    }
+                };
+                #[cfg(feature = "parallel")]
+                tiles.par_iter().for_each(train_tile);
+                #[cfg(not(feature = "parallel"))]
+                tiles.iter().for_each(train_tile);
+                field = Arc::new(learning.added_to(&field));
+            }
+            self.seed = seed;
+        }
+        self.guiding = Some(Guiding {
+            field,
+            learning: None,
+            ..guiding
+        });
+    }
+}
+
+impl Snapshot<'_> {
+    // A unit direction drawn in proportion to the light learned in each bin
+    pub(crate) fn sample(&self, rng: &mut Rng) -> FVec {
+        let mut target = rng.next_float() * self.total;
+        let mut bin = BINS - 1;
+        for (index, weight) in self.weights.iter().enumerate() {
+            if target < *weight {
+                bin = index;
+                break;
+            }
+            target -= weight;
+        }
+        let (band, sector) = (bin / SECTORS, bin % SECTORS);
+        let z = -1.0 + 2.0 * (band as Float + rng.next_float()) / BANDS as Float;
+        let phi = 2.0 * PI * (sector as Float + rng.next_float()) / SECTORS as Float;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        FVec::new(r * phi.cos(), r * phi.sin(), z)
+    }
+
+    // Probability density over solid angle of drawing the unit direction
+    pub(crate) fn pdf(&self, direction: &FVec) -> Float {
+        // Every bin covers the same solid angle
+        self.weights[bin_of(direction)] / self.total * BINS as Float / (4.0 * PI)
+    }
+}
+
+// Bin of a unit direction, by equal areas of the sphere
+fn bin_of(direction: &FVec) -> usize {
+    let band = ((direction.z + 1.0) * 0.5 * BANDS as Float) as usize;
+    let phi = direction.y.atan2(direction.x).rem_euclid(2.0 * PI);
+    let sector = (phi / (2.0 * PI) * SECTORS as Float) as usize;
+    band.min(BANDS - 1) * SECTORS + sector.min(SECTORS - 1)
+}
@@ -0,0 +1,93 @@
+use crate::config::find_asset;
+use crate::{Float, Scene};
+use image::imageops::{self, FilterType};
+use image::ImageResult;
+use serde::Deserialize;
+use std::path::Path;
+
+/*
+How the camera's samples are shared out between pixels. Each pixel takes
+the camera's sample count scaled by its importance, so the parts of the
+frame that matter are sampled finely and the rest coarsely.
+ */
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Importance {
+    /*
+    Greyscale image stretched over the frame, white for full importance.
+    When unset, pixels that see an object or are next to one are important
+    and those that only see the background are not.
+     */
+    pub map: Option<String>,
+    // Importance of the least important pixels, as a fraction of full
+    #[serde(default = "default_minimum")]
+    pub minimum: Float,
+}
+
+fn default_minimum() -> Float {
+    0.1
+}
+
+impl Importance {
+    /*
+    Importance of every film pixel, row by row, between the minimum and 1.
+    Overscan pixels take the importance of the nearest pixel of the frame.
+     */
+    pub fn resolve(&self, scene: &Scene, base_dir: &Path) -> ImageResult<Vec<Float>> {
+        let camera = &scene.camera;
+        let (columns, rows) = (camera.screen_columns, camera.screen_rows);
+        let frame = match &self.map {
+            Some(path) => load_map(&find_asset(base_dir, path), columns, rows)?,
+            None => geometry_map(scene),
+        };
+        let overscan = camera.overscan;
+        let (film_columns, film_rows) = (camera.film_columns(), camera.film_rows());
+        let film = (0..film_rows)
+            .flat_map(|y| (0..film_columns).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let x = x.saturating_sub(overscan).min(columns - 1);
+                let y = y.saturating_sub(overscan).min(rows - 1);
+                frame[(y * columns + x) as usize].max(self.minimum).min(1.0)
+            })
+            .collect();
+        Ok(film)
+    }
+}
+
+// Luminance of an image resized to the frame, in [0, 1]
+fn load_map(path: &Path, columns: u32, rows: u32) -> ImageResult<Vec<Float>> {
+    let image = image::open(path)?.to_luma32f();
+    let image = imageops::resize(&image, columns, rows, FilterType::Triangle);
+    Ok(image.pixels().map(|pixel| pixel.0[0] as Float).collect())
+}
+
+/*
+1 for frame pixels whose centre ray hits an object or that border such a
+pixel, so silhouettes stay antialiased, and 0 elsewhere.
+ */
+fn geometry_map(scene: &Scene) -> Vec<Float> {
+    let camera = &scene.camera;
+    let (columns, rows) = (camera.screen_columns, camera.screen_rows);
+    let overscan = camera.overscan as Float;
+    let hits: Vec<bool> = (0..rows)
+        .flat_map(|y| (0..columns).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            let ray = camera.get_ray(x as Float + overscan, y as Float + overscan);
+            scene._get_nearest_hit(&ray, 0.0, None).is_some()
+        })
+        .collect();
+    (0..rows)
+        .flat_map(|y| (0..columns).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            let near_hit = (y.saturating_sub(1)..(y + 2).min(rows)).any(|ny| {
+                (x.saturating_sub(1)..(x + 2).min(columns))
+                    .any(|nx| hits[(ny * columns + nx) as usize])
+            });
+            if near_hit {
+                1.0
+            } else {
+                0.0
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,107 @@
+use crate::bounds::Aabb;
+use crate::config::find_asset;
+use crate::transform::{transform_point, Components, Transform};
+use crate::{FVec, Float};
+use nalgebra::Matrix4;
+use serde::Deserialize;
+use std::error::Error;
+use std::path::Path;
+
+/*
+Copies of an object, each placed by a transform applied after the object's
+own, for scattering thousands of rocks, trees or blades of grass from a
+list a script wrote out. The copies share the object's geometry and the
+hierarchy over it, so each costs little more than its transform; the
+object itself is only seen where its copies are. Given as a list of
+transforms or as the path of a CSV file of them (see parse_csv).
+ */
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Instances {
+    List(Vec<Transform>),
+    File(String),
+}
+
+impl Instances {
+    // Matrix of each copy's transform, reading the file of them if there is one
+    pub(crate) fn matrices(&self, base_dir: &Path) -> Result<Vec<Matrix4<Float>>, Box<dyn Error>> {
+        let transforms = match self {
+            Instances::List(transforms) => transforms.clone(),
+            Instances::File(path) => {
+                let path = find_asset(base_dir, path);
+                let text = std::fs::read_to_string(&path)
+                    .map_err(|error| format!("{}: {error}", path.display()))?;
+                parse_csv(&text).map_err(|error| format!("{}: {error}", path.display()))?
+            }
+        };
+        let matrices = transforms.iter().enumerate().map(|(index, transform)| {
+            transform
+                .matrix()
+                .map_err(|error| format!("instance {index}: {error}"))
+        });
+        Ok(matrices.collect::<Result<_, _>>()?)
+    }
+}
+
+/*
+Transforms written one to a line as numbers separated by commas: x, y and z
+to translate by, optionally followed by the rotation in degrees about x, y
+and z and then the scale, or the twelve numbers of the first three rows of
+a matrix. Blank lines, lines starting with # and a first line naming the
+columns are skipped.
+ */
+fn parse_csv(text: &str) -> Result<Vec<Transform>, String> {
+    let mut transforms = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Result<Vec<Float>, _> = line.split(',').map(|f| f.trim().parse()).collect();
+        let numbers = match fields {
+            Ok(numbers) => numbers,
+            Err(_) if index == 0 => continue,
+            Err(error) => return Err(format!("line {}: {error}", index + 1)),
+        };
+        let vector = |i: usize| FVec::new(numbers[i], numbers[i + 1], numbers[i + 2]);
+        let transform = match numbers.len() {
+            3 | 6 | 7 => Transform::Components(Components {
+                translate: vector(0),
+                rotate: if numbers.len() > 3 {
+                    vector(3)
+                } else {
+                    FVec::zeros()
+                },
+                scale: numbers.get(6).copied().unwrap_or(1.0),
+            }),
+            12 => {
+                let row = |r: usize| [0, 1, 2, 3].map(|c| numbers[4 * r + c]);
+                Transform::Matrix {
+                    matrix: [row(0), row(1), row(2), [0.0, 0.0, 0.0, 1.0]],
+                }
+            }
+            count => {
+                let expected = "3, 6, 7 or 12";
+                return Err(format!(
+                    "line {}: {count} numbers, not {expected}",
+                    index + 1
+                ));
+            }
+        };
+        transforms.push(transform);
+    }
+    Ok(transforms)
+}
+
+// Box around a box moved by the matrix
+pub(crate) fn transformed_box(bounds: &Aabb, matrix: &Matrix4<Float>) -> Option<Aabb> {
+    let corner = |i: usize| {
+        let pick = |bit: usize, min: Float, max: Float| if i & bit == 0 { min } else { max };
+        FVec::new(
+            pick(1, bounds.min.x, bounds.max.x),
+            pick(2, bounds.min.y, bounds.max.y),
+            pick(4, bounds.min.z, bounds.max.z),
+        )
+    };
+    Aabb::around((0..8).map(|i| transform_point(matrix, &corner(i))))
+}
@@ -0,0 +1,114 @@
+use crate::core::consts::PI;
+use crate::{FVec, Float, UP};
+use serde::Deserialize;
+
+// Latitudes and longitudes the background is looked up at to project it
+const ROWS: usize = 128;
+const COLUMNS: usize = 256;
+
+// How much of each band of the background is left in the light it gives a surface, over pi
+const BANDS: [Float; 9] = [
+    1.0,
+    2.0 / 3.0,
+    2.0 / 3.0,
+    2.0 / 3.0,
+    0.25,
+    0.25,
+    0.25,
+    0.25,
+    0.25,
+];
+
+/*
+Light reaching a surface from the background, by its normal, for the ambient
+term of the Whitted integrator in place of a flat colour. The background is
+projected onto the first nine spherical harmonics, which is all of it that
+diffuse light depends on to within a few percent, as the cosine over the
+hemisphere smooths the finer detail away (Ramamoorthi and Hanrahan 2001).
+Taken over pi like the rest of the lighting, so a uniform background lights
+surfaces in its own colour, as ambientLight of that colour would.
+ */
+#[derive(Debug, Clone)]
+pub(crate) struct Irradiance {
+    coefficients: [FVec; 9],
+}
+
+// The first nine real spherical harmonics at the unit direction
+fn harmonics(d: &FVec) -> [Float; 9] {
+    [
+        0.282095,
+        0.488603 * d.y,
+        0.488603 * d.z,
+        0.488603 * d.x,
+        1.092548 * d.x * d.y,
+        1.092548 * d.y * d.z,
+        0.315392 * (3.0 * d.z * d.z - 1.0),
+        1.092548 * d.x * d.z,
+        0.546274 * (d.x * d.x - d.y * d.y),
+    ]
+}
+
+impl Irradiance {
+    // Project the colour seen along each unit direction, integrated with the midpoint rule
+    pub(crate) fn project(radiance: impl Fn(&FVec) -> FVec) -> Irradiance {
+        let (d_theta, d_phi) = (PI / ROWS as Float, 2.0 * PI / COLUMNS as Float);
+        let mut coefficients = [FVec::zeros(); 9];
+        for row in 0..ROWS {
+            let theta = (row as Float + 0.5) * d_theta;
+            let solid_angle = theta.sin() * d_theta * d_phi;
+            for column in 0..COLUMNS {
+                let phi = (column as Float + 0.5) * d_phi;
+                let direction = FVec::new(
+                    theta.sin() * phi.cos(),
+                    theta.sin() * phi.sin(),
+                    theta.cos(),
+                );
+                let colour = radiance(&direction) * solid_angle;
+                for (coefficient, y) in coefficients.iter_mut().zip(harmonics(&direction)) {
+                    *coefficient += colour * y;
+                }
+            }
+        }
+        for (coefficient, band) in coefficients.iter_mut().zip(BANDS) {
+            *coefficient *= band;
+        }
+        Irradiance { coefficients }
+    }
+
+    // Light reaching a surface facing along the unit normal, clamped where the harmonics ring
+    pub(crate) fn at(&self, normal: &FVec) -> FVec {
+        let colour: FVec = self
+            .coefficients
+            .iter()
+            .zip(harmonics(normal))
+            .map(|(coefficient, y)| coefficient * y)
+            .sum();
+        colour.map(|c| c.max(0.0))
+    }
+}
+
+/*
+A cheap stand-in for the light of an environment, when there is no image of
+one to hand: a sky of one colour over ground of another. A surface sees the
+sky over the share (1 + cos θ) / 2 of its hemisphere weighted by the cosine,
+θ being the angle of its normal from straight up, and the ground over the
+rest, so the ambient light it gets blends the two colours by that share.
+ */
+#[derive(Deserialize, Debug, Clone)]
+pub struct AmbientGradient {
+    pub(crate) sky: FVec,
+    pub(crate) ground: FVec,
+}
+
+impl AmbientGradient {
+    // Light reaching a surface facing along the unit normal, over pi
+    pub(crate) fn at(&self, normal: &FVec) -> FVec {
+        let sky_share = 0.5 * (1.0 + normal.dot(&UP));
+        self.ground + (self.sky - self.ground) * sky_share
+    }
+
+    // Light reaching a point from all around, as fog scatters it
+    pub(crate) fn mean(&self) -> FVec {
+        0.5 * (self.sky + self.ground)
+    }
+}
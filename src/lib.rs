@@ -0,0 +1,100 @@
+/*
+A Whitted-style ray tracer. Scenes are loaded from JSON descriptions and
+rendered through a `Renderer`:
+
+    let scene = Scene::from_file("scene.json")?;
+    let image = Renderer::new(&scene).render();
+
+The `raycaster` binary is a command-line front end to this library.
+ */
+
+#[macro_use]
+extern crate log;
+
+mod adaptive;
+mod animation;
+mod annotation;
+mod assets;
+mod atmosphere;
+mod blackbody;
+mod bounds;
+mod bvh;
+pub mod camera;
+pub mod checkpoint;
+mod colour;
+pub mod compare;
+pub mod config;
+pub mod convergence;
+pub mod csg;
+mod decal;
+mod decimate;
+mod deep;
+mod denoise;
+pub mod diagnose;
+mod edit;
+mod environment;
+mod expression;
+mod filter;
+mod fog;
+mod gbuffer;
+#[cfg(feature = "gltf")]
+mod gltf;
+mod grid;
+mod guiding;
+mod importance;
+mod instances;
+mod irradiance;
+mod light;
+mod light_path;
+pub mod logging;
+pub mod material;
+mod media;
+pub mod memory;
+pub mod mesh;
+mod microfacet;
+mod mis;
+#[cfg(feature = "exr")]
+mod multilayer;
+mod noise;
+#[cfg(feature = "obj")]
+mod obj;
+mod photon;
+pub mod plugin;
+#[cfg(feature = "ply")]
+mod ply;
+pub mod preview;
+mod primitives;
+pub mod progress;
+pub mod region;
+pub mod render;
+pub mod reproduce;
+mod sampling;
+pub mod scene;
+mod script;
+mod segmentation;
+pub mod selftest;
+pub mod sequence;
+pub mod shape;
+mod spectral;
+pub mod stats;
+mod studio;
+mod subsurface;
+mod sun;
+mod texture;
+mod tonemap;
+pub mod transform;
+mod uv;
+pub mod validate;
+mod wireframe;
+
+use raytracer_core as core;
+
+pub use crate::core::{FVec, Float};
+pub use camera::Camera;
+pub use material::Material;
+pub use region::{Region, TileRange};
+pub use render::{save_image, Renderer};
+pub use scene::Scene;
+pub use shape::{SceneObject, Shape};
+
+pub(crate) const UP: FVec = nalgebra::Vector3::new(0.0, 0.0, 1.0);
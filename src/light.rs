@@ -0,0 +1,708 @@
+use crate::animation::{interpolate, lattice_random, value_noise, Keyframe};
+use crate::blackbody::blackbody;
+use crate::colour::{ColourPipeline, TextureColourSpace};
+use crate::config::find_asset;
+use crate::core::consts::PI;
+use crate::sun::SunPosition;
+use crate::texture::ImageTexture;
+use crate::{FVec, Float, SceneObject, UP};
+use nalgebra::Matrix3;
+use serde::Deserialize;
+use std::error::Error;
+use std::path::Path;
+
+const DEFAULT_AREA_LIGHT_SAMPLES: u32 = 16;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LightSource {
+    // Used to find the light through the scene's query functions
+    pub name: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default = "default_colour")]
+    pub colour: FVec,
+    pub pos: FVec,
+    pub intensity: Float,
+    // Colour temperature in kelvin of an incandescent source, tinting the colour
+    pub temperature: Option<Float>,
+    // Surfaces further away than this are not lit by the light at all
+    pub cutoff_radius: Option<Float>,
+    // How the light dims with distance; as the inverse square by default
+    #[serde(default)]
+    pub attenuation: Attenuation,
+    #[serde(default)]
+    pub shape: LightShape,
+    // Shadow rays per shading point for area lights
+    pub shadow_samples: Option<u32>,
+    // Restricts the light to a cone when present
+    pub spot: Option<Spot>,
+    pub animation: Option<LightAnimation>,
+    // Objects by index, name or tag that are the only ones the light lights; all when unset
+    pub objects: Option<Vec<usize>>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LightAnimation {
+    #[serde(default)]
+    pub intensity: Vec<Keyframe<Float>>,
+    #[serde(default)]
+    pub colour: Vec<Keyframe<FVec>>,
+    pub flicker: Option<Flicker>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Flicker {
+    pub profile: FlickerProfile,
+    // Strength of the flicker, 0 for none and 1 for very pronounced
+    #[serde(default = "default_flicker_amount")]
+    pub amount: Float,
+    // Lights with different seeds flicker independently
+    #[serde(default)]
+    pub seed: u32,
+}
+
+fn default_colour() -> FVec {
+    FVec::new(1.0, 1.0, 1.0)
+}
+
+fn default_flicker_amount() -> Float {
+    0.5
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum FlickerProfile {
+    // Smooth, irregular wavering that reddens as the flame dips
+    Candle,
+    // Steady with a faint hum and the occasional stutter
+    Fluorescent,
+}
+
+impl Flicker {
+    // Intensity multiplier and colour tint at the given frame
+    fn evaluate(&self, frame: Float, frame_rate: Float) -> (Float, FVec) {
+        let time = frame / frame_rate;
+        match self.profile {
+            FlickerProfile::Candle => {
+                let noise = 0.6 * value_noise(self.seed, time * 8.0)
+                    + 0.4 * value_noise(self.seed.wrapping_add(1), time * 1.5);
+                let shift = self.amount * noise;
+                let tint = FVec::new(1.0, 1.0 + 0.1 * shift, 1.0 + 0.2 * shift);
+                ((1.0 + shift).max(0.0), tint)
+            }
+            FlickerProfile::Fluorescent => {
+                let frame_index = frame.floor() as i64;
+                let stutter = lattice_random(self.seed, frame_index) < 0.1 * self.amount;
+                let multiplier = if stutter {
+                    0.3 + 0.4 * lattice_random(self.seed.wrapping_add(1), frame_index)
+                } else {
+                    1.0 - 0.05 * self.amount * value_noise(self.seed.wrapping_add(2), time * 30.0)
+                };
+                (multiplier, FVec::new(1.0, 1.0, 1.0))
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Spot {
+    pub direction: FVec,
+    // Half-angle of the cone, in degrees
+    #[serde(alias = "coneAngle")]
+    pub angle: Float,
+    // Fraction of the cone over which the edge fades out
+    #[serde(default)]
+    pub blend: Float,
+    // Power of the cosine off the axis the light dims by, brightest in the middle like a torch
+    #[serde(default)]
+    pub falloff: Float,
+    // Image projected through the cone, like a gobo or cookie in front of a stage light
+    pub gobo: Option<String>,
+    // How the gobo image's values are stored: "srgb", "linear", "data" or an OCIO colour space
+    pub gobo_colour_space: Option<TextureColourSpace>,
+    #[serde(skip)]
+    gobo_texture: Option<ImageTexture>,
+}
+
+impl Spot {
+    /*
+    Colour filter applied to light leaving the spot in the given unit
+    direction: zero outside the cone, a smooth fade across the blend region
+    and dimming away from the axis, and the gobo image mapped across the
+    cone's cross-section.
+     */
+    fn filter(&self, direction: &FVec) -> FVec {
+        let axis = self.direction.normalize();
+        let cos_theta = direction.dot(&axis);
+        let cos_outer = self.angle.to_radians().cos();
+        let cos_inner = (self.angle * (1.0 - self.blend)).to_radians().cos();
+        if cos_theta <= cos_outer {
+            return FVec::zeros();
+        }
+        let edge = if cos_theta >= cos_inner {
+            1.0
+        } else {
+            let t = (cos_theta - cos_outer) / (cos_inner - cos_outer);
+            t * t * (3.0 - 2.0 * t)
+        };
+        let falloff = edge * cos_theta.powf(self.falloff);
+        let gobo = match &self.gobo_texture {
+            Some(texture) => {
+                // Keep the top of the image towards world up where possible
+                let (u, v) = match axis.cross(&UP).try_normalize(1e-9) {
+                    Some(u) => (u, u.cross(&axis)),
+                    None => coordinate_system(&axis),
+                };
+                let tan_angle = self.angle.to_radians().tan();
+                let x = direction.dot(&u) / cos_theta / tan_angle;
+                let y = direction.dot(&v) / cos_theta / tan_angle;
+                texture.sample((x + 1.0) * 0.5, (1.0 - y) * 0.5)
+            }
+            None => FVec::new(1.0, 1.0, 1.0),
+        };
+        falloff * gobo
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum LightShape {
+    #[default]
+    Point,
+    // Ball of the given radius centred on the light position
+    Sphere {
+        radius: Float,
+    },
+    // Capsule around the segment from the light position to `end`
+    Tube {
+        end: FVec,
+        radius: Float,
+    },
+    // Infinitely far away, so every point is lit from the same direction
+    Distant {
+        direction: DistantDirection,
+    },
+}
+
+/*
+Fraction of the intensity reaching a point at a distance from the light.
+Coefficients are in the units of the scene file like other lengths, so the
+light looks the same whatever units it is written in.
+ */
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum Attenuation {
+    // Physically based: the intensity is spread over a sphere as wide as the distance
+    #[default]
+    InverseSquare,
+    // One over constant + linear * distance + quadratic * distance squared
+    Polynomial {
+        #[serde(default)]
+        constant: Float,
+        #[serde(default)]
+        linear: Float,
+        #[serde(default)]
+        quadratic: Float,
+    },
+}
+
+impl Attenuation {
+    fn at(&self, distance: Float) -> Float {
+        match self {
+            Attenuation::InverseSquare => 1.0 / (distance * distance),
+            Attenuation::Polynomial {
+                constant,
+                linear,
+                quadratic,
+            } => 1.0 / (constant + distance * (linear + distance * quadratic)),
+        }
+    }
+
+    // Keep the falloff over the scaled distances matched by the intensity scaled by its square
+    fn scale(&mut self, factor: Float) {
+        if let Attenuation::Polynomial {
+            constant, linear, ..
+        } = self
+        {
+            *constant *= factor * factor;
+            *linear *= factor;
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum DistantDirection {
+    // Direction the light travels in
+    Vector(FVec),
+    // The sun as seen from a place on Earth at a given date and time
+    Sun(SunPosition),
+}
+
+impl DistantDirection {
+    // Unit vector from the scene towards the light
+    pub(crate) fn towards_light(&self) -> FVec {
+        match self {
+            DistantDirection::Vector(direction) => -direction.normalize(),
+            DistantDirection::Sun(sun) => sun.direction(),
+        }
+    }
+}
+
+pub enum LightSample {
+    Point(FVec),
+    // Unit vector towards a light at infinity
+    Distant(FVec),
+}
+
+impl LightSource {
+    // Whether the light is linked to the object at the index
+    pub(crate) fn lights_object(&self, object: usize) -> bool {
+        self.objects
+            .as_ref()
+            .is_none_or(|objects| objects.contains(&object))
+    }
+
+    // Fraction of the intensity reaching a point at the distance from the light
+    pub fn attenuation(&self, distance: Float) -> Float {
+        if self.is_distant() {
+            return 1.0;
+        }
+        self.attenuation.at(distance)
+    }
+
+    pub fn centre(&self) -> FVec {
+        match &self.shape {
+            LightShape::Point | LightShape::Sphere { .. } | LightShape::Distant { .. } => self.pos,
+            LightShape::Tube { end, .. } => (self.pos + end) * 0.5,
+        }
+    }
+
+    // Turned into another set of axes by a matrix that keeps lengths
+    pub(crate) fn reorient(&mut self, axes: &Matrix3<Float>) {
+        self.pos = axes * self.pos;
+        if let Some(spot) = &mut self.spot {
+            spot.direction = axes * spot.direction;
+        }
+        match &mut self.shape {
+            LightShape::Tube { end, .. } => *end = axes * *end,
+            LightShape::Distant {
+                direction: DistantDirection::Vector(direction),
+            } => *direction = axes * *direction,
+            _ => {}
+        }
+    }
+
+    pub fn scale(&mut self, factor: Float) {
+        self.pos *= factor;
+        self.intensity *= factor * factor;
+        self.cutoff_radius = self.cutoff_radius.map(|r| r * factor);
+        self.attenuation.scale(factor);
+        match &mut self.shape {
+            LightShape::Point | LightShape::Distant { .. } => {}
+            LightShape::Sphere { radius } => *radius *= factor,
+            LightShape::Tube { end, radius } => {
+                *end *= factor;
+                *radius *= factor;
+            }
+        }
+    }
+
+    /*
+    Replace the intensity and colour with their values at the given frame,
+    animated and then tinted by the colour temperature.
+     */
+    pub fn apply_frame(&mut self, frame: Float, frame_rate: Float) {
+        self.animate(frame, frame_rate);
+        if let Some(temperature) = self.temperature {
+            self.colour = self.colour.component_mul(&blackbody(temperature));
+        }
+    }
+
+    fn animate(&mut self, frame: Float, frame_rate: Float) {
+        let Some(animation) = &self.animation else {
+            return;
+        };
+        if let Some(intensity) = interpolate(&animation.intensity, frame) {
+            self.intensity = intensity;
+        }
+        if let Some(colour) = interpolate(&animation.colour, frame) {
+            self.colour = colour;
+        }
+        if let Some(flicker) = &animation.flicker {
+            let (multiplier, tint) = flicker.evaluate(frame, frame_rate);
+            self.intensity *= multiplier;
+            self.colour = self.colour.component_mul(&tint);
+        }
+    }
+
+    // Load images relative to base_dir or a search path, converting them to the working space
+    pub fn load_textures(
+        &mut self,
+        base_dir: &Path,
+        colour: &ColourPipeline,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(spot) = &mut self.spot {
+            if let Some(path) = &spot.gobo {
+                let path = find_asset(base_dir, path);
+                let to_working =
+                    colour.texture_processor(spot.gobo_colour_space.as_ref(), &path)?;
+                let mut texture = ImageTexture::load(&path)?;
+                texture.map_colours(|colour| to_working.apply(colour));
+                spot.gobo_texture = Some(texture);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_distant(&self) -> bool {
+        matches!(self.shape, LightShape::Distant { .. })
+    }
+
+    // Colour filter for light leaving the light in the given unit direction
+    pub fn filter(&self, direction: &FVec) -> FVec {
+        match &self.spot {
+            Some(spot) => spot.filter(direction),
+            None => FVec::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    pub fn num_samples(&self) -> u32 {
+        match self.shape {
+            LightShape::Point | LightShape::Distant { .. } => 1,
+            _ => self
+                .shadow_samples
+                .unwrap_or(DEFAULT_AREA_LIGHT_SAMPLES)
+                .max(1),
+        }
+    }
+
+    // How many of the random numbers passed to sample the shape uses
+    pub fn sample_dimensions(&self) -> usize {
+        match self.shape {
+            LightShape::Point | LightShape::Distant { .. } => 0,
+            LightShape::Sphere { .. } => 2,
+            LightShape::Tube { .. } => 3,
+        }
+    }
+
+    /*
+    Pick a point on the light as seen from `from`. Spheres are sampled by solid
+    angle, uniformly over the cone they subtend, so no samples are wasted on
+    the far side. Tubes pick a point evenly over their whole surface, weighed
+    by sample_weight.
+     */
+    pub fn sample(&self, from: &FVec, u: [Float; 3]) -> LightSample {
+        match &self.shape {
+            LightShape::Point => LightSample::Point(self.pos),
+            LightShape::Sphere { radius } => {
+                LightSample::Point(sample_sphere(&self.pos, *radius, from, u[0], u[1]))
+            }
+            LightShape::Tube { end, radius } => {
+                LightSample::Point(sample_capsule(&self.pos, end, *radius, u))
+            }
+            LightShape::Distant { direction } => LightSample::Distant(direction.towards_light()),
+        }
+    }
+
+    /*
+    Factor on the light's falloff at a sampled point, for shapes whose
+    samples are not drawn by solid angle. A tube glows evenly over its
+    surface as brightly as makes it as bright as a point light of the same
+    intensity seen side on from afar. A point drawn by area therefore counts
+    by the area over that cross-section times the cosine it is seen at,
+    which with the inverse square falloff turns the area into solid angle.
+    Points on the far side of the tube are hidden by it.
+     */
+    pub fn sample_weight(&self, from: &FVec, point: &FVec) -> Float {
+        let LightShape::Tube { end, radius } = &self.shape else {
+            return 1.0;
+        };
+        let axis = end - self.pos;
+        let length = axis.norm();
+        let along = match axis.try_normalize(0.0) {
+            Some(axis) => (point - self.pos).dot(&axis).clamp(0.0, length) * axis,
+            None => FVec::zeros(),
+        };
+        let normal = (point - (self.pos + along)) / *radius;
+        let Some(to_receiver) = (from - point).try_normalize(0.0) else {
+            return 0.0;
+        };
+        let cos_theta = normal.dot(&to_receiver);
+        if cos_theta <= 0.0 {
+            return 0.0;
+        }
+        let area = 2.0 * PI * radius * length + 4.0 * PI * radius * radius;
+        let cross_section = 2.0 * radius * length + PI * radius * radius;
+        area * cos_theta / cross_section
+    }
+
+    // Solid angle of a sphere light seen from outside it, the cone its samples are drawn from
+    pub(crate) fn solid_angle(&self, from: &FVec) -> Option<Float> {
+        let LightShape::Sphere { radius } = self.shape else {
+            return None;
+        };
+        let distance_squared = (self.pos - from).norm_squared();
+        if distance_squared <= radius * radius {
+            return None;
+        }
+        let cos_theta_max = (1.0 - radius * radius / distance_squared).max(0.0).sqrt();
+        Some(2.0 * PI * (1.0 - cos_theta_max))
+    }
+
+    // The point of a sphere light seen from outside it along the unit direction, if it is seen
+    pub(crate) fn sample_along(&self, from: &FVec, direction: &FVec) -> Option<LightSample> {
+        let LightShape::Sphere { radius } = self.shape else {
+            return None;
+        };
+        let b = direction.dot(&(from - self.pos));
+        let c = (from - self.pos).norm_squared() - radius * radius;
+        let discriminant = b * b - c;
+        if c <= 0.0 || discriminant < 0.0 || b >= 0.0 {
+            return None;
+        }
+        Some(LightSample::Point(
+            from + (-b - discriminant.sqrt()) * direction,
+        ))
+    }
+}
+
+fn sample_sphere(centre: &FVec, radius: Float, from: &FVec, u1: Float, u2: Float) -> FVec {
+    let to_centre = centre - from;
+    let distance_squared = to_centre.norm_squared();
+    if distance_squared <= radius * radius {
+        // Inside the light, so every direction sees it
+        let z = 1.0 - 2.0 * u1;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * PI * u2;
+        return centre + radius * FVec::new(r * phi.cos(), r * phi.sin(), z);
+    }
+    let distance = distance_squared.sqrt();
+    let w = to_centre / distance;
+    let sin_theta_max_squared = radius * radius / distance_squared;
+    let cos_theta_max = (1.0 - sin_theta_max_squared).max(0.0).sqrt();
+    let direction = sample_cone(&w, cos_theta_max, u1, u2);
+
+    // Nearest hit of the sampled direction with the sphere
+    let b = direction.dot(&(from - centre));
+    let c = distance_squared - radius * radius;
+    let t = -b - (b * b - c).max(0.0).sqrt();
+    from + t * direction
+}
+
+/*
+A point drawn evenly over the surface of a capsule around the segment from
+a to b: on the cylinder in proportion to its share of the area, and
+otherwise on a sphere about the segment's ends, whose halves outside the
+cylinder make up the two caps.
+ */
+fn sample_capsule(a: &FVec, b: &FVec, radius: Float, u: [Float; 3]) -> FVec {
+    let axis = b - a;
+    let length = axis.norm();
+    let side = 2.0 * PI * radius * length;
+    let caps = 4.0 * PI * radius * radius;
+    let phi = 2.0 * PI * u[1];
+    if u[2] * (side + caps) < side {
+        let w = axis / length;
+        let (s, t) = coordinate_system(&w);
+        return a + axis * u[0] + radius * (phi.cos() * s + phi.sin() * t);
+    }
+    let z = 1.0 - 2.0 * u[0];
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let offset = FVec::new(r * phi.cos(), r * phi.sin(), z) * radius;
+    if offset.dot(&axis) > 0.0 {
+        b + offset
+    } else {
+        a + offset
+    }
+}
+
+// A direction drawn evenly from the cone around the unit vector w, -1 giving the whole sphere
+pub(crate) fn sample_cone(w: &FVec, cos_theta_max: Float, u1: Float, u2: Float) -> FVec {
+    let cos_theta = 1.0 - u1 * (1.0 - cos_theta_max);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * u2;
+    let (u, v) = coordinate_system(w);
+    sin_theta * phi.cos() * u + sin_theta * phi.sin() * v + cos_theta * w
+}
+
+/*
+An object whose material gives off light, stood in for by a light of unit
+intensity in its emission colour so the Whitted integrator can light other
+surfaces with it. Shadow rays are drawn evenly from the cone of directions
+towards the sphere bounding the object, and those reaching the object before
+anything else bring its light weighted by the cone's solid angle over pi,
+as the path integrator would find it by bouncing. Unbounded objects, such as
+planes, cannot be sampled this way and only light others by bounces.
+ */
+#[derive(Debug, Clone)]
+pub(crate) struct Emitter {
+    pub(crate) object: usize,
+    pub(crate) light: LightSource,
+    radius: Float,
+}
+
+impl Emitter {
+    // The bounded objects with an emission, in the order of the objects
+    pub(crate) fn find(objects: &[SceneObject]) -> Vec<Emitter> {
+        let emitting = objects
+            .iter()
+            .enumerate()
+            .filter(|(_, object)| !object.degenerate && !object.hidden)
+            .filter(|(_, object)| object.material.emission != FVec::zeros());
+        emitting
+            .filter_map(|(index, object)| {
+                let bounds = object.bounding_box()?;
+                let radius = 0.5 * (bounds.max - bounds.min).norm();
+                let light = LightSource {
+                    name: None,
+                    tags: vec![],
+                    colour: object.material.emission,
+                    pos: 0.5 * (bounds.min + bounds.max),
+                    intensity: 1.0,
+                    temperature: None,
+                    cutoff_radius: None,
+                    attenuation: Attenuation::default(),
+                    shape: LightShape::Sphere { radius },
+                    shadow_samples: object.material.emission_samples,
+                    spot: None,
+                    animation: None,
+                    objects: None,
+                };
+                Some(Emitter {
+                    object: index,
+                    light,
+                    radius,
+                })
+            })
+            .collect()
+    }
+
+    // A unit direction from a point towards the object, and the solid angle it was drawn from
+    pub(crate) fn sample(&self, from: &FVec, u1: Float, u2: Float) -> (FVec, Float) {
+        let to_centre = self.light.pos - from;
+        let distance = to_centre.norm();
+        let cos_theta_max = if distance > self.radius {
+            (1.0 - (self.radius / distance).powi(2)).max(0.0).sqrt()
+        } else {
+            -1.0
+        };
+        let w = to_centre.try_normalize(0.0).unwrap_or(UP);
+        let direction = sample_cone(&w, cos_theta_max, u1, u2);
+        (direction, 2.0 * PI * (1.0 - cos_theta_max))
+    }
+}
+
+// Two unit vectors completing an orthonormal basis with the unit vector w
+pub fn coordinate_system(w: &FVec) -> (FVec, FVec) {
+    let helper = if w.x.abs() > 0.9 {
+        FVec::new(0.0, 1.0, 0.0)
+    } else {
+        FVec::new(1.0, 0.0, 0.0)
+    };
+    let u = w.cross(&helper).normalize();
+    let v = w.cross(&u);
+    (u, v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tube(pos: FVec, end: FVec, radius: Float) -> LightSource {
+        let light = serde_json::json!({
+            "pos": [pos.x, pos.y, pos.z],
+            "intensity": 1.0,
+            "shape": {"type": "tube", "end": [end.x, end.y, end.z], "radius": radius},
+        });
+        serde_json::from_value(light).unwrap()
+    }
+
+    // Irradiance at a point facing the normal from the tube's samples on a grid
+    fn sampled_irradiance(light: &LightSource, from: &FVec, normal: &FVec) -> Float {
+        let n = 48;
+        let cell = |i: usize| (i as Float + 0.5) / n as Float;
+        let mut total = 0.0;
+        for i in 0..n {
+            for j in 0..n {
+                for k in 0..n {
+                    let LightSample::Point(point) = light.sample(from, [cell(i), cell(j), cell(k)])
+                    else {
+                        unreachable!("tubes are sampled at points");
+                    };
+                    let to_light = point - from;
+                    let distance = to_light.norm();
+                    let cos_theta = normal.dot(&(to_light / distance)).max(0.0);
+                    total +=
+                        light.sample_weight(from, &point) * light.attenuation(distance) * cos_theta;
+                }
+            }
+        }
+        light.intensity * total / (n * n * n) as Float
+    }
+
+    /*
+    The same by brute force: the radiance the sample weights give the
+    surface times the share of cosine-weighted directions that meet it,
+    found by the distance from each direction's ray to the tube's axis.
+     */
+    fn traced_irradiance(a: &FVec, b: &FVec, radius: Float, from: &FVec, normal: &FVec) -> Float {
+        let n = 200;
+        let (s, t) = coordinate_system(normal);
+        let to_ray = |direction: &FVec, p: FVec| {
+            let along = (p - from).dot(direction).max(0.0);
+            (from + along * direction - p).norm()
+        };
+        let mut hits = 0;
+        for i in 0..n {
+            for j in 0..n {
+                let u = (i as Float + 0.5) / n as Float;
+                let v = (j as Float + 0.5) / n as Float;
+                let local = crate::sampling::cosine_hemisphere(u, v);
+                let direction = local.x * s + local.y * t + local.z * normal;
+                // The distance to the ray is convex along the axis
+                let (mut lo, mut hi) = (0.0, 1.0);
+                for _ in 0..40 {
+                    let (m1, m2) = (lo + (hi - lo) / 3.0, hi - (hi - lo) / 3.0);
+                    if to_ray(&direction, a + (b - a) * m1) < to_ray(&direction, a + (b - a) * m2) {
+                        hi = m2;
+                    } else {
+                        lo = m1;
+                    }
+                }
+                if to_ray(&direction, a + (b - a) * lo) <= radius {
+                    hits += 1;
+                }
+            }
+        }
+        let length = (b - a).norm();
+        let radiance = 1.0 / (2.0 * radius * length + PI * radius * radius);
+        radiance * PI * hits as Float / (n * n) as Float
+    }
+
+    #[test]
+    fn tube_samples_match_traced_irradiance() {
+        let (a, b, radius) = (FVec::new(-1.0, 0.0, 0.0), FVec::new(1.0, 0.0, 0.0), 0.2);
+        let light = tube(a, b, radius);
+        let receivers = [
+            // Close beside the tube, off its middle
+            (FVec::new(0.5, 0.0, -0.6), FVec::new(0.0, 0.0, 1.0)),
+            // Off the end, looking along the axis
+            (FVec::new(2.5, 0.0, 0.3), FVec::new(-1.0, 0.0, 0.0)),
+        ];
+        for (from, normal) in receivers {
+            let sampled = sampled_irradiance(&light, &from, &normal);
+            let traced = traced_irradiance(&a, &b, radius, &from, &normal);
+            let error = (sampled - traced).abs() / traced;
+            assert!(error < 0.02, "{from:?}: sampled {sampled}, traced {traced}");
+        }
+        // Far away side on, it is as bright as a point light of the same intensity
+        let (from, normal) = (FVec::new(0.0, 20.0, 0.0), FVec::new(0.0, -1.0, 0.0));
+        let sampled = sampled_irradiance(&light, &from, &normal);
+        assert!((sampled * 400.0 - 1.0).abs() < 0.01, "sampled {sampled}");
+    }
+}
@@ -0,0 +1,157 @@
+use serde::Deserialize;
+use std::cell::Cell;
+
+// Most steps an expression may have once each + is written out as the step and a repeat of it
+const MAX_STEPS: usize = 63;
+
+/*
+The paths light may take to the camera that a render layer keeps, written
+as a light path expression such as "CD*L" for light reaching the camera
+only by way of diffuse surfaces, or "CSDL" for diffuse light seen in a
+mirror. The expression starts at the camera, C, and ends at the light, L;
+between them, each surface met on the way back from the camera is S where
+it reflected or refracted the path as a mirror, glass or glossy surface
+does, D where the path integrator bounced it off diffuse, and . where it
+may be either. Any of these may be followed by * for any number of them or
++ for at least one. Starting with ! keeps every path but those matched.
+
+A surface's light from the lights, the ambient light, caustics and light
+scattered under its surface count as a D followed by the light, highlights
+included, as they are worked out together. What the surface emits, the
+background and the light fog, haze and volumes send along the ray count as
+the light itself. Rays carried on through dissolving surfaces or surfaces
+inside glass add nothing to the path.
+ */
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "String")]
+pub struct LightPaths {
+    // What the path may meet between the camera and the light, in order
+    steps: Vec<Step>,
+    // Keep the paths not matched instead
+    exclude: bool,
+}
+
+// How the path carried on from a surface
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Scatter {
+    Diffuse,
+    Specular,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Step {
+    // The surface matched, or None for either
+    scatter: Option<Scatter>,
+    // Matched any number of times rather than once
+    repeats: bool,
+}
+
+/*
+The steps of the expression a path could have got to, one bit each, with
+the bit past the last step set when the path so far matches all of them.
+ */
+pub(crate) type States = u64;
+
+thread_local! {
+    // Where the path being traced on this thread has got to, or None at the camera
+    static PATH: Cell<Option<States>> = const { Cell::new(None) };
+}
+
+impl LightPaths {
+    fn parse(source: &str) -> Result<LightPaths, String> {
+        let (exclude, expression) = match source.trim().strip_prefix('!') {
+            Some(rest) => (true, rest.trim()),
+            None => (false, source.trim()),
+        };
+        let inner = expression
+            .strip_prefix('C')
+            .and_then(|rest| rest.strip_suffix('L'))
+            .ok_or(format!(
+                "{source:?} must start at the camera, C, and end at the light, L"
+            ))?;
+        let mut steps: Vec<Step> = Vec::new();
+        for symbol in inner.chars() {
+            let scatter = match symbol {
+                'D' => Some(Scatter::Diffuse),
+                'S' => Some(Scatter::Specular),
+                '.' => None,
+                '*' | '+' => {
+                    let last = steps.last_mut().filter(|step| !step.repeats);
+                    let last = last.ok_or(format!("{source:?}: {symbol} repeats nothing"))?;
+                    let repeated = Step {
+                        repeats: true,
+                        ..*last
+                    };
+                    match symbol {
+                        '*' => *last = repeated,
+                        _ => steps.push(repeated),
+                    }
+                    continue;
+                }
+                _ => return Err(format!("{source:?}: unknown symbol {symbol:?}")),
+            };
+            steps.push(Step {
+                scatter,
+                repeats: false,
+            });
+        }
+        if steps.len() > MAX_STEPS {
+            return Err(format!("{source:?} has more than {MAX_STEPS} steps"));
+        }
+        Ok(LightPaths { steps, exclude })
+    }
+
+    // The states together with those reached by skipping repeated steps
+    fn closure(&self, mut states: States) -> States {
+        for (index, step) in self.steps.iter().enumerate() {
+            if step.repeats && states & (1 << index) != 0 {
+                states |= 1 << (index + 1);
+            }
+        }
+        states
+    }
+
+    fn step(&self, states: States, scatter: Scatter) -> States {
+        let mut next = 0;
+        for (index, step) in self.steps.iter().enumerate() {
+            if states & (1 << index) != 0 && step.scatter.is_none_or(|s| s == scatter) {
+                next |= 1 << if step.repeats { index } else { index + 1 };
+            }
+        }
+        self.closure(next)
+    }
+
+    fn current(&self) -> States {
+        PATH.get().unwrap_or_else(|| self.closure(1))
+    }
+
+    // Trace on from a surface that scattered the path, going back to the path before afterwards
+    pub(crate) fn after<T>(&self, scatter: Scatter, trace: impl FnOnce() -> T) -> T {
+        let before = PATH.get();
+        PATH.set(Some(self.step(self.current(), scatter)));
+        let traced = trace();
+        PATH.set(before);
+        traced
+    }
+
+    // Whether light reaching the camera along the path so far, after the scatters, is kept
+    pub(crate) fn keeps(&self, scatters: &[Scatter]) -> bool {
+        let states = scatters.iter().fold(self.current(), |states, &scatter| {
+            self.step(states, scatter)
+        });
+        (states & (1 << self.steps.len()) != 0) != self.exclude
+    }
+
+    // Whether any light further along the path so far could be kept
+    pub(crate) fn leads_anywhere(&self) -> bool {
+        self.exclude || self.current() != 0
+    }
+}
+
+impl TryFrom<String> for LightPaths {
+    type Error = String;
+
+    fn try_from(source: String) -> Result<LightPaths, String> {
+        LightPaths::parse(&source)
+    }
+}
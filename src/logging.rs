@@ -0,0 +1,28 @@
+use std::time::Instant;
+
+/*
+Messages are logged through the `log` crate's macros, which are in scope in
+every module of the library. Where they go is up to the program using it:
+the binary writes them to stderr as "[LEVEL] message".
+ */
+
+// Logs how long a stage of the render took, at debug level, when dropped
+pub struct StageTimer {
+    stage: String,
+    start: Instant,
+}
+
+impl StageTimer {
+    pub fn start(stage: impl Into<String>) -> StageTimer {
+        StageTimer {
+            stage: stage.into(),
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Drop for StageTimer {
+    fn drop(&mut self) {
+        debug!("{} took {:?}", self.stage, self.start.elapsed());
+    }
+}
@@ -1,334 +1,1178 @@
-use nalgebra as na;
+use image::DynamicImage;
+use log::{error, info, trace, warn, LevelFilter};
+use raytracer::checkpoint::Checkpoint;
+use raytracer::compare::{compare, split};
+use raytracer::config::{self, Settings};
+use raytracer::convergence;
+use raytracer::diagnose;
+use raytracer::logging::StageTimer;
+use raytracer::memory::megabytes;
+use raytracer::preview::{self, Refinement};
+use raytracer::progress::{self, ProgressFormat};
+use raytracer::reproduce::{self, Reproducibility};
+use raytracer::sequence::{self, FrameRange, SequenceError};
+use raytracer::stats::{self, Stats};
+use raytracer::validate::{self, LoadError, SceneProblem, EXIT_FAILURE, EXIT_INVALID_SCENE};
+use raytracer::{save_image, selftest, Float, Region, Renderer, Scene, TileRange};
+use serde_json::{json, Value};
+use std::fmt::Display;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-use image::{ImageBuffer, ImageError, Rgb};
-use serde::Deserialize;
-use std::error::Error;
-use std::fs::File;
-use std::io::BufReader;
+const USAGE: &str = "\
+Usage: raycaster [SCENE] [OPTIONS]
+       raycaster selftest
+       raycaster furnace [SCENE]
+       raycaster diff IMAGE REFERENCE [-o HEATMAP]
+       raycaster converge SCENE REFERENCE [OPTIONS] [-o CSV]
+       raycaster submit SCENE --frames FIRST-LAST [OPTIONS] [--chunk N] [--jobs-dir DIR]
+       raycaster merge SCENE PART... [OPTIONS]
+       raycaster bake SCENE --at X,Y,Z [OPTIONS]
+       raycaster ab SCENE [--set-a PATH=VALUE]... [--set-b PATH=VALUE]... [OPTIONS]
+       raycaster slice SCENE [--volume N] [--axis AXIS] [--depth FRACTION] [-o IMAGE]
 
-const UP: FVec = na::Vector3::new(0.0, 0.0, 1.0);
-const MAX_BOUNCES: u8 = 100;
+Renders SCENE (scene.json by default, - for stdin) to an image. Scenes ending
+.yaml, .yml or .toml are read as YAML or TOML, and any others as JSON. diff
+prints the PSNR and SSIM of IMAGE against REFERENCE and writes a heatmap of
+where they differ [default: diff.png]. converge renders SCENE with 1, 2, 4... up to
+its samples per pixel and writes the time and error against REFERENCE of
+each render as CSV [default: convergence.csv]. submit splits the frames into
+chunks of N [default: 10] and writes a shell script rendering each chunk with
+the other options into DIR [default: jobs], to run on any machine sharing the
+working directory; $RAYCASTER names the binary there. Temporal reuse starts
+afresh at every chunk. merge writes the image whose tiles the PARTs hold, as
+written by renders of SCENE with --tile-range and the same options on any
+number of machines, rendering any tiles none of them has. bake renders the
+light reaching the point X,Y,Z of SCENE from every direction as a 360°
+equirectangular image twice as wide as it is high [default: 2048 pixels wide]
+to light other scenes with as their environment [default: environment.hdr].
+ab renders SCENE twice, with the settings given by --set-a and then by
+--set-b, and writes the two as one image split down the middle, A on the
+left and B on the right. Each PATH names a value in the scene as errors do,
+such as integrator, camera.samples or objects[2].material, and VALUE is
+JSON, or else taken as a string. slice writes a cut through the Nth grid
+volume of SCENE [default: 0] square to AXIS [default: z], FRACTION of the way
+along it [default: 0.5], with a pixel per value coloured by the volume's
+transfer function and its opacity as alpha [default: slice.png].
 
-type Float = f64;
-type FVec = na::Vector3<Float>;
+A scene that fails validation or renders black gets a small diagnostic
+image beside the output as OUTPUT_diagnostic.png: the scene in grey clay
+under studio lights, seen from far enough back to show all of it, with
+its camera and lights drawn over it in red where they are likely the
+cause, as the log says.
 
-#[derive(Debug)]
-struct Ray {
-    origin: FVec,
-    direction: FVec,
+Options:
+      --scene PATH          Scene to render, in place of SCENE [default: scene.json]
+  -o, --output PATH         Image to write, - for PNG on stdout [default: output.png]
+      --output-dir DIR      Directory that relative output paths are written under
+      --width PIXELS        Image width; keeps the aspect ratio unless --height is given
+      --height PIXELS       Image height; keeps the aspect ratio unless --width is given
+      --samples N           Rays per pixel
+      --max-bounces N       Most reflections and refractions followed per path
+      --integrator NAME     whitted, path for light bounced between surfaces, or ao
+      --tone-map OPERATOR   linear, reinhard or aces, then the sRGB curve, for 8-bit images
+      --exposure STOPS      Brighten before tone mapping; negative values darken
+      --auto-exposure M     Meter the render by average or percentile luminance to expose it
+      --denoise             Smooth the noise of the image before tone mapping
+      --no-denoise          Leave the noise when the settings denoise by default
+      --region X,Y,W,H      Render only this rectangle of the film, clipped to its edges
+      --tile-range I/N      Render only the Ith of N slices of the tiles down the film, writing
+                            a part for merge to the output instead of an image
+      --isolate NAME        Render only the objects with this name or tag, or this index
+      --mask NAME           Also write a black and white mask of the objects with this name,
+                            tag or index beside the output as OUTPUT_mask_NAME; may be repeated
+      --seed N              Seed of the random sampling; the same seed gives the same image
+                            on any number of threads
+      --frames FIRST-LAST   Render frames of an animation, numbering the output
+      --progressive         Write coarse previews before the full render
+      --focus X,Y           Refine the area around a pixel first (implies --progressive)
+      --watch               Re-render whenever the scene file changes
+      --preview             Show the render refining, restarting on scene changes: in a window
+                            steered with WASD and the mouse, or without the window feature
+                            by rewriting the output as its samples double
+      --resume              Carry on from the checkpoint of an earlier render to the output
+      --checkpoint-every S  Seconds between checkpoints of finished pixels [default: 60]
+      --accumulate          Keep the samples beside the output, and add to those kept before
+      --set-a PATH=VALUE    Set a value of the scene for side A of ab; may be repeated
+      --set-b PATH=VALUE    Set a value of the scene for side B of ab; may be repeated
+      --split FRACTION      Where the line between the sides of ab crosses [default: 0.5]
+      --wipe DEGREES        Turn the line between the sides of ab clockwise from upright
+      --volume N            Grid volume of the scene slice cuts through [default: 0]
+      --axis AXIS           x, y or z, the axis slice cuts square to [default: z]
+      --depth FRACTION      How far along the axis slice cuts, from 0 to 1 [default: 0.5]
+      --validate-only       Check the scene and its assets without rendering
+      --no-diagnostic       Write no diagnostic image for an invalid scene or black render
+      --headless            Only write log lines to the terminal; nothing else is ever displayed
+      --threads N           Worker threads [default: all cores]
+      --asset-path DIR      Searched for textures and colour configs not next to the scene
+      --texture-cache MB    Memory kept for decoded textures [default: 4096]
+      --memory-budget MB    Refuse scenes estimated to need more memory [default: no limit]
+      --log-level LEVEL     off, error, warn, info, debug or trace [default: info]
+      --progress-format F   text or json [default: text]
+      --stats               Log the render time, rays cast, intersection tests, BVH nodes
+                            visited and peak memory once the render finishes
+      --stats-json PATH     Also write them to PATH as JSON (implies --stats)
+      --verify              Keep an existing output the render does not reproduce, writing
+                            the render beside it as OUTPUT.new, and fail
+      --force               Replace an existing output even when --verify finds it differs
+  -h, --help                Print this message
+";
+
+const DEFAULT_CHECKPOINT_SECONDS: f64 = 60.0;
+
+// Options followed by a value, which is never taken for the scene path
+const VALUE_OPTIONS: [&str; 37] = [
+    "-o",
+    "--output",
+    "--output-dir",
+    "--width",
+    "--height",
+    "--samples",
+    "--max-bounces",
+    "--integrator",
+    "--tone-map",
+    "--exposure",
+    "--auto-exposure",
+    "--region",
+    "--tile-range",
+    "--isolate",
+    "--mask",
+    "--seed",
+    "--frames",
+    "--focus",
+    "--threads",
+    "--asset-path",
+    "--texture-cache",
+    "--memory-budget",
+    "--log-level",
+    "--progress-format",
+    "--stats-json",
+    "--checkpoint-every",
+    "--chunk",
+    "--jobs-dir",
+    "--at",
+    "--set-a",
+    "--set-b",
+    "--split",
+    "--wipe",
+    "--volume",
+    "--axis",
+    "--depth",
+    "--scene",
+];
+
+const SWITCHES: [&str; 15] = [
+    "--denoise",
+    "--no-denoise",
+    "--progressive",
+    "--watch",
+    "--preview",
+    "--validate-only",
+    "--no-diagnostic",
+    "--headless",
+    "--resume",
+    "--accumulate",
+    "--stats",
+    "--verify",
+    "--force",
+    "-h",
+    "--help",
+];
+
+const SUBCOMMANDS: [&str; 9] = [
+    "selftest", "furnace", "diff", "converge", "submit", "merge", "bake", "ab", "slice",
+];
+// Frames in each chunk of a sequence split up by submit
+const DEFAULT_CHUNK: u32 = 10;
+// Width of the environment maps bake writes unless given
+const DEFAULT_BAKE_WIDTH: u32 = 2048;
+
+/*
+Changes to the scene description asked for on the command line, applied
+before the scene is loaded so every frame of a sequence gets them.
+ */
+#[derive(Default)]
+struct Overrides {
+    width: Option<u32>,
+    height: Option<u32>,
+    samples: Option<u32>,
+    max_bounces: Option<u8>,
+    integrator: Option<String>,
+    tone_map: Option<String>,
+    exposure: Option<f64>,
+    auto_exposure: Option<String>,
+    denoise: bool,
+    isolate: Option<String>,
+    // Objects to write masks of, each with the path to write it to
+    masks: Vec<(String, String)>,
+    seed: Option<u64>,
+    output_dir: Option<PathBuf>,
+    // Point to bake an environment map from, in the scene's units and axes
+    bake: Option<[f64; 3]>,
 }
 
-impl Ray {
-    fn extend(&self, t: Float) -> FVec {
-        self.origin + t * self.direction
+impl Overrides {
+    fn apply(&self, value: &mut Value) {
+        if let Some(max_bounces) = self.max_bounces {
+            value["maxBounces"] = max_bounces.into();
+        }
+        if let Some(integrator) = &self.integrator {
+            value["integrator"] = integrator.as_str().into();
+        }
+        if let Some(operator) = &self.tone_map {
+            value["toneMapping"]["operator"] = operator.as_str().into();
+        }
+        if let Some(exposure) = self.exposure {
+            value["toneMapping"]["exposure"] = exposure.into();
+        }
+        if let Some(metering) = &self.auto_exposure {
+            value["toneMapping"]["autoExposure"]["metering"] = metering.as_str().into();
+        }
+        // The scene's own settings are kept if it has them
+        if self.denoise && value.get("denoise").is_none() {
+            value["denoise"] = json!({});
+        }
+        if let Some(dir) = &self.output_dir {
+            // Extra outputs the scene writes go under the output directory as well
+            for path in extra_output_paths(value) {
+                if let Some(relative) = path.as_str().filter(|p| Path::new(p).is_relative()) {
+                    *path = dir.join(relative).to_string_lossy().into_owned().into();
+                }
+            }
+        }
+        if let Some(seed) = self.seed {
+            value["seed"] = seed.into();
+        }
+        if let Some(selection) = &self.isolate {
+            value["isolate"] = vec![selection_entry(selection)].into();
+        }
+        // Added after the output directory is applied, as their paths are beside the output
+        if !self.masks.is_empty() {
+            if !value["aovs"].is_array() {
+                value["aovs"] = Value::Array(vec![]);
+            }
+            let aovs = value["aovs"]
+                .as_array_mut()
+                .expect("aovs was just made an array");
+            aovs.extend(self.masks.iter().map(|(selection, path)| {
+                json!({
+                    "aov": "mask",
+                    "path": path,
+                    "objects": [selection_entry(selection)],
+                    "binary": true,
+                })
+            }));
+        }
+        let up = match value["upAxis"].as_str() {
+            Some("y") => [0, 1, 0],
+            _ => [0, 0, 1],
+        };
+        let camera = &mut value["camera"];
+        if let (Some(position), Some(camera)) = (self.bake, camera.as_object_mut()) {
+            // Level and facing +x, where an environment image has its centre
+            for key in ["lookAt", "animation", "importance"] {
+                camera.remove(key);
+            }
+            camera.insert("position".into(), json!(position));
+            camera.insert("direction".into(), json!([1, 0, 0]));
+            camera.insert("up".into(), json!(up));
+            camera.insert("roll".into(), 0.into());
+            camera.insert("aperture".into(), 0.into());
+            camera.insert("overscan".into(), 0.into());
+            camera.insert("projection".into(), json!({"type": "equirectangular"}));
+            camera.insert("screenColumns".into(), DEFAULT_BAKE_WIDTH.into());
+            camera.insert("screenRows".into(), (DEFAULT_BAKE_WIDTH / 2).into());
+        }
+        if let Some(samples) = self.samples {
+            // Replace the setting under either of its names
+            if let Some(camera) = camera.as_object_mut() {
+                camera.remove("samplesPerPixel");
+            }
+            camera["samples"] = samples.into();
+        }
+        let columns = camera["screenColumns"].as_f64().unwrap_or(1.0);
+        let rows = camera["screenRows"].as_f64().unwrap_or(1.0);
+        let (columns, rows) = match (self.width, self.height) {
+            (None, None) => return,
+            (Some(width), None) => (
+                width,
+                (width as f64 * rows / columns).round().max(1.0) as u32,
+            ),
+            (None, Some(height)) => (
+                (height as f64 * columns / rows).round().max(1.0) as u32,
+                height,
+            ),
+            (Some(width), Some(height)) => {
+                // Widen or narrow the screen so pixels stay square at the same vertical view
+                if let Some(screen_height) = camera["screenHeight"].as_f64() {
+                    camera["screenWidth"] = (screen_height * width as f64 / height as f64).into();
+                }
+                (width, height)
+            }
+        };
+        camera["screenColumns"] = columns.into();
+        camera["screenRows"] = rows.into();
     }
 }
 
-#[derive(Deserialize, Debug, Clone, Copy)]
-#[serde(rename_all = "camelCase")]
-struct Material {
-    colour: FVec,
-    k_diffuse: Float,
-    k_ambient: Float,
-    k_specular: Float,
-    k_reflect: Float,
-    shine: Float,
+// An index, or a name or tag resolved as the scene is loaded
+fn selection_entry(selection: &str) -> Value {
+    match selection.parse::<usize>() {
+        Ok(index) => Value::from(index),
+        Err(_) => Value::from(selection),
+    }
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-struct LightSource {
-    colour: FVec,
-    pos: FVec,
-    intensity: Float,
+// Path of the mask of a selection beside the output, e.g. "shot_mask_hero.png" for "shot.png"
+fn mask_path(output: &str, selection: &str) -> String {
+    let path = Path::new(output);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{stem}_mask_{selection}.{}", extension.to_string_lossy()),
+        None => format!("{stem}_mask_{selection}"),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
 }
 
-struct Intersection {
-    t: Float,
-    pos: FVec,
-    normal: FVec,
+// Path of the diagnostic image beside the output, e.g. "shot_diagnostic.png" for "shot.exr"
+fn diagnostic_path(output: &str) -> String {
+    let path = Path::new(output);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = format!("{stem}_diagnostic.png");
+    path.with_file_name(name).to_string_lossy().into_owned()
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase", tag = "type")]
-enum Shape {
-    Sphere { centre: FVec, radius: Float },
-    Plane { point: FVec, normal: FVec },
+/*
+Whether the image written to the path is black all over, to the eye: no
+channel of any pixel reaches 1/32 of white. False if it cannot be read back.
+ */
+fn is_black(path: &str) -> bool {
+    let Ok(image) = image::open(path) else {
+        return false;
+    };
+    let black = |channel: &f32| *channel < 1.0 / 32.0;
+    image
+        .into_rgb32f()
+        .pixels()
+        .all(|pixel| pixel.0.iter().all(black))
 }
 
-impl Shape {
-    /*
-    Return smallest t >= 0 such that P is on surface of self, where:
-        P = ray.origin + ray.direction * t
-    If no such t exists, return None
-     */
-    fn intersection(&self, ray: &Ray, min_distance: Float) -> Option<Intersection> {
-        match self {
-            Shape::Sphere { centre, radius } => {
-                let a = ray.direction.norm_squared();
-                let difference = ray.origin - centre;
-                let b = 2.0 * ray.direction.dot(&difference);
-                let c = difference.norm_squared() - (radius * radius);
-                let discriminant = b * b - 4.0 * a * c;
-                if discriminant < 0.0 {
-                    return None;
-                }
-                let t1 = (-b + discriminant.sqrt()) / (2.0 * a);
-                let t2 = (-b - discriminant.sqrt()) / (2.0 * a);
-                [t1, t2]
-                    .iter()
-                    .copied()
-                    .filter(|t| *t > min_distance)
-                    .min_by(|a, b| a.partial_cmp(&b).unwrap())
-                    .map(|t| {
-                        let point = ray.extend(t);
-                        let normal = (point - centre).normalize();
-                        Intersection {
-                            t: t,
-                            pos: point,
-                            normal: normal,
-                        }
-                    })
+// Write the diagnostic image of the scene beside the output and log what it found
+fn diagnose(scene: &Scene, output: &str) {
+    let path = diagnostic_path(output);
+    match scene.write_diagnostic(&path) {
+        Ok(problems) => {
+            for problem in &problems {
+                warn!("Likely problem: {}", problem);
             }
-            Shape::Plane { point, normal } => {
-                let n_dot_d = normal.dot(&ray.direction);
-                if n_dot_d == 0.0 {
-                    return None;
-                }
-                let a_minus_p = point - ray.origin;
-                let t = normal.dot(&a_minus_p) / n_dot_d;
-                if t <= min_distance {
-                    None
-                } else {
-                    Some(Intersection {
-                        t: t,
-                        pos: ray.extend(t),
-                        normal: *normal,
-                    })
-                }
+            info!(
+                "Wrote {} showing the camera and lights, in red where likely wrong",
+                path
+            );
+        }
+        Err(error) => warn!("Could not write {}: {}", path, error),
+    }
+}
+
+/*
+Write the diagnostic image of what is left of a scene that failed
+validation once the values with problems are taken out, if that loads.
+ */
+fn diagnose_invalid(
+    scene_path: &str,
+    overrides: &Overrides,
+    problems: &[SceneProblem],
+    output: &str,
+) {
+    let base_dir = Path::new(scene_path).parent().unwrap_or(Path::new(""));
+    let loaded = Scene::read_value(scene_path).and_then(|mut value| {
+        overrides.apply(&mut value);
+        Scene::from_value(diagnose::without_problems(&value, problems), base_dir)
+    });
+    match loaded {
+        Ok(scene) => diagnose(&scene, output),
+        Err(error) => warn!(
+            "No diagnostic image, as the rest of the scene fails too: {}",
+            error
+        ),
+    }
+}
+
+// Paths in a scene description of the files written besides the image
+fn extra_output_paths(value: &mut Value) -> Vec<&mut Value> {
+    let Some(scene) = value.as_object_mut() else {
+        return Vec::new();
+    };
+    let mut paths = Vec::new();
+    for (key, entry) in scene.iter_mut() {
+        match (key.as_str(), entry) {
+            ("deepOutput" | "multilayerOutput", path) => paths.push(path),
+            ("aovs" | "layers", Value::Array(outputs)) => {
+                paths.extend(
+                    outputs
+                        .iter_mut()
+                        .filter_map(|output| output.get_mut("path")),
+                );
             }
+            _ => {}
         }
     }
+    paths
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-struct SceneObject {
-    material: Material,
-    shape: Shape,
+// Pixel coordinates given as "x,y"
+fn parse_point(s: &str) -> Result<(u32, u32), String> {
+    let (x, y) = s
+        .split_once(',')
+        .ok_or(format!("expected x,y, found {s:?}"))?;
+    let parse = |n: &str| n.trim().parse::<u32>().map_err(|e| format!("{n:?}: {e}"));
+    Ok((parse(x)?, parse(y)?))
 }
 
-fn clamp<T: PartialOrd>(x: T, min: T, max: T) -> T {
-    if x < min {
-        min
-    } else if x > max {
-        max
-    } else {
-        x
+// An axis given as x, y or z, as its index
+fn parse_axis(s: &str) -> Result<usize, String> {
+    match s {
+        "x" => Ok(0),
+        "y" => Ok(1),
+        "z" => Ok(2),
+        _ => Err(format!("expected x, y or z, found {s:?}")),
     }
 }
 
-fn channel_float_to_int(value: Float) -> u8 {
-    let integer = (value * 255.0) as i32;
-    clamp(integer, 0, 255) as u8
+// A position given as "x,y,z"
+fn parse_position(s: &str) -> Result<[f64; 3], String> {
+    let numbers: Vec<f64> = s
+        .split(',')
+        .map(|n| n.trim().parse::<f64>().map_err(|e| format!("{n:?}: {e}")))
+        .collect::<Result<_, _>>()?;
+    numbers
+        .try_into()
+        .map_err(|_| format!("expected x,y,z, found {s:?}"))
 }
 
-impl SceneObject {
-    fn intersect(&self, ray: &Ray, min_distance: Float) -> Option<Intersection> {
-        self.shape
-            .intersection(ray, min_distance)
+// A value of the scene to set, given as "path=value" with the value as JSON or else a string
+fn parse_assignment(s: &str) -> Result<(String, Value), String> {
+    let (path, value) = s
+        .split_once('=')
+        .ok_or(format!("expected path=value, found {s:?}"))?;
+    let value = serde_json::from_str(value).unwrap_or_else(|_| Value::from(value));
+    Ok((path.trim().to_string(), value))
+}
+
+/*
+Set the value at a path such as "objects[2].material.colour" in a scene
+description, making the objects on the way that are missing. Lists are
+only indexed, never extended.
+ */
+fn set_path(value: &mut Value, path: &str, new: Value) -> Result<(), String> {
+    let mut target = value;
+    for segment in path.split('.') {
+        let (key, indices) = segment.split_once('[').unwrap_or((segment, ""));
+        if !key.is_empty() {
+            let object = target
+                .as_object_mut()
+                .ok_or(format!("{key:?} is not in an object"))?;
+            target = object.entry(key).or_insert(json!({}));
+        }
+        for index in indices.split('[').filter(|index| !index.is_empty()) {
+            let index = index.trim_end_matches(']');
+            let index: usize = index.parse().map_err(|e| format!("{index:?}: {e}"))?;
+            let list = target
+                .as_array_mut()
+                .ok_or(format!("{segment:?} is not a list"))?;
+            let length = list.len();
+            target = list.get_mut(index).ok_or(format!(
+                "{segment:?} is past the end of the {length} entries"
+            ))?;
+        }
+    }
+    *target = new;
+    Ok(())
+}
+
+/*
+Render the scene with each side's settings and write the two as one split
+image, returning the exit code.
+ */
+fn ab(value: &Value, base_dir: &Path, at: Float, angle: Float, path: &str) -> i32 {
+    let mut images = Vec::new();
+    for (side, option) in [("A", "--set-a"), ("B", "--set-b")] {
+        let mut variant = value.clone();
+        for assignment in option_values(option) {
+            let set = parse_assignment(&assignment)
+                .and_then(|(path, new)| set_path(&mut variant, &path, new));
+            if let Err(error) = set {
+                error!("Could not set {} for side {}: {}", assignment, side, error);
+                return EXIT_FAILURE;
+            }
+        }
+        let scene = match Scene::from_value(variant, base_dir) {
+            Ok(scene) => scene,
+            Err(error) => {
+                error!("Could not load side {}: {}", side, error);
+                progress::failed(&error.to_string());
+                return error.exit_code();
+            }
+        };
+        info!("Rendering side {}", side);
+        images.push(Renderer::new(&scene).render());
+    }
+    let (a, b) = (&images[0], &images[1]);
+    if a.dimensions() != b.dimensions() {
+        error!(
+            "The sides are {}x{} and {}x{} pixels; give both the same size",
+            a.width(),
+            a.height(),
+            b.width(),
+            b.height()
+        );
+        return EXIT_FAILURE;
     }
+    if let Err(error) = save_image(DynamicImage::from(split(a, b, at, angle)), path) {
+        error!("Could not write {}: {}", path, error);
+        progress::failed(&error.to_string());
+        return EXIT_FAILURE;
+    }
+    progress::finished(path);
+    0
+}
+
+// Whether the path names a format that keeps light brighter than white
+fn is_floating_point(path: &str) -> bool {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+    ["hdr", "exr"].contains(&extension.to_ascii_lowercase().as_str())
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-struct Camera {
-    position: FVec,
-    direction: FVec,
-    screen_distance: Float,
-    screen_width: Float,
-    screen_height: Float,
-    screen_columns: u32,
-    screen_rows: u32,
+// Value following a command-line option, e.g. "debug" for "--log-level debug"
+fn option_value(name: &str) -> Option<String> {
+    std::env::args().skip_while(|arg| arg != name).nth(1)
 }
 
-impl Camera {
-    fn get_basis_vectors(&self) -> (FVec, FVec, FVec) {
-        let u = self.direction.normalize();
-        let v = u.cross(&UP);
-        let w = v.cross(&u);
-        (u, v, w)
-    }
-
-    fn get_ray(&self, x: u32, y: u32) -> Ray {
-        // Center of screen is origin
-        let x_screen = ((x as i64) - (self.screen_columns as i64 / 2)) as Float
-            / self.screen_columns as Float
-            * self.screen_width
-            * 0.5;
-        let y_screen = ((y as i64) - (self.screen_rows as i64 / 2)) as Float
-            / self.screen_rows as Float
-            * self.screen_height
-            * -0.5;
-        let (u, v, w) = self.get_basis_vectors();
-        Ray {
-            origin: self.position,
-            direction: (self.screen_distance * u) + (x_screen * v) + (y_screen * w),
+/*
+Value following a command-line option read by the parse function, exiting
+with the reason when it cannot be read rather than going on without it.
+ */
+fn parsed_option_with<T, E: Display>(
+    name: &str,
+    parse: impl Fn(&str) -> Result<T, E>,
+) -> Option<T> {
+    let arg = option_value(name)?;
+    match parse(&arg) {
+        Ok(value) => Some(value),
+        Err(error) => {
+            error!("Invalid value {:?} for {}: {}", arg, name, error);
+            std::process::exit(EXIT_FAILURE);
         }
     }
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-struct Scene {
-    camera: Camera,
-    default_colour: FVec,
-    ambient_light: FVec,
-    lights: Vec<LightSource>,
-    objects: Vec<SceneObject>,
+// Value following a command-line option parsed as its type, exiting if it does not parse
+fn parsed_option<T: FromStr>(name: &str) -> Option<T>
+where
+    T::Err: Display,
+{
+    parsed_option_with(name, str::parse)
 }
 
-impl Scene {
-    fn from_file(path: &str) -> Result<Scene, Box<dyn Error>> {
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
-        let scene = serde_json::from_reader(reader)?;
-        Ok(scene)
-    }
-
-    fn _get_intersection(
-        &self,
-        ray: &Ray,
-        min_distance: Float,
-    ) -> Option<(Intersection, Material)> {
-        self.objects
-            .iter()
-            .filter_map(|object| {
-                object
-                    .intersect(ray, min_distance)
-                    .map(|x| (x, object.material))
-            })
-            .min_by(|a, b| a.0.t.partial_cmp(&b.0.t).unwrap())
-    }
-
-    fn _get_diffuse_lighting(
-        &self,
-        intersection: &Intersection,
-        material: &Material,
-        light: &LightSource,
-        ray: &Ray,
-    ) -> FVec {
-        let distance_squared = (intersection.pos - light.pos).norm_squared();
-        let coeff = clamp(intersection.normal.dot(&ray.direction), 0., 1.);
-        coeff / distance_squared
-            * light.intensity
-            * light
-                .colour
-                .component_mul(&material.colour)
-    }
-
-    fn _get_specular_lighting(
-        &self,
-        intersection: &Intersection,
-        material: &Material,
-        light: &LightSource,
-        ray: &Ray,
-    ) -> FVec {
-        let distance_squared = (intersection.pos - light.pos).norm_squared();
-        let l = light.pos - intersection.pos;
-        let v = ray.origin - intersection.pos;
-        let h = (l + v).normalize();
-        let coeff = h
-            .dot(&intersection.normal)
-            .powf(material.shine);
-        clamp(coeff, 0.0, 1.0) * light.colour / distance_squared * light.intensity
-    }
-
-    fn _get_reflection(
-        &self,
-        intersection: &Intersection,
-        material: &Material,
-        ray: &Ray,
-        num_bounces: u8,
-    ) -> FVec {
-        if num_bounces > MAX_BOUNCES || material.k_reflect == 0.0 {
-            return FVec::zeros();
-        }
-        let ray_proj_normal = ray.direction.dot(&intersection.normal) * intersection.normal;
-        let reflected_ray_direction = ray.direction - 2.0 * ray_proj_normal;
-        let reflected_ray = Ray {
-            origin: intersection.pos,
-            direction: reflected_ray_direction,
-        };
-        let reflected_ray_colour = self._get_ray_colour(&reflected_ray, 0.0001, num_bounces + 1);
-        material.k_reflect * &reflected_ray_colour
-    }
-
-    fn _get_surface_point_colour(&self, intersection: &Intersection, material: &Material) -> FVec {
-        let ambient = material.k_ambient
-            * self
-                .ambient_light
-                .component_mul(&material.colour);
-        let light_dependent_colouring: FVec = self
-            .lights
-            .iter()
-            .filter_map(|light| {
-                let point_to_light = light.pos - intersection.pos;
-                let distance_to_light = point_to_light.norm();
-                let ray = Ray {
-                    origin: intersection.pos,
-                    direction: point_to_light / distance_to_light,
-                };
-                let i = self._get_intersection(&ray, 0.1);
-                if i.filter(|x| x.0.t < distance_to_light)
-                    .is_some()
-                {
-                    return None;
-                }
-                Some((light, ray))
-            })
-            .map(|(light, ray)| {
-                let diffuse_light = material.k_diffuse
-                    * self._get_diffuse_lighting(intersection, material, light, &ray);
-                let specular_reflectance = material.k_specular
-                    * self._get_specular_lighting(intersection, material, light, &ray);
-                diffuse_light + specular_reflectance
-            })
-            .sum();
-        ambient + light_dependent_colouring
+// Values following each use of an option that may be given more than once
+fn option_values(name: &str) -> Vec<String> {
+    let args: Vec<String> = std::env::args().collect();
+    args.windows(2)
+        .filter(|pair| pair[0] == name)
+        .map(|pair| pair[1].clone())
+        .collect()
+}
+
+// Arguments that are neither options nor their values, warning about unknown options
+fn positional_args() -> Vec<String> {
+    let mut args = std::env::args().skip(1);
+    let mut positional = Vec::new();
+    while let Some(arg) = args.next() {
+        if VALUE_OPTIONS.contains(&arg.as_str()) {
+            args.next();
+        } else if arg == "-" || !arg.starts_with('-') {
+            positional.push(arg);
+        } else if !SWITCHES.contains(&arg.as_str()) {
+            warn!("Ignoring unknown option {}", arg);
+        }
+    }
+    positional
+}
+
+// Compare two images, printing their PSNR and SSIM, and return the exit code
+fn diff(paths: &[String], heatmap_path: &str) -> i32 {
+    let [path, reference_path] = paths else {
+        error!("diff takes an image and a reference image");
+        return EXIT_FAILURE;
+    };
+    let open = |path: &String| {
+        image::open(path).map_err(|error| error!("Could not read {}: {}", path, error))
+    };
+    let (Ok(image), Ok(reference)) = (open(path), open(reference_path)) else {
+        return EXIT_FAILURE;
+    };
+    let comparison = match compare(&image, &reference) {
+        Ok(comparison) => comparison,
+        Err(error) => {
+            error!(
+                "Could not compare {} with {}: {}",
+                path, reference_path, error
+            );
+            return EXIT_FAILURE;
+        }
+    };
+    println!("PSNR: {:.2} dB", comparison.psnr);
+    println!("SSIM: {:.4}", comparison.ssim);
+    if let Err(error) = save_image(DynamicImage::from(comparison.heatmap), heatmap_path) {
+        error!("Could not write {}: {}", heatmap_path, error);
+        return EXIT_FAILURE;
     }
+    0
+}
 
-    fn _get_ray_colour(&self, ray: &Ray, min_distance: Float, num_bounces: u8) -> FVec {
-        self._get_intersection(&ray, min_distance)
-            .map(|(i, m)| {
-                let object_colour = self._get_surface_point_colour(&i, &m);
-                let reflection = self._get_reflection(&i, &m, &ray, num_bounces);
-                object_colour + reflection
-            })
-            .unwrap_or(self.default_colour)
-    }
-
-    fn render_to_file(&self, path: &str) -> Result<(), ImageError> {
-        let image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::from_par_fn(
-            self.camera.screen_columns,
-            self.camera.screen_rows,
-            |x, y| {
-                let ray = self.camera.get_ray(x, y);
-                let rgb = self
-                    ._get_ray_colour(&ray, 0.0, 0)
-                    .map(channel_float_to_int)
-                    .into();
-                Rgb(rgb)
-            },
+// Write a cut through one of the scene's grid volumes, and return the exit code
+fn slice(scene: &Scene, volume: usize, axis: usize, depth: Float, path: &str) -> i32 {
+    let Some(image) = scene.volume_slice(volume, axis, depth) else {
+        error!("The scene has no grid volume {}", volume);
+        return EXIT_FAILURE;
+    };
+    if let Err(error) = save_image(DynamicImage::from(image), path) {
+        error!("Could not write {}: {}", path, error);
+        return EXIT_FAILURE;
+    }
+    0
+}
+
+// Log the convergence of the scene's render towards a reference image, and return the exit code
+fn converge(scene: &Scene, reference_path: Option<&String>, csv_path: &str) -> i32 {
+    let Some(reference_path) = reference_path else {
+        error!("converge takes a scene and a reference image");
+        return EXIT_FAILURE;
+    };
+    let reference = match image::open(reference_path) {
+        Ok(reference) => reference,
+        Err(error) => {
+            error!("Could not read {}: {}", reference_path, error);
+            return EXIT_FAILURE;
+        }
+    };
+    let result = std::fs::File::create(csv_path)
+        .map_err(|error| error.into())
+        .and_then(|mut file| convergence::run(scene, &reference, &mut file));
+    if let Err(error) = result {
+        error!("Could not write {}: {}", csv_path, error);
+        return EXIT_FAILURE;
+    }
+    0
+}
+
+// An argument quoted for the shell
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+// Log what the render did, and write it as JSON to the path if one is given
+fn report_stats(started: Instant, json_path: Option<&str>) {
+    let stats = Stats::collect(started.elapsed());
+    for line in stats.to_string().lines() {
+        info!("{}", line);
+    }
+    if let Some(path) = json_path {
+        if let Err(error) = fs::write(path, format!("{:#}\n", stats.to_json())) {
+            error!("Could not write {}: {}", path, error);
+        }
+    }
+}
+
+// Log the hash of a file written, with what else the render depended on
+fn report_hash(path: &str, reproducibility: &Reproducibility) {
+    match reproduce::file_hash(path) {
+        Ok(hash) => info!("{}: hash {} ({})", path, hash, reproducibility),
+        Err(error) => warn!("Could not hash {}: {}", path, error),
+    }
+}
+
+/*
+Compare a render written beside the output with the output it was checked
+against, replacing the output when they match or when forced, and return
+whether the output now holds the render.
+ */
+fn settle_verified(pending: &str, output: &str, force: bool) -> Result<bool, std::io::Error> {
+    let (new, old) = (
+        reproduce::file_hash(pending)?,
+        reproduce::file_hash(output)?,
+    );
+    if new == old {
+        info!("{} reproduced {} (hash {})", pending, output, old);
+    } else if force {
+        warn!(
+            "Replacing {} (hash {}) with a render hashing to {}",
+            output, old, new
+        );
+    } else {
+        error!(
+            "{} hashes to {} but the render to {}; kept as {}",
+            output, old, new, pending
+        );
+        return Ok(false);
+    }
+    fs::rename(pending, output)?;
+    Ok(true)
+}
+
+/*
+Split the frames into chunks and write a script rendering each chunk with
+the command line's other arguments, printing the paths of the scripts, and
+return the exit code.
+ */
+fn submit(frames: Option<FrameRange>, chunk: u32, jobs_dir: &Path) -> i32 {
+    let Some(frames) = frames else {
+        error!("submit takes the frames to split up with --frames");
+        return EXIT_FAILURE;
+    };
+    // Everything but the subcommand and the options that only concern the split
+    let mut args = Vec::new();
+    let mut all = std::env::args().skip(1).filter(|arg| arg != "submit");
+    while let Some(arg) = all.next() {
+        if ["--frames", "--chunk", "--jobs-dir"].contains(&arg.as_str()) {
+            all.next();
+        } else {
+            args.push(shell_quote(&arg));
+        }
+    }
+    let (directory, args) = match std::env::current_dir() {
+        Ok(directory) => (directory, args.join(" ")),
+        Err(error) => {
+            error!("Could not find the working directory: {}", error);
+            return EXIT_FAILURE;
+        }
+    };
+    if let Err(error) = fs::create_dir_all(jobs_dir) {
+        error!("Could not create {}: {}", jobs_dir.display(), error);
+        return EXIT_FAILURE;
+    }
+    for range in frames.chunks(chunk) {
+        let path = jobs_dir.join(format!("chunk_{:04}-{:04}.sh", range.first, range.last));
+        let directory = shell_quote(&directory.to_string_lossy());
+        let script = format!(
+            "#!/bin/sh\n# Frames {range}\ncd {directory} || exit 1\n\
+             exec \"${{RAYCASTER:-raycaster}}\" {args} --frames {range}\n"
         );
-        image.save(path)
+        if let Err(error) = fs::write(&path, script) {
+            error!("Could not write {}: {}", path.display(), error);
+            return EXIT_FAILURE;
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let _ = fs::set_permissions(&path, fs::Permissions::from_mode(0o755));
+        }
+        println!("{}", path.display());
     }
+    0
 }
 
 fn main() {
-    let scene = Scene::from_file("scene.json").unwrap();
-    println!("{:?}", scene);
-    scene
-        .render_to_file("output.png")
-        .unwrap();
+    if std::env::args().any(|arg| arg == "-h" || arg == "--help") {
+        print!("{}", USAGE);
+        return;
+    }
+    // Everything reaches the logger; the maximum level set below is what filters
+    env_logger::Builder::new()
+        .filter_level(LevelFilter::Trace)
+        .format(|out, record| writeln!(out, "[{}] {}", record.level(), record.args()))
+        .init();
+    log::set_max_level(LevelFilter::Info);
+    // Defaults from the user's config file and RAYTRACER_* environment variables
+    let settings = match Settings::load() {
+        Ok(settings) => settings,
+        Err(error) => {
+            error!("Could not read settings: {}", error);
+            std::process::exit(EXIT_FAILURE);
+        }
+    };
+    // Verbosity as error, warn, info, debug or trace; info by default
+    let log_level = parsed_option_with("--log-level", config::parse_log_level);
+    let log_level = log_level.or(settings.log_level);
+    log::set_max_level(log_level.unwrap_or(LevelFilter::Info));
+    let threads = parsed_option::<usize>("--threads");
+    let threads = threads.or(settings.threads).map(config::set_threads);
+    // Searched after the scene's own directory for textures and colour configs
+    let asset_path = option_value("--asset-path").map(PathBuf::from);
+    let mut asset_paths: Vec<PathBuf> = asset_path.into_iter().collect();
+    asset_paths.extend(settings.asset_paths);
+    let asset_paths = config::set_asset_paths(asset_paths);
+    let texture_cache = parsed_option::<usize>("--texture-cache");
+    let texture_cache = texture_cache
+        .or(settings.texture_cache_mb)
+        .map(config::set_texture_cache_mb);
+    let memory_budget = parsed_option::<usize>("--memory-budget");
+    let memory_budget = memory_budget
+        .or(settings.memory_budget_mb)
+        .map(config::set_memory_budget_mb);
+    let configured = [threads, Some(asset_paths), texture_cache, memory_budget];
+    if let Some(Err(error)) = configured.into_iter().flatten().find(Result::is_err) {
+        error!("Could not apply settings: {}", error);
+        std::process::exit(EXIT_FAILURE);
+    }
+    // "-" reads the scene from stdin and writes the image as PNG to stdout
+    let positional = positional_args();
+    let scene_path = option_value("--scene")
+        .or(positional
+            .into_iter()
+            .find(|arg| !SUBCOMMANDS.contains(&arg.as_str())))
+        .unwrap_or("scene.json".to_string());
+    /*
+    Relative output paths are written under the output directory, so with one
+    every file written is inside it: images, extra outputs of the scene,
+    checkpoints, and the files of the subcommands.
+     */
+    let output_dir = option_value("--output-dir")
+        .map(PathBuf::from)
+        .or(settings.output_dir);
+    let in_output_dir = |path: String| match &output_dir {
+        Some(dir) if path != "-" => dir.join(&path).to_string_lossy().into_owned(),
+        _ => path,
+    };
+    let bake = std::env::args().nth(1).as_deref() == Some("bake");
+    let default_output = if bake {
+        "environment.hdr"
+    } else {
+        "output.png"
+    };
+    let output_path = option_value("--output").or(option_value("-o"));
+    let output_path = in_output_dir(output_path.unwrap_or(default_output.to_string()));
+    if std::env::args().any(|arg| arg == "--headless") {
+        progress::disable_bar();
+    }
+    // Progress as text in the log or as JSON events on stdout
+    let progress_format = parsed_option::<ProgressFormat>("--progress-format");
+    progress::set_format(progress_format.unwrap_or(ProgressFormat::Text));
+    if output_path == "-" {
+        progress::use_stderr();
+    }
+    if std::env::args().nth(1).as_deref() == Some("selftest") {
+        // Keep progress lines of the built-in renders out of the report
+        log::set_max_level(log_level.unwrap_or(LevelFilter::Warn));
+        std::process::exit(if selftest::run() { 0 } else { 1 });
+    }
+    if std::env::args().nth(1).as_deref() == Some("diff") {
+        let heatmap_path = option_value("--output").or(option_value("-o"));
+        let heatmap_path = in_output_dir(heatmap_path.unwrap_or("diff.png".to_string()));
+        std::process::exit(diff(&positional_args()[1..], &heatmap_path));
+    }
+    if std::env::args().nth(1).as_deref() == Some("submit") {
+        let frames = parsed_option::<FrameRange>("--frames");
+        let chunk = parsed_option::<u32>("--chunk");
+        let jobs_dir = in_output_dir(option_value("--jobs-dir").unwrap_or("jobs".to_string()));
+        let jobs_dir = PathBuf::from(jobs_dir);
+        std::process::exit(submit(frames, chunk.unwrap_or(DEFAULT_CHUNK), &jobs_dir));
+    }
+    if std::env::args().nth(1).as_deref() == Some("furnace") {
+        let scene = match Scene::from_file(&scene_path) {
+            Ok(scene) => scene,
+            Err(error) => {
+                error!("Could not load {}: {}", scene_path, error);
+                std::process::exit(error.exit_code());
+            }
+        };
+        std::process::exit(if selftest::furnace(&scene) { 0 } else { 1 });
+    }
+    // Coarse passes written before the full render, optionally detailed around "x,y" first
+    let focus = parsed_option_with("--focus", parse_point);
+    let progressive = std::env::args().any(|arg| arg == "--progressive") || focus.is_some();
+    let refinement = (progressive && output_path != "-").then_some(Refinement { focus });
+    if std::env::args().any(|arg| arg == "--preview") {
+        #[cfg(feature = "window")]
+        let previewed = preview::window(&scene_path);
+        #[cfg(not(feature = "window"))]
+        let previewed = preview::progressive(&scene_path, &output_path);
+        if let Err(error) = previewed {
+            error!("Could not preview {}: {}", scene_path, error);
+            std::process::exit(EXIT_FAILURE);
+        }
+        return;
+    }
+    if std::env::args().any(|arg| arg == "--watch") {
+        if let Err(error) = preview::watch(&scene_path, &output_path, refinement) {
+            error!("Could not watch {}: {}", scene_path, error);
+            std::process::exit(EXIT_FAILURE);
+        }
+        return;
+    }
+    let bake = match (bake, parsed_option_with("--at", parse_position)) {
+        (false, _) => None,
+        (true, Some(at)) => Some(at),
+        (true, None) => {
+            error!("bake takes the point to bake the environment from with --at X,Y,Z");
+            std::process::exit(EXIT_FAILURE);
+        }
+    };
+    if bake.is_some() && !is_floating_point(&output_path) {
+        warn!(
+            "{} keeps no light brighter than white; bake to .hdr or .exr",
+            output_path
+        );
+    }
+    let masks = option_values("--mask");
+    if output_path == "-" && !masks.is_empty() {
+        error!("Masks cannot be written beside an image on stdout");
+        std::process::exit(EXIT_FAILURE);
+    }
+    let overrides = Overrides {
+        width: parsed_option("--width"),
+        height: parsed_option("--height"),
+        samples: parsed_option("--samples"),
+        max_bounces: parsed_option("--max-bounces"),
+        integrator: option_value("--integrator"),
+        tone_map: option_value("--tone-map"),
+        exposure: parsed_option("--exposure"),
+        auto_exposure: option_value("--auto-exposure"),
+        denoise: std::env::args().any(|arg| arg == "--denoise")
+            || (settings.denoise == Some(true)
+                && !std::env::args().any(|arg| arg == "--no-denoise")),
+        isolate: option_value("--isolate"),
+        masks: masks
+            .into_iter()
+            .map(|selection| {
+                let path = mask_path(&output_path, &selection);
+                (selection, path)
+            })
+            .collect(),
+        seed: parsed_option("--seed"),
+        output_dir: output_dir.clone(),
+        bake,
+    };
+    // Texture paths are relative to the scene file, or to the working directory for stdin
+    let base_dir = Path::new(&scene_path).parent().unwrap_or(Path::new(""));
+    let loaded = {
+        let _timer = StageTimer::start("Loading the scene");
+        Scene::read_value(&scene_path).and_then(|mut value| {
+            overrides.apply(&mut value);
+            Ok((Scene::from_value(value.clone(), base_dir)?, value))
+        })
+    };
+    // Diagnostic images for scenes that fail validation or render black
+    let diagnostics = output_path != "-"
+        && scene_path != "-"
+        && !std::env::args().any(|arg| arg == "--no-diagnostic" || arg == "--validate-only");
+    let (scene, value) = match loaded {
+        Ok(loaded) => loaded,
+        Err(error) => {
+            error!("Could not load {}: {}", scene_path, error);
+            progress::failed(&error.to_string());
+            if let (LoadError::Invalid(problems), true) = (&error, diagnostics) {
+                diagnose_invalid(&scene_path, &overrides, problems, &output_path);
+            }
+            std::process::exit(error.exit_code());
+        }
+    };
+    info!(
+        "{} objects and {} lights, {} of {}x{} pixels with {} samples each",
+        scene.objects().len(),
+        scene.lights().len(),
+        scene.camera().describe(),
+        scene.camera().film_columns(),
+        scene.camera().film_rows(),
+        scene.camera().samples
+    );
+    trace!("{:?}", scene);
+    // Refuse scenes that would run out of memory before any time is spent rendering them
+    let memory = scene.memory_usage();
+    info!("Estimated memory use: {}", memory);
+    if let Some(budget) = config::memory_budget_bytes().filter(|&budget| memory.total() > budget) {
+        let error = format!(
+            "the scene needs an estimated {}, more than the memory budget of {}",
+            memory,
+            megabytes(budget)
+        );
+        error!("Could not render {}: {}", scene_path, error);
+        progress::failed(&error);
+        std::process::exit(EXIT_FAILURE);
+    }
+    if std::env::args().nth(1).as_deref() == Some("converge") {
+        let csv_path = option_value("--output").or(option_value("-o"));
+        let csv_path = in_output_dir(csv_path.unwrap_or("convergence.csv".to_string()));
+        let reference_path = positional_args().get(2).cloned();
+        std::process::exit(converge(&scene, reference_path.as_ref(), &csv_path));
+    }
+    if std::env::args().nth(1).as_deref() == Some("slice") {
+        let image_path = option_value("--output").or(option_value("-o"));
+        let image_path = in_output_dir(image_path.unwrap_or("slice.png".to_string()));
+        let volume = parsed_option::<usize>("--volume").unwrap_or(0);
+        let axis = parsed_option_with("--axis", parse_axis).unwrap_or(2);
+        let depth = parsed_option::<Float>("--depth").unwrap_or(0.5);
+        std::process::exit(slice(&scene, volume, axis, depth, &image_path));
+    }
+    if std::env::args().nth(1).as_deref() == Some("ab") {
+        let at = parsed_option::<Float>("--split");
+        let angle = parsed_option::<Float>("--wipe");
+        let (at, angle) = (at.unwrap_or(0.5), angle.unwrap_or(0.0));
+        std::process::exit(ab(&value, base_dir, at, angle, &output_path));
+    }
+    if std::env::args().nth(1).as_deref() == Some("merge") {
+        let parts = positional_args().get(2..).unwrap_or_default().to_vec();
+        if parts.is_empty() {
+            error!("merge takes a scene and the parts rendered with --tile-range");
+            std::process::exit(EXIT_FAILURE);
+        }
+        if let Err(error) = Renderer::new(&scene).merge_to_file(&parts, &output_path) {
+            error!("Could not merge {}: {}", output_path, error);
+            progress::failed(&error.to_string());
+            std::process::exit(EXIT_FAILURE);
+        }
+        progress::finished(&output_path);
+        return;
+    }
+    // Check the scene and every file it refers to without rendering
+    if std::env::args().any(|arg| arg == "--validate-only") {
+        // Geometry problems were already reported while loading
+        let geometry_problems = validate::check_geometry(scene.objects()).len();
+        let layer_problems = validate::check_layers(&scene);
+        for problem in &layer_problems {
+            warn!("{}", problem);
+        }
+        let valid = geometry_problems == 0 && layer_problems.is_empty();
+        std::process::exit(if valid { 0 } else { EXIT_INVALID_SCENE });
+    }
+    // Counts of the work the render does, reported once it finishes
+    let stats_path = option_value("--stats-json").map(in_output_dir);
+    let keep_stats = stats_path.is_some() || std::env::args().any(|arg| arg == "--stats");
+    if keep_stats {
+        stats::enable();
+    }
+    let started = Instant::now();
+    // Frames of an animation as "first-last", each written to its own file
+    if let Some(frames) = parsed_option::<FrameRange>("--frames") {
+        if output_path == "-" {
+            error!("A sequence of frames cannot be written to stdout");
+            std::process::exit(EXIT_FAILURE);
+        }
+        if let Err(error) = sequence::render(&value, base_dir, frames, &output_path) {
+            error!("Could not render the sequence: {}", error);
+            progress::failed(&error.to_string());
+            std::process::exit(match error {
+                SequenceError::Load(_, error) => error.exit_code(),
+                SequenceError::Render(..) => EXIT_FAILURE,
+            });
+        }
+        if keep_stats {
+            report_stats(started, stats_path.as_deref());
+        }
+        let reproducibility = Reproducibility::of(&scene);
+        for frame in frames.first..=frames.last {
+            report_hash(&sequence::frame_path(&output_path, frame), &reproducibility);
+        }
+        if std::env::args().any(|arg| arg == "--verify") {
+            warn!("--verify is ignored for sequences of frames");
+        }
+        progress::finished(&output_path);
+        return;
+    }
+    // Finished pixels saved beside the output as the render goes, to resume it after a crash
+    let interval = parsed_option::<f64>("--checkpoint-every");
+    let checkpoint = Checkpoint {
+        interval: Duration::from_secs_f64(interval.unwrap_or(DEFAULT_CHECKPOINT_SECONDS)),
+        resume: std::env::args().any(|arg| arg == "--resume"),
+        accumulate: std::env::args().any(|arg| arg == "--accumulate"),
+    };
+    // A rectangle of the film to render on its own, as "x,y,width,height", clipped to the film
+    let film = scene.camera().film_region();
+    let region = parsed_option_with("--region", |arg| {
+        let clipped = arg.parse::<Region>()?.intersect(&film);
+        if clipped.is_empty() {
+            return Err(format!(
+                "lies outside the {}x{} film",
+                film.width, film.height
+            ));
+        }
+        Ok(clipped)
+    });
+    // A slice of the tiles to render for merging with the others, as "index/count"
+    let tile_range = parsed_option::<TileRange>("--tile-range");
+    if output_path == "-" && tile_range.is_some() {
+        error!("A part of a render cannot be written to stdout");
+        std::process::exit(EXIT_FAILURE);
+    }
+    // An existing output is only replaced by a render that reproduces it, unless forced
+    let verify = std::env::args().any(|arg| arg == "--verify")
+        && output_path != "-"
+        && Path::new(&output_path).exists();
+    let target = if verify {
+        reproduce::pending_path(&output_path)
+    } else {
+        output_path.clone()
+    };
+    let whole = tile_range.is_none() && region.is_none();
+    let result = match (tile_range, region) {
+        (Some(range), _) => Renderer::new(&scene).render_tile_range(&range, &target),
+        (None, Some(region)) => {
+            let image = Renderer::new(&scene).render_region(&region);
+            save_image(DynamicImage::from(image), &target)
+        }
+        (None, None) => refinement
+            .map_or(Ok(()), |refinement| {
+                refinement.write_passes(&scene, &target)
+            })
+            .and_then(|()| {
+                let renderer = Renderer::new(&scene);
+                match target.as_str() {
+                    "-" => renderer.render_to_file(&target),
+                    _ => renderer.with_checkpoint(checkpoint).render_to_file(&target),
+                }
+            }),
+    };
+    if keep_stats && result.is_ok() {
+        report_stats(started, stats_path.as_deref());
+    }
+    if let Err(error) = result {
+        error!("Could not render {}: {}", output_path, error);
+        progress::failed(&error.to_string());
+        std::process::exit(EXIT_FAILURE);
+    }
+    if target != "-" {
+        report_hash(&target, &Reproducibility::of(&scene));
+    }
+    if diagnostics && whole && is_black(&target) {
+        warn!("{} came out black", output_path);
+        diagnose(&scene, &output_path);
+    }
+    if verify {
+        let force = std::env::args().any(|arg| arg == "--force");
+        match settle_verified(&target, &output_path, force) {
+            Ok(true) => {}
+            Ok(false) => {
+                progress::failed(&format!("{} was not reproduced", output_path));
+                std::process::exit(EXIT_FAILURE);
+            }
+            Err(error) => {
+                error!("Could not verify {}: {}", output_path, error);
+                progress::failed(&error.to_string());
+                std::process::exit(EXIT_FAILURE);
+            }
+        }
+    }
+    progress::finished(&output_path);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usage_lists_every_option() {
+        for option in VALUE_OPTIONS.iter().chain(SWITCHES.iter()) {
+            assert!(
+                USAGE.contains(&format!("{} ", option)) || USAGE.contains(&format!("{},", option)),
+                "{} is missing from the usage",
+                option
+            );
+        }
+    }
 }
@@ -0,0 +1,607 @@
+use crate::animation::lattice_random;
+use crate::blackbody::blackbody;
+use crate::colour::ColourPipeline;
+use crate::core::consts::PI;
+use crate::core::shading::schlick;
+use crate::expression::{Expression, Inputs};
+use crate::light::coordinate_system;
+use crate::sampling::{instance_random, Rng};
+use crate::shape::Shape;
+use crate::subsurface::Subsurface;
+use crate::texture::Texture;
+use crate::{FVec, Float};
+use serde::{Deserialize, Deserializer};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Material {
+    // A plain colour, or an image or pattern mapped onto the surface
+    #[serde(alias = "baseColour")]
+    pub colour: Texture,
+    pub k_diffuse: Float,
+    pub k_ambient: Float,
+    pub k_specular: Float,
+    pub k_reflect: Float,
+    pub shine: Float,
+    // Fraction of light passing through the surface, refracted by the index of refraction
+    #[serde(default)]
+    pub k_transmit: Float,
+    #[serde(default = "default_ior")]
+    pub ior: Float,
+    /*
+    Abbe number of the index of refraction, for the spread of colours
+    refraction splits white light into when rendering spectrally: crown glass
+    has about 60 and flint glass about 35, which spreads colours further.
+    The index is that of yellow light.
+     */
+    pub abbe: Option<Float>,
+    /*
+    Split light between reflection and transmission by the Fresnel term of
+    the index of refraction, as water and glass do: more is reflected at
+    grazing angles. kReflect and kTransmit then scale the two parts.
+     */
+    #[serde(default)]
+    pub fresnel: bool,
+    /*
+    Treat the surface as a sheet too thin to bend light, such as a window
+    pane, a soap bubble or a leaf modelled as a single surface: light passing
+    through goes straight on, tinted by the surface's colour, and enters no
+    medium. The index of refraction still sets the Fresnel term.
+     */
+    #[serde(default)]
+    pub thin_walled: bool,
+    // Light absorbed per metre travelled inside a transparent object; water absorbs red most
+    #[serde(default = "default_absorption")]
+    pub absorption: FVec,
+    // Light scattered per metre inside a transparent object, as in murky water or smoky glass
+    #[serde(default = "default_absorption")]
+    pub scattering: FVec,
+    // Animated waves bending the normal, as on the surface of water
+    pub waves: Option<Waves>,
+    // Colour of the rim light caught by fibres at grazing angles, as on velvet; none by default
+    #[serde(default = "default_sheen")]
+    pub sheen: FVec,
+    // How far the sheen spreads from the rim, between 0 and 1
+    #[serde(default = "default_sheen_roughness")]
+    pub sheen_roughness: Float,
+    /*
+    Light given off by the surface. The path integrator finds it along the
+    paths it follows; the Whitted integrator samples bounded objects that
+    give off light as lights of their own to light the surfaces around them.
+     */
+    #[serde(default = "default_emission")]
+    pub emission: FVec,
+    // Shadow rays per shading point towards the object when the Whitted integrator samples it
+    pub emission_samples: Option<u32>,
+    // Colour temperature in kelvin of the emission, tinting it like a glowing filament
+    pub emission_temperature: Option<Float>,
+    // Light scattered beneath the surface, as in skin, wax and marble, in place of diffuse light
+    pub subsurface: Option<Subsurface>,
+    // Metallic flakes under the surface that glint in the light, as in car paint
+    pub flakes: Option<Flakes>,
+    // Strength of a clear glossy coat over the surface, which reflects most at grazing angles
+    #[serde(default)]
+    pub clearcoat: Float,
+    // Where transparent objects overlap, the one with the highest priority fills the overlap
+    #[serde(default)]
+    pub priority: u32,
+    // Random changes to the colour of each object with the material
+    pub variation: Option<Variation>,
+    // Normals in the surface's tangent space stored as colours, green pointing up the image
+    pub normal_map: Option<Texture>,
+    // Heights of the surface as the brightness of a texture, times the bump scale in metres
+    pub bump_map: Option<Texture>,
+    #[serde(default = "default_bump_scale")]
+    pub bump_scale: Float,
+    /*
+    Opacity of the surface as the brightness of a texture, 0 where it is cut
+    away, as for leaves and grass drawn on flat cards. Each ray passes a
+    partly opaque point by chance instead of blending what lies behind it.
+     */
+    pub opacity_map: Option<Texture>,
+    /*
+    Share of the light of lights the object stops from reaching what is
+    behind it, in place of how opaque it looks: 0 casts no shadow at all,
+    for lighting set up by hand. Shadows of a share in between are dappled,
+    smoothing out over the samples of a pixel.
+     */
+    pub shadow_opacity: Option<Float>,
+    /*
+    Set for physically based shading by a GGX microfacet model in place of
+    the Phong-style parameters, from 0 for plastics and other dielectrics to 1
+    for metals, which reflect in their own colour and have no diffuse colour.
+     */
+    pub metallic: Option<Float>,
+    // Spread of the microfacets of a physically based surface, from 0 for a mirror finish to 1
+    #[serde(default = "default_roughness")]
+    pub roughness: Float,
+    /*
+    Scalar parameters that vary over the surface or in time, worked out at
+    every point shaded. Written in a scene as a string in place of the number,
+    e.g. "kReflect": "0.8 * smoothstep(0, 2, z)" (see Expression).
+     */
+    #[serde(default, deserialize_with = "shared_expressions")]
+    pub(crate) expressions: Arc<BTreeMap<MaterialParameter, Expression>>,
+}
+
+// The scalar parameters of a material that can be given as expressions
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+pub enum MaterialParameter {
+    KDiffuse,
+    KAmbient,
+    KSpecular,
+    KReflect,
+    Shine,
+    KTransmit,
+    SheenRoughness,
+    Clearcoat,
+    Metallic,
+    Roughness,
+}
+
+/*
+Flakes suspended in paint. Space is divided into cubic cells the width of a
+flake and each cell holds a flake with a probability given by the density,
+tilted at random away from the surface. Each flake reflects lights in its
+own direction, so the surface sparkles.
+ */
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct Flakes {
+    #[serde(default = "default_flake_colour")]
+    pub colour: FVec,
+    // Fraction of cells holding a flake, between 0 and 1
+    #[serde(default = "default_flake_density")]
+    pub density: Float,
+    // Width of a flake in metres
+    #[serde(default = "default_flake_size")]
+    pub size: Float,
+    // Largest angle in degrees between a flake and the surface
+    #[serde(default = "default_flake_tilt")]
+    pub tilt: Float,
+    // Highlight exponent of a single flake, like the material's shine
+    #[serde(default = "default_flake_shine")]
+    pub shine: Float,
+}
+
+/*
+How far the colour of each object with the material may stray from the
+material's own, so copies of an object scattered through a scene do not all
+look alike. Every object draws its amounts from its own random values (as
+with rand in Expression), so it looks the same in every render with the same
+seed.
+ */
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct Variation {
+    // Largest turn of the hue either way, in degrees
+    #[serde(default)]
+    pub hue: Float,
+    // Largest fraction by which the saturation is raised or lowered
+    #[serde(default)]
+    pub saturation: Float,
+    // Largest fraction by which the brightness is raised or lowered
+    #[serde(default)]
+    pub brightness: Float,
+}
+
+/*
+Waves on water: trains of waves of falling lengths running roughly with the
+wind, each at the speed of deep water waves of its length, so they move
+naturally from frame to frame. Only the normal is bent; the surface itself
+stays where it is.
+ */
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct Waves {
+    // Height from trough to crest of the longest waves, in metres
+    #[serde(default = "default_wave_height")]
+    pub height: Float,
+    // Length of the longest waves, in metres
+    #[serde(default = "default_wavelength")]
+    pub wavelength: Float,
+    // Direction the wind blows the waves in, along the surface
+    #[serde(default = "default_wind")]
+    pub wind: FVec,
+    // Number of trains of waves, each shorter than the one before
+    #[serde(default = "default_wave_trains")]
+    pub trains: u32,
+    #[serde(default)]
+    pub seed: u32,
+}
+
+// Copies of a material, made for every point it is shaded at, share its expressions
+fn shared_expressions<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Arc<BTreeMap<MaterialParameter, Expression>>, D::Error> {
+    BTreeMap::deserialize(deserializer).map(Arc::new)
+}
+
+// First of the random values of an object used for colour variation
+const VARIATION_STREAM: u64 = 1 << 32;
+
+// Step in texture coordinates over which the slope of a bump map is measured
+const BUMP_STEP: Float = 1e-3;
+
+// Reflectance head on of a dielectric with an index of refraction of 1.5, such as a clearcoat
+const DIELECTRIC_R0: Float = 0.04;
+
+// Wavelengths in nanometres of the blue, yellow and red lines an Abbe number is measured at
+const BLUE_LINE: Float = 486.1;
+const YELLOW_LINE: Float = 587.6;
+const RED_LINE: Float = 656.3;
+
+// Acceleration due to gravity in metres per second squared, which sets the speed of waves
+const GRAVITY: Float = 9.81;
+
+// Ratio of the lengths of successive trains of waves
+const WAVE_LENGTH_RATIO: Float = 0.7;
+
+// Widest angle in radians between a train of waves and the wind
+const WAVE_SPREAD: Float = 0.6;
+
+// Narrowest microfacet distribution, which keeps highlights of point lights finite
+const MIN_ALPHA: Float = 1e-3;
+
+// Highlight exponent of the clearcoat, which is smoother than any paint under it
+pub(crate) const CLEARCOAT_SHINE: Float = 1000.0;
+
+impl Material {
+    pub(crate) fn load_textures(
+        &mut self,
+        base_dir: &Path,
+        colour: &ColourPipeline,
+    ) -> Result<(), Box<dyn Error>> {
+        self.colour.load(base_dir, colour)?;
+        let maps = self.normal_map.iter_mut().chain(&mut self.bump_map);
+        for map in maps.chain(&mut self.opacity_map) {
+            map.load_data(base_dir, colour)?;
+        }
+        Ok(())
+    }
+
+    // The colour texture and any maps of the material
+    pub(crate) fn textures(&self) -> impl Iterator<Item = &Texture> {
+        std::iter::once(&self.colour)
+            .chain(&self.normal_map)
+            .chain(&self.bump_map)
+            .chain(&self.opacity_map)
+    }
+
+    // Opacity at the texture coordinates and the point on the shape, 1 without an opacity map
+    pub(crate) fn opacity(&self, uv: (Float, Float), position: &FVec, normal: &FVec) -> Float {
+        match &self.opacity_map {
+            Some(map) => map
+                .at(uv, None, position, normal, None)
+                .mean()
+                .clamp(0.0, 1.0),
+            None => 1.0,
+        }
+    }
+
+    /*
+    The normal at a point bent by the material's bump map, as if the surface
+    were raised along the normal by the height (Blinn 1978), then by its
+    normal map. The tangents are the change in position per unit of u and of
+    v, which carry the maps' directions onto the surface; solid textures are
+    looked up at the point and at points along the tangents on the shape.
+     */
+    pub(crate) fn shading_normal(
+        &self,
+        shape: &Shape,
+        pos: &FVec,
+        normal: &FVec,
+        uv: (Float, Float),
+        (dpdu, dpdv): (FVec, FVec),
+    ) -> FVec {
+        let mut shading = *normal;
+        if let Some(bump) = &self.bump_map {
+            let height = |u: Float, v: Float, pos: FVec| {
+                let position = shape.object_position(&pos);
+                self.bump_scale * bump.at((u, v), None, &position, normal, None).mean()
+            };
+            let (u, v) = uv;
+            let here = height(u, v, *pos);
+            let dhdu = (height(u + BUMP_STEP, v, pos + dpdu * BUMP_STEP) - here) / BUMP_STEP;
+            let dhdv = (height(u, v + BUMP_STEP, pos + dpdv * BUMP_STEP) - here) / BUMP_STEP;
+            let bumped = (dpdu + shading * dhdu).cross(&(dpdv + shading * dhdv));
+            if let Some(bumped) = bumped.try_normalize(0.0) {
+                shading = if bumped.dot(normal) < 0.0 {
+                    -bumped
+                } else {
+                    bumped
+                };
+            }
+        }
+        if let Some(map) = &self.normal_map {
+            let position = shape.object_position(pos);
+            let local = map.at(uv, None, &position, normal, None) * 2.0 - FVec::repeat(1.0);
+            let Some(tangent) = (dpdu - shading * shading.dot(&dpdu)).try_normalize(0.0) else {
+                return shading;
+            };
+            // Up the image, against the direction v runs in
+            let mut bitangent = shading.cross(&tangent);
+            if bitangent.dot(&dpdv) > 0.0 {
+                bitangent = -bitangent;
+            }
+            let mapped = tangent * local.x + bitangent * local.y + shading * local.z;
+            shading = mapped.try_normalize(0.0).unwrap_or(shading);
+        }
+        shading
+    }
+
+    // Tint the emission by its colour temperature once, when the scene is loaded
+    pub(crate) fn apply_temperature(&mut self) {
+        if let Some(temperature) = self.emission_temperature.take() {
+            self.emission = self.emission.component_mul(&blackbody(temperature));
+        }
+    }
+
+    /*
+    Give parameters written as expressions their values at the origin in the
+    frame being rendered, for the object with the given random seed, for the
+    parts of the renderer that need one value for the whole material, such
+    as previews.
+     */
+    pub(crate) fn settle_expressions(&mut self, frame: Float, frame_rate: Float, instance: u64) {
+        let inputs = Inputs {
+            position: FVec::zeros(),
+            uv: (0.0, 0.0),
+            time: frame / frame_rate,
+            frame,
+            instance,
+        };
+        *self = self.at(&inputs).into_owned();
+    }
+
+    /*
+    The material with its expressions worked out for the inputs, or itself if
+    it has none. Values are kept within the range each parameter allows.
+     */
+    pub(crate) fn at(&self, inputs: &Inputs) -> Cow<'_, Material> {
+        if self.expressions.is_empty() {
+            return Cow::Borrowed(self);
+        }
+        let mut material = self.clone();
+        for (parameter, expression) in self.expressions.iter() {
+            let value = expression.evaluate(inputs);
+            let value = if value.is_finite() {
+                value.max(0.0)
+            } else {
+                0.0
+            };
+            match parameter {
+                MaterialParameter::KDiffuse => material.k_diffuse = value,
+                MaterialParameter::KAmbient => material.k_ambient = value,
+                MaterialParameter::KSpecular => material.k_specular = value,
+                MaterialParameter::KReflect => material.k_reflect = value,
+                MaterialParameter::Shine => material.shine = value,
+                MaterialParameter::KTransmit => material.k_transmit = value,
+                MaterialParameter::SheenRoughness => material.sheen_roughness = value.min(1.0),
+                MaterialParameter::Clearcoat => material.clearcoat = value,
+                MaterialParameter::Metallic => material.metallic = Some(value.min(1.0)),
+                MaterialParameter::Roughness => material.roughness = value.min(1.0),
+            }
+        }
+        Cow::Owned(material)
+    }
+
+    /*
+    Weight of the mirror reflection for a viewer at the given cosine to the
+    normal: the reflection coefficient plus the clearcoat's Fresnel term. For
+    physically based materials the reflection coefficient is estimated from
+    how metallic they are, as the colour varies over the surface.
+     */
+    pub(crate) fn reflectance(&self, cos_theta: Float) -> Float {
+        let reflect = match self.metallic {
+            Some(metallic) => schlick(
+                cos_theta.abs(),
+                DIELECTRIC_R0 + (1.0 - DIELECTRIC_R0) * metallic,
+            ),
+            None if self.fresnel => self.k_reflect * self.fresnel_term(cos_theta),
+            None => self.k_reflect,
+        };
+        reflect + self.clearcoat_fresnel(cos_theta)
+    }
+
+    // Weight of the light passing through the surface for a viewer at the given cosine
+    pub(crate) fn transmittance(&self, cos_theta: Float) -> Float {
+        if self.fresnel {
+            self.k_transmit * (1.0 - self.fresnel_term(cos_theta))
+        } else {
+            self.k_transmit
+        }
+    }
+
+    /*
+    Index of refraction for light of the wavelength in nanometres, by
+    Cauchy's equation fitted to the Abbe number, or the material's own index
+    for light of every wavelength together.
+     */
+    pub(crate) fn ior_at(&self, wavelength: Option<Float>) -> Float {
+        let (Some(abbe), Some(wavelength)) = (self.abbe, wavelength) else {
+            return self.ior;
+        };
+        let inverse_square = |wavelength: Float| 1.0 / (wavelength * wavelength);
+        let lines = inverse_square(BLUE_LINE) - inverse_square(RED_LINE);
+        let spread = (self.ior - 1.0) / (abbe * lines);
+        self.ior + spread * (inverse_square(wavelength) - inverse_square(YELLOW_LINE))
+    }
+
+    // Fraction of light reflected by a boundary with the index of refraction
+    fn fresnel_term(&self, cos_theta: Float) -> Float {
+        let r0 = ((self.ior - 1.0) / (self.ior + 1.0)).powi(2);
+        schlick(cos_theta.abs(), r0)
+    }
+
+    // Weight of the surface colour in direct and bounced light
+    pub(crate) fn diffuse_weight(&self) -> Float {
+        self.metallic
+            .map_or(self.k_diffuse, |metallic| 1.0 - metallic)
+    }
+
+    // Colour of the light reflected diffusely, less what translucent surfaces absorb beneath them
+    pub(crate) fn diffuse_colour(&self, albedo: &FVec) -> FVec {
+        let colour = self.diffuse_weight() * albedo;
+        match &self.subsurface {
+            Some(subsurface) => colour.component_mul(&subsurface.reflectance(self.ior)),
+            None => colour,
+        }
+    }
+
+    // Weight of the surface colour in ambient light
+    pub(crate) fn ambient_weight(&self) -> Float {
+        self.metallic
+            .map_or(self.k_ambient, |metallic| 1.0 - metallic)
+    }
+
+    // Reflectance head on of a physically based surface of the colour: a dielectric's, or a metal's
+    pub(crate) fn specular_colour(&self, albedo: &FVec) -> FVec {
+        FVec::repeat(DIELECTRIC_R0).lerp(albedo, self.metallic.unwrap_or(0.0))
+    }
+
+    // Width of the microfacet distribution of a physically based surface
+    pub(crate) fn alpha(&self) -> Float {
+        (self.roughness * self.roughness).max(MIN_ALPHA)
+    }
+
+    // Fraction of light the clearcoat reflects
+    pub(crate) fn clearcoat_fresnel(&self, cos_theta: Float) -> Float {
+        self.clearcoat * schlick(cos_theta.abs(), DIELECTRIC_R0)
+    }
+}
+
+impl Variation {
+    // The colour changed by the amounts drawn from the random values of an object
+    pub(crate) fn apply(&self, colour: FVec, instance: u64) -> FVec {
+        // Random values far past those expressions are likely to use
+        let amount = |index: u64| 2.0 * instance_random(instance, VARIATION_STREAM + index) - 1.0;
+        // Turning about the grey axis changes the hue and keeps the brightness
+        let angle = (self.hue * amount(0)).to_radians();
+        let axis = FVec::new(1.0, 1.0, 1.0).normalize();
+        let turned = colour * angle.cos()
+            + axis.cross(&colour) * angle.sin()
+            + axis * axis.dot(&colour) * (1.0 - angle.cos());
+        let grey = FVec::repeat(turned.mean());
+        let saturated = grey + (turned - grey) * (1.0 + self.saturation * amount(1)).max(0.0);
+        (saturated * (1.0 + self.brightness * amount(2))).map(|c| c.max(0.0))
+    }
+}
+
+impl Waves {
+    // The normal of a surface at the position bent by the slope of the waves at the time
+    pub(crate) fn normal(&self, pos: &FVec, normal: &FVec, time: Float) -> FVec {
+        let along = self.wind - normal * normal.dot(&self.wind);
+        let along = along
+            .try_normalize(0.0)
+            .unwrap_or(coordinate_system(normal).0);
+        let across = normal.cross(&along);
+        let (x, y) = (pos.dot(&along), pos.dot(&across));
+        let mut slope = (0.0, 0.0);
+        for train in 0..self.trains {
+            let random = |k: u32| lattice_random(self.seed, (train * 3 + k) as i64);
+            // Shorter waves are lower, keeping the steepness of the longest
+            let wavelength =
+                self.wavelength * WAVE_LENGTH_RATIO.powi(train as i32) * (0.8 + 0.4 * random(0));
+            let amplitude = 0.5 * self.height * wavelength / self.wavelength;
+            let angle = (2.0 * random(1) - 1.0) * WAVE_SPREAD;
+            let k = 2.0 * PI / wavelength;
+            // Deep water waves travel at angular frequency sqrt(g k)
+            let frequency = (GRAVITY * k).sqrt();
+            let (sin, cos) = angle.sin_cos();
+            let phase = k * (x * cos + y * sin) - frequency * time + 2.0 * PI * random(2);
+            let gradient = amplitude * k * phase.cos();
+            slope.0 += gradient * cos;
+            slope.1 += gradient * sin;
+        }
+        (normal - along * slope.0 - across * slope.1).normalize()
+    }
+}
+
+impl Flakes {
+    // Normal of the flake in the cell containing pos, or None if the cell is empty
+    pub(crate) fn normal(&self, pos: &FVec, normal: &FVec) -> Option<FVec> {
+        let cell = (pos / self.size).map(|c| c.floor() as i64);
+        let mut rng = Rng::for_cell([cell.x, cell.y, cell.z]);
+        if rng.next_float() >= self.density {
+            return None;
+        }
+        // Uniform over the cone of directions within the tilt of the normal
+        let cos_max = self.tilt.to_radians().cos();
+        let cos_theta = 1.0 - rng.next_float() * (1.0 - cos_max);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * crate::core::consts::PI * rng.next_float();
+        let (u, v) = coordinate_system(normal);
+        Some(normal * cos_theta + (u * phi.cos() + v * phi.sin()) * sin_theta)
+    }
+}
+
+pub fn default_ior() -> Float {
+    1.0
+}
+
+pub fn default_absorption() -> FVec {
+    FVec::zeros()
+}
+
+pub fn default_wave_height() -> Float {
+    0.1
+}
+
+pub fn default_wavelength() -> Float {
+    4.0
+}
+
+pub fn default_wind() -> FVec {
+    FVec::new(1.0, 0.0, 0.0)
+}
+
+pub fn default_wave_trains() -> u32 {
+    8
+}
+
+pub fn default_roughness() -> Float {
+    0.5
+}
+
+pub fn default_bump_scale() -> Float {
+    1.0
+}
+
+pub fn default_emission() -> FVec {
+    FVec::zeros()
+}
+
+pub fn default_sheen() -> FVec {
+    FVec::zeros()
+}
+
+pub fn default_sheen_roughness() -> Float {
+    0.3
+}
+
+pub fn default_flake_colour() -> FVec {
+    FVec::new(1.0, 1.0, 1.0)
+}
+
+pub fn default_flake_density() -> Float {
+    0.5
+}
+
+pub fn default_flake_size() -> Float {
+    0.005
+}
+
+pub fn default_flake_tilt() -> Float {
+    15.0
+}
+
+pub fn default_flake_shine() -> Float {
+    200.0
+}
@@ -0,0 +1,101 @@
+use crate::{FVec, Float, Material};
+
+#[derive(Debug, Clone, Copy)]
+struct Medium {
+    object: usize,
+    ior: Float,
+    priority: u32,
+    absorption: FVec,
+    scattering: FVec,
+}
+
+/*
+Transparent objects a ray is currently inside, for nested dielectrics
+(Schmidt and Budge 2002). Where volumes overlap, the one with the highest
+priority fills the overlap and the surfaces of the others inside it are
+ignored, so e.g. ice in water in a glass refracts with the right relative
+index at every boundary.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct MediumStack {
+    media: Vec<Medium>,
+    // Wavelength in nanometres of the light followed when rendering spectrally
+    wavelength: Option<Float>,
+}
+
+impl MediumStack {
+    // Outside every object, following light of the wavelength in nanometres
+    pub fn for_wavelength(wavelength: Float) -> MediumStack {
+        MediumStack {
+            media: Vec::new(),
+            wavelength: Some(wavelength),
+        }
+    }
+
+    // The medium the ray travels through; the latest entered wins a tie
+    fn current(&self) -> Option<&Medium> {
+        self.media.iter().max_by_key(|medium| medium.priority)
+    }
+
+    // Refractive index around the ray, 1 outside every object
+    pub fn ior(&self) -> Float {
+        self.current().map_or(1.0, |medium| medium.ior)
+    }
+
+    // Light absorbed per metre around the ray, none outside every object
+    pub fn absorption(&self) -> FVec {
+        self.current()
+            .map_or(FVec::zeros(), |medium| medium.absorption)
+    }
+
+    // Light scattered per metre around the ray, none outside every object
+    pub fn scattering(&self) -> FVec {
+        self.current()
+            .map_or(FVec::zeros(), |medium| medium.scattering)
+    }
+
+    // Whether the ray is outside every object
+    pub fn is_empty(&self) -> bool {
+        self.media.is_empty()
+    }
+
+    // Indices of the objects the ray is inside
+    pub fn objects(&self) -> impl Iterator<Item = usize> + '_ {
+        self.media.iter().map(|medium| medium.object)
+    }
+
+    /*
+    Whether a surface of the object is a real boundary between media rather
+    than lying inside a volume of higher priority.
+     */
+    pub fn is_interface(&self, object: usize, priority: u32) -> bool {
+        self.media
+            .iter()
+            .filter(|medium| medium.object != object)
+            .all(|medium| medium.priority <= priority)
+    }
+
+    // The media on the other side of a surface of the object, the same for a thin wall
+    pub fn crossed(&self, object: usize, material: &Material) -> MediumStack {
+        if material.thin_walled {
+            return self.clone();
+        }
+        let mut media = self.media.clone();
+        match media.iter().position(|medium| medium.object == object) {
+            Some(index) => {
+                media.remove(index);
+            }
+            None => media.push(Medium {
+                object,
+                ior: material.ior_at(self.wavelength),
+                priority: material.priority,
+                absorption: material.absorption,
+                scattering: material.scattering,
+            }),
+        }
+        MediumStack {
+            media,
+            wavelength: self.wavelength,
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use crate::config::texture_cache_bytes;
+use crate::environment::Environment;
+use crate::gbuffer::{PixelSamples, PrimarySample};
+use crate::grid::GridVolume;
+use crate::photon::PhotonMap;
+use crate::texture::Texture;
+use crate::{Camera, FVec, Scene, Shape};
+use std::collections::HashSet;
+use std::fmt;
+use std::mem::{size_of, size_of_val};
+use std::sync::Arc;
+
+/*
+Estimated memory a scene takes while it renders, by the part of the renderer
+holding it. Only the large allocations are counted, so the total is a lower
+bound, but one that grows with the scene the way the real figure does.
+ */
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct MemoryUsage {
+    // Triangles of meshes as read and as laid out for intersection, and the other primitives
+    pub meshes: usize,
+    // Decoded texture images, at most the texture cache's budget, and grids of volumes
+    pub textures: usize,
+    // The BVH, and the kd-tree of any caustic photons
+    pub bvh: usize,
+    // Primary ray hits kept for filtering and extra outputs, shaded pixels and the image
+    pub framebuffers: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.meshes + self.textures + self.bvh + self.framebuffers
+    }
+}
+
+// A number of bytes in megabytes, for messages
+pub fn megabytes(bytes: usize) -> String {
+    format!("{:.1} MB", bytes as f64 / (1 << 20) as f64)
+}
+
+impl fmt::Display for MemoryUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (meshes {}, textures {}, BVH {}, framebuffers {})",
+            megabytes(self.total()),
+            megabytes(self.meshes),
+            megabytes(self.textures),
+            megabytes(self.bvh),
+            megabytes(self.framebuffers)
+        )
+    }
+}
+
+impl Scene {
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            meshes: self._mesh_bytes() + self.primitives.geometry_bytes(),
+            textures: self._texture_bytes(),
+            bvh: self.primitives.bvh_bytes() + self.photons.as_ref().map_or(0, PhotonMap::bytes),
+            framebuffers: self._framebuffer_bytes(&self.camera),
+        }
+    }
+
+    // Triangles of every mesh, counting meshes shared between objects once
+    pub(crate) fn _mesh_bytes(&self) -> usize {
+        let mut seen = HashSet::new();
+        self.objects
+            .iter()
+            .filter_map(|object| match &object.shape {
+                Shape::Mesh { mesh, .. } => seen
+                    .insert(Arc::as_ptr(mesh))
+                    .then(|| size_of_val(mesh.triangles.as_slice())),
+                _ => None,
+            })
+            .sum()
+    }
+
+    /*
+    Decoded size of every image texture and map of the objects and layer overrides,
+    counting textures shared between materials once. Images are dropped from
+    the texture cache past its budget, so no more than that is held at once.
+     */
+    pub(crate) fn _texture_bytes(&self) -> usize {
+        let textures = self
+            .objects
+            .iter()
+            .flat_map(|object| object.material.textures())
+            .chain(
+                self.layers
+                    .iter()
+                    .flat_map(|layer| &layer.material_override)
+                    .flat_map(|m| m.textures()),
+            )
+            .chain(self.decals.iter().flat_map(|decal| decal.textures()));
+        let mut seen = HashSet::new();
+        let decoded: usize = textures
+            .filter_map(Texture::images)
+            .filter(|images| seen.insert(Arc::as_ptr(images)))
+            .map(|images| images.decoded_bytes())
+            .sum();
+        // The environment image is kept whole, outside the texture cache
+        let environment = self.environment.as_ref().map_or(0, Environment::bytes);
+        // As are the grids of volumes
+        let volumes: usize = self.volumes.iter().map(GridVolume::bytes).sum();
+        decoded.min(texture_cache_bytes()) + environment + volumes
+    }
+
+    /*
+    Every render keeps the shaded colour of each pixel and the 8-bit image.
+    Renders that filter across pixels, write extra outputs or have holdouts
+    also keep the first hit of every primary ray until shading is done.
+     */
+    pub(crate) fn _framebuffer_bytes(&self, camera: &Camera) -> usize {
+        let pixels = camera.film_columns() as usize * camera.film_rows() as usize;
+        let mut bytes = pixels * (size_of::<(u32, u32, FVec)>() + 3 * size_of::<u8>());
+        let keeps_hits = !camera.filter.is_pixel_sized()
+            || !self.aovs.is_empty()
+            || self.deep_output.is_some()
+            || self.multilayer_output.is_some()
+            || self.segmentation_output.is_some()
+            || self.annotation_output.is_some()
+            || self.objects.iter().any(|object| object.holdout);
+        if keeps_hits {
+            let samples = camera.samples.max(1) as usize;
+            bytes += pixels * (size_of::<PixelSamples>() + samples * size_of::<PrimarySample>());
+        }
+        bytes
+    }
+}
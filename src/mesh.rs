@@ -0,0 +1,248 @@
+use crate::bounds::Aabb;
+use crate::colour::Processor;
+use crate::core::ray::Dissolve;
+use crate::decimate::decimate;
+#[cfg(feature = "gltf")]
+use crate::gltf::parse_gltf;
+#[cfg(feature = "obj")]
+use crate::obj::parse_obj;
+#[cfg(feature = "ply")]
+use crate::ply::parse_ply;
+use crate::transform::{is_mirror, normal_matrix, transform_point, Transform};
+use crate::{FVec, Float};
+use nalgebra::Matrix4;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+
+// Normals used for shading a mesh
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum Normals {
+    // The vertex normals in the file where it has them, and face normals elsewhere
+    #[default]
+    Authored,
+    // Face normals everywhere, so every triangle is visibly flat
+    Flat,
+    /*
+    Vertex normals averaged over the faces around each vertex that meet it at
+    less than the given angle in degrees, so sharper edges stay crisp
+     */
+    AutoSmooth(Float),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Triangle {
+    pub(crate) vertices: [FVec; 3],
+    // Normals at the corners, interpolated across the face; the face normal is used without them
+    pub(crate) normals: Option<[FVec; 3]>,
+    // Linear colours at the corners, interpolated across the face
+    pub(crate) colours: Option<[FVec; 3]>,
+    // Texture coordinates at the corners, with v running down the image like Shape::uv
+    pub(crate) uvs: Option<[(Float, Float); 3]>,
+    // How much of what is behind shows through, from the material of the face in an OBJ file
+    pub(crate) dissolve: Option<Dissolve>,
+}
+
+#[derive(Default, Clone, PartialEq)]
+pub struct TriangleMesh {
+    pub(crate) triangles: Vec<Triangle>,
+    // Where the origin of the file's coordinates is in the scene
+    pub(crate) origin: FVec,
+    pub(crate) bounds: Option<Aabb>,
+}
+
+impl TriangleMesh {
+    /*
+    Read a PLY or glTF file, or a Wavefront OBJ file for any other extension,
+    and place it in the scene. Vertex colours in PLY and OBJ files are taken to
+    be stored with the sRGB transfer curve, as they almost always are, and
+    converted to linear; glTF stores them linear already. Meshes with more
+    triangles than a given target are simplified down to it.
+     */
+    pub fn load(
+        path: &Path,
+        transform: &Transform,
+        normals: Normals,
+        target_triangles: Option<usize>,
+    ) -> Result<TriangleMesh, Box<dyn Error>> {
+        // Read even when built without any mesh format, so a missing file is reported as such
+        #[cfg_attr(
+            not(any(feature = "obj", feature = "ply", feature = "gltf")),
+            allow(unused_variables)
+        )]
+        let bytes =
+            std::fs::read(path).map_err(|error| format!("{}: {}", path.display(), error))?;
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+        #[cfg(any(feature = "gltf", feature = "obj"))]
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let (triangles, srgb): (Result<Vec<Triangle>, String>, bool) = match extension.as_deref() {
+            #[cfg(feature = "ply")]
+            Some("ply") => (parse_ply(&bytes), true),
+            #[cfg(not(feature = "ply"))]
+            Some("ply") => (Err("built without PLY support".to_string()), true),
+            #[cfg(feature = "gltf")]
+            Some("gltf" | "glb") => (parse_gltf(&bytes, dir), false),
+            #[cfg(not(feature = "gltf"))]
+            Some("gltf" | "glb") => (Err("built without glTF support".to_string()), false),
+            #[cfg(feature = "obj")]
+            _ => (parse_obj(&String::from_utf8_lossy(&bytes), dir), true),
+            #[cfg(not(feature = "obj"))]
+            _ => (Err("built without OBJ support".to_string()), true),
+        };
+        let triangles = triangles.map_err(|error| format!("{}: {}", path.display(), error))?;
+        let to_linear = Processor::srgb_to_linear();
+        let triangles: Vec<Triangle> = triangles
+            .into_iter()
+            .map(|triangle| Triangle {
+                colours: match srgb {
+                    true => triangle
+                        .colours
+                        .map(|colours| colours.map(|c| to_linear.apply(c))),
+                    false => triangle.colours,
+                },
+                ..triangle
+            })
+            .collect();
+        let triangles = match target_triangles {
+            Some(target) => {
+                let count = triangles.len();
+                let triangles = decimate(triangles, target);
+                debug!(
+                    "Simplified {} from {} to {} triangles",
+                    path.display(),
+                    count,
+                    triangles.len()
+                );
+                triangles
+            }
+            None => triangles,
+        };
+        let matrix = transform
+            .matrix()
+            .map_err(|error| format!("{}: {}", path.display(), error))?;
+        let mut mesh = TriangleMesh::new(triangles, FVec::zeros()).transformed(&matrix);
+        match normals {
+            Normals::Authored => {}
+            Normals::Flat => mesh
+                .triangles
+                .iter_mut()
+                .for_each(|triangle| triangle.normals = None),
+            Normals::AutoSmooth(angle) => auto_smooth(&mut mesh.triangles, angle),
+        }
+        Ok(mesh)
+    }
+
+    /*
+    The mesh moved by a transform. Mirroring transforms also reverse the order
+    of every triangle's corners, so face normals keep facing out.
+     */
+    pub(crate) fn transformed(&self, matrix: &Matrix4<Float>) -> TriangleMesh {
+        let normal_matrix = normal_matrix(matrix);
+        let mirror = is_mirror(matrix);
+        let triangles = self
+            .triangles
+            .iter()
+            .map(|triangle| Triangle {
+                vertices: wind(
+                    triangle.vertices.map(|v| transform_point(matrix, &v)),
+                    mirror,
+                ),
+                normals: triangle
+                    .normals
+                    .map(|normals| wind(normals.map(|n| (normal_matrix * n).normalize()), mirror)),
+                colours: triangle.colours.map(|colours| wind(colours, mirror)),
+                uvs: triangle.uvs.map(|uvs| wind(uvs, mirror)),
+                dissolve: triangle.dissolve,
+            })
+            .collect();
+        TriangleMesh::new(triangles, transform_point(matrix, &self.origin))
+    }
+
+    pub(crate) fn new(triangles: Vec<Triangle>, origin: FVec) -> TriangleMesh {
+        let bounds = Aabb::around(triangles.iter().flat_map(|triangle| triangle.vertices));
+        TriangleMesh {
+            triangles,
+            origin,
+            bounds,
+        }
+    }
+
+    pub(crate) fn scale(&mut self, factor: Float) {
+        let triangles = std::mem::take(&mut self.triangles)
+            .into_iter()
+            .map(|triangle| Triangle {
+                vertices: triangle.vertices.map(|v| v * factor),
+                ..triangle
+            })
+            .collect();
+        *self = TriangleMesh::new(triangles, self.origin * factor);
+    }
+}
+
+// Corners of a triangle, in reverse winding order if asked
+fn wind<T>(mut corners: [T; 3], reverse: bool) -> [T; 3] {
+    if reverse {
+        corners.swap(1, 2);
+    }
+    corners
+}
+
+impl fmt::Debug for TriangleMesh {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TriangleMesh({} triangles)", self.triangles.len())
+    }
+}
+
+/*
+Replace the normals of every triangle with ones averaged, weighted by area,
+over the faces sharing each corner whose normals are within the angle of the
+triangle's own. Corners are shared when their positions are identical.
+ */
+fn auto_smooth(triangles: &mut [Triangle], angle: Float) {
+    let key = |v: &FVec| [v.x, v.y, v.z].map(Float::to_bits);
+    let mut faces_at: HashMap<_, Vec<usize>> = HashMap::new();
+    for (index, triangle) in triangles.iter().enumerate() {
+        for vertex in &triangle.vertices {
+            faces_at.entry(key(vertex)).or_default().push(index);
+        }
+    }
+    // Face normals with a length of twice the face's area
+    let face_normals: Vec<FVec> = triangles
+        .iter()
+        .map(|t| (t.vertices[1] - t.vertices[0]).cross(&(t.vertices[2] - t.vertices[0])))
+        .collect();
+    let cos_max = angle.to_radians().cos();
+    for (index, triangle) in triangles.iter_mut().enumerate() {
+        let Some(own) = face_normals[index].try_normalize(0.0) else {
+            triangle.normals = None;
+            continue;
+        };
+        let smooth = |vertex: &FVec| {
+            faces_at[&key(vertex)]
+                .iter()
+                .map(|&face| face_normals[face])
+                .filter(|normal| {
+                    normal
+                        .try_normalize(0.0)
+                        .is_some_and(|n| n.dot(&own) >= cos_max)
+                })
+                .sum::<FVec>()
+                .normalize()
+        };
+        triangle.normals = Some(triangle.vertices.each_ref().map(smooth));
+    }
+}
+
+// Values at the three corners of a triangle, if every corner has one
+#[cfg(any(feature = "obj", feature = "ply"))]
+pub(crate) fn all_corners<T: Copy>(values: [Option<T>; 3]) -> Option<[T; 3]> {
+    match values {
+        [Some(v0), Some(v1), Some(v2)] => Some([v0, v1, v2]),
+        _ => None,
+    }
+}
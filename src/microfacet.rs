@@ -0,0 +1,92 @@
+use crate::core::shading::smith_visibility;
+use crate::sampling::ggx_normal;
+use crate::{FVec, Float};
+use std::sync::OnceLock;
+
+// Entries of the table along the roughness and along the cosine of the view to the normal
+const SIZE: usize = 32;
+// Microfacet normals drawn per side of the grid of them averaged over for each entry
+const STRATA: usize = 64;
+
+/*
+Light lost by a GGX surface to light bouncing between its microfacets, which
+the microfacet model leaves out: a rough white metal reflects only part of
+the light reaching it and darkens the rougher it is. The lost share is put
+back in proportion to the reflectance (Fdez-Agüera 2019, after Kulla and
+Conty 2017), from a table of the share a white surface reflects when light
+scatters once, by roughness and view angle, worked out the first time it is
+needed. A white metal then reflects all the light at any roughness.
+ */
+struct AlbedoTable {
+    values: Vec<Float>,
+}
+
+impl AlbedoTable {
+    fn get() -> &'static AlbedoTable {
+        static TABLE: OnceLock<AlbedoTable> = OnceLock::new();
+        TABLE.get_or_init(AlbedoTable::generate)
+    }
+
+    // Averaged over microfacet normals drawn in a grid, weighted as in the microfacet reflection
+    fn generate() -> AlbedoTable {
+        let mut values = Vec::with_capacity(SIZE * SIZE);
+        for i in 0..SIZE {
+            let roughness = node(i);
+            let alpha = roughness * roughness;
+            for j in 0..SIZE {
+                let n_dot_v = node(j).max(1e-3);
+                let to_viewer = FVec::new((1.0 - n_dot_v * n_dot_v).sqrt(), 0.0, n_dot_v);
+                let mut total = 0.0;
+                for a in 0..STRATA {
+                    for b in 0..STRATA {
+                        let u = (a as Float + 0.5) / STRATA as Float;
+                        let v = (b as Float + 0.5) / STRATA as Float;
+                        let half = ggx_normal(alpha, u, v);
+                        let v_dot_h = to_viewer.dot(&half);
+                        let n_dot_l = 2.0 * v_dot_h * half.z - n_dot_v;
+                        if v_dot_h <= 0.0 || n_dot_l <= 0.0 {
+                            continue;
+                        }
+                        let masking =
+                            4.0 * n_dot_l * n_dot_v * smith_visibility(n_dot_l, n_dot_v, alpha);
+                        total += masking * v_dot_h / (n_dot_v * half.z);
+                    }
+                }
+                values.push(total / (STRATA * STRATA) as Float);
+            }
+        }
+        AlbedoTable { values }
+    }
+
+    // Interpolated between the entries around the roughness and cosine
+    fn albedo(&self, roughness: Float, n_dot_v: Float) -> Float {
+        let (i, s) = position(roughness);
+        let (j, t) = position(n_dot_v);
+        let at = |i: usize, j: usize| self.values[i * SIZE + j];
+        let low = at(i, j) * (1.0 - t) + at(i, j + 1) * t;
+        let high = at(i + 1, j) * (1.0 - t) + at(i + 1, j + 1) * t;
+        low * (1.0 - s) + high * s
+    }
+}
+
+// Value of the entry at the index, from 0 to 1
+fn node(index: usize) -> Float {
+    index as Float / (SIZE - 1) as Float
+}
+
+// Index of the entry at or below the value, and how far the value is towards the next
+fn position(value: Float) -> (usize, Float) {
+    let scaled = value.clamp(0.0, 1.0) * (SIZE - 1) as Float;
+    let index = (scaled.floor() as usize).min(SIZE - 2);
+    (index, scaled - index as Float)
+}
+
+/*
+Factor scaling the light a GGX surface of the width alpha and reflectance f0
+head on reflects towards a viewer at the cosine to its normal, making up for
+the light that scatters more than once between its microfacets.
+ */
+pub(crate) fn compensation(alpha: Float, n_dot_v: Float, f0: &FVec) -> FVec {
+    let albedo = AlbedoTable::get().albedo(alpha.sqrt(), n_dot_v).max(1e-3);
+    FVec::repeat(1.0) + f0 * (1.0 / albedo - 1.0)
+}
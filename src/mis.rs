@@ -0,0 +1,79 @@
+use crate::core::consts::PI;
+use crate::core::shading::ggx_distribution;
+use crate::light::coordinate_system;
+use crate::sampling::ggx_normal;
+use crate::{FVec, Float, Material};
+
+/*
+The highlight of a physically based surface, for drawing the directions
+light reaches it from as well as points on the light (multiple importance
+sampling, Veach 1995). Drawing points on a light finds it well when it looks
+small next to the highlight, but on a polished surface most of them fall
+outside the highlight, where drawing from the highlight hits the light
+instead. Each sample is drawn one way or the other at random and weighted by
+its value over the probability of it being drawn either way (the balance
+heuristic), so the estimate stays unbiased wherever either way works.
+ */
+pub(crate) struct Lobe {
+    alpha: Float,
+    // Share of the light the surface reflects in its highlight rather than diffusely
+    weight: Float,
+}
+
+impl Lobe {
+    // The highlight of a physically based material of the colour, or none otherwise
+    pub(crate) fn of(material: &Material, albedo: &FVec) -> Option<Lobe> {
+        material.metallic?;
+        let specular = material.specular_colour(albedo).mean();
+        let diffuse = material.diffuse_weight() * albedo.mean();
+        (specular > 0.0).then(|| Lobe {
+            alpha: material.alpha(),
+            weight: specular / (specular + diffuse),
+        })
+    }
+
+    /*
+    Share of the samples of a light of the solid angle to draw from the
+    highlight: more the more of the light the highlight holds, and only once
+    it is a few times narrower than the light by its peak density, as points
+    on the light are spread more evenly otherwise.
+     */
+    pub(crate) fn share(&self, normal: &FVec, to_viewer: &FVec, solid_angle: Float) -> Float {
+        let n_dot_v = normal.dot(to_viewer);
+        if n_dot_v <= 0.0 {
+            return 0.0;
+        }
+        let peak = 1.0 / (4.0 * PI * self.alpha * self.alpha * n_dot_v);
+        let narrowness = peak * solid_angle;
+        self.weight * narrowness * narrowness / (16.0 + narrowness * narrowness)
+    }
+
+    // A unit direction light could reach the viewer from, or none if it is below the surface
+    pub(crate) fn sample(
+        &self,
+        normal: &FVec,
+        to_viewer: &FVec,
+        u1: Float,
+        u2: Float,
+    ) -> Option<FVec> {
+        let local = ggx_normal(self.alpha, u1, u2);
+        let (s, t) = coordinate_system(normal);
+        let half = s * local.x + t * local.y + normal * local.z;
+        let v_dot_h = to_viewer.dot(&half);
+        let direction = 2.0 * v_dot_h * half - to_viewer;
+        (v_dot_h > 0.0 && normal.dot(&direction) > 0.0).then_some(direction)
+    }
+
+    // Probability per unit solid angle of sample drawing the direction
+    pub(crate) fn pdf(&self, normal: &FVec, to_viewer: &FVec, direction: &FVec) -> Float {
+        let Some(half) = (direction + to_viewer).try_normalize(1e-9) else {
+            return 0.0;
+        };
+        let (n_dot_h, v_dot_h) = (normal.dot(&half), to_viewer.dot(&half));
+        if n_dot_h <= 0.0 || v_dot_h <= 0.0 || normal.dot(direction) <= 0.0 {
+            return 0.0;
+        }
+        // Reflecting about the half vector squeezes the solid angle by 4 (v.h)
+        ggx_distribution(n_dot_h, self.alpha) / PI * n_dot_h / (4.0 * v_dot_h)
+    }
+}
@@ -0,0 +1,53 @@
+use exr::prelude::*;
+use image::Rgb32FImage;
+
+const TILE_SIZE: usize = 64;
+const CHANNEL_NAMES: [&str; 3] = ["R", "G", "B"];
+
+/*
+Write several RGB images of the same size into one tiled OpenEXR file. Each
+image's channels are named `<layer>.R`, `<layer>.G` and `<layer>.B`, which is
+how Nuke and most compositors split a file into layers; an image with an
+empty layer name becomes the plain R, G and B channels. The outermost
+`overscan` pixels on each side are stored outside the display window.
+ */
+pub fn write_multilayer_exr(
+    path: &str,
+    width: u32,
+    height: u32,
+    overscan: u32,
+    layers: &[(&str, Rgb32FImage)],
+) -> Result<()> {
+    let size = Vec2(width as usize, height as usize);
+    let mut channels = SmallVec::new();
+    for (layer, image) in layers {
+        for (index, channel) in CHANNEL_NAMES.iter().enumerate() {
+            let name = if layer.is_empty() {
+                channel.to_string()
+            } else {
+                format!("{}.{}", layer, channel)
+            };
+            let samples = image.pixels().map(|pixel| pixel[index]).collect();
+            channels.push(AnyChannel::new(name.as_str(), FlatSamples::F32(samples)));
+        }
+    }
+    let encoding = Encoding {
+        compression: Compression::ZIP16,
+        blocks: Blocks::Tiles(Vec2(TILE_SIZE, TILE_SIZE)),
+        line_order: LineOrder::Increasing,
+    };
+    let frame = Vec2(
+        (width - 2 * overscan) as usize,
+        (height - 2 * overscan) as usize,
+    );
+    let attributes = LayerAttributes {
+        layer_position: Vec2(-(overscan as i32), -(overscan as i32)),
+        ..LayerAttributes::default()
+    };
+    let layer = Layer::new(size, attributes, encoding, AnyChannels::sort(channels));
+    let image = Image {
+        attributes: ImageAttributes::new(IntegerBounds::from_dimensions(frame)),
+        layer_data: layer,
+    };
+    image.write().to_file(path)
+}
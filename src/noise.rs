@@ -0,0 +1,86 @@
+use crate::{FVec, Float};
+
+// Gradients towards the edges of a cube, as in Perlin's improved noise (2002)
+const GRADIENTS: [[Float; 3]; 12] = [
+    [1.0, 1.0, 0.0],
+    [-1.0, 1.0, 0.0],
+    [1.0, -1.0, 0.0],
+    [-1.0, -1.0, 0.0],
+    [1.0, 0.0, 1.0],
+    [-1.0, 0.0, 1.0],
+    [1.0, 0.0, -1.0],
+    [-1.0, 0.0, -1.0],
+    [0.0, 1.0, 1.0],
+    [0.0, -1.0, 1.0],
+    [0.0, 1.0, -1.0],
+    [0.0, -1.0, -1.0],
+];
+
+// Each octave of fractal noise is this much finer than the last, and this much fainter
+const LACUNARITY: Float = 2.0;
+const GAIN: Float = 0.5;
+
+fn hash(seed: u32, cell: [i64; 3]) -> u32 {
+    let mut h = seed.wrapping_mul(0x9e37_79b9);
+    for coordinate in cell {
+        h ^= coordinate as u32;
+        h ^= h >> 16;
+        h = h.wrapping_mul(0x7feb_352d);
+        h ^= h >> 15;
+        h = h.wrapping_mul(0x846c_a68b);
+        h ^= h >> 16;
+    }
+    h
+}
+
+fn fade(t: Float) -> Float {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+/*
+Perlin gradient noise at a point, roughly in [-1, 1] and varying on the scale
+of one unit. It is zero on the lattice points, so patterns built from it
+should not be sampled only there.
+ */
+pub fn perlin(seed: u32, p: &FVec) -> Float {
+    let floor = p.map(Float::floor);
+    let cell = [floor.x as i64, floor.y as i64, floor.z as i64];
+    let offset = p - floor;
+    let corner = |dx: i64, dy: i64, dz: i64| {
+        let corner = [cell[0] + dx, cell[1] + dy, cell[2] + dz];
+        let gradient = GRADIENTS[hash(seed, corner) as usize % GRADIENTS.len()];
+        let d = offset - FVec::new(dx as Float, dy as Float, dz as Float);
+        FVec::from(gradient).dot(&d)
+    };
+    let (u, v, w) = (fade(offset.x), fade(offset.y), fade(offset.z));
+    let lerp = |t: Float, a: Float, b: Float| a + (b - a) * t;
+    let x00 = lerp(u, corner(0, 0, 0), corner(1, 0, 0));
+    let x10 = lerp(u, corner(0, 1, 0), corner(1, 1, 0));
+    let x01 = lerp(u, corner(0, 0, 1), corner(1, 0, 1));
+    let x11 = lerp(u, corner(0, 1, 1), corner(1, 1, 1));
+    lerp(w, lerp(v, x00, x10), lerp(v, x01, x11))
+}
+
+// Octaves of noise summed from coarse to fine, roughly in [-1, 1]
+pub fn fractal(seed: u32, p: &FVec, octaves: u32) -> Float {
+    let (mut total, mut amplitude, mut norm, mut frequency) = (0.0, 1.0, 0.0, 1.0);
+    for octave in 0..octaves.max(1) {
+        total += amplitude * perlin(seed.wrapping_add(octave), &(p * frequency));
+        norm += amplitude;
+        amplitude *= GAIN;
+        frequency *= LACUNARITY;
+    }
+    total / norm
+}
+
+// Like fractal noise but summing the size of each octave, in [0, 1] with creases at the zeros
+pub fn turbulence(seed: u32, p: &FVec, octaves: u32) -> Float {
+    let (mut total, mut amplitude, mut norm, mut frequency) = (0.0, 1.0, 0.0, 1.0);
+    for octave in 0..octaves.max(1) {
+        total += amplitude * perlin(seed.wrapping_add(octave), &(p * frequency)).abs();
+        norm += amplitude;
+        amplitude *= GAIN;
+        frequency *= LACUNARITY;
+    }
+    (total / norm).min(1.0)
+}
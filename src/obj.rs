@@ -0,0 +1,207 @@
+use crate::core::clamp;
+use crate::core::ray::Dissolve;
+use crate::mesh::{all_corners, Triangle};
+use crate::{FVec, Float};
+use std::collections::HashMap;
+use std::path::Path;
+
+// The first three numbers on a line, e.g. a vertex position or normal
+fn parse_vector<'a>(mut words: impl Iterator<Item = &'a str>) -> Result<FVec, String> {
+    let mut component = || -> Result<Float, String> {
+        let word = words.next().ok_or("expected three coordinates")?;
+        word.parse().map_err(|_| format!("invalid number {word:?}"))
+    };
+    Ok(FVec::new(component()?, component()?, component()?))
+}
+
+/*
+Texture coordinates on a "vt" line. Files have v running up the image, so
+it is flipped to match the rest of the renderer.
+ */
+fn parse_uv<'a>(mut words: impl Iterator<Item = &'a str>) -> Result<(Float, Float), String> {
+    let mut component = |default: Option<Float>| -> Result<Float, String> {
+        match words.next() {
+            Some(word) => word.parse().map_err(|_| format!("invalid number {word:?}")),
+            None => default.ok_or("expected texture coordinates".to_string()),
+        }
+    };
+    let u = component(None)?;
+    let v = component(Some(0.0))?;
+    Ok((u, 1.0 - v))
+}
+
+// Entry of a list given by a 1-based index, or counting back from the end when negative
+fn lookup<T: Copy>(items: &[T], index: &str) -> Result<T, String> {
+    let index: i64 = index
+        .parse()
+        .map_err(|_| format!("invalid index {index:?}"))?;
+    let position = if index < 0 {
+        items.len() as i64 + index
+    } else {
+        index - 1
+    };
+    usize::try_from(position)
+        .ok()
+        .and_then(|position| items.get(position).copied())
+        .ok_or(format!("index {index} is out of range"))
+}
+
+// Attributes of a face's corner in an OBJ file
+#[derive(Clone, Copy)]
+struct Corner {
+    position: FVec,
+    normal: Option<FVec>,
+    colour: Option<FVec>,
+    uv: Option<(Float, Float)>,
+}
+
+/*
+Triangles of the faces in an OBJ file, with polygons split into fans. Faces
+whose corners all name a normal get per-vertex normals, those whose corners
+all name texture coordinates get them too, and those whose vertices all have
+colours, written after the position as "v x y z r g b", get vertex colours.
+Of the materials in the MTL files it names, relative to dir, only how much
+they let through is kept; groups are ignored.
+ */
+pub(crate) fn parse_obj(text: &str, dir: &Path) -> Result<Vec<Triangle>, String> {
+    // Positions, each with the colour written after it if any
+    let mut positions: Vec<(FVec, Option<FVec>)> = Vec::new();
+    let mut normals = Vec::new();
+    let mut uvs = Vec::new();
+    let mut triangles = Vec::new();
+    let mut materials: HashMap<String, Option<Dissolve>> = HashMap::new();
+    let mut dissolve = None;
+    for (index, line) in text.lines().enumerate() {
+        let mut words = line.split_whitespace();
+        let result = match words.next() {
+            // The name can contain spaces, so it is the rest of the line
+            Some("mtllib") => {
+                let path = dir.join(line.trim_start()["mtllib".len()..].trim());
+                match std::fs::read_to_string(&path) {
+                    Ok(text) => parse_mtl(&text)
+                        .map(|parsed| materials.extend(parsed))
+                        .map_err(|error| format!("{}: {}", path.display(), error)),
+                    // Models are often shared without their materials, which only matter here
+                    Err(error) => {
+                        warn!("{}: {}", path.display(), error);
+                        Ok(())
+                    }
+                }
+            }
+            Some("usemtl") => {
+                let name = line.trim_start()["usemtl".len()..].trim();
+                dissolve = materials.get(name).copied().flatten();
+                Ok(())
+            }
+            Some("v") => {
+                let numbers: Vec<&str> = words.collect();
+                parse_vector(numbers.iter().copied()).and_then(|position| {
+                    let colour = match numbers.get(3..) {
+                        Some(rest) if rest.len() >= 3 => Some(parse_vector(rest.iter().copied())?),
+                        _ => None,
+                    };
+                    positions.push((position, colour));
+                    Ok(())
+                })
+            }
+            Some("vn") => parse_vector(words).map(|n| normals.push(n)),
+            Some("vt") => parse_uv(words).map(|uv| uvs.push(uv)),
+            Some("f") => {
+                // Corners are written position/texture/normal, with the last two optional
+                let corners = words
+                    .map(|corner| {
+                        let mut indices = corner.split('/');
+                        let (position, colour) =
+                            lookup(&positions, indices.next().unwrap_or_default())?;
+                        let uv = match indices.next().filter(|index| !index.is_empty()) {
+                            Some(index) => Some(lookup(&uvs, index)?),
+                            None => None,
+                        };
+                        let normal = match indices.next().filter(|index| !index.is_empty()) {
+                            Some(index) => Some(lookup(&normals, index)?),
+                            None => None,
+                        };
+                        Ok(Corner {
+                            position,
+                            normal,
+                            colour,
+                            uv,
+                        })
+                    })
+                    .collect::<Result<Vec<Corner>, String>>();
+                corners.and_then(|corners| {
+                    if corners.len() < 3 {
+                        return Err("a face needs at least three corners".to_string());
+                    }
+                    for i in 1..corners.len() - 1 {
+                        let fan = [corners[0], corners[i], corners[i + 1]];
+                        triangles.push(Triangle {
+                            vertices: fan.map(|corner| corner.position),
+                            normals: all_corners(fan.map(|corner| corner.normal)),
+                            colours: all_corners(fan.map(|corner| corner.colour)),
+                            uvs: all_corners(fan.map(|corner| corner.uv)),
+                            dissolve,
+                        });
+                    }
+                    Ok(())
+                })
+            }
+            _ => Ok(()),
+        };
+        result.map_err(|error| format!("line {}: {}", index + 1, error))?;
+    }
+    Ok(triangles)
+}
+
+/*
+How much each material in an MTL file lets through, by name: none for
+materials with a dissolve ("d") of 1 or a transparency ("Tr") of 0, the
+default. What shows through is tinted by the transmission filter ("Tf").
+ */
+fn parse_mtl(text: &str) -> Result<HashMap<String, Option<Dissolve>>, String> {
+    let mut materials = HashMap::new();
+    // Name, opacity and filter of the material being read
+    let mut current: Option<(String, Float, FVec)> = None;
+    let mut finish = |current: Option<(String, Float, FVec)>| {
+        if let Some((name, opacity, filter)) = current {
+            let dissolve = (opacity < 1.0).then_some(Dissolve { opacity, filter });
+            materials.insert(name, dissolve);
+        }
+    };
+    let number = |word: Option<&str>| -> Result<Float, String> {
+        let word = word.ok_or("expected a number")?;
+        word.parse().map_err(|_| format!("invalid number {word:?}"))
+    };
+    for (index, line) in text.lines().enumerate() {
+        let mut words = line.split_whitespace();
+        let result = match (words.next(), &mut current) {
+            (Some("newmtl"), _) => {
+                let name = line.trim_start()["newmtl".len()..].trim().to_string();
+                finish(current.replace((name, 1.0, FVec::repeat(1.0))));
+                Ok(())
+            }
+            // "-halo" makes the surface more opaque side on, which is not modelled
+            (Some("d"), Some((_, opacity, _))) => {
+                number(words.find(|word| *word != "-halo")).map(|d| *opacity = clamp(d, 0.0, 1.0))
+            }
+            (Some("Tr"), Some((_, opacity, _))) => {
+                number(words.next()).map(|tr| *opacity = 1.0 - clamp(tr, 0.0, 1.0))
+            }
+            // A single number is grey; filters given as spectra or in CIE XYZ are ignored
+            (Some("Tf"), Some((_, _, filter))) => match words.next() {
+                Some("spectral" | "xyz") => Ok(()),
+                first => {
+                    let r = number(first)?;
+                    let mut rest = || words.next().map_or(Ok(r), |word| number(Some(word)));
+                    let (g, b) = (rest()?, rest()?);
+                    *filter = FVec::new(r, g, b).map(|c| clamp(c, 0.0, 1.0));
+                    Ok(())
+                }
+            },
+            _ => Ok(()),
+        };
+        result.map_err(|error| format!("line {}: {}", index + 1, error))?;
+    }
+    finish(current);
+    Ok(materials)
+}
@@ -0,0 +1,364 @@
+use crate::core::consts::PI;
+use crate::core::ray::{Intersection, Ray};
+use crate::core::shading::{reflect, refract, schlick_colour};
+use crate::light::{coordinate_system, sample_cone, LightSample, LightSource};
+use crate::logging::StageTimer;
+use crate::media::MediumStack;
+use crate::sampling::{concentric_disc, Rng};
+use crate::{FVec, Float, Scene};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::mem::size_of;
+
+// Streams of the random numbers of photons, apart from those of pixels and their bounces
+const PHOTON_STREAMS: u64 = 1 << 32;
+
+/*
+Light focused onto diffuse surfaces by mirrors and glass, which neither
+integrator finds as their rays cannot reach the lights through them. Before
+rendering, photons are sent from every light towards each object that
+reflects or refracts, followed through the mirror reflections and
+refractions they meet, and kept where they land on diffuse surfaces after at
+least one. Shading adds the light of the photons around each point (Jensen
+1996). Rough metals focus photons as if they were polished.
+ */
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Caustics {
+    // Photons sent from each light towards each object that reflects or refracts
+    #[serde(default = "default_photons")]
+    pub(crate) photons: u32,
+    // Distance photons are gathered from around a point; smaller is sharper but noisier
+    #[serde(default = "default_radius")]
+    pub(crate) radius: Float,
+}
+
+fn default_photons() -> u32 {
+    100_000
+}
+
+fn default_radius() -> Float {
+    0.05
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Photon {
+    pos: FVec,
+    // Of the side of the surface the photon landed on
+    normal: FVec,
+    // Light the photon carries, in the units of a light's intensity times solid angle
+    power: FVec,
+}
+
+/*
+Photons in a kd-tree laid out in place: the middle photon of every range
+splits the others of the range, those before it lying below it on the axis
+in `axes` and those after it above.
+ */
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PhotonMap {
+    photons: Vec<Photon>,
+    axes: Vec<u8>,
+    radius: Float,
+}
+
+impl PhotonMap {
+    fn build(mut photons: Vec<Photon>, radius: Float) -> PhotonMap {
+        let mut axes = vec![0; photons.len()];
+        split(&mut photons, &mut axes);
+        PhotonMap {
+            photons,
+            axes,
+            radius,
+        }
+    }
+
+    pub(crate) fn bytes(&self) -> usize {
+        self.photons.len() * size_of::<Photon>() + self.axes.len()
+    }
+
+    /*
+    Light per square metre brought by the photons within the radius of a
+    point and landing on the side of the surface the normal faces, nearer
+    photons counting for more (a cone filter) to keep the caustics sharp.
+     */
+    pub(crate) fn irradiance(&self, pos: &FVec, normal: &FVec) -> FVec {
+        let mut total = FVec::zeros();
+        let radius = self.radius;
+        gather(
+            &self.photons,
+            &self.axes,
+            pos,
+            radius * radius,
+            &mut |photon| {
+                if photon.normal.dot(normal) > 0.0 {
+                    total += photon.power * (1.0 - (photon.pos - pos).norm() / radius);
+                }
+            },
+        );
+        // The cone's weights average a third over the disc
+        total * 3.0 / (PI * radius * radius)
+    }
+}
+
+// Order a range of photons into a kd-tree, splitting each part on its widest axis
+fn split(photons: &mut [Photon], axes: &mut [u8]) {
+    if photons.len() <= 1 {
+        return;
+    }
+    let (min, max) = photons.iter().fold(
+        (
+            FVec::repeat(Float::INFINITY),
+            FVec::repeat(Float::NEG_INFINITY),
+        ),
+        |(min, max), photon| (min.inf(&photon.pos), max.sup(&photon.pos)),
+    );
+    let axis = (max - min).imax();
+    let middle = photons.len() / 2;
+    photons.select_nth_unstable_by(middle, |a, b| {
+        a.pos[axis]
+            .partial_cmp(&b.pos[axis])
+            .unwrap_or(Ordering::Equal)
+    });
+    axes[middle] = axis as u8;
+    let (below, rest) = photons.split_at_mut(middle);
+    let (below_axes, rest_axes) = axes.split_at_mut(middle);
+    split(below, below_axes);
+    split(&mut rest[1..], &mut rest_axes[1..]);
+}
+
+// Visit the photons of a range of the tree within the squared distance of a point
+fn gather(
+    photons: &[Photon],
+    axes: &[u8],
+    pos: &FVec,
+    distance_squared: Float,
+    visit: &mut impl FnMut(&Photon),
+) {
+    if photons.is_empty() {
+        return;
+    }
+    let middle = photons.len() / 2;
+    let photon = &photons[middle];
+    if (photon.pos - pos).norm_squared() <= distance_squared {
+        visit(photon);
+    }
+    let axis = axes[middle] as usize;
+    let offset = pos[axis] - photon.pos[axis];
+    let below = (&photons[..middle], &axes[..middle]);
+    let above = (&photons[middle + 1..], &axes[middle + 1..]);
+    let (near, far) = if offset < 0.0 {
+        (below, above)
+    } else {
+        (above, below)
+    };
+    gather(near.0, near.1, pos, distance_squared, visit);
+    if offset * offset <= distance_squared {
+        gather(far.0, far.1, pos, distance_squared, visit);
+    }
+}
+
+impl Scene {
+    /*
+    The map of the scene's caustics, if it has them. Photons are aimed at
+    the sphere around each bounded object that reflects or refracts, so none
+    are wasted on the rest of the scene.
+     */
+    pub(crate) fn trace_photons(&self) -> Option<PhotonMap> {
+        let caustics = self.caustics.as_ref()?;
+        let _timer = StageTimer::start("Tracing caustic photons");
+        let targets: Vec<(FVec, Float)> = self
+            .objects
+            .iter()
+            .filter(|object| !object.degenerate && !object.hidden)
+            .filter(|object| {
+                let material = &object.material;
+                material.k_reflect > 0.0 || material.k_transmit > 0.0 || material.metallic.is_some()
+            })
+            .filter_map(|object| {
+                let bounds = object.bounding_box()?;
+                Some((
+                    0.5 * (bounds.min + bounds.max),
+                    0.5 * (bounds.max - bounds.min).norm(),
+                ))
+            })
+            .collect();
+        let pairs: Vec<_> = self
+            .lights
+            .iter()
+            .flat_map(|light| targets.iter().map(move |target| (light, target)))
+            .collect();
+        let trace_pair =
+            |(index, (light, (centre, radius))): (usize, &(&LightSource, &(FVec, Float)))| {
+                let mut rng = Rng::new(self.seed, PHOTON_STREAMS + index as u64);
+                let mut landed = Vec::new();
+                for _ in 0..caustics.photons {
+                    let u = [rng.next_float(), rng.next_float(), rng.next_float()];
+                    let (u1, u2) = (rng.next_float(), rng.next_float());
+                    if let Some((ray, power)) =
+                        self._emit_photon(light, centre, *radius, u, (u1, u2))
+                    {
+                        let power = power / caustics.photons as Float;
+                        self._follow_photon(light, ray, power, &mut rng, &mut landed);
+                    }
+                }
+                landed
+            };
+        #[cfg(feature = "parallel")]
+        let photons: Vec<Photon> = pairs
+            .par_iter()
+            .enumerate()
+            .flat_map_iter(trace_pair)
+            .collect();
+        #[cfg(not(feature = "parallel"))]
+        let photons: Vec<Photon> = pairs.iter().enumerate().flat_map(trace_pair).collect();
+        debug!("{} caustic photons landed", photons.len());
+        Some(PhotonMap::build(photons, caustics.radius))
+    }
+
+    /*
+    A photon leaving the light towards the sphere, with the light it carries
+    for the whole of the light's share of the sphere.
+     */
+    fn _emit_photon(
+        &self,
+        light: &LightSource,
+        centre: &FVec,
+        radius: Float,
+        u: [Float; 3],
+        (u1, u2): (Float, Float),
+    ) -> Option<(Ray, FVec)> {
+        let power = light.intensity * light.colour;
+        match light.sample(centre, u) {
+            LightSample::Point(origin) => {
+                let to_centre = centre - origin;
+                let distance = to_centre.norm();
+                let cos_theta_max = if distance > radius {
+                    (1.0 - (radius / distance).powi(2)).max(0.0).sqrt()
+                } else {
+                    -1.0
+                };
+                let w = to_centre.try_normalize(0.0)?;
+                let direction = sample_cone(&w, cos_theta_max, u1, u2);
+                let solid_angle = 2.0 * PI * (1.0 - cos_theta_max);
+                let ray = Ray {
+                    origin,
+                    direction,
+                    differential: None,
+                    time: 0.0,
+                };
+                Some((
+                    ray,
+                    solid_angle * power.component_mul(&light.filter(&direction)),
+                ))
+            }
+            LightSample::Distant(towards) => {
+                // From a disc as wide as the sphere, facing the light just outside it
+                let (s, t) = coordinate_system(&towards);
+                let (x, y) = concentric_disc(u1, u2);
+                let origin = centre + radius * (x * s + y * t + towards);
+                let to_light = Ray {
+                    origin,
+                    direction: towards,
+                    differential: None,
+                    time: 0.0,
+                };
+                if self._is_occluded(&to_light, Float::INFINITY) {
+                    return None;
+                }
+                let ray = Ray {
+                    direction: -towards,
+                    ..to_light
+                };
+                Some((ray, PI * radius * radius * power))
+            }
+        }
+    }
+
+    /*
+    Follow a photon through the mirror reflections and refractions it meets,
+    picking one of them at random in proportion to its weight, and keep it
+    wherever it lands on a diffuse surface after at least one.
+     */
+    fn _follow_photon(
+        &self,
+        light: &LightSource,
+        mut ray: Ray,
+        mut power: FVec,
+        rng: &mut Rng,
+        landed: &mut Vec<Photon>,
+    ) {
+        let mut media = MediumStack::default();
+        let mut focused = false;
+        for bounce in 0..=self.max_bounces {
+            let stops = |object: usize, hit: &Intersection| self._stops_ray(&ray, object, hit);
+            let Some((object, hit)) = self.primitives.nearest(&ray, 0.0, None, stops) else {
+                return;
+            };
+            let material = &*self._get_material(object, &hit);
+            let direction = ray.direction;
+            power = power.component_mul(&(-media.absorption() * hit.t).map(Float::exp));
+            if bounce == 0 && !light.is_distant() {
+                // The photon's share already falls off as the inverse square
+                power *= light.attenuation(hit.t) * hit.t * hit.t;
+            }
+            if material.k_transmit > 0.0 && !media.is_interface(object, material.priority) {
+                media = media.crossed(object, material);
+                ray.origin = hit.offset_origin(&direction, self.ray_bias);
+                continue;
+            }
+            let cos_theta = direction.dot(&hit.normal);
+            let facing = if cos_theta > 0.0 {
+                -hit.normal
+            } else {
+                hit.normal
+            };
+            if focused && material.diffuse_weight() > 0.0 && light.lights_object(object) {
+                landed.push(Photon {
+                    pos: hit.pos,
+                    normal: facing,
+                    power,
+                });
+            }
+            let reflected = match material.metallic {
+                Some(_) => {
+                    let uv = self.objects[object].texture_uv(&hit);
+                    let albedo = self._get_albedo(object, &hit, material, uv);
+                    schlick_colour(cos_theta.abs(), &material.specular_colour(&albedo))
+                }
+                None => FVec::repeat(material.reflectance(cos_theta)),
+            };
+            let transmitted = if material.k_transmit > 0.0 {
+                material.transmittance(cos_theta)
+            } else {
+                0.0
+            };
+            // Chances of each path, scaled down when the weights add up to more than one
+            let reflect_weight = reflected.mean();
+            let scale = (reflect_weight + transmitted).max(1.0);
+            let choice = rng.next_float() * scale;
+            let reflection = reflect(&direction, &hit.normal);
+            ray.direction = if choice < reflect_weight {
+                power = power.component_mul(&reflected) * (scale / reflect_weight);
+                reflection
+            } else if choice < reflect_weight + transmitted {
+                let tint = self._get_thin_wall_tint(object, &hit, material);
+                power = power.component_mul(&tint) * scale;
+                let beyond = media.crossed(object, material);
+                match refract(&direction, &facing, media.ior() / beyond.ior()) {
+                    Some(refracted) => {
+                        media = beyond;
+                        refracted
+                    }
+                    None => reflection,
+                }
+            } else {
+                return;
+            };
+            ray.origin = hit.offset_origin(&ray.direction, self.ray_bias);
+            focused = true;
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use crate::media::MediumStack;
+use crate::{FVec, Float, Scene};
+use nalgebra::Matrix4;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+pub use crate::bounds::Aabb;
+pub use crate::core::ray::{Intersection, Ray};
+pub use crate::sampling::Rng;
+
+/*
+Shapes, textures and integrators added to the renderer by the program using
+it. Each is registered under a name before scenes are loaded, and scenes use
+it by that name like a built-in one:
+
+    plugin::register_shape("torus", |params| Ok(Box::new(Torus::new(params)?)));
+
+    {"type": "torus", "majorRadius": 2, ...}            as an object's shape
+    {"plugin": "marble", "scale": 0.5}                  as a material's colour
+    "integrator": "ambientOcclusion"                    as the scene's integrator
+
+The other fields of a shape or texture are handed to its factory as they are
+written. Plugins are compiled into the program, since Rust has no stable ABI
+to load them from shared libraries. Built-in names cannot be replaced.
+ */
+
+// A surface defined by the program; coordinates are in metres once the scene is loaded
+pub trait ShapePlugin: Send + Sync + fmt::Debug {
+    // Nearest hit along the ray no closer than min_distance, with the normal facing outwards
+    fn intersect(&self, ray: &Ray, min_distance: Float) -> Option<Intersection>;
+
+    // Box enclosing the shape, or None if it is unbounded and must be tested against every ray
+    fn bounding_box(&self) -> Option<Aabb>;
+
+    // Texture coordinates of a point on the shape, with v running down an image
+    fn uv(&self, pos: &FVec) -> (Float, Float) {
+        (pos.x, pos.y)
+    }
+
+    // A point on the shape relative to the shape itself, for solid textures
+    fn object_position(&self, pos: &FVec) -> FVec {
+        *pos
+    }
+
+    // The shape with every length multiplied by the factor, for the scene units
+    fn scaled(&self, factor: Float) -> Box<dyn ShapePlugin>;
+
+    // The shape moved by an object's transform
+    fn transformed(&self, _matrix: &Matrix4<Float>) -> Result<Box<dyn ShapePlugin>, String> {
+        Err("this shape cannot be transformed".to_string())
+    }
+}
+
+// A surface colour defined by the program
+pub trait TexturePlugin: Send + Sync + fmt::Debug {
+    fn colour(&self, uv: (Float, Float)) -> FVec;
+}
+
+/*
+A way of gathering light defined by the program. It is given each primary
+ray and the object it hits first, if any, and returns the linear colour of
+that sample; the public methods of Scene trace further rays.
+ */
+pub trait IntegratorPlugin: Send + Sync + fmt::Debug {
+    fn colour(
+        &self,
+        scene: &Scene,
+        ray: &Ray,
+        hit: Option<(usize, &Intersection)>,
+        rng: &mut Rng,
+    ) -> FVec;
+}
+
+// Makes a shape from the fields written in the scene besides its type
+pub type ShapeFactory = fn(&Value) -> Result<Box<dyn ShapePlugin>, String>;
+
+// Makes a texture from the fields written in the scene besides its name
+pub type TextureFactory = fn(&Value) -> Result<Box<dyn TexturePlugin>, String>;
+
+// Names that scenes already use for built-in shapes and integrators
+const BUILTIN_SHAPES: [&str; 10] = [
+    "sphere", "plane", "box", "cylinder", "cone", "disc", "torus", "csg", "mesh", "plugin",
+];
+const BUILTIN_INTEGRATORS: [&str; 2] = ["whitted", "path"];
+
+struct Registry {
+    shapes: BTreeMap<String, ShapeFactory>,
+    textures: BTreeMap<String, TextureFactory>,
+    integrators: BTreeMap<String, Arc<dyn IntegratorPlugin>>,
+}
+
+static REGISTRY: RwLock<Registry> = RwLock::new(Registry {
+    shapes: BTreeMap::new(),
+    textures: BTreeMap::new(),
+    integrators: BTreeMap::new(),
+});
+
+fn registry() -> std::sync::RwLockReadGuard<'static, Registry> {
+    REGISTRY
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn registry_mut() -> std::sync::RwLockWriteGuard<'static, Registry> {
+    REGISTRY
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// Registering a name again replaces the plugin registered before
+pub fn register_shape(name: &str, factory: ShapeFactory) {
+    if BUILTIN_SHAPES.contains(&name) {
+        warn!(
+            "Shape plugin {:?} has the name of a built-in shape; ignored",
+            name
+        );
+        return;
+    }
+    registry_mut().shapes.insert(name.to_string(), factory);
+}
+
+pub fn register_texture(name: &str, factory: TextureFactory) {
+    registry_mut().textures.insert(name.to_string(), factory);
+}
+
+pub fn register_integrator(name: &str, integrator: impl IntegratorPlugin + 'static) {
+    if BUILTIN_INTEGRATORS.contains(&name) {
+        warn!(
+            "Integrator plugin {:?} has the name of a built-in integrator; ignored",
+            name
+        );
+        return;
+    }
+    registry_mut()
+        .integrators
+        .insert(name.to_string(), Arc::new(integrator));
+}
+
+pub(crate) fn is_shape(name: &str) -> bool {
+    registry().shapes.contains_key(name)
+}
+
+pub(crate) fn make_shape(name: &str, params: &Value) -> Result<Arc<dyn ShapePlugin>, String> {
+    let factory = registry()
+        .shapes
+        .get(name)
+        .copied()
+        .ok_or(format!("no shape plugin named {name:?}"))?;
+    factory(params).map(Arc::from)
+}
+
+pub(crate) fn make_texture(name: &str, params: &Value) -> Result<Arc<dyn TexturePlugin>, String> {
+    let factory = registry()
+        .textures
+        .get(name)
+        .copied()
+        .ok_or(format!("no texture plugin named {name:?}"))?;
+    factory(params).map(Arc::from)
+}
+
+pub(crate) fn integrator(name: &str) -> Option<Arc<dyn IntegratorPlugin>> {
+    registry().integrators.get(name).cloned()
+}
+
+/*
+A plugin shape once made by its factory. Copies of an object share it, and
+two are only equal when they are the same shape.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct PluginShape(pub(crate) Option<Arc<dyn ShapePlugin>>);
+
+impl PluginShape {
+    pub(crate) fn get(&self) -> &dyn ShapePlugin {
+        self.0
+            .as_deref()
+            .expect("plugin shapes are made with the scene")
+    }
+}
+
+impl PartialEq for PluginShape {
+    fn eq(&self, other: &PluginShape) -> bool {
+        match (&self.0, &other.0) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
+
+// What integrator plugins can ask of the scene they are shading
+impl Scene {
+    // Nearest object along the ray and where it is hit
+    pub fn nearest_hit(&self, ray: &Ray) -> Option<(usize, Intersection)> {
+        self._get_nearest_hit(ray, 0.0, None)
+    }
+
+    // Whether any object lies along the ray before the given distance
+    pub fn is_occluded(&self, ray: &Ray, distance: Float) -> bool {
+        self._is_occluded(ray, distance)
+    }
+
+    // Colour of a hit as the built-in Whitted integrator shades it, or the background
+    pub fn shade(&self, ray: &Ray, hit: Option<(usize, &Intersection)>, rng: &mut Rng) -> FVec {
+        self._get_hit_colour(ray, hit, &MediumStack::default(), 0, rng)
+    }
+}
@@ -0,0 +1,290 @@
+use crate::mesh::{all_corners, Triangle};
+use crate::{FVec, Float};
+
+#[derive(Debug, Clone, Copy)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Result<Scalar, String> {
+        Ok(match name {
+            "char" | "int8" => Scalar::I8,
+            "uchar" | "uint8" => Scalar::U8,
+            "short" | "int16" => Scalar::I16,
+            "ushort" | "uint16" => Scalar::U16,
+            "int" | "int32" => Scalar::I32,
+            "uint" | "uint32" => Scalar::U32,
+            "float" | "float32" => Scalar::F32,
+            "double" | "float64" => Scalar::F64,
+            _ => return Err(format!("unknown property type {name:?}")),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8,
+        }
+    }
+
+    // Factor taking a colour channel of this type into [0, 1]
+    fn colour_scale(self) -> Float {
+        match self {
+            Scalar::U8 => 1.0 / u8::MAX as Float,
+            Scalar::U16 => 1.0 / u16::MAX as Float,
+            _ => 1.0,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Property {
+    Scalar {
+        name: String,
+        kind: Scalar,
+    },
+    List {
+        name: String,
+        count: Scalar,
+        item: Scalar,
+    },
+}
+
+impl Property {
+    fn name(&self) -> &str {
+        match self {
+            Property::Scalar { name, .. } | Property::List { name, .. } => name,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    LittleEndian,
+    BigEndian,
+}
+
+// The element data after the header, read one number at a time
+struct Body<'a> {
+    format: Format,
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Body<'_> {
+    fn read(&mut self, kind: Scalar) -> Result<Float, String> {
+        if self.format == Format::Ascii {
+            let rest = &self.bytes[self.position..];
+            let start = rest.iter().position(|b| !b.is_ascii_whitespace());
+            let start = start.ok_or("unexpected end of file")?;
+            let length = rest[start..]
+                .iter()
+                .take_while(|b| !b.is_ascii_whitespace())
+                .count();
+            self.position += start + length;
+            let word = String::from_utf8_lossy(&rest[start..start + length]);
+            return word.parse().map_err(|_| format!("invalid number {word:?}"));
+        }
+        let bytes = self
+            .bytes
+            .get(self.position..self.position + kind.size())
+            .ok_or("unexpected end of file")?;
+        self.position += kind.size();
+        let mut buffer = [0; 8];
+        buffer[..bytes.len()].copy_from_slice(bytes);
+        if self.format == Format::BigEndian {
+            buffer[..bytes.len()].reverse();
+        }
+        let [b0, b1, b2, b3, ..] = buffer;
+        Ok(match kind {
+            Scalar::I8 => b0 as i8 as Float,
+            Scalar::U8 => b0 as Float,
+            Scalar::I16 => i16::from_le_bytes([b0, b1]) as Float,
+            Scalar::U16 => u16::from_le_bytes([b0, b1]) as Float,
+            Scalar::I32 => i32::from_le_bytes([b0, b1, b2, b3]) as Float,
+            Scalar::U32 => u32::from_le_bytes([b0, b1, b2, b3]) as Float,
+            Scalar::F32 => f32::from_le_bytes([b0, b1, b2, b3]) as Float,
+            Scalar::F64 => f64::from_le_bytes(buffer) as Float,
+        })
+    }
+}
+
+// Format and elements described by the header, and where the data after it starts
+fn parse_header(bytes: &[u8]) -> Result<(Format, Vec<Element>, usize), String> {
+    const END: &[u8] = b"end_header";
+    let end = bytes
+        .windows(END.len())
+        .position(|window| window == END)
+        .ok_or("missing end_header")?;
+    let body_start = bytes[end..]
+        .iter()
+        .position(|&b| b == b'\n')
+        .map_or(bytes.len(), |newline| end + newline + 1);
+    let header = String::from_utf8_lossy(&bytes[..end]);
+    let mut lines = header.lines();
+    if lines.next().map(str::trim) != Some("ply") {
+        return Err("not a PLY file".to_string());
+    }
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    for line in lines {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["format", name, _] => {
+                format = Some(match *name {
+                    "ascii" => Format::Ascii,
+                    "binary_little_endian" => Format::LittleEndian,
+                    "binary_big_endian" => Format::BigEndian,
+                    _ => return Err(format!("unknown format {name:?}")),
+                })
+            }
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count
+                    .parse()
+                    .map_err(|_| format!("invalid count {count:?}"))?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", count, item, name] => elements
+                .last_mut()
+                .ok_or("property before any element")?
+                .properties
+                .push(Property::List {
+                    name: name.to_string(),
+                    count: Scalar::parse(count)?,
+                    item: Scalar::parse(item)?,
+                }),
+            ["property", kind, name] => elements
+                .last_mut()
+                .ok_or("property before any element")?
+                .properties
+                .push(Property::Scalar {
+                    name: name.to_string(),
+                    kind: Scalar::parse(kind)?,
+                }),
+            _ => {}
+        }
+    }
+    Ok((format.ok_or("missing format")?, elements, body_start))
+}
+
+// Vertex properties that are read, in the order they are stored while parsing
+const SLOTS: [&str; 11] = [
+    "x", "y", "z", "nx", "ny", "nz", "red", "green", "blue", "u", "v",
+];
+
+// Slot of a vertex property, accepting the other names texture coordinates are written under
+fn slot(name: &str) -> Option<usize> {
+    let name = match name {
+        "s" | "texture_u" | "texture_s" => "u",
+        "t" | "texture_v" | "texture_t" => "v",
+        _ => name,
+    };
+    SLOTS.iter().position(|slot| *slot == name)
+}
+
+/*
+Triangles of the faces in a PLY file, ASCII or binary, with polygons split
+into fans. Vertices may carry normals (nx, ny, nz), texture coordinates (u
+and v, or s and t) and colours (red, green, blue), stored as 8- or 16-bit
+integers or as floats between 0 and 1; every corner then gets them. Elements
+other than vertices and faces are skipped.
+ */
+pub fn parse_ply(bytes: &[u8]) -> Result<Vec<Triangle>, String> {
+    let (format, elements, position) = parse_header(bytes)?;
+    let mut body = Body {
+        format,
+        bytes,
+        position,
+    };
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut colours = Vec::new();
+    let mut uvs = Vec::new();
+    let mut triangles = Vec::new();
+    for element in &elements {
+        let has = |name: &str| {
+            element
+                .properties
+                .iter()
+                .any(|p| slot(p.name()) == slot(name))
+        };
+        let has_normals = has("nx") && has("ny") && has("nz");
+        let has_colours = has("red") && has("green") && has("blue");
+        let has_uvs = has("u") && has("v");
+        for index in 0..element.count {
+            let mut values = [0.0; SLOTS.len()];
+            let mut corners = Vec::new();
+            for property in &element.properties {
+                match property {
+                    Property::Scalar { name, kind } => {
+                        let value = body.read(*kind)?;
+                        if let Some(slot) = slot(name) {
+                            let is_colour = (6..9).contains(&slot);
+                            let scale = if is_colour { kind.colour_scale() } else { 1.0 };
+                            values[slot] = value * scale;
+                        }
+                    }
+                    Property::List { name, count, item } => {
+                        let count = body.read(*count)? as usize;
+                        for _ in 0..count {
+                            let value = body.read(*item)?;
+                            if name == "vertex_indices" || name == "vertex_index" {
+                                corners.push(value as usize);
+                            }
+                        }
+                    }
+                }
+            }
+            let [x, y, z, nx, ny, nz, r, g, b, u, v] = values;
+            match element.name.as_str() {
+                "vertex" => {
+                    positions.push(FVec::new(x, y, z));
+                    normals.push(has_normals.then(|| FVec::new(nx, ny, nz)));
+                    colours.push(has_colours.then(|| FVec::new(r, g, b)));
+                    // Files have v running up the image, unlike the renderer
+                    uvs.push(has_uvs.then_some((u, 1.0 - v)));
+                }
+                "face" => {
+                    let fail = |error: &str| format!("face {}: {}", index, error);
+                    if corners.len() < 3 {
+                        return Err(fail("a face needs at least three corners"));
+                    }
+                    if let Some(&corner) = corners.iter().find(|&&c| c >= positions.len()) {
+                        return Err(fail(&format!("index {corner} is out of range")));
+                    }
+                    for i in 1..corners.len() - 1 {
+                        let fan = [corners[0], corners[i], corners[i + 1]];
+                        triangles.push(Triangle {
+                            vertices: fan.map(|c| positions[c]),
+                            normals: all_corners(fan.map(|c| normals[c])),
+                            colours: all_corners(fan.map(|c| colours[c])),
+                            uvs: all_corners(fan.map(|c| uvs[c])),
+                            dissolve: None,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(triangles)
+}
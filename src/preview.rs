@@ -0,0 +1,408 @@
+#[cfg(feature = "window")]
+use crate::camera::Camera;
+use crate::gbuffer::{crop_overscan, GBuffer};
+use crate::region::Region;
+use crate::Scene;
+#[cfg(feature = "window")]
+use crate::{FVec, Float};
+use image::imageops::{self, FilterType};
+use image::{ImageBuffer, ImageError, Rgb, RgbImage};
+use serde_json::Value;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// How often the preview window looks for input once its render has all its samples
+#[cfg(feature = "window")]
+const FRAME_INTERVAL: Duration = Duration::from_millis(16);
+// Metres per second the camera of the preview window moves, and with shift held
+#[cfg(feature = "window")]
+const MOVE_SPEED: Float = 2.0;
+#[cfg(feature = "window")]
+const FAST_MOVE_SPEED: Float = 10.0;
+// Longest time a key is taken to have been held between looks, so a slow pass cannot fling it
+#[cfg(feature = "window")]
+const MAX_STEP_SECONDS: f64 = 0.25;
+// Radians the camera of the preview window turns per pixel the mouse is dragged
+#[cfg(feature = "window")]
+const TURN_PER_PIXEL: Float = 0.005;
+
+// How many times coarser than the frame each preview pass is, coarsest first
+const PASS_FACTORS: [u32; 3] = [8, 4, 2];
+
+/*
+Coarse passes written to the output before a full render, so a large frame
+gives usable feedback within a second. Each pass has half the pixel size of
+the one before and one sample per pixel.
+ */
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Refinement {
+    // Frame pixel whose surroundings are shown at full resolution from the first pass on
+    pub focus: Option<(u32, u32)>,
+}
+
+impl Refinement {
+    /*
+    Write each preview pass to the output in turn. The area around the focus
+    point is rendered at full resolution once, after the first pass, and
+    pasted over every later one.
+     */
+    pub fn write_passes(&self, scene: &Scene, output: &str) -> Result<(), ImageError> {
+        let camera = &scene.camera;
+        let (columns, rows) = (camera.screen_columns, camera.screen_rows);
+        let mut detail: Option<(Region, RgbImage)> = None;
+        for factor in PASS_FACTORS.into_iter().filter(|&f| f < columns.max(rows)) {
+            let start = Instant::now();
+            let mut coarse = camera.clone();
+            coarse.screen_columns = (columns / factor).max(1);
+            coarse.screen_rows = (rows / factor).max(1);
+            coarse.overscan = 0;
+            coarse.samples = 1;
+            coarse.prepare();
+            let image = scene.render(&coarse);
+            let mut image = imageops::resize(&image, columns, rows, FilterType::Nearest);
+            if let Some((area, detail)) = &detail {
+                imageops::replace(&mut image, detail, area.x as i64, area.y as i64);
+            }
+            image.save(output)?;
+            info!(
+                "Preview at 1/{} resolution in {:?}",
+                factor,
+                start.elapsed()
+            );
+            // The first pass is written before the focus area to show something straight away
+            if let (Some((x, y)), None) = (self.focus, &detail) {
+                let (area, focused) = Refinement::render_focus(scene, x, y);
+                imageops::replace(&mut image, &focused, area.x as i64, area.y as i64);
+                image.save(output)?;
+                info!("Preview around the focus point in {:?}", start.elapsed());
+                detail = Some((area, focused));
+            }
+        }
+        Ok(())
+    }
+
+    // A quarter of the frame's width and height around the point, rendered in full
+    fn render_focus(scene: &Scene, x: u32, y: u32) -> (Region, RgbImage) {
+        let camera = &scene.camera;
+        let (columns, rows) = (camera.screen_columns, camera.screen_rows);
+        let (width, height) = ((columns / 4).max(1), (rows / 4).max(1));
+        let area = Region::new(
+            x.min(columns - 1)
+                .saturating_sub(width / 2)
+                .min(columns - width),
+            y.min(rows - 1)
+                .saturating_sub(height / 2)
+                .min(rows - height),
+            width,
+            height,
+        );
+        let film_area = Region::new(
+            area.x + camera.overscan,
+            area.y + camera.overscan,
+            width,
+            height,
+        );
+        (area, scene.render_region(camera, &film_area))
+    }
+}
+
+/*
+Show a render refining in place: the output is rewritten after every pass
+that doubles the samples of its pixels, for an image viewer that reloads it,
+and the render starts over as soon as the scene file changes, such as when
+its camera is moved.
+ */
+pub fn progressive(path: &str, output: &str) -> Result<(), Box<dyn Error>> {
+    let mut last_modified: Option<SystemTime> = None;
+    loop {
+        let modified = fs::metadata(path)?.modified()?;
+        if last_modified == Some(modified) {
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+        last_modified = Some(modified);
+        let scene = match Scene::from_file(path) {
+            Ok(scene) => scene,
+            Err(error) => {
+                error!("Could not load {}: {}", path, error);
+                continue;
+            }
+        };
+        let start = Instant::now();
+        let camera = &scene.camera;
+        let mut written = Ok(());
+        scene.render_progressive(camera, |image, samples| {
+            written = scene._write_rgb(camera, image, output);
+            info!("{} samples per pixel in {:?}", samples, start.elapsed());
+            // Stop refining a scene that has since changed
+            let unchanged = fs::metadata(path).and_then(|m| m.modified()).ok() == last_modified;
+            written.is_ok() && unchanged
+        });
+        written?;
+    }
+}
+
+/*
+Show a render refining in a window, starting over as soon as the scene file
+changes or the camera is moved. W, A, S and D move the camera forwards, left,
+back and right, Q and E down and up, faster with shift held, and dragging
+with the left mouse button turns it. Escape or closing the window ends the
+preview; the moved camera is never written back to the scene.
+ */
+#[cfg(feature = "window")]
+pub fn window(path: &str) -> Result<(), Box<dyn Error>> {
+    use minifb::{Key, MouseButton, MouseMode, Window, WindowOptions};
+
+    let modified = || fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut scene = Scene::from_file(path)?;
+    let mut last_modified = modified();
+    let (columns, rows) = (scene.camera.screen_columns, scene.camera.screen_rows);
+    let title = format!("raycaster {}", path);
+    let options = WindowOptions::default();
+    let mut window = Window::new(&title, columns as usize, rows as usize, options)?;
+    let mut camera = scene.camera.clone();
+    let mut steering = Steering::default();
+    // Reads the keys and mouse, turning and moving the camera; true if it moved
+    let mut steer = |window: &Window, camera: &mut Camera| {
+        let pressed = |keys: &[Key]| keys.iter().any(|&key| window.is_key_down(key));
+        let speed = if pressed(&[Key::LeftShift, Key::RightShift]) {
+            FAST_MOVE_SPEED
+        } else {
+            MOVE_SPEED
+        };
+        let held = [Key::W, Key::S, Key::D, Key::A, Key::E, Key::Q].map(|key| pressed(&[key]));
+        let axis = |plus: bool, minus: bool| plus as i32 as Float - minus as i32 as Float;
+        let step = FVec::new(
+            axis(held[0], held[1]),
+            axis(held[2], held[3]),
+            axis(held[4], held[5]),
+        );
+        let mouse = window.get_mouse_pos(MouseMode::Pass);
+        let dragging = window.get_mouse_down(MouseButton::Left);
+        steering.steer(camera, step * speed, mouse.filter(|_| dragging))
+    };
+    while window.is_open() && !window.is_key_down(Key::Escape) {
+        let start = Instant::now();
+        let mut restart = false;
+        scene.render_progressive(&camera.clone(), |image, samples| {
+            let image = crop_overscan(scene._encode_image(image), camera.overscan);
+            let buffer: Vec<u32> = image
+                .pixels()
+                .map(|Rgb([r, g, b])| u32::from_be_bytes([0, *r, *g, *b]))
+                .collect();
+            let (width, height) = (image.width() as usize, image.height() as usize);
+            let shown = window.update_with_buffer(&buffer, width, height);
+            info!("{} samples per pixel in {:?}", samples, start.elapsed());
+            restart = steer(&window, &mut camera) || modified() != last_modified;
+            shown.is_ok() && window.is_open() && !restart
+        });
+        // Wait at the full samples for the camera to move or the scene to change
+        while !restart && window.is_open() && !window.is_key_down(Key::Escape) {
+            thread::sleep(FRAME_INTERVAL);
+            window.update();
+            restart = steer(&window, &mut camera) || modified() != last_modified;
+        }
+        if modified() != last_modified {
+            last_modified = modified();
+            match Scene::from_file(path) {
+                Ok(edited) => {
+                    scene = edited;
+                    camera = scene.camera.clone();
+                }
+                Err(error) => error!("Could not load {}: {}", path, error),
+            }
+        }
+    }
+    Ok(())
+}
+
+/*
+The state of a camera being moved by keyboard and mouse in the preview
+window: when it last moved, so keys move it by their speed times the time
+held, and where a mouse drag last was.
+ */
+#[cfg(feature = "window")]
+#[derive(Default)]
+struct Steering {
+    last_step: Option<Instant>,
+    drag: Option<(f32, f32)>,
+}
+
+#[cfg(feature = "window")]
+impl Steering {
+    /*
+    Move the camera along its view, right and up directions by the velocity
+    in metres per second, and turn it by how far the mouse has been dragged
+    since it was last seen, returning whether the camera changed.
+     */
+    fn steer(&mut self, camera: &mut Camera, velocity: FVec, drag: Option<(f32, f32)>) -> bool {
+        let now = Instant::now();
+        let elapsed = self
+            .last_step
+            .map_or(0.0, |last| now.duration_since(last).as_secs_f64());
+        self.last_step = Some(now);
+        let (forward, right, up) = camera.get_basis_vectors();
+        let mut moved = false;
+        if velocity != FVec::zeros() {
+            let step = velocity * elapsed.min(MAX_STEP_SECONDS) as Float;
+            camera.position += forward * step.x + right * step.y + up * step.z;
+            moved = true;
+        }
+        let turn = drag
+            .zip(self.drag)
+            .map(|(now, last)| (now.0 - last.0, now.1 - last.1));
+        self.drag = drag;
+        if let Some((dx, dy)) = turn.filter(|&turn| turn != (0.0, 0.0)) {
+            let (yaw, pitch) = (dx as Float * TURN_PER_PIXEL, dy as Float * TURN_PER_PIXEL);
+            let turned = forward * yaw.cos() + right * yaw.sin();
+            camera.direction = turned * pitch.cos() - up * pitch.sin();
+            moved = true;
+        }
+        if moved {
+            camera.look_at = None;
+            camera.prepare();
+        }
+        moved
+    }
+}
+
+struct PreviewState {
+    value: Value,
+    gbuffer: GBuffer,
+    image: ImageBuffer<Rgb<u8>, Vec<u8>>,
+}
+
+// Scene keys that only affect shading, so edits to them can reuse the first hits
+const LIGHTING_KEYS: [&str; 4] = [
+    "lights",
+    "ambientLight",
+    "ambientFromEnvironment",
+    "defaultColour",
+];
+
+// Which pixels of the previous render an edit can have changed
+enum Reshade {
+    // Pixels showing any of the flagged objects
+    Objects(Vec<bool>),
+    Everything,
+}
+
+/*
+Re-render the scene every time its file changes. When an edit only touches
+object materials or lighting, the first hits from the previous render are
+re-shaded in place instead of tracing primary rays again. Material edits only
+update pixels that can show the change; lighting edits re-shade every pixel.
+Full renders are preceded by coarse passes when refinement is enabled.
+ */
+pub fn watch(
+    path: &str,
+    output: &str,
+    refinement: Option<Refinement>,
+) -> Result<(), Box<dyn Error>> {
+    let mut last_modified: Option<SystemTime> = None;
+    let mut state: Option<PreviewState> = None;
+    loop {
+        let modified = fs::metadata(path)?.modified()?;
+        if last_modified != Some(modified) {
+            last_modified = Some(modified);
+            match update(path, output, refinement, state.take()) {
+                Ok(new_state) => {
+                    crop_overscan(new_state.image.clone(), new_state.gbuffer.overscan)
+                        .save(output)?;
+                    state = Some(new_state);
+                }
+                Err(error) => error!("Could not render {}: {}", path, error),
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn update(
+    path: &str,
+    output: &str,
+    refinement: Option<Refinement>,
+    previous: Option<PreviewState>,
+) -> Result<PreviewState, Box<dyn Error>> {
+    let start = Instant::now();
+    let value = Scene::read_value(path)?;
+    let base_dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    let scene = Scene::from_value(value.clone(), base_dir)?;
+
+    let changed = previous
+        .as_ref()
+        .and_then(|p| reshade_scope(&p.value, &value));
+    let state = match (previous, changed) {
+        (Some(mut previous), Some(Reshade::Everything)) => {
+            scene.shade_gbuffer(&previous.gbuffer, &mut previous.image, |_| true);
+            info!("Re-shaded lighting change in {:?}", start.elapsed());
+            PreviewState { value, ..previous }
+        }
+        (Some(mut previous), Some(Reshade::Objects(changed))) => {
+            // Reflective surfaces may show a changed object anywhere, so always re-shade them
+            scene.shade_gbuffer(&previous.gbuffer, &mut previous.image, |pixel| {
+                pixel.hits_any(|object| {
+                    let material = &scene.objects[object].material;
+                    changed[object] || material.k_reflect > 0.0 || material.clearcoat > 0.0
+                })
+            });
+            info!("Re-shaded material change in {:?}", start.elapsed());
+            PreviewState { value, ..previous }
+        }
+        _ => {
+            if let Some(refinement) = refinement {
+                refinement.write_passes(&scene, output)?;
+            }
+            let gbuffer = scene.trace_gbuffer(&scene.camera);
+            let mut image = ImageBuffer::new(gbuffer.width, gbuffer.height);
+            scene.shade_gbuffer(&gbuffer, &mut image, |_| true);
+            info!("Rendered in {:?}", start.elapsed());
+            PreviewState {
+                value,
+                gbuffer,
+                image,
+            }
+        }
+    };
+    Ok(state)
+}
+
+/*
+If the two scene descriptions differ only in ways that can be re-shaded from
+the previous first hits, return which pixels need updating; otherwise None.
+ */
+fn reshade_scope(old: &Value, new: &Value) -> Option<Reshade> {
+    let strip = |scene: &Value| {
+        let mut scene = scene.clone();
+        if let Some(objects) = scene.get_mut("objects").and_then(Value::as_array_mut) {
+            for object in objects.iter_mut().filter_map(Value::as_object_mut) {
+                object.remove("material");
+            }
+        }
+        if let Some(scene) = scene.as_object_mut() {
+            for key in LIGHTING_KEYS {
+                scene.remove(key);
+            }
+        }
+        scene
+    };
+    if strip(old) != strip(new) {
+        return None;
+    }
+    if LIGHTING_KEYS.iter().any(|key| old.get(key) != new.get(key)) {
+        return Some(Reshade::Everything);
+    }
+    let objects = |scene: &Value| scene["objects"].as_array().cloned().unwrap_or_default();
+    Some(Reshade::Objects(
+        objects(old)
+            .iter()
+            .zip(objects(new).iter())
+            .map(|(a, b)| a["material"] != b["material"])
+            .collect(),
+    ))
+}
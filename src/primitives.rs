@@ -0,0 +1,678 @@
+use crate::bounds::Aabb;
+use crate::bvh::Bvh;
+use crate::core::intersect::{
+    intersect_box, intersect_cone, intersect_cylinder, intersect_disc, intersect_plane,
+    intersect_sphere, intersect_torus, intersect_triangle,
+};
+use crate::core::ray::{gamma, Dissolve, Intersection, Ray};
+use crate::csg::intersect_csg;
+use crate::instances::transformed_box;
+use crate::plugin::ShapePlugin;
+use crate::stats::{self, Counter};
+use crate::transform::{normal_matrix, transform_point};
+use crate::{FVec, Float, SceneObject, Shape};
+use nalgebra::Matrix4;
+use std::sync::Arc;
+
+/*
+Scene geometry laid out as one structure of arrays per primitive type, so the
+intersection loop runs over contiguous data of a single kind instead of
+branching on every object's shape. Each primitive keeps the index of the
+scene object it came from. Spheres and triangles are found through a
+bounding volume hierarchy, as are boxes, cylinders, cones, discs, tori and
+combinations of shapes, which are few enough in a scene to be kept as whole
+shapes; planes are unbounded and tested against every ray. Combinations and
+plugin shapes go in the hierarchy when they have a bounding box, and are
+otherwise tested against every ray like planes. Objects that move while the
+shutter is open are bounded over their whole path and hit where they are at
+the moment of each ray. Each copy of an object with instances goes in the
+hierarchy as one primitive, hit by carrying the ray into the place of the
+object's own primitives, which all its copies share.
+ */
+#[derive(Debug, Default, Clone)]
+pub struct PrimitiveStore {
+    spheres: Spheres,
+    planes: Planes,
+    triangles: Triangles,
+    solids: Solids,
+    plugins: Plugins,
+    copies: Copies,
+    // Everything but planes and unbounded plugin shapes, in the order the hierarchy was built over
+    bounded: Vec<Bounded>,
+    bvh: Bvh,
+    // Distance each object moves over the shutter interval; empty when none of them move
+    motions: Vec<FVec>,
+    // Priority of each object where surfaces coincide; empty when all of them are equal
+    priorities: Vec<i32>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Spheres {
+    centres: Vec<FVec>,
+    radii: Vec<Float>,
+    objects: Vec<usize>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Planes {
+    points: Vec<FVec>,
+    normals: Vec<FVec>,
+    objects: Vec<usize>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Triangles {
+    vertices: Vec<[FVec; 3]>,
+    normals: Vec<Option<[FVec; 3]>>,
+    colours: Vec<Option<[FVec; 3]>>,
+    uvs: Vec<Option<[(Float, Float); 3]>>,
+    dissolves: Vec<Option<Dissolve>>,
+    objects: Vec<usize>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Solids {
+    shapes: Vec<Shape>,
+    objects: Vec<usize>,
+    // Those without a bounding box
+    unbounded: Vec<usize>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Plugins {
+    shapes: Vec<Arc<dyn ShapePlugin>>,
+    objects: Vec<usize>,
+    // Those without a bounding box
+    unbounded: Vec<usize>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Copies {
+    // Primitives of each object with copies where it is placed, shared by all its copies
+    prototypes: Vec<Arc<PrimitiveStore>>,
+    // The object's shape, for texture coordinates where its primitives have none
+    shapes: Vec<Shape>,
+    // Index of each copy's prototype
+    prototype: Vec<usize>,
+    to_world: Vec<Matrix4<Float>>,
+    to_local: Vec<Matrix4<Float>>,
+    objects: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Bounded {
+    Sphere(usize),
+    Triangle(usize),
+    Solid(usize),
+    Plugin(usize),
+    Copy(usize),
+}
+
+// Fraction of their distance within which hits are taken to be on coinciding surfaces
+const COINCIDENT: Float = 1e-5;
+
+/*
+Keep the hit if it is nearer than the one kept, or if the surfaces coincide
+and its object has the higher priority, so the same object is seen at every
+pixel instead of whichever rounding favours.
+ */
+fn keep_if_closer(
+    nearest: &mut Option<(usize, Intersection)>,
+    object: usize,
+    hit: Option<Intersection>,
+    priorities: &[i32],
+) {
+    let Some(hit) = hit else {
+        return;
+    };
+    let priority = |object: usize| priorities.get(object).copied().unwrap_or(0);
+    let kept = nearest.as_ref().is_none_or(|(other, n)| {
+        let coincident = (hit.t - n.t).abs() <= COINCIDENT * hit.t.max(n.t);
+        if coincident && priority(object) != priority(*other) {
+            priority(object) > priority(*other)
+        } else {
+            hit.t < n.t
+        }
+    });
+    if kept {
+        *nearest = Some((object, hit));
+    }
+}
+
+// Hit on a single analytic shape, such as a child of a combination
+pub(crate) fn intersect_solid(
+    shape: &Shape,
+    ray: &Ray,
+    min_distance: Float,
+) -> Option<Intersection> {
+    match shape {
+        Shape::Sphere { centre, radius } => intersect_sphere(centre, *radius, ray, min_distance),
+        Shape::Plane { point, normal } => intersect_plane(point, normal, ray, min_distance),
+        Shape::Box { min, max } => intersect_box(min, max, ray, min_distance),
+        Shape::Cylinder { base, top, radius } => {
+            intersect_cylinder(base, top, *radius, ray, min_distance)
+        }
+        Shape::Cone { base, apex, radius } => {
+            intersect_cone(base, apex, *radius, ray, min_distance)
+        }
+        Shape::Disc {
+            centre,
+            normal,
+            radius,
+        } => intersect_disc(centre, &normal.normalize(), *radius, ray, min_distance),
+        Shape::Torus {
+            centre,
+            axis,
+            major_radius,
+            minor_radius,
+        } => intersect_torus(
+            centre,
+            axis,
+            *major_radius,
+            *minor_radius,
+            ray,
+            min_distance,
+        ),
+        Shape::Csg { operation, a, b } => intersect_csg(*operation, a, b, ray, min_distance),
+        Shape::Mesh { .. } | Shape::Plugin { .. } => None,
+    }
+}
+
+impl PrimitiveStore {
+    pub fn build(objects: &[SceneObject]) -> PrimitiveStore {
+        PrimitiveStore::build_after(objects, None)
+    }
+
+    /*
+    The store for the objects after some of them have moved, as between the
+    frames of an animation. Where the objects break down into the same
+    primitives as before, the hierarchies over them, the scene's and that of
+    each object with copies, are refitted rather than built again unless
+    that has made them too slow to trace. Objects with copies whose shapes
+    have not changed keep theirs as they are.
+     */
+    pub fn rebuild(&self, objects: &[SceneObject]) -> PrimitiveStore {
+        PrimitiveStore::build_after(objects, Some(self))
+    }
+
+    fn build_after(objects: &[SceneObject], previous: Option<&PrimitiveStore>) -> PrimitiveStore {
+        let mut store = PrimitiveStore::default();
+        let mut bounds = Vec::new();
+        for (index, object) in objects.iter().enumerate() {
+            if object.degenerate || object.hidden {
+                continue;
+            }
+            if !object.copies.is_empty() {
+                store.add_copies(index, object, &mut bounds, previous);
+                continue;
+            }
+            match &object.shape {
+                Shape::Sphere { centre, radius } => {
+                    store
+                        .bounded
+                        .push(Bounded::Sphere(store.spheres.objects.len()));
+                    bounds.extend(object.bounding_box());
+                    store.spheres.centres.push(*centre);
+                    store.spheres.radii.push(*radius);
+                    store.spheres.objects.push(index);
+                }
+                Shape::Plane { point, normal } => {
+                    store.planes.points.push(*point);
+                    store.planes.normals.push(*normal);
+                    store.planes.objects.push(index);
+                }
+                Shape::Box { .. }
+                | Shape::Cylinder { .. }
+                | Shape::Cone { .. }
+                | Shape::Disc { .. }
+                | Shape::Torus { .. }
+                | Shape::Csg { .. } => {
+                    let solids = &mut store.solids;
+                    let solid = solids.objects.len();
+                    match object.bounding_box() {
+                        Some(bounding_box) => {
+                            store.bounded.push(Bounded::Solid(solid));
+                            bounds.push(bounding_box);
+                        }
+                        None => solids.unbounded.push(solid),
+                    }
+                    solids.shapes.push(object.shape.clone());
+                    solids.objects.push(index);
+                }
+                Shape::Mesh { mesh, .. } => {
+                    let triangles = &mut store.triangles;
+                    for triangle in &mesh.triangles {
+                        store
+                            .bounded
+                            .push(Bounded::Triangle(triangles.objects.len()));
+                        let triangle_bounds = Aabb::around(triangle.vertices);
+                        bounds.extend(triangle_bounds.map(|b| b.swept(&object.shutter_motion)));
+                        triangles.vertices.push(triangle.vertices);
+                        triangles.normals.push(triangle.normals);
+                        triangles.colours.push(triangle.colours);
+                        triangles.uvs.push(triangle.uvs);
+                        triangles.dissolves.push(triangle.dissolve);
+                        triangles.objects.push(index);
+                    }
+                }
+                Shape::Plugin { shape, .. } => {
+                    let plugins = &mut store.plugins;
+                    let plugin = plugins.objects.len();
+                    match object.bounding_box() {
+                        Some(bounding_box) => {
+                            store.bounded.push(Bounded::Plugin(plugin));
+                            bounds.push(bounding_box);
+                        }
+                        None => plugins.unbounded.push(plugin),
+                    }
+                    plugins.shapes.extend(shape.0.clone());
+                    plugins.objects.push(index);
+                }
+            }
+        }
+        store.bvh = match previous {
+            Some(previous) if previous.bounded == store.bounded => previous.bvh.rebuild(&bounds),
+            _ => Bvh::build(&bounds),
+        };
+        if objects
+            .iter()
+            .any(|object| object.shutter_motion != FVec::zeros())
+        {
+            store.motions = objects.iter().map(|object| object.shutter_motion).collect();
+        }
+        if objects.iter().any(|object| object.priority != 0) {
+            store.priorities = objects.iter().map(|object| object.priority).collect();
+        }
+        store
+    }
+
+    // Add the copies of an object, over primitives of its own shared by all of them
+    fn add_copies(
+        &mut self,
+        index: usize,
+        object: &SceneObject,
+        bounds: &mut Vec<Aabb>,
+        previous: Option<&PrimitiveStore>,
+    ) {
+        let own = SceneObject {
+            copies: Arc::default(),
+            shutter_motion: FVec::zeros(),
+            priority: 0,
+            ..object.clone()
+        };
+        let Some(own_bounds) = own.bounding_box() else {
+            return;
+        };
+        let copies = &mut self.copies;
+        let prototype = copies.prototypes.len();
+        // The prototype in the same place last time is used again if its shape has not changed,
+        // and refitted from if it has
+        let before = previous.and_then(|previous| {
+            let copies = &previous.copies;
+            Some((copies.prototypes.get(prototype)?, &copies.shapes[prototype]))
+        });
+        copies.prototypes.push(match before {
+            Some((store, shape)) if *shape == object.shape => store.clone(),
+            Some((store, _)) => Arc::new(store.rebuild(&[own])),
+            None => Arc::new(PrimitiveStore::build(&[own])),
+        });
+        copies.shapes.push(object.shape.clone());
+        for matrix in object.copies.iter() {
+            let Some(to_local) = matrix.try_inverse() else {
+                continue;
+            };
+            let Some(copy_bounds) = transformed_box(&own_bounds, matrix) else {
+                continue;
+            };
+            self.bounded.push(Bounded::Copy(copies.objects.len()));
+            bounds.push(copy_bounds.swept(&object.shutter_motion));
+            copies.prototype.push(prototype);
+            copies.to_world.push(*matrix);
+            copies.to_local.push(to_local);
+            copies.objects.push(index);
+        }
+    }
+
+    /*
+    Hit on a copy: the ray is carried into the place of the object's own
+    primitives without normalising its direction, so distances along it are
+    the same in both, and the hit carried back.
+     */
+    fn intersect_copy(&self, i: usize, ray: &Ray, min_distance: Float) -> Option<Intersection> {
+        let copies = &self.copies;
+        let (to_world, to_local) = (&copies.to_world[i], &copies.to_local[i]);
+        let local_ray = Ray {
+            origin: transform_point(to_local, &ray.origin),
+            direction: to_local.transform_vector(&ray.direction),
+            differential: None,
+            time: ray.time,
+        };
+        let prototype = copies.prototype[i];
+        let shared = &copies.prototypes[prototype];
+        let (_, mut hit) = shared.nearest(&local_ray, min_distance, None, |_, _| true)?;
+        hit.uv = Some(copies.shapes[prototype].texture_uv(&hit));
+        let linear = to_world.fixed_view::<3, 3>(0, 0);
+        let normals = normal_matrix(to_world);
+        let pos = transform_point(to_world, &hit.pos);
+        Some(Intersection {
+            pos,
+            normal: (normals * hit.normal).normalize(),
+            geometric_normal: hit.geometric_normal.map(|n| (normals * n).normalize()),
+            terminator_offset: hit.terminator_offset.map(|offset| linear * offset),
+            error: linear.abs() * hit.error + gamma(7) * pos.abs(),
+            uv_tangents: hit
+                .uv_tangents
+                .map(|(dpdu, dpdv)| (linear * dpdu, linear * dpdv)),
+            ..hit
+        })
+    }
+
+    // Boxes of the hierarchy over the primitives, with their depths; see Bvh::boxes
+    pub(crate) fn hierarchy_boxes(&self, levels: usize) -> Vec<(usize, Aabb)> {
+        self.bvh.boxes(levels)
+    }
+
+    // Memory taken by the primitives, leaving out the hierarchy over them
+    pub fn geometry_bytes(&self) -> usize {
+        use std::mem::size_of_val;
+        let (spheres, planes, triangles) = (&self.spheres, &self.planes, &self.triangles);
+        size_of_val(spheres.centres.as_slice())
+            + size_of_val(spheres.radii.as_slice())
+            + size_of_val(spheres.objects.as_slice())
+            + size_of_val(planes.points.as_slice())
+            + size_of_val(planes.normals.as_slice())
+            + size_of_val(planes.objects.as_slice())
+            + size_of_val(triangles.vertices.as_slice())
+            + size_of_val(triangles.normals.as_slice())
+            + size_of_val(triangles.colours.as_slice())
+            + size_of_val(triangles.uvs.as_slice())
+            + size_of_val(triangles.dissolves.as_slice())
+            + size_of_val(triangles.objects.as_slice())
+            + size_of_val(self.solids.shapes.as_slice())
+            + size_of_val(self.solids.objects.as_slice())
+            + size_of_val(self.solids.unbounded.as_slice())
+            + size_of_val(self.plugins.shapes.as_slice())
+            + size_of_val(self.plugins.objects.as_slice())
+            + size_of_val(self.plugins.unbounded.as_slice())
+            + size_of_val(self.bounded.as_slice())
+            + self.copies_bytes(PrimitiveStore::geometry_bytes)
+    }
+
+    pub fn bvh_bytes(&self) -> usize {
+        self.bvh.bytes() + self.copies_bytes(PrimitiveStore::bvh_bytes)
+    }
+
+    // Memory taken by the copies' transforms and the part of their prototypes measured
+    fn copies_bytes(&self, measure: fn(&PrimitiveStore) -> usize) -> usize {
+        use std::mem::size_of_val;
+        let copies = &self.copies;
+        let prototypes: usize = copies.prototypes.iter().map(|shared| measure(shared)).sum();
+        prototypes
+            + size_of_val(copies.prototype.as_slice())
+            + size_of_val(copies.to_world.as_slice())
+            + size_of_val(copies.to_local.as_slice())
+            + size_of_val(copies.objects.as_slice())
+    }
+
+    /*
+    Hit on an object where it is at the moment of the ray. A moving object is
+    hit by the ray moved back by the object's offset, and the hit moved forward
+    again, which leaves the primitives themselves where the shutter opens.
+     */
+    fn intersect_moving(
+        &self,
+        object: usize,
+        ray: &Ray,
+        intersect: impl FnOnce(&Ray) -> Option<Intersection>,
+    ) -> Option<Intersection> {
+        stats::add(Counter::IntersectionTests, 1);
+        let offset = match self.motions.get(object) {
+            Some(motion) if *motion != FVec::zeros() => motion * ray.time,
+            _ => return intersect(ray),
+        };
+        let moved = Ray {
+            origin: ray.origin - offset,
+            direction: ray.direction,
+            differential: None,
+            time: ray.time,
+        };
+        let mut hit = intersect(&moved)?;
+        hit.pos += offset;
+        hit.error += gamma(1) * hit.pos.abs();
+        Some(hit)
+    }
+
+    fn intersect_plane(&self, i: usize, ray: &Ray, min_distance: Float) -> Option<Intersection> {
+        let planes = &self.planes;
+        self.intersect_moving(planes.objects[i], ray, |ray| {
+            intersect_plane(&planes.points[i], &planes.normals[i], ray, min_distance)
+        })
+    }
+
+    // Hit on a bounded primitive and the index of its object
+    fn intersect_bounded(
+        &self,
+        primitive: Bounded,
+        ray: &Ray,
+        min_distance: Float,
+    ) -> (usize, Option<Intersection>) {
+        match primitive {
+            Bounded::Sphere(i) => {
+                let spheres = &self.spheres;
+                let object = spheres.objects[i];
+                let hit = self.intersect_moving(object, ray, |ray| {
+                    intersect_sphere(&spheres.centres[i], spheres.radii[i], ray, min_distance)
+                });
+                (object, hit)
+            }
+            Bounded::Triangle(i) => {
+                let triangles = &self.triangles;
+                let normals = triangles.normals[i].as_ref();
+                let colours = triangles.colours[i].as_ref();
+                let uvs = triangles.uvs[i].as_ref();
+                let dissolve = triangles.dissolves[i].as_ref();
+                let vertices = &triangles.vertices[i];
+                let object = triangles.objects[i];
+                let hit = self.intersect_moving(object, ray, |ray| {
+                    intersect_triangle(vertices, normals, colours, uvs, dissolve, ray, min_distance)
+                });
+                (object, hit)
+            }
+            Bounded::Solid(i) => {
+                let object = self.solids.objects[i];
+                let hit = self.intersect_moving(object, ray, |ray| {
+                    intersect_solid(&self.solids.shapes[i], ray, min_distance)
+                });
+                (object, hit)
+            }
+            Bounded::Plugin(i) => {
+                let object = self.plugins.objects[i];
+                let hit = self.intersect_moving(object, ray, |ray| {
+                    self.plugins.shapes[i].intersect(ray, min_distance)
+                });
+                (object, hit)
+            }
+            Bounded::Copy(i) => {
+                let object = self.copies.objects[i];
+                let hit = self
+                    .intersect_moving(object, ray, |ray| self.intersect_copy(i, ray, min_distance));
+                (object, hit)
+            }
+        }
+    }
+
+    /*
+    Nearest hit along the ray and the index of the object hit. When a mask is
+    given, only objects whose entry is true are considered. Hits the ray
+    passes by the test given, such as on cut-away parts of a surface, are
+    left out as the hierarchy is traversed.
+     */
+    pub fn nearest(
+        &self,
+        ray: &Ray,
+        min_distance: Float,
+        mask: Option<&[bool]>,
+        stops: impl Fn(usize, &Intersection) -> bool,
+    ) -> Option<(usize, Intersection)> {
+        let mut nearest: Option<(usize, Intersection)> = None;
+        self.bvh
+            .traverse(ray, min_distance, Float::INFINITY, |index| {
+                self.keep_bounded(index, ray, min_distance, mask, &stops, &mut nearest)
+            });
+        self.keep_unbounded(ray, min_distance, mask, &stops, &mut nearest);
+        nearest
+    }
+
+    /*
+    Nearest hits along each of a packet of at most PACKET_RAYS rays, as
+    nearest finds them for one, with the rays walking the hierarchy together.
+    Stops is given the index of the ray in the packet.
+     */
+    pub fn nearest_packet(
+        &self,
+        rays: &[&Ray],
+        min_distance: Float,
+        mask: Option<&[bool]>,
+        stops: impl Fn(usize, usize, &Intersection) -> bool,
+    ) -> Vec<Option<(usize, Intersection)>> {
+        let mut nearest: Vec<Option<(usize, Intersection)>> = vec![None; rays.len()];
+        let mut max_distances = vec![Float::INFINITY; rays.len()];
+        self.bvh
+            .traverse_packet(rays, min_distance, &mut max_distances, |index, i| {
+                let stops = |object: usize, hit: &Intersection| stops(i, object, hit);
+                self.keep_bounded(index, rays[i], min_distance, mask, &stops, &mut nearest[i])
+            });
+        for (i, (ray, nearest)) in rays.iter().zip(&mut nearest).enumerate() {
+            let stops = |object: usize, hit: &Intersection| stops(i, object, hit);
+            self.keep_unbounded(ray, min_distance, mask, &stops, nearest);
+        }
+        nearest
+    }
+
+    /*
+    Keep the hit on a primitive of the hierarchy if it is the nearest so far,
+    returning the distance beyond which nothing more is wanted.
+     */
+    fn keep_bounded(
+        &self,
+        index: usize,
+        ray: &Ray,
+        min_distance: Float,
+        mask: Option<&[bool]>,
+        stops: &impl Fn(usize, &Intersection) -> bool,
+        nearest: &mut Option<(usize, Intersection)>,
+    ) -> Float {
+        let (object, hit) = self.intersect_bounded(self.bounded[index], ray, min_distance);
+        if mask.is_none_or(|m| m[object]) {
+            let hit = hit.filter(|hit| stops(object, hit));
+            keep_if_closer(nearest, object, hit, &self.priorities);
+        }
+        // Surfaces just beyond the nearest may still win it by priority
+        let slack = if self.priorities.is_empty() {
+            1.0
+        } else {
+            1.0 + COINCIDENT
+        };
+        nearest
+            .as_ref()
+            .map_or(Float::INFINITY, |(_, n)| n.t * slack)
+    }
+
+    // Keep the nearest of the hits on planes and other shapes outside the hierarchy
+    fn keep_unbounded(
+        &self,
+        ray: &Ray,
+        min_distance: Float,
+        mask: Option<&[bool]>,
+        stops: &impl Fn(usize, &Intersection) -> bool,
+        nearest: &mut Option<(usize, Intersection)>,
+    ) {
+        let included = |object: usize| mask.is_none_or(|m| m[object]);
+        let stopping =
+            |object: usize, hit: Option<Intersection>| hit.filter(|hit| stops(object, hit));
+        let planes = &self.planes;
+        for i in 0..planes.objects.len() {
+            if included(planes.objects[i]) {
+                let hit = stopping(
+                    planes.objects[i],
+                    self.intersect_plane(i, ray, min_distance),
+                );
+                keep_if_closer(nearest, planes.objects[i], hit, &self.priorities);
+            }
+        }
+        let solids = &self.solids;
+        for &i in &solids.unbounded {
+            if included(solids.objects[i]) {
+                let hit = self.intersect_moving(solids.objects[i], ray, |ray| {
+                    intersect_solid(&solids.shapes[i], ray, min_distance)
+                });
+                let hit = stopping(solids.objects[i], hit);
+                keep_if_closer(nearest, solids.objects[i], hit, &self.priorities);
+            }
+        }
+        let plugins = &self.plugins;
+        for &i in &plugins.unbounded {
+            if included(plugins.objects[i]) {
+                let hit = self.intersect_moving(plugins.objects[i], ray, |ray| {
+                    plugins.shapes[i].intersect(ray, min_distance)
+                });
+                let hit = stopping(plugins.objects[i], hit);
+                keep_if_closer(nearest, plugins.objects[i], hit, &self.priorities);
+            }
+        }
+    }
+
+    /*
+    Whether anything is hit along the ray closer than max_distance, as for
+    shadow rays, leaving out hits the ray passes by the test given.
+     */
+    pub fn occluded(
+        &self,
+        ray: &Ray,
+        min_distance: Float,
+        max_distance: Float,
+        stops: impl Fn(usize, &Intersection) -> bool,
+    ) -> bool {
+        let blocks = |object: usize, hit: Option<Intersection>| {
+            hit.is_some_and(|hit| hit.t < max_distance && stops(object, &hit))
+        };
+        let planes = &self.planes;
+        if (0..planes.objects.len()).any(|i| {
+            blocks(
+                planes.objects[i],
+                self.intersect_plane(i, ray, min_distance),
+            )
+        }) {
+            return true;
+        }
+        let solids = &self.solids;
+        if solids.unbounded.iter().any(|&i| {
+            let hit = self.intersect_moving(solids.objects[i], ray, |ray| {
+                intersect_solid(&solids.shapes[i], ray, min_distance)
+            });
+            blocks(solids.objects[i], hit)
+        }) {
+            return true;
+        }
+        let plugins = &self.plugins;
+        if plugins.unbounded.iter().any(|&i| {
+            let hit = self.intersect_moving(plugins.objects[i], ray, |ray| {
+                plugins.shapes[i].intersect(ray, min_distance)
+            });
+            blocks(plugins.objects[i], hit)
+        }) {
+            return true;
+        }
+        let mut occluded = false;
+        self.bvh.traverse(ray, min_distance, max_distance, |index| {
+            let (object, hit) = self.intersect_bounded(self.bounded[index], ray, min_distance);
+            occluded = blocks(object, hit);
+            if occluded {
+                Float::NEG_INFINITY
+            } else {
+                max_distance
+            }
+        });
+        occluded
+    }
+}
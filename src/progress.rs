@@ -0,0 +1,217 @@
+use log::LevelFilter;
+use serde_json::{json, Value};
+use std::io::{IsTerminal, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+// Characters across the progress bar drawn on a terminal
+const BAR_WIDTH: usize = 30;
+
+// How render progress is reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressFormat {
+    // Every tenth of a stage in the log
+    Text,
+    // One JSON object per line on stdout, for GUIs, farm managers and CI wrappers
+    Json,
+}
+
+static JSON: AtomicBool = AtomicBool::new(false);
+static TO_STDERR: AtomicBool = AtomicBool::new(false);
+static NO_BAR: AtomicBool = AtomicBool::new(false);
+
+type Callback = Box<dyn Fn(&Progress) + Send + Sync>;
+
+static CALLBACK: RwLock<Option<Callback>> = RwLock::new(None);
+
+// How far a stage has got, as given to the progress callback
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    pub stage: &'static str,
+    // Steps finished so far, such as tiles of the image, out of the total
+    pub done: u64,
+    pub total: u64,
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    pub fn fraction(&self) -> f64 {
+        self.done as f64 / self.total.max(1) as f64
+    }
+
+    // Time left if the remaining steps take as long as the finished ones did on average
+    pub fn remaining(&self) -> Option<Duration> {
+        let left = (self.total - self.done) as f64 / self.done as f64;
+        (self.done > 0).then(|| self.elapsed.mul_f64(left))
+    }
+}
+
+/*
+Call the function every time a step of a stage finishes, from whichever
+thread finished it, so programs using the library can show their own
+progress. Replaces any callback set before.
+ */
+pub fn set_callback(callback: impl Fn(&Progress) + Send + Sync + 'static) {
+    *CALLBACK
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(Box::new(callback));
+}
+
+pub fn clear_callback() {
+    *CALLBACK
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = None;
+}
+
+// A duration rounded to seconds, as 45s or 3m07s
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs_f64().round() as u64;
+    if seconds < 60 {
+        format!("{seconds}s")
+    } else {
+        format!("{}m{:02}s", seconds / 60, seconds % 60)
+    }
+}
+
+/*
+Whether progress is drawn as a bar that redraws itself in place. Only done
+on a terminal, and not with debug logging, whose messages would break it up.
+ */
+fn draws_bar() -> bool {
+    !JSON.load(Ordering::Relaxed)
+        && !NO_BAR.load(Ordering::Relaxed)
+        && log::max_level() == LevelFilter::Info
+        && std::io::stderr().is_terminal()
+}
+
+pub fn set_format(format: ProgressFormat) {
+    JSON.store(format == ProgressFormat::Json, Ordering::Relaxed);
+}
+
+// Log progress as lines even on a terminal, for logs collected from containers
+pub fn disable_bar() {
+    NO_BAR.store(true, Ordering::Relaxed);
+}
+
+// Send JSON events to stderr instead, for when stdout carries the image
+pub fn use_stderr() {
+    TO_STDERR.store(true, Ordering::Relaxed);
+}
+
+impl FromStr for ProgressFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ProgressFormat, String> {
+        match s {
+            "text" => Ok(ProgressFormat::Text),
+            "json" => Ok(ProgressFormat::Json),
+            _ => Err(format!("unknown progress format {s:?}")),
+        }
+    }
+}
+
+fn emit(event: Value) {
+    if !JSON.load(Ordering::Relaxed) {
+        return;
+    }
+    if TO_STDERR.load(Ordering::Relaxed) {
+        eprintln!("{}", event);
+    } else {
+        println!("{}", event);
+    }
+}
+
+/*
+Progress through one stage of a render made of a known number of steps,
+such as blocks of pixels. Steps may be completed from any thread.
+ */
+pub struct Task {
+    stage: &'static str,
+    total: u64,
+    done: AtomicU64,
+    start: Instant,
+}
+
+impl Task {
+    pub fn start(stage: &'static str, total: usize) -> Task {
+        emit(json!({"event": "stageStarted", "stage": stage, "total": total}));
+        Task {
+            stage,
+            total: total as u64,
+            done: AtomicU64::new(0),
+            start: Instant::now(),
+        }
+    }
+
+    /*
+    Record one finished step. The callback hears of every step; the log,
+    terminal bar and JSON events whenever another percent is complete.
+     */
+    pub fn advance(&self) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        let progress = Progress {
+            stage: self.stage,
+            done,
+            total: self.total,
+            elapsed: self.start.elapsed(),
+        };
+        let callback = CALLBACK
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(callback) = &*callback {
+            callback(&progress);
+        }
+        drop(callback);
+        let percent = done * 100 / self.total;
+        if percent == (done - 1) * 100 / self.total {
+            return;
+        }
+        emit(json!({
+            "event": "progress",
+            "stage": self.stage,
+            "done": done,
+            "total": self.total,
+            "fraction": progress.fraction(),
+        }));
+        let remaining = progress
+            .remaining()
+            .map(format_duration)
+            .unwrap_or_default();
+        if draws_bar() {
+            let filled = (progress.fraction() * BAR_WIDTH as f64) as usize;
+            let bar = format!("{}{}", "#".repeat(filled), " ".repeat(BAR_WIDTH - filled));
+            // Back to the start of the line, clearing what was drawn there before
+            eprint!(
+                "\r{}: [{}] {:3}% {} left\x1b[K",
+                self.stage, bar, percent, remaining
+            );
+            let _ = std::io::stderr().flush();
+        } else if percent.is_multiple_of(10) && !JSON.load(Ordering::Relaxed) {
+            info!("{}: {}%, {} left", self.stage, percent, remaining);
+        }
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        if self.done.load(Ordering::Relaxed) > 0 && draws_bar() {
+            eprintln!();
+        }
+        emit(json!({
+            "event": "stageFinished",
+            "stage": self.stage,
+            "seconds": self.start.elapsed().as_secs_f64(),
+        }));
+    }
+}
+
+// The render finished and its image was written to the path
+pub fn finished(path: &str) {
+    emit(json!({"event": "finished", "output": path}));
+}
+
+pub fn failed(message: &str) {
+    emit(json!({"event": "failed", "message": message}));
+}
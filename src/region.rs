@@ -0,0 +1,175 @@
+use std::str::FromStr;
+
+// A rectangle of film pixels, which include any overscan margin
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Region {
+        Region {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        (self.x..self.x + self.width).contains(&x) && (self.y..self.y + self.height).contains(&y)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    // The part of this region that also lies inside the other
+    pub fn intersect(&self, other: &Region) -> Region {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = self
+            .x
+            .saturating_add(self.width)
+            .min(other.x.saturating_add(other.width));
+        let bottom = self
+            .y
+            .saturating_add(self.height)
+            .min(other.y.saturating_add(other.height));
+        Region::new(x, y, right.saturating_sub(x), bottom.saturating_sub(y))
+    }
+
+    // Grown by the margin on every side, without going past (0, 0)
+    pub fn padded(&self, margin: u32) -> Region {
+        let x = self.x.saturating_sub(margin);
+        let y = self.y.saturating_sub(margin);
+        let right = self.x + self.width + margin;
+        let bottom = self.y + self.height + margin;
+        Region::new(x, y, right - x, bottom - y)
+    }
+
+    // Squares of the given size covering the region, clipped to its edges
+    pub fn blocks(&self, size: u32) -> Vec<Region> {
+        let region = *self;
+        (self.y..self.y + self.height)
+            .step_by(size as usize)
+            .flat_map(|y| {
+                (region.x..region.x + region.width)
+                    .step_by(size as usize)
+                    .map(move |x| Region::new(x, y, size, size).intersect(&region))
+            })
+            .collect()
+    }
+}
+
+// Parsed from "x,y,width,height"
+impl FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Region, String> {
+        let values = s
+            .split(',')
+            .map(|value| value.trim().parse())
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|error| format!("invalid region {s:?}: {error}"))?;
+        match values[..] {
+            [x, y, width, height] => Ok(Region::new(x, y, width, height)),
+            _ => Err(format!("invalid region {s:?}: expected x,y,width,height")),
+        }
+    }
+}
+
+/*
+One of a number of equal slices of the tiles of a render, taken in the order
+they are rendered, down the image. Each slice can be rendered on its own
+machine and the parts merged into the image a single render would give.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TileRange {
+    // Counted from 1
+    pub index: u32,
+    pub count: u32,
+}
+
+impl TileRange {
+    // The tiles of the whole film in this slice
+    pub fn select(&self, tiles: Vec<Region>) -> Vec<Region> {
+        let (index, count) = (self.index as usize, self.count as usize);
+        let first = tiles.len() * (index - 1) / count;
+        let last = tiles.len() * index / count;
+        tiles[first..last].to_vec()
+    }
+}
+
+// Parsed from "index/count", such as "2/4" for the second quarter
+impl FromStr for TileRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TileRange, String> {
+        let (index, count) = s
+            .split_once('/')
+            .ok_or(format!("invalid tile range {s:?}: expected index/count"))?;
+        let parse = |n: &str| {
+            n.trim()
+                .parse::<u32>()
+                .map_err(|error| format!("invalid tile range {s:?}: {error}"))
+        };
+        let (index, count) = (parse(index)?, parse(count)?);
+        if index == 0 || index > count {
+            return Err(format!(
+                "invalid tile range {s:?}: the index must be from 1 to {count}"
+            ));
+        }
+        Ok(TileRange { index, count })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILM: Region = Region {
+        x: 0,
+        y: 0,
+        width: 32,
+        height: 24,
+    };
+
+    #[test]
+    fn clips_a_partly_overlapping_region_to_the_film() {
+        let clipped = Region::new(20, 10, 40, 40).intersect(&FILM);
+        assert_eq!(clipped, Region::new(20, 10, 12, 14));
+        assert!(!clipped.is_empty());
+    }
+
+    #[test]
+    fn keeps_a_region_inside_the_film() {
+        let inside = Region::new(4, 6, 10, 8);
+        assert_eq!(inside.intersect(&FILM), inside);
+        assert_eq!(FILM.intersect(&inside), inside);
+    }
+
+    #[test]
+    fn a_region_outside_the_film_clips_to_nothing() {
+        assert!(Region::new(60, 40, 10, 10).intersect(&FILM).is_empty());
+        // Touching the film's edge without covering any of it
+        assert!(Region::new(32, 0, 5, 5).intersect(&FILM).is_empty());
+        assert!(Region::new(0, 24, 5, 5).intersect(&FILM).is_empty());
+    }
+
+    #[test]
+    fn clips_regions_reaching_past_the_largest_coordinate() {
+        let far = Region::new(u32::MAX - 5, 0, 400, 1);
+        assert!(far.intersect(&FILM).is_empty());
+        let huge = Region::new(u32::MAX - 5, u32::MAX - 5, 400, 400);
+        let whole = Region::new(0, 0, u32::MAX, u32::MAX);
+        assert_eq!(
+            huge.intersect(&whole),
+            Region::new(u32::MAX - 5, u32::MAX - 5, 5, 5)
+        );
+        assert_eq!(whole.intersect(&FILM), FILM);
+    }
+}
@@ -0,0 +1,2888 @@
+use crate::adaptive::Estimate;
+use crate::bounds::Aabb;
+use crate::bvh::PACKET_RAYS;
+use crate::checkpoint::{self, Checkpoint, Fingerprint, Finished, Recorder};
+use crate::core::clamp;
+use crate::core::consts::PI;
+use crate::core::ray::{Intersection, Ray, RayDifferential};
+use crate::core::shading::{
+    blinn_phong, facing_normal, ggx, lambert, reflect, reflect_differential, refract,
+    refract_differential, schlick_colour, sheen, smith_visibility,
+};
+use crate::core::single;
+use crate::deep::{self, DeepSample};
+use crate::environment::Environment;
+use crate::expression::Inputs;
+use crate::filter::Film;
+use crate::fog::{Volume, VOLUME_STEPS};
+use crate::gbuffer::{crop_overscan, heat, Aov, FirstHit, GBuffer, PixelSamples, PrimarySample};
+use crate::grid::GridVolume;
+use crate::light::{coordinate_system, LightSample, LightSource};
+use crate::light_path::{LightPaths, Scatter};
+use crate::logging::StageTimer;
+use crate::material::CLEARCOAT_SHINE;
+use crate::media::MediumStack;
+use crate::microfacet::compensation;
+use crate::mis::Lobe;
+#[cfg(feature = "exr")]
+use crate::multilayer;
+use crate::plugin::{self, IntegratorPlugin};
+use crate::progress::Task;
+use crate::region::{Region, TileRange};
+use crate::sampling::{cosine_hemisphere, ggx_normal, ray_random, Rng, ScrambledHalton};
+use crate::scene::Pass;
+use crate::spectral::{sample_wavelength, wavelength_colour};
+use crate::stats::{self, Counter};
+use crate::tonemap::ToneMapping;
+use crate::wireframe;
+use crate::{Camera, FVec, Float, Material, Scene};
+use image::{
+    DynamicImage, ImageBuffer, ImageError, ImageFormat, Pixel, Rgb, Rgb32FImage, RgbImage, Rgba,
+    Rgba32FImage,
+};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::sync::Arc;
+
+// Default for the scene's bounce limit
+pub const MAX_BOUNCES: u8 = 100;
+// Bounces after which transparent surfaces follow only one of their two paths
+pub const MAX_SPLIT_BOUNCES: u8 = 8;
+// Share of a light's light below which a shadow ray through transparent objects is given up
+const MIN_SHADOW_TRANSMISSION: Float = 1e-4;
+// Times a tile with pixels that are not finite is rendered again before they are given up on
+const SALVAGE_ATTEMPTS: u32 = 2;
+// Least ray bias in metres of a tile rendered again, raised tenfold with each attempt
+const SALVAGE_RAY_BIAS: Float = 1e-4;
+// Colour of the bounding box overlay
+const BOUNDS_COLOUR: [u8; 3] = [0, 255, 0];
+
+// Linear colours of a render, before they are tone mapped and encoded for display
+pub type LinearImage = ImageBuffer<Rgb<Float>, Vec<Float>>;
+// Bounces after which the path integrator ends paths at random
+pub const ROULETTE_BOUNCES: u8 = 3;
+// Dimension of a pixel's sequence its wavelengths are drawn from, past those of lights
+const WAVELENGTH_DIMENSION: usize = 3;
+
+// How the light arriving at a surface is gathered
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(try_from = "String")]
+pub enum Integrator {
+    // Lights, mirror reflection and refraction, with a constant ambient term for everything else
+    #[default]
+    Whitted,
+    /*
+    Also light bounced off diffuse surfaces, gathered along random directions.
+    Objects and the background light the scene instead of the ambient term.
+     */
+    Path,
+    // Only how open the surfaces seen are to their surroundings, as grey, for checking geometry
+    AmbientOcclusion,
+    // Registered by the program under the name, and given every primary ray to shade
+    Plugin(String, Arc<dyn IntegratorPlugin>),
+}
+
+impl TryFrom<String> for Integrator {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Integrator, String> {
+        match name.as_str() {
+            "whitted" => Ok(Integrator::Whitted),
+            "path" => Ok(Integrator::Path),
+            "ao" => Ok(Integrator::AmbientOcclusion),
+            _ => match plugin::integrator(&name) {
+                Some(integrator) => Ok(Integrator::Plugin(name, integrator)),
+                None => Err(format!("unknown integrator {name:?}")),
+            },
+        }
+    }
+}
+
+/*
+Rays sent over the hemisphere above a surface to find how much of it nearby
+geometry hides, darkening the ambient term in crevices and where objects
+touch. Used by the ao integrator too.
+ */
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct AmbientOcclusion {
+    // Geometry further away than this does not occlude
+    #[serde(default = "default_occlusion_radius")]
+    pub(crate) radius: Float,
+    #[serde(default = "default_occlusion_samples")]
+    pub(crate) samples: u32,
+}
+
+fn default_occlusion_radius() -> Float {
+    1.0
+}
+
+fn default_occlusion_samples() -> u32 {
+    16
+}
+
+impl Default for AmbientOcclusion {
+    fn default() -> Self {
+        AmbientOcclusion {
+            radius: default_occlusion_radius(),
+            samples: default_occlusion_samples(),
+        }
+    }
+}
+
+// Write an image to a file, or as PNG to stdout when the path is "-"
+pub fn save_image(image: DynamicImage, path: &str) -> Result<(), ImageError> {
+    #[cfg(feature = "hdr")]
+    if ImageFormat::from_path(path).ok() == Some(ImageFormat::Hdr) {
+        // Radiance HDR is only written by its own encoder, and has no alpha channel
+        let image = image.into_rgb32f();
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let pixels: Vec<Rgb<f32>> = image.pixels().copied().collect();
+        return image::codecs::hdr::HdrEncoder::new(file).encode(&pixels, width, height);
+    }
+    if path != "-" {
+        return image.save(path);
+    }
+    // PNG encoding needs to seek, which stdout cannot
+    let mut png = std::io::Cursor::new(Vec::new());
+    image.write_to(&mut png, ImageFormat::Png)?;
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(png.get_ref())?;
+    stdout.flush()?;
+    Ok(())
+}
+
+// Whether the path names a format that keeps linear values beyond [0, 1]
+fn is_floating_point(path: &str) -> bool {
+    matches!(
+        ImageFormat::from_path(path),
+        Ok(ImageFormat::OpenExr | ImageFormat::Hdr)
+    )
+}
+
+// The same image with single-precision channels, as floating-point formats store them
+fn to_f32<P, Q>(image: &ImageBuffer<P, Vec<Float>>) -> ImageBuffer<Q, Vec<f32>>
+where
+    P: Pixel<Subpixel = Float>,
+    Q: Pixel<Subpixel = f32>,
+{
+    let channels = image.as_raw().iter().map(|&c| single(c)).collect();
+    ImageBuffer::from_raw(image.width(), image.height(), channels).expect("same channel count")
+}
+
+pub fn channel_float_to_int(value: Float) -> u8 {
+    let integer = (value * 255.0) as i32;
+    clamp(integer, 0, 255) as u8
+}
+
+/*
+Chance of the path integrator following the mirror and transmitted light
+off a surface rather than the light bounced diffusely, in proportion to
+their weights. Following both would double the rays with each bounce, so
+both are only followed for the first few bounces, or where one of them
+has no weight, when this is None.
+ */
+fn specular_chance(
+    material: &Material,
+    cos_theta: Float,
+    diffuse: &FVec,
+    num_bounces: u8,
+) -> Option<Float> {
+    let specular = material.reflectance(cos_theta) + material.transmittance(cos_theta);
+    let diffuse = diffuse.max();
+    (num_bounces >= ROULETTE_BOUNCES && specular > 0.0 && diffuse > 0.0)
+        .then(|| specular / (specular + diffuse))
+}
+
+/*
+State for rendering the scene through one camera. Anything that depends on
+the camera lives here rather than in the scene, so several views of the same
+scene can render at once from different threads.
+ */
+pub struct View<'a> {
+    pub(crate) camera: &'a Camera,
+    // Whether primary rays can hit each object
+    pub(crate) primary_mask: Vec<bool>,
+}
+
+impl Scene {
+    /*
+    Skip objects entirely outside the camera's view when tracing primary rays.
+    Shadow and reflection rays still test every object.
+     */
+    pub(crate) fn view<'a>(&self, camera: &'a Camera) -> View<'a> {
+        let frustum = camera.get_frustum();
+        let primary_mask = self
+            .objects
+            .iter()
+            .map(|object| {
+                let bounds = object.bounding_box();
+                frustum
+                    .as_ref()
+                    .is_none_or(|frustum| bounds.is_none_or(|aabb| frustum.may_contain(&aabb)))
+            })
+            .collect();
+        View {
+            camera,
+            primary_mask,
+        }
+    }
+
+    pub(crate) fn _get_intersection(
+        &self,
+        ray: &Ray,
+        min_distance: Float,
+    ) -> Option<(Intersection, Material)> {
+        self._get_nearest_intersection(ray, min_distance, None)
+    }
+
+    pub(crate) fn _get_nearest_intersection(
+        &self,
+        ray: &Ray,
+        min_distance: Float,
+        mask: Option<&[bool]>,
+    ) -> Option<(Intersection, Material)> {
+        self._get_nearest_hit(ray, min_distance, mask)
+            .map(|(index, hit)| (hit, self.objects[index].material.clone()))
+    }
+
+    pub(crate) fn _get_nearest_hit(
+        &self,
+        ray: &Ray,
+        min_distance: Float,
+        mask: Option<&[bool]>,
+    ) -> Option<(usize, Intersection)> {
+        self.primitives
+            .nearest(ray, min_distance, mask, |object, hit| {
+                self._stops_ray(ray, object, hit)
+            })
+            .map(|(index, hit)| (index, self.objects[index].with_differentials(hit, ray)))
+    }
+
+    // Nearest hits of a packet of rays traced together, as _get_nearest_hit finds them for one
+    pub(crate) fn _get_nearest_hits(
+        &self,
+        rays: &[&Ray],
+        min_distance: Float,
+        mask: Option<&[bool]>,
+    ) -> Vec<Option<(usize, Intersection)>> {
+        let stops =
+            |i: usize, object: usize, hit: &Intersection| self._stops_ray(rays[i], object, hit);
+        let hits = self
+            .primitives
+            .nearest_packet(rays, min_distance, mask, stops);
+        hits.into_iter()
+            .zip(rays)
+            .map(|(hit, ray)| {
+                hit.map(|(index, hit)| (index, self.objects[index].with_differentials(hit, ray)))
+            })
+            .collect()
+    }
+
+    // Whether any object lies along the ray before the given distance
+    pub(crate) fn _is_occluded(&self, ray: &Ray, distance: Float) -> bool {
+        stats::add(Counter::ShadowRays, 1);
+        let stops = |object: usize, hit: &Intersection| self._stops_shadow(ray, object, hit);
+        self.primitives.occluded(ray, 0.0, distance, stops)
+    }
+
+    /*
+    Whether a hit stops the ray. A point of a surface with an opacity map
+    stops it only by chance, as often as it is opaque, so over the samples of
+    a pixel cut-out surfaces such as leaves on cards blend with what is behind
+    them without sorting every surface the rays pass. The chance is fixed by
+    the ray and the hit, so tracing the same ray again finds the same surface.
+     */
+    pub(crate) fn _stops_ray(&self, ray: &Ray, object: usize, hit: &Intersection) -> bool {
+        let object = &self.objects[object];
+        if object.material.opacity_map.is_none() {
+            return true;
+        }
+        let position = object.shape.object_position(&hit.pos);
+        let opacity = object
+            .material
+            .opacity(object.texture_uv(hit), &position, &hit.normal);
+        ray_random(&ray.origin, &ray.direction, hit.t) < opacity
+    }
+
+    // Whether a hit stops a shadow ray, by chance for a material with a shadow opacity
+    pub(crate) fn _stops_shadow(&self, ray: &Ray, object: usize, hit: &Intersection) -> bool {
+        match self.objects[object].material.shadow_opacity {
+            Some(opacity) => ray_random(&ray.origin, &ray.direction, hit.t) < opacity,
+            None => self._stops_ray(ray, object, hit),
+        }
+    }
+
+    pub(crate) fn _get_diffuse_lighting(
+        &self,
+        intersection: &Intersection,
+        albedo: &FVec,
+        light: &LightSource,
+        ray: &Ray,
+    ) -> FVec {
+        let coeff = lambert(&intersection.normal, &ray.direction);
+        coeff * light.intensity * light.colour.component_mul(albedo)
+    }
+
+    pub(crate) fn _get_specular_lighting(
+        &self,
+        intersection: &Intersection,
+        material: &Material,
+        light: &LightSource,
+        ray: &Ray,
+    ) -> FVec {
+        let v = ray.origin - intersection.pos;
+        let coeff = blinn_phong(&intersection.normal, &ray.direction, &v, material.shine);
+        coeff * light.colour * light.intensity
+    }
+
+    pub(crate) fn _get_sheen_lighting(
+        &self,
+        intersection: &Intersection,
+        material: &Material,
+        light: &LightSource,
+        ray: &Ray,
+        to_viewer: &FVec,
+    ) -> FVec {
+        let coeff = sheen(
+            &intersection.normal,
+            &ray.direction,
+            to_viewer,
+            material.sheen_roughness,
+        );
+        coeff * light.intensity * light.colour.component_mul(&material.sheen)
+    }
+
+    /*
+    Highlights of layered paint: a glint where the flake at the point faces
+    halfway between the light and the viewer, and a sharp highlight on the
+    clearcoat over it.
+     */
+    pub(crate) fn _get_paint_lighting(
+        &self,
+        intersection: &Intersection,
+        material: &Material,
+        light: &LightSource,
+        ray: &Ray,
+        to_viewer: &FVec,
+    ) -> FVec {
+        let to_light = &ray.direction;
+        let facing = |normal: &FVec| normal.dot(to_light) > 0.0 && normal.dot(to_viewer) > 0.0;
+        let normal = &intersection.normal;
+        let mut colour = FVec::zeros();
+        if let Some(flakes) = &material.flakes {
+            let flake = flakes.normal(&intersection.pos, normal).filter(facing);
+            if let Some(flake) = flake {
+                colour += flakes.colour * blinn_phong(&flake, to_light, to_viewer, flakes.shine);
+            }
+        }
+        if material.clearcoat > 0.0 && facing(normal) {
+            let highlight = blinn_phong(normal, to_light, to_viewer, CLEARCOAT_SHINE);
+            colour += FVec::repeat(material.clearcoat_fresnel(normal.dot(to_viewer)) * highlight);
+        }
+        light.intensity * colour.component_mul(&light.colour)
+    }
+
+    pub(crate) fn _get_reflection(
+        &self,
+        intersection: &Intersection,
+        material: &Material,
+        ray: &Ray,
+        media: &MediumStack,
+        num_bounces: u8,
+        rng: &mut Rng,
+    ) -> FVec {
+        let reflectance = material.reflectance(ray.direction.normalize().dot(&intersection.normal));
+        if num_bounces > self.max_bounces || reflectance == 0.0 {
+            return FVec::zeros();
+        }
+        reflectance * self._get_mirror_colour(intersection, ray, media, num_bounces, rng)
+    }
+
+    /*
+    Light reflected by a physically based surface, along one direction drawn
+    from the microfacets facing it in proportion to their share of the
+    surface (Walter et al. 2007), plus the mirror reflection of any
+    clearcoat. The weight of the direction is the reflectance over its
+    probability, so rough surfaces blur their reflections on average over
+    the samples of a pixel.
+     */
+    pub(crate) fn _get_microfacet_reflection(
+        &self,
+        object: usize,
+        intersection: &Intersection,
+        ray: &Ray,
+        media: &MediumStack,
+        num_bounces: u8,
+        rng: &mut Rng,
+    ) -> FVec {
+        if num_bounces > self.max_bounces {
+            return FVec::zeros();
+        }
+        let material = &*self._get_material(object, intersection);
+        let normal = &intersection.normal;
+        let to_viewer = -ray.direction.normalize();
+        let coat = material.clearcoat_fresnel(to_viewer.dot(normal));
+        let mut colour = if coat > 0.0 {
+            coat * self._get_mirror_colour(intersection, ray, media, num_bounces, rng)
+        } else {
+            FVec::zeros()
+        };
+        let n_dot_v = normal.dot(&to_viewer);
+        if n_dot_v <= 0.0 {
+            return colour;
+        }
+        // Microfacet normal drawn with probability proportional to D(h) (n.h)
+        let alpha = material.alpha();
+        let local = ggx_normal(alpha, rng.next_float(), rng.next_float());
+        let (s, t) = coordinate_system(normal);
+        let half = s * local.x + t * local.y + normal * local.z;
+        let v_dot_h = to_viewer.dot(&half);
+        let direction = 2.0 * v_dot_h * half - to_viewer;
+        let n_dot_l = normal.dot(&direction);
+        if v_dot_h <= 0.0 || n_dot_l <= 0.0 {
+            return colour;
+        }
+        let uv = self.objects[object].texture_uv(intersection);
+        let albedo = self._get_albedo(object, intersection, material, uv);
+        let f0 = material.specular_colour(&albedo);
+        let fresnel = schlick_colour(v_dot_h, &f0);
+        let masking = 4.0 * n_dot_l * n_dot_v * smith_visibility(n_dot_l, n_dot_v, alpha);
+        let weight = fresnel.component_mul(&compensation(alpha, n_dot_v, &f0))
+            * (masking * v_dot_h / (n_dot_v * local.z));
+        let reflected_ray = Ray {
+            origin: intersection.offset_origin(&direction, self.ray_bias),
+            direction,
+            differential: None,
+            time: intersection.time,
+        };
+        let mut bounce_rng = rng.for_bounce(num_bounces + 1);
+        let reflected = self._after_scatter(Scatter::Specular, || {
+            self._get_ray_colour(&reflected_ray, 0.0, media, num_bounces + 1, &mut bounce_rng)
+        });
+        colour += weight.component_mul(&reflected);
+        colour
+    }
+
+    // Colour seen in the mirror direction, from within the same media
+    pub(crate) fn _get_mirror_colour(
+        &self,
+        intersection: &Intersection,
+        ray: &Ray,
+        media: &MediumStack,
+        num_bounces: u8,
+        rng: &mut Rng,
+    ) -> FVec {
+        let reflected_ray_direction = reflect(&ray.direction, &intersection.normal);
+        let reflected_ray = Ray {
+            origin: intersection.offset_origin(&reflected_ray_direction, self.ray_bias),
+            direction: reflected_ray_direction,
+            differential: self._get_reflected_differential(intersection, ray),
+            time: intersection.time,
+        };
+        let mut bounce_rng = rng.for_bounce(num_bounces + 1);
+        self._after_scatter(Scatter::Specular, || {
+            self._get_ray_colour(&reflected_ray, 0.0, media, num_bounces + 1, &mut bounce_rng)
+        })
+    }
+
+    /*
+    Light refracted through a transparent surface of the object, bending by
+    the ratio of the refractive indices on either side. Totally internally
+    reflected light is seen in the mirror direction instead.
+     */
+    pub(crate) fn _get_transmission(
+        &self,
+        object: usize,
+        intersection: &Intersection,
+        ray: &Ray,
+        media: &MediumStack,
+        num_bounces: u8,
+        rng: &mut Rng,
+    ) -> FVec {
+        let material = &*self._get_material(object, intersection);
+        if num_bounces > self.max_bounces || material.k_transmit == 0.0 {
+            return FVec::zeros();
+        }
+        let beyond = media.crossed(object, material);
+        let direction = ray.direction.normalize();
+        let normal = if direction.dot(&intersection.normal) > 0.0 {
+            -intersection.normal
+        } else {
+            intersection.normal
+        };
+        let eta = media.ior() / beyond.ior();
+        let Some(refracted) = refract(&direction, &normal, eta) else {
+            let mirror = self._get_mirror_colour(intersection, ray, media, num_bounces, rng);
+            return material.k_transmit * mirror;
+        };
+        let transmittance = material.transmittance(direction.dot(&normal));
+        let refracted_ray = Ray {
+            origin: intersection.offset_origin(&refracted, self.ray_bias),
+            direction: refracted,
+            differential: self._get_refracted_differential(intersection, ray, &normal, eta),
+            time: intersection.time,
+        };
+        let mut bounce_rng = rng.for_bounce(num_bounces + 1);
+        let colour = self._after_scatter(Scatter::Specular, || {
+            self._get_ray_colour(
+                &refracted_ray,
+                0.0,
+                &beyond,
+                num_bounces + 1,
+                &mut bounce_rng,
+            )
+        });
+        let tint = self._get_thin_wall_tint(object, intersection, material);
+        transmittance * colour.component_mul(&tint)
+    }
+
+    // Filter on light passing through a thin-walled surface: its colour there; none otherwise
+    pub(crate) fn _get_thin_wall_tint(
+        &self,
+        object: usize,
+        intersection: &Intersection,
+        material: &Material,
+    ) -> FVec {
+        if !material.thin_walled {
+            return FVec::repeat(1.0);
+        }
+        let uv = self.objects[object].texture_uv(intersection);
+        self._get_albedo(object, intersection, material, uv)
+    }
+
+    /*
+    Continue a ray through a surface that lies inside a transparent volume of
+    higher priority, as if the surface were not there.
+     */
+    pub(crate) fn _get_passed_through_colour(
+        &self,
+        object: usize,
+        intersection: &Intersection,
+        ray: &Ray,
+        media: &MediumStack,
+        num_bounces: u8,
+        rng: &mut Rng,
+    ) -> FVec {
+        if num_bounces > self.max_bounces {
+            return FVec::zeros();
+        }
+        let beyond = media.crossed(object, &self.objects[object].material);
+        let continued_ray = Ray {
+            origin: intersection.offset_origin(&ray.direction, self.ray_bias),
+            direction: ray.direction,
+            differential: ray.differential,
+            time: intersection.time,
+        };
+        self._get_ray_colour(&continued_ray, 0.0, &beyond, num_bounces + 1, rng)
+    }
+
+    // Continue a ray straight through a face that dissolves, staying in the same media
+    pub(crate) fn _get_see_through_colour(
+        &self,
+        intersection: &Intersection,
+        ray: &Ray,
+        media: &MediumStack,
+        num_bounces: u8,
+        rng: &mut Rng,
+    ) -> FVec {
+        if num_bounces > self.max_bounces {
+            return FVec::zeros();
+        }
+        let continued_ray = Ray {
+            origin: intersection.offset_origin(&ray.direction, self.ray_bias),
+            direction: ray.direction,
+            differential: ray.differential,
+            time: intersection.time,
+        };
+        let mut bounce_rng = rng.for_bounce(num_bounces + 1);
+        self._get_ray_colour(&continued_ray, 0.0, media, num_bounces + 1, &mut bounce_rng)
+    }
+
+    pub(crate) fn _get_reflected_differential(
+        &self,
+        intersection: &Intersection,
+        ray: &Ray,
+    ) -> Option<RayDifferential> {
+        let differential = ray.differential.as_ref()?;
+        let surface = intersection.differentials.as_ref()?;
+        let (n, d) = (&intersection.normal, &ray.direction);
+        Some(RayDifferential {
+            rx_origin: intersection.pos + surface.dpdx,
+            rx_direction: reflect_differential(d, n, &differential.rx_direction, &surface.dndx),
+            ry_origin: intersection.pos + surface.dpdy,
+            ry_direction: reflect_differential(d, n, &differential.ry_direction, &surface.dndy),
+        })
+    }
+
+    // Differentials of a ray refracted about the normal facing it, as for reflection
+    pub(crate) fn _get_refracted_differential(
+        &self,
+        intersection: &Intersection,
+        ray: &Ray,
+        normal: &FVec,
+        eta: Float,
+    ) -> Option<RayDifferential> {
+        let differential = ray.differential.as_ref()?;
+        let surface = intersection.differentials.as_ref()?;
+        // The normal's change turns with it when the ray arrives from inside
+        let sign = normal.dot(&intersection.normal).signum();
+        let d = ray.direction.normalize();
+        let offset = |dd: &FVec, dn: &FVec| {
+            refract_differential(&d, normal, eta, &dd.normalize(), &(dn * sign))
+        };
+        Some(RayDifferential {
+            rx_origin: intersection.pos + surface.dpdx,
+            rx_direction: offset(&differential.rx_direction, &surface.dndx)?,
+            ry_origin: intersection.pos + surface.dpdy,
+            ry_direction: offset(&differential.ry_direction, &surface.dndy)?,
+        })
+    }
+
+    pub(crate) fn _is_within_cutoff(
+        &self,
+        intersection: &Intersection,
+        light: &LightSource,
+    ) -> bool {
+        light.is_distant()
+            || light
+                .cutoff_radius
+                .is_none_or(|r| (light.centre() - intersection.pos).norm_squared() <= r * r)
+    }
+
+    // Rough contribution of a light at a point, used to pick which lights to sample
+    pub(crate) fn _get_light_importance(
+        &self,
+        intersection: &Intersection,
+        light: &LightSource,
+    ) -> Float {
+        if !self._is_within_cutoff(intersection, light) {
+            return 0.0;
+        }
+        let power = light.intensity * light.colour.sum() / 3.0;
+        power * light.attenuation((light.centre() - intersection.pos).norm())
+    }
+
+    /*
+    Lights to shade a point with, paired with the weight of their contribution.
+    With light sampling enabled, lights are drawn with probability proportional
+    to their power over squared distance and weighted by 1 / (n * p), so the
+    estimate stays unbiased while only n shadow rays are traced.
+     */
+    pub(crate) fn _get_sampled_lights(
+        &self,
+        object: usize,
+        intersection: &Intersection,
+        rng: &mut Rng,
+    ) -> Vec<(&LightSource, Float)> {
+        let num_samples = match self.light_samples {
+            Some(n) if (n as usize) < self.lights.len() => n,
+            _ => {
+                return self
+                    .lights
+                    .iter()
+                    .filter(|light| light.lights_object(object))
+                    .filter(|light| self._is_within_cutoff(intersection, light))
+                    .map(|light| (light, 1.0))
+                    .collect()
+            }
+        };
+        let importances: Vec<Float> = self
+            .lights
+            .iter()
+            .map(|light| {
+                if light.lights_object(object) {
+                    self._get_light_importance(intersection, light)
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+        let total: Float = importances.iter().sum();
+        if total <= 0.0 || num_samples == 0 {
+            return vec![];
+        }
+        (0..num_samples)
+            .map(|_| {
+                let target = rng.next_float() * total;
+                let mut cumulative = 0.0;
+                let index = importances
+                    .iter()
+                    .position(|importance| {
+                        cumulative += importance;
+                        cumulative > target
+                    })
+                    .unwrap_or(importances.len() - 1);
+                let probability = importances[index] / total;
+                (
+                    &self.lights[index],
+                    1.0 / (num_samples as Float * probability),
+                )
+            })
+            .collect()
+    }
+
+    // Direct light arriving from one sample of a light, or nothing if it is shadowed
+    pub(crate) fn _get_light_sample_colour(
+        &self,
+        intersection: &Intersection,
+        material: &Material,
+        albedo: &FVec,
+        light: &LightSource,
+        sample: &LightSample,
+        to_viewer: &FVec,
+    ) -> FVec {
+        let Some((ray, passed)) = self._get_light_ray(intersection, light, sample) else {
+            return FVec::zeros();
+        };
+        let reflected =
+            self._get_light_reflected(intersection, material, albedo, light, &ray, to_viewer);
+        reflected.component_mul(&passed)
+    }
+
+    /*
+    Ray from the point towards one sample of a light and the share of the
+    light's light arriving along it after falloff and any filter, or nothing
+    if it is shadowed.
+     */
+    pub(crate) fn _get_light_ray(
+        &self,
+        intersection: &Intersection,
+        light: &LightSource,
+        sample: &LightSample,
+    ) -> Option<(Ray, FVec)> {
+        let (origin, direction, distance_to_light, falloff) = match sample {
+            LightSample::Point(light_pos) => {
+                let to_light = light_pos - intersection.pos;
+                let origin = intersection.offset_origin(&to_light, self.ray_bias);
+                let point_to_light = light_pos - origin;
+                let distance = point_to_light.norm();
+                let falloff = light.attenuation((light_pos - intersection.pos).norm())
+                    * light.sample_weight(&intersection.pos, light_pos);
+                (origin, point_to_light / distance, distance, falloff)
+            }
+            LightSample::Distant(direction) => (
+                intersection.offset_origin(direction, self.ray_bias),
+                *direction,
+                Float::INFINITY,
+                1.0,
+            ),
+        };
+        let filter = light.filter(&-direction);
+        if filter == FVec::zeros() {
+            return None;
+        }
+        let ray = Ray {
+            origin,
+            direction,
+            differential: None,
+            time: intersection.time,
+        };
+        let passed = self._get_shadow_transmission(&ray, distance_to_light)?;
+        Some((ray, falloff * filter.component_mul(&passed)))
+    }
+
+    /*
+    Share of the light along a shadow ray that reaches the given distance, or
+    None if something opaque stops it. With transparent shadows the ray is
+    continued past each transparent surface it meets, in whatever order they
+    come, taking on each one's transmittance and the absorption of the media
+    it crosses between them, until it reaches the light or too little is
+    left to count.
+     */
+    pub(crate) fn _get_shadow_transmission(&self, ray: &Ray, distance: Float) -> Option<FVec> {
+        if !self.transparent_shadows {
+            return (!self._is_occluded(ray, distance)).then_some(FVec::repeat(1.0));
+        }
+        let mut passed = FVec::repeat(1.0);
+        let mut media = MediumStack::default();
+        let mut origin = ray.origin;
+        let mut left = distance;
+        for _ in 0..=self.max_bounces {
+            stats::add(Counter::ShadowRays, 1);
+            let segment = Ray {
+                origin,
+                direction: ray.direction,
+                differential: None,
+                time: ray.time,
+            };
+            let stops =
+                |object: usize, hit: &Intersection| self._stops_shadow(&segment, object, hit);
+            let hit = self.primitives.nearest(&segment, 0.0, None, stops);
+            let travelled = hit.as_ref().map_or(left, |(_, hit)| hit.t.min(left));
+            if media.absorption() != FVec::zeros() {
+                passed.component_mul_assign(&(-media.absorption() * travelled).map(Float::exp));
+            }
+            let Some((object, hit)) = hit.filter(|(_, hit)| hit.t < left) else {
+                return Some(passed);
+            };
+            let material = &*self._get_material(object, &hit);
+            if material.k_transmit == 0.0 || material.shadow_opacity.is_some() {
+                return None;
+            }
+            if media.is_interface(object, material.priority) {
+                let cos_theta = ray.direction.dot(&hit.normal).abs();
+                let tint = self._get_thin_wall_tint(object, &hit, material);
+                passed = passed.component_mul(&tint) * material.transmittance(cos_theta);
+            }
+            if passed.max() < MIN_SHADOW_TRANSMISSION {
+                return None;
+            }
+            media = media.crossed(object, material);
+            origin = hit.offset_origin(&ray.direction, self.ray_bias);
+            left -= (origin - segment.origin).norm();
+        }
+        None
+    }
+
+    /*
+    Direct light from one sample of a light. Sphere lights on a physically
+    based surface are also sampled from its highlight, picked by u[2], which
+    sphere lights otherwise leave unused; see Lobe. Either way the sample is
+    weighted by the light's own probability of drawing it over that of
+    drawing it either way.
+     */
+    pub(crate) fn _get_direct_sample(
+        &self,
+        intersection: &Intersection,
+        material: &Material,
+        albedo: &FVec,
+        light: &LightSource,
+        u: [Float; 3],
+        to_viewer: &FVec,
+    ) -> FVec {
+        let pos = &intersection.pos;
+        let lobe = Lobe::of(material, albedo);
+        let (Some(lobe), Some(solid_angle)) = (lobe, light.solid_angle(pos)) else {
+            let sample = light.sample(pos, u);
+            return self._get_light_sample_colour(
+                intersection,
+                material,
+                albedo,
+                light,
+                &sample,
+                to_viewer,
+            );
+        };
+        let normal = &intersection.normal;
+        let share = lobe.share(normal, to_viewer, solid_angle);
+        let sample = if u[2] < share {
+            let direction = lobe.sample(normal, to_viewer, u[0], u[1]);
+            match direction.and_then(|direction| light.sample_along(pos, &direction)) {
+                Some(sample) => sample,
+                None => return FVec::zeros(),
+            }
+        } else {
+            light.sample(pos, u)
+        };
+        let LightSample::Point(point) = sample else {
+            unreachable!("sphere lights are sampled at points");
+        };
+        let direction = (point - pos).normalize();
+        let colour = self._get_light_sample_colour(
+            intersection,
+            material,
+            albedo,
+            light,
+            &sample,
+            to_viewer,
+        );
+        let lobe_pdf = lobe.pdf(normal, to_viewer, &direction);
+        colour / (1.0 - share + share * lobe_pdf * solid_angle)
+    }
+
+    /*
+    Light of a light arriving along the unshadowed ray and reflected towards
+    the viewer, before it falls off with distance.
+     */
+    pub(crate) fn _get_light_reflected(
+        &self,
+        intersection: &Intersection,
+        material: &Material,
+        albedo: &FVec,
+        light: &LightSource,
+        ray: &Ray,
+        to_viewer: &FVec,
+    ) -> FVec {
+        let direction = ray.direction;
+        // Translucent surfaces give off diffuse light where it leaves them instead
+        let diffuse_light = match material.subsurface {
+            Some(_) => FVec::zeros(),
+            None => {
+                material.diffuse_weight()
+                    * self._get_diffuse_lighting(intersection, albedo, light, ray)
+            }
+        };
+        let specular_reflectance = match material.metallic {
+            // The highlight of a physically based surface, in the colour of the surface for metals
+            Some(_) => {
+                let f0 = material.specular_colour(albedo);
+                let alpha = material.alpha();
+                let normal = &intersection.normal;
+                let reflected = ggx(normal, &direction, to_viewer, alpha, &f0)
+                    .component_mul(&compensation(alpha, normal.dot(to_viewer), &f0));
+                light.intensity * reflected.component_mul(&light.colour)
+            }
+            None => {
+                material.k_specular
+                    * self._get_specular_lighting(intersection, material, light, ray)
+            }
+        };
+        let sheen = if material.sheen == FVec::zeros() {
+            FVec::zeros()
+        } else {
+            self._get_sheen_lighting(intersection, material, light, ray, to_viewer)
+        };
+        let paint = if material.flakes.is_none() && material.clearcoat == 0.0 {
+            FVec::zeros()
+        } else {
+            self._get_paint_lighting(intersection, material, light, ray, to_viewer)
+        };
+        diffuse_light + specular_reflectance + sheen + paint
+    }
+
+    // Light from the objects that give off light other than the one shaded; see Emitter
+    pub(crate) fn _get_emitter_lighting(
+        &self,
+        object: usize,
+        intersection: &Intersection,
+        material: &Material,
+        albedo: &FVec,
+        to_viewer: &FVec,
+        rng: &mut Rng,
+    ) -> FVec {
+        let emitters = self
+            .emitters
+            .iter()
+            .filter(|emitter| emitter.object != object);
+        emitters
+            .map(|emitter| {
+                let num_samples = emitter.light.num_samples();
+                let total: FVec = (0..num_samples)
+                    .map(|_| {
+                        let (u1, u2) = (rng.next_float(), rng.next_float());
+                        let (direction, solid_angle) = emitter.sample(&intersection.pos, u1, u2);
+                        let ray = Ray {
+                            origin: intersection.offset_origin(&direction, self.ray_bias),
+                            direction,
+                            differential: None,
+                            time: intersection.time,
+                        };
+                        // Reaching the object first is also what shows it is not shadowed
+                        stats::add(Counter::ShadowRays, 1);
+                        let stops = |object: usize, hit: &Intersection| {
+                            object == emitter.object || self._stops_shadow(&ray, object, hit)
+                        };
+                        match self.primitives.nearest(&ray, 0.0, None, stops) {
+                            Some((hit, _)) if hit == emitter.object => {
+                                let light = &emitter.light;
+                                let reflected = self._get_light_reflected(
+                                    intersection,
+                                    material,
+                                    albedo,
+                                    light,
+                                    &ray,
+                                    to_viewer,
+                                );
+                                solid_angle / PI * reflected
+                            }
+                            _ => FVec::zeros(),
+                        }
+                    })
+                    .sum();
+                total / num_samples as Float
+            })
+            .sum()
+    }
+
+    // Ambient light reaching a surface facing along the unit normal
+    pub(crate) fn _get_ambient_light(&self, normal: &FVec) -> FVec {
+        match (&self.irradiance, &self.ambient_gradient) {
+            (Some(irradiance), _) => irradiance.at(normal),
+            (None, Some(gradient)) => gradient.at(normal),
+            (None, None) => self.ambient_light,
+        }
+    }
+
+    pub(crate) fn _get_surface_point_colour(
+        &self,
+        object: usize,
+        intersection: &Intersection,
+        material: &Material,
+        albedo: &FVec,
+        ray: &Ray,
+        rng: &mut Rng,
+    ) -> FVec {
+        let to_viewer = -ray.direction.normalize();
+        // The path integrator gathers this light from the scene instead
+        let ambient = match self.integrator {
+            Integrator::Whitted | Integrator::Plugin(..) => {
+                let ambient_light = self._get_ambient_light(&intersection.normal);
+                let ambient = material.ambient_weight() * ambient_light.component_mul(albedo);
+                match &self.ambient_occlusion {
+                    Some(occlusion) if ambient != FVec::zeros() => {
+                        ambient * self._get_unoccluded_fraction(intersection, ray, occlusion, rng)
+                    }
+                    _ => ambient,
+                }
+            }
+            Integrator::Path | Integrator::AmbientOcclusion => FVec::zeros(),
+        };
+        let strata = rng.take_strata();
+        let light_dependent_colouring: FVec = self
+            ._get_sampled_lights(object, intersection, rng)
+            .into_iter()
+            .zip(0u32..)
+            .map(|((light, weight), draw)| {
+                let num_samples = light.num_samples();
+                /*
+                Shadow samples follow a Halton sequence scrambled per shading
+                point. Seen directly, each sample of the pixel takes the next
+                stretch of a sequence shared by the pixel, so the points of all
+                its samples stratify the light together, shifted by the
+                pixel's blue-noise offset.
+                 */
+                let (scramble, first, offset) = match strata {
+                    Some(strata) => (
+                        strata.light_scramble ^ draw.wrapping_mul(0x9e37_79b9),
+                        strata.sample.wrapping_mul(num_samples),
+                        strata.light_offset,
+                    ),
+                    None => (rng.next_u32(), 0, [0.0; 3]),
+                };
+                let sequences: Vec<_> = (0..light.sample_dimensions())
+                    .map(|d| ScrambledHalton::new(d, scramble))
+                    .collect();
+                let total: FVec = (0..num_samples)
+                    .map(|i| {
+                        let i = first.wrapping_add(i);
+                        let mut u = [0.0; 3];
+                        for (d, sequence) in sequences.iter().enumerate() {
+                            u[d] = (sequence.get(i) + offset[d]).fract();
+                        }
+                        self._get_direct_sample(
+                            intersection,
+                            material,
+                            albedo,
+                            light,
+                            u,
+                            &to_viewer,
+                        )
+                    })
+                    .sum();
+                weight / num_samples as Float * total
+            })
+            .sum();
+        // The path integrator finds emitting objects by bouncing off the surface instead
+        let emitted = match self.integrator {
+            Integrator::Whitted | Integrator::Plugin(..) => {
+                self._get_emitter_lighting(object, intersection, material, albedo, &to_viewer, rng)
+            }
+            Integrator::Path | Integrator::AmbientOcclusion => FVec::zeros(),
+        };
+        let caustics = match &self.photons {
+            Some(photons) => {
+                let irradiance = photons.irradiance(&intersection.pos, &intersection.normal);
+                irradiance.component_mul(&material.diffuse_colour(albedo))
+            }
+            None => FVec::zeros(),
+        };
+        let subsurface = match &material.subsurface {
+            Some(subsurface) => {
+                self._get_subsurface_colour(object, intersection, material, albedo, subsurface, rng)
+            }
+            None => FVec::zeros(),
+        };
+        ambient + light_dependent_colouring + emitted + caustics + subsurface
+    }
+
+    // Trace on from a surface that scattered the path as the layer's light paths follow it
+    pub(crate) fn _after_scatter<T>(&self, scatter: Scatter, trace: impl FnOnce() -> T) -> T {
+        match &self.light_paths {
+            Some(light_paths) => light_paths.after(scatter, trace),
+            None => trace(),
+        }
+    }
+
+    pub(crate) fn _light_path_keeps(&self, scatters: &[Scatter]) -> bool {
+        self.light_paths
+            .as_ref()
+            .is_none_or(|paths| paths.keeps(scatters))
+    }
+
+    pub(crate) fn _light_path_viable(&self) -> bool {
+        self.light_paths
+            .as_ref()
+            .is_none_or(LightPaths::leads_anywhere)
+    }
+
+    pub(crate) fn _get_ray_colour(
+        &self,
+        ray: &Ray,
+        min_distance: Float,
+        media: &MediumStack,
+        num_bounces: u8,
+        rng: &mut Rng,
+    ) -> FVec {
+        if self.pass.is_some_and(|pass| !pass.reaches(num_bounces)) || !self._light_path_viable() {
+            return FVec::zeros();
+        }
+        stats::add(Counter::ReflectionRays, 1);
+        let hit = self._get_nearest_hit(ray, min_distance, None);
+        let hit = hit.as_ref().map(|(object, i)| (*object, i));
+        self._get_hit_colour(ray, hit, media, num_bounces, rng)
+    }
+
+    pub(crate) fn _get_hit_colour(
+        &self,
+        ray: &Ray,
+        hit: Option<(usize, &Intersection)>,
+        media: &MediumStack,
+        num_bounces: u8,
+        rng: &mut Rng,
+    ) -> FVec {
+        if let (Some(pass @ (Pass::Reflection | Pass::Refraction)), 0) = (self.pass, num_bounces) {
+            return self._get_specular_pass_colour(pass == Pass::Reflection, ray, hit, media, rng);
+        }
+        // Light scattered towards the ray's origin here, rather than further along, in the pass
+        let in_pass = self.pass.is_none_or(|pass| pass.counts_bounce(num_bounces));
+        // Light sent along the ray, and light off the surface, on a path the layer keeps
+        let counted = in_pass && self._light_path_keeps(&[]);
+        let lit = in_pass && self._light_path_keeps(&[Scatter::Diffuse]);
+        let own = |light: FVec| if counted { light } else { FVec::zeros() };
+        let colour = hit
+            .map(|(object, i)| {
+                let m = &*self._get_material(object, i);
+                if m.k_transmit > 0.0 && !media.is_interface(object, m.priority) {
+                    return self._get_passed_through_colour(
+                        object,
+                        i,
+                        ray,
+                        media,
+                        num_bounces,
+                        rng,
+                    );
+                }
+                let uv = self.objects[object].texture_uv(i);
+                let shaded = self._get_shaded_intersection(object, i, m, uv, ray);
+                let i = &*shaded;
+                let albedo = self._get_albedo(object, i, m, uv);
+                let object_colour = self._get_surface_point_colour(object, i, m, &albedo, ray, rng);
+                let bounced = match self.integrator {
+                    Integrator::Whitted | Integrator::Plugin(..) | Integrator::AmbientOcclusion => {
+                        self._get_scattered_colour(object, i, ray, media, num_bounces, rng)
+                    }
+                    Integrator::Path => {
+                        let diffuse = m.diffuse_colour(&albedo);
+                        let scattered = |rng: &mut Rng| {
+                            self._get_scattered_colour(object, i, ray, media, num_bounces, rng)
+                        };
+                        let indirect = |rng: &mut Rng| {
+                            self._get_indirect_diffuse(i, diffuse, ray, media, num_bounces, rng)
+                        };
+                        let cos_theta = ray.direction.normalize().dot(&i.normal);
+                        match specular_chance(m, cos_theta, &diffuse, num_bounces) {
+                            None => scattered(rng) + indirect(rng),
+                            Some(chance) if rng.next_float() < chance => scattered(rng) / chance,
+                            Some(chance) => indirect(rng) / (1.0 - chance),
+                        }
+                    }
+                };
+                let object_colour = if lit { object_colour } else { FVec::zeros() };
+                let mut colour = object_colour + own(m.emission) + bounced;
+                if let Some(dissolve) = &i.dissolve {
+                    let behind = self._get_see_through_colour(i, ray, media, num_bounces, rng);
+                    colour = colour * dissolve.opacity
+                        + (1.0 - dissolve.opacity) * dissolve.filter.component_mul(&behind);
+                }
+                match &self.atmosphere {
+                    Some(atmosphere) => {
+                        let (haze, transmittance) = atmosphere.aerial_haze(&ray.origin, &i.pos);
+                        colour.component_mul(&transmittance) + own(haze)
+                    }
+                    None => colour,
+                }
+            })
+            .unwrap_or_else(|| own(self._get_background(ray)));
+        let colour = self._get_through_local_fog(ray, hit, colour, media, counted, rng);
+        let colour = self._get_through_medium(ray, hit, colour, media, counted, rng);
+        self._get_through_volumes(ray, hit, colour, counted, rng)
+    }
+
+    /*
+    The hit with its normal bent by the material's maps and waves, and back
+    towards the viewer where they or interpolation turn it away, before any
+    light is reflected.
+     */
+    pub(crate) fn _get_shaded_intersection<'a>(
+        &self,
+        object: usize,
+        intersection: &'a Intersection,
+        material: &Material,
+        uv: (Float, Float),
+        ray: &Ray,
+    ) -> Cow<'a, Intersection> {
+        let bent = self._get_shading_normal(object, intersection, material, uv);
+        let facing = self._get_facing_normal(intersection, bent.as_ref(), ray);
+        match facing.or(bent) {
+            Some(normal) => Cow::Owned(Intersection {
+                normal,
+                geometric_normal: Some(
+                    intersection.geometric_normal.unwrap_or(intersection.normal),
+                ),
+                ..intersection.clone()
+            }),
+            None => Cow::Borrowed(intersection),
+        }
+    }
+
+    // The shading normal turned back towards the ray's origin if needed; see facing_normal
+    pub(crate) fn _get_facing_normal(
+        &self,
+        intersection: &Intersection,
+        bent: Option<&FVec>,
+        ray: &Ray,
+    ) -> Option<FVec> {
+        let normal = bent.unwrap_or(&intersection.normal);
+        let geometric = intersection.geometric_normal.unwrap_or(intersection.normal);
+        facing_normal(normal, &geometric, &-ray.direction.normalize())
+    }
+
+    /*
+    The reflected or else the refracted light of the first surface along a
+    primary ray, dimmed where the surface dissolves but before any fog or
+    volumes between it and the camera. Surfaces the camera sits inside a
+    volume of higher priority than are looked past, as in the full render.
+     */
+    pub(crate) fn _get_specular_pass_colour(
+        &self,
+        reflection: bool,
+        ray: &Ray,
+        hit: Option<(usize, &Intersection)>,
+        media: &MediumStack,
+        rng: &mut Rng,
+    ) -> FVec {
+        let Some((object, i)) = hit else {
+            return FVec::zeros();
+        };
+        let m = &*self._get_material(object, i);
+        if m.k_transmit > 0.0 && !media.is_interface(object, m.priority) {
+            let beyond = media.crossed(object, m);
+            let continued_ray = Ray {
+                origin: i.offset_origin(&ray.direction, self.ray_bias),
+                ..*ray
+            };
+            let hit = self._get_nearest_hit(&continued_ray, 0.0, None);
+            let hit = hit.as_ref().map(|(object, i)| (*object, i));
+            return self._get_specular_pass_colour(reflection, &continued_ray, hit, &beyond, rng);
+        }
+        let uv = self.objects[object].texture_uv(i);
+        let i = &*self._get_shaded_intersection(object, i, m, uv, ray);
+        let colour = match (reflection, m.metallic) {
+            (true, Some(_)) => self._get_microfacet_reflection(object, i, ray, media, 0, rng),
+            (true, None) => self._get_reflection(i, m, ray, media, 0, rng),
+            (false, _) => self._get_transmission(object, i, ray, media, 0, rng),
+        };
+        match &i.dissolve {
+            Some(dissolve) => dissolve.opacity * colour,
+            None => colour,
+        }
+    }
+
+    /*
+    Light along a ray after the grid volumes it crosses before the hit, the
+    furthest first. Overlapping volumes are each drawn whole, the nearer over
+    the further, rather than blended where they overlap. The volumes' own
+    light is only added when glowing.
+     */
+    pub(crate) fn _get_through_volumes(
+        &self,
+        ray: &Ray,
+        hit: Option<(usize, &Intersection)>,
+        colour: FVec,
+        glowing: bool,
+        rng: &mut Rng,
+    ) -> FVec {
+        if self.volumes.is_empty() {
+            return colour;
+        }
+        let distance = hit.map_or(Float::INFINITY, |(_, i)| (i.pos - ray.origin).norm());
+        let mut volumes: Vec<_> = self.volumes.iter().collect();
+        let entry = |volume: &GridVolume| volume.entry(ray).unwrap_or(Float::INFINITY);
+        volumes.sort_by(|a, b| entry(b).total_cmp(&entry(a)));
+        volumes.into_iter().fold(colour, |behind, volume| {
+            volume.march(ray, distance, behind, glowing, rng)
+        })
+    }
+
+    /*
+    Light along a ray after the fog volumes it crosses before the hit, taken
+    like the grid volumes from the furthest in, each dimming what is behind
+    it and, when inscattering, adding the light it scatters along its stretch
+    of the ray. Rays inside objects are left as they are.
+     */
+    pub(crate) fn _get_through_local_fog(
+        &self,
+        ray: &Ray,
+        hit: Option<(usize, &Intersection)>,
+        colour: FVec,
+        media: &MediumStack,
+        inscattering: bool,
+        rng: &mut Rng,
+    ) -> FVec {
+        if self.fog_volumes.is_empty() || !media.is_empty() {
+            return colour;
+        }
+        let distance = hit.map_or(Float::INFINITY, |(_, i)| (i.pos - ray.origin).norm());
+        let mut crossed: Vec<_> = self
+            .fog_volumes
+            .iter()
+            .filter_map(|fog| Some((fog.entry_exit(ray, distance)?, fog.fog.volume())))
+            .collect();
+        crossed.sort_by(|((a, _), _), ((b, _), _)| b.total_cmp(a));
+        let direction = ray.direction.normalize();
+        crossed
+            .into_iter()
+            .fold(colour, |behind, ((near, far), volume)| {
+                let dimmed = behind.component_mul(&volume.transmittance(far - near));
+                if !inscattering || volume.extinction() == FVec::zeros() {
+                    return dimmed;
+                }
+                let stretch = Ray {
+                    origin: ray.origin + direction * near,
+                    ..*ray
+                };
+                dimmed + self._get_inscattered_colour(&stretch, far - near, &volume, media, rng)
+            })
+    }
+
+    /*
+    Light along a ray after the space it crossed to the hit, or out of the
+    scene: the volume of the object the ray is inside, or else the fog, dims
+    it by Beer's law and, when inscattering, adds the light it scatters
+    towards the viewer.
+     */
+    pub(crate) fn _get_through_medium(
+        &self,
+        ray: &Ray,
+        hit: Option<(usize, &Intersection)>,
+        colour: FVec,
+        media: &MediumStack,
+        inscattering: bool,
+        rng: &mut Rng,
+    ) -> FVec {
+        let volume = match (&self.fog, hit) {
+            (Some(fog), _) if media.is_empty() => fog.volume(),
+            // Rays leaving an object through a gap in its surface are left as they are
+            (_, Some(_)) if !media.is_empty() => Volume {
+                absorption: media.absorption(),
+                scattering: media.scattering(),
+                anisotropy: 0.0,
+                steps: VOLUME_STEPS,
+            },
+            _ => return colour,
+        };
+        if volume.extinction() == FVec::zeros() {
+            return colour;
+        }
+        let distance = hit.map_or(Float::INFINITY, |(_, i)| (i.pos - ray.origin).norm());
+        let dimmed = colour.component_mul(&volume.transmittance(distance));
+        if !inscattering {
+            return dimmed;
+        }
+        dimmed + self._get_inscattered_colour(ray, distance, &volume, media, rng)
+    }
+
+    /*
+    Light scattered towards the viewer by the volume along the ray up to the
+    distance. The march takes even steps from a random start, each lit by a
+    sample of every light it can see. The surfaces of the objects the ray is
+    inside cast no shadow there, as if light crossed them unbent.
+     */
+    pub(crate) fn _get_inscattered_colour(
+        &self,
+        ray: &Ray,
+        distance: Float,
+        volume: &Volume,
+        media: &MediumStack,
+        rng: &mut Rng,
+    ) -> FVec {
+        let ambient = match self.integrator {
+            Integrator::Whitted | Integrator::Plugin(..) => {
+                let ambient_light = match &self.ambient_gradient {
+                    Some(gradient) => gradient.mean(),
+                    None => self.ambient_light,
+                };
+                volume.ambient(&ambient_light, distance)
+            }
+            Integrator::Path | Integrator::AmbientOcclusion => FVec::zeros(),
+        };
+        if volume.steps == 0 || volume.scattering == FVec::zeros() || self.lights.is_empty() {
+            return ambient;
+        }
+        let mask = (!media.is_empty()).then(|| {
+            let mut mask = vec![true; self.objects.len()];
+            media.objects().for_each(|object| mask[object] = false);
+            mask
+        });
+        let direction = ray.direction.normalize();
+        let step = volume.march_length(distance) / volume.steps as Float;
+        let start = rng.next_float();
+        let mut lit = FVec::zeros();
+        for k in 0..volume.steps {
+            let t = (k as Float + start) * step;
+            let pos = ray.origin + direction * t;
+            let mut arriving = FVec::zeros();
+            for light in &self.lights {
+                let sample = self._get_light_in_space(&pos, light, mask.as_deref(), ray.time, rng);
+                if let Some((to_light, light_colour)) = sample {
+                    arriving += volume.phase(direction.dot(&to_light)) * light_colour;
+                }
+            }
+            lit += volume.transmittance(t).component_mul(&arriving);
+        }
+        ambient + volume.scattering.component_mul(&lit) * step
+    }
+
+    /*
+    Light from one sample of the light reaching a point in space, with the
+    unit direction towards it, or None if it is shadowed. Only objects whose
+    entry in the mask is true, if one is given, cast shadows.
+     */
+    pub(crate) fn _get_light_in_space(
+        &self,
+        pos: &FVec,
+        light: &LightSource,
+        mask: Option<&[bool]>,
+        time: Float,
+        rng: &mut Rng,
+    ) -> Option<(FVec, FVec)> {
+        let cut_off = |r: Float| (light.centre() - pos).norm_squared() > r * r;
+        if !light.is_distant() && light.cutoff_radius.is_some_and(cut_off) {
+            return None;
+        }
+        let u = [rng.next_float(), rng.next_float(), rng.next_float()];
+        let (direction, distance, falloff) = match light.sample(pos, u) {
+            LightSample::Point(light_pos) => {
+                let to_light = light_pos - pos;
+                let distance = to_light.norm();
+                let falloff = light.attenuation(distance) * light.sample_weight(pos, &light_pos);
+                (to_light / distance, distance, falloff)
+            }
+            LightSample::Distant(direction) => (direction, Float::INFINITY, 1.0),
+        };
+        let filter = light.filter(&-direction);
+        if filter == FVec::zeros() {
+            return None;
+        }
+        let ray = Ray {
+            origin: *pos,
+            direction,
+            differential: None,
+            time,
+        };
+        let shadowed = match mask {
+            Some(mask) => {
+                stats::add(Counter::ShadowRays, 1);
+                let stops =
+                    |object: usize, hit: &Intersection| self._stops_shadow(&ray, object, hit);
+                let hit = self.primitives.nearest(&ray, 0.0, Some(mask), stops);
+                hit.is_some_and(|(_, hit)| hit.t < distance)
+            }
+            None => self._is_occluded(&ray, distance),
+        };
+        if shadowed {
+            return None;
+        }
+        Some((
+            direction,
+            falloff * light.intensity * light.colour.component_mul(&filter),
+        ))
+    }
+
+    // Normal bent by the material's maps and waves, if it has any
+    pub(crate) fn _get_shading_normal(
+        &self,
+        object: usize,
+        intersection: &Intersection,
+        material: &Material,
+        uv: (Float, Float),
+    ) -> Option<FVec> {
+        let mut normal = None;
+        if material.normal_map.is_some() || material.bump_map.is_some() {
+            let shape = &self.objects[object].shape;
+            let (pos, flat) = (&intersection.pos, &intersection.normal);
+            let tangents = self.objects[object].uv_tangents(intersection);
+            normal = tangents.map(|t| material.shading_normal(shape, pos, flat, uv, t));
+        }
+        if let Some(waves) = &material.waves {
+            let time = self.frame as Float / self.frame_rate;
+            let flat = normal.unwrap_or(intersection.normal);
+            normal = Some(waves.normal(&intersection.pos, &flat, time));
+        }
+        normal
+    }
+
+    // Colour of the object's surface at the texture coordinates or point, varied for the object
+    pub(crate) fn _get_albedo(
+        &self,
+        object: usize,
+        intersection: &Intersection,
+        material: &Material,
+        uv: (Float, Float),
+    ) -> FVec {
+        let position = self.objects[object]
+            .shape
+            .object_position(&intersection.pos);
+        // Only images are filtered, so the footprint is not worked out for other textures
+        let footprint = material
+            .colour
+            .images()
+            .and_then(|_| self.objects[object].uv_differentials(intersection));
+        let mut albedo = material.colour.at(
+            uv,
+            footprint.as_ref(),
+            &position,
+            &intersection.normal,
+            intersection.vertex_colour,
+        );
+        if let Some(variation) = &material.variation {
+            albedo = variation.apply(albedo, self.objects[object].instance_seed);
+        }
+        let decals = self
+            .decals
+            .iter()
+            .filter(|decal| decal.covers_object(object));
+        decals.fold(albedo, |albedo, decal| {
+            decal.apply(albedo, &intersection.pos, &intersection.normal)
+        })
+    }
+
+    // An object's material with any expressions in it worked out at the hit
+    pub(crate) fn _get_material(
+        &self,
+        object: usize,
+        intersection: &Intersection,
+    ) -> Cow<'_, Material> {
+        let material = &self.objects[object].material;
+        if material.expressions.is_empty() {
+            return Cow::Borrowed(material);
+        }
+        let inputs = Inputs {
+            position: intersection.pos,
+            uv: self.objects[object].texture_uv(intersection),
+            time: self.frame as Float / self.frame_rate,
+            frame: self.frame as Float,
+            instance: self.objects[object].instance_seed,
+        };
+        material.at(&inputs)
+    }
+
+    // Colour of the environment, or else the sky, along a ray that hits nothing
+    pub(crate) fn _get_background(&self, ray: &Ray) -> FVec {
+        match (&self.environment, &self.atmosphere) {
+            (Some(environment), _) => environment.colour(&ray.direction),
+            (None, Some(atmosphere)) => atmosphere.sky(&ray.origin, &ray.direction),
+            (None, None) => self.default_colour,
+        }
+    }
+
+    /*
+    Fraction of the rays over the hemisphere facing the viewer, drawn in
+    proportion to the cosine with the normal as diffuse light arrives, that
+    reach the occlusion radius without hitting anything.
+     */
+    pub(crate) fn _get_unoccluded_fraction(
+        &self,
+        intersection: &Intersection,
+        ray: &Ray,
+        occlusion: &AmbientOcclusion,
+        rng: &mut Rng,
+    ) -> Float {
+        let normal = if intersection.normal.dot(&ray.direction) > 0.0 {
+            -intersection.normal
+        } else {
+            intersection.normal
+        };
+        let (u, v) = coordinate_system(&normal);
+        let scramble = rng.next_u32();
+        let sequences = [0, 1].map(|d| ScrambledHalton::new(d, scramble));
+        let open = (0..occlusion.samples)
+            .filter(|&i| {
+                let [a, b] = sequences.each_ref().map(|sequence| sequence.get(i));
+                let local = cosine_hemisphere(a, b);
+                let direction = local.x * u + local.y * v + local.z * normal;
+                let ray = Ray {
+                    origin: intersection.offset_origin(&direction, self.ray_bias),
+                    direction,
+                    differential: None,
+                    time: intersection.time,
+                };
+                !self._is_occluded(&ray, occlusion.radius)
+            })
+            .count();
+        open as Float / occlusion.samples.max(1) as Float
+    }
+
+    /*
+    Light reaching a diffuse surface from the rest of the scene, gathered along
+    one direction drawn in proportion to its cosine with the normal, which
+    cancels the cosine of the Lambert term. Some directions are drawn from the
+    path guide or towards the environment's bright parts instead, each
+    weighted by the ratio of the cosine's probability to that of the mixture
+    of the ways of drawing it. After the first few bounces paths
+    end at random, more often the darker the surface, and the surviving ones
+    are weighted up to make up for it.
+     */
+    pub(crate) fn _get_indirect_diffuse(
+        &self,
+        intersection: &Intersection,
+        diffuse: FVec,
+        ray: &Ray,
+        media: &MediumStack,
+        num_bounces: u8,
+        rng: &mut Rng,
+    ) -> FVec {
+        let normal = intersection.normal;
+        let mut weight = diffuse;
+        // Surfaces are lit on the side their normal faces, as with direct light
+        let facing = normal.dot(&ray.direction) < 0.0;
+        if num_bounces > self.max_bounces || weight.max() <= 0.0 || !facing {
+            return FVec::zeros();
+        }
+        if num_bounces >= ROULETTE_BOUNCES {
+            let survival = weight.max().min(1.0);
+            if rng.next_float() >= survival {
+                return FVec::zeros();
+            }
+            weight /= survival;
+        }
+        let guide = self.guiding.as_ref();
+        let snapshot = guide.and_then(|guiding| guiding.snapshot(&intersection.pos));
+        let fraction = guide
+            .filter(|_| snapshot.is_some())
+            .map_or(0.0, |g| g.fraction);
+        let sky = self.environment.as_ref().and_then(Environment::importance);
+        let towards_sky = sky.is_some_and(|(_, share)| rng.next_float() < share);
+        let direction = match (&snapshot, sky) {
+            (_, Some((sky, _))) if towards_sky => sky.sample(rng.next_float(), rng.next_float()).0,
+            (Some(snapshot), _) if rng.next_float() < fraction => snapshot.sample(rng),
+            _ => {
+                let local = cosine_hemisphere(rng.next_float(), rng.next_float());
+                let (u, v) = coordinate_system(&normal);
+                local.x * u + local.y * v + local.z * normal
+            }
+        };
+        if snapshot.is_some() || sky.is_some() {
+            // Cosine sampling alone cancels the cosine; a mixture leaves this ratio
+            let cosine_pdf = direction.dot(&normal).max(0.0) / PI;
+            if cosine_pdf == 0.0 {
+                return FVec::zeros();
+            }
+            let mut pdf = match &snapshot {
+                Some(snapshot) => {
+                    (1.0 - fraction) * cosine_pdf + fraction * snapshot.pdf(&direction)
+                }
+                None => cosine_pdf,
+            };
+            if let Some((sky, share)) = sky {
+                pdf = (1.0 - share) * pdf + share * sky.pdf(&direction);
+            }
+            weight *= cosine_pdf / pdf;
+        }
+        let bounced_ray = Ray {
+            origin: intersection.offset_origin(&direction, self.ray_bias),
+            direction,
+            differential: None,
+            time: intersection.time,
+        };
+        // Carry on with this generator, as the reflected ray takes the next bounce's
+        let colour = self._after_scatter(Scatter::Diffuse, || {
+            self._get_ray_colour(&bounced_ray, 0.0, media, num_bounces + 1, rng)
+        });
+        if let Some(guiding) = guide {
+            guiding.record(&intersection.pos, &direction, &colour);
+        }
+        weight.component_mul(&colour)
+    }
+
+    /*
+    Mirror reflection plus transmission. A transparent surface splits every
+    path in two, so after the first few bounces only one of the paths is
+    followed, picked at random in proportion to its weight and carrying both
+    weights. The expected colour stays the same while the ray count stops
+    doubling.
+     */
+    pub(crate) fn _get_scattered_colour(
+        &self,
+        object: usize,
+        intersection: &Intersection,
+        ray: &Ray,
+        media: &MediumStack,
+        num_bounces: u8,
+        rng: &mut Rng,
+    ) -> FVec {
+        let m = &*self._get_material(object, intersection);
+        let reflection = |rng: &mut Rng| match m.metallic {
+            Some(_) => {
+                self._get_microfacet_reflection(object, intersection, ray, media, num_bounces, rng)
+            }
+            None => self._get_reflection(intersection, m, ray, media, num_bounces, rng),
+        };
+        let transmission = |rng: &mut Rng| {
+            self._get_transmission(object, intersection, ray, media, num_bounces, rng)
+        };
+        if m.k_transmit == 0.0 || num_bounces < MAX_SPLIT_BOUNCES {
+            return reflection(rng) + transmission(rng);
+        }
+        let cos_theta = ray.direction.normalize().dot(&intersection.normal);
+        let reflectance = m.reflectance(cos_theta);
+        let transmittance = m.transmittance(cos_theta);
+        let total = reflectance + transmittance;
+        if rng.next_float() * total < reflectance {
+            reflection(rng) * (total / reflectance)
+        } else {
+            transmission(rng) * (total / transmittance)
+        }
+    }
+
+    /*
+    First hits of the primary rays of a block of pixels inside the film. The
+    rays of neighbouring pixels are traced through the hierarchy together,
+    up to PACKET_RAYS at a time. Blocks are packetSize pixels square, 4x4 by
+    default, so a packet holds the rays of all 16 pixels for up to four
+    samples per pixel and is split into packets of 64 beyond that.
+     */
+    pub(crate) fn _trace_packet(&self, view: &View, block: &Region) -> Vec<PixelSamples> {
+        let camera = view.camera;
+        let pixels: Vec<_> = (block.y..block.y + block.height)
+            .flat_map(|y| (block.x..block.x + block.width).map(move |x| (x, y)))
+            .map(|(x, y)| (x, y, camera.get_pixel_rays(x, y)))
+            .collect();
+        let all_rays: Vec<&Ray> = pixels
+            .iter()
+            .flat_map(|(_, _, rays)| rays.iter().map(|(_, ray)| ray))
+            .collect();
+        let mask = Some(view.primary_mask.as_slice());
+        let hits: Vec<_> = all_rays
+            .chunks(PACKET_RAYS)
+            .flat_map(|packet| self._get_nearest_hits(packet, 0.0, mask))
+            .collect();
+        let mut hits = hits.into_iter();
+        pixels
+            .into_iter()
+            .map(|(x, y, rays)| PixelSamples {
+                x,
+                y,
+                samples: rays
+                    .into_iter()
+                    .map(|(offset, ray)| {
+                        let hit = hits.next().expect("every ray of the block was traced");
+                        PrimarySample {
+                            hit: self._get_primary_hit(camera, &ray, hit),
+                            ray,
+                            offset,
+                        }
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    // First hit of a primary ray among the objects whose entry in the mask is true
+    pub(crate) fn _get_first_hit(
+        &self,
+        camera: &Camera,
+        ray: &Ray,
+        mask: &[bool],
+    ) -> Option<FirstHit> {
+        let hit = self._get_nearest_hit(ray, 0.0, Some(mask));
+        self._get_primary_hit(camera, ray, hit)
+    }
+
+    // What the G-buffer keeps of the nearest hit of a primary ray
+    pub(crate) fn _get_primary_hit(
+        &self,
+        camera: &Camera,
+        ray: &Ray,
+        hit: Option<(usize, Intersection)>,
+    ) -> Option<FirstHit> {
+        stats::add(Counter::PrimaryRays, 1);
+        let paths = self
+            .aovs
+            .iter()
+            .any(|output| matches!(output.aov, Aov::PathLength));
+        let corrections = self
+            .aovs
+            .iter()
+            .any(|output| matches!(output.aov, Aov::NormalCorrection));
+        hit.map(|(object, intersection)| {
+            let shape = &self.objects[object].shape;
+            let uv = self.objects[object].texture_uv(&intersection);
+            let material = self._get_material(object, &intersection);
+            let path_length = if paths {
+                self._get_path_length(ray, object, &intersection)
+            } else {
+                None
+            };
+            let normal_corrected = corrections && {
+                let bent = self._get_shading_normal(object, &intersection, &material, uv);
+                let facing = self._get_facing_normal(&intersection, bent.as_ref(), ray);
+                facing.is_some()
+            };
+            FirstHit {
+                object,
+                uv,
+                object_position: shape.object_position(&intersection.pos),
+                motion: camera.motion(&intersection.pos),
+                depth: camera
+                    .direction
+                    .normalize()
+                    .dot(&(intersection.pos - camera.position)),
+                albedo: self._get_albedo(object, &intersection, &material, uv),
+                path_length,
+                normal_corrected,
+                intersection,
+            }
+        })
+    }
+
+    /*
+    Distance light travels to the camera from the first surface along the
+    ray that scatters it, as time-of-flight cameras measure it. The ray is
+    followed on through surfaces that mirror or pass on more light than they
+    scatter, and distances inside objects are lengthened by their index of
+    refraction, as light is slower there. None if the ray leaves the scene.
+     */
+    pub(crate) fn _get_path_length(
+        &self,
+        ray: &Ray,
+        object: usize,
+        intersection: &Intersection,
+    ) -> Option<Float> {
+        let (mut object, mut hit) = (object, intersection.clone());
+        let (mut origin, mut direction) = (ray.origin, ray.direction.normalize());
+        let mut media = MediumStack::default();
+        let mut length = 0.0;
+        for _ in 0..=self.max_bounces {
+            length += media.ior() * (hit.pos - origin).norm();
+            let material = self._get_material(object, &hit);
+            let cos_theta = direction.dot(&hit.normal);
+            let normal = if cos_theta > 0.0 {
+                -hit.normal
+            } else {
+                hit.normal
+            };
+            let scattered = material.diffuse_weight();
+            let reflected = material.reflectance(cos_theta);
+            let transmitted = material.transmittance(cos_theta);
+            let (next, beyond) = if transmitted > scattered.max(reflected) {
+                let beyond = media.crossed(object, &material);
+                match refract(&direction, &normal, media.ior() / beyond.ior()) {
+                    Some(refracted) => (refracted, beyond),
+                    None => (reflect(&direction, &normal), media),
+                }
+            } else if reflected > scattered {
+                (reflect(&direction, &normal), media)
+            } else {
+                break;
+            };
+            (origin, direction) = (hit.offset_origin(&next, self.ray_bias), next);
+            let ray = Ray {
+                origin,
+                direction,
+                differential: None,
+                time: hit.time,
+            };
+            (object, hit) = self._get_nearest_hit(&ray, 0.0, None)?;
+            media = beyond;
+        }
+        Some(length)
+    }
+
+    pub(crate) fn _shade_sample(&self, pixel: &PixelSamples, index: u32) -> FVec {
+        self._shade_primary(pixel.x, pixel.y, &pixel.samples[index as usize], index)
+    }
+
+    // Colour of the sample with the given index of the pixel at (x, y)
+    pub(crate) fn _shade_primary(
+        &self,
+        x: u32,
+        y: u32,
+        sample: &PrimarySample,
+        index: u32,
+    ) -> FVec {
+        if self._is_holdout_hit(sample) {
+            return FVec::zeros();
+        }
+        let mut rng = Rng::for_sample(self.seed, x, y, index);
+        let hit = sample
+            .hit
+            .as_ref()
+            .map(|hit| (hit.object, &hit.intersection));
+        match &self.integrator {
+            Integrator::Plugin(_, integrator) => {
+                return integrator.colour(self, &sample.ray, hit, &mut rng);
+            }
+            Integrator::AmbientOcclusion => {
+                // Nothing occludes rays that leave the scene
+                let occlusion = self.ambient_occlusion.unwrap_or_default();
+                let open = hit.map_or(1.0, |(_, i)| {
+                    self._get_unoccluded_fraction(i, &sample.ray, &occlusion, &mut rng)
+                });
+                return FVec::repeat(open);
+            }
+            Integrator::Whitted | Integrator::Path => {}
+        }
+        if !self.spectral {
+            return self._get_hit_colour(&sample.ray, hit, &MediumStack::default(), 0, &mut rng);
+        }
+        // Samples of a pixel spread their wavelengths evenly over the spectrum
+        let u = match rng.strata() {
+            Some(strata) => {
+                ScrambledHalton::new(WAVELENGTH_DIMENSION, strata.scramble).get(strata.sample)
+            }
+            None => rng.next_float(),
+        };
+        let wavelength = sample_wavelength(u);
+        let media = MediumStack::for_wavelength(wavelength);
+        let colour = self._get_hit_colour(&sample.ray, hit, &media, 0, &mut rng);
+        colour.component_mul(&wavelength_colour(wavelength))
+    }
+
+    pub(crate) fn _shade_pixel(&self, pixel: &PixelSamples) -> FVec {
+        let total: FVec = (0..pixel.samples.len() as u32)
+            .map(|index| self._shade_sample(pixel, index))
+            .sum();
+        total / pixel.samples.len() as Float
+    }
+
+    /*
+    Colour of a pixel, which first takes more samples while it is too noisy
+    when sampling is adaptive. The samples taken are kept in the pixel, so a
+    G-buffer holds all of them.
+     */
+    pub(crate) fn _sample_pixel(&self, view: &View, pixel: &mut PixelSamples) -> FVec {
+        let camera = view.camera;
+        let Some(adaptive) = &camera.adaptive else {
+            return self._shade_pixel(pixel);
+        };
+        // Footprints stay those of the first samples, which textures were filtered for
+        let footprint = camera.pixel_samples(pixel.x, pixel.y);
+        let mut estimate = Estimate::default();
+        let mut total = FVec::zeros();
+        let mut index = 0;
+        loop {
+            while index < pixel.samples.len() as u32 {
+                let colour = self._shade_sample(pixel, index);
+                estimate.add(&colour);
+                total += colour;
+                index += 1;
+            }
+            if estimate.is_done(adaptive) {
+                break;
+            }
+            let count = adaptive.batch.max(1).min(adaptive.max_samples - index);
+            let rays = camera.get_jittered_rays(pixel.x, pixel.y, index..index + count, footprint);
+            pixel
+                .samples
+                .extend(rays.into_iter().map(|(offset, ray)| PrimarySample {
+                    hit: self._get_first_hit(camera, &ray, &view.primary_mask),
+                    ray,
+                    offset,
+                }));
+        }
+        total / index as Float
+    }
+
+    /*
+    A pixel finished before with fewer samples than the camera now takes,
+    with samples added from where it stopped and averaged in.
+     */
+    pub(crate) fn _continue_pixel(&self, view: &View, previous: Finished) -> Finished {
+        let (x, y, colour, taken) = previous;
+        let camera = view.camera;
+        let samples = camera.pixel_samples(x, y);
+        if taken >= samples {
+            return previous;
+        }
+        let rays = camera.get_jittered_rays(x, y, taken..samples, samples);
+        let added: FVec = rays
+            .into_iter()
+            .zip(taken..)
+            .map(|((offset, ray), index)| {
+                let hit = self._get_first_hit(camera, &ray, &view.primary_mask);
+                self._shade_primary(x, y, &PrimarySample { ray, offset, hit }, index)
+            })
+            .sum();
+        let colour = (colour * taken as Float + added) / samples as Float;
+        (x, y, colour, samples)
+    }
+
+    /*
+    One deep sample per object seen in the pixel, at the object's average
+    depth and sorted front to back. Alphas are chosen so that compositing
+    the samples in order covers the pixel in the same proportions as the
+    primary rays; rays that hit nothing leave the pixel uncovered.
+     */
+    pub(crate) fn _get_deep_samples(&self, pixel: &PixelSamples) -> Vec<DeepSample> {
+        // Per object: sample count, summed depth and summed colour
+        let mut groups: Vec<(usize, u32, Float, FVec)> = Vec::new();
+        for (sample, index) in pixel.samples.iter().zip(0..) {
+            let Some(hit) = &sample.hit else {
+                continue;
+            };
+            let colour = self._shade_sample(pixel, index);
+            match groups.iter_mut().find(|group| group.0 == hit.object) {
+                Some(group) => {
+                    group.1 += 1;
+                    group.2 += hit.intersection.t;
+                    group.3 += colour;
+                }
+                None => groups.push((hit.object, 1, hit.intersection.t, colour)),
+            }
+        }
+        let mut samples: Vec<(Float, Float, FVec)> = groups
+            .into_iter()
+            .map(|(_, count, depth, colour)| {
+                let coverage = count as Float / pixel.samples.len() as Float;
+                (depth / count as Float, coverage, colour / count as Float)
+            })
+            .collect();
+        samples.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let mut uncovered = 1.0;
+        samples
+            .into_iter()
+            .map(|(depth, coverage, colour)| {
+                let alpha = clamp(coverage / uncovered, 0.0, 1.0);
+                uncovered -= coverage;
+                DeepSample {
+                    depth: single(depth),
+                    colour: (colour * alpha).map(single).into(),
+                    alpha: single(alpha),
+                }
+            })
+            .collect()
+    }
+
+    pub(crate) fn write_deep(&self, gbuffer: &GBuffer, path: &str) -> std::io::Result<()> {
+        let deep_pixel = |pixel: &PixelSamples| (pixel.x, pixel.y, self._get_deep_samples(pixel));
+        #[cfg(feature = "parallel")]
+        let deep_pixels: Vec<_> = gbuffer.pixels.par_iter().map(deep_pixel).collect();
+        #[cfg(not(feature = "parallel"))]
+        let deep_pixels: Vec<_> = gbuffer.pixels.iter().map(deep_pixel).collect();
+        let mut pixels = vec![Vec::new(); (gbuffer.width * gbuffer.height) as usize];
+        let (x0, y0) = gbuffer.origin;
+        for (x, y, samples) in deep_pixels {
+            pixels[((y - y0) * gbuffer.width + x - x0) as usize] = samples;
+        }
+        deep::write_deep_exr(
+            path,
+            gbuffer.width,
+            gbuffer.height,
+            gbuffer.overscan,
+            &pixels,
+        )
+    }
+
+    /*
+    8-bit value of a working-space colour in the output colour space. Colours
+    encoded one at a time, as in progressive previews, are not metered for
+    automatic exposure and take the exposure as written.
+     */
+    pub(crate) fn _encode_colour(&self, colour: &FVec) -> [u8; 3] {
+        self._encode_colour_with(self.tone_mapping.as_ref(), colour)
+    }
+
+    pub(crate) fn _encode_colour_with(
+        &self,
+        tone_mapping: Option<&ToneMapping>,
+        colour: &FVec,
+    ) -> [u8; 3] {
+        let colour = match tone_mapping {
+            Some(tone_mapping) => tone_mapping.apply(*colour),
+            None => *colour,
+        };
+        let colour = match &self.grading {
+            Some(grading) => grading.apply(colour),
+            None => colour,
+        };
+        let colour = self.colour.output.apply(colour);
+        // An output colour space brings its own encoding
+        let colour = match (tone_mapping, &self.colour_management) {
+            (Some(tone_mapping), None) => tone_mapping.encode(colour),
+            _ => colour,
+        };
+        colour.map(channel_float_to_int).into()
+    }
+
+    // The tone mapping for an image of the colours, with any automatic exposure metered from them
+    pub(crate) fn _get_tone_mapping(
+        &self,
+        colours: impl Iterator<Item = FVec>,
+    ) -> Option<ToneMapping> {
+        self.tone_mapping
+            .map(|tone_mapping| tone_mapping.metered(colours))
+    }
+
+    pub(crate) fn _encode_image(&self, image: &LinearImage) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        let tone_mapping = self._get_tone_mapping(image.pixels().map(|pixel| pixel.0.into()));
+        ImageBuffer::from_fn(image.width(), image.height(), |x, y| {
+            Rgb(self._encode_colour_with(tone_mapping.as_ref(), &image.get_pixel(x, y).0.into()))
+        })
+    }
+
+    pub(crate) fn _is_holdout_hit(&self, sample: &PrimarySample) -> bool {
+        sample
+            .hit
+            .as_ref()
+            .is_some_and(|hit| self.objects[hit.object].holdout)
+    }
+
+    // Fraction of the pixel's samples not covered by holdout objects
+    pub(crate) fn _get_coverage(&self, pixel: &PixelSamples) -> Float {
+        let holdouts = pixel
+            .samples
+            .iter()
+            .filter(|sample| self._is_holdout_hit(sample))
+            .count();
+        1.0 - holdouts as Float / pixel.samples.len() as Float
+    }
+
+    pub(crate) fn _get_blocks(&self, region: &Region) -> Vec<Region> {
+        region.blocks(self.packet_size.max(1))
+    }
+
+    // Tiles of the region handed out to the worker threads, each traced as packets
+    pub(crate) fn _get_tiles(&self, region: &Region) -> Vec<Region> {
+        region.blocks(self.tile_size.max(1))
+    }
+
+    // First hits of the primary rays of a tile, traced a packet at a time
+    pub(crate) fn _trace_tile(&self, view: &View, tile: &Region) -> Vec<PixelSamples> {
+        self._get_blocks(tile)
+            .iter()
+            .flat_map(|block| self._trace_packet(view, block))
+            .collect()
+    }
+
+    /*
+    Render the scene as seen through the given camera, which must already be
+    prepared and in scene units. Only borrows the scene, so any number of
+    views can be rendered concurrently.
+     */
+    pub(crate) fn render(&self, camera: &Camera) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        self._encode_image(&self.render_linear(camera))
+    }
+
+    // The render in linear working-space colours, before tone mapping and encoding
+    pub(crate) fn render_linear(&self, camera: &Camera) -> LinearImage {
+        self._render_linear(camera, None)
+    }
+
+    // Tiles already in the recorder's checkpoint are taken from it, and new ones given to it
+    pub(crate) fn _render_linear(
+        &self,
+        camera: &Camera,
+        recorder: Option<&Recorder>,
+    ) -> LinearImage {
+        let _timer = StageTimer::start("Rendering");
+        let mut image = LinearImage::new(camera.film_columns(), camera.film_rows());
+        if !camera.filter.is_pixel_sized() {
+            // Filtering needs the samples of neighbouring pixels, so keep them all first
+            let gbuffer = self.trace_gbuffer(camera);
+            for (x, y, colour) in self._shade_gbuffer_colours(&gbuffer, |_| true) {
+                image.put_pixel(x, y, Rgb(colour.into()));
+            }
+            return image;
+        }
+        let view = self.view(camera);
+        let tiles = self._get_tiles(&camera.film_region());
+        let task = Task::start("render", tiles.len());
+        let render_tile = |tile: &Region| {
+            // Pixels finished before are only topped up with the samples they lack
+            let pixels = match recorder.and_then(|recorder| recorder.previous(tile)) {
+                Some(previous) => previous
+                    .into_iter()
+                    .map(|pixel| self._continue_pixel(&view, pixel))
+                    .collect::<Vec<_>>(),
+                None => self._render_tile(&view, tile),
+            };
+            if let Some(recorder) = recorder {
+                recorder.record(&pixels);
+            }
+            trace!("Rendered tile at ({}, {})", tile.x, tile.y);
+            task.advance();
+            pixels
+        };
+        // Threads take the next tile as they finish one, in order down the image
+        #[cfg(feature = "parallel")]
+        let pixels: Vec<Finished> = tiles.par_iter().flat_map_iter(render_tile).collect();
+        #[cfg(not(feature = "parallel"))]
+        let pixels: Vec<Finished> = tiles.iter().flat_map(render_tile).collect();
+        for (x, y, colour, _) in pixels {
+            image.put_pixel(x, y, Rgb(colour.into()));
+        }
+        image
+    }
+
+    // Every pixel of a tile, sampled afresh, and salvaged if any come out not finite
+    pub(crate) fn _render_tile(&self, view: &View, tile: &Region) -> Vec<Finished> {
+        let (mut pixels, objects) = self._sample_tile(view, tile);
+        if !objects.is_empty() {
+            self._salvage_tile(view, tile, &mut pixels, objects);
+        }
+        pixels
+    }
+
+    // Pixels of a tile, with the objects first seen by those whose colours are not finite
+    pub(crate) fn _sample_tile(
+        &self,
+        view: &View,
+        tile: &Region,
+    ) -> (Vec<Finished>, BTreeSet<Option<usize>>) {
+        let mut objects = BTreeSet::new();
+        let pixels = self
+            ._trace_tile(view, tile)
+            .into_iter()
+            .map(|mut pixel| {
+                let colour = self._sample_pixel(view, &mut pixel);
+                if !colour.iter().all(|c| c.is_finite()) {
+                    let hits = pixel.samples.iter().map(|sample| sample.hit.as_ref());
+                    objects.extend(hits.map(|hit| hit.map(|hit| hit.object)));
+                }
+                (pixel.x, pixel.y, colour, pixel.samples.len() as u32)
+            })
+            .collect();
+        (pixels, objects)
+    }
+
+    /*
+    Render a tile with pixels that are not finite again, with another seed
+    and rays leaving surfaces further from them so rounding errors that sent
+    rays back into the surface do not recur, taking the new colours of the
+    pixels that were bad. Pixels still bad after the last attempt are made
+    black rather than left to spread through filtering and denoising. The
+    scene is copied for the attempts, which bad tiles are rare enough to afford.
+     */
+    pub(crate) fn _salvage_tile(
+        &self,
+        view: &View,
+        tile: &Region,
+        pixels: &mut [Finished],
+        mut objects: BTreeSet<Option<usize>>,
+    ) {
+        let is_bad = |colour: &FVec| !colour.iter().all(|c| c.is_finite());
+        let mut retry = self.clone();
+        for attempt in 1..=SALVAGE_ATTEMPTS {
+            let bad = pixels
+                .iter()
+                .filter(|(.., colour, _)| is_bad(colour))
+                .count();
+            let seen: Vec<String> = objects
+                .iter()
+                .map(|object| match object {
+                    Some(index) => self.objects[*index].describe(*index),
+                    None => "the background".to_string(),
+                })
+                .collect();
+            warn!(
+                "{} pixels of the tile at ({}, {}) are not finite, seeing {}; rendering it again",
+                bad,
+                tile.x,
+                tile.y,
+                seen.join(", ")
+            );
+            retry.seed = self.seed ^ (attempt as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+            retry.ray_bias = (retry.ray_bias * 10.0).max(SALVAGE_RAY_BIAS);
+            let (again, still_bad) = retry._sample_tile(view, tile);
+            for (pixel, redone) in pixels.iter_mut().zip(again) {
+                if is_bad(&pixel.2) {
+                    *pixel = redone;
+                }
+            }
+            objects = still_bad;
+            if !pixels.iter().any(|(.., colour, _)| is_bad(colour)) {
+                return;
+            }
+        }
+        let mut given_up = 0;
+        for (.., colour, _) in pixels.iter_mut().filter(|(.., colour, _)| is_bad(colour)) {
+            *colour = FVec::zeros();
+            given_up += 1;
+        }
+        error!(
+            "{} pixels of the tile at ({}, {}) are still not finite; made black",
+            given_up, tile.x, tile.y
+        );
+    }
+
+    /*
+    Render only the tiles in a slice of the film, saving their pixels as they
+    are before tone mapping to a part for merge_to_file. Extra outputs and
+    render layers are left to a render of the whole image.
+     */
+    pub(crate) fn render_tile_range(
+        &self,
+        camera: &Camera,
+        range: &TileRange,
+        path: &str,
+    ) -> Result<(), ImageError> {
+        if !camera.filter.is_pixel_sized() {
+            let error = "tile ranges need a pixel-sized filter, as wider ones blend across tiles";
+            let error = std::io::Error::new(std::io::ErrorKind::Unsupported, error);
+            return Err(ImageError::IoError(error));
+        }
+        let extras = !self.aovs.is_empty()
+            || self.deep_output.is_some()
+            || self.multilayer_output.is_some()
+            || self.segmentation_output.is_some()
+            || self.annotation_output.is_some()
+            || !self.layers.is_empty();
+        if extras {
+            warn!("Only the image is rendered by tile range; extra outputs and layers are not");
+        }
+        let (index, count) = (range.index, range.count);
+        let _timer = StageTimer::start(format!("Rendering part {index} of {count}"));
+        let view = self.view(camera);
+        let tiles = range.select(self._get_tiles(&camera.film_region()));
+        let task = Task::start("render", tiles.len());
+        let render_tile = |tile: &Region| {
+            let pixels = self._render_tile(&view, tile);
+            task.advance();
+            pixels
+        };
+        #[cfg(feature = "parallel")]
+        let pixels: Vec<Finished> = tiles.par_iter().flat_map_iter(render_tile).collect();
+        #[cfg(not(feature = "parallel"))]
+        let pixels: Vec<Finished> = tiles.iter().flat_map(render_tile).collect();
+        checkpoint::save(path, Fingerprint::of(camera, self.seed), &pixels)
+            .map_err(ImageError::IoError)
+    }
+
+    // Write the image of the parts of a render split by tile range, rendering any tiles they lack
+    pub(crate) fn merge_to_file(
+        &self,
+        camera: &Camera,
+        parts: &[String],
+        path: &str,
+    ) -> Result<(), ImageError> {
+        let _timer = StageTimer::start(format!("Merging {}", path));
+        let fingerprint = Fingerprint::of(camera, self.seed);
+        let recorder = Recorder::merged(path, fingerprint, parts).map_err(ImageError::IoError)?;
+        self._write_rgb(camera, &self._render_linear(camera, Some(&recorder)), path)
+    }
+
+    /*
+    Render in passes that each double the samples of every pixel, up to the
+    camera's, handing the image after each pass and its samples per pixel to
+    the callback. Samples carry on from pass to pass, so the last pass is the
+    full render. The callback stops the render early by returning false.
+     */
+    pub(crate) fn render_progressive(
+        &self,
+        camera: &Camera,
+        mut pass: impl FnMut(&LinearImage, u32) -> bool,
+    ) {
+        let tiles = self._get_tiles(&camera.film_region());
+        let mut pixels: Vec<Vec<Finished>> = tiles
+            .iter()
+            .map(|tile| {
+                let rows = tile.y..tile.y + tile.height;
+                let columns = move |y| (tile.x..tile.x + tile.width).map(move |x| (x, y));
+                rows.flat_map(columns)
+                    .map(|(x, y)| (x, y, FVec::zeros(), 0))
+                    .collect()
+            })
+            .collect();
+        let mut samples = 1;
+        loop {
+            let mut pass_camera = camera.clone();
+            pass_camera.samples = samples.min(camera.samples.max(1));
+            let view = self.view(&pass_camera);
+            let continue_tile = |tile: &mut Vec<Finished>| {
+                for pixel in tile.iter_mut() {
+                    *pixel = self._continue_pixel(&view, *pixel);
+                }
+            };
+            #[cfg(feature = "parallel")]
+            pixels.par_iter_mut().for_each(continue_tile);
+            #[cfg(not(feature = "parallel"))]
+            pixels.iter_mut().for_each(continue_tile);
+            let mut image = LinearImage::new(camera.film_columns(), camera.film_rows());
+            for &(x, y, colour, _) in pixels.iter().flatten() {
+                image.put_pixel(x, y, Rgb(colour.into()));
+            }
+            if !pass(&image, pass_camera.samples) || pass_camera.samples == camera.samples.max(1) {
+                return;
+            }
+            samples *= 2;
+        }
+    }
+
+    /*
+    Render with an alpha channel that is zero where holdout objects are seen,
+    for compositing the image over other elements. Colours are stored
+    unpremultiplied, as PNG expects.
+     */
+    pub(crate) fn render_with_holdouts(
+        &self,
+        camera: &Camera,
+    ) -> ImageBuffer<Rgba<Float>, Vec<Float>> {
+        let gbuffer = self.trace_gbuffer(camera);
+        let mut image = ImageBuffer::new(gbuffer.width, gbuffer.height);
+        for (x, y, colour, alpha) in self._resolve_pixels(&gbuffer, |_| true) {
+            let colour = if alpha > 0.0 { colour / alpha } else { colour };
+            image.put_pixel(x, y, Rgba([colour.x, colour.y, colour.z, alpha]));
+        }
+        image
+    }
+
+    /*
+    Render one rectangle of the film into an image of its own size. Pixels are
+    sampled exactly as in a full render, and the neighbours a wide filter
+    draws samples from are traced too, so regions rendered separately tile
+    into the same image as a full render.
+     */
+    pub(crate) fn render_region(
+        &self,
+        camera: &Camera,
+        region: &Region,
+    ) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        let film = camera.film_region();
+        let region = region.intersect(&film);
+        let traced = region.padded(camera.filter.margin()).intersect(&film);
+        let gbuffer = self.trace_region(camera, &traced);
+        let colours =
+            self._shade_gbuffer_colours(&gbuffer, |pixel| region.contains(pixel.x, pixel.y));
+        // Metered on the region alone, so regions rendered apart may be exposed differently
+        let tone_mapping = self._get_tone_mapping(colours.iter().map(|(_, _, colour)| *colour));
+        let mut image = ImageBuffer::new(region.width, region.height);
+        for (x, y, colour) in colours {
+            let encoded = self._encode_colour_with(tone_mapping.as_ref(), &colour);
+            image.put_pixel(x - region.x, y - region.y, Rgb(encoded));
+        }
+        image
+    }
+
+    // Trace and keep the first hits of every primary ray, for re-shading later
+    pub(crate) fn trace_gbuffer(&self, camera: &Camera) -> GBuffer {
+        self.trace_region(camera, &camera.film_region())
+    }
+
+    pub(crate) fn trace_region(&self, camera: &Camera, region: &Region) -> GBuffer {
+        let view = self.view(camera);
+        let _timer = StageTimer::start("Tracing primary rays");
+        let tiles = self._get_tiles(region);
+        let task = Task::start("trace", tiles.len());
+        let trace_tile = |tile: &Region| {
+            let mut pixels = self._trace_tile(&view, tile);
+            if camera.adaptive.is_some() {
+                // Shaded to find how many samples each pixel needs, then again when resolved
+                for pixel in pixels.iter_mut() {
+                    self._sample_pixel(&view, pixel);
+                }
+            }
+            trace!("Traced tile at ({}, {})", tile.x, tile.y);
+            task.advance();
+            pixels
+        };
+        #[cfg(feature = "parallel")]
+        let pixels = tiles.par_iter().flat_map_iter(trace_tile).collect();
+        #[cfg(not(feature = "parallel"))]
+        let pixels = tiles.iter().flat_map(trace_tile).collect();
+        GBuffer {
+            width: region.width,
+            height: region.height,
+            origin: (region.x, region.y),
+            overscan: camera.overscan,
+            filter: camera.filter,
+            pixels,
+        }
+    }
+
+    /*
+    Shade the pixels of a G-buffer for which the predicate holds into an
+    existing image, leaving the rest untouched. Gives the same result as a
+    full render as long as the geometry and camera are unchanged.
+     */
+    pub(crate) fn shade_gbuffer(
+        &self,
+        gbuffer: &GBuffer,
+        image: &mut ImageBuffer<Rgb<u8>, Vec<u8>>,
+        predicate: impl Fn(&PixelSamples) -> bool + Sync,
+    ) {
+        for (x, y, colour) in self._shade_gbuffer_colours(gbuffer, predicate) {
+            image.put_pixel(x, y, Rgb(self._encode_colour(&colour)));
+        }
+    }
+
+    pub(crate) fn _shade_gbuffer_colours(
+        &self,
+        gbuffer: &GBuffer,
+        predicate: impl Fn(&PixelSamples) -> bool + Sync,
+    ) -> Vec<(u32, u32, FVec)> {
+        self._resolve_pixels(gbuffer, predicate)
+            .into_iter()
+            .map(|(x, y, colour, _)| (x, y, colour))
+            .collect()
+    }
+
+    /*
+    Filtered colour and coverage of the pixels for which the predicate holds.
+    Filters wider than a pixel spread samples into neighbouring pixels, so
+    then every sample is shaded whatever the predicate says.
+     */
+    pub(crate) fn _resolve_pixels(
+        &self,
+        gbuffer: &GBuffer,
+        predicate: impl Fn(&PixelSamples) -> bool + Sync,
+    ) -> Vec<(u32, u32, FVec, Float)> {
+        let _timer = StageTimer::start("Shading");
+        let task = Task::start("shade", gbuffer.pixels.len());
+        if gbuffer.filter.is_pixel_sized() {
+            let resolve = |pixel: &PixelSamples| {
+                let resolved = predicate(pixel).then(|| {
+                    let alpha = self._get_coverage(pixel);
+                    (pixel.x, pixel.y, self._shade_pixel(pixel), alpha)
+                });
+                task.advance();
+                resolved
+            };
+            #[cfg(feature = "parallel")]
+            return gbuffer.pixels.par_iter().filter_map(resolve).collect();
+            #[cfg(not(feature = "parallel"))]
+            return gbuffer.pixels.iter().filter_map(resolve).collect();
+        }
+        let shade = |pixel: &PixelSamples| {
+            let colours = (0..pixel.samples.len() as u32)
+                .map(|index| self._shade_sample(pixel, index))
+                .collect::<Vec<FVec>>();
+            task.advance();
+            colours
+        };
+        #[cfg(feature = "parallel")]
+        let colours: Vec<Vec<FVec>> = gbuffer.pixels.par_iter().map(shade).collect();
+        #[cfg(not(feature = "parallel"))]
+        let colours: Vec<Vec<FVec>> = gbuffer.pixels.iter().map(shade).collect();
+        let mut film = Film::new(gbuffer.width, gbuffer.height, gbuffer.filter);
+        let (x0, y0) = gbuffer.origin;
+        for (pixel, colours) in gbuffer.pixels.iter().zip(&colours) {
+            for (sample, colour) in pixel.samples.iter().zip(colours) {
+                let alpha = if self._is_holdout_hit(sample) {
+                    0.0
+                } else {
+                    1.0
+                };
+                let x = (pixel.x - x0) as Float + sample.offset.0;
+                let y = (pixel.y - y0) as Float + sample.offset.1;
+                film.add_sample(x, y, colour, alpha);
+            }
+        }
+        gbuffer
+            .pixels
+            .iter()
+            .filter(|pixel| predicate(pixel))
+            .map(|pixel| {
+                let (colour, alpha) = film.pixel(pixel.x - x0, pixel.y - y0);
+                (pixel.x, pixel.y, colour, alpha)
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "exr")]
+    pub(crate) fn write_multilayer(
+        &self,
+        gbuffer: &GBuffer,
+        colours: &[(u32, u32, FVec)],
+        path: &str,
+    ) -> Result<(), ImageError> {
+        use image::Rgb32FImage;
+        let mut beauty = Rgb32FImage::new(gbuffer.width, gbuffer.height);
+        for (x, y, colour) in colours {
+            beauty.put_pixel(*x, *y, Rgb(colour.map(single).into()));
+        }
+        let mut layers = vec![("", beauty)];
+        for output in &self.aovs {
+            layers.push((output.layer_name(), gbuffer.aov(output)));
+        }
+        let (width, height, overscan) = (gbuffer.width, gbuffer.height, gbuffer.overscan);
+        multilayer::write_multilayer_exr(path, width, height, overscan, &layers)
+            .map_err(|error| ImageError::IoError(std::io::Error::other(error)))
+    }
+
+    #[cfg(not(feature = "exr"))]
+    pub(crate) fn write_multilayer(
+        &self,
+        _gbuffer: &GBuffer,
+        _colours: &[(u32, u32, FVec)],
+        _path: &str,
+    ) -> Result<(), ImageError> {
+        use image::error::{ImageFormatHint, UnsupportedError, UnsupportedErrorKind};
+        use image::ImageFormat;
+        let format = ImageFormatHint::Exact(ImageFormat::OpenExr);
+        Err(ImageError::Unsupported(
+            UnsupportedError::from_format_and_kind(
+                format.clone(),
+                UnsupportedErrorKind::Format(format),
+            ),
+        ))
+    }
+
+    /*
+    Write a render cropped to the frame: as linear values to floating-point
+    formats (OpenEXR and Radiance HDR), and tone mapped and encoded to 8 bits
+    for anything else.
+     */
+    pub(crate) fn _write_rgb(
+        &self,
+        camera: &Camera,
+        image: &LinearImage,
+        path: &str,
+    ) -> Result<(), ImageError> {
+        let image = if is_floating_point(path) {
+            let mut image: Rgb32FImage = to_f32(image);
+            self._draw_bounds(camera, &mut image, |c| Rgb(c.map(|c| c as f32 / 255.0)));
+            DynamicImage::from(crop_overscan(image, camera.overscan))
+        } else {
+            let mut image = self._encode_image(image);
+            self._draw_bounds(camera, &mut image, Rgb);
+            DynamicImage::from(crop_overscan(image, camera.overscan))
+        };
+        save_image(image, path)
+    }
+
+    pub(crate) fn _write_rgba(
+        &self,
+        camera: &Camera,
+        image: &ImageBuffer<Rgba<Float>, Vec<Float>>,
+        path: &str,
+    ) -> Result<(), ImageError> {
+        let image = if is_floating_point(path) {
+            let mut image: Rgba32FImage = to_f32(image);
+            self._draw_bounds(camera, &mut image, |c| {
+                let [r, g, b] = c.map(|c| c as f32 / 255.0);
+                Rgba([r, g, b, 1.0])
+            });
+            DynamicImage::from(crop_overscan(image, camera.overscan))
+        } else {
+            let colours = image
+                .pixels()
+                .map(|pixel| FVec::new(pixel[0], pixel[1], pixel[2]));
+            let tone_mapping = self._get_tone_mapping(colours);
+            let mut image = ImageBuffer::from_fn(image.width(), image.height(), |x, y| {
+                let [red, green, blue, alpha] = image.get_pixel(x, y).0;
+                let colour = FVec::new(red, green, blue);
+                let [red, green, blue] = self._encode_colour_with(tone_mapping.as_ref(), &colour);
+                Rgba([red, green, blue, channel_float_to_int(alpha)])
+            });
+            self._draw_bounds(camera, &mut image, |[r, g, b]| Rgba([r, g, b, 255]));
+            DynamicImage::from(crop_overscan(image, camera.overscan))
+        };
+        save_image(image, path)
+    }
+
+    /*
+    Outline the boxes the scene asks for over an image rendered through the
+    camera: each object's bounding box, and the boxes of the hierarchy from
+    blue at its root to red at the deepest level shown. Colours are given to
+    the pixel function as 8-bit RGB.
+     */
+    pub(crate) fn _draw_bounds<P: Pixel>(
+        &self,
+        camera: &Camera,
+        image: &mut ImageBuffer<P, Vec<P::Subpixel>>,
+        pixel: impl Fn([u8; 3]) -> P,
+    ) {
+        let mut boxes: Vec<(Aabb, [u8; 3])> = Vec::new();
+        if let Some(levels) = self.show_hierarchy {
+            let nodes = self.primitives.hierarchy_boxes(levels);
+            let colour = |depth: usize| {
+                let colour = heat(depth as Float / levels.max(1) as Float);
+                colour.map(|c| (c * 255.0).round() as u8).into()
+            };
+            boxes.extend(nodes.into_iter().map(|(depth, aabb)| (aabb, colour(depth))));
+        }
+        if self.show_bounds {
+            let objects = self
+                .objects
+                .iter()
+                .filter_map(|object| object.bounding_box());
+            boxes.extend(objects.map(|aabb| (aabb, BOUNDS_COLOUR)));
+        }
+        for (aabb, colour) in boxes {
+            for (a, b) in aabb.edges() {
+                if let Some((from, to)) = camera.project_segment(&a, &b) {
+                    wireframe::draw_line(image, from, to, pixel(colour));
+                }
+            }
+        }
+    }
+
+    /*
+    Write the image and every extra output and render layer of the scene,
+    keeping checkpoints of each image that can have them.
+     */
+    pub(crate) fn render_to_file(
+        &self,
+        camera: &Camera,
+        path: &str,
+        checkpoint: Option<Checkpoint>,
+    ) -> Result<(), ImageError> {
+        let _timer = StageTimer::start(format!("Rendering {}", path));
+        let holdouts = self.objects.iter().any(|object| object.holdout);
+        // Denoising is guided by the first hits, so takes the same path as the extra outputs
+        let extras = !self.aovs.is_empty()
+            || self.deep_output.is_some()
+            || self.multilayer_output.is_some()
+            || self.segmentation_output.is_some()
+            || self.annotation_output.is_some()
+            || self.denoise.is_some();
+        if checkpoint.is_some() && (holdouts || extras || !camera.filter.is_pixel_sized()) {
+            warn!(
+                "No checkpoints are kept of {}, which needs every sample at once",
+                path
+            );
+        }
+        if holdouts {
+            let image = self.render_with_holdouts(camera);
+            self._write_rgba(camera, &image, path)?;
+        } else if !extras {
+            let fingerprint = Fingerprint::of(camera, self.seed);
+            let recorder = checkpoint.map(|c| Recorder::start(c, path, fingerprint));
+            self._write_rgb(
+                camera,
+                &self._render_linear(camera, recorder.as_ref()),
+                path,
+            )?;
+            if let Some(recorder) = recorder {
+                recorder.finish();
+            }
+        } else {
+            // Trace once and derive the image and every extra output from the same first hits
+            let gbuffer = self.trace_gbuffer(camera);
+            let colours = self._shade_gbuffer_colours(&gbuffer, |_| true);
+            let mut image = LinearImage::new(gbuffer.width, gbuffer.height);
+            for (x, y, colour) in &colours {
+                image.put_pixel(*x, *y, Rgb((*colour).into()));
+            }
+            if let Some(denoise) = &self.denoise {
+                let _timer = StageTimer::start("Denoising");
+                image = denoise.apply(&image, &gbuffer);
+            }
+            self._write_rgb(camera, &image, path)?;
+            for output in &self.aovs {
+                if let Some(aov_path) = &output.path {
+                    gbuffer.save_aov(output, aov_path)?;
+                }
+            }
+            if let Some(deep_path) = &self.deep_output {
+                self.write_deep(&gbuffer, deep_path)?;
+            }
+            if let Some(multilayer_path) = &self.multilayer_output {
+                self.write_multilayer(&gbuffer, &colours, multilayer_path)?;
+            }
+            if let Some(segmentation) = &self.segmentation_output {
+                self.write_segmentation(&gbuffer, segmentation)?;
+            }
+            if let Some(annotations) = &self.annotation_output {
+                self.write_annotations(&gbuffer, annotations, path)?;
+            }
+        }
+        if let Some(calibration_path) = &self.calibration_output {
+            write_calibration(camera, calibration_path)?;
+        }
+        for layer in &self.layers {
+            self.with_layer(layer)
+                .render_to_file(camera, &layer.path, checkpoint)?;
+        }
+        Ok(())
+    }
+}
+
+// Write the camera's calibration for computer vision tools, if OpenCV has a model of its lens
+fn write_calibration(camera: &Camera, path: &str) -> Result<(), ImageError> {
+    let Some(calibration) = camera.calibration() else {
+        warn!(
+            "No calibration is written to {}: OpenCV has no model of the projection",
+            path
+        );
+        return Ok(());
+    };
+    let text = serde_json::to_string_pretty(&calibration).map_err(std::io::Error::other)?;
+    std::fs::write(path, text + "\n").map_err(ImageError::IoError)
+}
+
+/*
+Renders a scene through a camera: the scene's own, or any other placed in
+the scene in metres. The scene is only borrowed, so any number of renderers
+can work on one scene at once from different threads.
+ */
+pub struct Renderer<'a> {
+    scene: &'a Scene,
+    camera: Camera,
+    checkpoint: Option<Checkpoint>,
+}
+
+impl<'a> Renderer<'a> {
+    pub fn new(scene: &'a Scene) -> Renderer<'a> {
+        Renderer {
+            scene,
+            camera: scene.camera.clone(),
+            checkpoint: None,
+        }
+    }
+
+    // Importance maps are only resolved for the scene's own camera
+    pub fn with_camera(scene: &'a Scene, mut camera: Camera) -> Renderer<'a> {
+        camera.prepare();
+        Renderer {
+            scene,
+            camera,
+            checkpoint: None,
+        }
+    }
+
+    // Keep checkpoints of the images written to files, to resume them after a crash
+    pub fn with_checkpoint(mut self, checkpoint: Checkpoint) -> Renderer<'a> {
+        self.checkpoint = Some(checkpoint);
+        self
+    }
+
+    // The frame as an 8-bit image in the output colour space, without overscan
+    pub fn render(&self) -> RgbImage {
+        crop_overscan(self.scene.render(&self.camera), self.camera.overscan)
+    }
+
+    // The frame in linear working-space colours, before tone mapping, without overscan
+    pub fn render_linear(&self) -> Rgb32FImage {
+        let image = self.scene.render_linear(&self.camera);
+        to_f32(&crop_overscan(image, self.camera.overscan))
+    }
+
+    // One rectangle of the film, in film pixel coordinates that include the overscan
+    pub fn render_region(&self, region: &Region) -> RgbImage {
+        self.scene.render_region(&self.camera, region)
+    }
+
+    // Write the image, along with every extra output and render layer the scene asks for
+    pub fn render_to_file(&self, path: &str) -> Result<(), ImageError> {
+        self.scene
+            .render_to_file(&self.camera, path, self.checkpoint)
+    }
+
+    // Render one slice of the film's tiles to a part for merging, not an image
+    pub fn render_tile_range(&self, range: &TileRange, path: &str) -> Result<(), ImageError> {
+        self.scene.render_tile_range(&self.camera, range, path)
+    }
+
+    // Write the image of the parts of a render split by tile range
+    pub fn merge_to_file(&self, parts: &[String], path: &str) -> Result<(), ImageError> {
+        self.scene.merge_to_file(&self.camera, parts, path)
+    }
+}
@@ -0,0 +1,62 @@
+use crate::{config, Float, Scene};
+use std::fmt;
+use std::path::Path;
+
+/*
+What a render depends on besides its scene, reported with the hash of the
+file it wrote so a render can be checked against an earlier one. Renders of
+the same scene with the same seed, precision and version come out the same
+on any number of threads, path guided ones included as the guide is learned
+before the render; the thread count is reported for the runs where they do not.
+ */
+pub struct Reproducibility {
+    pub seed: u64,
+    pub threads: usize,
+    // The floating-point type the renderer was built with
+    pub precision: &'static str,
+    pub version: &'static str,
+}
+
+impl Reproducibility {
+    pub fn of(scene: &Scene) -> Reproducibility {
+        Reproducibility {
+            seed: scene.seed,
+            threads: config::threads(),
+            precision: std::any::type_name::<Float>(),
+            version: env!("CARGO_PKG_VERSION"),
+        }
+    }
+}
+
+impl fmt::Display for Reproducibility {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "seed {}, threads {}, precision {}, raycaster {}",
+            self.seed, self.threads, self.precision, self.version
+        )
+    }
+}
+
+// FNV-1a hash of the bytes, as 16 hex digits; for telling files apart, not for security
+pub fn content_hash(bytes: &[u8]) -> String {
+    let hash = bytes.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{hash:016x}")
+}
+
+pub fn file_hash(path: &str) -> std::io::Result<String> {
+    Ok(content_hash(&std::fs::read(path)?))
+}
+
+// Path beside the output that a render is written to while checked against it, e.g. "shot.new.png"
+pub fn pending_path(output: &str) -> String {
+    let path = Path::new(output);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{stem}.new.{}", extension.to_string_lossy()),
+        None => format!("{stem}.new"),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
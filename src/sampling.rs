@@ -0,0 +1,522 @@
+use crate::core::consts::{FRAC_PI_2, FRAC_PI_4, PI};
+use crate::core::double;
+use crate::{FVec, Float};
+use std::sync::OnceLock;
+
+const MASK_SIZE: usize = 64;
+const MASK_SIGMA: Float = 1.5;
+const INITIAL_DENSITY: Float = 0.1;
+const GOLDEN_RATIO_CONJUGATE: Float = 0.618_033_988_749_895_f64 as Float;
+// Generator of the R2 sequence, the two-dimensional analogue of the golden ratio
+const PLASTIC_NUMBER: Float = 1.324_717_957_244_746_f64 as Float;
+
+// Sample dimensions; each reads the mask at a different toroidal offset so
+// that e.g. the pixel jitter and the light sample of a pixel are decorrelated.
+pub const DIMENSION_PIXEL_X: u32 = 0;
+pub const DIMENSION_PIXEL_Y: u32 = 1;
+// Takes this dimension and the next
+pub const DIMENSION_LENS: u32 = 2;
+pub const DIMENSION_TIME: u32 = 4;
+// Takes this dimension and the next two, offsetting the shadow samples of a pixel
+pub const DIMENSION_LIGHT: u32 = 5;
+
+/*
+Tileable blue-noise dither mask, generated once with the void-and-cluster
+method (Ulichney 1993). Each entry is the pixel's rank in [0, 1).
+ */
+pub struct BlueNoiseMask {
+    values: Vec<Float>,
+}
+
+impl BlueNoiseMask {
+    pub fn get() -> &'static BlueNoiseMask {
+        static MASK: OnceLock<BlueNoiseMask> = OnceLock::new();
+        MASK.get_or_init(BlueNoiseMask::generate)
+    }
+
+    /*
+    Return a value in [0, 1) for the given pixel, sample and dimension.
+    Successive samples of a pixel are rotated by the golden ratio so they stay
+    well distributed while keeping the blue-noise structure across pixels.
+     */
+    pub fn sample(&self, x: u32, y: u32, sample_index: u32, dimension: u32) -> Float {
+        let (offset_x, offset_y) = dimension_offset(dimension);
+        let mx = (x as usize + offset_x) % MASK_SIZE;
+        let my = (y as usize + offset_y) % MASK_SIZE;
+        let value = self.values[my * MASK_SIZE + mx];
+        (value + sample_index as Float * GOLDEN_RATIO_CONJUGATE).fract()
+    }
+
+    /*
+    A point in [0, 1)^2 from two dimensions starting at the given one.
+    Successive samples are rotated by the R2 sequence instead of the golden
+    ratio in both, which would keep every sample of a pixel on a few lines.
+     */
+    pub fn sample_2d(&self, x: u32, y: u32, sample_index: u32, dimension: u32) -> (Float, Float) {
+        let i = sample_index as Float;
+        let u = self.sample(x, y, 0, dimension) + i / PLASTIC_NUMBER;
+        let v = self.sample(x, y, 0, dimension + 1) + i / (PLASTIC_NUMBER * PLASTIC_NUMBER);
+        (u.fract(), v.fract())
+    }
+
+    fn generate() -> BlueNoiseMask {
+        let n = MASK_SIZE * MASK_SIZE;
+        let mut field = EnergyField::new();
+        let mut pattern = initial_pattern(n);
+        for (i, &on) in pattern.iter().enumerate() {
+            if on {
+                field.add(i, 1.0);
+            }
+        }
+
+        // Relax the initial pattern until the tightest cluster is also the largest void
+        loop {
+            let cluster = field.tightest_cluster(&pattern);
+            pattern[cluster] = false;
+            field.add(cluster, -1.0);
+            let void = field.largest_void(&pattern);
+            pattern[void] = true;
+            field.add(void, 1.0);
+            if cluster == void {
+                break;
+            }
+        }
+        let initial = pattern.clone();
+        let initial_field = field.clone();
+        let ones = pattern.iter().filter(|&&on| on).count();
+        let mut ranks = vec![0usize; n];
+
+        // Phase 1: rank the initial points by repeatedly removing the tightest cluster
+        for rank in (0..ones).rev() {
+            let cluster = field.tightest_cluster(&pattern);
+            pattern[cluster] = false;
+            field.add(cluster, -1.0);
+            ranks[cluster] = rank;
+        }
+
+        // Phases 2 and 3: fill the remaining pixels, largest void first
+        let mut pattern = initial;
+        let mut field = initial_field;
+        for rank in ones..n {
+            let void = field.largest_void(&pattern);
+            pattern[void] = true;
+            field.add(void, 1.0);
+            ranks[void] = rank;
+        }
+
+        BlueNoiseMask {
+            values: ranks
+                .into_iter()
+                .map(|rank| rank as Float / n as Float)
+                .collect(),
+        }
+    }
+}
+
+fn dimension_offset(dimension: u32) -> (usize, usize) {
+    // R2 low-discrepancy sequence keeps the per-dimension shifts far apart
+    let g = PLASTIC_NUMBER;
+    let d = dimension as Float;
+    let ox = ((d / g).fract() * MASK_SIZE as Float) as usize;
+    let oy = ((d / (g * g)).fract() * MASK_SIZE as Float) as usize;
+    (ox, oy)
+}
+
+fn initial_pattern(n: usize) -> Vec<bool> {
+    // Fixed-seed xorshift so the mask is identical between runs
+    let mut state: u32 = 0x9e37_79b9;
+    (0..n)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state as Float / u32::MAX as Float) < INITIAL_DENSITY
+        })
+        .collect()
+}
+
+#[derive(Clone)]
+struct EnergyField {
+    kernel: Vec<Float>,
+    energy: Vec<Float>,
+}
+
+impl EnergyField {
+    fn new() -> EnergyField {
+        let kernel = (0..MASK_SIZE * MASK_SIZE)
+            .map(|i| {
+                let dx = toroidal_distance(i % MASK_SIZE);
+                let dy = toroidal_distance(i / MASK_SIZE);
+                let d2 = (dx * dx + dy * dy) as Float;
+                (-d2 / (2.0 * MASK_SIGMA * MASK_SIGMA)).exp()
+            })
+            .collect();
+        EnergyField {
+            kernel,
+            energy: vec![0.0; MASK_SIZE * MASK_SIZE],
+        }
+    }
+
+    fn add(&mut self, index: usize, sign: Float) {
+        let (px, py) = (index % MASK_SIZE, index / MASK_SIZE);
+        for y in 0..MASK_SIZE {
+            let ky = (y + MASK_SIZE - py) % MASK_SIZE;
+            for x in 0..MASK_SIZE {
+                let kx = (x + MASK_SIZE - px) % MASK_SIZE;
+                self.energy[y * MASK_SIZE + x] += sign * self.kernel[ky * MASK_SIZE + kx];
+            }
+        }
+    }
+
+    fn tightest_cluster(&self, pattern: &[bool]) -> usize {
+        self.extreme(pattern, true, |a, b| a > b)
+    }
+
+    fn largest_void(&self, pattern: &[bool]) -> usize {
+        self.extreme(pattern, false, |a, b| a < b)
+    }
+
+    fn extreme(&self, pattern: &[bool], on: bool, better: impl Fn(Float, Float) -> bool) -> usize {
+        let mut best = None;
+        for (i, &value) in pattern.iter().enumerate() {
+            if value != on {
+                continue;
+            }
+            match best {
+                Some(b) if !better(self.energy[i], self.energy[b]) => {}
+                _ => best = Some(i),
+            }
+        }
+        best.expect("blue-noise pattern has no candidate pixel")
+    }
+}
+
+fn toroidal_distance(d: usize) -> usize {
+    d.min(MASK_SIZE - d)
+}
+
+/*
+Small PCG32 generator (O'Neill 2014) for the stochastic parts of shading.
+Generators are derived per pixel sample and per bounce from the scene seed,
+so results do not depend on thread scheduling or platform.
+ */
+pub struct Rng {
+    state: u64,
+    increment: u64,
+    seed: u64,
+    strata: Option<Strata>,
+}
+
+// Where a sample of a pixel falls in the sequences the pixel's samples share, until taken
+#[derive(Debug, Clone, Copy)]
+pub struct Strata {
+    // Scramble of the pixel's sequences and the index of this sample in them
+    pub scramble: u32,
+    pub sample: u32,
+    /*
+    Shadow samples instead follow a sequence scrambled the same way for every
+    pixel and shifted by the pixel's blue-noise offset, so their noise is
+    spread at high frequencies like that of the pixel and lens samples.
+     */
+    pub light_scramble: u32,
+    pub light_offset: [Float; 3],
+}
+
+impl Rng {
+    pub fn new(seed: u64, stream: u64) -> Rng {
+        let mut rng = Rng {
+            state: 0,
+            increment: (stream << 1) | 1,
+            seed,
+            strata: None,
+        };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+
+    // Generator for one sample of one pixel
+    pub fn for_sample(scene_seed: u64, x: u32, y: u32, sample: u32) -> Rng {
+        let pixel = ((y as u64) << 32) | x as u64;
+        let pixel_seed = mix(mix(scene_seed) ^ pixel);
+        let mask = BlueNoiseMask::get();
+        let strata = Strata {
+            scramble: pixel_seed as u32,
+            sample,
+            light_scramble: mix(scene_seed) as u32,
+            light_offset: [0, 1, 2].map(|d| mask.sample(x, y, 0, DIMENSION_LIGHT + d)),
+        };
+        Rng {
+            strata: Some(strata),
+            ..Rng::new(pixel_seed ^ sample as u64, 0)
+        }
+    }
+
+    // Generator for a cell of a lattice in space, for patterns fixed to surfaces
+    pub fn for_cell(cell: [i64; 3]) -> Rng {
+        let [x, y, z] = cell.map(|c| c as u64);
+        Rng::new(mix(mix(mix(x) ^ y) ^ z), 0)
+    }
+
+    /*
+    Independent generator for a later bounce of the same sample, so how many
+    numbers one bounce consumes does not shift the ones seen by the next.
+     */
+    pub fn for_bounce(&self, bounce: u8) -> Rng {
+        Rng::new(self.seed, bounce as u64 + 1)
+    }
+
+    /*
+    Scramble shared by every sample of the pixel and the index of this sample,
+    for the first surface the sample shades. Sequences indexed from the sample
+    index times their length then cover the pixel as one longer sequence.
+     */
+    pub fn take_strata(&mut self) -> Option<Strata> {
+        self.strata.take()
+    }
+
+    // The strata without taking them, for dimensions of the sequence past those of lights
+    pub fn strata(&self) -> Option<Strata> {
+        self.strata
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(self.increment);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rot = (old >> 59) as u32;
+        xorshifted.rotate_right(rot)
+    }
+
+    // Uniform value in [0, 1)
+    pub fn next_float(&mut self) -> Float {
+        self.next_u32() as Float / (u32::MAX as Float + 1.0)
+    }
+}
+
+/*
+Seed of an object's own random values, from the scene seed and the object's
+index, so each object varies the same way in every render with the same seed.
+ */
+pub fn instance_seed(scene_seed: u64, instance: usize) -> u64 {
+    mix(mix(scene_seed) ^ instance as u64)
+}
+
+// The index-th random value of an instance in [0, 1), independent of its others
+pub fn instance_random(instance_seed: u64, index: u64) -> Float {
+    Rng::new(instance_seed, index).next_float()
+}
+
+/*
+Value in [0, 1) fixed by a ray and a distance along it, for choices about
+what the ray hits that must come out the same each time it is traced.
+ */
+pub fn ray_random(origin: &FVec, direction: &FVec, t: Float) -> Float {
+    let bits = origin.iter().chain(direction.iter()).chain([&t]);
+    let hash = bits.fold(0, |hash, &x| mix(hash ^ double(x).to_bits()));
+    (hash >> 32) as u32 as Float / (u32::MAX as Float + 1.0)
+}
+
+// SplitMix64 finalizer, used to spread nearby seeds apart
+fn mix(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/*
+Map a point in the unit square to the unit disc, keeping areas in proportion
+and neighbouring points together (Shirley and Chiu 1997), so well spread
+samples stay well spread.
+ */
+pub fn concentric_disc(u: Float, v: Float) -> (Float, Float) {
+    let (a, b) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+    if a == 0.0 && b == 0.0 {
+        return (0.0, 0.0);
+    }
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, FRAC_PI_4 * (b / a))
+    } else {
+        (b, FRAC_PI_2 - FRAC_PI_4 * (a / b))
+    };
+    (r * theta.cos(), r * theta.sin())
+}
+
+/*
+A direction about the z axis with probability proportional to its cosine
+with the axis, by lifting a point of the disc onto the hemisphere (Malley's
+method). Turn it into any frame with the basis of the frame's normal.
+ */
+pub fn cosine_hemisphere(u: Float, v: Float) -> FVec {
+    let (x, y) = concentric_disc(u, v);
+    FVec::new(x, y, (1.0 - x * x - y * y).max(0.0).sqrt())
+}
+
+/*
+A microfacet normal about the z axis drawn from the GGX distribution of the
+width alpha, with probability proportional to D(h) times its cosine with the
+axis. Turn it into any frame like cosine_hemisphere.
+ */
+pub fn ggx_normal(alpha: Float, u: Float, v: Float) -> FVec {
+    let cos_theta = ((1.0 - u) / (1.0 + (alpha * alpha - 1.0) * u)).sqrt();
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    FVec::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+const HALTON_PRIMES: [u32; 8] = [2, 3, 5, 7, 11, 13, 17, 19];
+
+// Most digits of any base that still show at Float precision, those of base 2
+const HALTON_DIGITS: usize = Float::MANTISSA_DIGITS as usize;
+
+/*
+Component `dimension` of a Halton sequence with random digit scrambling:
+every digit position gets its own random shift modulo the base, chosen by
+`scramble`. Different scrambles give decorrelated copies of the sequence
+(e.g. one per pixel) that keep its low discrepancy. The shifts are drawn
+once, up to the last digit a Float resolves, so drawing many points of one
+scramble costs only the digits of each index.
+ */
+pub struct ScrambledHalton {
+    base: u32,
+    shifts: [u8; HALTON_DIGITS],
+    // Sum of the scrambled zeros from each digit on, which every index ends with
+    tails: [Float; HALTON_DIGITS + 1],
+}
+
+impl ScrambledHalton {
+    pub fn new(dimension: usize, scramble: u32) -> ScrambledHalton {
+        let base = HALTON_PRIMES[dimension % HALTON_PRIMES.len()];
+        let inv_base = 1.0 / base as Float;
+        let mut shifts = [0; HALTON_DIGITS];
+        let mut factors = [0.0; HALTON_DIGITS];
+        let mut factor = inv_base;
+        let mut digits = 0;
+        while digits < HALTON_DIGITS && factor > Float::EPSILON {
+            let key = mix(((scramble as u64) << 32) ^ ((dimension as u64) << 16) ^ digits as u64);
+            shifts[digits] = (key % base as u64) as u8;
+            factors[digits] = factor;
+            factor *= inv_base;
+            digits += 1;
+        }
+        let mut tails = [0.0; HALTON_DIGITS + 1];
+        for level in (0..digits).rev() {
+            tails[level] = tails[level + 1] + shifts[level] as Float * factors[level];
+        }
+        ScrambledHalton {
+            base,
+            shifts,
+            tails,
+        }
+    }
+
+    // The index-th point of the sequence, in [0, 1)
+    pub fn get(&self, index: u32) -> Float {
+        let inv_base = 1.0 / self.base as Float;
+        let mut n = index;
+        let mut factor = inv_base;
+        let mut result = 0.0;
+        let mut level = 0;
+        while n > 0 && level < HALTON_DIGITS {
+            let digit = (n % self.base + self.shifts[level] as u32) % self.base;
+            result += digit as Float * factor;
+            n /= self.base;
+            factor *= inv_base;
+            level += 1;
+        }
+        (result + self.tails[level]).min(1.0 - Float::EPSILON)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /*
+    Every image depends on these streams, so they are pinned to exact values:
+    a change to how they are derived must change these tests too, not just
+    quietly change every render made with a seed.
+     */
+    #[test]
+    fn pcg_matches_the_reference_generator() {
+        // The demo output of O'Neill's pcg32, seeded with 42 on stream 54
+        let mut rng = Rng::new(42, 54);
+        let expected = [
+            0xa15c_02b7,
+            0x7b47_f409,
+            0xba1d_3330,
+            0x83d2_f293,
+            0xbfa4_784b,
+        ];
+        assert_eq!(expected.map(|_| rng.next_u32()), expected);
+    }
+
+    #[test]
+    fn streams_of_a_seed_are_pinned() {
+        let mut rng = Rng::new(7, 3);
+        let expected = [3_682_281_251, 3_185_575_708, 1_655_154_972, 2_344_720_130];
+        assert_eq!(expected.map(|_| rng.next_u32()), expected);
+
+        let mut rng = Rng::for_sample(7, 12, 34, 5);
+        let strata = rng.strata().unwrap();
+        assert_eq!(strata.scramble, 2_778_743_122);
+        assert_eq!(strata.sample, 5);
+        assert_eq!(strata.light_scramble, 1_496_452_567);
+        let expected = [3_344_373_471, 1_168_933_756, 2_383_512_986, 2_055_831_039];
+        assert_eq!(expected.map(|_| rng.next_u32()), expected);
+
+        let mut bounce = rng.for_bounce(2);
+        let expected = [2_436_628_887, 1_935_633_687, 3_135_886_387, 2_387_749_657];
+        assert_eq!(expected.map(|_| bounce.next_u32()), expected);
+    }
+
+    #[test]
+    fn scrambled_halton_points_are_pinned() {
+        let scramble = Rng::for_sample(7, 12, 34, 5).strata().unwrap().scramble;
+        let indices = [0, 1, 2, 3, 1000];
+        let expected: [[Float; 5]; 4] = [
+            [
+                0.633_932_029,
+                0.133_932_029,
+                0.883_932_029,
+                0.383_932_029,
+                0.709_127_342,
+            ],
+            [
+                0.521_091_359,
+                0.854_424_692,
+                0.187_758_026,
+                0.632_202_470,
+                0.867_227_619,
+            ],
+            [
+                0.775_501_921,
+                0.975_501_921,
+                0.175_501_921,
+                0.375_501_921,
+                0.772_621_921,
+            ],
+            [
+                0.319_819_098,
+                0.462_676_241,
+                0.605_533_384,
+                0.748_390_527,
+                0.215_695_816,
+            ],
+        ];
+        for (dimension, expected) in expected.iter().enumerate() {
+            let sequence = ScrambledHalton::new(dimension, scramble);
+            for (index, expected) in indices.iter().zip(expected) {
+                let point = sequence.get(*index);
+                assert!(
+                    (point - expected).abs() < 1e-6,
+                    "dimension {dimension}, index {index}: {point}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,1225 @@
+use crate::annotation::AnnotationOutput;
+use crate::atmosphere::Atmosphere;
+use crate::colour::{ColourManagement, ColourPipeline};
+use crate::core::ray::Ray;
+use crate::decal::Decal;
+use crate::denoise::Denoise;
+use crate::environment::Environment;
+use crate::fog::{Fog, LocalFog};
+use crate::gbuffer::AovOutput;
+use crate::grid::GridVolume;
+use crate::guiding::Guiding;
+use crate::irradiance::{AmbientGradient, Irradiance};
+use crate::light::{DistantDirection, Emitter, LightShape, LightSource};
+use crate::light_path::LightPaths;
+use crate::material::MaterialParameter;
+use crate::photon::{Caustics, PhotonMap};
+use crate::plugin;
+use crate::primitives::PrimitiveStore;
+use crate::render::{AmbientOcclusion, Integrator, MAX_BOUNCES};
+use crate::sampling::instance_seed;
+use crate::segmentation::SegmentationOutput;
+use crate::sequence::TemporalReuse;
+use crate::studio::Studio;
+use crate::tonemap::{Grading, ToneMapping};
+use crate::validate::{self, LoadError};
+use crate::{Camera, FVec, Float, Material, SceneObject, Shape};
+use image::RgbaImage;
+use nalgebra::{Matrix3, Matrix4};
+use serde::Deserialize;
+use serde_json::Value;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub enum Units {
+    #[serde(rename = "mm")]
+    Millimetres,
+    #[serde(rename = "cm")]
+    Centimetres,
+    #[default]
+    #[serde(rename = "m")]
+    Metres,
+    #[serde(rename = "km")]
+    Kilometres,
+    #[serde(rename = "in")]
+    Inches,
+    #[serde(rename = "ft")]
+    Feet,
+}
+
+impl Units {
+    pub(crate) fn metres(self) -> Float {
+        match self {
+            Units::Millimetres => 0.001,
+            Units::Centimetres => 0.01,
+            Units::Metres => 1.0,
+            Units::Kilometres => 1000.0,
+            Units::Inches => 0.0254,
+            Units::Feet => 0.3048,
+        }
+    }
+}
+
+// Axis pointing up in the scene file, which tools exporting for games and the web make y
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UpAxis {
+    #[serde(rename = "y")]
+    Y,
+    #[default]
+    #[serde(rename = "z")]
+    Z,
+}
+
+// Left handed files, as from Unity, Unreal or DirectX, are the mirror image of right handed ones
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Handedness {
+    #[default]
+    Right,
+    Left,
+}
+
+/*
+Turns the axes of a scene file into the renderer's, z up and right handed,
+or None where they already are. Y up turns to z up with the depth axis
+pointing away from the viewer, as Blender imports glTF, and that axis is
+flipped for left handed files.
+ */
+pub(crate) fn axes(up_axis: UpAxis, handedness: Handedness) -> Option<Matrix3<Float>> {
+    let depth = match handedness {
+        Handedness::Right => 1.0,
+        Handedness::Left => -1.0,
+    };
+    // Where each of the file's axes ends up
+    let columns = match up_axis {
+        UpAxis::Z => [FVec::x(), depth * FVec::y(), FVec::z()],
+        UpAxis::Y => [FVec::x(), FVec::z(), -depth * FVec::y()],
+    };
+    let matrix = Matrix3::from_columns(&columns);
+    (matrix != Matrix3::identity()).then_some(matrix)
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Scene {
+    pub(crate) camera: Camera,
+    pub(crate) default_colour: FVec,
+    // Seen by rays that hit nothing, in place of the default colour
+    pub(crate) environment: Option<Environment>,
+    // Sky colour, when there is no environment, and haze over distant surfaces
+    pub(crate) atmosphere: Option<Atmosphere>,
+    // Fog filling the space between objects, dimming distant ones and lit by the lights
+    pub(crate) fog: Option<Fog>,
+    // Fog inside boxes and spheres only, drawn over the scene's fog
+    #[serde(default)]
+    pub(crate) fog_volumes: Vec<LocalFog>,
+    // Grids of densities, such as scans, drawn through their transfer functions
+    #[serde(default)]
+    pub(crate) volumes: Vec<GridVolume>,
+    // Images projected onto the surfaces in front of them
+    #[serde(default)]
+    pub(crate) decals: Vec<Decal>,
+    pub(crate) ambient_light: FVec,
+    // Ambient light from a sky colour above and a ground colour below, in place of ambientLight
+    pub(crate) ambient_gradient: Option<AmbientGradient>,
+    // Ambient light from the environment or sky by the normal, in place of either of those
+    #[serde(default)]
+    pub(crate) ambient_from_environment: bool,
+    pub(crate) lights: Vec<LightSource>,
+    pub(crate) objects: Vec<SceneObject>,
+    // A floor and three lights fitted around the objects, for models with no setting of their own
+    pub(crate) studio: Option<Studio>,
+    // Number of lights sampled per shading point; all lights when unset
+    pub(crate) light_samples: Option<u32>,
+    /*
+    Seed for all random sampling; renders with the same seed are identical
+    down to the bit, on any number of threads.
+     */
+    #[serde(default)]
+    pub(crate) seed: u64,
+    #[serde(default)]
+    pub(crate) units: Units,
+    #[serde(default = "default_scale")]
+    pub(crate) scale: Float,
+    // Convention the positions and directions of the file are written in, converted on loading
+    #[serde(default)]
+    pub(crate) up_axis: UpAxis,
+    #[serde(default)]
+    pub(crate) handedness: Handedness,
+    // Frame of an animation to render
+    #[serde(default)]
+    pub(crate) frame: u32,
+    #[serde(default = "default_frame_rate")]
+    pub(crate) frame_rate: Float,
+    // Blending of each frame of a sequence with the one before, to take fewer samples
+    pub(crate) temporal_reuse: Option<TemporalReuse>,
+    // Most reflections and refractions followed along a path from the camera
+    #[serde(default = "default_max_bounces")]
+    pub(crate) max_bounces: u8,
+    // Extra distance in scene units rays leave surfaces from, against shadow acne on coarse meshes
+    #[serde(default)]
+    pub(crate) ray_bias: Float,
+    #[serde(default)]
+    pub(crate) integrator: Integrator,
+    /*
+    Follow each sample from the camera at a single wavelength, drawn over
+    the visible spectrum, so materials with an Abbe number refract each
+    colour their own way and glass splits white light as a prism does. The
+    colour of each sample is that of its wavelength, so images are noisier
+    in colour for the same number of samples.
+     */
+    #[serde(default)]
+    pub(crate) spectral: bool,
+    /*
+    Let the light of lights through transparent objects to the surfaces
+    behind them, dimmed by every surface it crosses and by what absorbs it
+    between them, so a stack of glass panes shades what is behind it less
+    than one opaque object would. The light goes straight on without
+    bending; caustics focus it.
+     */
+    #[serde(default)]
+    pub(crate) transparent_shadows: bool,
+    // Darkens the ambient term where geometry hides the surroundings; also used by ao rendering
+    pub(crate) ambient_occlusion: Option<AmbientOcclusion>,
+    // Bounces of the path integrator steered by the light found so far
+    pub(crate) guiding: Option<Guiding>,
+    // Light focused by mirrors and glass onto other surfaces, traced from the lights
+    pub(crate) caustics: Option<Caustics>,
+    // Width and height in pixels of the blocks whose primary rays are traced together
+    #[serde(default = "default_packet_size")]
+    pub(crate) packet_size: u32,
+    // Width and height in pixels of the tiles rendered by one thread at a time
+    #[serde(default = "default_tile_size")]
+    pub(crate) tile_size: u32,
+    // Extra per-pixel passes written next to the image
+    #[serde(default)]
+    pub(crate) aovs: Vec<AovOutput>,
+    // Deep OpenEXR file keeping every surface seen in each pixel with its depth
+    pub(crate) deep_output: Option<String>,
+    // OpenEXR file holding the image and every AOV as separate layers
+    pub(crate) multilayer_output: Option<String>,
+    // JSON file of the camera's intrinsics, lens distortion and pose in OpenCV's convention
+    pub(crate) calibration_output: Option<String>,
+    // Indexed PNG of the class of object seen in each pixel, with a legend; see SegmentationOutput
+    pub(crate) segmentation_output: Option<SegmentationOutput>,
+    // COCO-style JSON of the boxes around objects seen in the frame; see AnnotationOutput
+    pub(crate) annotation_output: Option<AnnotationOutput>,
+    pub(crate) colour_management: Option<ColourManagement>,
+    // Smoothing of the noise of the image before it is tone mapped
+    pub(crate) denoise: Option<Denoise>,
+    // Exposure, tone curve and display encoding of 8-bit outputs; written linear when unset
+    pub(crate) tone_mapping: Option<ToneMapping>,
+    // Colour grading of 8-bit outputs after tone mapping
+    pub(crate) grading: Option<Grading>,
+    #[serde(skip)]
+    pub(crate) colour: ColourPipeline,
+    // Debug overlay outlining the bounding box of every bounded object
+    #[serde(default)]
+    pub(crate) show_bounds: bool,
+    // Debug overlay outlining the boxes of the hierarchy over them, this many levels below its root
+    pub(crate) show_hierarchy: Option<usize>,
+    // Further images of parts of the scene, each written to its own file
+    #[serde(default)]
+    pub(crate) layers: Vec<RenderLayer>,
+    // Objects by index, name or tag rendered on their own, as if the rest were deleted
+    pub(crate) isolate: Option<Vec<usize>>,
+    #[serde(skip)]
+    pub(crate) primitives: PrimitiveStore,
+    // Objects giving off light, sampled as lights by the Whitted integrator
+    #[serde(skip)]
+    pub(crate) emitters: Vec<Emitter>,
+    #[serde(skip)]
+    pub(crate) photons: Option<PhotonMap>,
+    // The background's light on surfaces when ambient_from_environment is set
+    #[serde(skip)]
+    pub(crate) irradiance: Option<Irradiance>,
+    // Directory that assets of the scene, and of materials set later, are found relative to
+    #[serde(skip)]
+    pub(crate) base_dir: PathBuf,
+    // Set when objects have been moved since the primitives were built
+    #[serde(skip)]
+    pub(crate) geometry_changed: bool,
+    // Part of the light of the first surfaces rendered alone, for a layer that asks for one
+    #[serde(skip)]
+    pub(crate) pass: Option<Pass>,
+    // Paths of light kept, for a layer that asks for some
+    #[serde(skip)]
+    pub(crate) light_paths: Option<LightPaths>,
+}
+
+// The loaded scene is shared read-only between render threads and views
+const _: fn() = || {
+    pub(crate) fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Scene>();
+};
+
+/*
+A variant of the scene for compositing: only the selected objects and lights
+are present, optionally all with the same material, and optionally only one
+part of the light seen or the light along some paths (see LightPaths).
+ */
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RenderLayer {
+    pub(crate) path: String,
+    // Objects by index, name or tag (see resolve_names); every object when unset
+    pub(crate) objects: Option<Vec<usize>>,
+    // Lights by index, name or tag; every light when unset
+    pub(crate) lights: Option<Vec<usize>>,
+    pub(crate) material_override: Option<Material>,
+    // Objects by index, name or tag that occlude the layer but render transparent
+    #[serde(default)]
+    pub(crate) holdouts: Vec<usize>,
+    pub(crate) pass: Option<Pass>,
+    pub(crate) light_paths: Option<LightPaths>,
+}
+
+/*
+Light the first surface seen sends to the camera by one way of scattering
+it, so reflections and refractions can be dialled separately in the
+composite. The light is everything seen along the reflected or refracted
+ray, however many bounces it took, weighted as in the full render, so the
+passes add up to the light the surface reflects and passes on; the rest of
+its light, from the lights and emission, is left out.
+ */
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Pass {
+    // Mirror, glossy and clearcoat reflections
+    Reflection,
+    // Light passed on through transparent surfaces, totally internally reflected light included
+    Refraction,
+    /*
+    Light by the number of surfaces it met on the way after leaving a light:
+    scattered to the camera by the first surface seen, by way of one other
+    surface, or by way of more. What a surface receives from the lights,
+    ambient light, caustics and its own emission count as scattered there,
+    as does the light fog, haze and volumes send along the ray to it. Rays
+    carried on through dissolving surfaces count as a bounce.
+     */
+    Direct,
+    FirstBounce,
+    LaterBounces,
+}
+
+impl Pass {
+    // Whether light scattered towards the camera after the number of bounces is in the pass
+    pub(crate) fn counts_bounce(self, bounces: u8) -> bool {
+        match self {
+            Pass::Direct => bounces == 0,
+            Pass::FirstBounce => bounces == 1,
+            Pass::LaterBounces => bounces >= 2,
+            Pass::Reflection | Pass::Refraction => true,
+        }
+    }
+
+    // Whether paths that have bounced the number of times can add anything to the pass
+    pub(crate) fn reaches(self, bounces: u8) -> bool {
+        match self {
+            Pass::Direct => bounces == 0,
+            Pass::FirstBounce => bounces <= 1,
+            _ => true,
+        }
+    }
+}
+
+impl RenderLayer {
+    pub(crate) fn _includes(selection: &Option<Vec<usize>>, index: usize) -> bool {
+        selection.as_ref().is_none_or(|s| s.contains(&index))
+    }
+}
+
+pub fn default_scale() -> Float {
+    1.0
+}
+
+/*
+Give every object that names an entry of the scene's "geometry" with
+"instance": "name" a copy of that entry as its shape, so one shape can be
+placed many times, each with its own transform.
+ */
+fn resolve_instances(value: &mut Value) -> Result<(), String> {
+    let geometry = value.get("geometry").cloned().unwrap_or_default();
+    let Some(objects) = value.get_mut("objects").and_then(Value::as_array_mut) else {
+        return Ok(());
+    };
+    for (index, object) in objects.iter_mut().enumerate() {
+        let Some(name) = object.get("instance") else {
+            continue;
+        };
+        let name = name.as_str().ok_or(format!(
+            "object {index}: instance must be the name of some geometry"
+        ))?;
+        let shape = geometry
+            .get(name)
+            .ok_or(format!("object {index}: no geometry named {name:?}"))?;
+        object["shape"] = shape.clone();
+    }
+    Ok(())
+}
+
+// Name and tags of every entry of a list of objects or lights
+fn labels(value: &Value, list: &str) -> Vec<(Option<String>, Vec<String>)> {
+    let entries = value.get(list).and_then(Value::as_array);
+    entries
+        .into_iter()
+        .flatten()
+        .map(|entry| {
+            let name = entry
+                .get("name")
+                .and_then(Value::as_str)
+                .map(str::to_string);
+            let tags = entry
+                .get("tags")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten();
+            (
+                name,
+                tags.filter_map(Value::as_str).map(str::to_string).collect(),
+            )
+        })
+        .collect()
+}
+
+// Indices of the entries with the name or tag
+fn select(name: &str, labels: &[(Option<String>, Vec<String>)]) -> Vec<usize> {
+    labels
+        .iter()
+        .enumerate()
+        .filter(|(_, (own, tags))| own.as_deref() == Some(name) || tags.iter().any(|t| t == name))
+        .map(|(index, _)| index)
+        .collect()
+}
+
+// A list of indices, names and tags as the indices of every object or light it picks out
+fn resolve_selection(
+    selection: &mut Value,
+    labels: &[(Option<String>, Vec<String>)],
+    kind: &str,
+) -> Result<(), String> {
+    let Some(entries) = selection.as_array() else {
+        return Ok(());
+    };
+    let mut indices = Vec::new();
+    for entry in entries {
+        match entry.as_str() {
+            Some(name) => {
+                let selected = select(name, labels);
+                if selected.is_empty() {
+                    return Err(format!("no {kind} is named or tagged {name:?}"));
+                }
+                indices.extend(selected.into_iter().map(Value::from));
+            }
+            None => indices.push(entry.clone()),
+        }
+    }
+    *selection = Value::Array(indices);
+    Ok(())
+}
+
+/*
+Replace names and tags wherever objects and lights are referred to, in the
+selections of render layers, the objects lit by lights, the parents of
+objects and the isolated objects, by the indices of the objects or lights
+they pick out, so scenes can be edited without renumbering them. A parent
+must pick out a single object.
+ */
+fn resolve_names(value: &mut Value) -> Result<(), String> {
+    let objects = labels(value, "objects");
+    let lights = labels(value, "lights");
+    let layers = value.get_mut("layers").and_then(Value::as_array_mut);
+    for (index, layer) in layers.into_iter().flatten().enumerate() {
+        for (field, labels, kind) in [
+            ("objects", &objects, "object"),
+            ("holdouts", &objects, "object"),
+            ("lights", &lights, "light"),
+        ] {
+            if let Some(selection) = layer.get_mut(field) {
+                resolve_selection(selection, labels, kind)
+                    .map_err(|error| format!("layer {index}: {error}"))?;
+            }
+        }
+    }
+    let aovs = value.get_mut("aovs").and_then(Value::as_array_mut);
+    for (index, output) in aovs.into_iter().flatten().enumerate() {
+        if let Some(selection) = output.get_mut("objects") {
+            resolve_selection(selection, &objects, "object")
+                .map_err(|error| format!("aov {index}: {error}"))?;
+        }
+    }
+    if let Some(selection) = value.pointer_mut("/annotationOutput/objects") {
+        resolve_selection(selection, &objects, "object")
+            .map_err(|error| format!("annotationOutput: {error}"))?;
+    }
+    let light_list = value.get_mut("lights").and_then(Value::as_array_mut);
+    for (index, light) in light_list.into_iter().flatten().enumerate() {
+        if let Some(selection) = light.get_mut("objects") {
+            resolve_selection(selection, &objects, "object")
+                .map_err(|error| format!("{}: {error}", label("light", index, &lights)))?;
+        }
+    }
+    let decals = value.get_mut("decals").and_then(Value::as_array_mut);
+    for (index, decal) in decals.into_iter().flatten().enumerate() {
+        if let Some(selection) = decal.get_mut("objects") {
+            resolve_selection(selection, &objects, "object")
+                .map_err(|error| format!("decal {index}: {error}"))?;
+        }
+    }
+    let object_list = value.get_mut("objects").and_then(Value::as_array_mut);
+    for (index, object) in object_list.into_iter().flatten().enumerate() {
+        let Some(name) = object.get("parent").and_then(Value::as_str) else {
+            continue;
+        };
+        let [parent] = select(name, &objects)[..] else {
+            let own = label("object", index, &objects);
+            return Err(format!(
+                "{own}: parent {name:?} must pick out exactly one object"
+            ));
+        };
+        object["parent"] = parent.into();
+    }
+    if let Some(selection) = value.get_mut("isolate") {
+        resolve_selection(selection, &objects, "object")
+            .map_err(|error| format!("isolate: {error}"))?;
+    }
+    Ok(())
+}
+
+// How an object or light is referred to in messages: by index, and by name when it has one
+pub(crate) fn describe(kind: &str, index: usize, name: Option<&str>) -> String {
+    match name {
+        Some(name) => format!("{kind} {index} ({name:?})"),
+        None => format!("{kind} {index}"),
+    }
+}
+
+fn label(kind: &str, index: usize, labels: &[(Option<String>, Vec<String>)]) -> String {
+    describe(kind, index, labels[index].0.as_deref())
+}
+
+/*
+Rewrite every object shape whose type is the name of a registered shape
+plugin as {"type": "plugin", "name": ..., "params": {...}}, with the other
+fields of the shape as the parameters.
+ */
+fn resolve_plugins(value: &mut Value) {
+    let Some(objects) = value.get_mut("objects").and_then(Value::as_array_mut) else {
+        return;
+    };
+    for shape in objects
+        .iter_mut()
+        .filter_map(|object| object.get_mut("shape"))
+    {
+        let Some(params) = shape.as_object_mut() else {
+            continue;
+        };
+        let Some(name) = params
+            .get("type")
+            .and_then(Value::as_str)
+            .map(str::to_string)
+        else {
+            continue;
+        };
+        if plugin::is_shape(&name) {
+            params.remove("type");
+            *shape = serde_json::json!({"type": "plugin", "name": name, "params": params});
+        }
+    }
+}
+
+/*
+Fill in what physically based materials leave out, then move every scalar
+parameter of a material that is written as a string into the material's
+"expressions", keyed by the parameter, leaving 0 in its place until the
+expression is worked out.
+ */
+fn resolve_materials(value: &mut Value) {
+    for (list, field) in [("objects", "material"), ("layers", "materialOverride")] {
+        let items = value.get_mut(list).and_then(Value::as_array_mut);
+        for item in items.into_iter().flatten() {
+            if let Some(material) = item.get_mut(field).and_then(Value::as_object_mut) {
+                fill_physically_based(material);
+                move_expressions(material);
+            }
+        }
+    }
+}
+
+/*
+The error of a scene that does not deserialize, prefixed with the camera,
+object, light or layer it is in, found by deserializing each on its own.
+ */
+fn locate_parse_error(value: &Value, error: serde_json::Error) -> String {
+    fn entry_error<'a, T: Deserialize<'a>>(value: &'a Value, path: String) -> Option<String> {
+        T::deserialize(value)
+            .err()
+            .map(|error| format!("{path}: {error}"))
+    }
+    fn list_error<'a, T: Deserialize<'a>>(value: &'a Value, list: &str) -> Option<String> {
+        let entries = value
+            .get(list)
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten();
+        let mut entries = entries.enumerate();
+        entries.find_map(|(index, entry)| entry_error::<T>(entry, format!("{list}[{index}]")))
+    }
+    let camera = value.get("camera");
+    camera
+        .and_then(|camera| entry_error::<Camera>(camera, "camera".into()))
+        .or_else(|| list_error::<SceneObject>(value, "objects"))
+        .or_else(|| list_error::<LightSource>(value, "lights"))
+        .or_else(|| list_error::<RenderLayer>(value, "layers"))
+        .unwrap_or_else(|| error.to_string())
+}
+
+// Materials with a metallic or roughness have no use for the Phong-style parameters
+fn fill_physically_based(material: &mut serde_json::Map<String, Value>) {
+    if !material.contains_key("metallic") && !material.contains_key("roughness") {
+        return;
+    }
+    material.entry("metallic").or_insert(0.into());
+    for parameter in ["kDiffuse", "kAmbient", "kSpecular", "kReflect", "shine"] {
+        material.entry(parameter).or_insert(0.into());
+    }
+}
+
+fn move_expressions(material: &mut serde_json::Map<String, Value>) {
+    let is_parameter = |key: &str| serde_json::from_value::<MaterialParameter>(key.into()).is_ok();
+    let parameters: Vec<String> = material
+        .iter()
+        .filter(|(key, field)| field.is_string() && is_parameter(key))
+        .map(|(key, _)| key.clone())
+        .collect();
+    for parameter in parameters {
+        let expression = material.insert(parameter.clone(), 0.into());
+        let expressions = material
+            .entry("expressions")
+            .or_insert(serde_json::json!({}));
+        if let (Some(expressions), Some(expression)) = (expressions.as_object_mut(), expression) {
+            expressions.insert(parameter, expression);
+        }
+    }
+}
+
+pub fn default_max_bounces() -> u8 {
+    MAX_BOUNCES
+}
+
+pub fn default_packet_size() -> u32 {
+    4
+}
+
+pub fn default_tile_size() -> u32 {
+    32
+}
+
+pub fn default_frame_rate() -> Float {
+    24.0
+}
+
+impl Scene {
+    // Load a scene file, or read the scene from stdin when the path is "-"
+    pub fn from_file(path: &str) -> Result<Scene, LoadError> {
+        let value = Scene::read_value(path)?;
+        // Texture paths are relative to the scene file, or to the working directory for stdin
+        let base_dir = Path::new(path).parent().unwrap_or(Path::new(""));
+        Scene::from_value(value, base_dir)
+    }
+
+    /*
+    The scene description in a file, as JSON whatever format it is written
+    in: YAML for files ending .yaml or .yml, TOML for .toml, and JSON
+    otherwise and from stdin. All formats describe scenes the same way.
+     */
+    pub fn read_value(path: &str) -> Result<serde_json::Value, LoadError> {
+        if path == "-" {
+            let value = serde_json::from_reader(std::io::stdin().lock());
+            return value.map_err(|error| LoadError::Parse(error.into()));
+        }
+        let extension = Path::new(path).extension().and_then(|e| e.to_str());
+        let extension = extension.map(str::to_ascii_lowercase);
+        let read = || fs::read_to_string(path).map_err(|error| LoadError::Parse(error.into()));
+        let value = match extension.as_deref() {
+            Some("yaml" | "yml") => serde_yaml::from_str(&read()?).map_err(Into::into),
+            Some("toml") => toml::from_str(&read()?).map_err(Into::into),
+            _ => {
+                let file = File::open(path).map_err(|error| LoadError::Parse(error.into()))?;
+                serde_json::from_reader(BufReader::new(file)).map_err(Into::into)
+            }
+        };
+        value.map_err(LoadError::Parse)
+    }
+
+    pub fn from_value(value: serde_json::Value, base_dir: &Path) -> Result<Scene, LoadError> {
+        Scene::from_value_after(value, base_dir, None)
+    }
+
+    /*
+    The scene described, with its intersection structures refitted from
+    those of the previous frame's where the objects are made of the same
+    primitives, as the frames of an animation mostly are.
+     */
+    pub(crate) fn from_value_after(
+        mut value: serde_json::Value,
+        base_dir: &Path,
+        previous: Option<&PrimitiveStore>,
+    ) -> Result<Scene, LoadError> {
+        resolve_instances(&mut value).map_err(|error| LoadError::Parse(error.into()))?;
+        resolve_names(&mut value).map_err(|error| LoadError::Parse(error.into()))?;
+        resolve_plugins(&mut value);
+        resolve_materials(&mut value);
+        let mut scene = Scene::deserialize(&value)
+            .map_err(|error| LoadError::Parse(locate_parse_error(&value, error).into()))?;
+        let problems = validate::check_scene(&scene);
+        if !problems.is_empty() {
+            return Err(LoadError::Invalid(problems));
+        }
+        // Meshes read from the same file the same way are only read once
+        let mut meshes: Vec<Shape> = Vec::new();
+        for (index, object) in scene.objects.iter_mut().enumerate() {
+            let made = object.shape.load_plugin();
+            let described = |error| format!("{}: {error}", object.describe(index));
+            made.map_err(|error| LoadError::Parse(described(error).into()))?;
+            match meshes
+                .iter()
+                .find(|mesh| mesh.same_mesh_source(&object.shape))
+            {
+                Some(mesh) => object.shape = mesh.clone(),
+                None => {
+                    object.shape.load_mesh(base_dir).map_err(LoadError::Asset)?;
+                    if let Shape::Mesh { .. } = object.shape {
+                        meshes.push(object.shape.clone());
+                    }
+                }
+            }
+            if let Some(instances) = &object.instances {
+                let copies = instances.matrices(base_dir).map_err(|error| {
+                    LoadError::Asset(format!("{}: {error}", object.describe(index)).into())
+                })?;
+                object.copies = Arc::new(copies);
+            }
+            object.instance_seed = instance_seed(scene.seed, index);
+            object.material.apply_temperature();
+            let frame = scene.frame as Float;
+            object
+                .material
+                .settle_expressions(frame, scene.frame_rate, object.instance_seed);
+            if let Some(animation) = &object.animation {
+                let animated = animation.at(object.transform, frame).map_err(|error| {
+                    LoadError::Parse(format!("{}: {error}", object.describe(index)).into())
+                })?;
+                object.transform = Some(animated);
+            }
+        }
+        for volume in scene.volumes.iter_mut() {
+            volume.load(base_dir).map_err(LoadError::Asset)?;
+        }
+        scene
+            .place_objects()
+            .map_err(|error| LoadError::Parse(error.into()))?;
+        for layer in scene.layers.iter_mut() {
+            if let Some(material) = &mut layer.material_override {
+                material.apply_temperature();
+                material.settle_expressions(scene.frame as Float, scene.frame_rate, 0);
+            }
+        }
+        if scene.camera.animation.is_some() {
+            let mut next = scene.camera.clone();
+            next.apply_frame(scene.frame as Float + 1.0);
+            scene.camera.next_frame = Some(Box::new(next));
+        }
+        scene.camera.apply_frame(scene.frame as Float);
+        scene.camera.direction = scene.camera.direction.normalize();
+        for light in scene.lights.iter_mut() {
+            light.apply_frame(scene.frame as Float, scene.frame_rate);
+        }
+        scene.convert_axes();
+        if let Some(atmosphere) = &mut scene.atmosphere {
+            // Lit by the scene's sun unless given its own
+            let sun = atmosphere.sun.as_ref().or_else(|| {
+                scene.lights.iter().find_map(|light| match &light.shape {
+                    LightShape::Distant { direction } => Some(direction),
+                    _ => None,
+                })
+            });
+            let sun = sun.ok_or_else(|| {
+                LoadError::Parse("the atmosphere needs a sun or a distant light".into())
+            })?;
+            atmosphere.to_sun = sun.towards_light();
+        }
+        for warning in validate::check_geometry(&scene.objects) {
+            warn!("{}", warning);
+            if warning.problem.is_degenerate() {
+                scene.objects[warning.object].degenerate = true;
+            }
+        }
+        if let Some(isolated) = &scene.isolate {
+            for (index, object) in scene.objects.iter_mut().enumerate() {
+                object.hidden = !isolated.contains(&index);
+            }
+        }
+        if let Integrator::AmbientOcclusion = scene.integrator {
+            // Give the default radius in the scene's units like a written one
+            scene
+                .ambient_occlusion
+                .get_or_insert_with(AmbientOcclusion::default);
+        }
+        scene.convert_to_metres();
+        scene.add_studio();
+        scene.camera.prepare();
+        for object in scene.objects.iter_mut() {
+            if let Some(velocity) = object.velocity {
+                object.shutter_motion = velocity * scene.camera.shutter / scene.frame_rate;
+            }
+        }
+        if let Some(management) = &scene.colour_management {
+            scene.colour = management.pipeline(base_dir).map_err(LoadError::Asset)?;
+        }
+        if let Some(environment) = &mut scene.environment {
+            let sun = environment
+                .load(base_dir, &scene.colour)
+                .map_err(LoadError::Asset)?;
+            scene.lights.extend(sun);
+        }
+        if scene.ambient_from_environment {
+            let origin = scene.camera.position;
+            let irradiance = Irradiance::project(|direction| {
+                scene._get_background(&Ray {
+                    origin,
+                    direction: *direction,
+                    differential: None,
+                    time: 0.0,
+                })
+            });
+            scene.irradiance = Some(irradiance);
+        }
+        for light in scene.lights.iter_mut() {
+            light
+                .load_textures(base_dir, &scene.colour)
+                .map_err(LoadError::Asset)?;
+        }
+        for object in scene.objects.iter_mut() {
+            object
+                .material
+                .load_textures(base_dir, &scene.colour)
+                .map_err(LoadError::Asset)?;
+        }
+        for layer in scene.layers.iter_mut() {
+            if let Some(material) = &mut layer.material_override {
+                material
+                    .load_textures(base_dir, &scene.colour)
+                    .map_err(LoadError::Asset)?;
+            }
+        }
+        for decal in scene.decals.iter_mut() {
+            decal
+                .load_textures(base_dir, &scene.colour)
+                .map_err(LoadError::Asset)?;
+        }
+        scene.primitives = match previous {
+            Some(previous) => previous.rebuild(&scene.objects),
+            None => PrimitiveStore::build(&scene.objects),
+        };
+        scene.emitters = Emitter::find(&scene.objects);
+        scene.photons = scene.trace_photons();
+        scene.base_dir = base_dir.to_path_buf();
+        if let Some(importance) = &scene.camera.importance {
+            let map = importance.resolve(&scene, base_dir).map_err(|error| {
+                LoadError::Asset(format!("{}: {error}", scene.camera.describe()).into())
+            });
+            scene.camera.importance_map = map?;
+        }
+        scene.train_guide();
+        Ok(scene)
+    }
+
+    pub fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    pub fn objects(&self) -> &[SceneObject] {
+        &self.objects
+    }
+
+    pub fn lights(&self) -> &[LightSource] {
+        &self.lights
+    }
+
+    // Cut square to axis 0, 1 or 2 through the grid volume with the index; see GridVolume::slice
+    pub fn volume_slice(&self, volume: usize, axis: usize, depth: Float) -> Option<RgbaImage> {
+        Some(self.volumes.get(volume)?.slice(axis, depth))
+    }
+
+    // The scene as seen by a render layer, without further layers or extra outputs
+    pub(crate) fn with_layer(&self, layer: &RenderLayer) -> Scene {
+        let mut scene = self.clone();
+        scene.layers.clear();
+        scene.aovs.clear();
+        scene.deep_output = None;
+        scene.multilayer_output = None;
+        scene.calibration_output = None;
+        scene.segmentation_output = None;
+        scene.annotation_output = None;
+        scene.pass = layer.pass;
+        scene.light_paths = layer.light_paths.clone();
+        let kept: Vec<usize> = (0..scene.objects.len())
+            .filter(|index| {
+                RenderLayer::_includes(&layer.objects, *index) || layer.holdouts.contains(index)
+            })
+            .collect();
+        scene.objects = std::mem::take(&mut scene.objects)
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| kept.contains(index))
+            .map(|(index, mut object)| {
+                object.holdout = layer.holdouts.contains(&index);
+                if let Some(material) = &layer.material_override {
+                    object.material = material.clone();
+                }
+                object
+            })
+            .collect();
+        scene.lights = std::mem::take(&mut scene.lights)
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| RenderLayer::_includes(&layer.lights, *index))
+            .map(|(_, mut light)| {
+                // Objects lit by the light, renumbered as the kept objects now are
+                if let Some(objects) = &mut light.objects {
+                    *objects = objects
+                        .iter()
+                        .filter_map(|object| kept.iter().position(|k| k == object))
+                        .collect();
+                }
+                light
+            })
+            .collect();
+        for decal in scene.decals.iter_mut() {
+            if let Some(objects) = &mut decal.objects {
+                *objects = objects
+                    .iter()
+                    .filter_map(|object| kept.iter().position(|k| k == object))
+                    .collect();
+            }
+        }
+        scene.primitives = PrimitiveStore::build(&scene.objects);
+        scene.emitters = Emitter::find(&scene.objects);
+        scene.photons = scene.trace_photons();
+        scene.train_guide();
+        scene
+    }
+
+    /*
+    The scene inside a white furnace: no lights, only a uniform environment of
+    unit radiance that is both seen directly and lights every surface as
+    ambient light. Energy-conserving materials then render exactly as bright
+    as the environment.
+     */
+    pub(crate) fn furnace(&self) -> Scene {
+        let mut scene = self.clone();
+        scene.lights.clear();
+        scene.ambient_light = FVec::repeat(1.0);
+        scene.ambient_gradient = None;
+        scene.irradiance = None;
+        scene.default_colour = FVec::repeat(1.0);
+        scene.environment = None;
+        scene.atmosphere = None;
+        scene.fog = None;
+        scene.fog_volumes.clear();
+        scene
+    }
+
+    // Largest difference from the furnace environment over the primary samples hitting each object
+    pub(crate) fn furnace_errors(&self) -> Vec<Option<Float>> {
+        let furnace = self.furnace();
+        let gbuffer = furnace.trace_gbuffer(&furnace.camera);
+        let mut errors = vec![None; self.objects.len()];
+        for pixel in &gbuffer.pixels {
+            for (sample, index) in pixel.samples.iter().zip(0..) {
+                let Some(object) = sample.object() else {
+                    continue;
+                };
+                let colour = furnace._shade_sample(pixel, index);
+                let error = (colour - FVec::repeat(1.0)).amax();
+                errors[object] = Some(errors[object].map_or(error, |e: Float| e.max(error)));
+            }
+        }
+        errors
+    }
+
+    // Move every object's shape by its own transform and those of its parents
+    fn place_objects(&mut self) -> Result<(), String> {
+        let matrices = (0..self.objects.len())
+            .map(|index| self.placement(index))
+            .collect::<Result<Vec<_>, _>>()?;
+        let axes = axes(self.up_axis, self.handedness).map(|axes| axes.to_homogeneous());
+        for (index, (object, matrix)) in self.objects.iter_mut().zip(matrices).enumerate() {
+            if let Some(matrix) = matrix {
+                let local = object.shape.clone();
+                object.shape = local
+                    .transformed(&matrix)
+                    .map_err(|error| format!("{}: {error}", object.describe(index)))?;
+                object.local_shape = Some(local);
+            }
+            if object.copies.is_empty() {
+                continue;
+            }
+            if object.shape.bounding_box().is_none() {
+                let error = "only shapes with a bounding box can have instances";
+                return Err(format!("{}: {error}", object.describe(index)));
+            }
+            // The copies are moved in the file's axes, like the object itself
+            if let Some(axes) = axes {
+                let copies = object
+                    .copies
+                    .iter()
+                    .map(|copy| axes * copy * axes.transpose());
+                object.copies = Arc::new(copies.collect());
+            }
+        }
+        Ok(())
+    }
+
+    // Product of the transforms of an object and its parents, or None if none has one
+    pub(crate) fn placement(&self, index: usize) -> Result<Option<Matrix4<Float>>, String> {
+        let mut matrix: Option<Matrix4<Float>> = None;
+        let mut current = Some(index);
+        let described = self.objects[index].describe(index);
+        for _ in 0..=self.objects.len() {
+            let Some(i) = current else {
+                // The file's axes are turned into the renderer's after every transform
+                return Ok(match axes(self.up_axis, self.handedness) {
+                    Some(axes) => {
+                        let placed = matrix.unwrap_or_else(Matrix4::identity);
+                        Some(axes.to_homogeneous() * placed)
+                    }
+                    None => matrix,
+                });
+            };
+            let object = self
+                .objects
+                .get(i)
+                .ok_or(format!("{described}: parent {i} does not exist"))?;
+            if let Some(transform) = &object.transform {
+                let own = transform
+                    .matrix()
+                    .map_err(|error| format!("{}: {error}", object.describe(i)))?;
+                matrix = Some(own * matrix.unwrap_or_else(Matrix4::identity));
+            }
+            current = object.parent;
+        }
+        Err(format!("{described}: its parents form a cycle"))
+    }
+
+    /*
+    Turn the positions and directions of everything but the shapes of the
+    objects, which are placed with the conversion, from the file's axes into
+    the renderer's. Directions given by the sun's place in the sky are the
+    renderer's already.
+     */
+    pub(crate) fn convert_axes(&mut self) {
+        let Some(axes) = axes(self.up_axis, self.handedness) else {
+            return;
+        };
+        self.camera.reorient(&axes);
+        for light in self.lights.iter_mut() {
+            light.reorient(&axes);
+        }
+        if let Some(DistantDirection::Vector(direction)) = self
+            .atmosphere
+            .as_mut()
+            .and_then(|atmosphere| atmosphere.sun.as_mut())
+        {
+            *direction = axes * *direction;
+        }
+        for object in self.objects.iter_mut() {
+            object.velocity = object.velocity.map(|velocity| axes * velocity);
+        }
+        let materials = self.objects.iter_mut().map(|object| &mut object.material);
+        let overrides = self
+            .layers
+            .iter_mut()
+            .filter_map(|layer| layer.material_override.as_mut());
+        for waves in materials
+            .chain(overrides)
+            .filter_map(|material| material.waves.as_mut())
+        {
+            waves.wind = axes * waves.wind;
+        }
+        for fog in self.fog_volumes.iter_mut() {
+            fog.reorient(&axes);
+        }
+        for volume in self.volumes.iter_mut() {
+            volume.reorient(&axes);
+        }
+        for decal in self.decals.iter_mut() {
+            decal.reorient(&axes);
+        }
+    }
+
+    /*
+    Bring every position and length into metres so objects authored in
+    different units can share a scene. Light intensities are scaled with the
+    square of the factor, which keeps inverse-square falloff looking the same
+    as it did in the authored units.
+     */
+    pub(crate) fn convert_to_metres(&mut self) {
+        let factor = self.units.metres() * self.scale;
+        self.camera.scale(factor);
+        for light in self.lights.iter_mut() {
+            light.scale(factor);
+        }
+        if let Some(occlusion) = &mut self.ambient_occlusion {
+            occlusion.radius *= factor;
+        }
+        for object in self.objects.iter_mut() {
+            let object_factor = object
+                .units
+                .map_or(factor, |units| units.metres() * self.scale);
+            object.shape.scale(object_factor);
+            if let Some(local) = &mut object.local_shape {
+                local.scale(object_factor);
+            }
+            object.velocity = object.velocity.map(|velocity| velocity * object_factor);
+            if !object.copies.is_empty() {
+                let copies = object.copies.iter().map(|copy| {
+                    let mut copy = *copy;
+                    copy.fixed_view_mut::<3, 1>(0, 3).scale_mut(object_factor);
+                    copy
+                });
+                object.copies = Arc::new(copies.collect());
+            }
+            object.metres = object_factor;
+        }
+        for fog in self.fog_volumes.iter_mut() {
+            fog.scale(factor);
+        }
+        for volume in self.volumes.iter_mut() {
+            volume.scale(factor);
+        }
+        for decal in self.decals.iter_mut() {
+            decal.scale(factor);
+        }
+        if let Some(caustics) = &mut self.caustics {
+            caustics.radius *= factor;
+        }
+        self.ray_bias *= factor;
+        self.units = Units::Metres;
+        self.scale = 1.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // The description written to a file with the extension and read back
+    fn read_as(extension: &str, text: &str) -> Result<serde_json::Value, LoadError> {
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let file = FILES.fetch_add(1, Ordering::Relaxed);
+        let name = format!(
+            "raytracer-read-value-{}-{file}.{extension}",
+            std::process::id()
+        );
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, text).unwrap();
+        let value = Scene::read_value(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        value
+    }
+
+    #[test]
+    fn reads_yaml_and_toml_as_json() {
+        let json = r#"{
+            "camera": {"samples": 4, "position": [0, 1.5, -2]},
+            "objects": [{"name": "floor # one"}, {"name": "caf\u00e9"}]
+        }"#;
+        let yaml = "\
+camera:
+  samples: 4  # comment
+  position: [0, 1.5, -2]
+objects:
+  - name: 'floor # one'
+  - name: \"caf\\u00e9\"
+";
+        let toml = "\
+[camera]
+samples = 4  # comment
+position = [
+  0,
+  1.5,
+  -2,
+]
+
+[[objects]]
+name = 'floor # one'
+
+[[objects]]
+name = \"caf\\u00e9\"
+";
+        let json = read_as("json", json).unwrap();
+        assert_eq!(read_as("yaml", yaml).unwrap(), json);
+        assert_eq!(read_as("toml", toml).unwrap(), json);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn guided_renders_do_not_depend_on_threads() {
+        let value = serde_json::json!({
+            "camera": {
+                "position": [-6, 0, 1],
+                "direction": [1, 0, -0.15],
+                "screenDistance": 1,
+                "screenWidth": 1.33333,
+                "screenHeight": 1,
+                "screenColumns": 24,
+                "screenRows": 18,
+                "samples": 2
+            },
+            "defaultColour": [0, 0, 0],
+            "ambientLight": [0, 0, 0],
+            "lights": [{"pos": [2, -1.5, 2], "colour": [1, 1, 1], "intensity": 5}],
+            "objects": [
+                {
+                    "material": {"colour": [0.8, 0.3, 0.3], "kDiffuse": 0.9, "kAmbient": 0.1, "kSpecular": 0, "kReflect": 0, "shine": 1},
+                    "shape": {"type": "sphere", "centre": [2, 0, 0], "radius": 1}
+                },
+                {
+                    "material": {"colour": [0.8, 0.8, 0.8], "kDiffuse": 0.9, "kAmbient": 0.1, "kSpecular": 0, "kReflect": 0, "shine": 1},
+                    "shape": {"type": "plane", "point": [0, 0, -1], "normal": [0, 0, 1]}
+                }
+            ],
+            "integrator": "path",
+            "guiding": {"cellSize": 2, "trainingPasses": 2},
+            "seed": 7
+        });
+        let render = |threads| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            pool.install(|| {
+                let scene = Scene::from_value(value.clone(), Path::new(".")).unwrap();
+                scene.render_linear(&scene.camera).into_raw()
+            })
+        };
+        let single = render(1);
+        assert_eq!(render(3), single);
+        assert_eq!(render(3), single);
+    }
+
+    #[test]
+    fn rejects_malformed_descriptions() {
+        let parse_error = |result| matches!(result, Err(LoadError::Parse(_)));
+        assert!(parse_error(read_as("yaml", "a:\n  b: 1\n c: 2\n")));
+        assert!(parse_error(read_as("yaml", "a: &x 1\nb: *y\n")));
+        assert!(parse_error(read_as("toml", "a = [1,")));
+        assert!(parse_error(read_as("toml", "a = 1\na = 2\n")));
+    }
+}
@@ -0,0 +1,119 @@
+#[cfg(feature = "scripting")]
+use crate::core::double;
+use crate::{FVec, Float};
+#[cfg(feature = "scripting")]
+use rhai::{Array, Dynamic, Engine, Scope, AST};
+use std::fmt;
+
+// Steps a script may take for one point before it is stopped, so a runaway loop cannot hang
+#[cfg(feature = "scripting")]
+const MAX_OPERATIONS: u64 = 100_000;
+
+/*
+A surface colour computed by a Rhai script, for trying out procedural looks
+without rebuilding the renderer. The script sees the point relative to the
+object in metres as `p`, its texture coordinates as `uv` and the surface
+normal as `normal`, each an array of numbers, and ends with the colour: an
+array of red, green and blue, or one number for a grey. It is compiled once
+when the material is loaded and then run for every point shaded.
+ */
+pub struct ScriptTexture {
+    #[cfg(feature = "scripting")]
+    engine: Engine,
+    #[cfg(feature = "scripting")]
+    ast: AST,
+}
+
+impl ScriptTexture {
+    // The compiled script, run once at the origin so mistakes are found before rendering
+    #[cfg(feature = "scripting")]
+    pub(crate) fn compile(source: &str) -> Result<ScriptTexture, String> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let ast = engine.compile(source).map_err(|error| error.to_string())?;
+        let texture = ScriptTexture { engine, ast };
+        texture.run(&FVec::zeros(), (0.0, 0.0), &FVec::z())?;
+        Ok(texture)
+    }
+
+    #[cfg(not(feature = "scripting"))]
+    pub(crate) fn compile(_source: &str) -> Result<ScriptTexture, String> {
+        Err("scripted texture support is not built in".to_string())
+    }
+
+    // Colour at the point, or black where the script fails
+    pub(crate) fn colour(&self, p: &FVec, uv: (Float, Float), normal: &FVec) -> FVec {
+        self.run(p, uv, normal).unwrap_or_else(|_| FVec::zeros())
+    }
+
+    #[cfg(feature = "scripting")]
+    fn run(&self, p: &FVec, (u, v): (Float, Float), normal: &FVec) -> Result<FVec, String> {
+        let array = |values: &[Float]| -> Array {
+            values
+                .iter()
+                .map(|&x| Dynamic::from_float(double(x)))
+                .collect()
+        };
+        let mut scope = Scope::new();
+        scope.push("p", array(p.as_slice()));
+        scope.push("uv", array(&[u, v]));
+        scope.push("normal", array(normal.as_slice()));
+        let colour: Dynamic = self
+            .engine
+            .eval_ast_with_scope(&mut scope, &self.ast)
+            .map_err(|error| error.to_string())?;
+        let number = |value: &Dynamic| {
+            let float = value.as_float().ok();
+            float
+                .or(value.as_int().ok().map(|i| i as f64))
+                .map(|x| x as Float)
+        };
+        if let Some(grey) = number(&colour) {
+            return Ok(FVec::repeat(grey));
+        }
+        let channels: Option<Vec<Float>> = colour
+            .into_array()
+            .ok()
+            .and_then(|channels| channels.iter().map(number).collect());
+        match channels.as_deref() {
+            Some(&[r, g, b]) => Ok(FVec::new(r, g, b)),
+            _ => Err("the script must end with [r, g, b] or a number".to_string()),
+        }
+    }
+
+    #[cfg(not(feature = "scripting"))]
+    fn run(&self, _p: &FVec, _uv: (Float, Float), _normal: &FVec) -> Result<FVec, String> {
+        Err("scripted texture support is not built in".to_string())
+    }
+}
+
+impl fmt::Debug for ScriptTexture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ScriptTexture")
+    }
+}
+
+#[cfg(all(test, feature = "scripting"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colours_from_arrays_and_numbers() {
+        let texture = ScriptTexture::compile("[p[0], uv[1], normal[2]]").unwrap();
+        let colour = texture.colour(&FVec::new(0.25, 0.0, 0.0), (0.0, 0.5), &FVec::z());
+        assert_eq!(colour, FVec::new(0.25, 0.5, 1.0));
+        let grey = ScriptTexture::compile("let x = 1; x").unwrap();
+        assert_eq!(
+            grey.colour(&FVec::zeros(), (0.0, 0.0), &FVec::z()),
+            FVec::repeat(1.0)
+        );
+    }
+
+    #[test]
+    fn rejects_scripts_without_a_colour() {
+        assert!(ScriptTexture::compile("[1, 2").is_err());
+        assert!(ScriptTexture::compile("\"red\"").is_err());
+        assert!(ScriptTexture::compile("[1, 2]").is_err());
+        assert!(ScriptTexture::compile("loop {}").is_err());
+    }
+}
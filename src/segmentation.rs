@@ -0,0 +1,137 @@
+use crate::gbuffer::{crop_overscan, GBuffer};
+use crate::sampling::Rng;
+use crate::Scene;
+use image::{GrayImage, ImageError, Luma};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/*
+Map of the class of object each pixel sees, for training models to segment
+images: an indexed PNG whose every index is the classId of the object most
+of the pixel's samples hit, 0 where they hit nothing or an object without a
+class, with a legend in JSON of each class's colour in the palette and its
+objects.
+ */
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SegmentationOutput {
+    pub path: String,
+    // File the legend is written to; the image's path with a json extension by default
+    pub legend: Option<String>,
+    // Names of the classes by id, written into the legend
+    #[serde(default)]
+    pub names: BTreeMap<u8, String>,
+}
+
+impl SegmentationOutput {
+    pub(crate) fn legend_path(&self) -> String {
+        match &self.legend {
+            Some(legend) => legend.clone(),
+            None => Path::new(&self.path)
+                .with_extension("json")
+                .to_string_lossy()
+                .into_owned(),
+        }
+    }
+}
+
+// Colour of a class in the palette: black for no class, and a stable random colour for the rest
+fn class_colour(class: u8) -> [u8; 3] {
+    if class == 0 {
+        return [0; 3];
+    }
+    let mut rng = Rng::new(class as u64, 0);
+    [(); 3].map(|_| 64 + (rng.next_u32() % 192) as u8)
+}
+
+impl GBuffer {
+    // Class of each pixel, by what most of its samples hit, cropped to the frame
+    pub fn classes(&self, class_of: impl Fn(usize) -> u8) -> GrayImage {
+        let mut image = GrayImage::new(self.width, self.height);
+        for pixel in &self.pixels {
+            let mut counts = [0u32; 256];
+            for sample in &pixel.samples {
+                counts[sample.object().map_or(0, &class_of) as usize] += 1;
+            }
+            let class = (0..=255u8)
+                .max_by_key(|&class| counts[class as usize])
+                .unwrap_or(0);
+            let (x, y) = (pixel.x - self.origin.0, pixel.y - self.origin.1);
+            image.put_pixel(x, y, Luma([class]));
+        }
+        crop_overscan(image, self.overscan)
+    }
+}
+
+impl Scene {
+    // Legend of the classes of the scene's objects, with the colours they have in the palette
+    fn segmentation_legend(&self, output: &SegmentationOutput) -> Value {
+        let mut objects: BTreeMap<u8, Vec<usize>> = BTreeMap::new();
+        objects.insert(0, Vec::new());
+        for (index, object) in self.objects.iter().enumerate() {
+            objects
+                .entry(object.class_id.unwrap_or(0))
+                .or_default()
+                .push(index);
+        }
+        let classes: Vec<Value> = objects
+            .into_iter()
+            .map(|(class, objects)| {
+                json!({
+                    "id": class,
+                    "name": output.names.get(&class),
+                    "colour": class_colour(class),
+                    "objects": objects,
+                })
+            })
+            .collect();
+        json!({ "classes": classes })
+    }
+
+    // Write the class map and its legend
+    pub(crate) fn write_segmentation(
+        &self,
+        gbuffer: &GBuffer,
+        output: &SegmentationOutput,
+    ) -> Result<(), ImageError> {
+        let classes = gbuffer.classes(|object| self.objects[object].class_id.unwrap_or(0));
+        write_indexed(&output.path, &classes)?;
+        let legend = serde_json::to_string_pretty(&self.segmentation_legend(output))
+            .map_err(std::io::Error::other)?;
+        std::fs::write(output.legend_path(), legend + "\n")?;
+        Ok(())
+    }
+}
+
+// Write the image's values as indices into the palette of class colours
+#[cfg(feature = "png")]
+fn write_indexed(path: &str, image: &GrayImage) -> Result<(), ImageError> {
+    use std::fs::File;
+    use std::io::{BufWriter, Error};
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, image.width(), image.height());
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette((0..=255).flat_map(class_colour).collect::<Vec<u8>>());
+    let mut writer = encoder.write_header().map_err(Error::other)?;
+    writer
+        .write_image_data(image.as_raw())
+        .map_err(Error::other)?;
+    writer.finish().map_err(Error::other)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "png"))]
+fn write_indexed(_path: &str, _image: &GrayImage) -> Result<(), ImageError> {
+    use image::error::{ImageFormatHint, UnsupportedError, UnsupportedErrorKind};
+    use image::ImageFormat;
+    let format = ImageFormatHint::Exact(ImageFormat::Png);
+    Err(ImageError::Unsupported(
+        UnsupportedError::from_format_and_kind(
+            format.clone(),
+            UnsupportedErrorKind::Format(format),
+        ),
+    ))
+}
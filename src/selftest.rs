@@ -0,0 +1,469 @@
+use crate::core::consts::PI;
+use crate::core::intersect::{intersect_plane, intersect_sphere, intersect_triangle};
+use crate::core::ray::{Intersection, Ray};
+use crate::core::shading::{blinn_phong, ggx, lambert, reflect};
+use crate::media::MediumStack;
+use crate::microfacet::compensation;
+use crate::{FVec, Float, Scene};
+use serde_json::json;
+use std::path::Path;
+
+// Relative tolerance for values computed in closed form, a few thousand rounding errors
+const TOLERANCE: Float = 4096.0 * Float::EPSILON;
+// Steps per angle when integrating over the hemisphere
+const QUADRATURE_STEPS: u32 = 512;
+// Furnace renders are exact up to rounding, accumulated over every bounce
+const FURNACE_TOLERANCE: Float = 1e-9;
+// Energy compensation is tabulated, and holds to a couple of percent at grazing angles
+const COMPENSATION_TOLERANCE: Float = 0.02;
+
+type Check = (&'static str, fn() -> Result<(), String>);
+
+const CHECKS: [Check; 15] = [
+    ("sphere hit from outside", sphere_hit_from_outside),
+    ("sphere hit from inside", sphere_hit_from_inside),
+    ("sphere miss", sphere_miss),
+    ("sphere hit within error bound", sphere_error_bound),
+    ("plane hit at an angle", plane_hit),
+    ("plane parallel and behind", plane_misses),
+    ("triangle hit with interpolated normal and uv", triangle_hit),
+    ("triangle outside, parallel and behind", triangle_misses),
+    (
+        "triangles sharing an edge leave no gap",
+        triangle_shared_edge,
+    ),
+    ("reflection preserves length", reflection_length),
+    ("Blinn-Phong peak at the mirror direction", blinn_phong_peak),
+    ("Lambert white furnace", lambert_furnace),
+    ("GGX white furnace with energy compensation", ggx_furnace),
+    ("material white furnace", material_furnace),
+    ("nested dielectric interfaces", nested_dielectrics),
+];
+
+/*
+Run numerical checks of the intersection and shading maths against analytic
+results, printing one line per check. Returns whether all of them passed.
+ */
+pub fn run() -> bool {
+    let mut passed = 0;
+    for (name, check) in CHECKS {
+        match check() {
+            Ok(()) => {
+                passed += 1;
+                println!("PASS {name}");
+            }
+            Err(message) => println!("FAIL {name}: {message}"),
+        }
+    }
+    println!("{passed}/{} checks passed", CHECKS.len());
+    passed == CHECKS.len()
+}
+
+/*
+Render the scene in a white furnace and report, per object, how far its
+brightness strays from the environment. Returns whether every visible
+object conserves energy exactly.
+ */
+pub fn furnace(scene: &Scene) -> bool {
+    let mut passed = true;
+    for (index, error) in scene.furnace_errors().into_iter().enumerate() {
+        let object = scene.objects()[index].describe(index);
+        match error {
+            None => println!("SKIP {object}: not visible"),
+            Some(error) if error <= FURNACE_TOLERANCE => println!("PASS {object}"),
+            Some(error) => {
+                passed = false;
+                println!("FAIL {object}: off the environment radiance by up to {error}");
+            }
+        }
+    }
+    passed
+}
+
+fn ray(origin: FVec, direction: FVec) -> Ray {
+    Ray {
+        origin,
+        direction,
+        differential: None,
+        time: 0.0,
+    }
+}
+
+fn expect_close(what: &str, actual: Float, expected: Float) -> Result<(), String> {
+    if (actual - expected).abs() <= TOLERANCE * expected.abs().max(1.0) {
+        Ok(())
+    } else {
+        Err(format!("{what} is {actual}, expected {expected}"))
+    }
+}
+
+fn expect_vec_close(what: &str, actual: &FVec, expected: &FVec) -> Result<(), String> {
+    if (actual - expected).norm() <= TOLERANCE * expected.norm().max(1.0) {
+        Ok(())
+    } else {
+        Err(format!("{what} is {actual:?}, expected {expected:?}"))
+    }
+}
+
+fn expect_hit(hit: Option<Intersection>) -> Result<Intersection, String> {
+    hit.ok_or_else(|| "no intersection found".to_string())
+}
+
+fn sphere_hit_from_outside() -> Result<(), String> {
+    let centre = FVec::new(5.0, 0.0, 0.0);
+    let r = ray(FVec::zeros(), FVec::new(2.0, 0.0, 0.0));
+    let hit = expect_hit(intersect_sphere(&centre, 1.0, &r, 0.0))?;
+    // Unnormalised directions scale t
+    expect_close("t", hit.t, 2.0)?;
+    expect_vec_close("position", &hit.pos, &FVec::new(4.0, 0.0, 0.0))?;
+    expect_vec_close("normal", &hit.normal, &FVec::new(-1.0, 0.0, 0.0))
+}
+
+fn sphere_hit_from_inside() -> Result<(), String> {
+    let centre = FVec::new(1.0, 2.0, 3.0);
+    let direction = FVec::new(1.0, 1.0, 1.0).normalize();
+    let hit = expect_hit(intersect_sphere(&centre, 2.0, &ray(centre, direction), 0.0))?;
+    expect_close("t", hit.t, 2.0)?;
+    expect_vec_close("normal", &hit.normal, &direction)
+}
+
+fn sphere_miss() -> Result<(), String> {
+    let centre = FVec::new(5.0, 0.0, 0.0);
+    let past = ray(FVec::zeros(), FVec::new(1.0, 0.0, 0.0));
+    let checks = [
+        // Passes just outside the silhouette
+        intersect_sphere(
+            &centre,
+            1.0,
+            &ray(FVec::new(0.0, 1.0 + 1e-6, 0.0), past.direction),
+            0.0,
+        ),
+        // Sphere behind the origin
+        intersect_sphere(&centre, 1.0, &ray(FVec::zeros(), -past.direction), 0.0),
+        // Both hits nearer than the minimum distance
+        intersect_sphere(&centre, 1.0, &past, 10.0),
+    ];
+    match checks.iter().position(Option::is_some) {
+        Some(index) => Err(format!("case {index} reported a hit")),
+        None => Ok(()),
+    }
+}
+
+fn sphere_error_bound() -> Result<(), String> {
+    let centre = FVec::new(1e3, -2e3, 5e2);
+    let radius = 3.7;
+    let origin = FVec::new(-1e3, 1e3, 1e3);
+    // Aim off-centre so the hit is at a grazing angle
+    let target = centre + FVec::new(radius * 0.9, 0.0, 0.0);
+    let hit = expect_hit(intersect_sphere(
+        &centre,
+        radius,
+        &ray(origin, target - origin),
+        0.0,
+    ))?;
+    let distance = ((hit.pos - centre).norm() - radius).abs();
+    let bound = hit.error.norm();
+    if distance <= bound {
+        Ok(())
+    } else {
+        Err(format!(
+            "hit is {distance} off the surface, error bound {bound}"
+        ))
+    }
+}
+
+fn plane_hit() -> Result<(), String> {
+    let point = FVec::new(0.0, 0.0, -2.0);
+    let normal = FVec::new(0.0, 0.0, 1.0);
+    let direction = FVec::new(1.0, 0.0, -1.0);
+    let hit = expect_hit(intersect_plane(
+        &point,
+        &normal,
+        &ray(FVec::zeros(), direction),
+        0.0,
+    ))?;
+    expect_close("t", hit.t, 2.0)?;
+    expect_vec_close("position", &hit.pos, &FVec::new(2.0, 0.0, -2.0))
+}
+
+fn plane_misses() -> Result<(), String> {
+    let point = FVec::new(0.0, 0.0, -2.0);
+    let normal = FVec::new(0.0, 0.0, 1.0);
+    let parallel = ray(FVec::zeros(), FVec::new(1.0, 1.0, 0.0));
+    let away = ray(FVec::zeros(), FVec::new(0.0, 0.0, 1.0));
+    if intersect_plane(&point, &normal, &parallel, 0.0).is_some() {
+        return Err("parallel ray reported a hit".to_string());
+    }
+    if intersect_plane(&point, &normal, &away, 0.0).is_some() {
+        return Err("ray pointing away reported a hit".to_string());
+    }
+    Ok(())
+}
+
+fn triangle_hit() -> Result<(), String> {
+    let vertices = [
+        FVec::new(0.0, 0.0, 0.0),
+        FVec::new(4.0, 0.0, 0.0),
+        FVec::new(0.0, 4.0, 0.0),
+    ];
+    let normals = [
+        FVec::new(0.0, 0.0, 1.0),
+        FVec::new(1.0, 0.0, 0.0),
+        FVec::new(0.0, 1.0, 0.0),
+    ];
+    let uvs = [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)];
+    // A quarter of the way along each leg, so the barycentric coordinates are (1/2, 1/4, 1/4)
+    let r = ray(FVec::new(1.0, 1.0, 3.0), FVec::new(0.0, 0.0, -1.0));
+    let hit = intersect_triangle(&vertices, Some(&normals), None, Some(&uvs), None, &r, 0.0);
+    let hit = expect_hit(hit)?;
+    expect_close("t", hit.t, 3.0)?;
+    expect_vec_close("position", &hit.pos, &FVec::new(1.0, 1.0, 0.0))?;
+    let normal = FVec::new(0.25, 0.25, 0.5).normalize();
+    expect_vec_close("normal", &hit.normal, &normal)?;
+    let face = hit.geometric_normal.unwrap_or(hit.normal);
+    expect_vec_close("face normal", &face, &FVec::new(0.0, 0.0, 1.0))?;
+    let (u, v) = hit.uv.ok_or("no texture coordinates")?;
+    expect_close("u", u, 0.25)?;
+    expect_close("v", v, 0.25)
+}
+
+fn triangle_misses() -> Result<(), String> {
+    let vertices = [
+        FVec::new(0.0, 0.0, 0.0),
+        FVec::new(4.0, 0.0, 0.0),
+        FVec::new(0.0, 4.0, 0.0),
+    ];
+    let down = FVec::new(0.0, 0.0, -1.0);
+    let checks = [
+        // Just past the hypotenuse
+        ray(FVec::new(2.0, 2.0 + 1e-3, 1.0), down),
+        // Beside the first leg
+        ray(FVec::new(1.0, -1e-3, 1.0), down),
+        // In the plane of the triangle
+        ray(FVec::new(-1.0, 1.0, 0.0), FVec::new(1.0, 0.0, 0.0)),
+        // Triangle behind the origin
+        ray(FVec::new(1.0, 1.0, -1.0), down),
+    ];
+    let hits = checks
+        .iter()
+        .map(|r| intersect_triangle(&vertices, None, None, None, None, r, 0.0));
+    match hits.collect::<Vec<_>>().iter().position(Option::is_some) {
+        Some(index) => Err(format!("case {index} reported a hit")),
+        None => Ok(()),
+    }
+}
+
+fn triangle_shared_edge() -> Result<(), String> {
+    // Two halves of a square split along its diagonal from (0, 0) to (2, 2)
+    let corners = [
+        FVec::new(0.0, 0.0, 0.0),
+        FVec::new(2.0, 0.0, 0.0),
+        FVec::new(2.0, 2.0, 0.0),
+        FVec::new(0.0, 2.0, 0.0),
+    ];
+    let halves = [
+        [corners[0], corners[1], corners[2]],
+        [corners[0], corners[2], corners[3]],
+    ];
+    // Through the diagonal, at a corner and at points along it
+    for along in [0.0, 0.25, 0.5, 1.0, 1.75, 2.0] {
+        let r = ray(FVec::new(along, along, 1.0), FVec::new(0.0, 0.0, -1.0));
+        let hits = halves
+            .iter()
+            .filter_map(|half| intersect_triangle(half, None, None, None, None, &r, 0.0));
+        if hits.count() == 0 {
+            return Err(format!(
+                "ray through ({along}, {along}) fell between the halves"
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn reflection_length() -> Result<(), String> {
+    let d = FVec::new(0.3, -0.4, -0.866);
+    let normal = FVec::new(0.2, 0.1, 1.0).normalize();
+    let r = reflect(&d, &normal);
+    expect_close("length", r.norm(), d.norm())?;
+    expect_close("normal component", r.dot(&normal), -d.dot(&normal))
+}
+
+fn blinn_phong_peak() -> Result<(), String> {
+    let normal = FVec::new(0.0, 0.0, 1.0);
+    let to_light = FVec::new(0.6, 0.0, 0.8);
+    let to_viewer = FVec::new(-0.6, 0.0, 0.8);
+    expect_close(
+        "highlight",
+        blinn_phong(&normal, &to_light, &to_viewer, 50.0),
+        1.0,
+    )
+}
+
+/*
+A Lambertian surface lit by a uniform white environment reflects exactly the
+incoming radiance: the cosine term over pi integrates to one over the
+hemisphere. The integral is evaluated with the midpoint rule.
+ */
+fn lambert_furnace() -> Result<(), String> {
+    let normal = FVec::new(0.0, 0.0, 1.0);
+    let d_theta = 0.5 * PI / QUADRATURE_STEPS as Float;
+    let d_phi = 2.0 * PI / QUADRATURE_STEPS as Float;
+    let mut total = 0.0;
+    for i in 0..QUADRATURE_STEPS {
+        let theta = (i as Float + 0.5) * d_theta;
+        for j in 0..QUADRATURE_STEPS {
+            let phi = (j as Float + 0.5) * d_phi;
+            let direction = FVec::new(
+                theta.sin() * phi.cos(),
+                theta.sin() * phi.sin(),
+                theta.cos(),
+            );
+            total += lambert(&normal, &direction) / PI * theta.sin() * d_theta * d_phi;
+        }
+    }
+    // The midpoint rule is accurate to the square of the step
+    let tolerance = 10.0 * d_theta * d_theta;
+    if (total - 1.0).abs() <= tolerance {
+        Ok(())
+    } else {
+        Err(format!("reflected {total} of the incoming light"))
+    }
+}
+
+/*
+A white metal reflects all the light reaching it at any roughness and view
+angle once the light scattering between its microfacets is made up for. The
+integral is evaluated with the midpoint rule, between the entries of the
+compensation's table.
+ */
+fn ggx_furnace() -> Result<(), String> {
+    let normal = FVec::new(0.0, 0.0, 1.0);
+    let white = FVec::repeat(1.0);
+    let d_theta = 0.5 * PI / QUADRATURE_STEPS as Float;
+    let d_phi = 2.0 * PI / QUADRATURE_STEPS as Float;
+    for roughness in [0.35, 0.65, 1.0 as Float] {
+        let alpha = roughness * roughness;
+        for n_dot_v in [0.1, 0.4, 0.9 as Float] {
+            let to_viewer = FVec::new((1.0 - n_dot_v * n_dot_v).sqrt(), 0.0, n_dot_v);
+            let scale = compensation(alpha, n_dot_v, &white).x;
+            let mut total = 0.0;
+            for i in 0..QUADRATURE_STEPS {
+                let theta = (i as Float + 0.5) * d_theta;
+                for j in 0..QUADRATURE_STEPS {
+                    let phi = (j as Float + 0.5) * d_phi;
+                    let direction = FVec::new(
+                        theta.sin() * phi.cos(),
+                        theta.sin() * phi.sin(),
+                        theta.cos(),
+                    );
+                    let reflected = ggx(&normal, &direction, &to_viewer, alpha, &white).x;
+                    total += scale * reflected / PI * theta.sin() * d_theta * d_phi;
+                }
+            }
+            if (total - 1.0).abs() > COMPENSATION_TOLERANCE {
+                return Err(format!(
+                    "reflected {total} of the incoming light at roughness {roughness} \
+                     and view cosine {n_dot_v}"
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn material(colour: Float, k_ambient: Float, k_reflect: Float) -> serde_json::Value {
+    json!({
+        "colour": [colour, colour, colour],
+        "kDiffuse": 0.8,
+        "kAmbient": k_ambient,
+        "kSpecular": 0.5,
+        "kReflect": k_reflect,
+        "shine": 20.0,
+    })
+}
+
+fn dielectric(k_reflect: Float, ior: Float, priority: u32) -> serde_json::Value {
+    let mut material = material(1.0, 0.0, k_reflect);
+    material["kTransmit"] = json!(1.0 - k_reflect);
+    material["ior"] = json!(ior);
+    material["priority"] = json!(priority);
+    material
+}
+
+/*
+Materials whose ambient albedo, mirror reflectance and transmittance sum to
+one, on spheres that reflect each other and a floor, must all render as
+bright as the furnace environment.
+ */
+fn material_furnace() -> Result<(), String> {
+    let sphere = |y: Float, z: Float, material| {
+        json!({
+            "material": material,
+            "shape": {"type": "sphere", "centre": [0.0, y, z], "radius": 1.0},
+        })
+    };
+    let value = json!({
+        "camera": {
+            "position": [-8.0, 0.0, 2.0],
+            "direction": [1.0, 0.0, -0.15],
+            "screenDistance": 1.0,
+            "screenWidth": 1.0,
+            "screenHeight": 0.75,
+            "screenColumns": 64,
+            "screenRows": 48,
+        },
+        "defaultColour": [0.0, 0.0, 0.0],
+        "ambientLight": [0.0, 0.0, 0.0],
+        "lights": [],
+        "objects": [
+            sphere(-2.2, 1.0, material(1.0, 1.0, 0.0)),
+            sphere(0.0, 1.0, material(1.0, 0.0, 1.0)),
+            sphere(2.2, 1.0, material(1.0, 0.3, 0.7)),
+            sphere(0.0, 3.2, dielectric(0.1, 1.5, 0)),
+            {
+                "material": material(1.0, 0.5, 0.5),
+                "shape": {"type": "plane", "point": [0.0, 0.0, 0.0], "normal": [0.0, 0.0, 1.0]},
+            },
+        ],
+    });
+    let scene = Scene::from_value(value, Path::new("")).map_err(|error| error.to_string())?;
+    for (index, error) in scene.furnace_errors().into_iter().enumerate() {
+        match error {
+            None => return Err(format!("object {index} is not visible")),
+            Some(error) if error > FURNACE_TOLERANCE => {
+                return Err(format!("object {index} is off by {error}"))
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/*
+Water with an overlapping glass of higher priority: inside the overlap the
+glass wins, the water's surface there is ignored, and leaving the glass
+returns the ray to the water.
+ */
+fn nested_dielectrics() -> Result<(), String> {
+    let parse = |value| serde_json::from_value(value).map_err(|error| error.to_string());
+    let (water, glass) = (
+        parse(dielectric(0.0, 1.33, 1))?,
+        parse(dielectric(0.0, 1.5, 2))?,
+    );
+    let in_water = MediumStack::default().crossed(0, &water);
+    expect_close("index in water", in_water.ior(), 1.33)?;
+    let in_both = in_water.crossed(1, &glass);
+    expect_close("index in the overlap", in_both.ior(), 1.5)?;
+    if in_both.is_interface(0, water.priority) {
+        return Err("water surface inside the glass is treated as a boundary".to_string());
+    }
+    if !in_both.is_interface(1, glass.priority) {
+        return Err("glass surface is not treated as a boundary".to_string());
+    }
+    expect_close(
+        "index after the glass",
+        in_both.crossed(1, &glass).ior(),
+        1.33,
+    )?;
+    expect_close("index outside", in_water.crossed(0, &water).ior(), 1.0)
+}
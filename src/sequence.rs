@@ -0,0 +1,339 @@
+use crate::assets;
+use crate::gbuffer::crop_overscan;
+use crate::progress::Task;
+use crate::validate::LoadError;
+use crate::{save_image, Camera, FVec, Float, Renderer, Scene};
+use image::{DynamicImage, ImageBuffer, ImageError, Rgb};
+use serde::Deserialize;
+use serde_json::Value;
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+// Frames of an animation to render, first and last included
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameRange {
+    pub first: u32,
+    pub last: u32,
+}
+
+impl FromStr for FrameRange {
+    type Err = String;
+
+    // "first-last", or a single frame
+    fn from_str(s: &str) -> Result<FrameRange, String> {
+        let parse = |n: &str| n.trim().parse::<u32>().map_err(|e| format!("{n:?}: {e}"));
+        let (first, last) = match s.split_once('-') {
+            Some((first, last)) => (parse(first)?, parse(last)?),
+            None => (parse(s)?, parse(s)?),
+        };
+        if last < first {
+            return Err(format!("frame range {s:?} ends before it starts"));
+        }
+        Ok(FrameRange { first, last })
+    }
+}
+
+impl fmt::Display for FrameRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.first, self.last)
+    }
+}
+
+impl FrameRange {
+    // The range split into consecutive chunks of at most the given number of frames
+    pub fn chunks(&self, size: u32) -> Vec<FrameRange> {
+        let size = size.max(1);
+        (self.first..=self.last)
+            .step_by(size as usize)
+            .map(|first| FrameRange {
+                first,
+                last: first.saturating_add(size - 1).min(self.last),
+            })
+            .collect()
+    }
+}
+
+/*
+Reuse of the previous frame's pixels when rendering a sequence. Each frame
+after the first takes only a few samples per pixel and blends them with the
+previous frame's colour at the same surface point, found by reprojecting
+the point into the previous camera. Where that point was hidden or on a
+different object, the history is discarded and the pixel starts afresh.
+ */
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct TemporalReuse {
+    // Samples per pixel on every frame after the first
+    #[serde(default = "default_reuse_samples")]
+    pub samples: u32,
+    // Most samples the history can count for, which limits how long lighting changes linger
+    #[serde(default = "default_history_samples")]
+    pub history: u32,
+}
+
+fn default_reuse_samples() -> u32 {
+    1
+}
+
+fn default_history_samples() -> u32 {
+    16
+}
+
+#[derive(Debug)]
+pub enum SequenceError {
+    Load(u32, LoadError),
+    Render(u32, ImageError),
+}
+
+impl fmt::Display for SequenceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SequenceError::Load(frame, error) => write!(f, "frame {frame}: {error}"),
+            SequenceError::Render(frame, error) => write!(f, "frame {frame}: {error}"),
+        }
+    }
+}
+
+impl Error for SequenceError {}
+
+/*
+Output path of one frame. A run of # in the path is replaced by the frame
+number padded to its length, e.g. "shot_####.png"; otherwise the number is
+added to the end of the file name.
+ */
+pub fn frame_path(pattern: &str, frame: u32) -> String {
+    if let Some(last) = pattern.rfind('#') {
+        let end = last + 1;
+        let start = pattern[..end].trim_end_matches('#').len();
+        let width = end - start;
+        return format!("{}{:0width$}{}", &pattern[..start], frame, &pattern[end..]);
+    }
+    let path = Path::new(pattern);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}_{:04}.{}", stem, frame, extension.to_string_lossy()),
+        None => format!("{}_{:04}", stem, frame),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+// Colour of a surface point, accumulated over the frames it stayed visible
+#[derive(Clone, Copy)]
+struct HistoryPixel {
+    colour: FVec,
+    position: FVec,
+    object: usize,
+    samples: u32,
+}
+
+struct History {
+    camera: Camera,
+    width: u32,
+    pixels: Vec<Option<HistoryPixel>>,
+}
+
+impl History {
+    /*
+    The previous frame's pixel showing the given point of the object, if the
+    point was visible then. Points further from it than about a pixel's
+    footprint were hidden behind something else on the same object.
+     */
+    fn reproject(&self, position: &FVec, object: usize, footprint: Float) -> Option<HistoryPixel> {
+        let camera = &self.camera;
+        if camera.direction.dot(&(position - camera.position)) <= 0.0 {
+            return None;
+        }
+        let (x, y) = camera.project(position);
+        let (x, y) = (x.round(), y.round());
+        if x < 0.0 || y < 0.0 || x >= self.width as Float {
+            return None;
+        }
+        let index = y as usize * self.width as usize + x as usize;
+        let pixel = (*self.pixels.get(index)?)?;
+        let close = (pixel.position - position).norm() <= 2.0 * footprint;
+        (pixel.object == object && close).then_some(pixel)
+    }
+}
+
+// Smallest and largest value of each channel in the 3x3 pixels around a pixel
+fn neighbourhood_range(colours: &[FVec], width: u32, height: u32, x: u32, y: u32) -> (FVec, FVec) {
+    let mut low = FVec::repeat(Float::INFINITY);
+    let mut high = FVec::repeat(Float::NEG_INFINITY);
+    for ny in y.saturating_sub(1)..(y + 2).min(height) {
+        for nx in x.saturating_sub(1)..(x + 2).min(width) {
+            let colour = &colours[(ny * width + nx) as usize];
+            low = low.inf(colour);
+            high = high.sup(colour);
+        }
+    }
+    (low, high)
+}
+
+/*
+Render a frame, blending in the history where it can be reused, and return
+the image together with the history for the next frame.
+ */
+fn render_reusing(
+    scene: &Scene,
+    history: Option<&History>,
+    reuse: &TemporalReuse,
+) -> (ImageBuffer<Rgb<u8>, Vec<u8>>, History) {
+    let camera = &scene.camera;
+    let gbuffer = scene.trace_gbuffer(camera);
+    let colours = scene._shade_gbuffer_colours(&gbuffer, |_| true);
+    let width = gbuffer.width;
+    let mut pixels = vec![None; (width * gbuffer.height) as usize];
+    for pixel in &gbuffer.pixels {
+        // Pixels on an object's silhouette mix surfaces and are never reused
+        let Some(hit) = pixel.samples.first().and_then(|sample| sample.hit.as_ref()) else {
+            continue;
+        };
+        if pixel
+            .samples
+            .iter()
+            .any(|sample| sample.object() != Some(hit.object))
+        {
+            continue;
+        }
+        pixels[(pixel.y * width + pixel.x) as usize] = Some(HistoryPixel {
+            colour: FVec::zeros(),
+            position: hit.intersection.pos,
+            object: hit.object,
+            samples: pixel.samples.len() as u32,
+        });
+    }
+    let height = gbuffer.height;
+    let mut current = vec![FVec::zeros(); (width * height) as usize];
+    for (x, y, colour) in colours {
+        current[(y * width + x) as usize] = colour;
+    }
+    // Metered on this frame's own samples, before the history is blended in
+    let tone_mapping = scene._get_tone_mapping(current.iter().copied());
+    let mut image = ImageBuffer::new(width, height);
+    let mut reused = 0;
+    for (index, pixel) in pixels.iter_mut().enumerate() {
+        let (x, y) = (index as u32 % width, index as u32 / width);
+        let mut colour = current[index];
+        if let Some(pixel) = pixel {
+            let distance = (pixel.position - camera.position).norm();
+            let footprint = distance * camera.screen.step_x.norm() / camera.screen_distance;
+            let previous =
+                history.and_then(|h| h.reproject(&pixel.position, pixel.object, footprint));
+            if let Some(previous) = previous {
+                /*
+                Reflections and highlights move across a surface as the camera
+                does, so the history is limited to the range of colours around
+                the pixel in this frame to keep them from smearing.
+                 */
+                let (low, high) = neighbourhood_range(&current, width, height, x, y);
+                let history_colour = previous.colour.sup(&low).inf(&high);
+                let total = (previous.samples + pixel.samples) as Float;
+                colour = (history_colour * previous.samples as Float
+                    + colour * pixel.samples as Float)
+                    / total;
+                pixel.samples = (previous.samples + pixel.samples).min(reuse.history);
+                reused += 1;
+            }
+            pixel.colour = colour;
+        }
+        image.put_pixel(
+            x,
+            y,
+            Rgb(scene._encode_colour_with(tone_mapping.as_ref(), &colour)),
+        );
+    }
+    debug!(
+        "Reused the history of {} of {} pixels",
+        reused,
+        width * height
+    );
+    let history = History {
+        camera: camera.clone(),
+        width,
+        pixels,
+    };
+    (image, history)
+}
+
+// Give each extra output of the scene its own file per frame
+fn number_outputs(scene: &mut Scene, frame: u32) {
+    let aov_paths = scene
+        .aovs
+        .iter_mut()
+        .filter_map(|output| output.path.as_mut());
+    let layer_paths = scene.layers.iter_mut().map(|layer| &mut layer.path);
+    let paths = aov_paths
+        .chain(scene.deep_output.as_mut())
+        .chain(scene.multilayer_output.as_mut())
+        .chain(scene.calibration_output.as_mut())
+        .chain(
+            scene
+                .annotation_output
+                .as_mut()
+                .map(|output| &mut output.path),
+        )
+        .chain(
+            scene
+                .segmentation_output
+                .iter_mut()
+                .flat_map(|output| std::iter::once(&mut output.path).chain(output.legend.as_mut())),
+        )
+        .chain(layer_paths);
+    for path in paths {
+        *path = frame_path(path, frame);
+    }
+}
+
+/*
+Render every frame of the range from the scene description, writing each to
+its own path. Animated values are evaluated per frame, and with temporal
+reuse enabled in the scene, frames after the first take fewer samples and
+lean on their predecessors. Extra outputs such as AOVs and render layers
+are numbered like the image, and only written without temporal reuse.
+Each frame's bounding volume hierarchies are refitted from the last frame's
+while that keeps them quick to trace, and meshes and images are only read
+again when their files change.
+ */
+pub fn render(
+    value: &Value,
+    base_dir: &Path,
+    frames: FrameRange,
+    output: &str,
+) -> Result<(), SequenceError> {
+    let task = Task::start("sequence", (frames.last - frames.first + 1) as usize);
+    let _assets = assets::Keep::start();
+    let mut history = None;
+    let mut primitives = None;
+    for frame in frames.first..=frames.last {
+        let mut value = value.clone();
+        value["frame"] = frame.into();
+        let mut scene = Scene::from_value_after(value, base_dir, primitives.as_ref())
+            .map_err(|e| SequenceError::Load(frame, e))?;
+        let path = frame_path(output, frame);
+        info!("Rendering frame {} to {}", frame, path);
+        let Some(reuse) = scene.temporal_reuse else {
+            number_outputs(&mut scene, frame);
+            Renderer::new(&scene)
+                .render_to_file(&path)
+                .map_err(|e| SequenceError::Render(frame, e))?;
+            primitives = Some(std::mem::take(&mut scene.primitives));
+            task.advance();
+            continue;
+        };
+        // Fresh noise every frame, so blending frames averages it away
+        scene.seed = scene.seed.wrapping_add(frame as u64);
+        if history.is_some() {
+            scene.camera.samples = reuse.samples;
+        }
+        let (mut image, next) = render_reusing(&scene, history.as_ref(), &reuse);
+        history = Some(next);
+        scene._draw_bounds(&scene.camera, &mut image, Rgb);
+        let image = DynamicImage::from(crop_overscan(image, scene.camera.overscan));
+        save_image(image, &path).map_err(|e| SequenceError::Render(frame, e))?;
+        primitives = Some(std::mem::take(&mut scene.primitives));
+        task.advance();
+    }
+    Ok(())
+}
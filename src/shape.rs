@@ -0,0 +1,695 @@
+use crate::assets;
+use crate::bounds::Aabb;
+use crate::config::find_asset;
+use crate::core::clamp;
+use crate::core::consts::PI;
+use crate::core::ray::{Intersection, Ray, RayDifferential, SurfaceDifferentials};
+use crate::csg::CsgOperation;
+use crate::instances::{transformed_box, Instances};
+use crate::light::coordinate_system;
+use crate::mesh::{Normals, TriangleMesh};
+use crate::plugin::{self, PluginShape};
+use crate::scene::{describe, Units};
+use crate::texture::UvDifferentials;
+use crate::transform::{
+    keeps_axes, transform_normal, transform_point, uniform_scale, Transform, TransformAnimation,
+};
+use crate::uv::UvMapping;
+use crate::{FVec, Float, Material};
+use nalgebra::Matrix4;
+use serde::Deserialize;
+use serde_json::Value;
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum Shape {
+    Sphere {
+        centre: FVec,
+        radius: Float,
+    },
+    Plane {
+        point: FVec,
+        normal: FVec,
+    },
+    // Axis-aligned, between two opposite corners
+    Box {
+        min: FVec,
+        max: FVec,
+    },
+    // Closed at both ends, around the segment from the centre of the base to that of the top
+    Cylinder {
+        base: FVec,
+        top: FVec,
+        radius: Float,
+    },
+    // Closed at the base, narrowing to a point at the apex
+    Cone {
+        base: FVec,
+        apex: FVec,
+        radius: Float,
+    },
+    Disc {
+        centre: FVec,
+        normal: FVec,
+        radius: Float,
+    },
+    // A tube of the minor radius around a circle of the major radius, about the axis
+    Torus {
+        centre: FVec,
+        axis: FVec,
+        #[serde(rename = "majorRadius")]
+        major_radius: Float,
+        #[serde(rename = "minorRadius")]
+        minor_radius: Float,
+    },
+    /*
+    Two shapes combined as solids, such as a sphere with a cylinder taken
+    out of it. Each can be a closed shape (a sphere, box, cylinder, cone or
+    torus), a plane, standing for the half-space behind its normal, or
+    another combination.
+     */
+    Csg {
+        operation: CsgOperation,
+        a: Box<Shape>,
+        b: Box<Shape>,
+    },
+    // Triangles read from a Wavefront OBJ file when the scene is loaded
+    Mesh {
+        path: String,
+        #[serde(default)]
+        transform: Transform,
+        #[serde(default)]
+        normals: Normals,
+        // Simplify the mesh as it is read until it has at most this many triangles
+        #[serde(rename = "targetTriangles")]
+        target_triangles: Option<usize>,
+        #[serde(skip)]
+        mesh: Arc<TriangleMesh>,
+    },
+    /*
+    A shape registered by the program under the name. Written in a scene with
+    that name as its type, which is rewritten to this when the scene is read.
+     */
+    Plugin {
+        name: String,
+        #[serde(default)]
+        params: Value,
+        #[serde(skip)]
+        shape: PluginShape,
+    },
+}
+
+impl Shape {
+    pub(crate) fn scale(&mut self, factor: Float) {
+        match self {
+            Shape::Sphere { centre, radius } => {
+                *centre *= factor;
+                *radius *= factor;
+            }
+            Shape::Plane { point, .. } => *point *= factor,
+            Shape::Box { min, max } => {
+                *min *= factor;
+                *max *= factor;
+            }
+            Shape::Cylinder {
+                base,
+                top: end,
+                radius,
+            }
+            | Shape::Cone {
+                base,
+                apex: end,
+                radius,
+            } => {
+                *base *= factor;
+                *end *= factor;
+                *radius *= factor;
+            }
+            Shape::Disc { centre, radius, .. } => {
+                *centre *= factor;
+                *radius *= factor;
+            }
+            Shape::Torus {
+                centre,
+                major_radius,
+                minor_radius,
+                ..
+            } => {
+                *centre *= factor;
+                *major_radius *= factor;
+                *minor_radius *= factor;
+            }
+            Shape::Csg { a, b, .. } => {
+                a.scale(factor);
+                b.scale(factor);
+            }
+            Shape::Mesh { mesh, .. } => Arc::make_mut(mesh).scale(factor),
+            Shape::Plugin { shape, .. } => shape.0 = Some(shape.get().scaled(factor).into()),
+        }
+    }
+
+    // Make a plugin shape from its parameters with the factory registered under its name
+    pub(crate) fn load_plugin(&mut self) -> Result<(), String> {
+        if let Shape::Plugin {
+            name,
+            params,
+            shape,
+        } = self
+        {
+            shape.0 = Some(plugin::make_shape(name, params)?);
+        }
+        Ok(())
+    }
+
+    // Read the triangles of a mesh, relative to base_dir or a search path
+    pub(crate) fn load_mesh(&mut self, base_dir: &Path) -> Result<(), Box<dyn Error>> {
+        if let Shape::Mesh {
+            path,
+            transform,
+            normals,
+            target_triangles,
+            mesh,
+        } = self
+        {
+            let path = find_asset(base_dir, &*path);
+            let source = (path.clone(), *transform, *normals, *target_triangles);
+            *mesh = assets::mesh(source, || {
+                TriangleMesh::load(&path, transform, *normals, *target_triangles)
+            })?;
+        }
+        Ok(())
+    }
+
+    // Whether two meshes come from the same file placed the same way, so can share triangles
+    pub(crate) fn same_mesh_source(&self, other: &Shape) -> bool {
+        match (self, other) {
+            (
+                Shape::Mesh {
+                    path,
+                    transform,
+                    normals,
+                    target_triangles,
+                    ..
+                },
+                Shape::Mesh {
+                    path: other_path,
+                    transform: other_transform,
+                    normals: other_normals,
+                    target_triangles: other_target,
+                    ..
+                },
+            ) => {
+                path == other_path
+                    && transform == other_transform
+                    && normals == other_normals
+                    && target_triangles == other_target
+            }
+            _ => false,
+        }
+    }
+
+    /*
+    The shape moved by a transform. Shapes with a radius can only be scaled
+    equally in every direction, and boxes can only be turned in steps that
+    keep them lined up with the axes.
+     */
+    pub(crate) fn transformed(&self, matrix: &Matrix4<Float>) -> Result<Shape, String> {
+        let scale = || {
+            uniform_scale(matrix)
+                .ok_or("shapes with a radius can only be scaled equally in every direction")
+        };
+        let point = |p: &FVec| transform_point(matrix, p);
+        Ok(match self {
+            Shape::Sphere { centre, radius } => Shape::Sphere {
+                centre: point(centre),
+                radius: radius * scale()?,
+            },
+            Shape::Plane { point: p, normal } => Shape::Plane {
+                point: point(p),
+                normal: transform_normal(matrix, normal),
+            },
+            Shape::Box { min, max } => {
+                if !keeps_axes(matrix) {
+                    return Err("boxes can only be turned in right angles about the axes".into());
+                }
+                let (a, b) = (point(min), point(max));
+                Shape::Box {
+                    min: a.inf(&b),
+                    max: a.sup(&b),
+                }
+            }
+            Shape::Cylinder { base, top, radius } => Shape::Cylinder {
+                base: point(base),
+                top: point(top),
+                radius: radius * scale()?,
+            },
+            Shape::Cone { base, apex, radius } => Shape::Cone {
+                base: point(base),
+                apex: point(apex),
+                radius: radius * scale()?,
+            },
+            Shape::Disc {
+                centre,
+                normal,
+                radius,
+            } => Shape::Disc {
+                centre: point(centre),
+                normal: transform_normal(matrix, normal),
+                radius: radius * scale()?,
+            },
+            Shape::Torus {
+                centre,
+                axis,
+                major_radius,
+                minor_radius,
+            } => Shape::Torus {
+                centre: point(centre),
+                axis: transform_normal(matrix, axis),
+                major_radius: major_radius * scale()?,
+                minor_radius: minor_radius * scale()?,
+            },
+            Shape::Csg { operation, a, b } => Shape::Csg {
+                operation: *operation,
+                a: Box::new(a.transformed(matrix)?),
+                b: Box::new(b.transformed(matrix)?),
+            },
+            Shape::Mesh { mesh, .. } => {
+                let mut shape = self.clone();
+                if let Shape::Mesh { mesh: moved, .. } = &mut shape {
+                    *moved = Arc::new(mesh.transformed(matrix));
+                }
+                shape
+            }
+            Shape::Plugin {
+                name,
+                params,
+                shape,
+            } => Shape::Plugin {
+                name: name.clone(),
+                params: params.clone(),
+                shape: PluginShape(Some(shape.get().transformed(matrix)?.into())),
+            },
+        })
+    }
+
+    // Box enclosing the shape, or None if it is unbounded
+    pub(crate) fn bounding_box(&self) -> Option<Aabb> {
+        match self {
+            Shape::Sphere { centre, radius } => {
+                let extent = FVec::new(*radius, *radius, *radius);
+                Some(Aabb {
+                    min: centre - extent,
+                    max: centre + extent,
+                })
+            }
+            Shape::Plane { .. } => None,
+            Shape::Box { min, max } => Some(Aabb {
+                min: *min,
+                max: *max,
+            }),
+            Shape::Cylinder { base, top, radius } => {
+                let extent = disc_extent(&(top - base), *radius);
+                Aabb::around([base - extent, base + extent, top - extent, top + extent])
+            }
+            Shape::Cone { base, apex, radius } => {
+                let extent = disc_extent(&(apex - base), *radius);
+                Aabb::around([base - extent, base + extent, *apex])
+            }
+            Shape::Disc {
+                centre,
+                normal,
+                radius,
+            } => {
+                let extent = disc_extent(normal, *radius);
+                Aabb::around([centre - extent, centre + extent])
+            }
+            Shape::Torus {
+                centre,
+                axis,
+                major_radius,
+                minor_radius,
+            } => {
+                let extent = disc_extent(axis, *major_radius).add_scalar(*minor_radius);
+                Aabb::around([centre - extent, centre + extent])
+            }
+            Shape::Csg { operation, a, b } => {
+                operation.bounding_box(a.bounding_box(), b.bounding_box())
+            }
+            Shape::Mesh { mesh, .. } => mesh.bounds,
+            Shape::Plugin { shape, .. } => shape.get().bounding_box(),
+        }
+    }
+
+    // Change in the unit normal for a small offset dp along the surface
+    pub(crate) fn normal_differential(&self, normal: &FVec, dp: &FVec) -> FVec {
+        match self {
+            Shape::Sphere { radius, .. } => (dp - normal * normal.dot(dp)) / *radius,
+            Shape::Cylinder { base, top, radius } => {
+                let axis = (top - base).normalize();
+                if normal.dot(&axis).abs() > 0.5 {
+                    // On a cap
+                    return FVec::zeros();
+                }
+                let across = dp - axis * axis.dot(dp);
+                (across - normal * normal.dot(&across)) / *radius
+            }
+            // Treated as flat, even where vertex normals or the shape's sides curve the shading
+            Shape::Plane { .. }
+            | Shape::Box { .. }
+            | Shape::Cone { .. }
+            | Shape::Disc { .. }
+            | Shape::Torus { .. }
+            | Shape::Csg { .. }
+            | Shape::Mesh { .. }
+            | Shape::Plugin { .. } => FVec::zeros(),
+        }
+    }
+
+    /*
+    Surface coordinates of a point on the shape. Spheres use longitude and
+    colatitude around the world up axis, and tori the angles around their
+    axis and around the tube, all in [0, 1]. Cylinders and cones use the
+    angle around their axis and the fraction of the way from the top or apex
+    to the base. Planes and discs use distances in metres along a tangent
+    basis from the plane's reference point or the disc's centre, boxes the
+    distances from the lowest corner along the two axes lying in the face,
+    and meshes the x and y distances in metres from the mesh's origin.
+    Combinations of shapes use the coordinates of the first, and plugin
+    shapes give their own. In every case v runs the same way as rows of an
+    image.
+     */
+    pub(crate) fn uv(&self, pos: &FVec) -> (Float, Float) {
+        let angle = |local: &FVec| 0.5 + local.y.atan2(local.x) / (2.0 * PI);
+        match self {
+            Shape::Sphere { centre, radius } => {
+                let d = (pos - centre) / *radius;
+                let u = 0.5 + d.y.atan2(d.x) / (2.0 * PI);
+                let v = clamp(d.z, -1.0, 1.0).acos() / PI;
+                (u, v)
+            }
+            Shape::Plane { point, normal } => {
+                let local = along_axis(point, normal, pos);
+                (local.x, local.y)
+            }
+            Shape::Box { min, max } => {
+                let offset = pos - min;
+                let distance = (pos - min).inf(&(max - pos));
+                let face = distance.imin();
+                (offset[(face + 1) % 3], offset[(face + 2) % 3])
+            }
+            Shape::Cylinder { base, top, .. } => {
+                let local = along_axis(base, &(top - base), pos);
+                (angle(&local), 1.0 - local.z / (top - base).norm())
+            }
+            Shape::Cone { base, apex, .. } => {
+                let local = along_axis(apex, &(base - apex), pos);
+                (angle(&local), local.z / (base - apex).norm())
+            }
+            Shape::Disc { centre, normal, .. } => {
+                let local = along_axis(centre, normal, pos);
+                (local.x, local.y)
+            }
+            Shape::Torus {
+                centre,
+                axis,
+                major_radius,
+                ..
+            } => {
+                let local = along_axis(centre, axis, pos);
+                let from_ring = local.xy().norm() - major_radius;
+                (angle(&local), 0.5 + local.z.atan2(from_ring) / (2.0 * PI))
+            }
+            Shape::Csg { a, .. } => a.uv(pos),
+            Shape::Mesh { mesh, .. } => {
+                let offset = pos - mesh.origin;
+                (offset.x, offset.y)
+            }
+            Shape::Plugin { shape, .. } => shape.get().uv(pos),
+        }
+    }
+
+    /*
+    Change in position per unit of u and of v of the texture coordinates at a
+    hit: from a mesh's own coordinates where it has them, and otherwise found
+    by differencing uv over a small step along the surface each way. None
+    where the coordinates do not change across the surface, as at the poles
+    of spheres.
+     */
+    pub(crate) fn uv_tangents(&self, intersection: &Intersection) -> Option<(FVec, FVec)> {
+        if intersection.uv.is_some() {
+            return intersection.uv_tangents;
+        }
+        uv_tangents(intersection, |pos| self.uv(pos))
+    }
+
+    // Coordinates for mapping textures: a mesh's own where it has them, otherwise as for uv
+    pub(crate) fn texture_uv(&self, intersection: &Intersection) -> (Float, Float) {
+        intersection
+            .uv
+            .unwrap_or_else(|| self.uv(&intersection.pos))
+    }
+
+    /*
+    A point on the shape relative to the shape itself, in metres: from the
+    centre along the world axes for spheres and boxes, along the tangent
+    basis and normal from the reference point for planes, along the tangent
+    basis and axis from the centre of the base for cylinders and cones and
+    from the centre for discs and tori, as for the first shape of a
+    combination, and from the origin of the file's coordinates along the
+    world axes for meshes, and as plugin shapes define it for them.
+     */
+    pub(crate) fn object_position(&self, pos: &FVec) -> FVec {
+        match self {
+            Shape::Sphere { centre, .. } => pos - centre,
+            Shape::Plane { point, normal } => along_axis(point, normal, pos),
+            Shape::Box { min, max } => pos - (min + max) * 0.5,
+            Shape::Cylinder { base, top, .. } => along_axis(base, &(top - base), pos),
+            Shape::Cone { base, apex, .. } => along_axis(base, &(apex - base), pos),
+            Shape::Disc { centre, normal, .. } => along_axis(centre, normal, pos),
+            Shape::Torus { centre, axis, .. } => along_axis(centre, axis, pos),
+            Shape::Csg { a, .. } => a.object_position(pos),
+            Shape::Mesh { mesh, .. } => pos - mesh.origin,
+            Shape::Plugin { shape, .. } => shape.get().object_position(pos),
+        }
+    }
+}
+
+// Change in position per unit of u and of v, from the coordinates a small step each way
+fn uv_tangents(
+    intersection: &Intersection,
+    uv: impl Fn(&FVec) -> (Float, Float),
+) -> Option<(FVec, FVec)> {
+    let pos = intersection.pos;
+    let (s, t) = coordinate_system(&intersection.normal);
+    let step = UV_STEP * pos.amax().max(1.0);
+    let slope = |direction: &FVec| {
+        let (u0, v0) = uv(&(pos - direction * step));
+        let (u1, v1) = uv(&(pos + direction * step));
+        // Coordinates that are angles wrap around from 1 to 0
+        let wrap = |d: Float| d - d.round();
+        (wrap(u1 - u0) / (2.0 * step), wrap(v1 - v0) / (2.0 * step))
+    };
+    let (du_ds, dv_ds) = slope(&s);
+    let (du_dt, dv_dt) = slope(&t);
+    let determinant = du_ds * dv_dt - du_dt * dv_ds;
+    if determinant == 0.0 || !determinant.is_finite() {
+        return None;
+    }
+    Some((
+        (s * dv_dt - t * dv_ds) / determinant,
+        (t * du_ds - s * du_dt) / determinant,
+    ))
+}
+
+// Step along the surface for differencing texture coordinates, relative to distance from origin
+const UV_STEP: Float = 1e-6;
+
+// Offset of the point from the origin along a tangent basis around the axis, then along the axis
+fn along_axis(origin: &FVec, axis: &FVec, pos: &FVec) -> FVec {
+    let axis = axis.normalize();
+    let (s, t) = coordinate_system(&axis);
+    let offset = pos - origin;
+    FVec::new(s.dot(&offset), t.dot(&offset), axis.dot(&offset))
+}
+
+// Half the size along each world axis of a circle of the radius facing along the normal
+fn disc_extent(normal: &FVec, radius: Float) -> FVec {
+    let normal = normal.normalize();
+    normal.map(|n| radius * (1.0 - n * n).max(0.0).sqrt())
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SceneObject {
+    // Used to find the object through the scene's query functions
+    pub name: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub material: Material,
+    pub shape: Shape,
+    // Overrides the scene units for this object only
+    pub(crate) units: Option<Units>,
+    // Moves the shape from its own coordinates into those of the parent, or the scene
+    pub(crate) transform: Option<Transform>,
+    // Moves the object through an animation by keying the components of its transform
+    pub(crate) animation: Option<TransformAnimation>,
+    // Speed and direction the object moves in while the shutter is open, in units per second
+    pub(crate) velocity: Option<FVec>,
+    // Index of an object whose transform is also applied, after this object's own
+    pub(crate) parent: Option<usize>,
+    // Copies of the object placed by transforms after all of its own; see Instances
+    pub(crate) instances: Option<Instances>,
+    /*
+    Where its surface coincides with another object's, the one of higher
+    priority is hit: a label on a box, or liquid filling a glass exactly,
+    which also needs the higher priority of its material to be the medium
+    inside.
+     */
+    #[serde(default)]
+    pub(crate) priority: i32,
+    // Class written to the segmentation map where the object is seen, from 1 to 255
+    pub(crate) class_id: Option<u8>,
+    // Texture coordinates generated in place of the shape's own, a mesh's included
+    pub(crate) uv_mapping: Option<UvMapping>,
+    // Set by render layers: blocks the view like any object but is cut out of the image
+    #[serde(skip)]
+    pub(crate) holdout: bool,
+    // Set at load time for geometry that cannot be rendered; left out of the render
+    #[serde(skip)]
+    pub(crate) degenerate: bool,
+    // Set when other objects are isolated; left out of the render like degenerate geometry
+    #[serde(skip)]
+    pub(crate) hidden: bool,
+    // Seed of the object's own random values, from the scene seed and its index
+    #[serde(skip)]
+    pub(crate) instance_seed: u64,
+    // The shape in metres before its transforms were applied, if it had any
+    #[serde(skip)]
+    pub(crate) local_shape: Option<Shape>,
+    // Metres in the units the object's transforms are written in
+    #[serde(skip)]
+    pub(crate) metres: Float,
+    // Distance in metres moved over the whole shutter interval, set when the scene is loaded
+    #[serde(skip)]
+    pub(crate) shutter_motion: FVec,
+    // Moves from where the object is placed to each of its copies, set when the scene is loaded
+    #[serde(skip)]
+    pub(crate) copies: Arc<Vec<Matrix4<Float>>>,
+}
+
+impl SceneObject {
+    // How the object at the index is referred to in messages
+    pub(crate) fn describe(&self, index: usize) -> String {
+        describe("object", index, self.name.as_deref())
+    }
+
+    // Coordinates for mapping textures: by the object's mapping if it has one, else the shape's
+    pub(crate) fn texture_uv(&self, intersection: &Intersection) -> (Float, Float) {
+        match &self.uv_mapping {
+            Some(mapping) => self.mapped_uv(mapping, &intersection.pos, &intersection.normal),
+            None => self.shape.texture_uv(intersection),
+        }
+    }
+
+    // Change in position per unit of u and of v of the texture coordinates at a hit
+    pub(crate) fn uv_tangents(&self, intersection: &Intersection) -> Option<(FVec, FVec)> {
+        match &self.uv_mapping {
+            Some(mapping) => uv_tangents(intersection, |pos| {
+                self.mapped_uv(mapping, pos, &intersection.normal)
+            }),
+            None => self.shape.uv_tangents(intersection),
+        }
+    }
+
+    /*
+    Change in texture coordinates per pixel step across the screen: the
+    steps in u and v whose tangents come closest to the ray's footprint on
+    the surface, in the least-squares sense.
+     */
+    pub(crate) fn uv_differentials(&self, intersection: &Intersection) -> Option<UvDifferentials> {
+        let surface = intersection.differentials.as_ref()?;
+        let (dpdu, dpdv) = self.uv_tangents(intersection)?;
+        let (a, b, c) = (dpdu.norm_squared(), dpdu.dot(&dpdv), dpdv.norm_squared());
+        let determinant = a * c - b * b;
+        if determinant == 0.0 || !determinant.is_finite() {
+            return None;
+        }
+        let solve = |dp: &FVec| {
+            let (pu, pv) = (dpdu.dot(dp), dpdv.dot(dp));
+            (
+                (c * pu - b * pv) / determinant,
+                (a * pv - b * pu) / determinant,
+            )
+        };
+        let (dudx, dvdx) = solve(&surface.dpdx);
+        let (dudy, dvdy) = solve(&surface.dpdy);
+        Some(UvDifferentials {
+            dudx,
+            dvdx,
+            dudy,
+            dvdy,
+        })
+    }
+
+    fn mapped_uv(&self, mapping: &UvMapping, pos: &FVec, normal: &FVec) -> (Float, Float) {
+        let local = self.shape.object_position(pos);
+        let local_normal = self.shape.object_position(&(pos + normal)) - local;
+        mapping.uv(&local, &local_normal)
+    }
+
+    // Box enclosing the shape, or all its copies, everywhere it moves to while the shutter is open
+    pub(crate) fn bounding_box(&self) -> Option<Aabb> {
+        let mut bounds = self.shape.bounding_box()?;
+        if !self.copies.is_empty() {
+            let copies = self
+                .copies
+                .iter()
+                .filter_map(|copy| transformed_box(&bounds, copy));
+            bounds = Aabb::around(copies.flat_map(|copy| [copy.min, copy.max]))?;
+        }
+        Some(bounds.swept(&self.shutter_motion))
+    }
+
+    pub(crate) fn with_differentials(
+        &self,
+        mut intersection: Intersection,
+        ray: &Ray,
+    ) -> Intersection {
+        intersection.differentials = ray
+            .differential
+            .as_ref()
+            .and_then(|d| self._get_surface_differentials(&intersection, d));
+        intersection
+    }
+
+    /*
+    Intersect the offset rays with the tangent plane at the hit point (as in
+    PBRT) to find how the hit position and normal vary across the pixel.
+     */
+    pub(crate) fn _get_surface_differentials(
+        &self,
+        intersection: &Intersection,
+        differential: &RayDifferential,
+    ) -> Option<SurfaceDifferentials> {
+        let n = intersection.normal;
+        let plane_offset = |origin: &FVec, direction: &FVec| {
+            let n_dot_d = n.dot(direction);
+            if n_dot_d == 0.0 {
+                return None;
+            }
+            let t = n.dot(&(intersection.pos - origin)) / n_dot_d;
+            Some(origin + t * direction - intersection.pos)
+        };
+        let dpdx = plane_offset(&differential.rx_origin, &differential.rx_direction)?;
+        let dpdy = plane_offset(&differential.ry_origin, &differential.ry_direction)?;
+        Some(SurfaceDifferentials {
+            dpdx,
+            dpdy,
+            dndx: self.shape.normal_differential(&n, &dpdx),
+            dndy: self.shape.normal_differential(&n, &dpdy),
+        })
+    }
+}
@@ -0,0 +1,29 @@
+use crate::blackbody::{colour_matching, xyz_to_srgb, FIRST_WAVELENGTH, LAST_WAVELENGTH};
+use crate::{FVec, Float};
+use std::sync::OnceLock;
+
+// Wavelengths averaged over to find the colour of light of every wavelength in equal measure
+const WHITE_STEPS: u32 = 400;
+
+// Wavelength in nanometres drawn evenly over the visible spectrum
+pub(crate) fn sample_wavelength(u: Float) -> Float {
+    FIRST_WAVELENGTH + u * (LAST_WAVELENGTH - FIRST_WAVELENGTH)
+}
+
+/*
+Linear sRGB of light of a single wavelength in nanometres, by the CIE colour
+matching functions, scaled so that its average over the wavelengths drawn is
+white. Colours of the spectrum outside the sRGB gamut have negative
+channels, which the other wavelengths of a pixel make up for.
+ */
+pub(crate) fn wavelength_colour(wavelength: Float) -> FVec {
+    static WHITE: OnceLock<FVec> = OnceLock::new();
+    let white = WHITE.get_or_init(|| {
+        let total: FVec = (0..WHITE_STEPS)
+            .map(|i| sample_wavelength((i as Float + 0.5) / WHITE_STEPS as Float))
+            .map(|wavelength| xyz_to_srgb(&colour_matching(wavelength)))
+            .sum();
+        total / WHITE_STEPS as Float
+    });
+    xyz_to_srgb(&colour_matching(wavelength)).component_div(white)
+}
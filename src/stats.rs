@@ -0,0 +1,138 @@
+use crate::memory::megabytes;
+use serde_json::{json, Value};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// What is counted while statistics are kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Counter {
+    // Rays from the camera
+    PrimaryRays,
+    // Rays towards lights and emissive objects
+    ShadowRays,
+    // Rays reflected, refracted or bounced off a surface
+    ReflectionRays,
+    // Rays tested against a single primitive
+    IntersectionTests,
+    // Nodes of the BVH visited
+    BvhNodes,
+}
+
+const COUNTERS: usize = 5;
+
+type Slots = [AtomicU64; COUNTERS];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+// The slots of every thread that has counted anything, summed when read
+static THREADS: Mutex<Vec<Arc<Slots>>> = Mutex::new(Vec::new());
+
+thread_local! {
+    static LOCAL: Arc<Slots> = {
+        let slots = Arc::new(Slots::default());
+        THREADS.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(slots.clone());
+        slots
+    };
+}
+
+/*
+Start counting the work renders do, for measuring how acceleration structures
+and sampling change it. Off until then, as counting costs a little on every
+ray; each thread counts in its own slots so threads do not contend.
+ */
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub(crate) fn add(counter: Counter, count: u64) {
+    if ENABLED.load(Ordering::Relaxed) {
+        LOCAL.with(|slots| slots[counter as usize].fetch_add(count, Ordering::Relaxed));
+    }
+}
+
+fn total(counter: Counter) -> u64 {
+    let threads = THREADS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    threads
+        .iter()
+        .map(|slots| slots[counter as usize].load(Ordering::Relaxed))
+        .sum()
+}
+
+// Most memory the process has held at once, where the system reports it
+fn peak_memory() -> Option<usize> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kilobytes: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+// What a render did, counted since statistics were enabled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub time: Duration,
+    pub primary_rays: u64,
+    pub shadow_rays: u64,
+    pub reflection_rays: u64,
+    pub intersection_tests: u64,
+    pub bvh_nodes: u64,
+    // Peak resident memory in bytes, if known
+    pub peak_memory: Option<usize>,
+}
+
+impl Stats {
+    // The counts so far, for a render that took the given time
+    pub fn collect(time: Duration) -> Stats {
+        Stats {
+            time,
+            primary_rays: total(Counter::PrimaryRays),
+            shadow_rays: total(Counter::ShadowRays),
+            reflection_rays: total(Counter::ReflectionRays),
+            intersection_tests: total(Counter::IntersectionTests),
+            bvh_nodes: total(Counter::BvhNodes),
+            peak_memory: peak_memory(),
+        }
+    }
+
+    pub fn rays(&self) -> u64 {
+        self.primary_rays + self.shadow_rays + self.reflection_rays
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "seconds": self.time.as_secs_f64(),
+            "rays": {
+                "primary": self.primary_rays,
+                "shadow": self.shadow_rays,
+                "reflection": self.reflection_rays,
+            },
+            "intersectionTests": self.intersection_tests,
+            "bvhNodes": self.bvh_nodes,
+            "peakMemoryBytes": self.peak_memory,
+        })
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = self.time.as_secs_f64();
+        writeln!(f, "Render time: {:.3}s", seconds)?;
+        writeln!(
+            f,
+            "Rays: {} ({} primary, {} shadow, {} reflection), {:.0} per second",
+            self.rays(),
+            self.primary_rays,
+            self.shadow_rays,
+            self.reflection_rays,
+            self.rays() as f64 / seconds.max(1e-9)
+        )?;
+        writeln!(f, "Intersection tests: {}", self.intersection_tests)?;
+        writeln!(f, "BVH nodes visited: {}", self.bvh_nodes)?;
+        match self.peak_memory {
+            Some(bytes) => write!(f, "Peak memory: {}", megabytes(bytes)),
+            None => write!(f, "Peak memory: unknown"),
+        }
+    }
+}
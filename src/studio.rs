@@ -0,0 +1,153 @@
+use crate::bounds::Aabb;
+use crate::light::{Attenuation, LightShape, LightSource};
+use crate::sampling::instance_seed;
+use crate::{FVec, Float, Scene, SceneObject, UP};
+use serde::Deserialize;
+use serde_json::json;
+
+/*
+A photo studio set up around whatever the scene holds, so a bare model
+renders well with no lighting of its own: a floor under the lowest point of
+the objects, and a key, fill and rim light placed about the camera's view
+of them, far enough away and bright enough for their size. Either true for
+all of it or an object choosing its parts. It is added to the scene's own
+lights and objects, after them, so their indices are unchanged.
+ */
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Studio {
+    Enabled(bool),
+    Setup(StudioSetup),
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct StudioSetup {
+    #[serde(default = "default_true")]
+    pub(crate) ground: bool,
+    #[serde(default = "default_true")]
+    pub(crate) lights: bool,
+    // Brightness of the key light on the objects, the fill and rim being dimmer
+    #[serde(default = "default_intensity")]
+    pub(crate) intensity: Float,
+    #[serde(default = "default_ground_colour")]
+    pub(crate) ground_colour: FVec,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_intensity() -> Float {
+    1.0
+}
+
+fn default_ground_colour() -> FVec {
+    FVec::repeat(0.8)
+}
+
+impl Studio {
+    pub(crate) fn setup(&self) -> Option<StudioSetup> {
+        match self {
+            Studio::Enabled(true) => Some(StudioSetup {
+                ground: true,
+                lights: true,
+                intensity: default_intensity(),
+                ground_colour: default_ground_colour(),
+            }),
+            Studio::Enabled(false) => None,
+            Studio::Setup(setup) => Some(setup.clone()),
+        }
+    }
+}
+
+// Each light's direction from the objects, as amounts towards the camera, to its right and up
+const KEY: (Float, Float, Float) = (1.0, 1.0, 1.0);
+const FILL: (Float, Float, Float) = (1.0, -1.0, 0.4);
+const RIM: (Float, Float, Float) = (-1.0, -0.5, 1.2);
+
+impl Scene {
+    // Add the studio's floor and lights, once the objects are placed and in metres
+    pub(crate) fn add_studio(&mut self) {
+        let Some(setup) = self.studio.as_ref().and_then(Studio::setup) else {
+            return;
+        };
+        let boxes = self
+            .objects
+            .iter()
+            .filter(|object| !object.degenerate && !object.hidden)
+            .filter_map(SceneObject::bounding_box);
+        let bounds = Aabb::around(boxes.flat_map(|bounds| [bounds.min, bounds.max]));
+        let bounds = bounds.unwrap_or_else(|| {
+            warn!("the studio has no bounded objects to fit around; fitting a metre around 0");
+            Aabb {
+                min: FVec::repeat(-0.5),
+                max: FVec::repeat(0.5),
+            }
+        });
+        let centre = 0.5 * (bounds.min + bounds.max);
+        let radius = (0.5 * (bounds.max - bounds.min).norm()).max(1e-3);
+        if setup.lights {
+            self.add_studio_lights(&setup, &centre, radius);
+        }
+        if setup.ground {
+            let index = self.objects.len();
+            let ground = json!({
+                "name": "studio ground",
+                "material": {
+                    "colour": setup.ground_colour.as_slice(),
+                    "kDiffuse": 1,
+                    "kAmbient": 1,
+                    "kSpecular": 0,
+                    "kReflect": 0,
+                    "shine": 1,
+                },
+                "shape": {
+                    "type": "plane",
+                    "point": [centre.x, centre.y, bounds.min.z],
+                    "normal": UP.as_slice(),
+                },
+            });
+            let mut ground =
+                SceneObject::deserialize(ground).expect("the studio ground is a valid object");
+            ground.metres = 1.0;
+            ground.instance_seed = instance_seed(self.seed, index);
+            self.objects.push(ground);
+        }
+    }
+
+    pub(crate) fn add_studio_lights(&mut self, setup: &StudioSetup, centre: &FVec, radius: Float) {
+        // Level with the ground, from the objects towards the camera and to its right
+        let towards = self.camera.position - centre;
+        let towards = FVec::new(towards.x, towards.y, 0.0)
+            .try_normalize(1e-9)
+            .unwrap_or(FVec::x());
+        let right = UP.cross(&towards);
+        let distance = 4.0 * radius;
+        for (name, (back, side, up), share) in [
+            ("studio key", KEY, 1.0),
+            ("studio fill", FILL, 0.4),
+            ("studio rim", RIM, 0.6),
+        ] {
+            let direction = (back * towards + side * right + up * UP).normalize();
+            self.lights.push(LightSource {
+                name: Some(name.to_string()),
+                tags: Vec::new(),
+                colour: FVec::repeat(1.0),
+                pos: centre + distance * direction,
+                // As bright on the objects at any size, falling off as the inverse square
+                intensity: setup.intensity * share * distance * distance,
+                temperature: None,
+                cutoff_radius: None,
+                attenuation: Attenuation::InverseSquare,
+                shape: LightShape::Sphere {
+                    radius: 0.5 * radius,
+                },
+                shadow_samples: None,
+                spot: None,
+                animation: None,
+                objects: None,
+            });
+        }
+    }
+}
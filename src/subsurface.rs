@@ -0,0 +1,163 @@
+use crate::core::consts::PI;
+use crate::core::ray::{Intersection, Ray};
+use crate::light::coordinate_system;
+use crate::sampling::Rng;
+use crate::{FVec, Float, Material, Scene};
+use serde::Deserialize;
+
+/*
+Light entering a translucent material such as skin, wax or marble, scattered
+beneath the surface and leaving it again around where it entered, which
+softens shadows and lets light bleed past edges. Its diffuse light is
+replaced by the light gathered from the surface around each point shaded.
+ */
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct Subsurface {
+    // Light absorbed per metre travelled beneath the surface, per channel
+    pub absorption: FVec,
+    // Light scattered per metre travelled beneath the surface, per channel
+    pub scattering: FVec,
+    // Points around each point shaded that light is gathered from
+    #[serde(default = "default_samples")]
+    pub samples: u32,
+}
+
+fn default_samples() -> u32 {
+    8
+}
+
+// Widths of a profile beyond which the little light still leaving is ignored
+const REACH: Float = 20.0;
+
+impl Subsurface {
+    /*
+    Share of the light entering the surface that leaves it again, per
+    channel, by the dipole of Jensen et al. (2001) under a boundary with the
+    index of refraction.
+     */
+    pub(crate) fn reflectance(&self, ior: Float) -> FVec {
+        let fdr = -1.440 / (ior * ior) + 0.710 / ior + 0.668 + 0.0636 * ior;
+        let a = (1.0 + fdr) / (1.0 - fdr);
+        let extinction = self.absorption + self.scattering;
+        self.scattering
+            .zip_map(&extinction, |scattering, extinction| {
+                let albedo = if extinction > 0.0 {
+                    scattering / extinction
+                } else {
+                    0.0
+                };
+                let root = (3.0 * (1.0 - albedo)).sqrt();
+                albedo / 2.0 * (1.0 + (-4.0 / 3.0 * a * root).exp()) * (-root).exp()
+            })
+    }
+
+    /*
+    Width of the profile of each channel: the mean free path, scaled by the
+    fit of Christensen and Burley (2015) for how far light of the channel's
+    reflectance spreads.
+     */
+    fn widths(&self, reflectance: &FVec) -> FVec {
+        let extinction = self.absorption + self.scattering;
+        extinction.zip_map(reflectance, |extinction, reflectance| {
+            let scale = 1.85 - reflectance + 7.0 * (reflectance - 0.8).abs().powi(3);
+            1.0 / (extinction * scale)
+        })
+    }
+}
+
+// Share per unit area of the light leaving at the distance from where it entered
+fn profile(distance: Float, width: Float) -> Float {
+    let distance = distance.max(1e-6 * width);
+    let spread = (-distance / width).exp() + (-distance / (3.0 * width)).exp();
+    spread / (8.0 * PI * width * distance)
+}
+
+// Distance drawn from the profile: from its narrower part a quarter of the time
+fn sample_distance(width: Float, u: Float) -> Float {
+    if u < 0.25 {
+        -width * (1.0 - 4.0 * u).ln()
+    } else {
+        -3.0 * width * (1.0 - (u - 0.25) / 0.75).ln()
+    }
+}
+
+impl Scene {
+    /*
+    Light leaving a translucent surface at the point after entering it
+    around the point (Christensen and Burley 2015). Points are drawn at
+    distances from the profile of a channel picked at random and found on
+    the object by a ray along the normal, as if the surface were flat about
+    the point, and the light of the lights arriving at each is weighted by
+    every channel's profile. Emitting objects light only the highlights.
+     */
+    pub(crate) fn _get_subsurface_colour(
+        &self,
+        object: usize,
+        intersection: &Intersection,
+        material: &Material,
+        albedo: &FVec,
+        subsurface: &Subsurface,
+        rng: &mut Rng,
+    ) -> FVec {
+        if subsurface.samples == 0 {
+            return FVec::zeros();
+        }
+        let reflectance = subsurface.reflectance(material.ior);
+        let widths = subsurface.widths(&reflectance);
+        let reach = REACH * widths.max();
+        let normal = intersection.geometric_normal.unwrap_or(intersection.normal);
+        let (tangent, bitangent) = coordinate_system(&normal);
+        let only: Vec<bool> = (0..self.objects.len()).map(|i| i == object).collect();
+        let mut total = FVec::zeros();
+        for _ in 0..subsurface.samples {
+            let channel = ((rng.next_float() * 3.0) as usize).min(2);
+            let distance = sample_distance(widths[channel], rng.next_float());
+            if distance >= reach {
+                continue;
+            }
+            let angle = 2.0 * PI * rng.next_float();
+            let height = (reach * reach - distance * distance).sqrt();
+            let across = (tangent * angle.cos() + bitangent * angle.sin()) * distance;
+            let probe = Ray {
+                origin: intersection.pos + across + normal * height,
+                direction: -normal,
+                differential: None,
+                time: intersection.time,
+            };
+            let stops = |object: usize, hit: &Intersection| self._stops_ray(&probe, object, hit);
+            let exit = match self.primitives.nearest(&probe, 0.0, Some(&only), stops) {
+                Some((_, exit)) if exit.t <= 2.0 * height => exit,
+                _ => continue,
+            };
+            let travelled = (exit.pos - intersection.pos).norm();
+            let density = widths.map(|width| profile(distance, width)).mean();
+            let weights = widths.zip_map(&reflectance, |width, reflectance| {
+                reflectance * profile(travelled, width) / density
+            });
+            total += self
+                ._get_irradiance(object, &exit, rng)
+                .component_mul(&weights);
+        }
+        material.diffuse_weight() * albedo.component_mul(&total) / subsurface.samples as Float
+    }
+
+    // Light of the lights arriving at a point of the object, one sample of each
+    fn _get_irradiance(&self, object: usize, intersection: &Intersection, rng: &mut Rng) -> FVec {
+        let white = FVec::repeat(1.0);
+        self._get_sampled_lights(object, intersection, rng)
+            .into_iter()
+            .map(|(light, weight)| {
+                let u = [rng.next_float(), rng.next_float(), rng.next_float()];
+                let sample = light.sample(&intersection.pos, u);
+                match self._get_light_ray(intersection, light, &sample) {
+                    Some((ray, passed)) => {
+                        let lit = self._get_diffuse_lighting(intersection, &white, light, &ray);
+                        weight * lit.component_mul(&passed)
+                    }
+                    None => FVec::zeros(),
+                }
+            })
+            .sum()
+    }
+}
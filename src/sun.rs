@@ -0,0 +1,81 @@
+use crate::core::consts::PI;
+use crate::{FVec, Float};
+use serde::Deserialize;
+
+/*
+Where and when the scene is observed, for placing the sun. The scene is
+assumed to use +x as east, +y as north and +z as up.
+ */
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct SunPosition {
+    // Degrees, north positive
+    pub latitude: Float,
+    // Degrees, east positive
+    pub longitude: Float,
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+    // Local clock time in hours, e.g. 14.5 for half past two
+    pub hour: Float,
+    // Hours ahead of UTC for the local clock
+    #[serde(default)]
+    pub utc_offset: Float,
+}
+
+impl SunPosition {
+    /*
+    Unit vector pointing from the scene towards the sun, using the NOAA
+    fractional-year approximations for declination and the equation of time
+    (accurate to a fraction of a degree, plenty for lighting studies).
+     */
+    pub fn direction(&self) -> FVec {
+        let day_of_year = day_of_year(self.year, self.month, self.day) as Float;
+        let days_in_year = if is_leap_year(self.year) {
+            366.0
+        } else {
+            365.0
+        };
+        let utc_hour = self.hour - self.utc_offset;
+        let gamma = 2.0 * PI / days_in_year * (day_of_year - 1.0 + (utc_hour - 12.0) / 24.0);
+
+        let equation_of_time = 229.18
+            * (0.000075 + 0.001868 * gamma.cos()
+                - 0.032077 * gamma.sin()
+                - 0.014615 * (2.0 * gamma).cos()
+                - 0.040849 * (2.0 * gamma).sin());
+        let declination = 0.006918 - 0.399912 * gamma.cos() + 0.070257 * gamma.sin()
+            - 0.006758 * (2.0 * gamma).cos()
+            + 0.000907 * (2.0 * gamma).sin()
+            - 0.002697 * (3.0 * gamma).cos()
+            + 0.00148 * (3.0 * gamma).sin();
+
+        // True solar time in minutes, then the hour angle (zero at solar noon)
+        let time_offset = equation_of_time + 4.0 * self.longitude - 60.0 * self.utc_offset;
+        let solar_minutes = self.hour * 60.0 + time_offset;
+        let hour_angle = (solar_minutes / 4.0 - 180.0).to_radians();
+
+        let latitude = self.latitude.to_radians();
+        let east = -declination.cos() * hour_angle.sin();
+        let north = declination.sin() * latitude.cos()
+            - declination.cos() * hour_angle.cos() * latitude.sin();
+        let up = declination.sin() * latitude.sin()
+            + declination.cos() * hour_angle.cos() * latitude.cos();
+        FVec::new(east, north, up).normalize()
+    }
+}
+
+fn is_leap_year(year: i32) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+fn day_of_year(year: i32, month: u32, day: u32) -> u32 {
+    const DAYS_BEFORE_MONTH: [u32; 12] = [0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334];
+    let month_index = (month.clamp(1, 12) - 1) as usize;
+    let leap_day = if month > 2 && is_leap_year(year) {
+        1
+    } else {
+        0
+    };
+    DAYS_BEFORE_MONTH[month_index] + leap_day + day
+}
@@ -0,0 +1,670 @@
+use crate::assets;
+use crate::colour::{ColourPipeline, Processor, TextureColourSpace};
+use crate::config::{find_asset, texture_cache_bytes};
+use crate::core::consts::PI;
+use crate::core::single;
+use crate::noise::{fractal, turbulence};
+use crate::plugin::{self, TexturePlugin};
+use crate::script::ScriptTexture;
+use crate::{FVec, Float};
+use image::{ImageError, Rgb32FImage};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+// Stands for the tile number in the path of a UDIM texture set
+const UDIM_TOKEN: &str = "<UDIM>";
+
+// Rows of UDIM tiles looked for, each ten tiles wide, covering tiles 1001 to 1100
+const UDIM_ROWS: u32 = 10;
+
+/*
+Colour of a surface, looked up by the texture coordinates of the point being
+shaded (see SceneObject::texture_uv). Written in a scene as a plain colour, as
+{"image": "wood.png"}, as {"checker": [[1, 1, 1], [0, 0, 0]]} or as
+{"vertexColours": [1, 1, 1]}, as {"script": "..."} for a Rhai script (see
+ScriptTexture), or as {"plugin": "marble", ...} for a texture registered by
+the program. Patterns repeat every `scale` units of the
+coordinates: once around a sphere at the default of 1, every metre on planes
+and meshes without texture coordinates, and once per unit of a mesh's own.
+Solid textures such as {"pattern": "marble"} are instead looked up by the
+point's position relative to the object, in metres, and need no coordinates.
+ */
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Texture {
+    Solid(FVec),
+    Image {
+        /*
+        Relative to the scene file or a search path. A path containing <UDIM>,
+        such as "skin.<UDIM>.png", names a set of tiles instead of one image.
+         */
+        #[serde(rename = "image")]
+        path: String,
+        #[serde(default = "default_scale")]
+        scale: Float,
+        #[serde(rename = "colourSpace")]
+        colour_space: Option<TextureColourSpace>,
+        #[serde(skip)]
+        images: Option<Arc<ImageSet>>,
+    },
+    // Squares of two alternating colours
+    Checker {
+        checker: [FVec; 2],
+        #[serde(default = "default_scale")]
+        scale: Float,
+    },
+    /*
+    A pattern filling space between two colours, carved out by the surface
+    like a block of stone or wood. Features are about `scale` metres across,
+    and `turbulence` scales how much noise disturbs the marble's veins and
+    the wood's rings.
+     */
+    Procedural {
+        pattern: Pattern,
+        #[serde(default = "default_colours")]
+        colours: [FVec; 2],
+        #[serde(default = "default_scale")]
+        scale: Float,
+        #[serde(default = "default_octaves")]
+        octaves: u32,
+        #[serde(default = "default_turbulence")]
+        turbulence: Float,
+        #[serde(default)]
+        seed: u32,
+    },
+    // The colours of a mesh's vertices, or the given colour on surfaces without them
+    VertexColours {
+        #[serde(rename = "vertexColours")]
+        fallback: FVec,
+    },
+    // Computed by a Rhai script of the point, its texture coordinates and the normal
+    Script {
+        #[serde(rename = "script")]
+        source: String,
+        #[serde(skip)]
+        script: Option<Arc<ScriptTexture>>,
+    },
+    // Made by the plugin registered under the name, from the other fields
+    Plugin {
+        #[serde(rename = "plugin")]
+        name: String,
+        #[serde(flatten)]
+        params: serde_json::Map<String, Value>,
+        #[serde(skip)]
+        texture: Option<Arc<dyn TexturePlugin>>,
+    },
+}
+
+fn default_scale() -> Float {
+    1.0
+}
+
+fn default_colours() -> [FVec; 2] {
+    [FVec::zeros(), FVec::repeat(1.0)]
+}
+
+fn default_octaves() -> u32 {
+    4
+}
+
+fn default_turbulence() -> Float {
+    1.0
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum Pattern {
+    // Cubes of alternating colours
+    Checker,
+    // Fractal Perlin noise, blending smoothly between the colours
+    Noise,
+    // Bands of the second colour along x, bent by turbulence into veins
+    Marble,
+    // Rings of the second colour around the z axis, warped by noise
+    Wood,
+}
+
+impl Pattern {
+    // How far towards the second colour the point is, from 0 to 1
+    fn at(self, p: &FVec, octaves: u32, amount: Float, seed: u32) -> Float {
+        match self {
+            Pattern::Checker => {
+                let parity = p.map(|x| (x * 2.0).floor()).sum();
+                parity.rem_euclid(2.0)
+            }
+            Pattern::Noise => 0.5 + 0.5 * fractal(seed, p, octaves),
+            Pattern::Marble => {
+                let phase = PI * (p.x + 4.0 * amount * turbulence(seed, p, octaves));
+                let vein = 0.5 + 0.5 * phase.sin();
+                // Thin dark veins in lighter stone
+                1.0 - vein.powf(0.25)
+            }
+            Pattern::Wood => {
+                let radius = p.xy().norm() + 0.4 * amount * fractal(seed, p, octaves);
+                let ring = (radius * 4.0).rem_euclid(1.0);
+                // Late wood grows in a thin dark band at the end of each year
+                ring.powi(3)
+            }
+        }
+    }
+}
+
+impl Texture {
+    // Load images relative to base_dir or a search path, converting them to the working space
+    pub fn load(&mut self, base_dir: &Path, colour: &ColourPipeline) -> Result<(), Box<dyn Error>> {
+        if let Texture::Plugin {
+            name,
+            params,
+            texture,
+        } = self
+        {
+            let made = plugin::make_texture(name, &Value::Object(params.clone()));
+            *texture = Some(made.map_err(|error| format!("texture {name:?}: {error}"))?);
+        }
+        if let Texture::Script { source, script } = self {
+            let compiled = ScriptTexture::compile(source);
+            *script = Some(Arc::new(
+                compiled.map_err(|error| format!("script: {error}"))?,
+            ));
+        }
+        if let Texture::Image {
+            path,
+            colour_space,
+            images,
+            ..
+        } = self
+        {
+            let path = path.as_str();
+            let load = |path: &Path| -> Result<Tile, Box<dyn Error>> {
+                let to_working = colour.texture_processor(colour_space.as_ref(), path)?;
+                Tile::new(path, to_working)
+            };
+            let read = || -> Result<ImageSet, Box<dyn Error>> {
+                if !path.contains(UDIM_TOKEN) {
+                    return Ok(ImageSet::Single(load(&find_asset(base_dir, path))?));
+                }
+                let mut tiles = HashMap::new();
+                for tile in 1001..1001 + 10 * UDIM_ROWS {
+                    let tile_path = path.replace(UDIM_TOKEN, &tile.to_string());
+                    let tile_path = find_asset(base_dir, tile_path);
+                    if tile_path.exists() {
+                        tiles.insert(tile, load(&tile_path)?);
+                    }
+                }
+                if tiles.is_empty() {
+                    return Err(format!("{}: no UDIM tiles found", path).into());
+                }
+                Ok(ImageSet::Udim(tiles))
+            };
+            // Images read for an earlier frame are used again while their files are unchanged
+            let current = |set: &ImageSet| {
+                set.tiles().all(|tile| {
+                    let to_working = colour.texture_processor(colour_space.as_ref(), &tile.path);
+                    to_working.is_ok_and(|to_working| to_working == tile.to_working)
+                        && assets::modified(&tile.path) == tile.modified
+                })
+            };
+            let kept = assets::images(base_dir.join(path), colour_space.clone(), current, read);
+            *images = Some(kept?);
+        }
+        Ok(())
+    }
+
+    // Load as values such as normals or heights, not converted unless a colour space is given
+    pub(crate) fn load_data(
+        &mut self,
+        base_dir: &Path,
+        colour: &ColourPipeline,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Texture::Image { colour_space, .. } = self {
+            colour_space.get_or_insert(TextureColourSpace::Data);
+        }
+        self.load(base_dir, colour)
+    }
+
+    /*
+    Colour at the texture coordinates of a point, or at its position relative
+    to the object for solid textures. Only scripts look at the normal. Images
+    are filtered over the footprint of the pixel where one is given.
+     */
+    pub fn at(
+        &self,
+        (u, v): (Float, Float),
+        footprint: Option<&UvDifferentials>,
+        position: &FVec,
+        normal: &FVec,
+        vertex_colour: Option<FVec>,
+    ) -> FVec {
+        match self {
+            Texture::Solid(colour) => *colour,
+            Texture::Image { scale, images, .. } => images
+                .as_ref()
+                .expect("textures are loaded with the scene")
+                .sample(
+                    u / scale,
+                    v / scale,
+                    footprint.map(|f| f.scale(1.0 / scale)),
+                ),
+            Texture::Checker { checker, scale } => {
+                let parity = (u / scale * 2.0).floor() + (v / scale * 2.0).floor();
+                checker[parity.rem_euclid(2.0) as usize]
+            }
+            Texture::Procedural {
+                pattern,
+                colours,
+                scale,
+                octaves,
+                turbulence,
+                seed,
+            } => {
+                let t = pattern.at(&(position / *scale), *octaves, *turbulence, *seed);
+                colours[0] * (1.0 - t) + colours[1] * t
+            }
+            Texture::VertexColours { fallback } => vertex_colour.unwrap_or(*fallback),
+            Texture::Script { script, .. } => script
+                .as_ref()
+                .expect("textures are loaded with the scene")
+                .colour(position, (u, v), normal),
+            Texture::Plugin { texture, .. } => texture
+                .as_ref()
+                .expect("textures are loaded with the scene")
+                .colour((u, v)),
+        }
+    }
+
+    // The loaded images of an image texture, shared by every copy of the texture
+    pub(crate) fn images(&self) -> Option<&Arc<ImageSet>> {
+        match self {
+            Texture::Image { images, .. } => images.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+// Change in texture coordinates per pixel step in screen x and y
+#[derive(Debug, Clone, Copy)]
+pub struct UvDifferentials {
+    pub dudx: Float,
+    pub dvdx: Float,
+    pub dudy: Float,
+    pub dvdy: Float,
+}
+
+impl UvDifferentials {
+    fn scale(&self, s: Float) -> UvDifferentials {
+        UvDifferentials {
+            dudx: self.dudx * s,
+            dvdx: self.dvdx * s,
+            dudy: self.dudy * s,
+            dvdy: self.dvdy * s,
+        }
+    }
+}
+
+// The images of an image texture
+#[derive(Debug)]
+pub enum ImageSet {
+    // One image repeated across the whole surface
+    Single(Tile),
+    /*
+    One image per unit square of texture coordinates, numbered 1001 + u + 10v
+    from the square at the origin, with v counting up from the bottom of an
+    image as in the files the coordinates come from. Squares without a tile
+    are black.
+     */
+    Udim(HashMap<u32, Tile>),
+}
+
+impl ImageSet {
+    fn tiles(&self) -> Box<dyn Iterator<Item = &Tile> + '_> {
+        match self {
+            ImageSet::Single(tile) => Box::new(std::iter::once(tile)),
+            ImageSet::Udim(tiles) => Box::new(tiles.values()),
+        }
+    }
+
+    // Memory the images take once all of them are decoded
+    pub(crate) fn decoded_bytes(&self) -> usize {
+        match self {
+            ImageSet::Single(tile) => tile.decoded_bytes(),
+            ImageSet::Udim(tiles) => tiles.values().map(Tile::decoded_bytes).sum(),
+        }
+    }
+
+    fn sample(&self, u: Float, v: Float, footprint: Option<UvDifferentials>) -> FVec {
+        match self {
+            ImageSet::Single(tile) => {
+                tile.image()
+                    .sample_footprint(u.rem_euclid(1.0), v.rem_euclid(1.0), footprint)
+            }
+            ImageSet::Udim(tiles) => {
+                // Texture coordinates here have v running down from the top of the first row
+                let (column, row) = (u.floor(), (1.0 - v).floor());
+                if !(0.0..10.0).contains(&column) || row < 0.0 {
+                    return FVec::zeros();
+                }
+                let tile = 1001 + column as u32 + 10 * row as u32;
+                tiles.get(&tile).map_or(FVec::zeros(), |tile| {
+                    tile.image()
+                        .sample_footprint(u - column, v + row, footprint)
+                })
+            }
+        }
+    }
+}
+
+/*
+Decoded images of every image texture in memory, keyed by tile. Images are
+decoded the first time they are sampled rather than when the scene loads,
+and once together they take more than the budget in config the least
+recently sampled are dropped, to be decoded again if they are needed later.
+ */
+#[derive(Default)]
+struct TileCache {
+    tiles: BTreeMap<u64, CachedTile>,
+    bytes: usize,
+    // Counts samples, ordering tiles by when they were last sampled
+    clock: u64,
+}
+
+struct CachedTile {
+    image: Arc<ImageTexture>,
+    last_used: u64,
+}
+
+static TILE_CACHE: Mutex<TileCache> = Mutex::new(TileCache {
+    tiles: BTreeMap::new(),
+    bytes: 0,
+    clock: 0,
+});
+
+static NEXT_TILE_ID: AtomicU64 = AtomicU64::new(0);
+
+impl TileCache {
+    fn get(&mut self, id: u64) -> Option<Arc<ImageTexture>> {
+        self.clock += 1;
+        let tile = self.tiles.get_mut(&id)?;
+        tile.last_used = self.clock;
+        Some(tile.image.clone())
+    }
+
+    fn insert(&mut self, id: u64, image: Arc<ImageTexture>) {
+        self.clock += 1;
+        let bytes = image.bytes();
+        let tile = CachedTile {
+            image,
+            last_used: self.clock,
+        };
+        if let Some(previous) = self.tiles.insert(id, tile) {
+            // Another thread decoded the same tile at the same time
+            self.bytes -= previous.image.bytes();
+        }
+        self.bytes += bytes;
+        let budget = texture_cache_bytes();
+        while self.bytes > budget {
+            let oldest = self
+                .tiles
+                .iter()
+                .filter(|(&other, _)| other != id)
+                .min_by_key(|(_, tile)| tile.last_used)
+                .map(|(&other, _)| other);
+            let Some(oldest) = oldest else {
+                break;
+            };
+            self.remove(oldest);
+        }
+    }
+
+    fn remove(&mut self, id: u64) {
+        if let Some(tile) = self.tiles.remove(&id) {
+            self.bytes -= tile.image.bytes();
+        }
+    }
+}
+
+// One image of a texture, decoded into the working space through the tile cache
+#[derive(Debug)]
+pub struct Tile {
+    id: u64,
+    path: PathBuf,
+    // Width and height in texels, from the file's header
+    dimensions: (u32, u32),
+    to_working: Processor,
+    // When the file was last changed, for telling whether it can be used again
+    modified: Option<SystemTime>,
+}
+
+impl Tile {
+    // Only the header is read here, so missing and unreadable files are still found early
+    fn new(path: &Path, to_working: Processor) -> Result<Tile, Box<dyn Error>> {
+        let dimensions = image::image_dimensions(path)
+            .map_err(|error| format!("{}: {}", path.display(), error))?;
+        Ok(Tile {
+            id: NEXT_TILE_ID.fetch_add(1, Ordering::Relaxed),
+            path: path.to_path_buf(),
+            dimensions,
+            to_working,
+            modified: assets::modified(path),
+        })
+    }
+
+    // Including the smaller levels built for filtering
+    fn decoded_bytes(&self) -> usize {
+        let (mut width, mut height) = self.dimensions;
+        let mut texels = width as usize * height as usize;
+        while width > 1 || height > 1 {
+            (width, height) = (half(width), half(height));
+            texels += width as usize * height as usize;
+        }
+        texels * 3 * std::mem::size_of::<f32>()
+    }
+
+    fn image(&self) -> Arc<ImageTexture> {
+        if let Some(image) = TILE_CACHE.lock().unwrap().get(self.id) {
+            return image;
+        }
+        // Decode without holding the lock so other threads keep sampling their tiles
+        let image = match ImageTexture::load(&self.path) {
+            Ok(mut image) => {
+                image.map_colours(|colour| self.to_working.apply(colour));
+                image.build_levels();
+                image
+            }
+            Err(error) => {
+                error!("{}: {}", self.path.display(), error);
+                ImageTexture::black()
+            }
+        };
+        let image = Arc::new(image);
+        TILE_CACHE.lock().unwrap().insert(self.id, image.clone());
+        image
+    }
+}
+
+impl Drop for Tile {
+    fn drop(&mut self) {
+        if let Ok(mut cache) = TILE_CACHE.lock() {
+            cache.remove(self.id);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ImageTexture {
+    image: Rgb32FImage,
+    // Each half the size of the one before, down to a single texel, once built
+    levels: Vec<Rgb32FImage>,
+}
+
+impl ImageTexture {
+    pub fn load(path: &Path) -> Result<ImageTexture, ImageError> {
+        #[cfg(feature = "hdr")]
+        if image::ImageFormat::from_path(path).ok() == Some(image::ImageFormat::Hdr) {
+            // Decoded by the generic reader, Radiance images are clamped to 8 bits
+            let file = std::io::BufReader::new(std::fs::File::open(path)?);
+            let decoder = image::codecs::hdr::HdrDecoder::new(file)?;
+            let (width, height) = (decoder.metadata().width, decoder.metadata().height);
+            let pixels = decoder.read_image_hdr()?;
+            let texels = pixels.iter().flat_map(|pixel| pixel.0).collect();
+            let image = Rgb32FImage::from_raw(width, height, texels)
+                .expect("decoded images have a texel per pixel");
+            return Ok(ImageTexture {
+                image,
+                levels: vec![],
+            });
+        }
+        let image = image::open(path)?.into_rgb32f();
+        Ok(ImageTexture {
+            image,
+            levels: vec![],
+        })
+    }
+
+    // A single black texel, standing in for an image that could not be decoded
+    fn black() -> ImageTexture {
+        ImageTexture {
+            image: Rgb32FImage::new(1, 1),
+            levels: vec![],
+        }
+    }
+
+    // Memory taken by the decoded texels
+    pub(crate) fn bytes(&self) -> usize {
+        let levels = std::iter::once(&self.image).chain(&self.levels);
+        levels
+            .map(|level| std::mem::size_of_val(level.as_raw().as_slice()))
+            .sum()
+    }
+
+    // The texels themselves, for processing the whole image
+    pub(crate) fn image_mut(&mut self) -> &mut Rgb32FImage {
+        self.levels.clear();
+        &mut self.image
+    }
+
+    /*
+    Build the smaller levels sampled for pixels that cover many texels, each
+    texel the average of the two by two below it. Changing the texels drops
+    them again.
+     */
+    pub(crate) fn build_levels(&mut self) {
+        self.levels.clear();
+        let mut previous = &self.image;
+        while previous.width() > 1 || previous.height() > 1 {
+            let (width, height) = (half(previous.width()), half(previous.height()));
+            let level = Rgb32FImage::from_fn(width, height, |x, y| {
+                let texel = |dx: u32, dy: u32| {
+                    let sx = (2 * x + dx).min(previous.width() - 1);
+                    let sy = (2 * y + dy).min(previous.height() - 1);
+                    previous.get_pixel(sx, sy).0
+                };
+                let texels = [texel(0, 0), texel(1, 0), texel(0, 1), texel(1, 1)];
+                image::Rgb([0, 1, 2].map(|c| texels.iter().map(|t| t[c]).sum::<f32>() * 0.25))
+            });
+            self.levels.push(level);
+            previous = self.levels.last().expect("a level was just added");
+        }
+    }
+
+    // Replace every texel's colour, e.g. to convert between colour spaces
+    pub fn map_colours(&mut self, f: impl Fn(FVec) -> FVec) {
+        self.levels.clear();
+        for pixel in self.image.pixels_mut() {
+            let colour = f(FVec::new(
+                pixel[0] as Float,
+                pixel[1] as Float,
+                pixel[2] as Float,
+            ));
+            pixel.0 = colour.map(single).into();
+        }
+    }
+
+    pub(crate) fn dimensions(&self) -> (u32, u32) {
+        self.image.dimensions()
+    }
+
+    pub(crate) fn texel(&self, x: i64, y: i64) -> FVec {
+        level_texel(&self.image, x, y)
+    }
+
+    /*
+    Bilinearly filtered colour at texture coordinates (u, v), with (0, 0) the
+    top-left corner and (1, 1) the bottom-right. Coordinates outside the unit
+    square are clamped to the edge.
+     */
+    pub fn sample(&self, u: Float, v: Float) -> FVec {
+        bilinear(&self.image, u, v)
+    }
+
+    /*
+    Colour at (u, v) averaged over the footprint of a pixel: trilinearly
+    between the two levels whose texels are nearest the size of the longer
+    side of the footprint, so distant and minified textures do not alias.
+    Without a footprint or built levels this is the bilinear sample.
+     */
+    pub fn sample_footprint(&self, u: Float, v: Float, footprint: Option<UvDifferentials>) -> FVec {
+        let (Some(footprint), false) = (footprint, self.levels.is_empty()) else {
+            return self.sample(u, v);
+        };
+        let (width, height) = (self.image.width() as Float, self.image.height() as Float);
+        let along_x = (footprint.dudx * width).hypot(footprint.dvdx * height);
+        let along_y = (footprint.dudy * width).hypot(footprint.dvdy * height);
+        let texels = along_x.max(along_y);
+        if !texels.is_finite() || texels <= 1.0 {
+            return self.sample(u, v);
+        }
+        let level = texels.log2().min(self.levels.len() as Float);
+        let below = level.floor() as usize;
+        let fraction = level - below as Float;
+        let at = |index: usize| match index {
+            0 => self.sample(u, v),
+            _ => bilinear(&self.levels[(index - 1).min(self.levels.len() - 1)], u, v),
+        };
+        if fraction == 0.0 {
+            return at(below);
+        }
+        at(below) * (1.0 - fraction) + at(below + 1) * fraction
+    }
+}
+
+fn level_texel(image: &Rgb32FImage, x: i64, y: i64) -> FVec {
+    let x = x.clamp(0, image.width() as i64 - 1) as u32;
+    let y = y.clamp(0, image.height() as i64 - 1) as u32;
+    let p = image.get_pixel(x, y);
+    FVec::new(p[0] as Float, p[1] as Float, p[2] as Float)
+}
+
+fn bilinear(image: &Rgb32FImage, u: Float, v: Float) -> FVec {
+    let x = u * image.width() as Float - 0.5;
+    let y = v * image.height() as Float - 0.5;
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let (x0, y0) = (x0 as i64, y0 as i64);
+    let texel = |x, y| level_texel(image, x, y);
+    let top = texel(x0, y0) * (1.0 - fx) + texel(x0 + 1, y0) * fx;
+    let bottom = texel(x0, y0 + 1) * (1.0 - fx) + texel(x0 + 1, y0 + 1) * fx;
+    top * (1.0 - fy) + bottom * fy
+}
+
+// Size of the next smaller level along a side
+fn half(size: u32) -> u32 {
+    (size / 2).max(1)
+}
+
+impl fmt::Debug for ImageTexture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ImageTexture({}x{})",
+            self.image.width(),
+            self.image.height()
+        )
+    }
+}
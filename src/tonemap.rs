@@ -0,0 +1,208 @@
+use crate::colour::Processor;
+use crate::{FVec, Float};
+use serde::Deserialize;
+
+// Curve taking scene-linear values of any brightness into the displayable range
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum ToneMapOperator {
+    // Values are kept as they are and clamped to [0, 1] when written
+    #[default]
+    Linear,
+    // x / (1 + x) on each channel, so highlights roll off without ever clipping
+    Reinhard,
+    // Krzysztof Narkowicz's fit of the ACES filmic curve, with a toe and a shoulder
+    Aces,
+}
+
+/*
+How linear colours become an 8-bit image: scaled by the exposure, mapped
+into [0, 1] by the operator, then encoded for display with the sRGB curve or
+a plain power of 1 / gamma. With colour management the output space does the
+encoding instead. Floating-point outputs are written before any of this.
+ */
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ToneMapping {
+    #[serde(default)]
+    pub operator: ToneMapOperator,
+    // Stops of brightening before the operator; negative values darken
+    #[serde(default)]
+    pub exposure: Float,
+    pub gamma: Option<Float>,
+    // Exposure chosen from the render, which the exposure above then adjusts
+    pub auto_exposure: Option<AutoExposure>,
+}
+
+// Weights of the red, green and blue of the working space in its luminance (Rec. 709)
+pub(crate) const LUMINANCE_WEIGHTS: [Float; 3] = [0.2126, 0.7152, 0.0722];
+
+// How the brightness of a render is measured for automatic exposure
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Metering {
+    /*
+    The average of the logarithm of the luminance of every pixel, as in
+    Reinhard et al. 2002, which small bright lights barely move
+     */
+    Average,
+    // The luminance that the given fraction of the pixels are darker than
+    Percentile,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AutoExposure {
+    #[serde(default = "default_metering")]
+    pub metering: Metering,
+    // Luminance the average is brought to, middle grey by default
+    #[serde(default = "default_key")]
+    pub key: Float,
+    // Fraction of the pixels kept below white when metering by percentile
+    #[serde(default = "default_percentile")]
+    pub percentile: Float,
+}
+
+fn default_metering() -> Metering {
+    Metering::Average
+}
+
+fn default_key() -> Float {
+    0.18
+}
+
+fn default_percentile() -> Float {
+    0.95
+}
+
+impl AutoExposure {
+    // Stops that bring the metered brightness of the colours to its target, if any are lit
+    fn stops(&self, colours: impl Iterator<Item = FVec>) -> Option<Float> {
+        let weights = FVec::from(LUMINANCE_WEIGHTS);
+        let mut luminances: Vec<Float> = colours
+            .map(|colour| colour.dot(&weights))
+            .filter(|luminance| luminance.is_finite())
+            .map(|luminance| luminance.max(0.0))
+            .collect();
+        let stops = match self.metering {
+            Metering::Average => {
+                // Offset so black pixels do not pull the average to zero
+                let delta = 1e-4;
+                let count = luminances.len() as Float;
+                let log_sum: Float = luminances.iter().map(|l| (l + delta).ln()).sum();
+                let average = (log_sum / count).exp() - delta;
+                (average > 0.0).then(|| (self.key / average).log2())
+            }
+            Metering::Percentile => {
+                luminances.sort_by(Float::total_cmp);
+                let rank = self.percentile.clamp(0.0, 1.0) * (luminances.len() as Float - 1.0);
+                let luminance = *luminances.get(rank.round() as usize)?;
+                (luminance > 0.0).then(|| (1.0 / luminance).log2())
+            }
+        };
+        stops.filter(|stops| stops.is_finite())
+    }
+}
+
+impl ToneMapOperator {
+    fn apply(self, x: Float) -> Float {
+        match self {
+            ToneMapOperator::Linear => x,
+            ToneMapOperator::Reinhard => x / (1.0 + x),
+            ToneMapOperator::Aces => {
+                let (a, b, c, d, e) = (2.51, 0.03, 2.43, 0.59, 0.14);
+                (x * (a * x + b)) / (x * (c * x + d) + e)
+            }
+        }
+    }
+}
+
+impl ToneMapping {
+    /*
+    The tone mapping for an image of the given colours, with the automatic
+    exposure, if any, metered from them and added to the exposure. Images
+    that are all black keep the exposure as written.
+     */
+    pub fn metered(&self, colours: impl Iterator<Item = FVec>) -> ToneMapping {
+        let Some(auto_exposure) = &self.auto_exposure else {
+            return *self;
+        };
+        let stops = auto_exposure.stops(colours).unwrap_or(0.0);
+        debug!("Metered an exposure of {:+.2} stops", stops);
+        ToneMapping {
+            exposure: self.exposure + stops,
+            auto_exposure: None,
+            ..*self
+        }
+    }
+
+    // Display-linear colour in [0, 1] for a scene-linear colour
+    pub fn apply(&self, colour: FVec) -> FVec {
+        let scale = self.exposure.exp2();
+        colour.map(|c| self.operator.apply((c * scale).max(0.0)).clamp(0.0, 1.0))
+    }
+
+    // Display-linear colour encoded for an 8-bit image
+    pub fn encode(&self, colour: FVec) -> FVec {
+        match self.gamma {
+            Some(gamma) => colour.map(|c| c.max(0.0).powf(1.0 / gamma)),
+            None => Processor::linear_to_srgb().apply(colour),
+        }
+    }
+}
+
+/*
+Look adjustments to 8-bit outputs, made to display-linear colours after
+tone mapping and before they are encoded: lift, gamma and gain per channel
+for the shadows, midtones and highlights, then contrast about middle grey,
+then saturation. The defaults change nothing.
+ */
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Grading {
+    // Raises the black level towards the given colour, leaving white where it is
+    #[serde(default = "default_lift")]
+    pub lift: FVec,
+    // Brightens the midtones above 1 and darkens them below, leaving black and white
+    #[serde(default = "default_unit")]
+    pub gamma: FVec,
+    // Multiplies every channel, scaling the highlights most
+    #[serde(default = "default_unit")]
+    pub gain: FVec,
+    // Above 1 spreads values away from middle grey, below 1 draws them towards it
+    #[serde(default = "default_one")]
+    pub contrast: Float,
+    // 0 for greys, 1 unchanged and above 1 for more vivid colours
+    #[serde(default = "default_one")]
+    pub saturation: Float,
+}
+
+// Display-linear value left in place by changes of contrast
+const CONTRAST_PIVOT: Float = 0.18;
+
+fn default_lift() -> FVec {
+    FVec::zeros()
+}
+
+fn default_unit() -> FVec {
+    FVec::repeat(1.0)
+}
+
+fn default_one() -> Float {
+    1.0
+}
+
+impl Grading {
+    pub fn apply(&self, colour: FVec) -> FVec {
+        let graded = FVec::from_fn(|channel, _| {
+            let x = colour[channel].max(0.0);
+            let lifted = self.gain[channel] * (x + self.lift[channel] * (1.0 - x));
+            let gamma = self.gamma[channel].max(Float::EPSILON);
+            let corrected = lifted.max(0.0).powf(1.0 / gamma);
+            CONTRAST_PIVOT * (corrected / CONTRAST_PIVOT).powf(self.contrast.max(0.0))
+        });
+        let luminance = graded.dot(&FVec::from(LUMINANCE_WEIGHTS));
+        (FVec::repeat(luminance) + (graded - FVec::repeat(luminance)) * self.saturation.max(0.0))
+            .map(|c| c.max(0.0))
+    }
+}
@@ -0,0 +1,159 @@
+use crate::animation::{interpolate, Keyframe};
+use crate::{FVec, Float};
+use nalgebra::{Matrix3, Matrix4, Point3, Rotation3};
+use serde::Deserialize;
+
+// Relative difference allowed between the scale factors of a uniform scale
+const UNIFORM_SCALE_TOLERANCE: Float = 1e-9;
+
+/*
+Placement of geometry in the scene. Either components applied in the order
+scale, rotate, translate, with the rotation in degrees about the x, y and z
+axes in that order, or a 4x4 matrix given row by row and applied to points
+as column vectors, whose last row must be 0, 0, 0, 1.
+ */
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(untagged)]
+pub enum Transform {
+    Matrix { matrix: [[Float; 4]; 4] },
+    Components(Components),
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Components {
+    #[serde(default = "default_translate")]
+    pub translate: FVec,
+    #[serde(default = "default_rotate")]
+    pub rotate: FVec,
+    #[serde(default = "default_scale")]
+    pub scale: Float,
+}
+
+fn default_translate() -> FVec {
+    FVec::zeros()
+}
+
+fn default_rotate() -> FVec {
+    FVec::zeros()
+}
+
+fn default_scale() -> Float {
+    1.0
+}
+
+impl Default for Transform {
+    fn default() -> Transform {
+        Transform::Components(Components {
+            translate: default_translate(),
+            rotate: default_rotate(),
+            scale: default_scale(),
+        })
+    }
+}
+
+// Keyframes of the components of an object's transform, in place of those written in it
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TransformAnimation {
+    #[serde(default)]
+    pub translate: Vec<Keyframe<FVec>>,
+    #[serde(default)]
+    pub rotate: Vec<Keyframe<FVec>>,
+    #[serde(default)]
+    pub scale: Vec<Keyframe<Float>>,
+}
+
+impl TransformAnimation {
+    // The object's transform at the frame, keeping the written components that are not keyed
+    pub fn at(&self, transform: Option<Transform>, frame: Float) -> Result<Transform, String> {
+        let Transform::Components(mut components) = transform.unwrap_or_default() else {
+            return Err("a transform given as a matrix cannot be animated".to_string());
+        };
+        if let Some(translate) = interpolate(&self.translate, frame) {
+            components.translate = translate;
+        }
+        if let Some(rotate) = interpolate(&self.rotate, frame) {
+            components.rotate = rotate;
+        }
+        if let Some(scale) = interpolate(&self.scale, frame) {
+            components.scale = scale;
+        }
+        Ok(Transform::Components(components))
+    }
+}
+
+impl Transform {
+    pub fn matrix(&self) -> Result<Matrix4<Float>, String> {
+        match self {
+            Transform::Matrix { matrix } => {
+                if matrix[3] != [0.0, 0.0, 0.0, 1.0] {
+                    let error = "the last row of a transform matrix must be 0, 0, 0, 1";
+                    return Err(error.to_string());
+                }
+                let matrix = Matrix4::from_fn(|row, column| matrix[row][column]);
+                if matrix.fixed_view::<3, 3>(0, 0).determinant() == 0.0 {
+                    return Err("transform matrix cannot be inverted".to_string());
+                }
+                Ok(matrix)
+            }
+            Transform::Components(components) => {
+                let [x, y, z] = [
+                    components.rotate.x,
+                    components.rotate.y,
+                    components.rotate.z,
+                ]
+                .map(Float::to_radians);
+                if components.scale == 0.0 {
+                    return Err("transform scale must not be 0".to_string());
+                }
+                Ok(Matrix4::new_translation(&components.translate)
+                    * Rotation3::from_euler_angles(x, y, z).to_homogeneous()
+                    * Matrix4::new_scaling(components.scale))
+            }
+        }
+    }
+}
+
+pub fn transform_point(matrix: &Matrix4<Float>, point: &FVec) -> FVec {
+    matrix.transform_point(&Point3::from(*point)).coords
+}
+
+// Normals are carried by the inverse transpose, so they stay perpendicular to the surface
+pub fn transform_normal(matrix: &Matrix4<Float>, normal: &FVec) -> FVec {
+    (normal_matrix(matrix) * normal).normalize()
+}
+
+pub fn normal_matrix(matrix: &Matrix4<Float>) -> Matrix3<Float> {
+    let linear: Matrix3<Float> = matrix.fixed_view::<3, 3>(0, 0).into();
+    linear
+        .try_inverse()
+        .map_or(Matrix3::identity(), |inverse| inverse.transpose())
+}
+
+// Whether the transform turns shapes inside out, as a mirror does
+pub fn is_mirror(matrix: &Matrix4<Float>) -> bool {
+    matrix.fixed_view::<3, 3>(0, 0).determinant() < 0.0
+}
+
+// The factor every length is scaled by, if the transform scales all directions alike
+pub fn uniform_scale(matrix: &Matrix4<Float>) -> Option<Float> {
+    let linear: Matrix3<Float> = matrix.fixed_view::<3, 3>(0, 0).into();
+    let squared = linear.transpose() * linear;
+    let scale_squared = squared.trace() / 3.0;
+    let deviation = (squared - Matrix3::identity() * scale_squared).abs().max();
+    (deviation <= UNIFORM_SCALE_TOLERANCE * scale_squared).then(|| scale_squared.sqrt())
+}
+
+// Whether the transform carries each axis onto an axis, so boxes lined up with them stay so
+pub fn keeps_axes(matrix: &Matrix4<Float>) -> bool {
+    let linear = matrix.fixed_view::<3, 3>(0, 0);
+    linear.column_iter().all(|column| {
+        let largest = column.amax();
+        column
+            .iter()
+            .filter(|c| c.abs() > UNIFORM_SCALE_TOLERANCE * largest)
+            .count()
+            == 1
+    })
+}
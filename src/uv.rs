@@ -0,0 +1,65 @@
+use crate::core::consts::PI;
+use crate::{FVec, Float};
+use serde::Deserialize;
+
+/*
+Texture coordinates worked out from where a point is on an object, relative
+to the object itself (see Shape::object_position), for shapes whose own
+coordinates do not suit a texture. Lengths are in metres divided by the
+scale, so a texture repeats every scale metres; the angles go once around
+whatever the scale. As with shapes' own coordinates, v runs the same way as
+rows of an image.
+ */
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum UvMapping {
+    // Longitude and colatitude around the object's z axis, in [0, 1]
+    Spherical,
+    // Angle around the z axis in [0, 1] and height down it
+    Cylindrical {
+        #[serde(default = "default_scale")]
+        scale: Float,
+    },
+    // x and y, as if projected down the z axis
+    Planar {
+        #[serde(default = "default_scale")]
+        scale: Float,
+    },
+    // The two axes of the side of a cube around the object that the surface faces most
+    Box {
+        #[serde(default = "default_scale")]
+        scale: Float,
+    },
+}
+
+fn default_scale() -> Float {
+    1.0
+}
+
+impl UvMapping {
+    pub(crate) fn scale(&self) -> Option<Float> {
+        match self {
+            UvMapping::Spherical => None,
+            UvMapping::Cylindrical { scale }
+            | UvMapping::Planar { scale }
+            | UvMapping::Box { scale } => Some(*scale),
+        }
+    }
+
+    // Coordinates of a point and the normal there, both relative to the object
+    pub(crate) fn uv(&self, local: &FVec, normal: &FVec) -> (Float, Float) {
+        let angle = 0.5 + local.y.atan2(local.x) / (2.0 * PI);
+        match *self {
+            UvMapping::Spherical => {
+                let z = local.z / local.norm().max(Float::MIN_POSITIVE);
+                (angle, z.clamp(-1.0, 1.0).acos() / PI)
+            }
+            UvMapping::Cylindrical { scale } => (angle, -local.z / scale),
+            UvMapping::Planar { scale } => (local.x / scale, local.y / scale),
+            UvMapping::Box { scale } => {
+                let face = normal.abs().imax();
+                (local[(face + 1) % 3] / scale, local[(face + 2) % 3] / scale)
+            }
+        }
+    }
+}
@@ -0,0 +1,573 @@
+use crate::camera::Projection;
+use crate::environment::Environment;
+use crate::fog::FogBounds;
+use crate::light::{DistantDirection, LightShape};
+use crate::material::{Material, MaterialParameter};
+use crate::scene::describe;
+use crate::studio::Studio;
+use crate::uv::UvMapping;
+use crate::{FVec, Float, Scene, SceneObject, Shape};
+use std::error::Error;
+use std::fmt;
+
+// Process exit codes, distinct per kind of failure for use in asset pipelines
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_PARSE_ERROR: i32 = 2;
+pub const EXIT_MISSING_ASSET: i32 = 3;
+pub const EXIT_INVALID_SCENE: i32 = 4;
+
+#[derive(Debug)]
+pub enum LoadError {
+    // The scene file could not be read or does not describe a scene
+    Parse(Box<dyn Error>),
+    // A texture, colour configuration or other file the scene refers to could not be loaded
+    Asset(Box<dyn Error>),
+    // Values that parse but cannot be rendered, every one found
+    Invalid(Vec<SceneProblem>),
+}
+
+impl LoadError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            LoadError::Parse(_) => EXIT_PARSE_ERROR,
+            LoadError::Asset(_) => EXIT_MISSING_ASSET,
+            LoadError::Invalid(_) => EXIT_INVALID_SCENE,
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Parse(error) => write!(f, "invalid scene: {}", error),
+            LoadError::Asset(error) => write!(f, "could not load asset: {}", error),
+            LoadError::Invalid(problems) => {
+                write!(f, "invalid scene:")?;
+                for problem in problems {
+                    write!(f, "\n  {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+// A value in the scene file that cannot be rendered, and where it is
+#[derive(Debug, Clone)]
+pub struct SceneProblem {
+    // Path to the value as written in the file, such as objects[2].material.shine
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for SceneProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GeometryProblem {
+    NonFinite,
+    NonPositiveRadius,
+    ZeroNormal,
+    // A cylinder, cone or torus whose axis has no length
+    ZeroAxis,
+    // A box whose min corner is above its max corner on some axis
+    InvertedBox,
+    // A combination of shapes with a part that does not enclose a solid, such as a mesh
+    NotSolid,
+    EmptyMesh,
+    // Same shape as an earlier object
+    DuplicateOf(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct GeometryWarning {
+    pub object: usize,
+    pub name: Option<String>,
+    pub problem: GeometryProblem,
+}
+
+impl GeometryProblem {
+    // Whether the object cannot be intersected sensibly and is left out of the render
+    pub fn is_degenerate(&self) -> bool {
+        !matches!(self, GeometryProblem::DuplicateOf(_))
+    }
+}
+
+impl fmt::Display for GeometryWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: ",
+            describe("object", self.object, self.name.as_deref())
+        )?;
+        match self.problem {
+            GeometryProblem::NonFinite => write!(f, "non-finite coordinates; skipped"),
+            GeometryProblem::NonPositiveRadius => write!(f, "radius is not positive; skipped"),
+            GeometryProblem::ZeroNormal => write!(f, "normal has zero length; skipped"),
+            GeometryProblem::ZeroAxis => write!(f, "axis has zero length; skipped"),
+            GeometryProblem::InvertedBox => write!(f, "box min is above its max; skipped"),
+            GeometryProblem::NotSolid => {
+                write!(f, "csg can only combine closed shapes and planes; skipped")
+            }
+            GeometryProblem::EmptyMesh => write!(f, "mesh has no triangles; skipped"),
+            GeometryProblem::DuplicateOf(other) => write!(f, "same shape as object {other}"),
+        }
+    }
+}
+
+fn is_finite(v: &FVec) -> bool {
+    v.iter().all(|c| c.is_finite())
+}
+
+fn shape_problem(shape: &Shape) -> Option<GeometryProblem> {
+    match shape {
+        Shape::Sphere { centre, radius } => {
+            if !is_finite(centre) || !radius.is_finite() {
+                Some(GeometryProblem::NonFinite)
+            } else if *radius <= 0.0 {
+                Some(GeometryProblem::NonPositiveRadius)
+            } else {
+                None
+            }
+        }
+        Shape::Plane { point, normal } => {
+            if !is_finite(point) || !is_finite(normal) {
+                Some(GeometryProblem::NonFinite)
+            } else if normal.norm_squared() == 0.0 {
+                Some(GeometryProblem::ZeroNormal)
+            } else {
+                None
+            }
+        }
+        Shape::Box { min, max } => {
+            if !is_finite(min) || !is_finite(max) {
+                Some(GeometryProblem::NonFinite)
+            } else if min.iter().zip(max.iter()).any(|(low, high)| low > high) {
+                Some(GeometryProblem::InvertedBox)
+            } else {
+                None
+            }
+        }
+        Shape::Cylinder {
+            base,
+            top: end,
+            radius,
+        }
+        | Shape::Cone {
+            base,
+            apex: end,
+            radius,
+        } => {
+            if !is_finite(base) || !is_finite(end) || !radius.is_finite() {
+                Some(GeometryProblem::NonFinite)
+            } else if *radius <= 0.0 {
+                Some(GeometryProblem::NonPositiveRadius)
+            } else if base == end {
+                Some(GeometryProblem::ZeroAxis)
+            } else {
+                None
+            }
+        }
+        Shape::Disc {
+            centre,
+            normal,
+            radius,
+        } => {
+            if !is_finite(centre) || !is_finite(normal) || !radius.is_finite() {
+                Some(GeometryProblem::NonFinite)
+            } else if *radius <= 0.0 {
+                Some(GeometryProblem::NonPositiveRadius)
+            } else if normal.norm_squared() == 0.0 {
+                Some(GeometryProblem::ZeroNormal)
+            } else {
+                None
+            }
+        }
+        Shape::Torus {
+            centre,
+            axis,
+            major_radius,
+            minor_radius,
+        } => {
+            let radii = [major_radius, minor_radius];
+            if !is_finite(centre) || !is_finite(axis) || !radii.iter().all(|r| r.is_finite()) {
+                Some(GeometryProblem::NonFinite)
+            } else if radii.iter().any(|r| **r <= 0.0) {
+                Some(GeometryProblem::NonPositiveRadius)
+            } else if axis.norm_squared() == 0.0 {
+                Some(GeometryProblem::ZeroAxis)
+            } else {
+                None
+            }
+        }
+        Shape::Csg { a, b, .. } => {
+            let solid = |child: &Shape| {
+                !matches!(
+                    child,
+                    Shape::Disc { .. } | Shape::Mesh { .. } | Shape::Plugin { .. }
+                )
+            };
+            if !solid(a) || !solid(b) {
+                Some(GeometryProblem::NotSolid)
+            } else {
+                shape_problem(a).or_else(|| shape_problem(b))
+            }
+        }
+        Shape::Mesh { mesh, .. } => {
+            let mut vertices = mesh.triangles.iter().flat_map(|triangle| triangle.vertices);
+            if mesh.triangles.is_empty() {
+                Some(GeometryProblem::EmptyMesh)
+            } else if !vertices.all(|v| is_finite(&v)) {
+                Some(GeometryProblem::NonFinite)
+            } else {
+                None
+            }
+        }
+        // Plugins check their own parameters when they are made
+        Shape::Plugin { .. } => None,
+    }
+}
+
+/*
+Problems with the objects' geometry, found at load time so they can be
+reported by index instead of showing up as NaN pixels in the render.
+ */
+pub fn check_geometry(objects: &[SceneObject]) -> Vec<GeometryWarning> {
+    let mut warnings = Vec::new();
+    for (index, object) in objects.iter().enumerate() {
+        let duplicate = || {
+            objects[..index]
+                .iter()
+                .position(|other| other.shape == object.shape)
+                .map(GeometryProblem::DuplicateOf)
+        };
+        if let Some(problem) = shape_problem(&object.shape).or_else(duplicate) {
+            warnings.push(GeometryWarning {
+                object: index,
+                name: object.name.clone(),
+                problem,
+            });
+        }
+    }
+    warnings
+}
+
+/*
+References from render layers to objects or lights that do not exist. These
+are silently ignored when rendering, so they usually point at a stale layer.
+ */
+pub fn check_layers(scene: &Scene) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (index, layer) in scene.layers.iter().enumerate() {
+        let objects = layer.objects.iter().flatten().chain(&layer.holdouts);
+        for object in objects.filter(|&&object| object >= scene.objects.len()) {
+            problems.push(format!("layer {index}: object {object} does not exist"));
+        }
+        let lights = layer.lights.iter().flatten();
+        for light in lights.filter(|&&light| light >= scene.lights.len()) {
+            problems.push(format!("layer {index}: light {light} does not exist"));
+        }
+    }
+    problems
+}
+
+// Collects problems under a common path prefix
+struct Problems<'a> {
+    problems: &'a mut Vec<SceneProblem>,
+    prefix: String,
+}
+
+impl<'a> Problems<'a> {
+    fn at(problems: &'a mut Vec<SceneProblem>, prefix: impl Into<String>) -> Problems<'a> {
+        let prefix = prefix.into();
+        Problems { problems, prefix }
+    }
+
+    fn add(&mut self, field: &str, message: impl Into<String>) {
+        let path = match self.prefix.as_str() {
+            "" => field.to_string(),
+            prefix => format!("{prefix}.{field}"),
+        };
+        self.problems.push(SceneProblem {
+            path,
+            message: message.into(),
+        });
+    }
+
+    fn positive(&mut self, field: &str, value: Float) {
+        if !(value > 0.0 && value.is_finite()) {
+            self.add(field, format!("must be a positive number, not {value}"));
+        }
+    }
+
+    fn non_negative(&mut self, field: &str, value: Float) {
+        if !(value >= 0.0 && value.is_finite()) {
+            self.add(field, format!("must be zero or more, not {value}"));
+        }
+    }
+
+    fn fraction(&mut self, field: &str, value: Float) {
+        if !(0.0..=1.0).contains(&value) {
+            self.add(field, format!("must be between 0 and 1, not {value}"));
+        }
+    }
+
+    fn direction(&mut self, field: &str, value: &FVec) {
+        if !is_finite(value) {
+            self.add(field, "has non-finite components");
+        } else if value.norm_squared() == 0.0 {
+            self.add(field, "has zero length, so it points nowhere");
+        }
+    }
+
+    fn material(&mut self, material: &Material) {
+        self.non_negative("kDiffuse", material.k_diffuse);
+        self.non_negative("kAmbient", material.k_ambient);
+        self.non_negative("kSpecular", material.k_specular);
+        self.non_negative("kReflect", material.k_reflect);
+        self.non_negative("kTransmit", material.k_transmit);
+        // An exponent of zero lights the whole surface as one flat highlight
+        let physically_based = material.metallic.is_some();
+        if !physically_based && !material.expressions.contains_key(&MaterialParameter::Shine) {
+            self.positive("shine", material.shine);
+        }
+        self.positive("ior", material.ior);
+        if let Some(abbe) = material.abbe {
+            self.positive("abbe", abbe);
+        }
+        self.fraction("roughness", material.roughness);
+        self.fraction("sheenRoughness", material.sheen_roughness);
+        self.fraction("clearcoat", material.clearcoat);
+        if let Some(metallic) = material.metallic {
+            self.fraction("metallic", metallic);
+        }
+        if let Some(opacity) = material.shadow_opacity {
+            self.fraction("shadowOpacity", opacity);
+        }
+        if let Some(subsurface) = &material.subsurface {
+            self.non_negative("subsurface.absorption", subsurface.absorption.min());
+            self.non_negative("subsurface.scattering", subsurface.scattering.min());
+            // Light neither absorbed nor scattered would spread without end
+            if (subsurface.absorption + subsurface.scattering).min() <= 0.0 {
+                self.add(
+                    "subsurface",
+                    "needs absorption or scattering in every channel",
+                );
+            }
+        }
+    }
+}
+
+/*
+Values that deserialize but make no sense to render, such as a camera looking
+nowhere or a highlight exponent of zero, which would otherwise panic or fill
+the image with NaNs part way through a render. Every problem is reported,
+each with the path to the value in the scene file. Parameters given as
+expressions are only known per shading point and are not checked.
+ */
+pub fn check_scene(scene: &Scene) -> Vec<SceneProblem> {
+    let mut problems = Vec::new();
+    let camera = &scene.camera;
+    let mut checked = Problems::at(&mut problems, "camera");
+    match camera.look_at {
+        Some(target) if target == camera.position => {
+            checked.add("lookAt", "is where the camera is, so it looks nowhere");
+        }
+        Some(target) if !is_finite(&target) => checked.add("lookAt", "has non-finite components"),
+        Some(_) => {}
+        None => checked.direction("direction", &camera.direction),
+    }
+    if let Some(up) = &camera.up {
+        checked.direction("up", up);
+    }
+    if !camera.roll.is_finite() {
+        checked.add("roll", "must be a number of degrees");
+    }
+    match camera.projection {
+        Projection::Perspective | Projection::Orthographic => {
+            if let Projection::Perspective = camera.projection {
+                checked.positive("screenDistance", camera.screen_distance);
+            }
+            checked.positive("screenWidth", camera.screen_width);
+            checked.positive("screenHeight", camera.screen_height);
+        }
+        Projection::Stereographic { field_of_view } | Projection::Fisheye { field_of_view } => {
+            checked.positive("projection.fieldOfView", field_of_view);
+        }
+        Projection::Equirectangular => {}
+    }
+    if camera.screen_columns == 0 || camera.screen_rows == 0 {
+        checked.add(
+            "screenColumns",
+            "the image needs at least one column and row",
+        );
+    }
+    if camera.samples == 0 {
+        checked.add("samples", "at least one sample per pixel is needed");
+    }
+    checked.non_negative("aperture", camera.aperture);
+    if let Some(focal_distance) = camera.focal_distance {
+        checked.positive("focalDistance", focal_distance);
+    }
+    checked.fraction("shutter", camera.shutter);
+    let mut checked = Problems::at(&mut problems, "");
+    checked.positive("scale", scene.scale);
+    checked.positive("frameRate", scene.frame_rate);
+    checked.non_negative("rayBias", scene.ray_bias);
+    for (index, object) in scene.objects.iter().enumerate() {
+        let mut checked = Problems::at(&mut problems, format!("objects[{index}].material"));
+        checked.material(&object.material);
+        if let Some(scale) = object.uv_mapping.as_ref().and_then(UvMapping::scale) {
+            let prefix = format!("objects[{index}].uvMapping");
+            Problems::at(&mut problems, prefix).positive("scale", scale);
+        }
+    }
+    for (index, light) in scene.lights.iter().enumerate() {
+        let mut checked = Problems::at(&mut problems, format!("lights[{index}]"));
+        checked.non_negative("intensity", light.intensity);
+        if let Some(cutoff_radius) = light.cutoff_radius {
+            checked.positive("cutoffRadius", cutoff_radius);
+        }
+        if light.shadow_samples == Some(0) {
+            checked.add("shadowSamples", "at least one shadow ray is needed");
+        }
+        match &light.shape {
+            LightShape::Sphere { radius } | LightShape::Tube { radius, .. } => {
+                checked.positive("shape.radius", *radius)
+            }
+            LightShape::Distant { direction } => {
+                if let DistantDirection::Vector(vector) = direction {
+                    checked.direction("shape.direction", vector);
+                }
+            }
+            LightShape::Point => {}
+        }
+        if let Some(spot) = &light.spot {
+            checked.direction("spot.direction", &spot.direction);
+            if !(spot.angle > 0.0 && spot.angle < 90.0) {
+                checked.add("spot.angle", "must be between 0 and 90 degrees");
+            }
+            checked.fraction("spot.blend", spot.blend);
+        }
+    }
+    for (index, local) in scene.fog_volumes.iter().enumerate() {
+        let mut checked = Problems::at(&mut problems, format!("fogVolumes[{index}]"));
+        checked.non_negative("density", local.fog.density);
+        match local.bounds {
+            FogBounds::Box { min, max } => {
+                if min.iter().zip(max.iter()).any(|(low, high)| low >= high) {
+                    checked.add("bounds.max", "must be above min on every axis");
+                }
+            }
+            FogBounds::Sphere { radius, .. } => checked.positive("bounds.radius", radius),
+        }
+    }
+    for (index, volume) in scene.volumes.iter().enumerate() {
+        let mut checked = Problems::at(&mut problems, format!("volumes[{index}]"));
+        if volume.dimensions.contains(&0) {
+            checked.add(
+                "dimensions",
+                "the grid needs at least one value along each axis",
+            );
+        }
+        if volume
+            .min
+            .iter()
+            .zip(volume.max.iter())
+            .any(|(low, high)| low >= high)
+        {
+            checked.add("max", "must be above min on every axis");
+        }
+        if let Some([low, high]) = volume.range {
+            if low == high {
+                checked.add("range", "needs two different values");
+            }
+        }
+        if volume.transfer_function.is_empty() {
+            checked.add("transferFunction", "needs at least one colour and opacity");
+        }
+        for (point, transfer) in volume.transfer_function.iter().enumerate() {
+            checked.fraction(
+                &format!("transferFunction[{point}].opacity"),
+                transfer.opacity,
+            );
+        }
+        checked.non_negative("extinction", volume.extinction);
+        if let Some(step) = volume.step {
+            checked.positive("step", step);
+        }
+    }
+    if let Some(Environment::Image {
+        blur,
+        sun_threshold,
+        importance_share,
+        ..
+    }) = &scene.environment
+    {
+        let mut checked = Problems::at(&mut problems, "environment");
+        checked.non_negative("blur", *blur);
+        if let Some(threshold) = sun_threshold {
+            checked.positive("sunThreshold", *threshold);
+        }
+        // Bounces drawn by the surface alone are what find the light of other surfaces
+        if !(0.0..1.0).contains(importance_share) {
+            let message = format!("must be at least 0 and below 1, not {importance_share}");
+            checked.add("importanceShare", message);
+        }
+    }
+    if let Some(denoise) = &scene.denoise {
+        let mut checked = Problems::at(&mut problems, "denoise");
+        checked.positive("colourSigma", denoise.colour_sigma);
+        checked.positive("normalSigma", denoise.normal_sigma);
+        checked.positive("albedoSigma", denoise.albedo_sigma);
+        checked.positive("depthSigma", denoise.depth_sigma);
+    }
+    if let Some(caustics) = &scene.caustics {
+        let mut checked = Problems::at(&mut problems, "caustics");
+        if caustics.photons == 0 {
+            checked.add("photons", "must be at least 1");
+        }
+        checked.positive("radius", caustics.radius);
+    }
+    if let Some(Studio::Setup(setup)) = &scene.studio {
+        let mut checked = Problems::at(&mut problems, "studio");
+        checked.non_negative("intensity", setup.intensity);
+        if setup.ground_colour.min() < 0.0 {
+            checked.add("groundColour", "has negative components");
+        }
+    }
+    for (index, decal) in scene.decals.iter().enumerate() {
+        let mut checked = Problems::at(&mut problems, format!("decals[{index}]"));
+        checked.fraction("opacity", decal.opacity);
+        checked.direction("direction", &decal.direction);
+        if let Some(up) = &decal.up {
+            checked.direction("up", up);
+        }
+        checked.positive("width", decal.width);
+        checked.positive("height", decal.height);
+        // Infinite by default, reaching everything in front of the projector
+        if decal.depth.is_nan() || decal.depth <= 0.0 {
+            checked.add(
+                "depth",
+                format!("must be a positive number, not {}", decal.depth),
+            );
+        }
+        if let Some(angle) = decal.field_of_view {
+            if !(angle > 0.0 && angle < 180.0) {
+                checked.add("fieldOfView", "must be between 0 and 180 degrees");
+            }
+        }
+    }
+    for (index, layer) in scene.layers.iter().enumerate() {
+        if let Some(material) = &layer.material_override {
+            let prefix = format!("layers[{index}].materialOverride");
+            Problems::at(&mut problems, prefix).material(material);
+        }
+    }
+    problems
+}
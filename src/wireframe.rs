@@ -0,0 +1,24 @@
+use crate::Float;
+use image::{ImageBuffer, Pixel};
+
+/*
+Draw a one-pixel line between two points in film pixel coordinates, skipping
+the parts outside the image.
+ */
+pub fn draw_line<P: Pixel>(
+    image: &mut ImageBuffer<P, Vec<P::Subpixel>>,
+    from: (Float, Float),
+    to: (Float, Float),
+    colour: P,
+) {
+    let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+    let steps = dx.abs().max(dy.abs()).ceil().max(1.0) as u32;
+    for i in 0..=steps {
+        let t = i as Float / steps as Float;
+        let x = (from.0 + t * dx).round();
+        let y = (from.1 + t * dy).round();
+        if x >= 0.0 && y >= 0.0 && x < image.width() as Float && y < image.height() as Float {
+            image.put_pixel(x as u32, y as u32, colour);
+        }
+    }
+}